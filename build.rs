@@ -0,0 +1,16 @@
+//! Generates the gRPC code of `fsync agent` when the `agent` feature is enabled.
+//!
+//! The proto file is compiled with protox, so `protoc` is not required.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "agent")]
+    {
+        println!("cargo:rerun-if-changed=proto/agent.proto");
+        let descriptors = protox::compile(["proto/agent.proto"], ["proto"]).expect("proto/agent.proto is valid");
+        tonic_build::configure()
+            .build_client(true)
+            .compile_fds(descriptors)
+            .expect("gRPC code generation");
+    }
+}
@@ -0,0 +1,560 @@
+//! gRPC agent mode.
+//!
+//! `fsync agent [--listen <addr>]` exposes the synchronisation operations
+//! over gRPC (see `proto/agent.proto`), so a central controller can drive
+//! agents on multiple hosts and collect their status. Destination backends
+//! are configured in the configuration file of the agent.
+//!
+//! The service only runs over TLS, with the `tls_cert` and `tls_key` of the
+//! `[agent]` section and, with a `client_ca`, client certificates. Every
+//! call must carry an `authorization: Bearer <token>` header with the
+//! `[agent]` token of the configuration file. The controller may only
+//! synchronise the `sources` directories of the section and write to its
+//! `destinations`.
+
+use std::{
+    fs,
+    net::ToSocketAddrs,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tonic::{
+    service::Interceptor,
+    transport::{Certificate, Identity, Server, ServerTlsConfig},
+    Request, Response, Status,
+};
+
+use crate::{App, AppError, AppHandle, BackendsConfig, Command, Config, Secret};
+use proto::{
+    agent_server::{Agent, AgentServer},
+    job::State,
+    Job as JobInfo, StartSyncReply, StatusReply, StatusRequest, StopSyncReply, StopSyncRequest, SyncOnceReply, SyncRequest,
+};
+
+/// Code generated from `proto/agent.proto`, including the client for controllers
+#[allow(missing_docs, clippy::missing_docs_in_private_items, clippy::missing_errors_doc)]
+pub mod proto {
+    tonic::include_proto!("fwatch.agent.v1");
+}
+
+/// Default port of `fsync agent`
+const DEFAULT_PORT: u16 = 7980;
+
+/// `[agent]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AgentConfig {
+    /// Bearer token expected from the controller
+    #[serde(default)]
+    pub(crate) token: Secret,
+    /// Address `fsync agent` listens on
+    #[serde(default)]
+    pub(crate) listen: Option<String>,
+    /// PEM certificate chain of the agent
+    #[serde(default)]
+    pub(crate) tls_cert: Option<PathBuf>,
+    /// PEM private key of [AgentConfig::tls_cert]
+    #[serde(default)]
+    pub(crate) tls_key: Option<PathBuf>,
+    /// PEM CA the controller certificates must be issued by, client
+    /// certificates are not required without it
+    #[serde(default)]
+    pub(crate) client_ca: Option<PathBuf>,
+    /// Directories the controller may synchronise, with everything below
+    #[serde(default)]
+    pub(crate) sources: Vec<PathBuf>,
+    /// Destinations the controller may synchronise to: directories for the
+    /// local ones, `scheme:location` prefixes for the remote ones
+    #[serde(default)]
+    pub(crate) destinations: Vec<String>,
+}
+
+/// Sources and destinations of [AgentConfig] the controller may use
+#[derive(Debug, Default)]
+struct Allowed {
+    /// Canonical source directories
+    sources: Vec<PathBuf>,
+    /// Canonical local destination directories
+    directories: Vec<PathBuf>,
+    /// Prefixes of the remote destinations
+    remotes: Vec<String>,
+}
+
+impl Allowed {
+    /// Resolves the allowed entries of `config`
+    fn new(config: &AgentConfig) -> Result<Self, AppError> {
+        let mut allowed = Self::default();
+        for source in &config.sources {
+            allowed.sources.push(fs::canonicalize(source).map_err(|e| {
+                AppError::PathErr(format!(
+                    "[agent] source {source:?}: {e}"
+                ))
+            })?);
+        }
+        for destination in &config.destinations {
+            if crate::target::split_scheme(Path::new(destination)).is_some() {
+                allowed.remotes.push(destination.clone());
+            } else {
+                allowed.directories.push(
+                    resolve(Path::new(destination)).ok_or_else(|| {
+                        AppError::PathErr(format!(
+                            "[agent] destination {destination:?} is not a plain path"
+                        ))
+                    })?,
+                );
+            }
+        }
+        Ok(allowed)
+    }
+
+    /// Canonical `source` if it is inside an allowed source directory
+    fn source(&self, source: &str) -> Option<PathBuf> {
+        fs::canonicalize(source)
+            .ok()
+            .filter(|path| self.sources.iter().any(|root| path.starts_with(root)))
+    }
+
+    /// `destination` if it is inside an allowed directory or starts with
+    /// an allowed remote prefix
+    fn destination(&self, destination: &str) -> Option<PathBuf> {
+        let path = Path::new(destination);
+        if let Some((_, location)) = crate::target::split_scheme(path) {
+            let allowed = self.remotes.iter().any(|prefix| {
+                destination
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || prefix.ends_with(['/', ':']) || rest.starts_with('/'))
+            });
+            let parent = location.split(['/', '\\']).any(|part| part == "..");
+            return (allowed && !parent).then(|| path.to_owned());
+        }
+        resolve(path).filter(|path| self.directories.iter().any(|root| path.starts_with(root)))
+    }
+}
+
+/// Absolute `path` with its symbolic links resolved as far as it exists,
+/// none if it has `..` components
+fn resolve(path: &Path) -> Option<PathBuf> {
+    if path.components().any(|c| c == Component::ParentDir) {
+        return None;
+    }
+    let path = std::path::absolute(path).ok()?;
+    let mut existing = path.as_path();
+    loop {
+        if let Ok(canonical) = fs::canonicalize(existing) {
+            return Some(canonical.join(path.strip_prefix(existing).ok()?));
+        }
+        existing = existing.parent()?;
+    }
+}
+
+/// Background synchronisation started with `StartSync`
+struct Job {
+    /// Identifier returned to the controller
+    id: u64,
+    /// Source directory
+    source: String,
+    /// Destination as given by the controller
+    destination: String,
+    /// Seconds since the epoch
+    started_at: u64,
+    /// Current state and the failure description
+    state: Mutex<(State, String)>,
+    /// `StopSync` was called
+    stop: AtomicBool,
+    /// Handle of the running app, to stop it
+    handle: Mutex<Option<AppHandle>>,
+}
+
+impl Job {
+    /// Updates the state of the job
+    fn set(&self, state: State, error: String) {
+        *self.state.lock().unwrap_or_else(|e| e.into_inner()) = (state, error);
+    }
+
+    /// Runs the initial sync followed by watching, blocks until the watcher stops
+    fn run(&self, config: Config) {
+        let mut app = match App::new(config) {
+            Ok(app) => app,
+            Err(err) => return self.set(State::Failed, err.to_string()),
+        };
+        *self.handle.lock().unwrap_or_else(|e| e.into_inner()) = Some(app.handle());
+        if let Err(err) = app.sync_once() {
+            return self.set(State::Failed, err.to_string());
+        }
+        if self.stop.load(Ordering::Relaxed) {
+            return self.set(State::Finished, String::new());
+        }
+        self.set(State::Watching, String::new());
+        match app.watch_source() {
+            Ok(()) => self.set(State::Finished, String::new()),
+            Err(err) => self.set(State::Failed, err.to_string()),
+        }
+    }
+
+    /// Stops watching, after the initial sync if it is still running
+    fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            handle.stop();
+        }
+    }
+
+    /// Message sent to the controller
+    fn info(&self) -> JobInfo {
+        let (state, error) = self.state.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        JobInfo {
+            id: self.id,
+            source: self.source.clone(),
+            destination: self.destination.clone(),
+            state: state.into(),
+            error,
+            started_at: self.started_at,
+        }
+    }
+}
+
+/// Implementation of the `Agent` service
+struct AgentService {
+    /// Backend settings of the agent configuration file
+    backends: BackendsConfig,
+    /// Sources and destinations the controller may use
+    allowed: Allowed,
+    /// Jobs started with `StartSync`
+    jobs: Mutex<Vec<Arc<Job>>>,
+    /// Identifier of the next job
+    next_id: AtomicU64,
+}
+
+impl AgentService {
+    /// Configuration of a synchronisation requested by the controller
+    fn config(&self, request: &SyncRequest) -> Result<Config, Box<Status>> {
+        if request.source.is_empty() || request.destination.is_empty() {
+            return Err(Box::new(Status::invalid_argument(
+                "source and destination are required",
+            )));
+        }
+        let source = self.allowed.source(&request.source).ok_or_else(|| {
+            Status::permission_denied(format!(
+                "source {:?} is not allowed",
+                request.source
+            ))
+        })?;
+        let destination = self.allowed.destination(&request.destination).ok_or_else(|| {
+            Status::permission_denied(format!(
+                "destination {:?} is not allowed",
+                request.destination
+            ))
+        })?;
+        Ok(Config {
+            command: Command::Sync,
+            backends: self.backends.clone(),
+            ..Config::build(source, destination)
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Agent for AgentService {
+    async fn status(&self, _request: Request<StatusRequest>) -> Result<Response<StatusReply>, Status> {
+        let jobs = self
+            .jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|job| job.info())
+            .collect();
+        Ok(Response::new(StatusReply {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            hostname: crate::peer::hostname(),
+            jobs,
+        }))
+    }
+
+    async fn sync_once(&self, request: Request<SyncRequest>) -> Result<Response<SyncOnceReply>, Status> {
+        let config = self.config(request.get_ref()).map_err(|status| *status)?;
+        tracing::info!(
+            "agent: sync {:?} -> {:?}",
+            config.source(),
+            config.destination()
+        );
+        tokio::task::spawn_blocking(move || App::new(config)?.sync_once())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::aborted(e.to_string()))?;
+        Ok(Response::new(SyncOnceReply {}))
+    }
+
+    async fn start_sync(&self, request: Request<SyncRequest>) -> Result<Response<StartSyncReply>, Status> {
+        let config = self.config(request.get_ref()).map_err(|status| *status)?;
+        let request = request.into_inner();
+        let job = Arc::new(Job {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            source: request.source,
+            destination: request.destination,
+            started_at: crate::peer::unix_secs(SystemTime::now()),
+            state: Mutex::new((State::Syncing, String::new())),
+            stop: AtomicBool::new(false),
+            handle: Mutex::default(),
+        });
+        tracing::info!(
+            "agent: job {} started: {:?} -> {:?}",
+            job.id,
+            job.source,
+            job.destination
+        );
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner()).push(job.clone());
+
+        let id = job.id;
+        std::thread::spawn(move || job.run(config));
+        Ok(Response::new(StartSyncReply { id }))
+    }
+
+    async fn stop_sync(&self, request: Request<StopSyncRequest>) -> Result<Response<StopSyncReply>, Status> {
+        let id = request.get_ref().id;
+        let job = self
+            .jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|job| job.id == id)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("no job {id}")))?;
+        tracing::info!("agent: stopping job {id}");
+        job.stop();
+        Ok(Response::new(StopSyncReply {}))
+    }
+}
+
+/// Checks the bearer token of every call
+#[derive(Clone)]
+struct Authorization {
+    /// Expected `authorization` header
+    expected: Arc<[u8]>,
+}
+
+impl Interceptor for Authorization {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        match request.metadata().get("authorization") {
+            // Constant time, the time taken does not tell how much of the token matched
+            Some(value) if bool::from(value.as_bytes().ct_eq(&self.expected)) => Ok(request),
+            _ => Err(Status::unauthenticated("invalid token")),
+        }
+    }
+}
+
+/// TLS settings of the server
+fn tls(agent: &AgentConfig) -> Result<ServerTlsConfig, AppError> {
+    let (Some(cert), Some(key)) = (&agent.tls_cert, &agent.tls_key) else {
+        return Err(AppError::Backend(
+            "fsync agent requires an [agent] tls_cert and tls_key".into(),
+        ));
+    };
+    let read = |path: &PathBuf| fs::read(path).map_err(|e| AppError::PathErr(format!("{path:?}: {e}")));
+    let mut tls = ServerTlsConfig::new().identity(Identity::from_pem(
+        read(cert)?,
+        read(key)?,
+    ));
+    if let Some(ca) = &agent.client_ca {
+        tls = tls.client_ca_root(Certificate::from_pem(read(ca)?));
+    }
+    Ok(tls)
+}
+
+/// Serves the agent API until the process is stopped.
+///
+/// # Errors
+///
+/// [AppError] is returned if no token, certificate, source or destination
+/// is configured or the gRPC server could not be started.
+pub fn run(config: &Config) -> Result<(), AppError> {
+    let agent = config.backends.agent.clone().unwrap_or_default();
+    let token = agent.token.expose()?;
+    if token.is_empty() {
+        return Err(AppError::Backend(
+            "fsync agent requires an [agent] token".into(),
+        ));
+    }
+    let authorize = Authorization {
+        expected: format!("Bearer {token}").into_bytes().into(),
+    };
+    let tls = tls(&agent)?;
+    let allowed = Allowed::new(&agent)?;
+    if allowed.sources.is_empty() || (allowed.directories.is_empty() && allowed.remotes.is_empty()) {
+        return Err(AppError::Backend(
+            "fsync agent requires [agent] sources and destinations".into(),
+        ));
+    }
+
+    let listen = config
+        .listen
+        .clone()
+        .or(agent.listen)
+        .unwrap_or_else(|| format!("0.0.0.0:{DEFAULT_PORT}"));
+    let address = listen
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| AppError::Backend(format!("{listen}: no address")))?;
+
+    let service = AgentService {
+        backends: config.backends.clone(),
+        allowed,
+        jobs: Mutex::default(),
+        next_id: AtomicU64::new(1),
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    tracing::info!("agent listening on {address}");
+    runtime
+        .block_on(
+            Server::builder()
+                .tls_config(tls)
+                .map_err(|e| AppError::Backend(format!("agent: {e}")))?
+                .add_service(AgentServer::with_interceptor(
+                    service, authorize,
+                ))
+                .serve(address),
+        )
+        .map_err(|e| AppError::Backend(format!("agent: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confines_the_requests() {
+        let root = std::env::temp_dir().join(format!(
+            "fsync-agent-{}",
+            std::process::id()
+        ));
+        for dir in ["src/docs", "dst", "outside"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let allowed = Allowed::new(&AgentConfig {
+            sources: vec![root.join("src")],
+            destinations: vec![root.join("dst").to_string_lossy().into_owned(), "gs:bucket/backups".into()],
+            ..AgentConfig::default()
+        })
+        .unwrap();
+        let path = |dir: &str| root.join(dir).to_string_lossy().into_owned();
+
+        assert!(allowed.source(&path("src/docs")).is_some());
+        assert!(allowed.source(&path("outside")).is_none());
+        assert!(allowed.source(&path("src/../outside")).is_none());
+        assert!(allowed.source(&path("src/missing")).is_none());
+        assert!(allowed.destination(&path("dst/new/dir")).is_some());
+        assert!(allowed.destination(&path("dst/../outside")).is_none());
+        assert!(allowed.destination(&path("dstx")).is_none());
+        assert!(allowed.destination("gs:bucket/backups/host1").is_some());
+        assert!(allowed.destination("gs:bucket/backupsx").is_none());
+        assert!(allowed.destination("gs:bucket/backups/../other").is_none());
+        assert!(allowed.destination("gs:other").is_none());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(
+                root.join("outside"),
+                root.join("src/escape"),
+            )
+            .unwrap();
+            std::os::unix::fs::symlink(
+                root.join("outside"),
+                root.join("dst/escape"),
+            )
+            .unwrap();
+            assert!(allowed.source(&path("src/escape")).is_none());
+            assert!(allowed.destination(&path("dst/escape/dir")).is_none());
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn checks_the_token() {
+        let mut authorize = Authorization {
+            expected: b"Bearer secret".to_vec().into(),
+        };
+        let request = |header: Option<&'static str>| {
+            let mut request = Request::new(());
+            if let Some(header) = header {
+                request.metadata_mut().insert("authorization", header.parse().unwrap());
+            }
+            request
+        };
+        assert!(authorize.call(request(Some("Bearer secret"))).is_ok());
+        assert!(authorize.call(request(Some("Bearer secreT"))).is_err());
+        assert!(authorize.call(request(Some("Bearer"))).is_err());
+        assert!(authorize.call(request(None)).is_err());
+        assert!(tls(&AgentConfig::default()).is_err());
+    }
+
+    #[test]
+    fn stops_started_jobs() {
+        let root = std::env::temp_dir().join(format!(
+            "fsync-agent-job-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("dst")).unwrap();
+        fs::write(root.join("src/a.txt"), "a").unwrap();
+        let service = AgentService {
+            backends: BackendsConfig::default(),
+            allowed: Allowed::new(&AgentConfig {
+                sources: vec![root.join("src")],
+                destinations: vec![root.join("dst").to_string_lossy().into_owned()],
+                ..AgentConfig::default()
+            })
+            .unwrap(),
+            jobs: Mutex::default(),
+            next_id: AtomicU64::new(1),
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let state = |service: &AgentService| {
+            let reply = runtime.block_on(service.status(Request::new(StatusRequest {}))).unwrap();
+            reply.into_inner().jobs[0].state()
+        };
+        let wait_for = |expected: State| {
+            for _ in 0..100 {
+                if state(&service) == expected {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            panic!(
+                "job not {expected:?}: {:?}",
+                state(&service)
+            );
+        };
+
+        let id = runtime
+            .block_on(
+                service.start_sync(Request::new(SyncRequest {
+                    source: root.join("src").to_string_lossy().into_owned(),
+                    destination: root.join("dst").to_string_lossy().into_owned(),
+                })),
+            )
+            .unwrap()
+            .into_inner()
+            .id;
+        wait_for(State::Watching);
+        assert_eq!(
+            fs::read(root.join("dst/a.txt")).unwrap(),
+            b"a"
+        );
+        runtime
+            .block_on(service.stop_sync(Request::new(StopSyncRequest { id })))
+            .unwrap();
+        wait_for(State::Finished);
+        assert!(runtime
+            .block_on(
+                service.stop_sync(Request::new(StopSyncRequest {
+                    id: id + 1
+                }))
+            )
+            .is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
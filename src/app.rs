@@ -1,82 +1,681 @@
 //! Main worker module
 //! Represented by [App] structure.
 
-use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
 use std::{
+    collections::HashSet,
     fs,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-/// Application error wrapper
-#[derive(Debug)]
+use crate::{
+    audit::{AuditLog, Record},
+    coalesce::Coalescer,
+    compare::{CompareMode, DiffEntry, Difference, TreeDiff},
+    confirm::Prompt,
+    events::Events,
+    executor::{DirectExecutor, Executor},
+    failover::FailoverTarget,
+    failures::{ErrorReport, Failure},
+    git::Git,
+    hooks::{self, Batch, Exec, HooksConfig},
+    ignore::Ignore,
+    metrics,
+    names::Names,
+    only::Only,
+    paths::{self, EventPaths, UnicodeForm},
+    plan::{Action, MetadataReport, PlanReport, Preview, SyncPlan},
+    planner::{Candidate, MetadataPlanner, Planner},
+    policy::{ErrorPolicy, OnError},
+    queue::{OfflineQueue, Operation},
+    quota::Quota,
+    rename::Renamer,
+    report::Reporter,
+    retry::{self, RetryConfig},
+    route::Router,
+    selection::Selection,
+    stats::Counters,
+    template::Template,
+    transform::{self, Transform, TransformChain},
+    watchdog::{self, Watchdog, WatchdogConfig},
+    watcher::{EventSource, Poll},
+    FilterChain, PathFilter, Phase, Stats, Status, SyncEvent, SyncObserver, SyncTarget, TargetMetadata,
+};
+
+/// Interval between attempts to reach an unavailable destination
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Longest interval between the scans moving the files which became old
+/// enough to be archived
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Shortest interval between these scans, each one walks the whole source
+const MIN_ARCHIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest wait for a change before a requested rescan starts
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Files from this size on are only copied if the destination has space
+/// for them
+const PREFLIGHT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Application error wrapper.
+///
+/// Failures of the operations on files are wrapped in [AppError::Failed]
+/// naming the operation and the paths, the original error stays available
+/// through [source()](std::error::Error::source).
+#[derive(Debug, thiserror::Error)]
 pub enum AppError {
     /// [Error](std::error::Error) wrapper to represent errors from Input/Output
-    IoError(std::io::Error),
+    #[error("IO: {0}")]
+    IoError(#[from] std::io::Error),
     /// [SystemTimeError](std::time::SystemTimeError) wrapper
-    SystemTime(std::time::SystemTimeError),
+    #[error("SystemTime: {0}")]
+    SystemTime(#[from] std::time::SystemTimeError),
+    /// [notify::Error] wrapper, errors of the watcher
+    #[error("Watch: {0}")]
+    Watch(#[from] notify::Error),
     /// Generic Path error. Mostly represents invalid paths.
+    #[error("Path error: {0}")]
     PathErr(String),
     /// [StripPrefixError](std::path::StripPrefixError) wrapper.
     /// Used in ['build_dest_path()'] as error propogation from [std::path::Path::strip_prefix()] function
-    StripPrefix(std::path::StripPrefixError),
+    #[error("Strip Prefix: {0}")]
+    StripPrefix(#[from] std::path::StripPrefixError),
+    /// Error reported by a remote destination backend
+    #[error("Backend: {0}")]
+    Backend(String),
+    /// The destination has not enough free space left
+    #[error("not enough space at the destination: {needed} bytes needed, {available} available")]
+    InsufficientSpace {
+        /// Bytes to be stored
+        needed: u64,
+        /// Bytes free at the destination
+        available: u64,
+    },
+    /// The copy would grow the destination beyond its `[quota]`
+    #[error("destination quota of {quota} bytes exceeded: {used} bytes stored, {needed} more needed")]
+    QuotaExceeded {
+        /// Most bytes stored at the destination
+        quota: u64,
+        /// Bytes stored now
+        used: u64,
+        /// Bytes the copy would add
+        needed: u64,
+    },
+    /// `operation` on `path` failed
+    #[error("{operation} {}: {source}", describe(.path, .to.as_deref()))]
+    Failed {
+        /// What was done, e.g. `copy` or `read metadata`
+        operation: &'static str,
+        /// Path the operation failed on
+        path: PathBuf,
+        /// Second path of the operation: the destination of a copy or the new name of a rename
+        to: Option<PathBuf>,
+        /// Cause of the failure
+        source: Box<AppError>,
+    },
+    /// Entries changed since a saved [SyncPlan] was made
+    #[error(
+        "the plan is stale, {} entries changed since it was made: {}",
+        .changed.len(),
+        .changed.first().map(|path| path.display().to_string()).unwrap_or_default()
+    )]
+    StalePlan {
+        /// Source paths and destination paths, relative to the destination
+        /// root, of the changed entries
+        changed: Vec<PathBuf>,
+    },
+    /// The watch stopped after `errors` failed changes in a row
+    #[error("stopped after {errors} consecutive failures, the last one: {last}")]
+    TooManyErrors {
+        /// Failed changes in a row
+        errors: u32,
+        /// Last failure
+        #[source]
+        last: Box<AppError>,
+    },
 }
 
-impl std::error::Error for AppError {}
-
-impl From<std::io::Error> for AppError {
-    fn from(value: std::io::Error) -> Self {
-        Self::IoError(value)
+/// `path` or `path -> to`
+fn describe(path: &Path, to: Option<&Path>) -> String {
+    match to {
+        Some(to) => format!("{} -> {}", path.display(), to.display()),
+        None => path.display().to_string(),
     }
 }
 
-impl From<std::time::SystemTimeError> for AppError {
-    fn from(value: std::time::SystemTimeError) -> Self {
-        Self::SystemTime(value)
+impl AppError {
+    /// Wraps the error in [AppError::Failed]
+    pub fn context(self, operation: &'static str, path: impl Into<PathBuf>) -> Self {
+        Self::Failed {
+            operation,
+            path: path.into(),
+            to: None,
+            source: Box::new(self),
+        }
+    }
+
+    /// Wraps the error in [AppError::Failed] of an operation involving two paths
+    pub fn context_to(self, operation: &'static str, path: impl Into<PathBuf>, to: impl Into<PathBuf>) -> Self {
+        Self::Failed {
+            operation,
+            path: path.into(),
+            to: Some(to.into()),
+            source: Box::new(self),
+        }
     }
-}
 
-impl From<std::path::StripPrefixError> for AppError {
-    fn from(value: std::path::StripPrefixError) -> Self {
-        Self::StripPrefix(value)
+    /// Innermost error, below all [AppError::Failed] and
+    /// [AppError::TooManyErrors] wrappers
+    pub fn root_cause(&self) -> &AppError {
+        match self {
+            Self::Failed { source, .. } => source.root_cause(),
+            Self::TooManyErrors { last, .. } => last.root_cause(),
+            other => other,
+        }
     }
 }
 
-impl std::fmt::Display for AppError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match *self {
-            AppError::IoError(ref e) => write!(f, "IO: {e}"),
-            AppError::SystemTime(ref e) => write!(f, "SystemTime: {e}"),
-            AppError::PathErr(ref e) => write!(f, "Path error: {e}"),
-            AppError::StripPrefix(ref e) => write!(f, "Strip Prefix: {e}"),
-        }
+/// Adds the operation and the path to the error of a [Result]
+pub(crate) trait Context<T> {
+    /// See [AppError::context]
+    fn context(self, operation: &'static str, path: &Path) -> Result<T, AppError>;
+}
+
+impl<T, E: Into<AppError>> Context<T> for Result<T, E> {
+    fn context(self, operation: &'static str, path: &Path) -> Result<T, AppError> {
+        self.map_err(|err| err.into().context(operation, path))
     }
 }
 
 /// Main worker.
 ///
-/// Contains two paths:
-/// source and destination as [PathBuf]
+/// Contains the source path as [PathBuf]
+/// and the destination as [SyncTarget]
 pub struct App {
     /// Source path to monitor changes
     source: PathBuf,
-    /// Destination path for syncronisation
-    destination: PathBuf,
+    /// Source and destination as configured, before a file or pattern
+    /// source is resolved
+    origin: (PathBuf, PathBuf),
+    /// Part of the source synchronised if it is a file or a pattern,
+    /// [App::source] is the directory watched for it
+    selection: Option<Selection>,
+    /// Destination for syncronisation
+    target: Box<dyn SyncTarget>,
+    /// Journal of the changes made while the destination is unavailable
+    queue_file: PathBuf,
+    /// Template of the destination paths
+    template: Option<Template>,
+    /// Routes of the files by their type
+    router: Option<Router>,
+    /// Rules renaming the files at the destination
+    renamer: Option<Renamer>,
+    /// Unicode form of the destination names
+    unicode: Option<UnicodeForm>,
+    /// Escaping of the names rejected by the destination
+    names: Option<Names>,
+    /// Audit trail of the applied changes
+    audit: Option<Mutex<AuditLog>>,
+    /// Statistics, shared with the [AppHandle]s
+    stats: Arc<Counters>,
+    /// A rescan was requested through an [AppHandle]
+    rescan_requested: Arc<AtomicBool>,
+    /// Watching was asked to stop through an [AppHandle], shared with the
+    /// [pairs](App::pairs)
+    stop_requested: Arc<AtomicBool>,
+    /// Commands run around synchronisations
+    hooks: HooksConfig,
+    /// Operations applied since the last hook, recorded only with hooks set
+    batch: Mutex<Batch>,
+    /// `--exec` command
+    exec: Option<Exec>,
+    /// Observers registered by the embedding application
+    observers: Vec<Box<dyn SyncObserver>>,
+    /// Filters registered by the embedding application
+    filters: FilterChain,
+    /// Transforms of the copied contents registered by the embedding
+    /// application
+    transforms: TransformChain,
+    /// Source of the changes while watching
+    event_source: Mutex<Box<dyn EventSource>>,
+    /// Whether the source is watched with inotify, whose watch limit the
+    /// initial scan checks
+    #[cfg(target_os = "linux")]
+    inotify: bool,
+    /// Decides the actions of the initial sync
+    planner: Box<dyn Planner>,
+    /// Performs the changes of the destination
+    executor: Box<dyn Executor>,
+    /// Files which are never synchronised
+    ignore: Option<Ignore>,
+    /// Patterns of the only synchronised files
+    only: Option<Only>,
+    /// `--interactive` confirmation of overwrites and removals
+    prompt: Option<Mutex<Prompt>>,
+    /// `--move`: source files are removed once they are stored
+    move_files: bool,
+    /// `--archive-after`: only the files not modified for that long are
+    /// moved
+    archive_after: Option<Duration>,
+    /// How changed files are compared with their stored copies
+    compare: CompareMode,
+    /// Permissions `fsync repair-perms` gives the stored entries
+    permissions: crate::PermissionsConfig,
+    /// Webhook notifications
+    #[cfg(feature = "webhooks")]
+    webhooks: Option<Arc<crate::webhooks::Webhooks>>,
+    /// Alerts for a watcher without events
+    watchdog: Option<WatchdogConfig>,
+    /// Script filtering and routing the changes
+    #[cfg(feature = "scripting")]
+    script: Option<crate::script::Script>,
+    /// Thumbnails of the copied images
+    #[cfg(feature = "media")]
+    media: Option<crate::MediaConfig>,
+    /// Periodic summary reports
+    report: Option<Reporter>,
+    /// Retries of transient failures
+    retry: Option<RetryConfig>,
+    /// Cap on the bytes stored at the destination
+    quota: Option<Quota>,
+    /// Reactions to failed changes
+    errors: ErrorPolicy,
+    /// Changes failed in a row in the watch loop, with the last failure
+    failures: Mutex<(u32, Option<AppError>)>,
+    /// Changes given up since the last [ErrorReport] was returned
+    failed: Mutex<ErrorReport>,
+    /// Email alerts
+    #[cfg(feature = "email")]
+    email: Option<crate::email::Alerts>,
+    /// Desktop notifications
+    #[cfg(feature = "desktop")]
+    desktop: Option<crate::desktop::Desktop>,
+    /// Commits of the settled changes of the destination
+    git: Option<Git>,
+    /// Further pairs watched by the same loop, the destinations the source
+    /// is fanned out to first
+    pairs: Vec<App>,
+    /// Senders of the changes of the source to the destinations it is
+    /// fanned out to, dropped once watching stopped
+    mirrors: Mutex<Vec<Sender<Vec<Operation>>>>,
+}
+
+/// State of the watch of one pair
+struct Watching<'a> {
+    /// Source of the changes, locked while watching
+    source: MutexGuard<'a, Box<dyn EventSource>>,
+    /// Changes waiting for an unavailable destination
+    queue: OfflineQueue,
+    /// Last replay of the queue
+    last_attempt: Instant,
+    /// Last scan for the files to archive
+    last_archive: Instant,
+    /// Maps the paths of the events to the watched path
+    event_paths: EventPaths,
+    /// Copies held to coalesce atomic saves
+    coalescer: Coalescer,
+    /// Alerts for a watcher without events, stopped when dropped
+    _watchdog: Option<Watchdog>,
+}
+
+/// Change applied to the destination, reported by [App::report]
+struct Outcome<'a> {
+    /// `copy`, `remove` or `rename`
+    name: &'static str,
+    /// Change by source paths, or by destination paths if [Outcome::stored]
+    operation: &'a Operation,
+    /// Destination path
+    destination: &'a Path,
+    /// Old destination path of a rename
+    from: Option<&'a Path>,
+    /// Size of the copied file
+    bytes: u64,
+    /// Attempts made
+    attempts: u32,
+    /// Planned for entries only the destination has, e.g. the ones the
+    /// initial sync removes
+    stored: bool,
+}
+
+/// Handle to a running [App], usable from other threads
+#[derive(Debug, Clone)]
+pub struct AppHandle {
+    /// Statistics of the app
+    stats: Arc<Counters>,
+    /// A rescan was requested
+    rescan: Arc<AtomicBool>,
+    /// Watching was asked to stop
+    stop: Arc<AtomicBool>,
+    /// Source and description of the destination
+    pair: Arc<(PathBuf, String)>,
+}
+
+impl AppHandle {
+    /// Statistics since the app was created
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot()
+    }
+
+    /// Current stage of the app
+    pub fn phase(&self) -> Phase {
+        self.stats.current_phase()
+    }
+
+    /// Changes waiting for an unavailable destination
+    pub fn queue_depth(&self) -> u64 {
+        self.stats.queue_depth()
+    }
+
+    /// Time of the last change of the source seen by the watcher
+    pub fn last_event(&self) -> Option<SystemTime> {
+        self.stats.last_event()
+    }
+
+    /// Time and description of the last failure
+    pub fn last_error(&self) -> Option<(SystemTime, String)> {
+        self.stats.last_error()
+    }
+
+    /// Current state of the app
+    pub fn status(&self) -> Status {
+        self.stats.status(self.pair.0.clone(), self.pair.1.clone())
+    }
+
+    /// Asks the watching app for an [App::rescan()], it starts within a
+    /// second between the live changes
+    pub fn rescan(&self) {
+        self.rescan.store(true, Ordering::Relaxed);
+    }
+
+    /// Asks the watching app and its pairs to stop, the held changes are
+    /// applied and [App::run()] returns within a second
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
 }
 
 impl App {
     /// Application constructor.
     ///
-    /// Accepts [Config](crate::Config) as an input.
-    pub fn new(config: crate::Config) -> Self {
-        let crate::Config { source, destination } = config;
+    /// Accepts [Config](crate::Config) as an input. A file given as the
+    /// source is synchronised alone, to a destination file or directory, a
+    /// pattern synchronises the matching files only.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] whould be returned if the destination backend
+    /// is not available. See [open](crate::target::open).
+    /// [AppError::IoError] is returned if the audit file could not be opened.
+    pub fn new(mut config: crate::Config) -> Result<Self, AppError> {
+        if config.move_files && !config.destinations.is_empty() {
+            return Err(AppError::PathErr(
+                "--move removes the source files, they can not be mirrored to further destinations".into(),
+            ));
+        }
+        let mut pairs = Vec::new();
+        let mut mirrors = Vec::new();
+        for destination in std::mem::take(&mut config.destinations) {
+            let (tx, changes) = crate::watcher::mirror();
+            let mut fan_out = App::new(config.fan_out(destination))?;
+            fan_out.set_event_source(changes);
+            pairs.push(fan_out);
+            mirrors.push(tx);
+        }
+        for (source, destination) in std::mem::take(&mut config.pairs) {
+            pairs.push(App::new(
+                config.pair(source, destination),
+            )?);
+        }
+        let origin = (
+            config.source.clone(),
+            config.destination.clone(),
+        );
+        let selection = Selection::resolve(&mut config);
+        let rescan_requested = Arc::<AtomicBool>::default();
+        let stop_requested = Arc::<AtomicBool>::default();
+        for pair in &mut pairs {
+            pair.stop_requested = stop_requested.clone();
+        }
+        let target = match &config.failover {
+            Some(failover) => {
+                let secondary = crate::target::open(&config.pair(
+                    config.source.clone(),
+                    failover.secondary.clone(),
+                ))?;
+                Box::new(FailoverTarget::new(
+                    crate::target::open(&config)?,
+                    secondary,
+                    failover,
+                    rescan_requested.clone(),
+                ))
+            }
+            None => crate::target::open(&config)?,
+        };
+        let audit = config.audit.map(AuditLog::open).transpose()?.map(Mutex::new);
+        let git = config
+            .git
+            .as_ref()
+            .map(|git| Git::new(git, &config.destination))
+            .transpose()?;
+        #[cfg(feature = "webhooks")]
+        let webhooks = crate::webhooks::Webhooks::new(
+            &config.backends.webhooks,
+            &config.backends.network,
+        )?
+        .map(Arc::new);
+        let queue_file = config
+            .queue_file
+            .unwrap_or_else(|| OfflineQueue::default_path(&config.source, &config.destination));
+        #[cfg(feature = "email")]
+        let email = config
+            .backends
+            .email
+            .as_ref()
+            .map(|email| crate::email::Alerts::new(email, &config.backends.network))
+            .transpose()?;
+        let names = config
+            .names
+            .as_ref()
+            .map(|names| {
+                let mapping = queue_file.with_extension("names.jsonl");
+                Names::open(names, mapping.clone()).context("open name mapping", &mapping)
+            })
+            .transpose()?;
+        let event_source = crate::watcher::event_source(&config.watcher, &queue_file)?;
+        let template = config.destination_template.as_deref().map(Template::new).transpose()?;
+        let renamer = Renamer::new(&config.renames)?;
+        let source = config.source;
+        let ignore = Ignore::new(&config.ignore, &source);
+        let only = Only::new(&config.only, &source);
+        let prompt = config.interactive.then(|| Mutex::new(Prompt::stdio()));
+        let hooks = config.hooks;
+        let report = config.report;
+        #[cfg(feature = "scripting")]
+        let script = config.backends.script.as_ref().map(crate::script::Script::load).transpose()?;
+        let exec = hooks.exec.clone().map(Exec::new);
+
+        tracing::info!("source path is set to: {:?}", source);
+        tracing::info!(
+            "destination is set to: {}",
+            target.describe()
+        );
+
+        let mut app = Self {
+            source,
+            origin,
+            selection,
+            target,
+            queue_file,
+            template,
+            router: Router::new(&config.routes),
+            renamer,
+            unicode: config.unicode_normalization,
+            names,
+            audit,
+            stats: Arc::default(),
+            rescan_requested,
+            stop_requested,
+            hooks,
+            batch: Mutex::default(),
+            exec,
+            observers: Vec::new(),
+            filters: FilterChain::new(),
+            transforms: TransformChain::new(),
+            event_source: Mutex::new(event_source),
+            #[cfg(target_os = "linux")]
+            inotify: config.watcher.backend == crate::watcher::Backend::Notify,
+            planner: Box::new(MetadataPlanner),
+            executor: Box::new(DirectExecutor),
+            ignore,
+            only,
+            prompt,
+            move_files: config.move_files,
+            archive_after: config.archive_after,
+            compare: config.compare,
+            permissions: config.permissions,
+            #[cfg(feature = "webhooks")]
+            webhooks,
+            watchdog: config.watchdog,
+            #[cfg(feature = "scripting")]
+            script,
+            #[cfg(feature = "media")]
+            media: config.backends.media.clone(),
+            report: None,
+            retry: config.retry,
+            quota: None,
+            errors: config.errors,
+            failures: Mutex::default(),
+            failed: Mutex::default(),
+            #[cfg(feature = "email")]
+            email,
+            #[cfg(feature = "desktop")]
+            desktop: config.backends.desktop.map(crate::desktop::Desktop::new),
+            git,
+            pairs,
+            mirrors: Mutex::new(mirrors),
+        };
+        if let Some(quota) = &config.quota {
+            #[cfg(feature = "webhooks")]
+            let alert = app.notifier(crate::webhooks::Webhooks::quota);
+            #[cfg(not(feature = "webhooks"))]
+            let alert = |_: &str| {};
+            app.quota = Some(Quota::new(quota, alert));
+        }
+        if let Some(report) = report {
+            #[cfg(feature = "webhooks")]
+            let notify = app.notifier(crate::webhooks::Webhooks::report);
+            #[cfg(not(feature = "webhooks"))]
+            let notify = |_: &str| {};
+            let (reporter, digest) = Reporter::start(report, app.stats.clone(), notify);
+            app.report = Some(reporter);
+            app.add_observer(digest);
+        }
+        #[cfg(feature = "media")]
+        if let Some(media) = &app.media {
+            if let Some(dir) = &media.thumbnails {
+                if dir.as_os_str().is_empty() || !dir.components().all(|part| matches!(part, Component::Normal(_))) {
+                    return Err(AppError::PathErr(format!(
+                        "the thumbnail directory {} is not below the destination",
+                        dir.display()
+                    )));
+                }
+            }
+            if media.strip_gps {
+                app.add_transform(crate::StripGps);
+            }
+        }
+        Ok(app)
+    }
+
+    /// Registers `observer` for the changes applied from now on
+    pub fn add_observer(&mut self, observer: impl SyncObserver + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Events of the changes seen and applied from now on
+    pub fn events(&mut self) -> Receiver<SyncEvent> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.add_observer(Events(tx));
+        rx
+    }
+
+    /// Registers `filter`, asked before the changes applied from now on
+    /// after the filters added before
+    pub fn add_filter(&mut self, filter: impl PathFilter + 'static) {
+        self.filters.push(filter);
+    }
 
-        log::info!("source path is set to: {:?}", source);
-        log::info!(
-            "destination path is set to: {:?}",
-            destination
+    /// Registers `transform`, applied to the contents of the files copied
+    /// from now on after the transforms added before
+    pub fn add_transform(&mut self, transform: impl Transform + 'static) {
+        self.transforms.push(transform);
+    }
+
+    /// Stores the changes at `target` instead of the destination of the
+    /// [Config](crate::Config)
+    pub fn set_target(&mut self, target: impl SyncTarget + 'static) {
+        tracing::info!(
+            "destination is set to: {}",
+            target.describe()
         );
+        self.target = Box::new(target);
+    }
+
+    /// Watches `source` instead of the watcher of the platform
+    pub fn set_event_source(&mut self, source: impl EventSource + 'static) {
+        self.event_source = Mutex::new(Box::new(source));
+        #[cfg(target_os = "linux")]
+        {
+            self.inotify = false;
+        }
+    }
+
+    /// Decides the actions of the initial sync with `planner` instead of
+    /// comparing the size and modification time
+    pub fn set_planner(&mut self, planner: impl Planner + 'static) {
+        self.planner = Box::new(planner);
+    }
+
+    /// Passes the changes applied from now on to `executor`
+    pub fn set_executor(&mut self, executor: impl Executor + 'static) {
+        self.executor = Box::new(executor);
+    }
+
+    /// Synchronises and watches the pair of `config` as well, by the same
+    /// loop and thread as this app. Its settings apply to it alone.
+    ///
+    /// # Errors
+    ///
+    /// Same as [App::new()]
+    pub fn add_pair(&mut self, config: crate::Config) -> Result<(), AppError> {
+        self.pairs.push(App::new(config)?);
+        Ok(())
+    }
 
-        Self { source, destination }
+    /// Pairs added with [App::add_pair()] or configured with `[[pair]]`,
+    /// after the further destinations of the source
+    pub fn pairs(&self) -> &[App] {
+        &self.pairs
+    }
+
+    /// Handle for reading the statistics while [App::run()] blocks
+    pub fn handle(&self) -> AppHandle {
+        AppHandle {
+            stats: self.stats.clone(),
+            rescan: self.rescan_requested.clone(),
+            stop: self.stop_requested.clone(),
+            pair: Arc::new((
+                self.source.clone(),
+                self.target.describe(),
+            )),
+        }
     }
 
     /// Main worker method.
@@ -84,331 +683,3651 @@ impl App {
     /// # Errors
     ///
     /// - [AppError::IoError] whould be returned if the source path doesn't exist
-    /// - [AppError::IoError] whould be returned if the destination path doesn't exist
+    /// - [AppError] whould be returned if the destination is not reachable
     /// - [App::initial_sync()] can also throw [AppError]
+    /// - [AppError::TooManyErrors] is returned once the watch stopped after
+    ///   [ErrorPolicy] `max_consecutive` failed changes in a row
     ///
-    pub fn run(&mut self) -> Result<(), AppError> {
-        // Just an error propogation
-        let _ = self.source.read_dir()?;
-        let _ = self.destination.read_dir()?;
-        // Initial scan of source directory
-        // with copying everything mismatched
-        self.initial_sync()?;
+    /// Returns the changes which could not be applied, by the initial scan
+    /// and by the watch.
+    ///
+    /// The further destinations and [pairs](App::add_pair) are synchronised
+    /// after this one and then watched together with it. Those failing
+    /// their initial synchronisation are logged and left out.
+    pub fn run(&mut self) -> Result<ErrorReport, AppError> {
+        let mut report = self.start_run()?;
+        for pair in &mut self.pairs {
+            // Left out of the watch, the other pairs go on
+            match pair.start_run() {
+                Ok(pair) => report.append(pair),
+                Err(error) => tracing::error!("{}: {error}", pair.target.describe()),
+            }
+        }
         // Main watch event handler
-        if let Err(error) = self.watch(self.source.as_path()) {
-            log::error!("Error: {error:?}");
+        let watched = match self.pairs.is_empty() {
+            true => self.watch_source(),
+            false => self.watch_pairs(),
+        };
+        if let Err(error) = &watched {
+            tracing::error!("Error: {error:?}");
+            self.stats.failed(error);
         }
+        let mut aborted = self.stop_run(&watched);
+        for pair in &self.pairs {
+            if pair.stats.current_phase() != Phase::Stopped {
+                aborted = aborted.or(pair.stop_run(&watched));
+            }
+            report.append(pair.take_failed());
+        }
+        report.append(self.take_failed());
+        aborted.map_or(Ok(report), Err)
+    }
 
-        Ok(())
+    /// Initial synchronisation of [App::run()]
+    fn start_run(&mut self) -> Result<ErrorReport, AppError> {
+        self.sync_once().inspect_err(|error| {
+            self.stats.failed(error);
+            self.stats.phase(Phase::Stopped);
+        })
     }
 
-    /// First run syncronisation.
+    /// Ends [App::run()] once `watched` returned, with the error stopping
+    /// the watch if there was one
+    fn stop_run(&self, watched: &Result<(), AppError>) -> Option<AppError> {
+        #[cfg(not(feature = "email"))]
+        let _ = watched;
+        let aborted = match std::mem::take(&mut *self.failures.lock().unwrap_or_else(|e| e.into_inner())) {
+            (errors, Some(last)) if self.too_many_errors_after(errors) => Some(AppError::TooManyErrors {
+                errors,
+                last: Box::new(last),
+            }),
+            _ => None,
+        };
+        if let Some(error) = &aborted {
+            tracing::error!("{error}");
+            self.stats.failed(error);
+        }
+        #[cfg(feature = "email")]
+        if let Some(email) = &self.email {
+            let reason = match (&aborted, &watched) {
+                (Some(error), _) => error.to_string(),
+                (None, Err(error)) => error.to_string(),
+                (None, Ok(())) => "no more events".into(),
+            };
+            email.watcher_died(&self.source.to_string_lossy(), &reason);
+        }
+        self.stats.phase(Phase::Stopped);
+        tracing::info!("summary: {}", self.stats.snapshot());
+        aborted
+    }
+
+    /// Failures recorded since the last call
+    fn take_failed(&self) -> ErrorReport {
+        std::mem::take(&mut *self.failed.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Single synchronisation pass without watching for changes.
     ///
-    /// Initial scan of source directory is triggered only
-    /// at the beginning of the execution
-    /// with copying everything mismatched
+    /// Returns the changes which could not be applied.
     ///
     /// # Errors
     ///
-    /// [AppError] whould be returned if:
-    ///
-    /// - [sync_by_metadata](fn@App::sync_by_metadata) function fails
-    fn initial_sync(&mut self) -> Result<(), AppError> {
-        log::info!(
-            "Initial scan started: {:?}",
-            self.source.as_path()
-        );
-        let src_entries = App::collect_dir_entries(self.source.as_path());
-
-        for src_entry in src_entries {
-            if src_entry.is_file() {
-                // Sync
-                self.sync_by_metadata(src_entry)?;
-            }
+    /// Same as [App::run()]
+    pub fn sync_once(&mut self) -> Result<ErrorReport, AppError> {
+        self.hook(
+            "before_sync",
+            self.hooks.before_sync.as_deref(),
+            &self.take_batch(),
+        )?;
+        // Just an error propogation
+        let _ = self.source.read_dir().context("read directory", &self.source)?;
+        self.target.connect()?;
+        // Changes made while the destination was unavailable go first
+        let mut queue = OfflineQueue::open(self.queue_file.clone()).context("open offline queue", &self.queue_file)?;
+        if !queue.is_empty() {
+            self.replay(&mut queue);
+        }
+        // Changes made while fsync was not running, if the event source
+        // knows them, otherwise an initial scan of source directory
+        // with copying everything mismatched
+        let recursive = self.selection.as_ref().is_none_or(Selection::recursive);
+        let missed = self
+            .event_source
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .missed(&self.source, recursive)?;
+        match missed {
+            Some(operations) => self.catch_up(&mut queue, operations),
+            None => self.initial_sync()?,
+        }
+        #[cfg(feature = "webhooks")]
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.initial_sync(&crate::webhooks::Details {
+                message: format!(
+                    "initial sync of {} complete: {}",
+                    self.source.display(),
+                    self.stats.snapshot()
+                ),
+                source: &self.source.to_string_lossy(),
+                destination: &self.target.describe(),
+                ..Default::default()
+            });
+        }
+        let batch = self.take_batch();
+        if let Err(err) = self.hook(
+            "after_sync",
+            self.hooks.after_sync.as_deref(),
+            &batch,
+        ) {
+            tracing::warn!("{err}");
         }
+        self.commit(&batch);
+        for observer in &self.observers {
+            observer.on_batch_complete(&batch);
+        }
+        Ok(self.take_failed())
+    }
 
-        log::info!(
-            "Initial scan finished: {:?}",
+    /// Applies the changes the event source saw since the last run instead
+    /// of the initial scan
+    fn catch_up(&self, queue: &mut OfflineQueue, operations: Vec<Operation>) {
+        tracing::info!(
+            "{} changes made since the last run of {:?}",
+            operations.len(),
             self.source
         );
-
-        Ok(())
+        let event_paths = EventPaths::new(&self.source);
+        for operation in operations {
+            self.submit(queue, event_paths.operation(operation));
+        }
     }
 
-    /// Rename file from destination path to the same name at the destination
-    fn rename<P: AsRef<Path>>(&self, from: P, to: P) -> Result<(), AppError> {
-        let new_filename = to.as_ref().file_name().unwrap();
-        let old_filename = from.as_ref().file_name().unwrap();
-        let destination = self.build_dest_path(to.as_ref())?;
-
-        let from = destination.with_file_name(old_filename);
-        let to = destination.with_file_name(new_filename);
-
-        log::info!("renaming:\n{:?}\n{:?}", from, to);
+    /// Operations of the current batch are recorded, for hooks and notifications
+    fn batching(&self) -> bool {
+        #[cfg(feature = "desktop")]
+        if self.desktop.as_ref().is_some_and(|desktop| desktop.wants_batches()) {
+            return true;
+        }
+        !self.observers.is_empty()
+            || self.hooks.any()
+            || self.exec.as_ref().is_some_and(|exec| !exec.per_path())
+            || self.git.is_some()
+    }
 
-        Ok(fs::rename(from, to)?)
+    /// Commits the changes of `batch` to the git history of the destination
+    fn commit(&self, batch: &Batch) {
+        if let Some(Err(err)) = self.git.as_ref().map(|git| git.commit(batch)) {
+            tracing::warn!("git: {err}");
+        }
     }
 
-    /// Copies the file from source to destination
-    /// creating all necessary directories recursively
-    fn copy<P: AsRef<Path>>(&self, src: P) -> Result<(), AppError> {
-        let src = src.as_ref();
-        let dst = self.build_dest_path(src)?;
-        log::info!("copy: {:?}", dst.file_name().unwrap());
+    /// Operations recorded since the last call, starting a new batch
+    fn take_batch(&self) -> Batch {
+        std::mem::take(&mut *self.batch.lock().unwrap_or_else(|e| e.into_inner()))
+    }
 
-        if src.is_dir() {
-            log::debug!("IS DIRECTORY: {src:?}");
-            fs::create_dir_all(dst.as_path())?;
-            return Ok(());
+    /// Runs `command` for the operations of `batch`
+    fn hook(&self, hook: &str, command: Option<&str>, batch: &Batch) -> Result<(), AppError> {
+        match command {
+            Some(command) => hooks::run(
+                hook,
+                command,
+                &self.source,
+                &self.target.describe(),
+                batch,
+            ),
+            None => Ok(()),
         }
+    }
 
-        match fs::copy(src, dst.as_path()) {
-            Ok(_) => Ok(()),
-            Err(err) => match err.kind() {
-                std::io::ErrorKind::NotFound => {
-                    fs::create_dir_all(dst.as_path().parent().unwrap())?;
-                    fs::copy(src, dst)?;
-                    Ok(())
-                }
-                _ => {
-                    log::error!("{err}");
-                    Err(err.into())
-                }
-            },
+    /// Reports the batch of changes the watcher just applied
+    fn finish_batch(&self) {
+        let batch = self.take_batch();
+        if let Err(err) = self.hook(
+            "after_batch",
+            self.hooks.after_batch.as_deref(),
+            &batch,
+        ) {
+            tracing::warn!("{err}");
+        }
+        if let Some(exec) = self.exec.as_ref().filter(|exec| !exec.per_path()) {
+            if let Err(err) = exec.batch(
+                &self.source,
+                &self.target.describe(),
+                &batch,
+            ) {
+                tracing::warn!("exec: {err}");
+            }
+        }
+        #[cfg(feature = "desktop")]
+        if let Some(desktop) = &self.desktop {
+            desktop.batch(&batch);
+        }
+        self.commit(&batch);
+        for observer in &self.observers {
+            observer.on_batch_complete(&batch);
         }
     }
 
-    /// Removes directory or file from the destination
-    /// keeping the same path as in the src parameter
-    fn remove<P: AsRef<Path>>(&self, src: P) -> Result<(), AppError> {
-        let src = src.as_ref();
-        let dst = self.build_dest_path(src)?;
-        log::info!("remove: {:?}", dst.file_name().unwrap());
-
-        // src doesn't exist anymore
-        if dst.is_dir() {
-            log::debug!("IS DIRECTORY: {src:?}");
-            fs::remove_dir(dst.as_path())?;
-            return Ok(());
+    /// Sends the messages of a background thread to the webhooks with `send`
+    #[cfg(feature = "webhooks")]
+    fn notifier(&self, send: fn(&crate::webhooks::Webhooks, &crate::webhooks::Details)) -> impl Fn(&str) + Send + 'static {
+        let webhooks = self.webhooks.clone();
+        let source = self.source.to_string_lossy().into_owned();
+        let destination = self.target.describe();
+        move |message| {
+            if let Some(webhooks) = &webhooks {
+                send(
+                    webhooks,
+                    &crate::webhooks::Details {
+                        message: message.to_owned(),
+                        source: &source,
+                        destination: &destination,
+                        ..Default::default()
+                    },
+                );
+            }
         }
+    }
 
-        Ok(fs::remove_file(dst)?)
+    /// Compares the whole source with the destination again and applies the
+    /// differences like the initial sync, for changes the watcher missed.
+    ///
+    /// While [App::run()] watches, [AppHandle::rescan()] runs it between the
+    /// live changes.
+    ///
+    /// # Errors
+    ///
+    /// - [AppError] is returned if the destination is not reachable
+    /// - the error of the first entry which could not be compared or
+    ///   applied is returned if the [ErrorPolicy] of the initial sync
+    ///   aborts, otherwise it is reported by [App::run()]
+    pub fn rescan(&self) -> Result<PlanReport, AppError> {
+        let _span = tracing::info_span!("rescan", source = %self.source.display()).entered();
+        tracing::info!("rescan started: {:?}", self.source);
+        let phase = self.stats.current_phase();
+        self.stats.phase(Phase::Scanning);
+        let report = self.rescan_entries();
+        self.stats.phase(phase);
+        report
     }
 
-    /// Replaces the suffix in the provided path
-    /// to create the same path at the destination folder
-    fn build_dest_path<P: AsRef<Path>>(&self, from_str: P) -> Result<PathBuf, AppError> {
-        let src_str = from_str.as_ref().to_string_lossy().to_string();
-        let soruce_prefix = self.source.as_path().to_string_lossy().to_string();
-        if let Some(mut offset) = src_str.find(&soruce_prefix) {
-            let prefix = match offset {
-                0 => self.source.as_path(),
-                _ => {
-                    offset += soruce_prefix.len();
-                    log::debug!(
-                        "counted offset for {} == {}:",
-                        src_str,
-                        offset
-                    );
-                    Path::new(src_str.get(..offset).ok_or(AppError::PathErr(soruce_prefix.clone()))?)
-                }
-            };
-            let src_stripped = from_str.as_ref().strip_prefix(prefix)?;
-            let result = Path::new(self.destination.as_path()).join(src_stripped);
-
-            log::debug!(
-                "buildig destination:\nsource path: {}\nstripped to: {:?}\nresult: {:?}",
-                &src_str,
-                src_stripped,
-                result
-            );
-            return Ok(result);
+    /// Applies the differences found by a [App::rescan()]
+    fn rescan_entries(&self) -> Result<PlanReport, AppError> {
+        self.target.connect()?;
+        let plan = self.plan_entries(SyncPlan::new(), &self.scan())?;
+        if let Err(err) = self.preflight(&plan) {
+            if self.errors.initial_sync == OnError::Abort {
+                return Err(err);
+            }
+            tracing::error!("{err}");
         }
-
-        Err(AppError::PathErr(soruce_prefix))
+        let report = self.apply_plan(&plan)?;
+        tracing::info!(
+            "rescan finished: {} applied, {} skipped",
+            report.applied.len(),
+            report.skipped.len()
+        );
+        metrics::synced();
+        Ok(report)
     }
 
-    /// Syncronises source path to the destination by checking
-    /// the source file metadata.
+    /// Compares the source directory `dir` with its stored copy and applies
+    /// the differences, for a watcher only telling that something below it
+    /// changed.
+    ///
+    /// Unlike a [App::rescan()] the stored entries without a source entry
+    /// are removed as well, unless the source files are moved or the
+    /// destination is not a mirror of the source directories.
+    ///
+    /// # Errors
     ///
-    /// If the elapsed time in seconds since the last change
-    /// differs from destination file, then copies the file.
-    /// Or if the file at the destination directory does not exist.
-    fn sync_by_metadata<P: AsRef<Path>>(&self, src: P) -> Result<(), AppError> {
-        let src_meta = fs::metadata(&src)?;
-        let src_last_modified = src_meta.modified()?.elapsed()?.as_secs();
+    /// - [AppError] is returned if the destination is not reachable
+    /// - errors listing the stored entries are returned
+    fn rescan_directory(&self, dir: &Path) -> Result<PlanReport, AppError> {
+        let _span = tracing::info_span!("rescan", source = %dir.display()).entered();
+        if !dir.starts_with(&self.source) {
+            return Ok(PlanReport::default());
+        }
+        self.target.connect()?;
+        if fs::symlink_metadata(paths::extended(dir)).is_err() {
+            // Removed along with everything below it
+            self.execute(&Operation::Remove { path: dir.to_path_buf() })?;
+            return Ok(PlanReport::default());
+        }
+        let phase = self.stats.current_phase();
+        self.stats.phase(Phase::Scanning);
+        let walked = App::collect_dir_entries(dir);
+        let entries: Vec<_> = walked.iter().filter(|entry| !self.excluded(entry)).cloned().collect();
+        let mut plan = self.plan_entries(SyncPlan::new(), &entries)?;
+        if self.mirrors_dirs() && !self.move_files {
+            // Ignored source entries keep their stored copies
+            let mut stored = HashSet::new();
+            for entry in &walked {
+                stored.extend(self.build_dest_path(entry)?.ancestors().map(Path::to_path_buf));
+            }
+            let mut diff = TreeDiff::default();
+            self.unstored(
+                &self.build_dest_path(dir)?,
+                &stored,
+                &mut diff,
+            )?;
+            for entry in diff.entries {
+                if !self.internal(&entry.path) {
+                    plan.push(Action::Remove { destination: entry.path });
+                }
+            }
+        }
+        self.stats.phase(phase);
+        let report = self.apply_plan(&plan)?;
+        tracing::info!(
+            "rescan of {} finished: {} applied, {} skipped",
+            dir.display(),
+            report.applied.len(),
+            report.skipped.len()
+        );
+        Ok(report)
+    }
 
-        let dst = self.build_dest_path(src.as_ref())?;
+    /// Whether the destination entry at `path` is kept by fsync itself, the
+    /// git repository or the thumbnails, and has no source entry
+    fn internal(&self, path: &Path) -> bool {
+        #[cfg(feature = "media")]
+        if let Some(dir) = self.media.as_ref().and_then(|media| media.thumbnails.as_ref()) {
+            if path.starts_with(dir) {
+                return true;
+            }
+        }
+        self.git.is_some() && crate::git::in_repository(Path::new(""), path)
+    }
 
-        match fs::metadata(&dst) {
-            Ok(dst_meta) => {
-                let dst_last_modified = dst_meta.modified()?.elapsed()?.as_secs();
+    /// Watches the source path until the watcher stops
+    pub(crate) fn watch_source(&self) -> Result<(), AppError> {
+        self.watch(self.source.as_path())
+    }
 
-                log::debug!(
-                    "{} modified: {}",
-                    src.as_ref().file_name().unwrap().to_str().unwrap(),
-                    src_last_modified
-                );
-                log::debug!(
-                    "{} modified: {}",
-                    dst.file_name().unwrap().to_str().unwrap(),
-                    dst_last_modified
-                );
+    /// Compares the source with the destination without changing either.
+    ///
+    /// Entries which could not be compared are left out of the plan and
+    /// reported by the next [App::apply()], unless the [ErrorPolicy] of the
+    /// initial sync aborts.
+    ///
+    /// # Errors
+    ///
+    /// - [AppError] is returned if the destination is not reachable
+    /// - the error of the first entry which could not be compared is
+    ///   returned if the [ErrorPolicy] of the initial sync aborts
+    pub fn plan(&self) -> Result<SyncPlan, AppError> {
+        self.target.connect()?;
+        let plan = SyncPlan::made_for(
+            &self.origin.0,
+            &self.origin.1,
+            SystemTime::now(),
+        );
+        self.plan_entries(plan, &self.scan())
+    }
 
-                if src_last_modified != dst_last_modified {
-                    // File found and was modified - need to sync
-                    log::info!(
-                        "syncing(metadata change): {:?}",
-                        dst.file_name().unwrap()
-                    );
-                    // let _ = fs::copy(src, dst)?;
-                    self.copy(src)?;
-                }
+    /// Applies `plan` to the destination.
+    ///
+    /// A plan made by [App::plan()] is only applied if nothing it depends on
+    /// changed since, see [SyncPlan].
+    ///
+    /// Returns the actions applied and skipped, and the changes which could
+    /// not be applied.
+    ///
+    /// # Errors
+    ///
+    /// - [AppError] is returned if the destination is not reachable
+    /// - [AppError::PathErr] is returned for a plan made for another source
+    ///   or destination
+    /// - [AppError::StalePlan] is returned if entries changed since the
+    ///   plan was made
+    /// - the error of the first failed change is returned if the
+    ///   [ErrorPolicy] of the initial sync aborts
+    pub fn apply(&self, plan: &SyncPlan) -> Result<PlanReport, AppError> {
+        self.target.connect()?;
+        if let (Some(source), Some(destination)) = (plan.source(), plan.destination()) {
+            if (source, destination)
+                != (
+                    self.origin.0.as_path(),
+                    self.origin.1.as_path(),
+                )
+            {
+                return Err(AppError::PathErr(format!(
+                    "the plan was made for {} -> {}",
+                    source.display(),
+                    destination.display()
+                )));
             }
-            Err(err) => match err.kind() {
-                std::io::ErrorKind::NotFound => {
-                    // File not found - need to sync
-                    log::info!(
-                        "syncing(file not present): {:?}",
-                        dst.file_name().unwrap()
-                    );
-                    // let _ = fs::copy(src, dst)?;
-                    self.copy(src)?;
-                }
-                _ => log::error!("{err}"),
-            },
         }
-        Ok(())
+        if let Some(planned_at) = plan.planned_at() {
+            let planned_at = humantime::parse_rfc3339(planned_at).map_err(|err| {
+                AppError::PathErr(format!(
+                    "invalid plan time {planned_at:?}: {err}"
+                ))
+            })?;
+            let changed = self.changed_since(plan, planned_at)?;
+            if !changed.is_empty() {
+                return Err(AppError::StalePlan { changed });
+            }
+        }
+        let mut report = self.apply_plan(plan)?;
+        report.failures = self.take_failed();
+        Ok(report)
     }
 
-    /// Recursive walkthrough all directories and collect them.
-    fn collect_dir_entries<P: AsRef<Path>>(path: P) -> Vec<PathBuf> {
-        walkdir::WalkDir::new(path)
-            .into_iter()
-            .filter_map(|entry| match entry {
-                Ok(entry) => Some(entry.into_path()),
+    /// `--metadata-only`: gives the stored files the modification time, the
+    /// permissions and, on Linux, the `user.` extended attributes of their
+    /// sources without copying them. Files missing at the destination or
+    /// of another size there are reported and left alone.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if the destination can not update
+    /// metadata in place, the errors of a file are reported as failures.
+    pub fn sync_metadata(&self) -> Result<MetadataReport, AppError> {
+        self.target.connect()?;
+        let mut report = MetadataReport::default();
+        for entry in self.scan() {
+            let Ok(meta) = fs::metadata(paths::extended(&entry)) else {
+                continue;
+            };
+            if !meta.is_file() {
+                continue;
+            }
+            let dst = self.build_dest_path(&entry)?;
+            let synced = self.target.metadata(&dst).and_then(|stored| match stored {
+                Some(stored) if !stored.is_dir && stored.len == meta.len() => self.target.sync_metadata(&entry, &dst).map(Some),
+                _ => Ok(None),
+            });
+            match synced {
+                Ok(Some(Some(true))) => {
+                    tracing::info!("metadata: {}", dst.display());
+                    report.updated.push(dst);
+                }
+                Ok(Some(Some(false))) => report.unchanged += 1,
+                Ok(Some(None)) => {
+                    return Err(AppError::Backend(format!(
+                        "{} can not update metadata in place",
+                        self.target.describe()
+                    )))
+                }
+                Ok(None) => {
+                    tracing::warn!(
+                        "{} differs from its source, not copied",
+                        dst.display()
+                    );
+                    report.differing.push(dst);
+                }
                 Err(err) => {
-                    log::warn!("{err}");
-                    None
+                    let err = err.context_to("update metadata", &entry, &dst);
+                    tracing::error!("{err}");
+                    report.failures.push(Failure {
+                        operation: "metadata",
+                        path: entry,
+                        error: err.to_string(),
+                        attempts: 1,
+                    });
                 }
-            })
-            .collect::<Vec<_>>()
+            }
+        }
+        Ok(report)
     }
 
-    /// Watcher method.
+    /// `fsync repair-perms`: gives the stored entries the permissions and
+    /// the owners of their sources, or the modes of the `[permissions]`
+    /// section, without copying them. Entries missing at the destination or
+    /// of another type there are reported and left alone.
     ///
-    /// All data from watcher is sent via [MPSC channels](std::sync::mpsc::channel())
-    /// Events being captured:
-    ///
-    /// - [Modify](notify::EventKind::Modify)
-    /// - [Create](notify::EventKind::Create)
-    /// - [Remove](notify::EventKind::Remove)
+    /// # Errors
     ///
-    /// Modify event captured differently
-    /// based on the [ModifyKind](notify::event::ModifyKind)
-    /// One is captured during renaming, another one is during file modification
-    fn watch<P: AsRef<Path>>(&self, path: P) -> notify::Result<()> {
-        use notify::event::ModifyKind;
-        use notify::event::RenameMode;
-        use notify::EventKind;
-
-        let (tx, rx) = std::sync::mpsc::channel();
-
-        // Automatically select the best implementation for your platform.
-        // You can also access each implementation directly e.g. INotifyWatcher.
-        let mut watcher = RecommendedWatcher::new(tx, Config::default())?;
-
-        // Add a path to be watched. All files and directories at that path and
-        // below will be monitored for changes.
-        watcher.watch(path.as_ref(), RecursiveMode::Recursive)?;
-
-        log::info!("watch started: {:?}", path.as_ref());
-        // 95 percent of cases there should be only one path
-        let mut files_to_rename = Vec::with_capacity(1);
-
-        for res in rx {
-            match res {
-                Ok(event) => {
-                    log::trace!("Change: {event:?}");
-                    match event.kind {
-                        EventKind::Modify(ModifyKind::Name(rename_mode)) => match rename_mode {
-                            RenameMode::From => files_to_rename = event.paths,
-                            RenameMode::To => {
-                                let mut new_filenames = event.paths;
-                                files_to_rename.iter().for_each(
-                                    |old_filename| match new_filenames.pop() {
-                                        Some(new_filename) => {
-                                            if let Err(e) = self.rename(old_filename, &new_filename) {
-                                                log::error!("{e}");
-                                            }
-                                        }
-                                        None => log::error!(
-                                            "Cannot rename {:?}. Nothing left in the event",
-                                            old_filename
-                                        ),
-                                    },
-                                )
-                            }
-                            _ => log::warn!("rename mode could not be handled: {rename_mode:?}"),
-                        },
-                        EventKind::Create(_) => {
-                            event.paths.iter().for_each(|p| {
-                                if let Err(e) = self.copy(p) {
-                                    log::error!("{e}");
-                                }
-                            });
-                        }
-                        EventKind::Modify(ModifyKind::Any) => {
-                            // During directory removal there will be the second MODYFY(ANY) event
-                            // causing parent directory to update itself for some reason
-                            event.paths.iter().for_each(|p| {
-                                if let Err(e) = self.copy(p) {
-                                    log::error!("{e}");
-                                }
-                            });
-                        }
-                        EventKind::Remove(_) => event.paths.iter().for_each(|p| {
-                            if let Err(e) = self.remove(p) {
-                                log::error!("{e}");
-                            }
-                        }),
-                        _ => todo!(),
-                    }
+    /// [AppError::Backend] is returned if the destination keeps no
+    /// permissions, the errors of an entry are reported as failures.
+    pub fn repair_permissions(&self) -> Result<MetadataReport, AppError> {
+        self.target.connect()?;
+        let mut report = MetadataReport::default();
+        for entry in self.scan() {
+            let Ok(meta) = fs::metadata(paths::extended(&entry)) else {
+                continue;
+            };
+            // Only the directories of the source are mirrored as they are
+            if !(meta.is_file() || meta.is_dir() && self.mirrors_dirs()) {
+                continue;
+            }
+            let dst = self.build_dest_path(&entry)?;
+            let repaired = self.target.metadata(&dst).and_then(|stored| match stored {
+                Some(stored) if stored.is_dir == meta.is_dir() => {
+                    self.target.repair_permissions(&entry, &dst, &self.permissions).map(Some)
+                }
+                _ => Ok(None),
+            });
+            match repaired {
+                Ok(Some(Some(true))) => {
+                    tracing::info!("permissions: {}", dst.display());
+                    report.updated.push(dst);
+                }
+                Ok(Some(Some(false))) => report.unchanged += 1,
+                Ok(Some(None)) => {
+                    return Err(AppError::Backend(format!(
+                        "{} keeps no permissions",
+                        self.target.describe()
+                    )))
                 }
-                Err(error) => {
-                    log::error!("Error: {error:?}")
+                Ok(None) => {
+                    tracing::warn!(
+                        "{} differs from its source, left alone",
+                        dst.display()
+                    );
+                    report.differing.push(dst);
+                }
+                Err(err) => {
+                    let err = err.context_to("repair permissions", &entry, &dst);
+                    tracing::error!("{err}");
+                    report.failures.push(Failure {
+                        operation: "permissions",
+                        path: entry,
+                        error: err.to_string(),
+                        attempts: 1,
+                    });
                 }
             }
         }
-
-        Ok(())
+        Ok(report)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{App, Config};
-    use log::{error, LevelFilter};
+    /// Destination entries `plan` overwrites or removes.
+    ///
+    /// # Errors
+    ///
+    /// Errors querying the destination are returned.
+    pub fn preview(&self, plan: &SyncPlan) -> Result<Preview, AppError> {
+        let mut preview = Preview::default();
+        for action in plan {
+            match self.destroys(action)? {
+                Some((destination, false)) => preview.overwritten.push(destination.to_path_buf()),
+                Some((destination, true)) => preview.removed.push(destination.to_path_buf()),
+                None => {}
+            }
+        }
+        Ok(preview)
+    }
 
-    fn init() {
-        let _ = env_logger::builder()
-            .filter_level(LevelFilter::Debug)
-            .is_test(true)
-            .try_init();
+    /// Whether the changes are confirmed one by one with `--interactive`
+    pub fn interactive(&self) -> bool {
+        self.prompt.is_some()
+    }
+
+    /// Compares the source with the destination without changing either,
+    /// by the size and the modification time of the files like the initial
+    /// sync.
+    ///
+    /// # Errors
+    ///
+    /// - [AppError] is returned if the destination is not reachable
+    /// - errors reading the source or querying the destination are returned
+    pub fn diff(&self) -> Result<TreeDiff, AppError> {
+        self.target.connect()?;
+        let mut diff = TreeDiff::default();
+        // Destination paths of the source entries and their parents
+        let mut stored = HashSet::new();
+        // Source directory missing at the destination, and its entry
+        let mut missing: Option<(PathBuf, usize)> = None;
+        for entry in self.scan() {
+            let meta = fs::metadata(paths::extended(&entry)).context("read metadata", &entry)?;
+            if let Some((dir, index)) = missing.as_ref().filter(|(dir, _)| entry.starts_with(dir) && entry != *dir) {
+                if meta.is_file() {
+                    *diff.entries[*index].source_bytes.get_or_insert(0) += meta.len();
+                }
+                tracing::trace!(
+                    "below {}: {}",
+                    dir.display(),
+                    entry.display()
+                );
+                continue;
+            }
+            if meta.is_dir() && !self.mirrors_dirs() {
+                continue;
+            }
+            let dst = self.build_dest_path(&entry)?;
+            stored.extend(dst.ancestors().map(Path::to_path_buf));
+            // The source root itself
+            if dst.as_os_str().is_empty() {
+                continue;
+            }
+            let difference = match self.target.metadata(&dst).context("read metadata", &dst)? {
+                None => {
+                    if meta.is_dir() {
+                        missing = Some((entry.clone(), diff.entries.len()));
+                    }
+                    DiffEntry {
+                        path: dst,
+                        difference: Difference::OnlyInSource,
+                        is_dir: meta.is_dir(),
+                        source_bytes: Some(if meta.is_file() { meta.len() } else { 0 }),
+                        destination_bytes: None,
+                    }
+                }
+                Some(stored) if meta.is_dir() && stored.is_dir => continue,
+                Some(stored)
+                    if meta.is_dir()
+                        || stored.is_dir
+                        || self
+                            .planner
+                            .plan(&Candidate {
+                                source: &entry,
+                                metadata: &meta,
+                                destination: &dst,
+                                stored: Some(&stored),
+                            })?
+                            .is_some() =>
+                {
+                    DiffEntry {
+                        path: dst,
+                        difference: Difference::Differs,
+                        is_dir: meta.is_dir(),
+                        source_bytes: meta.is_file().then_some(meta.len()),
+                        destination_bytes: (!stored.is_dir).then_some(stored.len),
+                    }
+                }
+                Some(_) => {
+                    diff.identical += 1;
+                    continue;
+                }
+            };
+            diff.entries.push(difference);
+        }
+        diff.listed = self.unstored(Path::new(""), &stored, &mut diff)?;
+        diff.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(diff)
+    }
+
+    /// Adds the entries of the destination directory `dir` which are not
+    /// `stored` for a source entry to `diff`, returns whether the
+    /// destination could be listed
+    fn unstored(&self, dir: &Path, stored: &HashSet<PathBuf>, diff: &mut TreeDiff) -> Result<bool, AppError> {
+        let Some(names) = self.target.read_dir(dir)? else {
+            return Ok(false);
+        };
+        for name in names {
+            let path = dir.join(name);
+            let Some(meta) = self.target.metadata(&path)? else {
+                continue;
+            };
+            match (stored.contains(&path), meta.is_dir) {
+                (true, true) => {
+                    self.unstored(&path, stored, diff)?;
+                }
+                (true, false) => {}
+                (false, is_dir) => {
+                    let bytes = match is_dir {
+                        true => self.stored_bytes(&path)?,
+                        false => meta.len,
+                    };
+                    diff.entries.push(DiffEntry {
+                        path,
+                        difference: Difference::OnlyInDestination,
+                        is_dir,
+                        source_bytes: None,
+                        destination_bytes: Some(bytes),
+                    });
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Bytes of the files below the destination directory `dir`
+    fn stored_bytes(&self, dir: &Path) -> Result<u64, AppError> {
+        let mut bytes = 0;
+        for name in self.target.read_dir(dir)?.unwrap_or_default() {
+            let path = dir.join(name);
+            bytes += match self.target.metadata(&path)? {
+                Some(meta) if meta.is_dir => self.stored_bytes(&path)?,
+                Some(meta) => meta.len,
+                None => 0,
+            };
+        }
+        Ok(bytes)
+    }
+
+    /// Entries of `plan` changed since `planned_at`
+    ///
+    /// # Errors
+    ///
+    /// Errors of the destination are returned.
+    fn changed_since(&self, plan: &SyncPlan, planned_at: SystemTime) -> Result<Vec<PathBuf>, AppError> {
+        let modified_after = |meta: Option<TargetMetadata>| meta.is_some_and(|meta| meta.modified > planned_at);
+        let mut changed = Vec::new();
+        for action in plan {
+            match action {
+                Action::Copy { source, destination } => {
+                    let source_changed = fs::metadata(paths::extended(source))
+                        .and_then(|meta| meta.modified())
+                        .map_or(true, |modified| modified > planned_at);
+                    if source_changed {
+                        changed.push(source.clone());
+                    }
+                    if modified_after(self.target.metadata(destination)?) {
+                        changed.push(destination.clone());
+                    }
+                }
+                Action::Mkdir { .. } => {}
+                Action::Remove { destination } => {
+                    if modified_after(self.target.metadata(destination)?) {
+                        changed.push(destination.clone());
+                    }
+                }
+                Action::Rename { from, to } => {
+                    let from_meta = self.target.metadata(from)?;
+                    if from_meta.is_none() || modified_after(from_meta) {
+                        changed.push(from.clone());
+                    }
+                    if self.target.metadata(to)?.is_some() {
+                        changed.push(to.clone());
+                    }
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    /// First run syncronisation.
+    ///
+    /// Initial scan of source directory is triggered only
+    /// at the beginning of the execution
+    /// with copying everything mismatched
+    ///
+    /// # Errors
+    ///
+    /// [AppError] whould be returned if:
+    ///
+    /// - [plan_entries](fn@App::plan_entries) or [apply_plan](fn@App::apply_plan)
+    ///   fail
+    fn initial_sync(&mut self) -> Result<(), AppError> {
+        let _span = tracing::info_span!("initial_sync", source = %self.source.display()).entered();
+        tracing::info!(
+            "Initial scan started: {:?}",
+            self.source.as_path()
+        );
+        let src_entries = self.scan();
+        self.count_quota(&src_entries);
+        #[cfg(target_os = "linux")]
+        if self.inotify && self.selection.as_ref().is_none_or(Selection::recursive) {
+            crate::watcher::inotify::check(src_entries.iter().filter(|entry| entry.is_dir()).count());
+        }
+        let plan = self.plan_entries(SyncPlan::new(), &src_entries)?;
+        if let Err(err) = self.preflight(&plan) {
+            if self.errors.initial_sync == OnError::Abort {
+                return Err(err);
+            }
+            tracing::error!("{err}");
+        }
+        let (files, bytes) = Self::copied_by(&plan);
+        self.stats.initial_sync(Some(bytes));
+        for observer in &self.observers {
+            observer.on_initial_sync(files, bytes);
+        }
+        let applied = self.apply_plan(&plan);
+        self.stats.initial_sync(None);
+        applied?;
+        // Stored before, e.g. by a run stopped before it removed them
+        if self.move_files {
+            for entry in &src_entries {
+                if let Err(err) = self.release(entry) {
+                    tracing::warn!("{err}");
+                }
+            }
+        }
+
+        tracing::info!(
+            "Initial scan finished: {:?}",
+            self.source
+        );
+        metrics::synced();
+
+        Ok(())
+    }
+
+    /// Files `plan` copies and their bytes
+    fn copied_by(plan: &SyncPlan) -> (u64, u64) {
+        plan.iter()
+            .filter_map(|action| match action {
+                Action::Copy { source, .. } => Some(fs::metadata(paths::extended(source)).map_or(0, |meta| meta.len())),
+                _ => None,
+            })
+            .fold((0, 0), |(files, bytes), len| {
+                (files + 1, bytes + len)
+            })
+    }
+
+    /// Entries of the source which are synchronised
+    fn scan(&self) -> Vec<PathBuf> {
+        let mut entries = match &self.selection {
+            Some(selection) => selection.entries(&self.source, || {
+                App::collect_dir_entries(&self.source)
+            }),
+            None => App::collect_dir_entries(self.source.as_path()),
+        };
+        entries.retain(|entry| !self.excluded(entry));
+        entries
+    }
+
+    /// `plan` with the changes bringing the destination entries of the
+    /// source `entries` up to date
+    ///
+    /// # Errors
+    ///
+    /// The error of the first entry which could not be compared is returned
+    /// if the [ErrorPolicy] of the initial sync aborts, otherwise it is
+    /// recorded as a failure.
+    fn plan_entries(&self, mut plan: SyncPlan, entries: &[PathBuf]) -> Result<SyncPlan, AppError> {
+        for entry in entries {
+            if let Err(err) = self.plan_entry(entry, &mut plan) {
+                if self.errors.initial_sync == OnError::Abort {
+                    return Err(err);
+                }
+                tracing::warn!("initial sync skips: {err}");
+                self.failed.lock().unwrap_or_else(|e| e.into_inner()).push(Failure {
+                    operation: "sync",
+                    path: entry.clone(),
+                    error: err.to_string(),
+                    attempts: 1,
+                });
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Applies the actions of `plan` in order, returns the ones applied and
+    /// skipped
+    ///
+    /// # Errors
+    ///
+    /// The error of the first failed action is returned if the
+    /// [ErrorPolicy] of the initial sync aborts, otherwise it is recorded as
+    /// a failure.
+    fn apply_plan(&self, plan: &SyncPlan) -> Result<PlanReport, AppError> {
+        for _ in 0..plan.unchanged() {
+            self.stats.skipped();
+        }
+        let mut report = PlanReport::default();
+        for action in plan {
+            if !self.confirmed(action)? {
+                tracing::info!("{action}: declined");
+                self.stats.skipped();
+                report.skipped.push(action.clone());
+                continue;
+            }
+            let recorded = self.failed.lock().unwrap_or_else(|e| e.into_inner()).len();
+            let skipped = self.stats.snapshot().skipped;
+            let result = self.apply_action(action);
+            match &result {
+                Ok(()) if self.stats.snapshot().skipped > skipped => report.skipped.push(action.clone()),
+                Ok(()) => report.applied.push(action.clone()),
+                Err(_) => {}
+            }
+            if let Err(err) = result {
+                if self.errors.initial_sync == OnError::Abort {
+                    return Err(err);
+                }
+                tracing::warn!("initial sync skips: {err}");
+                // Recorded by execute if the copy failed
+                let mut failed = self.failed.lock().unwrap_or_else(|e| e.into_inner());
+                if failed.len() == recorded {
+                    failed.push(Failure {
+                        operation: "sync",
+                        path: match action {
+                            Action::Copy { source, .. } | Action::Mkdir { source, .. } => source.clone(),
+                            Action::Remove { destination } => destination.clone(),
+                            Action::Rename { to, .. } => to.clone(),
+                        },
+                        error: err.to_string(),
+                        attempts: 1,
+                    });
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Whether `action` is applied: with `--interactive` overwriting or
+    /// removing a destination entry has to be confirmed
+    ///
+    /// # Errors
+    ///
+    /// Errors of the destination and of the prompt are returned.
+    fn confirmed(&self, action: &Action) -> Result<bool, AppError> {
+        let Some(prompt) = &self.prompt else {
+            return Ok(true);
+        };
+        let question = match self.destroys(action)? {
+            Some((destination, false)) => format!("overwrite {}", destination.display()),
+            Some((destination, true)) => format!("remove {}", destination.display()),
+            None => return Ok(true),
+        };
+        Ok(prompt.lock().unwrap_or_else(|e| e.into_inner()).confirm(&question)?)
+    }
+
+    /// Destination entry `action` overwrites, or removes if the flag is set
+    fn destroys<'a>(&self, action: &'a Action) -> Result<Option<(&'a Path, bool)>, AppError> {
+        Ok(match action {
+            Action::Copy { destination, .. } if self.target.metadata(destination)?.is_some() => Some((destination, false)),
+            Action::Remove { destination } => Some((destination, true)),
+            _ => None,
+        })
+    }
+
+    /// Applies `action`, copies and directories like the changes of the
+    /// watcher
+    fn apply_action(&self, action: &Action) -> Result<(), AppError> {
+        match action {
+            Action::Copy { source, .. } | Action::Mkdir { source, .. } => self.execute(&Operation::Copy { path: source.clone() }),
+            Action::Remove { destination } => {
+                let destination = Self::below_root(destination.clone())?;
+                self.apply_stored(
+                    action,
+                    &Operation::Remove {
+                        path: destination.clone(),
+                    },
+                    &mut || self.target.remove(&destination),
+                )
+            }
+            Action::Rename { from, to } => {
+                let (from, to) = (
+                    Self::below_root(from.clone())?,
+                    Self::below_root(to.clone())?,
+                );
+                self.apply_stored(
+                    action,
+                    &Operation::Rename {
+                        from: from.clone(),
+                        to: to.clone(),
+                    },
+                    &mut || self.target.rename(&from, &to),
+                )
+            }
+        }
+    }
+
+    /// Applies `action` on entries only the destination has, `operation`
+    /// is the same change by destination paths, and reports it like
+    /// [App::execute] does
+    fn apply_stored(
+        &self,
+        action: &Action,
+        operation: &Operation,
+        apply: &mut dyn FnMut() -> Result<(), AppError>,
+    ) -> Result<(), AppError> {
+        for observer in &self.observers {
+            observer.on_change(operation);
+        }
+        let (name, from, destination) = match operation {
+            Operation::Rename { from, to } => ("rename", Some(from.as_path()), to),
+            Operation::Copy { path } | Operation::Remove { path } => ("remove", None, path),
+        };
+        let started = Instant::now();
+        let result = self.perform(action, apply).map_err(|err| match from {
+            Some(from) => err.context_to(name, from, destination),
+            None => err.context(name, destination),
+        });
+        self.stats.busy(started.elapsed());
+        if result.is_ok() {
+            match from {
+                Some(_) => {
+                    metrics::renamed();
+                    self.stats.renamed();
+                }
+                None => {
+                    metrics::removed();
+                    self.stats.removed();
+                }
+            }
+        }
+        self.report(
+            &Outcome {
+                name,
+                operation,
+                destination,
+                from,
+                bytes: 0,
+                attempts: 1,
+                stored: true,
+            },
+            result,
+        )
+    }
+
+    /// Performs `action` through the [Executor], in flight while it runs
+    fn perform(&self, action: &Action, apply: &mut dyn FnMut() -> Result<(), AppError>) -> Result<(), AppError> {
+        let (operation, path) = match action {
+            Action::Copy { destination, .. } => ("copy", destination),
+            Action::Mkdir { destination, .. } => ("mkdir", destination),
+            Action::Remove { destination } => ("remove", destination),
+            Action::Rename { to, .. } => ("rename", to),
+        };
+        let _flight = self.stats.in_flight(operation, path.clone());
+        self.executor.execute(action, apply)
+    }
+
+    /// Checks that the destination has space for the files `plan` copies
+    /// which are missing or smaller there.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::InsufficientSpace] is returned if they do not fit.
+    fn preflight(&self, plan: &SyncPlan) -> Result<(), AppError> {
+        let Some(available) = self.target.available_space()? else {
+            return Ok(());
+        };
+        let needed = plan
+            .iter()
+            .filter_map(|action| match action {
+                Action::Copy { source, .. } => Some(source),
+                _ => None,
+            })
+            .filter_map(|entry| {
+                fs::metadata(paths::extended(entry))
+                    .ok()
+                    .filter(fs::Metadata::is_file)
+                    .map(|meta| (entry, meta.len()))
+            })
+            .map(|(entry, len)| len.saturating_sub(self.stored_len(entry)))
+            .sum();
+        Self::fits(needed, available)
+    }
+
+    /// Counts the bytes stored at the destination for the source `entries`
+    fn count_quota(&self, entries: &[PathBuf]) {
+        if let Some(quota) = &self.quota {
+            quota.set(
+                entries
+                    .iter()
+                    .filter(|entry| paths::extended(entry).is_file())
+                    .map(|entry| self.stored_len(entry))
+                    .sum(),
+            );
+        }
+    }
+
+    /// Whether the source `path` is a directory at the destination
+    fn stored_dir(&self, path: &Path) -> bool {
+        self.build_dest_path(path)
+            .ok()
+            .and_then(|dst| self.target.metadata(&dst).ok().flatten())
+            .is_some_and(|meta| meta.is_dir)
+    }
+
+    /// Size of the destination file of the source `path`, zero if missing
+    fn stored_len(&self, path: &Path) -> u64 {
+        self.build_dest_path(path)
+            .ok()
+            .and_then(|dst| self.target.metadata(&dst).ok().flatten())
+            .map_or(0, |meta| meta.len)
+    }
+
+    /// Whether `needed` bytes fit in `available`
+    ///
+    /// # Errors
+    ///
+    /// [AppError::InsufficientSpace] is returned if they do not.
+    fn fits(needed: u64, available: u64) -> Result<(), AppError> {
+        if needed > available {
+            return Err(AppError::InsufficientSpace { needed, available });
+        }
+        Ok(())
+    }
+
+    /// Rename file from destination path to the same name at the destination.
+    ///
+    /// Entries missing at the destination, e.g. created and renamed while
+    /// the destination was unreachable, are copied from the new source path
+    /// instead.
+    fn rename<P: AsRef<Path>>(&self, from: P, to: P) -> Result<(), AppError> {
+        let (source, from_source) = (to.as_ref(), from.as_ref());
+        let from = Self::below_root(self.build_dest_path(from_source)?)?;
+        // The new name may be placed where the old one was
+        self.forget(from_source);
+        let to = Self::below_root(self.build_dest_path(source)?)?;
+
+        if let Err(err) = self.target.rename(&from, &to) {
+            if self.target.metadata(&from)?.is_some() {
+                return Err(err);
+            }
+            tracing::info!(
+                "{} is missing at the destination, copying {} instead",
+                from.display(),
+                source.display()
+            );
+            return self.copy_tree(source);
+        }
+        metrics::renamed();
+        self.stats.renamed();
+        #[cfg(feature = "media")]
+        self.follow_thumbnail(&from, Some(&to));
+        // Renamed before it was moved, e.g. a scan saved under a temporary name
+        if self.move_files {
+            for entry in Self::collect_dir_entries(source) {
+                if paths::extended(&entry).is_file() && !self.excluded(&entry) && !self.release(&entry)? {
+                    self.copy(entry)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the file from source to destination
+    /// creating all necessary directories recursively
+    fn copy<P: AsRef<Path>>(&self, src: P) -> Result<(), AppError> {
+        let src = src.as_ref();
+        let dst = self.build_dest_path(src)?;
+
+        if paths::extended(src).is_dir() {
+            // Only the directories of the placed files exist
+            if !self.mirrors_dirs() {
+                return Ok(());
+            }
+            tracing::debug!("IS DIRECTORY: {src:?}");
+            self.target.create_dir_all(dst.as_path())?;
+            return Ok(());
+        }
+
+        let len = fs::metadata(paths::extended(src)).map_or(0, |meta| meta.len());
+        if self.compare == CompareMode::Checksum && self.touched(src, &dst, len)? {
+            tracing::info!("touch: {}", dst.display());
+            if self.move_files {
+                self.release(src)?;
+            }
+            return Ok(());
+        }
+        // The transformed contents are stored instead
+        let transformed = match self.transforms.is_empty() {
+            true => None,
+            false => Some(transform::apply(&self.transforms, src).context("transform", src)?),
+        };
+        let (upload, len) = match &transformed {
+            Some(transformed) => (
+                transformed.path.as_path(),
+                fs::metadata(&transformed.path).map_or(len, |meta| meta.len()),
+            ),
+            None => (src, len),
+        };
+        // Size of the copy it replaces, looked up once
+        let old = if self.quota.is_some() || len >= PREFLIGHT_SIZE {
+            self.stored_len(src)
+        } else {
+            0
+        };
+        if let Some(quota) = &self.quota {
+            quota.check(old, len)?;
+        }
+        if len >= PREFLIGHT_SIZE {
+            if let Some(available) = self.target.available_space()? {
+                Self::fits(len.saturating_sub(old), available)?;
+            }
+        }
+        let mut stored = 0;
+        self.target.upload_with_progress(upload, dst.as_path(), &mut |copied| {
+            self.stats.transferred(copied.saturating_sub(stored));
+            stored = copied;
+            for observer in &self.observers {
+                observer.on_progress(src, &dst, copied, len);
+            }
+        })?;
+        if let Some(quota) = &self.quota {
+            quota.update(old, len);
+        }
+        metrics::copied(len);
+        self.stats.copied(len);
+        #[cfg(feature = "media")]
+        self.thumbnail(src, &dst);
+        if self.move_files && !self.release(src)? {
+            tracing::warn!(
+                "kept {}: it changed while it was stored",
+                src.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether the stored file `dst` has the contents of the source file
+    /// `src` of `len` bytes, its metadata is updated instead of copying it
+    /// then
+    fn touched(&self, src: &Path, dst: &Path, len: u64) -> Result<bool, AppError> {
+        match self.target.metadata(dst)? {
+            Some(stored) if !stored.is_dir && stored.len == len => {}
+            _ => return Ok(false),
+        }
+        let Some(stored) = self.target.checksum(dst)? else {
+            return Ok(false);
+        };
+        let source = fs::File::open(paths::extended(src)).context("open", src)?;
+        if crate::compare::checksum(source).context("read", src)? != stored {
+            return Ok(false);
+        }
+        Ok(self.target.sync_metadata(src, dst)?.is_some())
+    }
+
+    /// Stores the thumbnail of the image `src` stored at `dst` in the
+    /// thumbnail directory of the `[media]` section, failures are logged
+    #[cfg(feature = "media")]
+    fn thumbnail(&self, src: &Path, dst: &Path) {
+        let Some(media) = &self.media else {
+            return;
+        };
+        let Some(dir) = &media.thumbnails else {
+            return;
+        };
+        let path = dir.join(dst);
+        let stored = crate::media::thumbnail(src, media.thumbnail_size).and_then(|thumbnail| match thumbnail {
+            Some(thumbnail) => self.target.upload(&thumbnail.path, &path).map(|()| true),
+            None => Ok(false),
+        });
+        match stored {
+            Ok(true) => tracing::info!("thumbnail: {}", path.display()),
+            Ok(false) => {}
+            Err(err) => tracing::warn!(
+                "no thumbnail of {}: {err}",
+                src.display()
+            ),
+        }
+    }
+
+    /// Removes the thumbnails of the entry stored at `dst`, or renames them
+    /// along to `to`, failures are logged
+    #[cfg(feature = "media")]
+    fn follow_thumbnail(&self, dst: &Path, to: Option<&Path>) {
+        let Some(dir) = self.media.as_ref().and_then(|media| media.thumbnails.as_ref()) else {
+            return;
+        };
+        let path = dir.join(dst);
+        let followed = self.target.metadata(&path).and_then(|stored| match (stored, to) {
+            (None, _) => Ok(()),
+            (Some(_), None) => self.target.remove(&path),
+            (Some(_), Some(to)) => self.target.rename(&path, &dir.join(to)),
+        });
+        if let Err(err) = followed {
+            tracing::warn!(
+                "thumbnail {} left behind: {err}",
+                path.display()
+            );
+        }
+    }
+
+    /// With `--move`, removes the source file `src` if the [Planner] finds
+    /// it up to date at the destination, returns whether it did
+    fn release(&self, src: &Path) -> Result<bool, AppError> {
+        let Ok(meta) = fs::metadata(paths::extended(src)) else {
+            return Ok(false);
+        };
+        if !meta.is_file() {
+            return Ok(false);
+        }
+        let dst = self.build_dest_path(src)?;
+        let stored = self.target.metadata(&dst)?;
+        let candidate = Candidate {
+            source: src,
+            metadata: &meta,
+            destination: &dst,
+            stored: stored.as_ref(),
+        };
+        if self.planner.plan(&candidate)?.is_some() {
+            return Ok(false);
+        }
+        fs::remove_file(paths::extended(src)).context("remove", src)?;
+        tracing::info!("moved: {}", src.display());
+        Ok(true)
+    }
+
+    /// Copies the entry at `src` with everything in it which is not
+    /// excluded
+    fn copy_tree(&self, src: &Path) -> Result<(), AppError> {
+        for entry in Self::collect_dir_entries(src) {
+            if !self.excluded(&entry) {
+                self.copy(entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the source entry at `path` is ignored or, with `--only`, not
+    /// a matching file
+    fn excluded(&self, path: &Path) -> bool {
+        self.ignore.as_ref().is_some_and(|ignore| ignore.ignores(path))
+            || self.git.is_some() && crate::git::in_repository(&self.source, path)
+            || self.only.as_ref().is_some_and(|only| !only.includes(path))
+            || self.recent(path)
+    }
+
+    /// Whether `path` is a file modified too recently to be archived with
+    /// `--archive-after`, one modified in the future is
+    fn recent(&self, path: &Path) -> bool {
+        self.archive_after.is_some_and(|after| {
+            fs::metadata(paths::extended(path))
+                .and_then(|meta| match meta.is_file() {
+                    true => meta.modified().map(Some),
+                    false => Ok(None),
+                })
+                .is_ok_and(|modified| modified.is_some_and(|modified| modified.elapsed().map_or(true, |age| age < after)))
+        })
+    }
+
+    /// Removes directory or file from the destination
+    /// keeping the same path as in the src parameter
+    fn remove<P: AsRef<Path>>(&self, src: P) -> Result<(), AppError> {
+        let src = src.as_ref();
+        let dst = Self::below_root(self.build_dest_path(src)?)?;
+
+        // Bytes the removal frees, none if they are not known
+        let freed = match self.quota {
+            Some(_) => match self.target.metadata(&dst)? {
+                Some(meta) if meta.is_dir => match self.target.read_dir(&dst)? {
+                    Some(_) => Some(self.stored_bytes(&dst)?),
+                    None => None,
+                },
+                meta => Some(meta.map_or(0, |meta| meta.len)),
+            },
+            None => Some(0),
+        };
+        // src doesn't exist anymore
+        self.target.remove(dst.as_path())?;
+        self.forget(src);
+        #[cfg(feature = "media")]
+        self.follow_thumbnail(&dst, None);
+        match (&self.quota, freed) {
+            (Some(quota), Some(freed)) => quota.update(freed, 0),
+            // Only the files still in the source are known
+            (Some(_), None) => self.count_quota(&Self::collect_dir_entries(&self.source)),
+            _ => {}
+        }
+        metrics::removed();
+        self.stats.removed();
+        Ok(())
+    }
+
+    /// Forgets the destination the template gave to the source entry
+    /// `source`, once it is removed or renamed
+    fn forget(&self, source: &Path) {
+        if let Some(template) = &self.template {
+            template.forget(source);
+        }
+        if let Some(renamer) = &self.renamer {
+            renamer.forget(source);
+        }
+    }
+
+    /// Rejects the destination root itself, which must not be removed or
+    /// renamed when the source root is
+    fn below_root(path: PathBuf) -> Result<PathBuf, AppError> {
+        if path.as_os_str().is_empty() {
+            return Err(AppError::PathErr(
+                "the destination root itself".into(),
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Replaces the prefix in the provided path
+    /// to create the same path relative to the destination root.
+    ///
+    /// The source prefix is matched by components, in the form reported or
+    /// with both paths made absolute, so relative and absolute forms of the
+    /// same paths match.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::PathErr] is returned for paths outside of the source and
+    /// for results leaving the destination root, e.g. through `..`.
+    fn build_dest_path<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf, AppError> {
+        let path = path.as_ref();
+        let stripped = self.relative_to_source(path).ok_or_else(|| {
+            AppError::PathErr(format!(
+                "{} is not below the source {}",
+                path.display(),
+                self.source.display()
+            ))
+        })?;
+        let stripped = match &self.selection {
+            Some(selection) => selection.destination(stripped),
+            None => stripped,
+        };
+        let stripped = match &self.template {
+            Some(template) => template.destination(path, &stripped)?,
+            None => stripped,
+        };
+        let stripped = match &self.router {
+            Some(router) => router.destination(path, stripped),
+            None => stripped,
+        };
+        let stripped = match &self.renamer {
+            Some(renamer) => renamer.destination(path, stripped)?,
+            None => stripped,
+        };
+        #[cfg(feature = "scripting")]
+        let result = match &self.script {
+            Some(script) => script.destination(path, stripped.clone()),
+            None => stripped.clone(),
+        };
+        #[cfg(not(feature = "scripting"))]
+        let result = stripped.clone();
+        let result = match self.unicode {
+            Some(form) => form.normalize(&result),
+            None => result,
+        };
+        let result = match &self.names {
+            Some(names) => names.map(&result)?,
+            None => result,
+        };
+
+        tracing::debug!(
+            "building destination:\nsource path: {}\nstripped to: {:?}\nresult: {:?}",
+            path.display(),
+            stripped,
+            result
+        );
+        if !result.components().all(|part| {
+            matches!(
+                part,
+                Component::Normal(_) | Component::CurDir
+            )
+        }) {
+            return Err(AppError::PathErr(format!(
+                "{} leaves the destination",
+                result.display()
+            )));
+        }
+        Ok(result)
+    }
+
+    /// `path` relative to the source root
+    fn relative_to_source(&self, path: &Path) -> Option<PathBuf> {
+        if let Ok(relative) = path.strip_prefix(&self.source) {
+            return Some(relative.to_path_buf());
+        }
+        // Either of them may be given in the `\\?\` form on Windows
+        let (path, source) = (
+            std::path::absolute(paths::strip_verbatim(path)).ok()?,
+            std::path::absolute(paths::strip_verbatim(&self.source)).ok()?,
+        );
+        path.strip_prefix(source).ok().map(Path::to_path_buf)
+    }
+
+    /// Adds the changes of the destination entry of the source entry `src`
+    /// to `plan` by checking the source metadata.
+    ///
+    /// The [Planner] decides for files, directories missing at the
+    /// destination are created.
+    fn plan_entry(&self, src: &Path, plan: &mut SyncPlan) -> Result<(), AppError> {
+        let src_meta = fs::metadata(paths::extended(src)).context("read metadata", src)?;
+        if src_meta.is_dir() {
+            let dst = self.build_dest_path(src)?;
+            // The source root itself
+            if self.mirrors_dirs()
+                && !dst.as_os_str().is_empty()
+                && self.target.metadata(&dst).context("read metadata", &dst)?.is_none()
+            {
+                plan.push(Action::Mkdir {
+                    source: src.to_path_buf(),
+                    destination: dst,
+                });
+            }
+            return Ok(());
+        }
+        if !src_meta.is_file() {
+            return Ok(());
+        }
+        let dst = self.build_dest_path(src)?;
+
+        let stored = self.stored_metadata(&dst, plan).context("read metadata", &dst)?;
+        match self.planner.plan(&Candidate {
+            source: src,
+            metadata: &src_meta,
+            destination: &dst,
+            stored: stored.as_ref(),
+        })? {
+            Some(action) => plan.push(action),
+            None => plan.unchanged_file(),
+        }
+        Ok(())
+    }
+
+    /// Metadata of the destination file `dst`.
+    ///
+    /// With [UnicodeForm] set a file stored under the name in the other
+    /// form is renamed to `dst` by `plan` first, so it is compared instead
+    /// of copied next to it.
+    ///
+    /// # Errors
+    ///
+    /// Errors of the destination are returned.
+    fn stored_metadata(&self, dst: &Path, plan: &mut SyncPlan) -> Result<Option<TargetMetadata>, AppError> {
+        let meta = self.target.metadata(dst)?;
+        let Some(form) = self.unicode.filter(|_| meta.is_none()) else {
+            return Ok(meta);
+        };
+        let other = form.other().normalize(dst);
+        if other == dst {
+            return Ok(None);
+        }
+        let Some(meta) = self.target.metadata(&other)? else {
+            return Ok(None);
+        };
+        tracing::info!(
+            "{} is stored in its {:?} form",
+            dst.display(),
+            form.other()
+        );
+        plan.push(Action::Rename {
+            from: other,
+            to: dst.to_path_buf(),
+        });
+        Ok(Some(meta))
+    }
+
+    /// Whether the directories of the source are created at the
+    /// destination, otherwise only the ones of the stored files are
+    fn mirrors_dirs(&self) -> bool {
+        self.selection.is_none() && self.template.is_none() && self.router.is_none() && self.only.is_none()
+    }
+
+    /// Applies a change of the source to the destination.
+    ///
+    /// Runs in an `operation` span with the source path, the size and the
+    /// duration, the outcome is logged inside of it.
+    fn execute(&self, operation: &Operation) -> Result<(), AppError> {
+        let selected;
+        let operation = match &self.selection {
+            Some(selection) => match selection.select(&self.source, operation, |path| {
+                self.stored_dir(path)
+            }) {
+                Some(operation) => {
+                    selected = operation;
+                    &selected
+                }
+                // Another entry of the watched directory
+                None => return Ok(()),
+            },
+            None => operation,
+        };
+        let included;
+        let operation = match &self.only {
+            Some(only) => match only.select(operation, |path| self.stored_dir(path)) {
+                Some(operation) => {
+                    included = operation;
+                    &included
+                }
+                None => {
+                    tracing::debug!("not included: {operation:?}");
+                    return Ok(());
+                }
+            },
+            None => operation,
+        };
+        let kept;
+        let watched = operation;
+        let operation = match &self.ignore {
+            Some(ignore) => match ignore.select(operation) {
+                Some(operation) => {
+                    kept = operation;
+                    &kept
+                }
+                None => {
+                    tracing::debug!("ignored: {operation:?}");
+                    return Ok(());
+                }
+            },
+            None => operation,
+        };
+        let outside;
+        let operation = match self.git.is_some() {
+            true => {
+                let inside = |path: &Path| crate::git::in_repository(&self.source, path);
+                outside = match operation {
+                    Operation::Copy { path } | Operation::Remove { path } if inside(path) => None,
+                    Operation::Rename { from, to } => match (inside(from), inside(to)) {
+                        (false, false) => Some(operation.clone()),
+                        (true, false) => Some(Operation::Copy { path: to.clone() }),
+                        (false, true) => Some(Operation::Remove { path: from.clone() }),
+                        (true, true) => None,
+                    },
+                    _ => Some(operation.clone()),
+                };
+                match &outside {
+                    Some(operation) => operation,
+                    None => {
+                        tracing::debug!("in a git repository: {operation:?}");
+                        return Ok(());
+                    }
+                }
+            }
+            false => operation,
+        };
+        let probe = self
+            .watchdog
+            .as_ref()
+            .filter(|watchdog| watchdog.probe)
+            .map(|_| watchdog::probe_path(&self.source));
+        if let (Some(probe), Operation::Copy { path } | Operation::Remove { path } | Operation::Rename { to: path, .. }) =
+            (probe, operation)
+        {
+            if path.starts_with(probe) {
+                return Ok(());
+            }
+        }
+        let (name, path) = match operation {
+            // Removed again before it could be copied
+            Operation::Copy { path } if !paths::extended(path).exists() => {
+                self.stats.skipped();
+                return Ok(());
+            }
+            // Moved to the destination, it stays there
+            Operation::Remove { path } if self.move_files => {
+                tracing::debug!("moved away: {}", path.display());
+                return Ok(());
+            }
+            // Archived once it is old enough
+            Operation::Copy { path } if self.recent(path) => {
+                tracing::debug!(
+                    "too recent to archive: {}",
+                    path.display()
+                );
+                return Ok(());
+            }
+            Operation::Copy { path } => ("copy", path),
+            Operation::Remove { path } => ("remove", path),
+            Operation::Rename { to, .. } => ("rename", to),
+        };
+        let bytes = match operation {
+            Operation::Copy { path } => fs::metadata(paths::extended(path)).map_or(0, |meta| {
+                if meta.is_file() {
+                    meta.len()
+                } else {
+                    0
+                }
+            }),
+            _ => 0,
+        };
+        let destination = self.build_dest_path(path)?;
+        #[cfg(feature = "scripting")]
+        if self
+            .script
+            .as_ref()
+            .is_some_and(|script| !script.filter(operation, &destination))
+        {
+            tracing::debug!(
+                "{name} {}: skipped by the script",
+                path.display()
+            );
+            self.stats.skipped();
+            return Ok(());
+        }
+        if !self.filters.allows(name, path, &destination) {
+            tracing::debug!(
+                "{name} {}: skipped by a filter",
+                path.display()
+            );
+            self.stats.skipped();
+            return Ok(());
+        }
+        if self.router.as_ref().is_some_and(|router| router.skips(path)) {
+            tracing::debug!(
+                "{name} {}: skipped by a route",
+                path.display()
+            );
+            self.stats.skipped();
+            return Ok(());
+        }
+        if self.names.as_ref().is_some_and(|names| names.skips(&destination)) {
+            tracing::warn!(
+                "{name} {}: skipped, the name is reserved by Windows",
+                path.display()
+            );
+            self.stats.skipped();
+            return Ok(());
+        }
+        for observer in &self.observers {
+            observer.on_change(operation);
+        }
+        let span = tracing::info_span!(
+            "operation",
+            operation = name,
+            path = %path.display(),
+            destination = %destination.display(),
+            bytes,
+            duration_ms = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+
+        let action = match operation {
+            Operation::Copy { path } if paths::extended(path).is_dir() => Action::Mkdir {
+                source: path.clone(),
+                destination: destination.clone(),
+            },
+            Operation::Copy { path } => Action::Copy {
+                source: path.clone(),
+                destination: destination.clone(),
+            },
+            Operation::Remove { .. } => Action::Remove {
+                destination: destination.clone(),
+            },
+            Operation::Rename { from, .. } => Action::Rename {
+                from: self.build_dest_path(from)?,
+                to: destination.clone(),
+            },
+        };
+
+        let started = Instant::now();
+        let apply = || {
+            self.perform(
+                &action,
+                &mut || match (watched, operation) {
+                    // Renamed from an ignored name, nothing of it is stored yet
+                    (Operation::Rename { .. }, Operation::Copy { path }) => self.copy_tree(path),
+                    (_, Operation::Copy { path }) => self.copy(path),
+                    (_, Operation::Remove { path }) => self.remove(path),
+                    (_, Operation::Rename { from, to }) => self.rename(from, to),
+                },
+            )
+        };
+        let (result, attempts) = match &self.retry {
+            Some(retry) => retry.run(
+                &format!("{name} {}", destination.display()),
+                apply,
+            ),
+            None => (apply(), 1),
+        };
+        // Old destination path of a rename
+        let from = match &action {
+            Action::Rename { from, .. } => Some(from.as_path()),
+            _ => None,
+        };
+        let result = result.map_err(|err| match (operation, from) {
+            (Operation::Copy { path }, _) => err.context_to(name, path, &destination),
+            (_, Some(from)) => err.context_to(name, from, &destination),
+            _ => err.context(name, &destination),
+        });
+        let elapsed = started.elapsed();
+        span.record(
+            "duration_ms",
+            elapsed.as_millis() as u64,
+        );
+        self.stats.busy(elapsed);
+        self.report(
+            &Outcome {
+                name,
+                operation,
+                destination: &destination,
+                from,
+                bytes,
+                attempts,
+                stored: false,
+            },
+            result,
+        )
+    }
+
+    /// Reports the `result` of a change to the log, the failures, the
+    /// observers, the notifications, the batch and the audit log
+    fn report(&self, outcome: &Outcome<'_>, result: Result<(), AppError>) -> Result<(), AppError> {
+        let &Outcome {
+            name,
+            operation,
+            destination,
+            from,
+            bytes,
+            attempts,
+            stored,
+        } = outcome;
+        let path = match operation {
+            Operation::Copy { path } | Operation::Remove { path } | Operation::Rename { to: path, .. } => path,
+        };
+        let message = match from {
+            Some(from) => format!(
+                "{name}: {} -> {}",
+                from.display(),
+                destination.display()
+            ),
+            None => format!("{name}: {}", destination.display()),
+        };
+        match &result {
+            Ok(()) => tracing::info!(outcome = "ok", "{message}"),
+            Err(err) => tracing::error!(outcome = "error", error = %err.root_cause(), "{err}"),
+        }
+        // An unreachable destination queues the change instead
+        let given_up = result.is_err() && self.target.connect().is_ok();
+        if let (true, Err(err)) = (given_up, &result) {
+            self.failed.lock().unwrap_or_else(|e| e.into_inner()).push(Failure {
+                operation: name,
+                path: path.clone(),
+                error: err.to_string(),
+                attempts,
+            });
+        }
+        if let (Some(retry), Err(err)) = (&self.retry, &result) {
+            if given_up && !stored {
+                if attempts > 1 {
+                    tracing::error!("giving up after {attempts} attempts: {err}");
+                }
+                if let Some(file) = &retry.dead_letter {
+                    if let Err(e) = retry::dead_letter(file, operation, attempts, err) {
+                        tracing::error!(
+                            "could not write the dead letter {}: {e}",
+                            file.display()
+                        );
+                    }
+                }
+            }
+        }
+        if let (Some(exec), Ok(()), Operation::Copy { .. } | Operation::Rename { .. }) = (&self.exec, &result, operation) {
+            if exec.per_path() && !stored && paths::extended(path).is_file() {
+                if let Err(err) = exec.path(path) {
+                    tracing::warn!("{err}");
+                }
+            }
+        }
+        #[cfg(feature = "webhooks")]
+        if let Some(webhooks) = &self.webhooks {
+            let mut details = crate::webhooks::Details {
+                message: message.clone(),
+                path: Some(destination),
+                source: &self.source.to_string_lossy(),
+                destination: &self.target.describe(),
+                ..Default::default()
+            };
+            match (operation, &result) {
+                (Operation::Copy { path }, Ok(())) if paths::extended(path).is_file() => webhooks.synced(&details),
+                (_, Err(err)) => {
+                    details.message = err.to_string();
+                    webhooks.failed(&mut details);
+                }
+                _ => {}
+            }
+        }
+        #[cfg(feature = "email")]
+        if let (Some(email), Err(err)) = (&self.email, &result) {
+            email.failed(format!("{} {err}", AuditLog::now()));
+        }
+        #[cfg(feature = "desktop")]
+        if let (Some(desktop), Err(err)) = (&self.desktop, &result) {
+            desktop.error(&err.to_string());
+        }
+        for observer in &self.observers {
+            match (operation, &result) {
+                (_, Err(err)) => observer.on_error(name, path, err),
+                (Operation::Copy { path }, Ok(())) => observer.on_copy(path, destination, bytes),
+                (Operation::Remove { path }, Ok(())) => observer.on_remove(path, destination),
+                (Operation::Rename { from, to }, Ok(())) => observer.on_rename(from, to, destination),
+            }
+        }
+        if self.batching() {
+            let mut batch = self.batch.lock().unwrap_or_else(|e| e.into_inner());
+            match (operation, &result) {
+                (_, Err(_)) => batch.errors += 1,
+                (Operation::Copy { .. }, Ok(())) => batch.copied += 1,
+                (Operation::Remove { .. }, Ok(())) => batch.removed += 1,
+                (Operation::Rename { .. }, Ok(())) => batch.renamed += 1,
+            }
+            if result.is_ok() {
+                batch.paths.push(destination.to_path_buf());
+            }
+        }
+        if let Some(audit) = &self.audit {
+            let (from, path) = match operation {
+                Operation::Rename { from, to } => (Some(from.as_path()), to),
+                Operation::Copy { path } | Operation::Remove { path } => (None, path),
+            };
+            let sha256 = match operation {
+                Operation::Copy { path } if result.is_ok() && paths::extended(path).is_file() => {
+                    AuditLog::sha256(&paths::extended(path)).ok()
+                }
+                _ => None,
+            };
+            let record = Record {
+                timestamp: AuditLog::now(),
+                operation: name,
+                path,
+                from,
+                destination,
+                bytes,
+                sha256,
+                outcome: if result.is_ok() { "ok" } else { "error" },
+                error: result.as_ref().err().map(ToString::to_string),
+            };
+            let mut audit = audit.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(err) = audit.append(&record) {
+                tracing::error!("could not write the audit record: {err}");
+            }
+        }
+        result.inspect_err(|err| {
+            metrics::error(err);
+            self.stats.error(err);
+        })
+    }
+
+    /// Applies `operation` or queues it if the destination is not reachable.
+    ///
+    /// Once something is queued, later changes are queued too, so they are
+    /// replayed in the order they happened.
+    fn submit(&self, queue: &mut OfflineQueue, operation: Operation) {
+        if queue.is_empty() {
+            match self.execute(&operation) {
+                Ok(()) => {
+                    *self.failures.lock().unwrap_or_else(|e| e.into_inner()) = (0, None);
+                    return;
+                }
+                // Logged by execute
+                Err(err) if self.target.connect().is_ok() => {
+                    let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+                    *failures = (failures.0 + 1, Some(err));
+                    return;
+                }
+                Err(err) => tracing::warn!("destination is not reachable, queueing changes: {err}"),
+            }
+        }
+        if let Err(err) = queue.push(operation) {
+            tracing::error!("could not queue the change: {err}");
+        }
+    }
+
+    /// Applies the queued operations in order while the destination is reachable
+    fn replay(&self, queue: &mut OfflineQueue) {
+        if let Err(err) = self.target.connect() {
+            return tracing::debug!(
+                "destination still not reachable, {} changes queued: {err}",
+                queue.len()
+            );
+        }
+        tracing::info!(
+            "replaying {} queued changes",
+            queue.len()
+        );
+        while let Some(operation) = queue.front() {
+            if let Err(err) = self.execute(operation) {
+                if self.target.connect().is_err() {
+                    tracing::warn!("destination is not reachable again: {err}");
+                    break;
+                }
+            }
+            queue.pop();
+        }
+        if let Err(err) = queue.persist() {
+            tracing::error!("could not update the queue journal: {err}");
+        }
+    }
+
+    /// Recursive walkthrough all directories and collect them.
+    fn collect_dir_entries<P: AsRef<Path>>(path: P) -> Vec<PathBuf> {
+        walkdir::WalkDir::new(path)
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry.into_path()),
+                Err(err) => {
+                    tracing::warn!("{err}");
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+    }
+
+    /// Watcher method.
+    ///
+    /// The changes come from the [EventSource], the watcher of the platform
+    /// unless one was set. Their paths are [normalized](EventPaths) to the
+    /// watched path first, copies are held briefly to [coalesce](Coalescer)
+    /// atomic saves.
+    ///
+    /// While the destination is unavailable the changes are queued and
+    /// replayed every [RETRY_INTERVAL] until it is reachable again.
+    fn watch<P: AsRef<Path>>(&self, path: P) -> Result<(), AppError> {
+        let mut watching = self.start_watch(path.as_ref())?;
+        while self.watch_step(&mut watching, RESCAN_INTERVAL) {}
+        self.end_watch(watching);
+        Ok(())
+    }
+
+    /// Watches the sources of this app and of its [pairs](App::add_pair),
+    /// each on a thread of its own so a slow destination doesn't hold up
+    /// the others, until every watcher stopped. A pair whose watch fails
+    /// is logged and left out, the error of the watch of this app is
+    /// returned.
+    fn watch_pairs(&self) -> Result<(), AppError> {
+        std::thread::scope(|scope| {
+            for pair in self.pairs.iter().filter(|pair| pair.stats.current_phase() != Phase::Stopped) {
+                scope.spawn(move || {
+                    if let Err(error) = pair.watch_source() {
+                        tracing::error!("{}: {error}", pair.target.describe());
+                        pair.stats.failed(&error);
+                    }
+                });
+            }
+            self.watch_source()
+        })
+    }
+
+    /// Starts watching `path`
+    ///
+    /// # Errors
+    ///
+    /// Errors starting the [EventSource] or opening the queue are returned.
+    fn start_watch(&self, path: &Path) -> Result<Watching<'_>, AppError> {
+        let mut source = self.event_source.lock().unwrap_or_else(|e| e.into_inner());
+        // Add a path to be watched. All files and directories at that path and
+        // below will be monitored for changes.
+        let recursive = self.selection.as_ref().is_none_or(Selection::recursive);
+        source.start(path, recursive)?;
+
+        tracing::info!("watch started: {:?}", path);
+        self.stats.phase(Phase::Watching);
+        #[cfg(feature = "webhooks")]
+        let alert = self.notifier(crate::webhooks::Webhooks::stale);
+        #[cfg(not(feature = "webhooks"))]
+        let alert = |_: &str| {};
+        let watchdog = self
+            .watchdog
+            .as_ref()
+            .map(|config| Watchdog::start(config, path, self.stats.clone(), alert));
+        let queue = OfflineQueue::open(self.queue_file.clone())?;
+        self.stats.queued(queue.len());
+        Ok(Watching {
+            source,
+            queue,
+            last_attempt: Instant::now(),
+            last_archive: Instant::now(),
+            event_paths: EventPaths::new(path),
+            coalescer: Coalescer::default(),
+            _watchdog: watchdog,
+        })
+    }
+
+    /// Waits up to `wait` for changes and applies the ones which are due,
+    /// returns whether watching goes on
+    fn watch_step(&self, watching: &mut Watching<'_>, wait: Duration) -> bool {
+        let Watching {
+            source,
+            queue,
+            last_attempt,
+            last_archive,
+            event_paths,
+            coalescer,
+            ..
+        } = watching;
+        if self.stop_requested.load(Ordering::Relaxed) {
+            return false;
+        }
+        for operation in coalescer.due(Instant::now()) {
+            self.submit(queue, operation);
+        }
+        // Files age past `--archive-after` without a change
+        if self
+            .archive_after
+            .is_some_and(|after| last_archive.elapsed() >= after.clamp(MIN_ARCHIVE_INTERVAL, ARCHIVE_INTERVAL))
+        {
+            self.rescan_requested.store(true, Ordering::Relaxed);
+            *last_archive = Instant::now();
+        }
+        if self.rescan_requested.swap(false, Ordering::Relaxed) {
+            if let Err(err) = self.rescan() {
+                tracing::error!("rescan failed: {err}");
+                self.stats.failed(&err);
+            }
+        }
+        if !queue.is_empty() && last_attempt.elapsed() >= RETRY_INTERVAL {
+            self.replay(queue);
+            self.stats.queued(queue.len());
+            *last_attempt = Instant::now();
+        }
+        if queue.is_empty() && coalescer.next_due(Instant::now()).is_none() {
+            self.stats.caught_up();
+        }
+        let batch_pending = self.batching() && !self.batch.lock().unwrap_or_else(|e| e.into_inner()).is_empty();
+        let timeout = if batch_pending { hooks::SETTLE_TIME } else { RESCAN_INTERVAL };
+        let timeout = coalescer
+            .next_due(Instant::now())
+            .map_or(timeout, |due| due.min(timeout))
+            .min(wait);
+        let operations = match source.poll(timeout) {
+            Ok(Poll::Changes(operations)) => {
+                self.stats.event();
+                for mirror in self.mirrors.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+                    let _ = mirror.send(operations.clone());
+                }
+                operations
+            }
+            Ok(Poll::Idle) => {
+                if batch_pending && coalescer.next_due(Instant::now()).is_none() {
+                    self.finish_batch();
+                }
+                return true;
+            }
+            Ok(Poll::Rescan(dir)) => {
+                if let Err(err) = self.rescan_directory(&event_paths.normalize(dir)) {
+                    tracing::error!("rescan failed: {err}");
+                    self.stats.failed(&err);
+                }
+                return true;
+            }
+            Ok(Poll::Closed) => return false,
+            Err(error) => {
+                tracing::error!("Error: {error:?}");
+                self.stats.failed(&error);
+                Vec::new()
+            }
+        };
+        for operation in operations {
+            for operation in coalescer.push(
+                event_paths.operation(operation),
+                Instant::now(),
+            ) {
+                self.submit(queue, operation);
+            }
+        }
+        self.stats.queued(queue.len());
+        !self.too_many_errors()
+    }
+
+    /// Applies the copies still held once watching stopped, the
+    /// destinations fanned out to stop as well
+    fn end_watch(&self, mut watching: Watching<'_>) {
+        self.mirrors.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        for operation in watching.coalescer.release() {
+            self.submit(&mut watching.queue, operation);
+        }
+    }
+
+    /// Whether the watch has to stop because of
+    /// [ErrorPolicy::max_consecutive] failed changes in a row
+    fn too_many_errors(&self) -> bool {
+        self.too_many_errors_after(self.failures.lock().unwrap_or_else(|e| e.into_inner()).0)
+    }
+
+    /// Whether `errors` failed changes in a row stop the watch
+    fn too_many_errors_after(&self, errors: u32) -> bool {
+        self.errors.max_consecutive.is_some_and(|max| errors >= max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesystem::{Fs, MemFs},
+        App, Config,
+    };
+    use tracing::Level;
+
+    fn init() {
+        let _ = tracing_subscriber::fmt()
+            .with_max_level(Level::DEBUG)
+            .with_test_writer()
+            .try_init();
+    }
+
+    #[test]
+    fn non_existing_path() {
+        init();
+
+        let memory = MemFs::new();
+        memory.create_dir_all("/test2".as_ref()).unwrap();
+        let mut app = App::new(Config::build(
+            "./test".into(),
+            "/test2".into(),
+        ))
+        .unwrap();
+        app.set_target(crate::target::LocalTarget::with_fs(
+            "/test2".into(),
+            memory.clone(),
+        ));
+        let (_injector, events) = crate::watcher::injector();
+        app.set_event_source(events);
+
+        let err = app.run().unwrap_err();
+        assert!(
+            err.to_string().contains("test"),
+            "{err}"
+        );
+        assert_eq!(
+            memory.paths(),
+            [PathBuf::from("/test2")]
+        );
+    }
+
+    #[test]
+    fn applies_injected_changes() {
+        init();
+        let source = std::env::temp_dir().join(format!(
+            "fsync-injected-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("docs/a.txt"), "a").unwrap();
+
+        let memory = MemFs::new();
+        memory.create_dir_all("/dst".as_ref()).unwrap();
+        let mut app = App::new(Config::build(
+            source.clone(),
+            "/dst".into(),
+        ))
+        .unwrap();
+        app.set_target(crate::target::LocalTarget::with_fs(
+            "/dst".into(),
+            memory.clone(),
+        ));
+        let (injector, events) = crate::watcher::injector();
+        app.set_event_source(events);
+        app.sync_once().unwrap();
+        assert!(memory.read("/dst/docs/a.txt").is_some());
+
+        fs::write(source.join("b.txt"), "b").unwrap();
+        injector.copy(source.join("b.txt"));
+        fs::rename(
+            source.join("docs"),
+            source.join("notes"),
+        )
+        .unwrap();
+        injector.rename(
+            source.join("docs"),
+            source.join("notes"),
+        );
+        fs::remove_file(source.join("b.txt")).unwrap();
+        injector.remove(source.join("b.txt"));
+        drop(injector);
+        app.watch_source().unwrap();
+        assert_eq!(
+            memory.paths(),
+            [
+                PathBuf::from("/dst"),
+                PathBuf::from("/dst/notes"),
+                PathBuf::from("/dst/notes/a.txt")
+            ]
+        );
+        let stats = app.handle().stats();
+        assert_eq!((stats.renamed, stats.errors), (1, 0));
+        fs::remove_dir_all(source).unwrap();
+    }
+
+    /// Observer counting the copies and batches
+    #[derive(Default)]
+    struct Counting {
+        /// Destination paths of the copies
+        copies: Mutex<Vec<PathBuf>>,
+        /// Copies reported by the completed batches
+        batches: Mutex<Vec<usize>>,
+        /// Progress reports, copied and total bytes
+        progress: Mutex<Vec<(u64, u64)>>,
+        /// Destination paths of the removals
+        removed: Mutex<Vec<PathBuf>>,
+    }
+
+    impl SyncObserver for Arc<Counting> {
+        fn on_copy(&self, _source: &Path, destination: &Path, _bytes: u64) {
+            self.copies.lock().unwrap().push(destination.to_path_buf());
+        }
+
+        fn on_progress(&self, _source: &Path, _destination: &Path, copied: u64, total: u64) {
+            self.progress.lock().unwrap().push((copied, total));
+        }
+
+        fn on_remove(&self, _source: &Path, destination: &Path) {
+            self.removed.lock().unwrap().push(destination.to_path_buf());
+        }
+
+        fn on_batch_complete(&self, batch: &Batch) {
+            self.batches.lock().unwrap().push(batch.copied);
+        }
+    }
+
+    #[test]
+    fn errors_name_the_operation_and_paths() {
+        use std::error::Error as _;
+
+        let err = AppError::from(std::io::Error::from(
+            std::io::ErrorKind::PermissionDenied,
+        ))
+        .context("create", "/dst/a.txt")
+        .context_to("copy", "/src/a.txt", "a.txt");
+        assert_eq!(
+            err.to_string(),
+            "copy /src/a.txt -> a.txt: create /dst/a.txt: IO: permission denied"
+        );
+        let io = err.source().and_then(|create| create.source()).unwrap();
+        assert_eq!(io.to_string(), "IO: permission denied");
+        assert!(matches!(
+            err.root_cause(),
+            AppError::IoError(_)
+        ));
+    }
+
+    #[test]
+    fn observers_follow_the_initial_sync() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-observer-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(source.join("a"), "a").unwrap();
+
+        let observer = Arc::new(Counting::default());
+        let mut app = App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap();
+        app.add_observer(observer.clone());
+        app.sync_once().unwrap();
+        assert_eq!(
+            *observer.copies.lock().unwrap(),
+            [PathBuf::from("a")]
+        );
+        assert_eq!(
+            *observer.progress.lock().unwrap(),
+            [(1, 1)]
+        );
+        assert_eq!(*observer.batches.lock().unwrap(), [1]);
+        // Entries only the destination has are reported too
+        fs::write(destination.join("stale"), "stale").unwrap();
+        app.rescan_directory(&source).unwrap();
+        assert_eq!(
+            *observer.removed.lock().unwrap(),
+            [PathBuf::from("stale")]
+        );
+        assert_eq!(app.handle().stats().removed, 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn streams_the_events() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-events-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(source.join("a"), "a").unwrap();
+
+        let mut app = App::new(Config::build(
+            source.clone(),
+            destination,
+        ))
+        .unwrap();
+        let events = app.events();
+        app.sync_once().unwrap();
+        fs::remove_file(source.join("a")).unwrap();
+        let (injector, changes) = crate::watcher::injector();
+        injector.remove(source.join("a"));
+        drop(injector);
+        app.set_event_source(changes);
+        app.watch_source().unwrap();
+        drop(app);
+        let events: Vec<_> = events
+            .into_iter()
+            .map(|event| match event {
+                SyncEvent::Changed(Operation::Copy { .. }) => "changed copy",
+                SyncEvent::Changed(Operation::Remove { .. }) => "changed remove",
+                SyncEvent::InitialSync { files: 1, bytes: 1 } => "initial sync",
+                SyncEvent::Progress { .. } => "progress",
+                SyncEvent::Copied { .. } => "copied",
+                SyncEvent::Removed { .. } => "removed",
+                SyncEvent::BatchComplete(_) => "batch",
+                _ => "other",
+            })
+            .collect();
+        assert_eq!(
+            events[..7],
+            [
+                "initial sync",
+                "changed copy",
+                "progress",
+                "copied",
+                "batch",
+                "changed remove",
+                "removed"
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
     }
 
     #[test]
-    fn non_existing_path() {
+    fn syncs_to_memory() {
         init();
+        let source = std::env::temp_dir().join(format!(
+            "fsync-memfs-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("docs/a.txt"), "a").unwrap();
 
-        error!("tests not implemented");
+        let memory = MemFs::new();
+        memory.write("/dst/old.txt", "old");
+        let mut app = App::new(Config::build(
+            source.clone(),
+            "/dst".into(),
+        ))
+        .unwrap();
+        app.set_target(crate::target::LocalTarget::with_fs(
+            "/dst".into(),
+            memory.clone(),
+        ));
+        app.sync_once().unwrap();
+        assert_eq!(
+            memory.read("/dst/docs/a.txt").as_deref(),
+            Some(&b"a"[..])
+        );
+        assert_eq!(
+            app.diff().unwrap().to_string().lines().next(),
+            Some("- old.txt")
+        );
+        fs::remove_dir_all(source).unwrap();
+    }
 
-        let mut _app = App::new(Config::build(
-            "./test".into(),
-            "./test2".into(),
+    #[test]
+    fn rescans_while_watching() {
+        init();
+        let source = std::env::temp_dir().join(format!(
+            "fsync-rescan-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a.txt"), "a").unwrap();
+
+        let memory = MemFs::new();
+        memory.create_dir_all("/dst".as_ref()).unwrap();
+        let mut app = App::new(Config::build(
+            source.clone(),
+            "/dst".into(),
+        ))
+        .unwrap();
+        app.set_target(crate::target::LocalTarget::with_fs(
+            "/dst".into(),
+            memory.clone(),
+        ));
+        app.sync_once().unwrap();
+        // Missed by the watcher
+        fs::write(source.join("b.txt"), "b").unwrap();
+        let (injector, events) = crate::watcher::injector();
+        drop(injector);
+        app.set_event_source(events);
+        app.handle().rescan();
+        app.watch_source().unwrap();
+        assert_eq!(
+            memory.read("/dst/b.txt").as_deref(),
+            Some(&b"b"[..])
+        );
+        assert!(app.rescan().unwrap().applied.is_empty());
+        fs::remove_dir_all(source).unwrap();
+    }
+
+    #[test]
+    fn rescans_a_directory() {
+        init();
+        let source = std::env::temp_dir().join(format!(
+            "fsync-rescan-dir-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(source.join("dir/sub")).unwrap();
+        fs::write(source.join("dir/a.txt"), "a").unwrap();
+        fs::write(source.join("dir/sub/b.txt"), "b").unwrap();
+        fs::write(source.join("c.txt"), "c").unwrap();
+
+        let memory = MemFs::new();
+        memory.create_dir_all("/dst".as_ref()).unwrap();
+        let mut app = App::new(Config::build(
+            source.clone(),
+            "/dst".into(),
+        ))
+        .unwrap();
+        app.set_target(crate::target::LocalTarget::with_fs(
+            "/dst".into(),
+            memory.clone(),
+        ));
+        app.sync_once().unwrap();
+        // Only the directory is reported
+        fs::write(source.join("dir/a.txt"), "changed").unwrap();
+        fs::remove_file(source.join("dir/sub/b.txt")).unwrap();
+        fs::remove_file(source.join("c.txt")).unwrap();
+        app.rescan_directory(&source.join("dir")).unwrap();
+        assert_eq!(
+            memory.read("/dst/dir/a.txt").as_deref(),
+            Some(&b"changed"[..])
+        );
+        assert!(memory.read("/dst/dir/sub/b.txt").is_none());
+        assert!(memory.read("/dst/c.txt").is_some());
+        fs::remove_dir_all(source.join("dir")).unwrap();
+        app.rescan_directory(&source.join("dir")).unwrap();
+        assert!(memory.read("/dst/dir/a.txt").is_none());
+        fs::remove_dir_all(source).unwrap();
+    }
+
+    #[test]
+    fn watches_several_pairs() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-pairs-{}",
+            std::process::id()
+        ));
+        let (first, second) = (root.join("first"), root.join("second"));
+        fs::create_dir_all(&first).unwrap();
+        fs::create_dir_all(&second).unwrap();
+        fs::write(first.join("a.txt"), "a").unwrap();
+        fs::write(second.join("b.txt"), "b").unwrap();
+
+        let memory = MemFs::new();
+        memory.create_dir_all("/one".as_ref()).unwrap();
+        memory.create_dir_all("/two".as_ref()).unwrap();
+        let mut app = App::new(Config::build(first, "/one".into())).unwrap();
+        app.add_pair(Config::build(
+            second.clone(),
+            "/two".into(),
+        ))
+        .unwrap();
+        app.set_target(crate::target::LocalTarget::with_fs(
+            "/one".into(),
+            memory.clone(),
+        ));
+        app.pairs[0].set_target(crate::target::LocalTarget::with_fs(
+            "/two".into(),
+            memory.clone(),
+        ));
+        let (one, events) = crate::watcher::injector();
+        app.set_event_source(events);
+        let (two, events) = crate::watcher::injector();
+        app.pairs[0].set_event_source(events);
+        fs::write(second.join("c.txt"), "c").unwrap();
+        two.copy(second.join("c.txt"));
+        drop((one, two));
+        app.run().unwrap();
+        for (path, contents) in [("/one/a.txt", "a"), ("/two/b.txt", "b"), ("/two/c.txt", "c")] {
+            assert_eq!(
+                memory.read(path).as_deref(),
+                Some(contents.as_bytes()),
+                "{path}"
+            );
+        }
+        assert_eq!(
+            app.pairs()[0].handle().phase(),
+            Phase::Stopped
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn removed_directories_free_their_bytes() {
+        init();
+        let source = crate::testing::TempDir::new("quota-remove");
+        let memory = MemFs::new();
+        memory.write("/dst/dir/a.txt", [0; 60]);
+        memory.write("/dst/dir/sub/b.txt", [0; 20]);
+        memory.write("/dst/c.txt", [0; 40]);
+        let mut app = App::new(Config::build(
+            source.to_path_buf(),
+            "/dst".into(),
+        ))
+        .unwrap();
+        app.set_target(crate::target::LocalTarget::with_fs(
+            "/dst".into(),
+            memory.clone(),
+        ));
+        let quota = Quota::new(
+            &crate::QuotaConfig { max_bytes: 100 },
+            |_| {},
+        );
+        quota.set(120);
+        app.quota = Some(quota);
+
+        app.remove(source.join("dir")).unwrap();
+        assert!(memory.read("/dst/dir/a.txt").is_none());
+        // c.txt is left of the stored bytes
+        let quota = app.quota.as_ref().unwrap();
+        quota.check(0, 60).unwrap();
+        assert!(quota.check(0, 61).is_err());
+    }
+
+    #[test]
+    fn pairs_failing_to_watch_are_left_out() {
+        /// [EventSource] which can not be started
+        struct Broken;
+
+        impl EventSource for Broken {
+            fn start(&mut self, _: &Path, _: bool) -> Result<(), AppError> {
+                Err(AppError::Backend("no watcher".into()))
+            }
+
+            fn poll(&mut self, _: Duration) -> Result<crate::watcher::Poll, AppError> {
+                Ok(crate::watcher::Poll::Closed)
+            }
+        }
+
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-broken-pair-{}",
+            std::process::id()
+        ));
+        let (first, second) = (root.join("first"), root.join("second"));
+        fs::create_dir_all(&first).unwrap();
+        fs::create_dir_all(&second).unwrap();
+
+        let memory = MemFs::new();
+        memory.create_dir_all("/one".as_ref()).unwrap();
+        memory.create_dir_all("/two".as_ref()).unwrap();
+        let mut app = App::new(Config::build(
+            first.clone(),
+            "/one".into(),
+        ))
+        .unwrap();
+        app.add_pair(Config::build(second, "/two".into())).unwrap();
+        app.set_target(crate::target::LocalTarget::with_fs(
+            "/one".into(),
+            memory.clone(),
+        ));
+        app.pairs[0].set_target(crate::target::LocalTarget::with_fs(
+            "/two".into(),
+            memory.clone(),
+        ));
+        let (one, events) = crate::watcher::injector();
+        app.set_event_source(events);
+        app.pairs[0].set_event_source(Broken);
+        fs::write(first.join("a.txt"), "a").unwrap();
+        one.copy(first.join("a.txt"));
+        drop(one);
+        app.run().unwrap();
+        assert_eq!(
+            memory.read("/one/a.txt").as_deref(),
+            Some(&b"a"[..])
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn fans_out_to_several_destinations() {
+        init();
+        let source = std::env::temp_dir().join(format!(
+            "fsync-fan-out-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a.txt"), "a").unwrap();
+
+        let memory = MemFs::new();
+        memory.create_dir_all("/one".as_ref()).unwrap();
+        memory.create_dir_all("/two".as_ref()).unwrap();
+        let mut config = Config::build(source.clone(), "/one".into());
+        config.destinations = vec!["/missing".into(), "/two".into()];
+        let mut app = App::new(config).unwrap();
+        let target = |root: &str| crate::target::LocalTarget::with_fs(root.into(), memory.clone());
+        app.set_target(target("/one"));
+        app.pairs[0].set_target(target("/missing"));
+        app.pairs[1].set_target(target("/two"));
+        let (injector, events) = crate::watcher::injector();
+        app.set_event_source(events);
+        fs::write(source.join("c.txt"), "c").unwrap();
+        injector.copy(source.join("c.txt"));
+        drop(injector);
+        // The unreachable destination stalls neither of the others
+        app.run().unwrap();
+        for path in ["/one/a.txt", "/one/c.txt", "/two/a.txt", "/two/c.txt"] {
+            assert!(memory.read(path).is_some(), "{path}");
+        }
+        assert!(app.pairs()[0].handle().last_error().is_some());
+        fs::remove_dir_all(source).unwrap();
+    }
+
+    #[test]
+    fn plans_before_applying() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-plan-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(source.join("a")).unwrap();
+        fs::create_dir_all(source.join("empty")).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(source.join("a/b.txt"), "b").unwrap();
+
+        let app = App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap();
+        let mut plan = app.plan().unwrap();
+        let mut actions: Vec<_> = plan.iter().map(ToString::to_string).collect();
+        actions.sort();
+        assert_eq!(
+            actions,
+            ["copy a/b.txt", "mkdir a", "mkdir empty"]
+        );
+        // Nothing changed yet
+        assert!(!destination.join("a").exists());
+
+        plan.retain(|action| !matches!(action, Action::Mkdir { destination, .. } if destination.ends_with("empty")));
+        let report = app.apply(&plan).unwrap();
+        assert_eq!(report.applied.len(), 2);
+        assert!(report.failures.is_empty());
+        assert_eq!(
+            fs::read_to_string(destination.join("a/b.txt")).unwrap(),
+            "b"
+        );
+        assert!(!destination.join("empty").exists());
+        let plan = app.plan().unwrap();
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan.unchanged(), 1);
+
+        // Changed after it was planned
+        fs::write(source.join("c.txt"), "c").unwrap();
+        let plan = app.plan().unwrap();
+        fs::File::options()
+            .write(true)
+            .open(source.join("c.txt"))
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+        assert!(matches!(
+            app.apply(&plan),
+            Err(AppError::StalePlan { changed }) if changed == [source.join("c.txt")]
+        ));
+        assert!(!destination.join("c.txt").exists());
+        fs::create_dir_all(root.join("other")).unwrap();
+        let other = App::new(Config::build(
+            source.clone(),
+            root.join("other"),
+        ))
+        .unwrap();
+        assert!(matches!(
+            other.apply(&plan),
+            Err(AppError::PathErr(_))
+        ));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Planner leaving every file as it is
+    struct Never;
+
+    impl Planner for Never {
+        fn plan(&self, _candidate: &Candidate<'_>) -> Result<Option<Action>, AppError> {
+            Ok(None)
+        }
+    }
+
+    /// Executor recording the actions it applies
+    struct Recording(Arc<Mutex<Vec<Action>>>);
+
+    impl Executor for Recording {
+        fn execute(&self, action: &Action, apply: &mut dyn FnMut() -> Result<(), AppError>) -> Result<(), AppError> {
+            self.0.lock().unwrap().push(action.clone());
+            apply()
+        }
+    }
+
+    #[test]
+    fn embedders_swap_the_layers() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-layers-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(source.join("a.txt"), "a").unwrap();
+        fs::write(source.join("b.txt"), "b").unwrap();
+
+        let mut app = App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap();
+        let actions = Arc::default();
+        let (injector, events) = crate::watcher::injector();
+        injector.copy(source.join("b.txt"));
+        drop(injector);
+        app.set_event_source(events);
+        app.set_planner(Never);
+        app.set_executor(Recording(Arc::clone(&actions)));
+        app.sync_once().unwrap();
+        app.watch_source().unwrap();
+        assert!(!destination.join("a.txt").exists());
+        assert_eq!(
+            fs::read_to_string(destination.join("b.txt")).unwrap(),
+            "b"
+        );
+        assert_eq!(
+            *actions.lock().unwrap(),
+            [Action::Copy {
+                source: source.join("b.txt"),
+                destination: "b.txt".into(),
+            }]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn interactive_overwrites_are_confirmed() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-interactive-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(source.join(name), "new").unwrap();
+        }
+        for name in ["a.txt", "b.txt"] {
+            fs::write(destination.join(name), "older").unwrap();
+        }
+
+        let mut config = Config::build(source.clone(), destination.clone());
+        config.interactive = true;
+        let mut app = App::new(config).unwrap();
+        app.prompt = Some(Mutex::new(Prompt::new(
+            std::io::Cursor::new("skip all\n"),
+            std::io::sink(),
+        )));
+        let plan = app.plan().unwrap();
+        let mut overwritten = app.preview(&plan).unwrap().overwritten;
+        overwritten.sort();
+        assert_eq!(
+            overwritten,
+            [PathBuf::from("a.txt"), PathBuf::from("b.txt")]
+        );
+        let report = app.apply(&plan).unwrap();
+        assert_eq!(report.skipped.len(), 2);
+        assert_eq!(report.applied.len(), 1);
+        for name in ["a.txt", "b.txt"] {
+            assert_eq!(
+                fs::read_to_string(destination.join(name)).unwrap(),
+                "older"
+            );
+        }
+        assert_eq!(
+            fs::read_to_string(destination.join("c.txt")).unwrap(),
+            "new"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn diffs_source_and_destination() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-diff-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(source.join("new/deep")).unwrap();
+        fs::create_dir_all(destination.join("old")).unwrap();
+        fs::write(source.join("new/a.txt"), "aa").unwrap();
+        fs::write(source.join("new/deep/b.txt"), "b").unwrap();
+        fs::write(source.join("same.txt"), "same").unwrap();
+        fs::write(source.join("changed.txt"), "new").unwrap();
+        fs::write(destination.join("changed.txt"), "older").unwrap();
+        fs::write(destination.join("old/gone.txt"), "gone").unwrap();
+        fs::copy(
+            source.join("same.txt"),
+            destination.join("same.txt"),
+        )
+        .unwrap();
+        let modified = fs::metadata(source.join("same.txt")).unwrap().modified().unwrap();
+        fs::File::options()
+            .write(true)
+            .open(destination.join("same.txt"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let app = App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap();
+        let diff = app.diff().unwrap();
+        assert!(diff.listed);
+        assert_eq!(diff.identical, 1);
+        assert_eq!(
+            diff.to_string().lines().take(3).collect::<Vec<_>>(),
+            ["~ changed.txt", "+ new/", "- old/"]
+        );
+        assert_eq!(diff.entries[1].source_bytes, Some(3));
+        assert_eq!(
+            diff.entries[2].destination_bytes,
+            Some(4)
+        );
+        // Nothing changed
+        assert!(!destination.join("new").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn dest_paths_follow_the_source_components() {
+        init();
+        let dest = |source: &str, path: &str| {
+            App::new(Config::build(
+                source.into(),
+                "/dst".into(),
+            ))
+            .unwrap()
+            .build_dest_path(path)
+            .ok()
+        };
+        let cwd = std::env::current_dir().unwrap();
+        let some = |path: &str| Some(PathBuf::from(path));
+
+        assert_eq!(
+            dest("/data", "/data/a/b.txt"),
+            some("a/b.txt")
+        );
+        assert_eq!(dest("/data/", "/data/a"), some("a"));
+        assert_eq!(
+            dest("/data", "/data/./a//b"),
+            some("a/b")
+        );
+        // The prefix only once, and only whole components
+        assert_eq!(
+            dest("/data", "/data/data/a"),
+            some("data/a")
+        );
+        assert_eq!(dest("/data", "/database/a"), None);
+        assert_eq!(dest("/data", "/other/data/a"), None);
+        assert_eq!(dest("/data", "data/a"), None);
+        // Relative and absolute forms
+        assert_eq!(
+            dest(
+                "./src",
+                &format!("{}/src/a", cwd.display())
+            ),
+            some("a")
+        );
+        assert_eq!(
+            dest(
+                &format!("{}/src", cwd.display()),
+                "src/a"
+            ),
+            some("a")
+        );
+        assert_eq!(dest("src", "./src/a"), some("a"));
+        assert_eq!(dest("/data", "/data/a/.."), None);
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+
+            let path = Path::new(std::ffi::OsStr::from_bytes(
+                b"/data/\xff/\xfe.txt",
+            ));
+            let app = App::new(Config::build(
+                "/data".into(),
+                "/dst".into(),
+            ))
+            .unwrap();
+            assert_eq!(
+                app.build_dest_path(path).unwrap().as_os_str().as_bytes(),
+                b"\xff/\xfe.txt"
+            );
+        }
+    }
+
+    #[test]
+    fn pathological_paths_fail_without_panicking() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-paths-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(source.join("a")).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        let app = App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap();
+
+        assert!(app.build_dest_path(source.join("a/..")).is_err());
+        assert!(app.build_dest_path(source.join("../dst")).is_err());
+        assert!(app.build_dest_path("/").is_err());
+        for operation in [
+            Operation::Remove { path: source.clone() },
+            Operation::Remove { path: source.join("..") },
+            Operation::Remove { path: "/".into() },
+            Operation::Rename {
+                from: source.join("a"),
+                to: source.clone(),
+            },
+            Operation::Rename {
+                from: source.clone(),
+                to: source.join("a/.."),
+            },
+            Operation::Copy {
+                path: source.join("a/.."),
+            },
+        ] {
+            assert!(
+                app.execute(&operation).is_err(),
+                "{operation:?}"
+            );
+        }
+        // Syncing the source root itself is harmless
+        app.execute(&Operation::Copy { path: source.clone() }).unwrap();
+        assert!(destination.is_dir());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn removes_directory_trees() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-remove-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        let outside = root.join("outside");
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(destination.join("d/a/b")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(destination.join("d/a/b/c.txt"), "c").unwrap();
+        fs::write(outside.join("keep.txt"), "keep").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&outside, destination.join("d/link")).unwrap();
+        let app = App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap();
+
+        app.execute(&Operation::Remove { path: source.join("d") }).unwrap();
+        assert!(!destination.join("d").exists());
+        assert!(outside.join("keep.txt").exists());
+        // The event of a child arriving after the one of its parent
+        app.execute(&Operation::Remove {
+            path: source.join("d/a/b/c.txt"),
+        })
+        .unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn renames_into_new_directories_and_copies_missing_entries() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-rename-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(source.join("new/dir")).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(source.join("new/dir/a"), "a").unwrap();
+        fs::write(destination.join("a"), "a").unwrap();
+        fs::create_dir_all(source.join("moved")).unwrap();
+        fs::write(source.join("moved/b"), "b").unwrap();
+        let app = App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap();
+
+        app.execute(&Operation::Rename {
+            from: source.join("a"),
+            to: source.join("new/dir/a"),
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read(destination.join("new/dir/a")).unwrap(),
+            b"a"
+        );
+        // Never synchronised before the rename
+        app.execute(&Operation::Rename {
+            from: source.join("unknown"),
+            to: source.join("moved"),
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read(destination.join("moved/b")).unwrap(),
+            b"b"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn initial_sync_error_policy() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-policy-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        // A directory where the file should go
+        fs::create_dir_all(destination.join("a")).unwrap();
+        fs::write(source.join("a"), "a").unwrap();
+        fs::write(source.join("b"), "b").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(source.join("a"))
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        let config = Config::build(source.clone(), destination.clone());
+        let err = App::new(config).unwrap().sync_once().unwrap_err();
+        assert!(
+            err.to_string().starts_with("copy "),
+            "{err}"
+        );
+
+        let mut config = Config::build(source.clone(), destination.clone());
+        config.errors.initial_sync = OnError::Continue;
+        let report = App::new(config).unwrap().sync_once().unwrap();
+        assert_eq!(
+            fs::read(destination.join("b")).unwrap(),
+            b"b"
+        );
+        assert_eq!(report.len(), 1);
+        assert_eq!(report.failures[0].operation, "copy");
+        assert_eq!(
+            report.failures[0].path,
+            source.join("a")
+        );
+        assert!(
+            report.to_string().starts_with("1 failures\n  copy "),
+            "{report}"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn archives_old_files() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-archive-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(source.join("2023")).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(source.join("2023/old.log"), "old").unwrap();
+        fs::write(source.join("new.log"), "new").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(source.join("2023/old.log"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60 * 60 * 24 * 90))
+            .unwrap();
+
+        let mut config = Config::build(source.clone(), destination.clone());
+        (config.move_files, config.archive_after) = (
+            true,
+            Some(Duration::from_secs(60 * 60 * 24 * 30)),
+        );
+        let mut app = App::new(config).unwrap();
+        app.sync_once().unwrap();
+        assert!(!source.join("2023/old.log").exists());
+        assert_eq!(
+            fs::read(destination.join("2023/old.log")).unwrap(),
+            b"old"
+        );
+        // Left hot at the source, also when it changes
+        app.execute(&Operation::Copy {
+            path: source.join("new.log"),
+        })
+        .unwrap();
+        assert!(source.join("new.log").exists());
+        assert!(!destination.join("new.log").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn transforms_the_copied_contents() {
+        /// Redacts the passwords of the configuration files
+        struct Redact;
+
+        impl Transform for Redact {
+            fn transform<'a>(
+                &self,
+                _source: &Path,
+                mut input: Box<dyn std::io::Read + 'a>,
+            ) -> std::io::Result<Box<dyn std::io::Read + 'a>> {
+                let mut text = String::new();
+                input.read_to_string(&mut text)?;
+                Ok(Box::new(std::io::Cursor::new(
+                    text.replace("hunter2", "********"),
+                )))
+            }
+        }
+
+        /// Stores `\n` line endings
+        struct Unix;
+
+        impl Transform for Unix {
+            fn transform<'a>(
+                &self,
+                _source: &Path,
+                mut input: Box<dyn std::io::Read + 'a>,
+            ) -> std::io::Result<Box<dyn std::io::Read + 'a>> {
+                let mut text = String::new();
+                input.read_to_string(&mut text)?;
+                Ok(Box::new(std::io::Cursor::new(
+                    text.replace("\r\n", "\n"),
+                )))
+            }
+        }
+
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-transform-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(
+            source.join("app.conf"),
+            "user = admin\r\npassword = hunter2\r\n",
+        )
+        .unwrap();
+
+        let mut app = App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap();
+        app.add_transform(TransformChain::new().with(Redact).with(Unix));
+        app.execute(&Operation::Copy {
+            path: source.join("app.conf"),
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(destination.join("app.conf")).unwrap(),
+            "user = admin\npassword = ********\n"
+        );
+        assert_eq!(
+            fs::metadata(destination.join("app.conf")).unwrap().modified().unwrap(),
+            fs::metadata(source.join("app.conf")).unwrap().modified().unwrap()
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn moves_the_stored_files() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-move-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(source.join("scans")).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(source.join("scans/1.pdf"), "1").unwrap();
+        // Scanned a while ago, the copy keeps the time
+        fs::File::options()
+            .write(true)
+            .open(source.join("scans/1.pdf"))
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .unwrap();
+
+        let mut config = Config::build(source.clone(), destination.clone());
+        config.move_files = true;
+        let app = App::new(config).unwrap();
+        app.execute(&Operation::Copy {
+            path: source.join("scans/1.pdf"),
+        })
+        .unwrap();
+        assert!(!source.join("scans/1.pdf").exists());
+        // The removal from the source stays at the source
+        app.execute(&Operation::Remove {
+            path: source.join("scans/1.pdf"),
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read(destination.join("scans/1.pdf")).unwrap(),
+            b"1"
+        );
+
+        // Saved under a temporary name first
+        fs::write(source.join("scans/3.tmp"), "3").unwrap();
+        fs::rename(
+            source.join("scans/3.tmp"),
+            source.join("scans/3.pdf"),
+        )
+        .unwrap();
+        app.execute(&Operation::Rename {
+            from: source.join("scans/3.tmp"),
+            to: source.join("scans/3.pdf"),
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read(destination.join("scans/3.pdf")).unwrap(),
+            b"3"
+        );
+        assert_eq!(
+            fs::read_dir(source.join("scans")).unwrap().count(),
+            0
+        );
+
+        // Stored already by an earlier run
+        fs::write(source.join("scans/4.pdf"), "4").unwrap();
+        App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap()
+        .sync_once()
+        .unwrap();
+        let mut config = Config::build(source.clone(), destination.clone());
+        config.move_files = true;
+        App::new(config).unwrap().sync_once().unwrap();
+        assert!(!source.join("scans/4.pdf").exists());
+
+        let mut config = Config::build(source.clone(), destination.clone());
+        config.move_files = true;
+        config.destinations = vec![root.join("other")];
+        assert!(App::new(config).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn updates_metadata_in_place() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-metadata-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(source.join("a.raw"), "aaaa").unwrap();
+        fs::write(source.join("b.raw"), "bb").unwrap();
+        // Same size, so it is not copied even if the contents differ
+        fs::write(destination.join("a.raw"), "AAAA").unwrap();
+        fs::write(destination.join("b.raw"), "b").unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        fs::File::options()
+            .write(true)
+            .open(source.join("a.raw"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let mut readonly = fs::metadata(source.join("a.raw")).unwrap().permissions();
+        readonly.set_readonly(true);
+        fs::set_permissions(source.join("a.raw"), readonly.clone()).unwrap();
+
+        let app = App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap();
+        let report = app.sync_metadata().unwrap();
+        assert_eq!(report.updated, [PathBuf::from("a.raw")]);
+        assert_eq!(
+            report.differing,
+            [PathBuf::from("b.raw")]
+        );
+        let stored = fs::metadata(destination.join("a.raw")).unwrap();
+        assert_eq!(
+            (
+                stored.modified().unwrap(),
+                stored.permissions()
+            ),
+            (modified, readonly)
+        );
+        assert_eq!(
+            fs::read(destination.join("a.raw")).unwrap(),
+            b"AAAA"
+        );
+        assert_eq!(
+            app.sync_metadata().unwrap().unchanged,
+            1
+        );
+
+        let mut writable = fs::metadata(source.join("a.raw")).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        writable.set_readonly(false);
+        fs::set_permissions(source.join("a.raw"), writable.clone()).unwrap();
+        fs::set_permissions(destination.join("a.raw"), writable).unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn propagates_touches() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-touch-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(source.join("video.mkv"), "frames").unwrap();
+        let mut config = Config::build(source.clone(), destination.clone());
+        config.compare = CompareMode::Checksum;
+        let mut app = App::new(config).unwrap();
+        app.sync_once().unwrap();
+
+        let touched = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        fs::File::options()
+            .write(true)
+            .open(source.join("video.mkv"))
+            .unwrap()
+            .set_modified(touched)
+            .unwrap();
+        app.execute(&Operation::Copy {
+            path: source.join("video.mkv"),
+        })
+        .unwrap();
+        assert_eq!(
+            fs::metadata(destination.join("video.mkv")).unwrap().modified().unwrap(),
+            touched
+        );
+        assert_eq!(app.handle().stats().copied, 1);
+
+        // Same size, other contents
+        fs::write(source.join("video.mkv"), "FRAMES").unwrap();
+        app.execute(&Operation::Copy {
+            path: source.join("video.mkv"),
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read(destination.join("video.mkv")).unwrap(),
+            b"FRAMES"
+        );
+        assert_eq!(app.handle().stats().copied, 2);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn repairs_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-perms-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(source.join("keys")).unwrap();
+        fs::create_dir_all(destination.join("keys")).unwrap();
+        fs::write(source.join("keys/id"), "secret").unwrap();
+        fs::write(destination.join("keys/id"), "secret").unwrap();
+        let mode = |path: &str| fs::metadata(destination.join(path)).unwrap().permissions().mode() & 0o7777;
+        let set = |path: PathBuf, mode| fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+        set(source.join("keys"), 0o700);
+        set(source.join("keys/id"), 0o600);
+        set(destination.join("keys/id"), 0o666);
+
+        let app = App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap();
+        let report = app.repair_permissions().unwrap();
+        assert_eq!(
+            report.updated,
+            [PathBuf::from("keys"), PathBuf::from("keys/id")]
+        );
+        assert_eq!(
+            (mode("keys"), mode("keys/id")),
+            (0o700, 0o600)
+        );
+
+        let mut config = Config::build(source.clone(), destination.clone());
+        config.permissions.file_mode = Some(0o640);
+        let report = App::new(config).unwrap().repair_permissions().unwrap();
+        assert_eq!(
+            report.updated,
+            [PathBuf::from("keys/id")]
+        );
+        assert_eq!(mode("keys/id"), 0o640);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn synchronises_a_single_file() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-single-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(source.join("notes.txt"), "a").unwrap();
+        fs::write(source.join("other.txt"), "other").unwrap();
+
+        // Into a directory, under its own name
+        App::new(Config::build(
+            source.join("notes.txt"),
+            destination.clone(),
+        ))
+        .unwrap()
+        .sync_once()
+        .unwrap();
+        assert_eq!(
+            fs::read(destination.join("notes.txt")).unwrap(),
+            b"a"
+        );
+        assert!(!destination.join("other.txt").exists());
+
+        // To a file of another name
+        let app = App::new(Config::build(
+            source.join("notes.txt"),
+            destination.join("backup.txt"),
+        ))
+        .unwrap();
+        // Saved through a temporary file
+        fs::write(source.join("notes.txt.tmp"), "b").unwrap();
+        fs::rename(
+            source.join("notes.txt.tmp"),
+            source.join("notes.txt"),
+        )
+        .unwrap();
+        app.execute(&Operation::Rename {
+            from: source.join("notes.txt.tmp"),
+            to: source.join("notes.txt"),
+        })
+        .unwrap();
+        app.execute(&Operation::Copy {
+            path: source.join("other.txt"),
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read(destination.join("backup.txt")).unwrap(),
+            b"b"
+        );
+        assert!(!destination.join("other.txt").exists());
+        assert!(!destination.join("notes.txt.tmp").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn synchronises_trees_beyond_max_path() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-long-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        // Well beyond the 260 characters of MAX_PATH
+        let deep: PathBuf = (0..8).map(|level| format!("{level}{}", "d".repeat(40))).collect();
+        fs::create_dir_all(paths::extended(&source.join(&deep))).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(
+            paths::extended(&source.join(deep.join("a.txt"))),
+            "a",
+        )
+        .unwrap();
+        assert!(source.join(deep.join("a.txt")).as_os_str().len() > 300);
+
+        let mut app = App::new(Config::build(
+            source.clone(),
+            destination.clone(),
+        ))
+        .unwrap();
+        app.sync_once().unwrap();
+        assert_eq!(
+            fs::read(paths::extended(
+                &destination.join(deep.join("a.txt"))
+            ))
+            .unwrap(),
+            b"a"
+        );
+        fs::rename(
+            paths::extended(&source.join(deep.join("a.txt"))),
+            paths::extended(&source.join(deep.join("b.txt"))),
+        )
+        .unwrap();
+        app.execute(&Operation::Rename {
+            from: source.join(deep.join("a.txt")),
+            to: source.join(deep.join("b.txt")),
+        })
+        .unwrap();
+        assert!(paths::extended(&destination.join(deep.join("b.txt"))).exists());
+        app.execute(&Operation::Remove {
+            path: source.join(deep.join("b.txt")),
+        })
+        .unwrap();
+        assert!(!paths::extended(&destination.join(deep.join("b.txt"))).exists());
+        fs::remove_dir_all(paths::extended(&root)).unwrap();
+    }
+
+    #[test]
+    fn destination_names_follow_the_unicode_form() {
+        init();
+        let root = std::env::temp_dir().join(format!(
+            "fsync-unicode-{}",
+            std::process::id()
         ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        // Stored decomposed by an earlier sync from macOS
+        for (dir, name, content) in [(&source, "caf\u{e9}.txt", "new"), (&destination, "cafe\u{301}.txt", "old")] {
+            fs::write(dir.join(name), content).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(dir.join(name))
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH)
+                .unwrap();
+        }
+        fs::write(source.join("nai\u{308}ve.txt"), "naive").unwrap();
 
-        // assert!(app.run().is_ok());
-        todo!()
+        let mut config = Config::build(source.clone(), destination.clone());
+        config.unicode_normalization = Some(UnicodeForm::Nfc);
+        App::new(config).unwrap().sync_once().unwrap();
+        // Renamed and compared, not copied next to it
+        assert!(!destination.join("cafe\u{301}.txt").exists());
+        assert_eq!(
+            fs::read(destination.join("caf\u{e9}.txt")).unwrap(),
+            b"old"
+        );
+        assert_eq!(
+            fs::read(destination.join("na\u{ef}ve.txt")).unwrap(),
+            b"naive"
+        );
+        fs::remove_dir_all(root).unwrap();
     }
 }
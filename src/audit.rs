@@ -0,0 +1,200 @@
+//! Audit trail of the applied changes.
+//!
+//! With an `[audit]` section every copy, removal and rename applied to the
+//! destination is appended to a JSON Lines file, including failed attempts:
+//!
+//! ```json
+//! {"timestamp":"2024-05-01T10:00:00.000Z","operation":"copy","path":"/data/a","destination":"a","bytes":2,"sha256":"…","outcome":"ok"}
+//! ```
+//!
+//! Records are flushed to disk before the next change is applied. The file
+//! is only ever appended to: when it grows beyond `max_size` or the
+//! `rotation` period ends it is renamed with the time as suffix
+//! (`audit.jsonl.2024-05-01T10-00-00Z`) and a new one is started, rotated
+//! files are never removed by fsync.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Rotation;
+
+/// `[audit]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditConfig {
+    /// Path of the current audit file
+    pub(crate) path: PathBuf,
+    /// Size in bytes after which the file is rotated, unlimited if not set
+    #[serde(default)]
+    pub(crate) max_size: Option<u64>,
+    /// Time based rotation
+    #[serde(default)]
+    pub(crate) rotation: Rotation,
+}
+
+/// Line of the audit file
+#[derive(Debug, Serialize)]
+pub(crate) struct Record<'a> {
+    /// RFC 3339 time the change was applied
+    pub(crate) timestamp: String,
+    /// `copy`, `remove` or `rename`
+    pub(crate) operation: &'a str,
+    /// Source path, the new one for renames
+    pub(crate) path: &'a Path,
+    /// Old source path of a rename
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) from: Option<&'a Path>,
+    /// Path relative to the destination root
+    pub(crate) destination: &'a Path,
+    /// Size of a copied file
+    pub(crate) bytes: u64,
+    /// SHA-256 of a copied file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sha256: Option<String>,
+    /// `ok` or `error`
+    pub(crate) outcome: &'a str,
+    /// Failure description
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// Append-only audit file
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    /// Settings
+    config: AuditConfig,
+    /// Current file, opened for appending
+    file: File,
+    /// Size of the current file
+    size: u64,
+    /// Rotation period of the current file
+    period: u64,
+}
+
+impl AuditLog {
+    /// Opens (or creates) the audit file, creating its directory
+    pub(crate) fn open(config: AuditConfig) -> io::Result<Self> {
+        if let Some(dir) = config.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let metadata = file.metadata()?;
+        let period = config
+            .rotation
+            .period(metadata.modified().unwrap_or_else(|_| SystemTime::now()));
+        Ok(Self {
+            size: metadata.len(),
+            period,
+            config,
+            file,
+        })
+    }
+
+    /// Current time in the format of [Record::timestamp]
+    pub(crate) fn now() -> String {
+        humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+    }
+
+    /// Hex encoded SHA-256 of the file at `path`
+    pub(crate) fn sha256(path: &Path) -> io::Result<String> {
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut buf)? {
+                0 => return Ok(crate::peer::hex(&hasher.finalize())),
+                len => hasher.update(&buf[..len]),
+            }
+        }
+    }
+
+    /// Appends `record` and waits until it is on disk
+    pub(crate) fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let now = SystemTime::now();
+        let period = self.config.rotation.period(now);
+        let full = self
+            .config
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 > max);
+        if full || period != self.period {
+            self.rotate(now)?;
+            self.period = period;
+        }
+
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Renames the current file with the time as suffix and starts a new one
+    fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        let path = &self.config.path;
+        let suffix = humantime::format_rfc3339_seconds(now).to_string().replace(':', "-");
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(format!(".{suffix}"));
+        let mut rotated = PathBuf::from(rotated);
+        // Several rotations within a second keep their own files
+        let mut index = 1;
+        while rotated.exists() {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{suffix}.{index}"));
+            rotated = name.into();
+            index += 1;
+        }
+        fs::rename(path, rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_never_dropped() {
+        let dir = std::env::temp_dir().join(format!(
+            "fsync-audit-test-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let config = AuditConfig {
+            path: dir.join("audit.jsonl"),
+            max_size: Some(400),
+            rotation: Rotation::Never,
+        };
+        let record = Record {
+            timestamp: AuditLog::now(),
+            operation: "remove",
+            path: Path::new("/src/a"),
+            from: None,
+            destination: Path::new("a"),
+            bytes: 0,
+            sha256: None,
+            outcome: "ok",
+            error: None,
+        };
+
+        for _ in 0..4 {
+            AuditLog::open(config.clone()).unwrap().append(&record).unwrap();
+        }
+        let lines = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap().lines().count())
+            .sum::<usize>();
+        assert_eq!(lines, 4);
+        assert!(fs::read_dir(&dir).unwrap().count() > 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
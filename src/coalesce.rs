@@ -0,0 +1,169 @@
+//! Coalescing of atomic saves.
+//!
+//! Many editors save by writing a temporary file and renaming it over the
+//! document. Copies seen by the watcher are held for [SAVE_WINDOW], so a
+//! rename of the held file within that time turns into a single copy of the
+//! final file instead of a copy of the temporary file followed by a rename
+//! at the destination. Repeated changes of a held file collapse into one
+//! copy, and a held file removed again is not copied at all.
+//!
+//! Any other change first releases the held copies, so the destination sees
+//! the changes in the order they happened.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::queue::Operation;
+
+/// Time a copy is held, waiting for the rename of the file
+pub(crate) const SAVE_WINDOW: Duration = Duration::from_millis(500);
+
+/// Copies held for [SAVE_WINDOW]
+#[derive(Debug, Default)]
+pub(crate) struct Coalescer {
+    /// Source paths of the held copies in the order they were first seen,
+    /// with the time they were
+    held: BTreeMap<u64, (PathBuf, Instant)>,
+    /// Key in [Coalescer::held] of every held path
+    index: HashMap<PathBuf, u64>,
+    /// Key of the next held copy
+    next: u64,
+}
+
+impl Coalescer {
+    /// Adds `operation` seen at `now`, returns the operations to apply now
+    pub(crate) fn push(&mut self, operation: Operation, now: Instant) -> Vec<Operation> {
+        match operation {
+            Operation::Copy { path } => {
+                self.hold(path, now);
+                Vec::new()
+            }
+            Operation::Rename { from, to } if self.holds(&from) => {
+                tracing::debug!("coalesced the save of {to:?} through {from:?}");
+                self.unhold(&from);
+                self.unhold(&to);
+                self.hold(to, now);
+                Vec::new()
+            }
+            Operation::Remove { path } if self.holds(&path) => {
+                self.unhold(&path);
+                let mut operations = self.release();
+                // Stored before it was held
+                operations.push(Operation::Remove { path });
+                operations
+            }
+            operation => {
+                let mut operations = self.release();
+                operations.push(operation);
+                operations
+            }
+        }
+    }
+
+    /// Copies held since [SAVE_WINDOW] before `now`, and the ones held
+    /// before them
+    pub(crate) fn due(&mut self, now: Instant) -> Vec<Operation> {
+        let Some(last) = self
+            .held
+            .iter()
+            .rev()
+            .find(|(_, (_, seen))| now.duration_since(*seen) >= SAVE_WINDOW)
+            .map(|(key, _)| *key)
+        else {
+            return Vec::new();
+        };
+        let later = self.held.split_off(&(last + 1));
+        let due = std::mem::replace(&mut self.held, later);
+        due.into_values()
+            .map(|(path, _)| {
+                self.index.remove(&path);
+                Operation::Copy { path }
+            })
+            .collect()
+    }
+
+    /// Time from `now` until the next held copy is due
+    pub(crate) fn next_due(&self, now: Instant) -> Option<Duration> {
+        self.held
+            .values()
+            .map(|(_, seen)| SAVE_WINDOW.saturating_sub(now.duration_since(*seen)))
+            .min()
+    }
+
+    /// All held copies
+    pub(crate) fn release(&mut self) -> Vec<Operation> {
+        self.index.clear();
+        std::mem::take(&mut self.held)
+            .into_values()
+            .map(|(path, _)| Operation::Copy { path })
+            .collect()
+    }
+
+    /// Whether a copy of `path` is held
+    fn holds(&self, path: &Path) -> bool {
+        self.index.contains_key(path)
+    }
+
+    /// Holds the copy of `path` seen at `now`, unless it is held already
+    fn hold(&mut self, path: PathBuf, now: Instant) {
+        if !self.index.contains_key(&path) {
+            self.index.insert(path.clone(), self.next);
+            self.held.insert(self.next, (path, now));
+            self.next += 1;
+        }
+    }
+
+    /// Drops the held copy of `path`
+    fn unhold(&mut self, path: &Path) {
+        if let Some(key) = self.index.remove(path) {
+            self.held.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atomic_saves_become_one_copy() {
+        let mut coalescer = Coalescer::default();
+        let start = Instant::now();
+        let copy = |path: &str| Operation::Copy { path: path.into() };
+        assert!(coalescer.push(copy("/src/.doc.tmp"), start).is_empty());
+        assert!(coalescer.push(copy("/src/.doc.tmp"), start).is_empty());
+        assert!(coalescer
+            .push(
+                Operation::Rename {
+                    from: "/src/.doc.tmp".into(),
+                    to: "/src/doc".into(),
+                },
+                start
+            )
+            .is_empty());
+        assert!(coalescer.due(start + SAVE_WINDOW / 2).is_empty());
+        assert_eq!(
+            coalescer.next_due(start + SAVE_WINDOW / 2),
+            Some(SAVE_WINDOW / 2)
+        );
+        assert_eq!(
+            coalescer.due(start + SAVE_WINDOW),
+            vec![copy("/src/doc")]
+        );
+
+        // Other changes keep their order
+        coalescer.push(copy("/src/a/b"), start);
+        let rename = Operation::Rename {
+            from: "/src/a".into(),
+            to: "/src/c".into(),
+        };
+        assert_eq!(
+            coalescer.push(rename.clone(), start),
+            vec![copy("/src/a/b"), rename]
+        );
+        assert_eq!(coalescer.next_due(start), None);
+    }
+}
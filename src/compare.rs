@@ -0,0 +1,230 @@
+//! Comparison of the source with the destination.
+//!
+//! [App::diff](crate::App::diff) compares the files like the initial sync
+//! does, by their size and modification time, and lists what is only in the
+//! source, only in the destination, and what differs. Directories found on
+//! one side only are listed once, with the bytes of the files below them.
+//! `fsync diff <source> <destination>` prints it:
+//!
+//! ```text
+//! + photos/2024/            only in the source
+//! - old-notes.txt           only in the destination
+//! ~ report.docx             differs
+//! ```
+//!
+//! With `--stat` the byte counts of both sides follow every entry, with
+//! `--output json` the [TreeDiff] is printed as JSON.
+//! Destinations which can not list their directories (the ones other than
+//! local directories) only report the entries of the source.
+//!
+//! With `compare = "checksum"` a changed file whose size still matches at
+//! the destination is compared by its contents before it is copied: a file
+//! which was only touched gets the new modification time at the
+//! destination instead of a copy. Only local destinations not compressing
+//! the file tell their checksums, the others copy it.
+
+use std::{
+    fmt,
+    io::{self, Read},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// `compare` setting: how a changed file is told apart from its stored
+/// copy of the same size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompareMode {
+    /// By the modification time, a touched file is copied again
+    #[default]
+    Metadata,
+    /// By the SHA-256 of the contents, only the modification time of a
+    /// touched file is updated
+    Checksum,
+}
+
+/// SHA-256 of the contents of `reader`
+///
+/// # Errors
+///
+/// Errors reading `reader` are returned.
+pub(crate) fn checksum(mut reader: impl Read) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; crate::target::CHUNK_SIZE];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(hasher.finalize().into()),
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// How an entry differs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Difference {
+    /// The entry is missing at the destination
+    OnlyInSource,
+    /// The entry has no counterpart in the source
+    OnlyInDestination,
+    /// The file has another size or modification time, or one side is a
+    /// directory
+    Differs,
+}
+
+impl Difference {
+    /// Marker of the entries in the printed diff
+    fn marker(self) -> char {
+        match self {
+            Difference::OnlyInSource => '+',
+            Difference::OnlyInDestination => '-',
+            Difference::Differs => '~',
+        }
+    }
+}
+
+/// Entry which is not the same on both sides
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct DiffEntry {
+    /// Destination path, relative to the destination root
+    pub path: PathBuf,
+    /// How the entry differs
+    pub difference: Difference,
+    /// The entry is a directory, on the side it is on
+    pub is_dir: bool,
+    /// Bytes of the file, or of the files below the directory, in the source
+    pub source_bytes: Option<u64>,
+    /// Bytes of the file, or of the files below the directory, at the
+    /// destination
+    pub destination_bytes: Option<u64>,
+}
+
+/// Differences between the source and the destination.
+///
+/// Prints one entry per line and a summary, the alternate form (`{:#}`)
+/// adds the byte counts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct TreeDiff {
+    /// Differing entries, by their path
+    pub entries: Vec<DiffEntry>,
+    /// Files which are the same on both sides
+    pub identical: u64,
+    /// The destination was listed, entries only in the destination are
+    /// included
+    pub listed: bool,
+}
+
+impl TreeDiff {
+    /// Source and destination are the same
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of entries which differ in `difference`
+    pub fn count(&self, difference: Difference) -> usize {
+        self.entries.iter().filter(|entry| entry.difference == difference).count()
+    }
+}
+
+impl fmt::Display for TreeDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            let slash = if entry.is_dir { "/" } else { "" };
+            write!(
+                f,
+                "{} {}{slash}",
+                entry.difference.marker(),
+                entry.path.display()
+            )?;
+            if f.alternate() {
+                match (
+                    entry.source_bytes,
+                    entry.destination_bytes,
+                ) {
+                    (Some(source), Some(destination)) => write!(f, "  ({source} -> {destination} bytes)")?,
+                    (Some(bytes), None) | (None, Some(bytes)) => write!(f, "  ({bytes} bytes)")?,
+                    (None, None) => {}
+                }
+            }
+            writeln!(f)?;
+        }
+        write!(
+            f,
+            "{} only in the source, {} only in the destination, {} differ, {} identical",
+            self.count(Difference::OnlyInSource),
+            self.count(Difference::OnlyInDestination),
+            self.count(Difference::Differs),
+            self.identical
+        )?;
+        if !self.listed {
+            write!(
+                f,
+                "\nthe destination can not be listed, entries only in the destination are not shown"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prints_entries_and_byte_counts() {
+        let entry = |path: &str, difference, is_dir, source_bytes, destination_bytes| DiffEntry {
+            path: path.into(),
+            difference,
+            is_dir,
+            source_bytes,
+            destination_bytes,
+        };
+        let diff = TreeDiff {
+            entries: vec![
+                entry(
+                    "photos",
+                    Difference::OnlyInSource,
+                    true,
+                    Some(2048),
+                    None,
+                ),
+                entry(
+                    "old.txt",
+                    Difference::OnlyInDestination,
+                    false,
+                    None,
+                    Some(3),
+                ),
+                entry(
+                    "report.docx",
+                    Difference::Differs,
+                    false,
+                    Some(10),
+                    Some(12),
+                ),
+            ],
+            identical: 4,
+            listed: true,
+        };
+        assert_eq!(
+            diff.to_string(),
+            "+ photos/\n- old.txt\n~ report.docx\n1 only in the source, 1 only in the destination, 1 differ, 4 identical"
+        );
+        assert_eq!(
+            format!("{diff:#}").lines().nth(2),
+            Some("~ report.docx  (10 -> 12 bytes)")
+        );
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(
+            json["entries"][0]["difference"],
+            "only_in_source"
+        );
+        assert_eq!(json["identical"], 4);
+    }
+}
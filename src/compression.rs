@@ -0,0 +1,333 @@
+//! zstd compression at local destinations.
+//!
+//! With a `[compression]` section a local destination stores every file
+//! compressed, under its name with `.zst` appended: `notes/todo.txt` as
+//! `notes/todo.txt.zst`. Directories keep their names. The zstd frame
+//! records the size of the original file, which the destination reports
+//! instead of the compressed one, so comparisons, removals and renames see
+//! the files of the source.
+//!
+//! Files which are compressed already, media and archives, are stored as
+//! they are: zstd would spend time on them without saving space. They are
+//! recognised by their extension or by the entropy of their first bytes,
+//! unless `skip_incompressible` is turned off. Files named `*.zst` are
+//! compressed anyway, as `*.zst.zst`, so every stored name ending in `.zst`
+//! is a compressed file and two sources never share a stored name.
+//!
+//! ```toml
+//! [compression]
+//! level = 3
+//! skip_incompressible = true
+//! ```
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, Read, Seek},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::Deserialize;
+
+/// Suffix appended to the names of the compressed files
+const SUFFIX: &str = ".zst";
+
+/// Longest zstd frame header, holding the size of the original contents
+pub(crate) const HEADER_LEN: u64 = 18;
+
+/// Extensions of the formats which are compressed already
+const INCOMPRESSIBLE: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "lz4",
+    "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odp", "ods", "odt", "ogg", "opus", "png", "pptx", "rar", "tgz", "webm", "webp",
+    "xlsx", "xz", "zip", "zst",
+];
+
+/// Bytes read from the start of a file to estimate its entropy
+const SAMPLE_LEN: u64 = 64 * 1024;
+
+/// Bits of entropy per byte above which a sample is taken as compressed
+/// already, 8 being random
+const MAX_ENTROPY: f64 = 7.5;
+
+/// `[compression]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompressionConfig {
+    /// zstd level, from 1 (fastest) to 22 (smallest)
+    #[serde(default = "CompressionConfig::default_level")]
+    pub(crate) level: i32,
+    /// Stores the files which are compressed already as they are
+    #[serde(default = "CompressionConfig::default_skip_incompressible")]
+    pub(crate) skip_incompressible: bool,
+}
+
+impl CompressionConfig {
+    /// Default of [CompressionConfig::level]
+    fn default_level() -> i32 {
+        zstd::DEFAULT_COMPRESSION_LEVEL
+    }
+
+    /// Default of [CompressionConfig::skip_incompressible]
+    fn default_skip_incompressible() -> bool {
+        true
+    }
+}
+
+/// Whether the file `src` looks compressed already, by its extension or
+/// the entropy of its first bytes
+///
+/// # Errors
+///
+/// Errors reading `src` are returned.
+pub(crate) fn incompressible(src: &Path) -> io::Result<bool> {
+    let known = src
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| INCOMPRESSIBLE.iter().any(|known| ext.eq_ignore_ascii_case(known)));
+    if known {
+        return Ok(true);
+    }
+    let mut sample = Vec::new();
+    fs::File::open(src)?.take(SAMPLE_LEN).read_to_end(&mut sample)?;
+    Ok(entropy(&sample) > MAX_ENTROPY)
+}
+
+/// Shannon entropy of `bytes`, in bits per byte
+fn entropy(bytes: &[u8]) -> f64 {
+    let mut counts = [0u64; 256];
+    bytes.iter().for_each(|&byte| counts[usize::from(byte)] += 1);
+    let len = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Path the file `path` is stored at
+pub(crate) fn compressed(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(SUFFIX);
+    name.into()
+}
+
+/// Whether the file `path` may be stored under its own name: unless it
+/// ends in `.zst`, which is read as the name of a compressed file
+pub(crate) fn plain_name(path: &Path) -> bool {
+    !path.as_os_str().as_encoded_bytes().ends_with(SUFFIX.as_bytes())
+}
+
+/// Name of the file stored as `name`, none if it is not compressed
+pub(crate) fn original(name: &OsStr) -> Option<OsString> {
+    name.to_str()?
+        .strip_suffix(SUFFIX)
+        .filter(|name| !name.is_empty())
+        .map(Into::into)
+}
+
+/// Size of the original contents of the file starting with `head`, none
+/// if the frame does not record it
+pub(crate) fn content_size(head: &[u8]) -> Option<u64> {
+    zstd::zstd_safe::get_frame_content_size(head).ok().flatten()
+}
+
+/// Compressed copy of a file in the temporary directory, removed when
+/// dropped
+pub(crate) struct Compressed {
+    /// Copy, opened for reading
+    pub(crate) file: fs::File,
+    /// Bytes of the copy
+    pub(crate) len: u64,
+    /// Path of the copy
+    path: PathBuf,
+}
+
+impl Drop for Compressed {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Compresses the file `src` at `level`, the copy gets its modification
+/// time and permissions
+///
+/// # Errors
+///
+/// Errors reading `src` or writing the copy are returned.
+pub(crate) fn compress(src: &Path, level: i32) -> io::Result<Compressed> {
+    /// Copies made so far, naming the next one
+    static COPIES: AtomicU64 = AtomicU64::new(0);
+
+    let mut source = fs::File::open(src)?;
+    let meta = source.metadata()?;
+    let path = std::env::temp_dir().join(format!(
+        "fsync-{}-{}{SUFFIX}",
+        std::process::id(),
+        COPIES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    let mut compressed = Compressed { file, len: 0, path };
+    let mut encoder = zstd::Encoder::new(&compressed.file, level)?;
+    encoder.include_contentsize(true)?;
+    encoder.set_pledged_src_size(Some(meta.len()))?;
+    io::copy(&mut source, &mut encoder)?;
+    encoder.finish()?;
+    compressed.file.set_modified(meta.modified()?)?;
+    // A read-only copy could not be removed on Windows
+    #[cfg(unix)]
+    compressed.file.set_permissions(meta.permissions())?;
+    compressed.len = compressed.file.stream_position()?;
+    compressed.file.rewind()?;
+    Ok(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesystem::{Fs, MemFs},
+        target::LocalTarget,
+        SyncTarget,
+    };
+
+    #[test]
+    fn stores_files_compressed() {
+        let src = std::env::temp_dir().join(format!(
+            "fsync-compress-{}.txt",
+            std::process::id()
+        ));
+        let text = "to be compressed ".repeat(1000);
+        fs::write(&src, &text).unwrap();
+
+        let memory = MemFs::new();
+        memory.create_dir_all("/dst".as_ref()).unwrap();
+        let target = LocalTarget::with_fs("/dst".into(), memory.clone()).compressed(3, true);
+        target.upload(&src, "docs/a.txt".as_ref()).unwrap();
+        let stored = memory.read("/dst/docs/a.txt.zst").unwrap();
+        assert!(stored.len() < text.len() / 10);
+        let mut contents = String::new();
+        zstd::Decoder::new(&stored[..])
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, text);
+
+        let meta = target.metadata("docs/a.txt".as_ref()).unwrap().unwrap();
+        assert_eq!(meta.len, text.len() as u64);
+        assert_eq!(
+            target.read_dir("docs".as_ref()).unwrap().unwrap(),
+            ["a.txt"]
+        );
+        target
+            .rename(
+                "docs/a.txt".as_ref(),
+                "docs/b.txt".as_ref(),
+            )
+            .unwrap();
+        assert!(memory.read("/dst/docs/b.txt.zst").is_some());
+        target.remove("docs/b.txt".as_ref()).unwrap();
+        assert!(target.metadata("docs/b.txt".as_ref()).unwrap().is_none());
+        fs::remove_file(src).unwrap();
+    }
+
+    #[test]
+    fn stores_incompressible_files_plain() {
+        let dir = std::env::temp_dir().join(format!(
+            "fsync-incompressible-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let text = dir.join("a.bin");
+        fs::write(&text, "compressible ".repeat(1000)).unwrap();
+        // xorshift noise, as random as compressed data
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let noise = (0..16 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        let random = dir.join("b.bin");
+        fs::write(&random, &noise).unwrap();
+        let photo = dir.join("c.JPG");
+        fs::write(&photo, "not really a photo").unwrap();
+        assert!(!incompressible(&text).unwrap());
+        assert!(incompressible(&random).unwrap());
+        assert!(incompressible(&photo).unwrap());
+
+        let memory = MemFs::new();
+        memory.create_dir_all("/dst".as_ref()).unwrap();
+        let target = LocalTarget::with_fs("/dst".into(), memory.clone()).compressed(3, true);
+        target.upload(&text, "x.bin".as_ref()).unwrap();
+        assert!(memory.read("/dst/x.bin.zst").is_some());
+        // Replaces the compressed copy of the earlier contents
+        target.upload(&random, "x.bin".as_ref()).unwrap();
+        assert_eq!(
+            memory.read("/dst/x.bin").unwrap(),
+            noise
+        );
+        assert!(memory.read("/dst/x.bin.zst").is_none());
+        assert_eq!(
+            target.metadata("x.bin".as_ref()).unwrap().unwrap().len,
+            noise.len() as u64
+        );
+        target.upload(&text, "x.bin".as_ref()).unwrap();
+        assert!(memory.read("/dst/x.bin").is_none());
+
+        // Compressed sources keep their names apart from the compressed copies
+        target.upload(&random, "backup.tar".as_ref()).unwrap();
+        target.upload(&random, "backup.tar.zst".as_ref()).unwrap();
+        assert!(memory.read("/dst/backup.tar").is_some());
+        assert!(memory.read("/dst/backup.tar.zst.zst").is_some());
+        let mut names = target.read_dir("".as_ref()).unwrap().unwrap();
+        names.sort();
+        assert_eq!(
+            names,
+            ["backup.tar", "backup.tar.zst", "x.bin"]
+        );
+        assert_eq!(
+            target.metadata("backup.tar.zst".as_ref()).unwrap().unwrap().len,
+            noise.len() as u64
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unchanged_files_are_up_to_date() {
+        let root = std::env::temp_dir().join(format!(
+            "fsync-compress-plan-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(
+            source.join("a.txt"),
+            "to be compressed ".repeat(100),
+        )
+        .unwrap();
+        // Written well before it is stored
+        fs::File::options()
+            .write(true)
+            .open(source.join("a.txt"))
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(3600))
+            .unwrap();
+        let mut config = crate::Config::build(source, destination.clone());
+        config.backends.compression = Some(toml::from_str("").unwrap());
+
+        let app = crate::App::new(config).unwrap();
+        let report = app.apply(&app.plan().unwrap()).unwrap();
+        assert_eq!(report.applied.len(), 1);
+        assert!(destination.join("a.txt.zst").is_file());
+        let plan = app.plan().unwrap();
+        assert_eq!(plan.len(), 0);
+        assert_eq!(plan.unchanged(), 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
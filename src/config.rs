@@ -3,6 +3,7 @@
 use std::{
     fmt::{Debug, Display},
     path::PathBuf,
+    time::Duration,
 };
 
 /// Config Result type used for error propogation while creating
@@ -17,6 +18,8 @@ pub enum ConfigError {
     WrongArguments,
     /// [std::io::Error] wrapper to represent errors from environment variables
     IOError(std::io::Error),
+    /// [toml::de::Error] wrapper to represent errors in the configuration file
+    FileFormat(toml::de::Error),
     /// Unknown type of error
     Other,
 }
@@ -28,6 +31,7 @@ impl ConfigError {
         match *self {
             WrongArguments => "Wrong arguments",
             IOError(_) => "IO Error",
+            FileFormat(_) => "Config file error",
             Other => "Other error",
         }
     }
@@ -40,6 +44,9 @@ impl std::fmt::Display for ConfigError {
             ConfigError::IOError(ref cause) => {
                 write!(f, "{}", cause)
             }
+            ConfigError::FileFormat(ref cause) => {
+                write!(f, "{}: {}", self.as_str(), cause)
+            }
             ConfigError::Other => f.write_str(self.as_str()),
         }
     }
@@ -53,16 +60,330 @@ impl From<std::io::Error> for ConfigError {
     }
 }
 
+impl From<toml::de::Error> for ConfigError {
+    fn from(value: toml::de::Error) -> Self {
+        Self::FileFormat(value)
+    }
+}
+
+/// Contents of the configuration file passed with `--config`.
+///
+/// Every field is optional, command line arguments take precedence.
+#[derive(Debug, Default, serde::Deserialize)]
+struct ConfigFile {
+    /// Source path to monitor changes
+    source: Option<PathBuf>,
+    /// Destination path or `scheme:location` of a remote backend
+    destination: Option<PathBuf>,
+    /// Journal of the changes made while the destination is unavailable
+    queue_file: Option<PathBuf>,
+    /// Unicode form of the destination names
+    unicode_normalization: Option<crate::UnicodeForm>,
+    /// `[names]` section
+    names: Option<crate::NamesConfig>,
+    /// Template of the destination paths
+    destination_template: Option<String>,
+    /// `[ignore]` section
+    ignore: Option<crate::IgnoreConfig>,
+    /// `[[route]]` entries
+    #[serde(default)]
+    route: Vec<crate::RouteConfig>,
+    /// `[[rename]]` entries
+    #[serde(default)]
+    rename: Vec<crate::RenameConfig>,
+    /// Patterns of the only synchronised files
+    #[serde(default)]
+    only: Vec<String>,
+    /// Remove the source files once they are stored at the destination
+    #[serde(default, rename = "move")]
+    move_files: bool,
+    /// Age of the files moved to the destination, the younger ones stay
+    #[serde(default, with = "humantime_serde::option")]
+    archive_after: Option<Duration>,
+    /// How changed files are compared with their stored copies
+    compare: Option<crate::CompareMode>,
+    /// Address of the Prometheus metrics endpoint
+    metrics: Option<String>,
+    /// Address of the health and status endpoint
+    status: Option<String>,
+    /// Bearer token of `POST /rescan` on the status endpoint
+    status_token: Option<crate::Secret>,
+    /// Output format of the log
+    log_format: Option<crate::LogFormat>,
+    /// `[log_file]` section
+    log_file: Option<crate::LogFileConfig>,
+    /// `[syslog]` section
+    syslog: Option<crate::SyslogConfig>,
+    /// `[event_log]` section
+    event_log: Option<crate::EventLogConfig>,
+    /// `[audit]` section
+    audit: Option<crate::AuditConfig>,
+    /// `[watcher]` section
+    watcher: Option<crate::watcher::WatcherConfig>,
+    /// `[watchdog]` section
+    watchdog: Option<crate::WatchdogConfig>,
+    /// `[failover]` section
+    failover: Option<crate::FailoverConfig>,
+    /// `[git]` section
+    git: Option<crate::GitConfig>,
+    /// `[permissions]` section
+    permissions: Option<crate::PermissionsConfig>,
+    /// `[retention]` section
+    retention: Option<crate::RetentionConfig>,
+    /// `[report]` section
+    report: Option<crate::ReportConfig>,
+    /// `[retry]` section
+    retry: Option<crate::RetryConfig>,
+    /// `[errors]` section
+    errors: Option<crate::ErrorPolicy>,
+    /// `[quota]` section
+    quota: Option<crate::QuotaConfig>,
+    /// `[hooks]` section
+    hooks: Option<crate::HooksConfig>,
+    /// Further destinations of the source
+    #[serde(default)]
+    destinations: Vec<PathBuf>,
+    /// `[[pair]]` entries
+    #[serde(default)]
+    pair: Vec<PairConfig>,
+    /// Remote backend sections
+    #[serde(flatten)]
+    backends: BackendsConfig,
+}
+
+/// `[[pair]]` entry of the configuration file
+#[derive(Debug, serde::Deserialize)]
+struct PairConfig {
+    /// Source path to monitor changes
+    source: PathBuf,
+    /// Destination path or `scheme:location` of a remote backend
+    destination: PathBuf,
+}
+
+/// Settings of the remote backends, one section per backend
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub(crate) struct BackendsConfig {
+    /// `[gdrive]` section
+    #[cfg(feature = "gdrive")]
+    pub(crate) gdrive: Option<crate::target::GoogleDriveConfig>,
+    /// `[azure]` section
+    #[cfg(feature = "azure")]
+    pub(crate) azure: Option<crate::target::AzureConfig>,
+    /// `[b2]` section
+    #[cfg(feature = "b2")]
+    pub(crate) b2: Option<crate::target::B2Config>,
+    /// `[gcs]` section
+    #[cfg(feature = "gcs")]
+    pub(crate) gcs: Option<crate::target::GcsConfig>,
+    /// `[network]` section shared by the HTTP backends
+    #[cfg(feature = "http")]
+    #[serde(default)]
+    pub(crate) network: crate::target::NetworkConfig,
+    /// `[rclone]` section
+    pub(crate) rclone: Option<crate::target::RcloneConfig>,
+    /// `[peer]` section
+    pub(crate) peer: Option<crate::peer::PeerConfig>,
+    /// `[agent]` section
+    #[cfg(feature = "agent")]
+    pub(crate) agent: Option<crate::agent::AgentConfig>,
+    /// `[[webhooks]]` entries
+    #[cfg(feature = "webhooks")]
+    #[serde(default)]
+    pub(crate) webhooks: Vec<crate::WebhookConfig>,
+    /// `[email]` section
+    #[cfg(feature = "email")]
+    pub(crate) email: Option<crate::EmailConfig>,
+    /// `[desktop]` section
+    #[cfg(feature = "desktop")]
+    pub(crate) desktop: Option<crate::DesktopConfig>,
+    /// `[script]` section
+    #[cfg(feature = "scripting")]
+    pub(crate) script: Option<crate::ScriptConfig>,
+    /// `[compression]` section
+    #[cfg(feature = "compression")]
+    pub(crate) compression: Option<crate::CompressionConfig>,
+    /// `[encryption]` section
+    #[cfg(feature = "encryption")]
+    pub(crate) encryption: Option<crate::EncryptionConfig>,
+    /// `[parity]` section
+    #[cfg(feature = "parity")]
+    pub(crate) parity: Option<crate::ParityConfig>,
+    /// `[media]` section
+    #[cfg(feature = "media")]
+    pub(crate) media: Option<crate::MediaConfig>,
+    /// `[otlp]` section
+    #[cfg(feature = "otel")]
+    pub(crate) otlp: Option<crate::otel::OtlpConfig>,
+}
+
+impl ConfigFile {
+    /// Reads and parses the file at `path`
+    fn load(path: PathBuf) -> CResult<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+}
+
+/// What the application should do
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Command {
+    /// Mirror the source to the destination
+    #[default]
+    Sync,
+    /// `fsync serve <dir>`: accept changes pushed by other instances into the destination
+    Serve,
+    /// `fsync agent`: gRPC service driven by a central controller
+    Agent,
+    /// `fsync keyring set <name>`: store a secret read from standard input in the keychain
+    KeyringSet,
+    /// `fsync plan <source> <destination>`: print the changes of the initial sync without applying them
+    Plan,
+    /// `fsync apply <plan.json>`: apply a plan saved by `fsync plan --json`
+    Apply,
+    /// `fsync diff <source> <destination>`: print how the destination differs from the source
+    Diff,
+    /// `fsync decrypt <file> <output>`: restore a file stored at an encrypted destination
+    Decrypt,
+    /// `fsync restore <manifest> <output>`: rebuild a file stored at a dedup destination
+    Restore,
+    /// `fsync scrub <dir>`: check the files of a destination against their parity and repair them
+    Scrub,
+    /// `fsync prune <dir>`: remove the versions of a dedup destination the retention rules do not keep
+    Prune,
+    /// `fsync repair-perms <source> <destination>`: give the stored entries the permissions of the source
+    RepairPerms,
+}
+
+/// `--output text|json`: format of what the subcommands print on the
+/// standard output, the log stays on the standard error
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Output {
+    /// Human readable text
+    #[default]
+    Text,
+    /// One JSON document per result
+    Json,
+}
+
+impl std::str::FromStr for Output {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
 /// Configuration of the application.
 ///
-/// Stores only source and destination paths.
+/// Stores source and destination paths
+/// and the settings of the remote backends.
 ///
 #[derive(Debug)]
 pub struct Config {
+    /// Selected subcommand
+    pub(super) command: Command,
     /// Source path to monitor changes
     pub(super) source: PathBuf,
     /// Destination path for syncronisation
     pub(super) destination: PathBuf,
+    /// Remote backend settings
+    pub(super) backends: BackendsConfig,
+    /// Journal of the changes made while the destination is unavailable,
+    /// a file in the temporary directory if not set
+    pub(super) queue_file: Option<PathBuf>,
+    /// Unicode form the destination names are normalized to, unchanged if
+    /// not set
+    pub(super) unicode_normalization: Option<crate::UnicodeForm>,
+    /// Escaping of the names rejected by the destination
+    pub(super) names: Option<crate::NamesConfig>,
+    /// Template the destination paths are built from instead of the source
+    /// paths
+    pub(super) destination_template: Option<String>,
+    /// Files which are never synchronised
+    pub(super) ignore: crate::IgnoreConfig,
+    /// Routes of the files by their type
+    pub(super) routes: Vec<crate::RouteConfig>,
+    /// Rules renaming the files at the destination
+    pub(super) renames: Vec<crate::RenameConfig>,
+    /// `--only <pattern>`: patterns of the only synchronised files, every
+    /// file is if there are none
+    pub(super) only: Vec<String>,
+    /// `--listen <addr>` of `fsync serve` and `fsync agent`
+    pub(super) listen: Option<String>,
+    /// `--metrics <addr>`: address of the Prometheus metrics endpoint
+    pub(super) metrics: Option<String>,
+    /// `--status <addr>`: address of the health and status endpoint
+    pub(super) status: Option<String>,
+    /// Bearer token of `POST /rescan` on the status endpoint
+    pub(super) status_token: Option<crate::Secret>,
+    /// `--log-format text|json`
+    pub(super) log_format: crate::LogFormat,
+    /// `--log-file <path>`: log file written instead of the standard error
+    pub(super) log_file: Option<crate::LogFileConfig>,
+    /// Syslog daemon or server receiving the log
+    pub(super) syslog: Option<crate::SyslogConfig>,
+    /// Windows event source receiving warnings and errors
+    pub(super) event_log: Option<crate::EventLogConfig>,
+    /// Audit trail of the applied changes
+    pub(super) audit: Option<crate::AuditConfig>,
+    /// Source of the changes of the watch
+    pub(super) watcher: crate::watcher::WatcherConfig,
+    /// Alerts for a watcher without events
+    pub(super) watchdog: Option<crate::WatchdogConfig>,
+    /// Secondary destination used while the destination is unavailable
+    pub(super) failover: Option<crate::FailoverConfig>,
+    /// Git repository the settled changes of the destination are committed to
+    pub(super) git: Option<crate::GitConfig>,
+    /// Permissions `fsync repair-perms` gives the stored entries
+    pub(super) permissions: crate::PermissionsConfig,
+    /// Versions `fsync prune` keeps
+    pub(super) retention: Option<crate::RetentionConfig>,
+    /// Periodic summary reports
+    pub(super) report: Option<crate::ReportConfig>,
+    /// Retries of transient failures
+    pub(super) retry: Option<crate::RetryConfig>,
+    /// Reactions to failed changes
+    pub(super) errors: crate::ErrorPolicy,
+    /// Cap on the bytes stored at the destination
+    pub(super) quota: Option<crate::QuotaConfig>,
+    /// Commands run around synchronisations
+    pub(super) hooks: crate::HooksConfig,
+    /// Entry name of `fsync keyring set <name>`
+    pub(super) entry: Option<String>,
+    /// `--output text|json`, `--json` of `fsync plan` and `fsync apply`
+    pub(super) output: Output,
+    /// Plan of `fsync apply <plan.json>`
+    pub(super) plan: Option<crate::SyncPlan>,
+    /// `--stat` of `fsync diff`: print the byte counts
+    pub(super) stat: bool,
+    /// `--interactive`: ask before overwriting or removing destination
+    /// entries in the initial sync and `fsync apply`
+    pub(super) interactive: bool,
+    /// `--yes`: overwrite and remove destination entries in the initial
+    /// sync without asking first
+    pub(super) yes: bool,
+    /// `--tui`: show the terminal dashboard instead of the log
+    pub(super) tui: bool,
+    /// `--move`: remove the source files once they are stored at the
+    /// destination
+    pub(super) move_files: bool,
+    /// `--archive-after <duration>`: move only the files not modified for
+    /// that long, the younger ones are left at the source
+    pub(super) archive_after: Option<Duration>,
+    /// `--metadata-only`: update the metadata of the stored files instead
+    /// of synchronising
+    pub(super) metadata_only: bool,
+    /// How changed files are compared with their stored copies
+    pub(super) compare: crate::CompareMode,
+    /// Further sources and destinations watched by the same
+    /// [App](crate::App)
+    pub(super) pairs: Vec<(PathBuf, PathBuf)>,
+    /// Further destinations the source is mirrored to
+    pub(super) destinations: Vec<PathBuf>,
 }
 
 impl Config {
@@ -71,21 +392,307 @@ impl Config {
     /// # Panics
     /// Should not panic
     ///
+    /// Source and destination can also be given in the configuration
+    /// file passed with `-c`/`--config <file>`.
+    ///
+    /// `fsync serve <dir> [--listen <addr>]` only needs the served directory,
+    /// which is stored as the destination, and so do `fsync scrub <dir>` and
+    /// `fsync prune <dir>`.
+    /// `fsync agent [--listen <addr>]` takes no paths at all,
+    /// `fsync keyring set <name>` only the entry name.
+    /// `fsync plan` takes the same paths as a synchronisation, and `--json`,
+    /// `fsync diff` the paths and `--stat`, `fsync repair-perms` the paths.
+    /// `--move` removes the source
+    /// files once they are stored, `--archive-after <duration>` moves only
+    /// the ones not modified for that long, `--metadata-only` only updates the
+    /// metadata of the stored files and exits.
+    /// `fsync decrypt <file> <output>` takes the encrypted file as the source
+    /// and the restored one as the destination, `fsync restore <manifest>
+    /// <output>` the manifest of a `dedup:` destination.
+    /// `fsync apply <plan.json>` takes the paths from the plan unless they
+    /// are given after it. `--output json` is accepted by every subcommand,
+    /// `--json` is short for it.
+    ///
     /// # Errors
     /// Will return [Err(ConfigError::WrongArguments)](ConfigError::WrongArguments)
-    /// if less than two arguments were given
+    /// if neither arguments nor the configuration file provide both paths,
+    /// or for an option which is unknown or not one of the subcommand.
     /// Arguments mapped via [PathBuf::from] function, which should not fail.
     /// However, paths could probably be invalid.
+    ///
+    /// [ConfigError::IOError] or [ConfigError::FileFormat] are returned
+    /// if the configuration file could not be read or parsed,
+    /// [ConfigError::IOError] if the plan of `fsync apply` could not.
     pub fn from_args() -> CResult<Config> {
         use std::{collections::VecDeque, env};
 
-        let mut args = env::args().skip(1).map(PathBuf::from).collect::<VecDeque<_>>();
+        let mut args = env::args_os().skip(1).peekable();
+        let mut positional = VecDeque::new();
+        let mut config_file = None;
+        let mut listen = None;
+        let mut metrics = None;
+        let mut status = None;
+        let mut log_format = None;
+        let mut log_file = None;
+        let mut exec = None;
+        let mut only = Vec::new();
+        let mut output = Output::Text;
+        let mut stat = false;
+        let mut interactive = false;
+        let mut yes = false;
+        let mut tui = false;
+        let mut move_files = false;
+        let mut archive_after = None;
+        let mut metadata_only = false;
 
-        let (Some(source), Some(destination)) = (args.pop_front(), args.pop_front()) else {
+        let command = match args.peek().and_then(|arg| arg.to_str()) {
+            Some("serve") => {
+                args.next();
+                Command::Serve
+            }
+            Some("agent") => {
+                args.next();
+                Command::Agent
+            }
+            Some("keyring") => {
+                args.next();
+                Command::KeyringSet
+            }
+            Some("plan") => {
+                args.next();
+                Command::Plan
+            }
+            Some("apply") => {
+                args.next();
+                Command::Apply
+            }
+            Some("diff") => {
+                args.next();
+                Command::Diff
+            }
+            Some("decrypt") => {
+                args.next();
+                Command::Decrypt
+            }
+            Some("restore") => {
+                args.next();
+                Command::Restore
+            }
+            Some("scrub") => {
+                args.next();
+                Command::Scrub
+            }
+            Some("prune") => {
+                args.next();
+                Command::Prune
+            }
+            Some("repair-perms") => {
+                args.next();
+                Command::RepairPerms
+            }
+            _ => Command::Sync,
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("-c" | "--config") => {
+                    config_file = Some(PathBuf::from(
+                        args.next().ok_or(ConfigError::WrongArguments)?,
+                    ));
+                }
+                Some("--listen") if matches!(command, Command::Serve | Command::Agent) => {
+                    listen = Some(
+                        args.next()
+                            .and_then(|a| a.into_string().ok())
+                            .ok_or(ConfigError::WrongArguments)?,
+                    );
+                }
+                Some("--metrics") => {
+                    metrics = Some(
+                        args.next()
+                            .and_then(|a| a.into_string().ok())
+                            .ok_or(ConfigError::WrongArguments)?,
+                    );
+                }
+                Some("--status") => {
+                    status = Some(
+                        args.next()
+                            .and_then(|a| a.into_string().ok())
+                            .ok_or(ConfigError::WrongArguments)?,
+                    );
+                }
+                Some("--log-format") => {
+                    log_format = Some(
+                        args.next()
+                            .and_then(|a| a.to_str()?.parse().ok())
+                            .ok_or(ConfigError::WrongArguments)?,
+                    );
+                }
+                Some("--log-file") => {
+                    log_file = Some(PathBuf::from(
+                        args.next().ok_or(ConfigError::WrongArguments)?,
+                    ));
+                }
+                Some("--exec") => {
+                    exec = Some(
+                        args.next()
+                            .and_then(|a| a.into_string().ok())
+                            .ok_or(ConfigError::WrongArguments)?,
+                    );
+                }
+                Some("--json") if matches!(command, Command::Plan | Command::Apply) => output = Output::Json,
+                Some("--output") => {
+                    output = args
+                        .next()
+                        .and_then(|a| a.to_str()?.parse().ok())
+                        .ok_or(ConfigError::WrongArguments)?;
+                }
+                Some("--stat") if command == Command::Diff => stat = true,
+                Some("--interactive") => interactive = true,
+                Some("--yes" | "-y") => yes = true,
+                Some("--tui") if command == Command::Sync => tui = true,
+                Some("--move") => move_files = true,
+                Some("--archive-after") => {
+                    archive_after = Some(
+                        args.next()
+                            .and_then(|a| humantime::parse_duration(a.to_str()?).ok())
+                            .ok_or(ConfigError::WrongArguments)?,
+                    );
+                }
+                Some("--metadata-only") if command == Command::Sync => metadata_only = true,
+                Some("--only") => {
+                    only.push(
+                        args.next()
+                            .and_then(|a| a.into_string().ok())
+                            .ok_or(ConfigError::WrongArguments)?,
+                    );
+                }
+                // Unknown, or not one of this subcommand: not a destination
+                _ if arg.as_encoded_bytes().starts_with(b"-") && arg != "-" => return Err(ConfigError::WrongArguments),
+                _ => positional.push_back(PathBuf::from(arg)),
+            }
+        }
+
+        let file = config_file.map(ConfigFile::load).transpose()?.unwrap_or_default();
+        let mut plan = None;
+
+        let (source, destination) = match command {
+            Command::Sync | Command::Plan | Command::Diff | Command::RepairPerms => (
+                positional.pop_front().or(file.source),
+                positional.pop_front().or(file.destination),
+            ),
+            Command::Decrypt | Command::Restore => (
+                positional.pop_front(),
+                positional.pop_front(),
+            ),
+            Command::Serve | Command::Scrub | Command::Prune => (
+                Some(PathBuf::new()),
+                positional.pop_front().or(file.destination),
+            ),
+            Command::Agent => (
+                Some(PathBuf::new()),
+                Some(PathBuf::new()),
+            ),
+            Command::Apply => {
+                let path = positional.pop_front().ok_or(ConfigError::WrongArguments)?;
+                let saved: crate::SyncPlan = serde_json::from_str(&std::fs::read_to_string(path)?).map_err(std::io::Error::from)?;
+                let paths = (
+                    positional.pop_front().or(saved.source().map(PathBuf::from)).or(file.source),
+                    positional
+                        .pop_front()
+                        .or(saved.destination().map(PathBuf::from))
+                        .or(file.destination),
+                );
+                plan = Some(saved);
+                paths
+            }
+            Command::KeyringSet => {
+                let (Some(action), Some(name)) = (
+                    positional.pop_front(),
+                    positional.pop_front(),
+                ) else {
+                    return Err(ConfigError::WrongArguments);
+                };
+                if action.as_os_str() != "set" {
+                    return Err(ConfigError::WrongArguments);
+                }
+                let name = name.into_os_string().into_string().map_err(|_| ConfigError::WrongArguments)?;
+                return Ok(Config {
+                    command,
+                    entry: Some(name),
+                    ..Config::build(PathBuf::new(), PathBuf::new())
+                });
+            }
+        };
+        let (Some(source), Some(destination)) = (source, destination) else {
             return Err(ConfigError::WrongArguments);
         };
 
-        Ok(Config::build(source, destination))
+        // Every further path of a synchronisation is a destination
+        let destinations: Vec<_> = match command {
+            Command::Sync => positional.drain(..).collect(),
+            _ => Vec::new(),
+        };
+
+        let hooks = file.hooks.unwrap_or_default();
+        Ok(Config {
+            command,
+            backends: file.backends,
+            queue_file: file.queue_file,
+            unicode_normalization: file.unicode_normalization,
+            names: file.names,
+            destination_template: file.destination_template,
+            ignore: file.ignore.unwrap_or_default(),
+            routes: file.route,
+            renames: file.rename,
+            output,
+            plan,
+            stat,
+            interactive,
+            yes,
+            tui,
+            archive_after: archive_after.or(file.archive_after),
+            // Archiving moves the old files
+            move_files: move_files || file.move_files || archive_after.or(file.archive_after).is_some(),
+            metadata_only,
+            compare: file.compare.unwrap_or_default(),
+            only: match only.is_empty() {
+                true => file.only,
+                false => only,
+            },
+            listen,
+            metrics: metrics.or(file.metrics),
+            status: status.or(file.status),
+            status_token: file.status_token,
+            log_format: log_format.or(file.log_format).unwrap_or_default(),
+            log_file: match (log_file, file.log_file) {
+                (Some(path), Some(section)) => Some(crate::LogFileConfig { path, ..section }),
+                (path, section) => path.map(crate::LogFileConfig::new).or(section),
+            },
+            syslog: file.syslog,
+            event_log: file.event_log,
+            audit: file.audit,
+            watcher: file.watcher.unwrap_or_default(),
+            watchdog: file.watchdog,
+            failover: file.failover,
+            git: file.git,
+            permissions: file.permissions.unwrap_or_default(),
+            retention: file.retention,
+            report: file.report,
+            retry: file.retry,
+            errors: file.errors.unwrap_or_default(),
+            quota: file.quota,
+            hooks: crate::HooksConfig {
+                exec: exec.or(hooks.exec),
+                ..hooks
+            },
+            pairs: file.pair.into_iter().map(|pair| (pair.source, pair.destination)).collect(),
+            destinations: match destinations.is_empty() {
+                true => file.destinations,
+                false => destinations,
+            },
+            ..Config::build(source, destination)
+        })
     }
 
     /// Default builder from two paths.
@@ -95,13 +702,151 @@ impl Config {
     /// Simple builder usage:
     ///
     /// ```
+    /// # use fsync::Config;
     /// let config = Config::build(
     ///     "./sync_test/dir1".into(),
     ///     "./sync_test/dir2".into(),
     /// );
     /// ```
     pub fn build(source: PathBuf, destination: PathBuf) -> Self {
-        Self { source, destination }
+        Self {
+            command: Command::default(),
+            source,
+            destination,
+            backends: BackendsConfig::default(),
+            queue_file: None,
+            unicode_normalization: None,
+            names: None,
+            destination_template: None,
+            ignore: crate::IgnoreConfig::default(),
+            routes: Vec::new(),
+            renames: Vec::new(),
+            only: Vec::new(),
+            listen: None,
+            metrics: None,
+            status: None,
+            status_token: None,
+            log_format: crate::LogFormat::default(),
+            log_file: None,
+            syslog: None,
+            event_log: None,
+            audit: None,
+            watcher: crate::watcher::WatcherConfig::default(),
+            watchdog: None,
+            failover: None,
+            git: None,
+            permissions: crate::PermissionsConfig::default(),
+            retention: None,
+            report: None,
+            retry: None,
+            errors: crate::ErrorPolicy::default(),
+            quota: None,
+            hooks: crate::HooksConfig::default(),
+            entry: None,
+            output: Output::Text,
+            plan: None,
+            stat: false,
+            interactive: false,
+            yes: false,
+            tui: false,
+            move_files: false,
+            archive_after: None,
+            metadata_only: false,
+            compare: crate::CompareMode::default(),
+            pairs: Vec::new(),
+            destinations: Vec::new(),
+        }
+    }
+
+    /// Configuration of a further pair of the same daemon, sharing the
+    /// backend settings, the ignore patterns and the reactions to failures
+    pub(crate) fn pair(&self, source: PathBuf, destination: PathBuf) -> Self {
+        Self {
+            backends: self.backends.clone(),
+            names: self.names.clone(),
+            ignore: self.ignore.clone(),
+            retry: self.retry.clone(),
+            errors: self.errors.clone(),
+            yes: self.yes,
+            move_files: self.move_files,
+            archive_after: self.archive_after,
+            compare: self.compare,
+            watcher: self.watcher.clone(),
+            ..Config::build(source, destination)
+        }
+    }
+
+    /// Configuration of a further destination of the source, sharing the
+    /// settings deciding what is stored where
+    pub(crate) fn fan_out(&self, destination: PathBuf) -> Self {
+        Self {
+            unicode_normalization: self.unicode_normalization,
+            destination_template: self.destination_template.clone(),
+            routes: self.routes.clone(),
+            renames: self.renames.clone(),
+            only: self.only.clone(),
+            ..self.pair(self.source.clone(), destination)
+        }
+    }
+
+    /// Log format getter
+    pub fn log_format(&self) -> crate::LogFormat {
+        self.log_format
+    }
+
+    /// Status endpoint getter
+    pub fn status(&self) -> Option<&str> {
+        self.status.as_deref()
+    }
+
+    /// Status endpoint token getter
+    pub fn status_token(&self) -> Option<&crate::Secret> {
+        self.status_token.as_ref()
+    }
+
+    /// Metrics endpoint getter
+    pub fn metrics(&self) -> Option<&str> {
+        self.metrics.as_deref()
+    }
+
+    /// Subcommand getter
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    /// Entry name getter of `fsync keyring set <name>`
+    pub fn entry(&self) -> Option<&str> {
+        self.entry.as_deref()
+    }
+
+    /// `--output` getter
+    pub fn output(&self) -> Output {
+        self.output
+    }
+
+    /// `--tui` getter
+    pub fn tui(&self) -> bool {
+        self.tui
+    }
+
+    /// `--yes` getter
+    pub fn yes(&self) -> bool {
+        self.yes
+    }
+
+    /// `--metadata-only` getter
+    pub fn metadata_only(&self) -> bool {
+        self.metadata_only
+    }
+
+    /// `--stat` getter of `fsync diff`
+    pub fn stat(&self) -> bool {
+        self.stat
+    }
+
+    /// Plan getter of `fsync apply <plan.json>`
+    pub fn plan(&self) -> Option<&crate::SyncPlan> {
+        self.plan.as_ref()
     }
 
     /// Source getter
@@ -115,6 +860,30 @@ impl Config {
     }
 }
 
+/// `humantime` durations in the configuration file
+pub(crate) mod humantime_serde {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer};
+
+    /// Parses `15m`, `1h 30m`, ...
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        humantime::parse_duration(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+
+    /// Optional `humantime` durations
+    pub(crate) mod option {
+        use std::time::Duration;
+
+        use serde::Deserializer;
+
+        /// Parses `30 days`, `12h`, ... if the setting is present
+        pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+            super::deserialize(deserializer).map(Some)
+        }
+    }
+}
+
 impl Display for Config {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:#?}", self)
@@ -0,0 +1,107 @@
+//! Interactive confirmation of destructive changes.
+//!
+//! With `--interactive` the initial sync and `fsync apply` ask before a file
+//! of the destination is overwritten or an entry removed, like `rm -i`:
+//!
+//! ```text
+//! overwrite docs/report.docx? [y]es, [n]o, [a]ll, [s]kip all:
+//! ```
+//!
+//! `a` applies this change and every following one without asking, `s`
+//! leaves this one and every following one out. The questions are asked on
+//! the standard error and answered on the standard input, the end of the
+//! input answers no. Changes declined are counted as skipped; changes seen
+//! by the watcher afterwards are applied without asking.
+
+use std::io::{self, BufRead, Write};
+
+/// Asks for confirmations until every following question is answered
+pub(crate) struct Prompt {
+    /// Answers
+    input: Box<dyn BufRead + Send>,
+    /// Questions
+    output: Box<dyn Write + Send>,
+    /// Answer to every following question, after `all` or `skip all`
+    decided: Option<bool>,
+}
+
+impl Prompt {
+    /// Prompt answered on `input`, asking on `output`
+    pub(crate) fn new(input: impl BufRead + Send + 'static, output: impl Write + Send + 'static) -> Self {
+        Self {
+            input: Box::new(input),
+            output: Box::new(output),
+            decided: None,
+        }
+    }
+
+    /// Prompt of the terminal, asking on the standard error
+    pub(crate) fn stdio() -> Self {
+        Self::new(
+            io::BufReader::new(io::stdin()),
+            io::stderr(),
+        )
+    }
+
+    /// Whether `question` is answered yes, asked again until the answer is
+    /// understood
+    ///
+    /// # Errors
+    ///
+    /// Errors reading the answer or writing the question are returned.
+    pub(crate) fn confirm(&mut self, question: &str) -> io::Result<bool> {
+        if let Some(decided) = self.decided {
+            return Ok(decided);
+        }
+        loop {
+            write!(
+                self.output,
+                "{question}? [y]es, [n]o, [a]ll, [s]kip all: "
+            )?;
+            self.output.flush()?;
+            let mut answer = String::new();
+            if self.input.read_line(&mut answer)? == 0 {
+                writeln!(self.output)?;
+                return Ok(false);
+            }
+            match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                "a" | "all" => {
+                    self.decided = Some(true);
+                    return Ok(true);
+                }
+                "s" | "skip all" | "skip-all" => {
+                    self.decided = Some(false);
+                    return Ok(false);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_per_item_and_for_the_rest() {
+        let mut prompt = Prompt::new(
+            io::Cursor::new("maybe\ny\nn\nskip all\n"),
+            io::sink(),
+        );
+        assert!(prompt.confirm("overwrite a").unwrap());
+        assert!(!prompt.confirm("overwrite b").unwrap());
+        assert!(!prompt.confirm("remove c").unwrap());
+        // Nothing read any more
+        assert!(!prompt.confirm("remove d").unwrap());
+
+        let mut prompt = Prompt::new(io::Cursor::new("a\n"), io::sink());
+        assert!(prompt.confirm("overwrite a").unwrap());
+        assert!(prompt.confirm("overwrite b").unwrap());
+        // End of the input
+        let mut prompt = Prompt::new(io::empty(), io::sink());
+        assert!(!prompt.confirm("overwrite a").unwrap());
+    }
+}
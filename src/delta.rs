@@ -0,0 +1,490 @@
+//! rsync-style delta transfer.
+//!
+//! The receiver splits its copy of a file into blocks and sends a
+//! [Signature] (weak rolling checksum and SHA-256 per block). The sender
+//! slides a window over the new content and emits either references to
+//! matching blocks or literal bytes, so only the changed regions travel
+//! over the network. The receiver rebuilds the file with [apply] and
+//! verifies the SHA-256 of the result.
+//!
+//! Delta stream format:
+//!
+//! - `C <u64 first block> <u32 block count>`: copy blocks of the old file
+//! - `L <u32 length> <bytes>`: literal data
+//! - `E <32 bytes SHA-256>`: end of the stream and digest of the new file
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Smallest block size
+const MIN_BLOCK: u32 = 8 * 1024;
+/// Largest block size
+const MAX_BLOCK: u32 = 1024 * 1024;
+/// Target number of blocks per signature
+const TARGET_BLOCKS: u64 = 16 * 1024;
+/// Literal data is flushed in pieces of this size
+const MAX_LITERAL: usize = 1024 * 1024;
+
+/// Block size suitable for a file of `len` bytes
+pub(crate) fn block_size(len: u64) -> u32 {
+    (len / TARGET_BLOCKS).clamp(MIN_BLOCK as u64, MAX_BLOCK as u64) as u32
+}
+
+/// Checks a block size received from the other side before buffers of
+/// its size are allocated
+fn check_block_size(block_size: u32) -> io::Result<()> {
+    if block_size == 0 || block_size > MAX_BLOCK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("block size {block_size} out of range"),
+        ));
+    }
+    Ok(())
+}
+
+/// Rolling checksum of the rsync algorithm
+#[derive(Debug, Default, Clone, Copy)]
+struct Rolling {
+    /// Sum of the bytes
+    a: u32,
+    /// Sum of the running `a` values
+    b: u32,
+    /// Window length
+    len: u32,
+}
+
+impl Rolling {
+    /// Checksum of a whole window
+    fn new(window: &[u8]) -> Self {
+        let mut rolling = Self {
+            len: window.len() as u32,
+            ..Self::default()
+        };
+        for (i, &byte) in window.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(byte as u32);
+            rolling.b = rolling.b.wrapping_add((window.len() - i) as u32 * byte as u32);
+        }
+        rolling
+    }
+
+    /// Slides the window by one byte
+    fn roll(&mut self, out: u8, input: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(input as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    /// 32 bit digest
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Signature of a single block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BlockSignature {
+    /// Rolling checksum
+    pub(crate) weak: u32,
+    /// Hex encoded SHA-256 of the block
+    pub(crate) strong: String,
+}
+
+/// Signatures of all full-sized blocks of the old file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Signature {
+    /// Block size in bytes
+    pub(crate) block_size: u32,
+    /// Block signatures in file order
+    pub(crate) blocks: Vec<BlockSignature>,
+}
+
+impl Signature {
+    /// Computes the signature of `reader`.
+    /// A trailing partial block is not included.
+    pub(crate) fn compute<R: Read>(mut reader: R, block_size: u32) -> io::Result<Self> {
+        let mut blocks = Vec::new();
+        let mut block = vec![0; block_size as usize];
+        loop {
+            let read = read_full(&mut reader, &mut block)?;
+            if read < block.len() {
+                return Ok(Self { block_size, blocks });
+            }
+            blocks.push(BlockSignature {
+                weak: Rolling::new(&block).digest(),
+                strong: strong(&block),
+            });
+        }
+    }
+}
+
+/// Hex encoded SHA-256
+fn strong(data: &[u8]) -> String {
+    crate::peer::hex(&Sha256::digest(data))
+}
+
+/// Reads until `buf` is full or the end of the input is reached
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Encoder of the delta stream
+struct Encoder<'a, W: Write> {
+    /// Delta stream
+    out: &'a mut W,
+    /// Pending literal bytes
+    literal: Vec<u8>,
+    /// Pending run of consecutive blocks: first block and count
+    run: Option<(u64, u32)>,
+    /// Bytes referenced from the old file
+    matched: u64,
+}
+
+impl<W: Write> Encoder<'_, W> {
+    /// Queues a literal byte
+    fn literal(&mut self, byte: u8) -> io::Result<()> {
+        self.flush_run()?;
+        self.literal.push(byte);
+        if self.literal.len() >= MAX_LITERAL {
+            self.flush_literal()?;
+        }
+        Ok(())
+    }
+
+    /// Queues a block reference, merging consecutive blocks
+    fn block(&mut self, index: u64, block_size: u32) -> io::Result<()> {
+        self.flush_literal()?;
+        self.matched += block_size as u64;
+        self.run = match self.run {
+            Some((first, count)) if first + count as u64 == index => Some((first, count + 1)),
+            Some(_) => {
+                self.flush_run()?;
+                Some((index, 1))
+            }
+            None => Some((index, 1)),
+        };
+        Ok(())
+    }
+
+    /// Writes pending literal bytes
+    fn flush_literal(&mut self) -> io::Result<()> {
+        if !self.literal.is_empty() {
+            self.out.write_all(b"L")?;
+            self.out.write_all(&(self.literal.len() as u32).to_be_bytes())?;
+            self.out.write_all(&self.literal)?;
+            self.literal.clear();
+        }
+        Ok(())
+    }
+
+    /// Writes the pending block run
+    fn flush_run(&mut self) -> io::Result<()> {
+        if let Some((first, count)) = self.run.take() {
+            self.out.write_all(b"C")?;
+            self.out.write_all(&first.to_be_bytes())?;
+            self.out.write_all(&count.to_be_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the delta of `new` against the `signature` of the old file.
+///
+/// Returns the number of bytes reused from the old file.
+pub(crate) fn encode<R: Read, W: Write>(signature: &Signature, mut new: R, out: &mut W) -> io::Result<u64> {
+    check_block_size(signature.block_size)?;
+    let block_size = signature.block_size as usize;
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in signature.blocks.iter().enumerate() {
+        index.entry(block.weak).or_default().push(i);
+    }
+
+    let mut encoder = Encoder {
+        out,
+        literal: Vec::new(),
+        run: None,
+        matched: 0,
+    };
+    let mut hasher = Sha256::new();
+    // Sliding window lives in `buf[start..start + block_size]`
+    let mut buf = Vec::with_capacity(block_size * 4);
+    let mut start = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+
+    loop {
+        // Keep at least one window in the buffer
+        if !eof && buf.len() - start < block_size + 1 {
+            buf.drain(..start);
+            start = 0;
+            let filled = buf.len();
+            buf.resize(filled + block_size * 2, 0);
+            let read = read_full(&mut new, &mut buf[filled..])?;
+            buf.truncate(filled + read);
+            hasher.update(&buf[filled..]);
+            eof = read < block_size * 2;
+        }
+
+        if signature.blocks.is_empty() || buf.len() - start < block_size {
+            for &byte in &buf[start..] {
+                encoder.literal(byte)?;
+            }
+            start = buf.len();
+            if eof {
+                break;
+            }
+            continue;
+        }
+
+        let window = &buf[start..start + block_size];
+        let checksum = *rolling.get_or_insert_with(|| Rolling::new(window));
+        let matched = index.get(&checksum.digest()).and_then(|candidates| {
+            let hash = strong(window);
+            candidates.iter().find(|&&i| signature.blocks[i].strong == hash).copied()
+        });
+
+        match matched {
+            Some(block) => {
+                encoder.block(block as u64, signature.block_size)?;
+                start += block_size;
+                rolling = None;
+            }
+            None => {
+                let out = buf[start];
+                encoder.literal(out)?;
+                start += 1;
+                if let (Some(rolling), Some(&input)) = (
+                    rolling.as_mut(),
+                    buf.get(start + block_size - 1),
+                ) {
+                    rolling.roll(out, input);
+                } else {
+                    rolling = None;
+                }
+            }
+        }
+    }
+
+    encoder.flush_literal()?;
+    encoder.flush_run()?;
+    let matched = encoder.matched;
+    let out = encoder.out;
+    out.write_all(b"E")?;
+    out.write_all(&hasher.finalize())?;
+    Ok(matched)
+}
+
+/// Rebuilds the new file from the `base` file and the delta stream.
+///
+/// # Errors
+///
+/// [io::ErrorKind::InvalidData] is returned for malformed streams, block
+/// sizes out of range or if the digest of the result differs from the one
+/// of the sender.
+pub(crate) fn apply<B, R, W>(mut base: B, block_size: u32, delta: &mut R, out: &mut W) -> io::Result<u64>
+where
+    B: Read + Seek,
+    R: Read,
+    W: Write,
+{
+    check_block_size(block_size)?;
+    let invalid = |message: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            message.to_owned(),
+        )
+    };
+    let mut hasher = Sha256::new();
+    let mut written = 0;
+    let mut buf = vec![0; block_size as usize];
+
+    loop {
+        let mut tag = [0];
+        delta.read_exact(&mut tag)?;
+        match &tag {
+            b"C" => {
+                let mut first = [0; 8];
+                let mut count = [0; 4];
+                delta.read_exact(&mut first)?;
+                delta.read_exact(&mut count)?;
+                let offset = u64::from_be_bytes(first)
+                    .checked_mul(block_size as u64)
+                    .ok_or_else(|| invalid("block index out of range"))?;
+                base.seek(SeekFrom::Start(offset))?;
+                for _ in 0..u32::from_be_bytes(count) {
+                    if read_full(&mut base, &mut buf)? != buf.len() {
+                        return Err(invalid(
+                            "block outside of the base file",
+                        ));
+                    }
+                    hasher.update(&buf);
+                    out.write_all(&buf)?;
+                    written += buf.len() as u64;
+                }
+            }
+            b"L" => {
+                let mut len = [0; 4];
+                delta.read_exact(&mut len)?;
+                let len = u32::from_be_bytes(len) as usize;
+                if len > MAX_LITERAL {
+                    return Err(invalid("literal too long"));
+                }
+                let mut literal = vec![0; len];
+                delta.read_exact(&mut literal)?;
+                hasher.update(&literal);
+                out.write_all(&literal)?;
+                written += len as u64;
+            }
+            b"E" => {
+                let mut digest = [0; 32];
+                delta.read_exact(&mut digest)?;
+                if hasher.finalize().as_slice() != digest {
+                    return Err(invalid("digest mismatch"));
+                }
+                return Ok(written);
+            }
+            _ => return Err(invalid("unknown delta operation")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Pseudo random content
+    fn content(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// Runs the whole exchange and returns the rebuilt file and the reused bytes
+    fn roundtrip(old: &[u8], new: &[u8], block_size: u32) -> (Vec<u8>, u64) {
+        let signature = Signature::compute(old, block_size).unwrap();
+        let mut delta = Vec::new();
+        let matched = encode(&signature, new, &mut delta).unwrap();
+        let mut rebuilt = Vec::new();
+        apply(
+            Cursor::new(old),
+            block_size,
+            &mut delta.as_slice(),
+            &mut rebuilt,
+        )
+        .unwrap();
+        (rebuilt, matched)
+    }
+
+    #[test]
+    fn rolling_matches_full_computation() {
+        let data = content(64, 1);
+        let mut rolling = Rolling::new(&data[0..16]);
+        for i in 1..=48 {
+            rolling.roll(data[i - 1], data[i + 15]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&data[i..i + 16]).digest()
+            );
+        }
+    }
+
+    #[test]
+    fn insertion_reuses_blocks() {
+        let old = content(64 * 1024, 7);
+        let mut new = old.clone();
+        new.splice(
+            10_000..10_000,
+            b"inserted bytes".iter().copied(),
+        );
+        new.truncate(60_000);
+        new.extend_from_slice(b"tail");
+
+        let (rebuilt, matched) = roundtrip(&old, &new, 4096);
+        assert_eq!(rebuilt, new);
+        assert!(
+            matched >= 10 * 4096,
+            "only {matched} bytes reused"
+        );
+    }
+
+    #[test]
+    fn unrelated_and_empty_files() {
+        let (rebuilt, matched) = roundtrip(
+            &content(20_000, 1),
+            &content(30_000, 2),
+            1024,
+        );
+        assert_eq!(rebuilt, content(30_000, 2));
+        assert_eq!(matched, 0);
+
+        assert_eq!(
+            roundtrip(b"", &content(100_000, 5), 1024).0,
+            content(100_000, 5)
+        );
+        assert_eq!(
+            roundtrip(&content(5000, 3), b"", 1024).0,
+            b""
+        );
+    }
+
+    #[test]
+    fn corrupted_base_and_invalid_block_sizes_are_detected() {
+        let old = content(8192, 4);
+        let signature = Signature::compute(old.as_slice(), 1024).unwrap();
+        let mut delta = Vec::new();
+        encode(&signature, old.as_slice(), &mut delta).unwrap();
+
+        let mut corrupted = old.clone();
+        corrupted[100] ^= 0xff;
+        let result = apply(
+            Cursor::new(corrupted),
+            1024,
+            &mut delta.as_slice(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            result.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        for block_size in [0, MAX_BLOCK + 1, u32::MAX] {
+            let result = apply(
+                Cursor::new(old.clone()),
+                block_size,
+                &mut delta.as_slice(),
+                &mut Vec::new(),
+            );
+            assert_eq!(
+                result.unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+            let signature = Signature {
+                block_size,
+                blocks: Vec::new(),
+            };
+            assert!(encode(
+                &signature,
+                old.as_slice(),
+                &mut Vec::new()
+            )
+            .is_err());
+        }
+    }
+}
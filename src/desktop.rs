@@ -0,0 +1,110 @@
+//! Desktop notifications.
+//!
+//! With a `[desktop]` section a system notification is shown when an
+//! operation fails (at most one a minute) and when a batch of at least
+//! `batch_size` changes was applied.
+//!
+//! ```toml
+//! [desktop]
+//! # errors = true
+//! # batch_size = 50   # 0 disables the batch notifications
+//! ```
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::hooks::Batch;
+
+/// Minimum time between two error notifications
+const ERROR_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `[desktop]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct DesktopConfig {
+    /// Notify failed operations
+    #[serde(default = "DesktopConfig::default_errors")]
+    pub(crate) errors: bool,
+    /// Changes of a batch needed for a notification, `0` to disable
+    #[serde(default = "DesktopConfig::default_batch_size")]
+    pub(crate) batch_size: usize,
+}
+
+impl DesktopConfig {
+    /// Default of [DesktopConfig::errors]
+    fn default_errors() -> bool {
+        true
+    }
+
+    /// Default of [DesktopConfig::batch_size]
+    fn default_batch_size() -> usize {
+        50
+    }
+}
+
+/// Desktop notifications of an [App](crate::App)
+#[derive(Debug)]
+pub(crate) struct Desktop {
+    /// Settings
+    config: DesktopConfig,
+    /// Last error notification
+    last_error: Mutex<Option<Instant>>,
+}
+
+impl Desktop {
+    /// Notifications of `config`
+    pub(crate) fn new(config: DesktopConfig) -> Self {
+        Self {
+            config,
+            last_error: Mutex::default(),
+        }
+    }
+
+    /// Batches are notified
+    pub(crate) fn wants_batches(&self) -> bool {
+        self.config.batch_size > 0
+    }
+
+    /// Shows a notification, failures are only logged
+    fn show(summary: &str, body: &str) {
+        if let Err(err) = notify_rust::Notification::new()
+            .appname("fsync")
+            .summary(summary)
+            .body(body)
+            .show()
+        {
+            tracing::debug!("desktop notification: {err}");
+        }
+    }
+
+    /// Notifies a failed operation
+    pub(crate) fn error(&self, message: &str) {
+        if !self.config.errors {
+            return;
+        }
+        let mut last_error = self.last_error.lock().unwrap_or_else(|e| e.into_inner());
+        if last_error.is_some_and(|time| time.elapsed() < ERROR_INTERVAL) {
+            return;
+        }
+        *last_error = Some(Instant::now());
+        Self::show("fsync: synchronisation error", message);
+    }
+
+    /// Notifies a completed batch if it is large enough
+    pub(crate) fn batch(&self, batch: &Batch) {
+        let changes = batch.copied + batch.removed + batch.renamed;
+        if !self.wants_batches() || changes < self.config.batch_size {
+            return;
+        }
+        Self::show(
+            "fsync: changes synchronised",
+            &format!(
+                "{} copied, {} removed, {} renamed, {} failed",
+                batch.copied, batch.removed, batch.renamed, batch.errors
+            ),
+        );
+    }
+}
@@ -0,0 +1,434 @@
+//! Email alerts.
+//!
+//! With an `[email]` section fsync mails the recent failures once at least
+//! `min_failures` operations failed, at most once per `interval` so a broken
+//! destination does not flood the mailbox. The death of the watcher is
+//! mailed right away.
+//!
+//! ```toml
+//! [email]
+//! smtp = "smtp.example.com:587"
+//! username = "fsync@example.com"
+//! password = { env = "SMTP_PASSWORD" }
+//! from = "fsync@example.com"
+//! to = ["ops@example.com"]
+//! # security = "starttls"   # "tls" (port 465) or "none"
+//! # min_failures = 5
+//! # interval = "15m"
+//! ```
+//!
+//! The `[network.tls]` certificate authorities apply to the SMTP server too.
+
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use base64::Engine;
+use serde::Deserialize;
+
+use crate::{target::http, AppError, Secret};
+
+/// Timeout of the SMTP connection and replies
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Failures listed in a single mail
+const MAX_LISTED: usize = 50;
+
+/// Transport security of the SMTP connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain connection upgraded with `STARTTLS`
+    #[default]
+    StartTls,
+    /// TLS from the start
+    Tls,
+    /// Unencrypted, for a relay on the local host
+    None,
+}
+
+/// `[email]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmailConfig {
+    /// `<host>:<port>` of the SMTP server
+    pub(crate) smtp: String,
+    /// Transport security
+    #[serde(default)]
+    pub(crate) security: SmtpSecurity,
+    /// Login, no authentication if not set
+    #[serde(default)]
+    pub(crate) username: Option<String>,
+    /// Password of `username`
+    #[serde(default)]
+    pub(crate) password: Secret,
+    /// Sender address
+    pub(crate) from: String,
+    /// Recipient addresses
+    pub(crate) to: Vec<String>,
+    /// Failures needed before a mail is sent
+    #[serde(default = "EmailConfig::default_min_failures")]
+    pub(crate) min_failures: usize,
+    /// Minimum time between two mails about failures, e.g. `15m`
+    #[serde(default = "EmailConfig::default_interval", with = "crate::config::humantime_serde")]
+    pub(crate) interval: Duration,
+}
+
+impl EmailConfig {
+    /// Default of [EmailConfig::min_failures]
+    fn default_min_failures() -> usize {
+        5
+    }
+
+    /// Default of [EmailConfig::interval]
+    fn default_interval() -> Duration {
+        Duration::from_secs(15 * 60)
+    }
+}
+
+/// Connection to the SMTP server, plain or encrypted
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// SMTP client sending one message per connection
+struct Mailer {
+    /// Settings
+    config: EmailConfig,
+    /// TLS settings
+    tls: Arc<rustls::ClientConfig>,
+}
+
+impl Mailer {
+    /// Reads a possibly multi-line reply, failing unless its code is `expected`
+    fn reply(stream: &mut dyn Stream, expected: &[u16]) -> io::Result<String> {
+        let mut text = String::new();
+        loop {
+            let mut line = Vec::new();
+            let mut byte = [0];
+            while !line.ends_with(b"\r\n") {
+                if stream.read(&mut byte)? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                line.push(byte[0]);
+            }
+            let line = String::from_utf8_lossy(&line).into_owned();
+            text.push_str(&line);
+            if line.as_bytes().get(3) != Some(&b'-') {
+                let code = line.get(..3).and_then(|code| code.parse().ok()).unwrap_or(0);
+                if !expected.contains(&code) {
+                    return Err(io::Error::other(format!(
+                        "SMTP: {}",
+                        text.trim_end()
+                    )));
+                }
+                return Ok(text);
+            }
+        }
+    }
+
+    /// Sends `command` and reads the reply
+    fn command(stream: &mut dyn Stream, command: &str, expected: &[u16]) -> io::Result<String> {
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        Self::reply(stream, expected)
+    }
+
+    /// Wraps `stream` in TLS for `host`
+    fn encrypt(&self, stream: TcpStream, host: &str) -> io::Result<Box<dyn Stream>> {
+        let name =
+            rustls::pki_types::ServerName::try_from(host.to_owned()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let connection = rustls::ClientConnection::new(self.tls.clone(), name).map_err(io::Error::other)?;
+        Ok(Box::new(rustls::StreamOwned::new(
+            connection, stream,
+        )))
+    }
+
+    /// Sends a message with `subject` and `body` to all recipients
+    fn send(&self, subject: &str, body: &str) -> io::Result<()> {
+        let config = &self.config;
+        let host = config.smtp.rsplit_once(':').map_or(config.smtp.as_str(), |(host, _)| host);
+        let address = config
+            .smtp
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other(format!("{}: no address", config.smtp)))?;
+        let tcp = TcpStream::connect_timeout(&address, TIMEOUT)?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+        let client = crate::peer::hostname();
+
+        let mut stream = match config.security {
+            SmtpSecurity::StartTls => {
+                let mut tcp = tcp;
+                Self::reply(&mut tcp, &[220])?;
+                Self::command(
+                    &mut tcp,
+                    &format!("EHLO {client}"),
+                    &[250],
+                )?;
+                Self::command(&mut tcp, "STARTTLS", &[220])?;
+                self.encrypt(tcp, host)?
+            }
+            SmtpSecurity::Tls => {
+                let mut stream = self.encrypt(tcp, host)?;
+                Self::reply(&mut *stream, &[220])?;
+                stream
+            }
+            SmtpSecurity::None => {
+                let mut tcp = tcp;
+                Self::reply(&mut tcp, &[220])?;
+                Box::new(tcp)
+            }
+        };
+        Self::command(
+            &mut *stream,
+            &format!("EHLO {client}"),
+            &[250],
+        )?;
+        if let Some(username) = &config.username {
+            let password = config.password.expose().map_err(io::Error::other)?;
+            let token = base64::engine::general_purpose::STANDARD.encode(format!("\0{username}\0{password}"));
+            Self::command(
+                &mut *stream,
+                &format!("AUTH PLAIN {token}"),
+                &[235],
+            )?;
+        }
+        Self::command(
+            &mut *stream,
+            &format!("MAIL FROM:<{}>", config.from),
+            &[250],
+        )?;
+        for to in &config.to {
+            Self::command(
+                &mut *stream,
+                &format!("RCPT TO:<{to}>"),
+                &[250, 251],
+            )?;
+        }
+        Self::command(&mut *stream, "DATA", &[354])?;
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {subject}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+            config.from,
+            config.to.join(", "),
+            httpdate::fmt_http_date(SystemTime::now()),
+        );
+        for line in body.lines() {
+            // Dot stuffing
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message.push('.');
+        Self::command(&mut *stream, &message, &[250])?;
+        let _ = Self::command(&mut *stream, "QUIT", &[221]);
+        Ok(())
+    }
+}
+
+/// Failures not mailed yet, the first [MAX_LISTED] of them by description
+#[derive(Debug, Default)]
+struct Pending {
+    /// Descriptions of the first failures
+    listed: Vec<String>,
+    /// Failures, listed or not
+    count: usize,
+}
+
+impl Pending {
+    /// Records the failure of `description`
+    fn push(&mut self, description: String) {
+        if self.listed.len() < MAX_LISTED {
+            self.listed.push(description);
+        }
+        self.count += 1;
+    }
+
+    /// Lines of the listed failures and the count of the others
+    fn list(&self) -> String {
+        let mut list = self.listed.join("\n");
+        if self.count > self.listed.len() {
+            list.push_str(&format!(
+                "\n... and {} more",
+                self.count - self.listed.len()
+            ));
+        }
+        list
+    }
+}
+
+/// Email alerts of an [App](crate::App)
+pub(crate) struct Alerts {
+    /// Queue of the background thread batching the failures
+    sender: mpsc::Sender<String>,
+    /// Client, shared with the background thread
+    mailer: Arc<Mailer>,
+    /// Failures not mailed yet
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl Alerts {
+    /// Alerts of `config`.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if the TLS settings could not be loaded.
+    pub(crate) fn new(config: &EmailConfig, network: &http::NetworkConfig) -> Result<Self, AppError> {
+        let mailer = Arc::new(Mailer {
+            config: config.clone(),
+            tls: network.tls.rustls_config()?,
+        });
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let (sender, receiver) = mpsc::channel();
+        let thread_mailer = mailer.clone();
+        let thread_pending = pending.clone();
+        std::thread::spawn(move || {
+            batch(
+                &thread_mailer,
+                &thread_pending,
+                &receiver,
+            )
+        });
+        Ok(Self { sender, mailer, pending })
+    }
+
+    /// Records a failed operation
+    pub(crate) fn failed(&self, description: String) {
+        let _ = self.sender.send(description);
+    }
+
+    /// Mails the death of the watcher with the pending failures, waiting until it is sent
+    pub(crate) fn watcher_died(&self, source: &str, error: &str) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut body = format!(
+            "The watcher of {source} on {} stopped: {error}\n",
+            crate::peer::hostname()
+        );
+        if pending.count > 0 {
+            body.push_str("\nRecent failures:\n");
+            body.push_str(&pending.list());
+        }
+        if let Err(err) = self.mailer.send("fsync: watcher stopped", &body) {
+            tracing::error!("email alert: {err}");
+        }
+    }
+}
+
+/// Collects the failures and mails them in batches
+fn batch(mailer: &Mailer, pending: &Mutex<Pending>, receiver: &mpsc::Receiver<String>) {
+    let config = &mailer.config;
+    let mut last_sent: Option<Instant> = None;
+    loop {
+        match receiver.recv_timeout(Duration::from_secs(10)) {
+            Ok(description) => pending.lock().unwrap_or_else(|e| e.into_inner()).push(description),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        if last_sent.is_some_and(|time| time.elapsed() < config.interval) {
+            continue;
+        }
+        let failures = {
+            let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+            if pending.count < config.min_failures {
+                continue;
+            }
+            std::mem::take(&mut *pending)
+        };
+        let mut body = format!(
+            "{} operations failed on {}:\n\n",
+            failures.count,
+            crate::peer::hostname()
+        );
+        body.push_str(&failures.list());
+        last_sent = Some(Instant::now());
+        if let Err(err) = mailer.send(
+            &format!(
+                "fsync: {} failed operations",
+                failures.count
+            ),
+            &body,
+        ) {
+            tracing::error!("email alert: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn smtp_conversation() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let smtp = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut transcript = String::new();
+            stream.write_all(b"220 test ESMTP\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                transcript.push_str(&line);
+                let reply: &[u8] = match line.trim_end() {
+                    line if line.starts_with("EHLO") => b"250-test\r\n250 AUTH PLAIN\r\n",
+                    "DATA" => b"354 go ahead\r\n",
+                    "." => b"250 queued\r\n",
+                    "QUIT" => {
+                        stream.write_all(b"221 bye\r\n").unwrap();
+                        return transcript;
+                    }
+                    line if line.starts_with("AUTH") => b"235 ok\r\n",
+                    line if line.starts_with("MAIL") || line.starts_with("RCPT") => b"250 ok\r\n",
+                    _ => continue,
+                };
+                stream.write_all(reply).unwrap();
+            }
+        });
+
+        let config: EmailConfig = toml::from_str(&format!(
+            r#"
+            smtp = "{smtp}"
+            security = "none"
+            username = "u"
+            password = "p"
+            from = "fsync@example.com"
+            to = ["ops@example.com"]
+            "#
+        ))
+        .unwrap();
+        let mailer = Mailer {
+            tls: http::TlsConfig::default().rustls_config().unwrap(),
+            config,
+        };
+        mailer.send("subject", "first\n.hidden").unwrap();
+
+        let transcript = server.join().unwrap();
+        assert!(transcript.contains("AUTH PLAIN AHUAcA==\r\n"));
+        assert!(transcript.contains("RCPT TO:<ops@example.com>\r\n"));
+        assert!(transcript.contains("Subject: subject\r\n"));
+        assert!(transcript.contains("\r\nfirst\r\n..hidden\r\n.\r\n"));
+    }
+
+    #[test]
+    fn pending_failures_are_bounded() {
+        let mut pending = Pending::default();
+        for i in 0..MAX_LISTED + 5 {
+            pending.push(format!("failure {i}"));
+        }
+        assert_eq!(pending.listed.len(), MAX_LISTED);
+        assert_eq!(pending.count, MAX_LISTED + 5);
+        assert!(pending.list().starts_with("failure 0\nfailure 1\n"));
+        assert!(pending.list().ends_with("\n... and 5 more"));
+    }
+}
@@ -0,0 +1,538 @@
+//! Client-side encryption of the stored files.
+//!
+//! With an `[encryption]` section every file is encrypted with
+//! XChaCha20-Poly1305 before it leaves the machine, whichever the
+//! destination: a NAS or a cloud backend only ever sees the encrypted
+//! contents. The names of the files and directories are kept, so the tree
+//! stays browsable and renames stay cheap.
+//!
+//! The key is 32 bytes written as 64 hexadecimal digits, e.g. made with
+//! `openssl rand -hex 32`, and is a [Secret](crate::Secret): a key file, a
+//! keychain entry or an environment variable.
+//!
+//! ```toml
+//! [encryption]
+//! key = { file = "/etc/fsync/key" }
+//! ```
+//!
+//! Files are encrypted in chunks of [CHUNK] bytes following the STREAM
+//! construction, each chunk authenticated on its own, behind a header with
+//! a random nonce. The size of the encrypted file follows from the size of
+//! the original one, which the destination reports instead, and the
+//! encrypted copy keeps the modification time of the original, so
+//! unchanged files are recognised. `fsync decrypt <file> <output>` with the
+//! same configuration restores a file, `fsync decrypt <dir> <output>` a
+//! whole destination.
+//!
+//! With `names = true` the paths are encrypted too, see [names].
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use chacha20poly1305::{
+    aead::stream::{DecryptorBE32, EncryptorBE32},
+    KeyInit, XChaCha20Poly1305,
+};
+use serde::Deserialize;
+
+use crate::{app::Context, paths, AppError, Secret, SyncTarget, TargetMetadata};
+
+mod names;
+
+/// Start of every encrypted file
+const MAGIC: &[u8; 8] = b"fsync\x00e1";
+
+/// Bytes of the STREAM nonce, the XChaCha20 one without the counter
+const NONCE_LEN: usize = 19;
+
+/// Bytes before the first chunk
+const HEADER_LEN: u64 = (MAGIC.len() + NONCE_LEN) as u64;
+
+/// Bytes of the original file encrypted together
+const CHUNK: usize = 64 * 1024;
+
+/// Bytes of the tag authenticating every chunk
+const TAG_LEN: u64 = 16;
+
+/// `[encryption]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct EncryptionConfig {
+    /// 32 bytes key, as 64 hexadecimal digits
+    pub(crate) key: Secret,
+    /// Encrypts the paths too
+    #[serde(default)]
+    pub(crate) names: bool,
+    /// Index of the encrypted paths, in the per-user state directory by default
+    pub(crate) index: Option<PathBuf>,
+}
+
+impl EncryptionConfig {
+    /// Configured key
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if the key can not be looked up or is
+    /// not 64 hexadecimal digits.
+    fn key(&self) -> Result<[u8; 32], AppError> {
+        let hex = self.key.expose()?.trim();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+            .collect::<Option<Vec<_>>>()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| AppError::Backend("encryption key must be 64 hexadecimal digits".into()))
+    }
+
+    /// Cipher of the configured key
+    ///
+    /// # Errors
+    ///
+    /// See [EncryptionConfig::key].
+    fn cipher(&self) -> Result<XChaCha20Poly1305, AppError> {
+        Ok(XChaCha20Poly1305::new(
+            &self.key()?.into(),
+        ))
+    }
+
+    /// Index of the encrypted paths of the source and destination pair
+    pub(crate) fn index(&self, source: &Path, destination: &Path) -> PathBuf {
+        match &self.index {
+            Some(index) => index.clone(),
+            None => crate::queue::state_file("names", source, destination),
+        }
+    }
+}
+
+/// Size of the original file of an encrypted file of `len` bytes
+fn original_len(len: u64) -> u64 {
+    let Some(body) = len.checked_sub(HEADER_LEN).filter(|&body| body >= TAG_LEN) else {
+        return len;
+    };
+    body - TAG_LEN * body.div_ceil(CHUNK as u64 + TAG_LEN)
+}
+
+/// Reads up to `len` bytes, less only at the end of `reader`
+fn read_chunk(reader: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Error of a chunk which does not decrypt
+fn damaged() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "decryption failed: wrong key or damaged file",
+    )
+}
+
+/// Encrypts the contents of `source` into `output`
+fn encrypt(cipher: XChaCha20Poly1305, source: &mut impl Read, output: &mut impl Write) -> io::Result<()> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+    output.write_all(MAGIC)?;
+    output.write_all(&nonce)?;
+    let mut encryptor = EncryptorBE32::from_aead(cipher, (&nonce).into());
+    let mut chunk = read_chunk(source, CHUNK)?;
+    loop {
+        let next = match chunk.len() {
+            CHUNK => read_chunk(source, CHUNK)?,
+            _ => Vec::new(),
+        };
+        // The last chunk is marked, a truncated file does not decrypt
+        if next.is_empty() {
+            return output.write_all(&encryptor.encrypt_last(&chunk[..]).map_err(|_| damaged())?);
+        }
+        output.write_all(&encryptor.encrypt_next(&chunk[..]).map_err(|_| damaged())?)?;
+        chunk = next;
+    }
+}
+
+/// Decrypts the contents of `source` into `output`
+fn decrypt_into(cipher: XChaCha20Poly1305, source: &mut impl Read, output: &mut impl Write) -> io::Result<()> {
+    let header = read_chunk(source, HEADER_LEN as usize)?;
+    if header.len() != HEADER_LEN as usize || !header.starts_with(MAGIC) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not encrypted by fsync",
+        ));
+    }
+    let mut decryptor = DecryptorBE32::from_aead(cipher, header[MAGIC.len()..].into());
+    let sealed = CHUNK + TAG_LEN as usize;
+    let mut chunk = read_chunk(source, sealed)?;
+    loop {
+        let next = match chunk.len() == sealed {
+            true => read_chunk(source, sealed)?,
+            false => Vec::new(),
+        };
+        if next.is_empty() {
+            return output.write_all(&decryptor.decrypt_last(&chunk[..]).map_err(|_| damaged())?);
+        }
+        output.write_all(&decryptor.decrypt_next(&chunk[..]).map_err(|_| damaged())?)?;
+        chunk = next;
+    }
+}
+
+/// Decrypts the file `src` into the file `dst`
+fn decrypt_file(cipher: XChaCha20Poly1305, src: &Path, dst: &Path) -> Result<(), AppError> {
+    let mut source = io::BufReader::new(fs::File::open(src).context("open", src)?);
+    let mut output = io::BufWriter::new(fs::File::create(dst).context("create", dst)?);
+    decrypt_into(cipher, &mut source, &mut output).context("decrypt", src)?;
+    output.flush().context("write", dst)
+}
+
+/// `fsync decrypt <file> <output>`: decrypts the source of `config`, a file
+/// stored at an encrypted destination, into its destination. A directory
+/// is restored with the files below it, under their original paths.
+///
+/// # Errors
+///
+/// [AppError::Backend] is returned without an `[encryption]` section or
+/// with an invalid key, [AppError::IoError] if a file can not be
+/// read, does not decrypt or the output can not be written.
+pub fn decrypt(config: &crate::Config) -> Result<(), AppError> {
+    let Some(encryption) = &config.backends.encryption else {
+        return Err(AppError::Backend(
+            "decrypt requires an [encryption] section in the config file".into(),
+        ));
+    };
+    let key = encryption.key()?;
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    let (src, dst) = (config.source(), config.destination());
+    if !src.is_dir() {
+        return decrypt_file(cipher, src, dst);
+    }
+    let names = encryption.names.then(|| names::NameCipher::new(&key));
+    for entry in walkdir::WalkDir::new(src) {
+        let entry = entry.map_err(io::Error::from).context("read", src)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let stored = entry.path().strip_prefix(src)?;
+        let Some(path) = names.as_ref().map_or(Some(stored.to_path_buf()), |names| {
+            names.original(stored)
+        }) else {
+            tracing::warn!(
+                "{} was not stored by fsync",
+                stored.display()
+            );
+            continue;
+        };
+        let output = dst.join(path);
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent).context("create directory", parent)?;
+        }
+        decrypt_file(cipher.clone(), entry.path(), &output)?;
+    }
+    Ok(())
+}
+
+/// Encrypted copy of a file in the temporary directory, removed when
+/// dropped
+struct Encrypted {
+    /// Path of the copy
+    path: PathBuf,
+}
+
+impl Drop for Encrypted {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Destination storing the files encrypted at another one
+pub(crate) struct EncryptedTarget {
+    /// Destination the encrypted files are stored at
+    inner: Box<dyn SyncTarget>,
+    /// Cipher of the configured key
+    cipher: XChaCha20Poly1305,
+    /// Cipher of the paths with the index of the stored ones, if the paths
+    /// are encrypted
+    names: Option<(names::NameCipher, Mutex<names::Index>)>,
+}
+
+impl EncryptedTarget {
+    /// Encrypts the files stored at `inner` with the key of `config`, the
+    /// stored paths are kept in `index` if they are encrypted too
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if the key can not be looked up or is
+    /// invalid, [AppError::IoError] if the index can not be read or written.
+    pub(crate) fn new(inner: Box<dyn SyncTarget>, config: &EncryptionConfig, index: PathBuf) -> Result<Self, AppError> {
+        let names = match config.names {
+            true => {
+                let names = names::NameCipher::new(&config.key()?);
+                let (mut opened, found) = names::Index::open(&index).context("read", &index)?;
+                if !found {
+                    tracing::info!("rebuilding the index of the encrypted names at {index:?}");
+                    if let Err(err) = opened.rebuild(inner.as_ref(), &names, Path::new("")) {
+                        tracing::warn!("rebuilding the index of the encrypted names: {err}");
+                    }
+                }
+                Some((names, Mutex::new(opened)))
+            }
+            false => None,
+        };
+        Ok(Self {
+            inner,
+            cipher: config.cipher()?,
+            names,
+        })
+    }
+
+    /// Removes the directories left empty by moving or removing the file
+    /// stored at `stored`
+    fn prune(&self, stored: &Path) -> Result<(), AppError> {
+        let head = names::NameCipher::head(stored);
+        if head != stored && self.inner.metadata(&head)?.is_some_and(|meta| meta.is_dir) {
+            self.inner.remove(&head)?;
+        }
+        Ok(())
+    }
+
+    /// Encrypts the file `src` into the temporary directory, with its
+    /// modification time and permissions
+    fn encrypt(&self, src: &Path) -> io::Result<Encrypted> {
+        /// Copies made so far, naming the next one
+        static COPIES: AtomicU64 = AtomicU64::new(0);
+
+        let source = fs::File::open(paths::extended(src))?;
+        let meta = source.metadata()?;
+        let mut source = io::BufReader::new(source);
+        let encrypted = Encrypted {
+            path: std::env::temp_dir().join(format!(
+                "fsync-{}-{}.enc",
+                std::process::id(),
+                COPIES.fetch_add(1, Ordering::Relaxed)
+            )),
+        };
+        let mut output = io::BufWriter::new(fs::OpenOptions::new().write(true).create_new(true).open(&encrypted.path)?);
+        encrypt(
+            self.cipher.clone(),
+            &mut source,
+            &mut output,
+        )?;
+        let file = output.into_inner().map_err(io::IntoInnerError::into_error)?;
+        file.set_modified(meta.modified()?)?;
+        // A read-only copy could not be removed on Windows
+        #[cfg(unix)]
+        file.set_permissions(meta.permissions())?;
+        Ok(encrypted)
+    }
+}
+
+impl SyncTarget for EncryptedTarget {
+    fn describe(&self) -> String {
+        format!("{} (encrypted)", self.inner.describe())
+    }
+
+    fn connect(&self) -> Result<(), AppError> {
+        self.inner.connect()
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError> {
+        let path = match &self.names {
+            Some((names, index)) if !path.as_os_str().is_empty() => {
+                if lock(index).is_dir(path) == Some(true) {
+                    return Ok(Some(TargetMetadata {
+                        is_dir: true,
+                        len: 0,
+                        modified: std::time::SystemTime::UNIX_EPOCH,
+                    }));
+                }
+                &names.stored(path)?
+            }
+            _ => path,
+        };
+        Ok(
+            self.inner.metadata(path)?.map(|meta| match meta.is_dir {
+                true => meta,
+                false => TargetMetadata {
+                    len: original_len(meta.len),
+                    ..meta
+                },
+            }),
+        )
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Option<Vec<std::ffi::OsString>>, AppError> {
+        match &self.names {
+            Some((_, index)) => Ok(Some(lock(index).children(path))),
+            None => self.inner.read_dir(path),
+        }
+    }
+
+    fn available_space(&self) -> Result<Option<u64>, AppError> {
+        self.inner.available_space()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), AppError> {
+        match &self.names {
+            // Directories only exist in the index
+            Some((_, index)) => lock(index).add(path, true),
+            None => self.inner.create_dir_all(path),
+        }
+    }
+
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError> {
+        self.upload_with_progress(src, path, &mut |_| {})
+    }
+
+    fn upload_with_progress(&self, src: &Path, path: &Path, progress: &mut dyn FnMut(u64)) -> Result<(), AppError> {
+        let encrypted = self.encrypt(src).context("encrypt", src)?;
+        let stored = match &self.names {
+            Some((names, _)) => &names.stored(path)?,
+            None => path,
+        };
+        // Progress in bytes of the source
+        self.inner.upload_with_progress(&encrypted.path, stored, &mut |copied| {
+            progress(original_len(copied))
+        })?;
+        match &self.names {
+            Some((_, index)) => lock(index).add(path, false),
+            None => Ok(()),
+        }
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), AppError> {
+        let Some((names, index)) = &self.names else {
+            return self.inner.remove(path);
+        };
+        let mut files = lock(index).files(path);
+        if files.is_empty() {
+            files.push(path.to_path_buf());
+        }
+        for file in files {
+            let stored = names.stored(&file)?;
+            self.inner.remove(&stored)?;
+            self.prune(&stored)?;
+        }
+        lock(index).remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        let Some((names, index)) = &self.names else {
+            return self.inner.rename(from, to);
+        };
+        // Every file below a directory is stored under a name of its own
+        let mut files = lock(index).files(from);
+        // Missing from the index, e.g. stored before it was rebuilt
+        if files.is_empty() && lock(index).is_dir(from).is_none() {
+            files.push(from.to_path_buf());
+        }
+        for file in files {
+            let stored = names.stored(&file)?;
+            let moved = to.join(file.strip_prefix(from)?);
+            self.inner.rename(&stored, &names.stored(&moved)?)?;
+            self.prune(&stored)?;
+        }
+        lock(index).rename(from, to)
+    }
+}
+
+/// Locks the index of the encrypted paths, poisoning is ignored
+fn lock(index: &Mutex<names::Index>) -> std::sync::MutexGuard<'_, names::Index> {
+    index.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesystem::{Fs, MemFs},
+        target::LocalTarget,
+    };
+
+    #[test]
+    fn stores_files_encrypted() {
+        let src = std::env::temp_dir().join(format!(
+            "fsync-encrypt-{}.txt",
+            std::process::id()
+        ));
+        // Spans several chunks, the last one full
+        let text = "secret!\n".repeat(3 * CHUNK / 8);
+        fs::write(&src, &text).unwrap();
+        let config: EncryptionConfig = toml::from_str(&format!("key = {:?}", "1f".repeat(32))).unwrap();
+
+        let memory = MemFs::new();
+        memory.create_dir_all("/dst".as_ref()).unwrap();
+        let target = EncryptedTarget::new(
+            Box::new(LocalTarget::with_fs(
+                "/dst".into(),
+                memory.clone(),
+            )),
+            &config,
+            PathBuf::new(),
+        )
+        .unwrap();
+        target.upload(&src, "a.txt".as_ref()).unwrap();
+        let stored = memory.read("/dst/a.txt").unwrap();
+        assert!(!String::from_utf8_lossy(&stored).contains("secret"));
+        assert_eq!(
+            target.metadata("a.txt".as_ref()).unwrap().unwrap().len,
+            text.len() as u64
+        );
+
+        let mut decrypted = Vec::new();
+        decrypt_into(
+            config.cipher().unwrap(),
+            &mut &stored[..],
+            &mut decrypted,
+        )
+        .unwrap();
+        assert_eq!(decrypted, text.as_bytes());
+        // Truncated at a chunk boundary
+        let truncated = &stored[..stored.len() - CHUNK - TAG_LEN as usize];
+        assert!(decrypt_into(
+            config.cipher().unwrap(),
+            &mut &truncated[..],
+            &mut Vec::new()
+        )
+        .is_err());
+        let other: EncryptionConfig = toml::from_str(&format!("key = {:?}", "2e".repeat(32))).unwrap();
+        assert!(decrypt_into(
+            other.cipher().unwrap(),
+            &mut &stored[..],
+            &mut Vec::new()
+        )
+        .is_err());
+        fs::remove_file(src).unwrap();
+    }
+
+    #[test]
+    fn unchanged_files_are_up_to_date() {
+        let root = std::env::temp_dir().join(format!(
+            "fsync-encrypt-plan-{}",
+            std::process::id()
+        ));
+        let (source, destination) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(source.join("a.txt"), "secret").unwrap();
+        // Written well before it is stored
+        fs::File::options()
+            .write(true)
+            .open(source.join("a.txt"))
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(3600))
+            .unwrap();
+        let mut config = crate::Config::build(source, destination);
+        config.backends.encryption = Some(toml::from_str(&format!("key = {:?}", "1f".repeat(32))).unwrap());
+
+        let app = crate::App::new(config).unwrap();
+        let report = app.apply(&app.plan().unwrap()).unwrap();
+        assert_eq!(report.applied.len(), 1);
+        let plan = app.plan().unwrap();
+        assert_eq!(plan.len(), 0);
+        assert_eq!(plan.unchanged(), 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
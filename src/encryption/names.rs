@@ -0,0 +1,481 @@
+//! Encrypted names of the stored files.
+//!
+//! With `names = true` the path of every file is encrypted as a whole,
+//! deterministically so the same path is always stored under the same name,
+//! and the destination only holds a flat set of random looking names: it
+//! learns neither the names nor how the files are organised. The encrypted
+//! path is spread over directories of [SEGMENT] characters below one of
+//! the shards named after its first two characters, to stay within the
+//! name limits of the filesystems.
+//!
+//! Directories only exist in a local index of the stored paths, a journal
+//! of its changes readable by the user only. It is rebuilt from the
+//! destination when it is lost, if the destination can list its
+//! directories.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    ops::Bound,
+    path::{Component, Path, PathBuf},
+};
+
+use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{AppError, SyncTarget};
+
+/// Digits of the encoded names, lower case only so names differing in case
+/// can not be confused
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Characters of every directory the encoded path is spread over
+const SEGMENT: usize = 128;
+
+/// Characters naming the shard of a path
+const SHARD: usize = 2;
+
+/// Bytes of the synthetic nonce starting an encrypted path
+const NONCE_LEN: usize = 24;
+
+/// Encodes `bytes` as base32 without padding
+fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u16, 0);
+    for &byte in bytes {
+        buffer = buffer << 8 | u16::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(char::from(
+                ALPHABET[usize::from(buffer >> bits & 31)],
+            ));
+        }
+    }
+    if bits > 0 {
+        encoded.push(char::from(
+            ALPHABET[usize::from(buffer << (5 - bits) & 31)],
+        ));
+    }
+    encoded
+}
+
+/// Decodes [encode] output
+fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u16, 0);
+    for digit in encoded.bytes() {
+        let value = ALPHABET.iter().position(|&d| d == digit)?;
+        buffer = buffer << 5 | value as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// Encryption of the paths
+pub(super) struct NameCipher {
+    /// Cipher of the paths
+    cipher: XChaCha20Poly1305,
+    /// Key deriving the nonce of a path from the path
+    nonces: Hmac<Sha256>,
+}
+
+impl NameCipher {
+    /// Cipher of the names derived from the content `key`
+    pub(super) fn new(key: &[u8; 32]) -> Self {
+        let derive = |label: &[u8]| {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("any key length");
+            mac.update(label);
+            mac.finalize().into_bytes()
+        };
+        Self {
+            cipher: XChaCha20Poly1305::new(&derive(b"fsync names")),
+            nonces: <Hmac<Sha256> as Mac>::new_from_slice(&derive(b"fsync name nonces")).expect("any key length"),
+        }
+    }
+
+    /// Path the file `path` is stored at
+    ///
+    /// # Errors
+    ///
+    /// [AppError::PathErr] is returned if `path` is not valid UTF-8.
+    pub(super) fn stored(&self, path: &Path) -> Result<PathBuf, AppError> {
+        let name = path
+            .components()
+            .filter_map(|part| match part {
+                Component::Normal(part) => Some(part.to_str()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| {
+                AppError::PathErr(format!(
+                    "{} is not valid UTF-8",
+                    path.display()
+                ))
+            })?
+            .join("/");
+        let mut nonces = self.nonces.clone();
+        nonces.update(name.as_bytes());
+        let nonce = nonces.finalize().into_bytes();
+        let nonce = XNonce::from_slice(&nonce[..NONCE_LEN]);
+        let sealed = self.cipher.encrypt(nonce, name.as_bytes()).expect("names are short");
+        let encoded = encode(&[nonce.as_slice(), &sealed].concat());
+        let mut stored = PathBuf::from(&encoded[..SHARD]);
+        // Only made of ASCII digits
+        encoded.as_bytes().chunks(SEGMENT).for_each(|segment| {
+            stored.push(std::str::from_utf8(segment).expect("ASCII"));
+        });
+        Ok(stored)
+    }
+
+    /// Path of the file stored at `stored`, none if it was not stored by
+    /// this cipher
+    pub(super) fn original(&self, stored: &Path) -> Option<PathBuf> {
+        let mut parts = stored.iter().map(|part| part.to_str());
+        let shard = parts.next()??;
+        let encoded = parts.collect::<Option<String>>()?;
+        if !encoded.starts_with(shard) || shard.len() != SHARD {
+            return None;
+        }
+        let sealed = decode(&encoded)?;
+        let (nonce, sealed) = sealed.split_at_checked(NONCE_LEN)?;
+        let name = self.cipher.decrypt(XNonce::from_slice(nonce), sealed).ok()?;
+        Some(PathBuf::from(
+            String::from_utf8(name).ok()?,
+        ))
+    }
+
+    /// Entry at the top of the shard holding the file stored at `stored`,
+    /// the file itself or the directories it is spread over
+    pub(super) fn head(stored: &Path) -> PathBuf {
+        stored.iter().take(2).collect()
+    }
+}
+
+/// Change of the [Index], one line of its journal
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    /// The file or directory `path` was stored
+    Add {
+        /// Path of the entry
+        path: PathBuf,
+        /// The entry is a directory
+        dir: bool,
+    },
+    /// The entry `path` was removed, with the ones below it
+    Remove {
+        /// Path of the entry
+        path: PathBuf,
+    },
+    /// The entry `from` was renamed to `to`, with the ones below it
+    Rename {
+        /// Old path
+        from: PathBuf,
+        /// New path
+        to: PathBuf,
+    },
+}
+
+/// Paths stored at the destination, whether they are directories
+#[derive(Debug)]
+pub(super) struct Index {
+    /// Stored paths
+    entries: BTreeMap<PathBuf, bool>,
+    /// Journal the changes are appended to
+    journal: fs::File,
+}
+
+impl Index {
+    /// Loads the index at `path`, compacting its journal, or a new one if
+    /// there is none. The flag tells whether the index was there.
+    ///
+    /// # Errors
+    ///
+    /// Errors reading the index or writing it back are returned.
+    pub(super) fn open(path: &Path) -> io::Result<(Self, bool)> {
+        let mut entries = BTreeMap::new();
+        let found = match fs::File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    match serde_json::from_str(&line?) {
+                        Ok(record) => apply(&mut entries, &record),
+                        Err(err) => tracing::warn!("{path:?}: skipping index entry: {err}"),
+                    }
+                }
+                true
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err),
+        };
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let compacted = path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // The paths are what the encryption hides from the destination
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = io::BufWriter::new(options.open(&compacted)?);
+        for (path, &dir) in &entries {
+            let record = Record::Add { path: path.clone(), dir };
+            writeln!(
+                file,
+                "{}",
+                serde_json::to_string(&record)?
+            )?;
+        }
+        file.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+        fs::rename(&compacted, path)?;
+        let journal = fs::OpenOptions::new().append(true).open(path)?;
+        Ok((Self { entries, journal }, found))
+    }
+
+    /// Whether `path` is a directory, none if it is not stored
+    pub(super) fn is_dir(&self, path: &Path) -> Option<bool> {
+        self.entries.get(path).copied()
+    }
+
+    /// Names of the entries right below the directory `path`
+    pub(super) fn children(&self, path: &Path) -> Vec<OsString> {
+        below(&self.entries, path)
+            .filter(|(entry, _)| entry.parent() == Some(path))
+            .filter_map(|(entry, _)| entry.file_name().map(Into::into))
+            .collect()
+    }
+
+    /// Files stored at `path` or below it
+    pub(super) fn files(&self, path: &Path) -> Vec<PathBuf> {
+        let file = self.entries.get(path).is_some_and(|&dir| !dir).then(|| path.to_path_buf());
+        file.into_iter()
+            .chain(
+                below(&self.entries, path)
+                    .filter(|(_, dir)| !**dir)
+                    .map(|(entry, _)| entry.clone()),
+            )
+            .collect()
+    }
+
+    /// The file or directory `path` was stored
+    ///
+    /// # Errors
+    ///
+    /// Errors writing the journal are returned.
+    pub(super) fn add(&mut self, path: &Path, dir: bool) -> Result<(), AppError> {
+        if path.as_os_str().is_empty() || self.entries.get(path) == Some(&dir) {
+            return Ok(());
+        }
+        self.record(Record::Add {
+            path: path.to_path_buf(),
+            dir,
+        })
+    }
+
+    /// The entry `path` was removed
+    ///
+    /// # Errors
+    ///
+    /// Errors writing the journal are returned.
+    pub(super) fn remove(&mut self, path: &Path) -> Result<(), AppError> {
+        self.record(Record::Remove {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// The entry `from` was renamed to `to`
+    ///
+    /// # Errors
+    ///
+    /// Errors writing the journal are returned.
+    pub(super) fn rename(&mut self, from: &Path, to: &Path) -> Result<(), AppError> {
+        self.record(Record::Rename {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        })
+    }
+
+    /// Applies `record` and appends it to the journal
+    fn record(&mut self, record: Record) -> Result<(), AppError> {
+        apply(&mut self.entries, &record);
+        let line = serde_json::to_string(&record).map_err(io::Error::from)?;
+        Ok(writeln!(self.journal, "{line}")?)
+    }
+
+    /// Adds the files stored at `inner` below `dir`, when the index was lost
+    ///
+    /// # Errors
+    ///
+    /// Errors listing the destination or writing the journal are returned.
+    pub(super) fn rebuild(&mut self, inner: &dyn SyncTarget, names: &NameCipher, dir: &Path) -> Result<(), AppError> {
+        let Some(entries) = inner.read_dir(dir)? else {
+            tracing::warn!(
+                "{} can not list its directories, the index of the encrypted names starts empty",
+                inner.describe()
+            );
+            return Ok(());
+        };
+        for name in entries {
+            let stored = dir.join(name);
+            match inner.metadata(&stored)? {
+                Some(meta) if meta.is_dir => self.rebuild(inner, names, &stored)?,
+                Some(_) => match names.original(&stored) {
+                    Some(path) => self.add(&path, false)?,
+                    None => tracing::warn!(
+                        "{} was not stored by fsync",
+                        stored.display()
+                    ),
+                },
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Entries of `entries` below `path`, which follow it in the order of the
+/// paths
+fn below<'a>(entries: &'a BTreeMap<PathBuf, bool>, path: &'a Path) -> impl Iterator<Item = (&'a PathBuf, &'a bool)> {
+    entries
+        .range::<Path, _>((Bound::Excluded(path), Bound::Unbounded))
+        .take_while(move |(entry, _)| entry.starts_with(path))
+}
+
+/// Applies `record` to `entries`
+fn apply(entries: &mut BTreeMap<PathBuf, bool>, record: &Record) {
+    /// Adds the directories above `path`
+    fn add_parents(entries: &mut BTreeMap<PathBuf, bool>, path: &Path) {
+        for parent in path.ancestors().skip(1).filter(|parent| !parent.as_os_str().is_empty()) {
+            entries.insert(parent.to_path_buf(), true);
+        }
+    }
+
+    match record {
+        Record::Add { path, dir } => {
+            add_parents(entries, path);
+            entries.insert(path.clone(), *dir);
+        }
+        Record::Remove { path } => {
+            let removed: Vec<_> = below(entries, path).map(|(entry, _)| entry.clone()).collect();
+            removed.iter().for_each(|entry| {
+                entries.remove(entry);
+            });
+            entries.remove(path);
+        }
+        Record::Rename { from, to } => {
+            let moved: Vec<_> = below(entries, from).map(|(entry, &dir)| (entry.clone(), dir)).collect();
+            let renamed = entries.remove(from);
+            for (entry, dir) in moved {
+                entries.remove(&entry);
+                if let Ok(relative) = entry.strip_prefix(from) {
+                    entries.insert(to.join(relative), dir);
+                }
+            }
+            if let Some(dir) = renamed {
+                add_parents(entries, to);
+                entries.insert(to.clone(), dir);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encryption::{EncryptedTarget, EncryptionConfig},
+        filesystem::{Fs, MemFs},
+        target::LocalTarget,
+    };
+
+    #[test]
+    fn hides_names_and_structure() {
+        let dir = std::env::temp_dir().join(format!(
+            "fsync-names-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("report.txt");
+        fs::write(&src, "quarterly numbers").unwrap();
+        // Spread over two directories
+        let name = "quarterly-report-of-the-finance-department.txt";
+        let index = dir.join("index.jsonl");
+        let config: EncryptionConfig = toml::from_str(&format!(
+            "key = {:?}\nnames = true\nindex = {:?}",
+            "3c".repeat(32),
+            index.to_str().unwrap()
+        ))
+        .unwrap();
+        let memory = MemFs::new();
+        memory.create_dir_all("/dst".as_ref()).unwrap();
+        let open = || {
+            EncryptedTarget::new(
+                Box::new(LocalTarget::with_fs(
+                    "/dst".into(),
+                    memory.clone(),
+                )),
+                &config,
+                index.clone(),
+            )
+            .unwrap()
+        };
+
+        let target = open();
+        target.create_dir_all("docs/2024".as_ref()).unwrap();
+        target.upload(&src, &Path::new("docs/2024").join(name)).unwrap();
+        let stored = memory.paths();
+        assert!(stored.iter().all(|path| !path.to_string_lossy().contains("docs")));
+        assert_eq!(
+            target.read_dir("docs".as_ref()).unwrap().unwrap(),
+            ["2024"]
+        );
+        target.rename("docs/2024".as_ref(), "archive".as_ref()).unwrap();
+        assert!(target.metadata(&Path::new("docs/2024").join(name)).unwrap().is_none());
+        assert_eq!(
+            target.metadata(&Path::new("archive").join(name)).unwrap().unwrap().len,
+            17
+        );
+
+        // Rebuilt from the destination
+        drop(target);
+        fs::remove_file(&index).unwrap();
+        let target = open();
+        assert_eq!(
+            target.read_dir("archive".as_ref()).unwrap().unwrap(),
+            [name]
+        );
+        target.remove("archive".as_ref()).unwrap();
+        assert!(target.read_dir("".as_ref()).unwrap().unwrap().is_empty());
+        // Only the shards are left
+        assert!(memory.paths().iter().all(|path| path.iter().count() <= 3));
+
+        // Stored without being indexed, e.g. by another index
+        target.upload(&src, "a.txt".as_ref()).unwrap();
+        drop(target);
+        fs::remove_file(&index).unwrap();
+        fs::write(&index, "").unwrap();
+        let target = open();
+        target.rename("a.txt".as_ref(), "b.txt".as_ref()).unwrap();
+        assert_eq!(
+            target.metadata("b.txt".as_ref()).unwrap().unwrap().len,
+            17
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(&index).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+//! Stream of the synchronisation events.
+//!
+//! [App::events](crate::App::events) returns a channel receiving a
+//! [SyncEvent] for every change fsync is about to apply, once it passed the
+//! filters and the debouncing, and for everything it then did with it, so
+//! host applications can build their own UI or reactions without
+//! implementing a [SyncObserver]:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), fsync::AppError> {
+//! use fsync::{App, Config, SyncEvent};
+//!
+//! let mut app = App::new(Config::build("./src".into(), "./dst".into()))?;
+//! let events = app.events();
+//! std::thread::spawn(move || {
+//!     for event in events {
+//!         if let SyncEvent::Failed { source, error, .. } = event {
+//!             eprintln!("{}: {error}", source.display());
+//!         }
+//!     }
+//! });
+//! app.run()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Events are sent as they happen and queue up in the channel until they
+//! are received. The channel closes when the [App](crate::App) is dropped.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+};
+
+use crate::{watcher::Operation, AppError, Batch, SyncObserver};
+
+/// What fsync saw or did.
+///
+/// Destination paths are relative to the destination root.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum SyncEvent {
+    /// A change of the source is about to be applied
+    Changed(Operation),
+    /// The initial synchronisation is about to copy `files` files of
+    /// `bytes` bytes
+    InitialSync {
+        /// Files to copy
+        files: u64,
+        /// Bytes to copy
+        bytes: u64,
+    },
+    /// Part of a file was stored
+    Progress {
+        /// Source path
+        source: PathBuf,
+        /// Destination path
+        destination: PathBuf,
+        /// Bytes stored so far
+        copied: u64,
+        /// Size of the file
+        total: u64,
+    },
+    /// A file was copied or a directory created
+    Copied {
+        /// Source path
+        source: PathBuf,
+        /// Destination path
+        destination: PathBuf,
+        /// Bytes copied
+        bytes: u64,
+    },
+    /// An entry was removed from the destination
+    Removed {
+        /// Source path
+        source: PathBuf,
+        /// Destination path
+        destination: PathBuf,
+    },
+    /// An entry of the destination was renamed
+    Renamed {
+        /// Old source path
+        from: PathBuf,
+        /// New source path
+        to: PathBuf,
+        /// New destination path
+        destination: PathBuf,
+    },
+    /// Applying a change failed
+    Failed {
+        /// `copy`, `remove` or `rename`
+        operation: String,
+        /// Source path
+        source: PathBuf,
+        /// What went wrong
+        error: String,
+    },
+    /// The initial synchronisation finished, or the changes seen by the
+    /// watcher settled
+    BatchComplete(Batch),
+}
+
+/// Observer sending the events to a channel
+pub(crate) struct Events(pub(crate) Sender<SyncEvent>);
+
+impl Events {
+    /// Sends `event`, dropped if nothing receives them any more
+    fn send(&self, event: SyncEvent) {
+        let _ = self.0.send(event);
+    }
+}
+
+impl SyncObserver for Events {
+    fn on_change(&self, change: &Operation) {
+        self.send(SyncEvent::Changed(change.clone()));
+    }
+
+    fn on_copy(&self, source: &Path, destination: &Path, bytes: u64) {
+        self.send(SyncEvent::Copied {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            bytes,
+        });
+    }
+
+    fn on_initial_sync(&self, files: u64, bytes: u64) {
+        self.send(SyncEvent::InitialSync { files, bytes });
+    }
+
+    fn on_progress(&self, source: &Path, destination: &Path, copied: u64, total: u64) {
+        self.send(SyncEvent::Progress {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            copied,
+            total,
+        });
+    }
+
+    fn on_remove(&self, source: &Path, destination: &Path) {
+        self.send(SyncEvent::Removed {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+        });
+    }
+
+    fn on_rename(&self, from: &Path, to: &Path, destination: &Path) {
+        self.send(SyncEvent::Renamed {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            destination: destination.to_path_buf(),
+        });
+    }
+
+    fn on_error(&self, operation: &str, source: &Path, error: &AppError) {
+        self.send(SyncEvent::Failed {
+            operation: operation.into(),
+            source: source.to_path_buf(),
+            error: error.to_string(),
+        });
+    }
+
+    fn on_batch_complete(&self, batch: &Batch) {
+        self.send(SyncEvent::BatchComplete(batch.clone()));
+    }
+}
@@ -0,0 +1,37 @@
+//! Application of the changes to the destination.
+//!
+//! Every change fsync applies, planned by the initial sync or seen by the
+//! watcher, passes through an [Executor] as an [Action] with destination
+//! paths, together with the way fsync applies it: through the destination
+//! with the retries, hooks, statistics and the audit trail. The default
+//! [DirectExecutor] just does that. Embedders can set their own with
+//! [App::set_executor](crate::App::set_executor) to throttle or record the
+//! changes, leave some of them out, or apply them elsewhere.
+//!
+//! The filters, ignore patterns and routes are applied before, an executor
+//! only sees the changes fsync would make.
+
+use crate::{Action, AppError};
+
+/// Performs the changes of the destination
+pub trait Executor: Send + Sync {
+    /// Performs `action`, `apply` applies it the way fsync does.
+    ///
+    /// Copies are retried as a whole with a [RetryConfig](crate::RetryConfig),
+    /// this is called for every attempt.
+    ///
+    /// # Errors
+    ///
+    /// Errors of the change are returned, it counts as failed.
+    fn execute(&self, action: &Action, apply: &mut dyn FnMut() -> Result<(), AppError>) -> Result<(), AppError>;
+}
+
+/// Executor applying every change the way fsync does
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirectExecutor;
+
+impl Executor for DirectExecutor {
+    fn execute(&self, _action: &Action, apply: &mut dyn FnMut() -> Result<(), AppError>) -> Result<(), AppError> {
+        apply()
+    }
+}
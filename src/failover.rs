@@ -0,0 +1,255 @@
+//! Failover to a secondary destination.
+//!
+//! With a `[failover]` section the changes go to the destination while it
+//! is reachable. Once it was unreachable for longer than `after`, fsync
+//! switches to the `secondary` destination and rescans the source to bring
+//! it up to date. The primary destination is checked every
+//! [PROBE_INTERVAL] in the meantime: when it is back, fsync switches to it
+//! again and a rescan reconciles it with the changes it missed.
+//!
+//! ```toml
+//! [failover]
+//! secondary = "/mnt/usb/backup"
+//! after = "5m"
+//! ```
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::{AppError, SyncTarget, TargetMetadata};
+
+/// Time between two checks of the primary destination while failed over
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// `[failover]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct FailoverConfig {
+    /// Destination used while the primary one is unavailable, a path or
+    /// `scheme:location`
+    pub(crate) secondary: PathBuf,
+    /// Time the primary destination has to be unreachable before switching
+    #[serde(default = "FailoverConfig::default_after", with = "crate::config::humantime_serde")]
+    pub(crate) after: Duration,
+}
+
+impl FailoverConfig {
+    /// Default of [FailoverConfig::after]
+    fn default_after() -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+}
+
+/// Destinations of a [FailoverTarget], shared with its probing thread
+struct Destinations {
+    /// Destination used while it is reachable
+    primary: Box<dyn SyncTarget>,
+    /// Destination used while the primary one is not
+    secondary: Box<dyn SyncTarget>,
+    /// Time the primary destination has to be unreachable before switching
+    after: Duration,
+    /// Since when the primary destination is unreachable
+    down_since: Mutex<Option<Instant>>,
+    /// The changes go to the secondary destination
+    failed_over: AtomicBool,
+    /// Asks the [App](crate::App) for a rescan of the destination switched to
+    rescan: Arc<AtomicBool>,
+}
+
+impl Destinations {
+    /// Destination the changes currently go to
+    fn active(&self) -> &dyn SyncTarget {
+        match self.failed_over.load(Ordering::Relaxed) {
+            true => self.secondary.as_ref(),
+            false => self.primary.as_ref(),
+        }
+    }
+
+    /// Switches back to the primary destination if it is reachable again
+    fn probe(&self) {
+        if !self.failed_over.load(Ordering::Relaxed) || self.primary.connect().is_err() {
+            return;
+        }
+        tracing::info!(
+            "{} is reachable again, switching back from {}",
+            self.primary.describe(),
+            self.secondary.describe()
+        );
+        *self.down_since.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        self.failed_over.store(false, Ordering::Relaxed);
+        self.rescan.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Destination failing over to a secondary one
+pub(crate) struct FailoverTarget {
+    /// Destinations, shared with the probing thread
+    shared: Arc<Destinations>,
+}
+
+impl FailoverTarget {
+    /// Stores the changes at `primary`, or at `secondary` while `primary`
+    /// is unavailable. `rescan` is set whenever a rescan has to bring the
+    /// destination switched to up to date.
+    pub(crate) fn new(
+        primary: Box<dyn SyncTarget>,
+        secondary: Box<dyn SyncTarget>,
+        config: &FailoverConfig,
+        rescan: Arc<AtomicBool>,
+    ) -> Self {
+        let shared = Arc::new(Destinations {
+            primary,
+            secondary,
+            after: config.after,
+            down_since: Mutex::default(),
+            failed_over: AtomicBool::new(false),
+            rescan,
+        });
+        // Ends with the last use of the target
+        let probed = Arc::downgrade(&shared);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(PROBE_INTERVAL);
+            let Some(shared) = probed.upgrade() else {
+                break;
+            };
+            shared.probe();
+        });
+        Self { shared }
+    }
+}
+
+impl SyncTarget for FailoverTarget {
+    fn describe(&self) -> String {
+        format!(
+            "{} (failover to {})",
+            self.shared.primary.describe(),
+            self.shared.secondary.describe()
+        )
+    }
+
+    fn connect(&self) -> Result<(), AppError> {
+        let shared = &self.shared;
+        if shared.failed_over.load(Ordering::Relaxed) {
+            return shared.secondary.connect();
+        }
+        let mut down_since = shared.down_since.lock().unwrap_or_else(|e| e.into_inner());
+        let err = match shared.primary.connect() {
+            Ok(()) => {
+                *down_since = None;
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+        if down_since.get_or_insert_with(Instant::now).elapsed() < shared.after {
+            return Err(err);
+        }
+        // Stays on the primary destination if neither is reachable
+        shared.secondary.connect()?;
+        tracing::warn!(
+            "{} unreachable for {}, failing over to {}: {err}",
+            shared.primary.describe(),
+            humantime::format_duration(shared.after),
+            shared.secondary.describe()
+        );
+        shared.failed_over.store(true, Ordering::Relaxed);
+        shared.rescan.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError> {
+        self.shared.active().metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Option<Vec<OsString>>, AppError> {
+        self.shared.active().read_dir(path)
+    }
+
+    fn available_space(&self) -> Result<Option<u64>, AppError> {
+        self.shared.active().available_space()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), AppError> {
+        self.shared.active().create_dir_all(path)
+    }
+
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError> {
+        self.shared.active().upload(src, path)
+    }
+
+    fn upload_with_progress(&self, src: &Path, path: &Path, progress: &mut dyn FnMut(u64)) -> Result<(), AppError> {
+        self.shared.active().upload_with_progress(src, path, progress)
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), AppError> {
+        self.shared.active().remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        self.shared.active().rename(from, to)
+    }
+
+    fn sync_metadata(&self, src: &Path, path: &Path) -> Result<Option<bool>, AppError> {
+        self.shared.active().sync_metadata(src, path)
+    }
+
+    fn checksum(&self, path: &Path) -> Result<Option<[u8; 32]>, AppError> {
+        self.shared.active().checksum(path)
+    }
+
+    fn repair_permissions(&self, src: &Path, path: &Path, config: &crate::PermissionsConfig) -> Result<Option<bool>, AppError> {
+        self.shared.active().repair_permissions(src, path, config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesystem::{Fs, MemFs},
+        target::LocalTarget,
+    };
+
+    #[test]
+    fn fails_over_and_back() {
+        let memory = MemFs::new();
+        memory.create_dir_all("/secondary".as_ref()).unwrap();
+        let rescan = Arc::default();
+        let target = FailoverTarget::new(
+            Box::new(LocalTarget::with_fs(
+                "/primary".into(),
+                memory.clone(),
+            )),
+            Box::new(LocalTarget::with_fs(
+                "/secondary".into(),
+                memory.clone(),
+            )),
+            &FailoverConfig {
+                secondary: "/secondary".into(),
+                after: Duration::ZERO,
+            },
+            Arc::clone(&rescan),
+        );
+
+        target.connect().unwrap();
+        assert!(rescan.swap(false, Ordering::Relaxed));
+        target.create_dir_all("a".as_ref()).unwrap();
+        assert!(memory.is_dir("/secondary/a".as_ref()));
+
+        // Not back yet
+        target.shared.probe();
+        assert!(!rescan.load(Ordering::Relaxed));
+        memory.create_dir_all("/primary".as_ref()).unwrap();
+        target.shared.probe();
+        assert!(rescan.load(Ordering::Relaxed));
+        target.create_dir_all("b".as_ref()).unwrap();
+        assert!(memory.is_dir("/primary/b".as_ref()));
+    }
+}
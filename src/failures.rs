@@ -0,0 +1,85 @@
+//! Failures collected during a synchronisation.
+//!
+//! [App::sync_once](crate::App::sync_once) and [App::run](crate::App::run)
+//! return an [ErrorReport] listing every change which could not be applied,
+//! so embedding applications do not have to scrape the logs. It prints one
+//! failure per line and serializes with serde, e.g. to JSON.
+
+use std::{fmt, path::PathBuf};
+
+use serde::Serialize;
+
+/// Failures kept by an [ErrorReport], later ones are only counted
+const MAX_FAILURES: usize = 1000;
+
+/// Change which could not be applied
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct Failure {
+    /// `copy`, `remove`, `rename`, `sync` for a failed comparison with the
+    /// destination, `metadata` for a failed `--metadata-only` update or
+    /// `permissions` for a failed `fsync repair-perms` one
+    pub operation: &'static str,
+    /// Source path, the new one of renames
+    pub path: PathBuf,
+    /// Error message with its causes
+    pub error: String,
+    /// Attempts made, more than one if the error was retried
+    pub attempts: u32,
+}
+
+/// Failures of a synchronisation pass, or of a whole run
+#[derive(Debug, Default, Clone, Serialize)]
+#[non_exhaustive]
+pub struct ErrorReport {
+    /// Failures in the order they happened, at most 1000
+    pub failures: Vec<Failure>,
+    /// Failures not kept because there were too many
+    pub omitted: usize,
+}
+
+impl ErrorReport {
+    /// Nothing failed
+    pub fn is_empty(&self) -> bool {
+        self.failures.is_empty() && self.omitted == 0
+    }
+
+    /// Number of failures, the omitted ones included
+    pub fn len(&self) -> usize {
+        self.failures.len() + self.omitted
+    }
+
+    /// Adds a failure
+    pub(crate) fn push(&mut self, failure: Failure) {
+        if self.failures.len() < MAX_FAILURES {
+            self.failures.push(failure);
+        } else {
+            self.omitted += 1;
+        }
+    }
+
+    /// Adds the failures of `other` after the ones of `self`
+    pub(crate) fn append(&mut self, other: ErrorReport) {
+        for failure in other.failures {
+            self.push(failure);
+        }
+        self.omitted += other.omitted;
+    }
+}
+
+impl fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failures", self.len())?;
+        for failure in &self.failures {
+            write!(
+                f,
+                "\n  {} (attempts: {})",
+                failure.error, failure.attempts
+            )?;
+        }
+        if self.omitted > 0 {
+            write!(f, "\n  ... and {} more", self.omitted)?;
+        }
+        Ok(())
+    }
+}
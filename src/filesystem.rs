@@ -0,0 +1,464 @@
+//! Filesystems of the local destinations.
+//!
+//! [LocalTarget](crate::target::LocalTarget) stores the files through an
+//! [Fs], the disk with [RealFs]. Built with the `test-util` feature,
+//! `MemFs` keeps them in memory instead, which lets the synchronisation be
+//! tested or simulated without writing to the disk.
+//!
+//! Paths are absolute. The source is always read from the disk.
+
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, ErrorKind, Read},
+    path::Path,
+};
+
+use crate::{app::Context, AppError, TargetMetadata};
+
+#[cfg(any(test, feature = "test-util"))]
+mod memory;
+#[cfg(any(test, feature = "test-util"))]
+pub use memory::MemFs;
+
+/// Operations of a destination filesystem
+pub trait Fs: Send + Sync {
+    /// Metadata of the entry at `path`, following symbolic links, none if
+    /// there is no such entry
+    ///
+    /// # Errors
+    ///
+    /// Errors querying the entry are returned.
+    fn metadata(&self, path: &Path) -> io::Result<Option<TargetMetadata>>;
+
+    /// Names of the entries of the directory `path`
+    ///
+    /// # Errors
+    ///
+    /// Errors reading the directory are returned.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+
+    /// Opens the file `path` for reading
+    ///
+    /// # Errors
+    ///
+    /// Errors opening the file are returned.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Creates the directory `path` with its missing parents
+    ///
+    /// # Errors
+    ///
+    /// Errors creating a directory are returned.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Copies the contents of `source` to the file `path`, replacing it and
+    /// creating its missing parents, `progress` is called with the bytes
+    /// copied so far after every chunk. The file keeps the modification
+    /// time of `source`, which tells the initial sync it is up to date.
+    ///
+    /// # Errors
+    ///
+    /// Errors reading `source` or writing the file are returned.
+    fn copy(&self, source: &mut fs::File, path: &Path, progress: &mut dyn FnMut(u64)) -> io::Result<()>;
+
+    /// Removes the file, symbolic link or directory tree at `path`
+    ///
+    /// # Errors
+    ///
+    /// [ErrorKind::NotFound] is returned if there is no such entry, and the
+    /// errors removing it.
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Renames the entry `from` to `to`, creating the missing parents of `to`
+    ///
+    /// # Errors
+    ///
+    /// Errors of the rename are returned.
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError>;
+
+    /// Bytes available at `path`, none if unknown
+    ///
+    /// # Errors
+    ///
+    /// Errors querying the filesystem are returned.
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        let _ = path;
+        Ok(None)
+    }
+
+    /// Gives the file `path` the modification time, permissions and
+    /// extended attributes of the local file `source`, returns whether
+    /// anything changed, none if the filesystem can not
+    ///
+    /// # Errors
+    ///
+    /// Errors reading or writing the metadata are returned.
+    fn sync_metadata(&self, source: &Path, path: &Path) -> io::Result<Option<bool>> {
+        let _ = (source, path);
+        Ok(None)
+    }
+
+    /// Gives the entry `path` the permissions and the owner of the local
+    /// entry `source`, or the modes of `config`, returns whether anything
+    /// changed, none if the filesystem has no permissions
+    ///
+    /// # Errors
+    ///
+    /// Errors reading or changing the permissions are returned.
+    fn repair_permissions(&self, source: &Path, path: &Path, config: &crate::PermissionsConfig) -> io::Result<Option<bool>> {
+        let _ = (source, path, config);
+        Ok(None)
+    }
+}
+
+/// Filesystem of the disk
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl Fs for RealFs {
+    fn metadata(&self, path: &Path) -> io::Result<Option<TargetMetadata>> {
+        match fs::metadata(path) {
+            Ok(meta) => Ok(Some(TargetMetadata {
+                is_dir: meta.is_dir(),
+                len: meta.len(),
+                modified: meta.modified()?,
+            })),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        fs::read_dir(path)?.map(|entry| Ok(entry?.file_name())).collect()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn copy(&self, source: &mut fs::File, path: &Path, progress: &mut dyn FnMut(u64)) -> io::Result<()> {
+        let mut file = match fs::File::create(path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let parent = path
+                    .parent()
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no parent"))?;
+                fs::create_dir_all(parent)?;
+                fs::File::create(path)?
+            }
+            result => result?,
+        };
+        crate::target::copy_chunked(source, &mut file, progress)?;
+        let meta = source.metadata()?;
+        file.set_modified(meta.modified()?)?;
+        // Same as fs::copy
+        file.set_permissions(meta.permissions())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        // Symbolic links are removed, not followed
+        if fs::symlink_metadata(path)?.is_dir() {
+            tracing::debug!("IS DIRECTORY: {path:?}");
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        let renamed = match fs::rename(from, to) {
+            // Moved into a directory which is not synchronised yet
+            Err(err) if err.kind() == ErrorKind::NotFound && fs::symlink_metadata(from).is_ok() => {
+                if let Some(parent) = to.parent() {
+                    fs::create_dir_all(parent).context("create directory", parent)?;
+                }
+                fs::rename(from, to)
+            }
+            result => result,
+        };
+        match renamed {
+            // The destination spans several filesystems, e.g. bind mounts
+            Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+                tracing::debug!("moving {from:?} to {to:?} across filesystems");
+                move_across(from, to)
+            }
+            result => result.map_err(|e| AppError::from(e).context_to("rename", from, to)),
+        }
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        free_space(path).map(Some)
+    }
+
+    fn sync_metadata(&self, source: &Path, path: &Path) -> io::Result<Option<bool>> {
+        let (meta, stored) = (
+            fs::metadata(source)?,
+            fs::metadata(path)?,
+        );
+        let mut changed = xattr::sync(source, path)?;
+        // Before the permissions, which may make the file read-only
+        if stored.modified()? != meta.modified()? {
+            open_times(path)?.set_modified(meta.modified()?)?;
+            changed = true;
+        }
+        if stored.permissions() != meta.permissions() {
+            fs::set_permissions(path, meta.permissions())?;
+            changed = true;
+        }
+        Ok(Some(changed))
+    }
+
+    fn repair_permissions(&self, source: &Path, path: &Path, config: &crate::PermissionsConfig) -> io::Result<Option<bool>> {
+        crate::permissions::repair(source, path, config).map(Some)
+    }
+}
+
+/// Opens the file `path` to set its times, which only takes owning it
+#[cfg(not(windows))]
+fn open_times(path: &Path) -> io::Result<fs::File> {
+    fs::File::open(path)
+}
+
+/// Opens the file `path` to set its times, also if it is read-only
+#[cfg(windows)]
+fn open_times(path: &Path) -> io::Result<fs::File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    use windows_sys::Win32::Storage::FileSystem::FILE_WRITE_ATTRIBUTES;
+
+    fs::File::options().access_mode(FILE_WRITE_ATTRIBUTES).open(path)
+}
+
+/// `user.` extended attributes of Linux files
+#[cfg(target_os = "linux")]
+mod xattr {
+    use std::{
+        collections::BTreeMap,
+        ffi::{CStr, CString},
+        io,
+        os::unix::ffi::OsStrExt,
+        path::Path,
+    };
+
+    /// Namespace of the synchronised attributes, the others need privileges
+    const NAMESPACE: &[u8] = b"user.";
+
+    /// `path` as a C string
+    fn c_path(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Calls `f` with a buffer grown until the value fits, `f` returns the
+    /// length of the value or -1 with `errno` set
+    fn read(mut f: impl FnMut(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+        loop {
+            let len = f(std::ptr::null_mut(), 0);
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buf = vec![0; len as usize];
+            match f(buf.as_mut_ptr(), buf.len()) {
+                // Grew in between
+                -1 if io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE) => continue,
+                -1 => return Err(io::Error::last_os_error()),
+                len => {
+                    buf.truncate(len as usize);
+                    return Ok(buf);
+                }
+            }
+        }
+    }
+
+    /// `user.` attributes of `path` with their values, none if the
+    /// filesystem has no extended attributes
+    fn list(path: &CStr) -> io::Result<BTreeMap<CString, Vec<u8>>> {
+        // SAFETY: `path` is NUL terminated, `buf` has `len` bytes
+        let names = match read(|buf, len| unsafe { libc::llistxattr(path.as_ptr(), buf.cast(), len) }) {
+            Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => return Ok(BTreeMap::new()),
+            result => result?,
+        };
+        let mut attributes = BTreeMap::new();
+        for name in names.split(|&byte| byte == 0).filter(|name| name.starts_with(NAMESPACE)) {
+            let name = CString::new(name).expect("split at the NUL bytes");
+            // SAFETY: `path` and `name` are NUL terminated, `buf` has `len` bytes
+            let value = read(|buf, len| unsafe {
+                libc::lgetxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    buf.cast(),
+                    len,
+                )
+            })?;
+            attributes.insert(name, value);
+        }
+        Ok(attributes)
+    }
+
+    /// Gives `path` the `user.` attributes of `source`, returns whether
+    /// any changed
+    pub(super) fn sync(source: &Path, path: &Path) -> io::Result<bool> {
+        let (source, path) = (c_path(source)?, c_path(path)?);
+        let (wanted, stored) = (list(&source)?, list(&path)?);
+        let mut changed = false;
+        for (name, value) in &wanted {
+            if stored.get(name) == Some(value) {
+                continue;
+            }
+            // SAFETY: `path` and `name` are NUL terminated, `value` has its length
+            if unsafe {
+                libc::lsetxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                )
+            } != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            changed = true;
+        }
+        for name in stored.keys().filter(|name| !wanted.contains_key(*name)) {
+            // SAFETY: `path` and `name` are NUL terminated
+            if unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            changed = true;
+        }
+        Ok(changed)
+    }
+}
+
+/// Extended attributes are only synchronised on Linux
+#[cfg(not(target_os = "linux"))]
+mod xattr {
+    use std::{io, path::Path};
+
+    /// Nothing to synchronise
+    pub(super) fn sync(_source: &Path, _path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem of `path`
+#[cfg(unix)]
+fn free_space(path: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    // SAFETY: statvfs only writes to the zeroed struct
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a NUL terminated string
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Bytes available to the user on the volume of `path`
+#[cfg(windows)]
+fn free_space(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let path = path.as_os_str().encode_wide().chain([0]).collect::<Vec<_>>();
+    let mut available = 0;
+    // SAFETY: `path` is NUL terminated, the other counts are optional
+    if unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    } == 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+/// Copies `from` with its contents to `to` and removes it, for renames
+/// between filesystems
+fn move_across(from: &Path, to: &Path) -> Result<(), AppError> {
+    for entry in walkdir::WalkDir::new(from) {
+        let entry = entry.map_err(io::Error::from).context("read", from)?;
+        let relative = entry.path().strip_prefix(from)?;
+        let target = if relative.as_os_str().is_empty() {
+            to.to_path_buf()
+        } else {
+            to.join(relative)
+        };
+        let kind = entry.file_type();
+        if kind.is_dir() {
+            fs::create_dir_all(&target).context("create directory", &target)?;
+        } else if kind.is_symlink() {
+            copy_link(entry.path(), &target)?;
+        } else {
+            // Keeps the permissions
+            fs::copy(entry.path(), &target).map_err(|e| AppError::from(e).context_to("copy", entry.path(), &target))?;
+        }
+    }
+    if fs::symlink_metadata(from).context("read metadata", from)?.is_dir() {
+        fs::remove_dir_all(from).context("remove directory", from)
+    } else {
+        fs::remove_file(from).context("remove", from)
+    }
+}
+
+/// Recreates the symbolic link `link` at `target`
+fn copy_link(link: &Path, target: &Path) -> Result<(), AppError> {
+    #[cfg(unix)]
+    {
+        let points_to = fs::read_link(link).context("read link", link)?;
+        std::os::unix::fs::symlink(points_to, target).context("create link", target)
+    }
+    #[cfg(not(unix))]
+    {
+        tracing::warn!(
+            "skipping the symbolic link {} moved across filesystems",
+            link.display()
+        );
+        let _ = target;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_trees_by_copying() {
+        let root = std::env::temp_dir().join(format!(
+            "fsync-move-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(root.join("from/a")).unwrap();
+        fs::write(root.join("from/a/b.txt"), "b").unwrap();
+        fs::write(root.join("file.txt"), "f").unwrap();
+
+        move_across(&root.join("from"), &root.join("to")).unwrap();
+        move_across(
+            &root.join("file.txt"),
+            &root.join("to/file.txt"),
+        )
+        .unwrap();
+        assert!(!root.join("from").exists());
+        assert!(!root.join("file.txt").exists());
+        assert_eq!(
+            fs::read(root.join("to/a/b.txt")).unwrap(),
+            b"b"
+        );
+        assert_eq!(
+            fs::read(root.join("to/file.txt")).unwrap(),
+            b"f"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+//! Filesystem in memory
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt, fs,
+    io::{self, ErrorKind, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use super::Fs;
+use crate::{AppError, TargetMetadata};
+
+/// Entry of a [MemFs]
+#[derive(Debug, Clone)]
+enum Node {
+    /// Directory
+    Dir {
+        /// Last modification time
+        modified: SystemTime,
+    },
+    /// File
+    File {
+        /// Contents
+        contents: Vec<u8>,
+        /// Last modification time
+        modified: SystemTime,
+    },
+}
+
+/// Filesystem in memory.
+///
+/// Clones share the same entries, the root directory always exists.
+///
+/// ```
+/// # fn main() -> Result<(), fsync::AppError> {
+/// use fsync::{filesystem::MemFs, target::LocalTarget, SyncTarget};
+///
+/// let fs = MemFs::new();
+/// let target = LocalTarget::with_fs("/dst".into(), fs.clone());
+/// target.create_dir_all("docs".as_ref())?;
+/// assert!(fs.is_dir("/dst/docs".as_ref()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct MemFs {
+    /// Entries by their path
+    nodes: Arc<Mutex<BTreeMap<PathBuf, Node>>>,
+}
+
+impl fmt::Debug for MemFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.nodes().keys()).finish()
+    }
+}
+
+/// [ErrorKind::NotFound] error for `path`
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+impl MemFs {
+    /// Empty filesystem
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Entries, even if a thread panicked while holding them
+    fn nodes(&self) -> std::sync::MutexGuard<'_, BTreeMap<PathBuf, Node>> {
+        self.nodes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes `contents` to the file `path`, creating its parents
+    pub fn write(&self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        let path = path.as_ref();
+        let mut nodes = self.nodes();
+        Self::create_parents(&mut nodes, path);
+        nodes.insert(
+            path.to_path_buf(),
+            Node::File {
+                contents: contents.into(),
+                modified: SystemTime::now(),
+            },
+        );
+    }
+
+    /// Contents of the file `path`, none if it is not a file
+    pub fn read(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        match self.nodes().get(path.as_ref()) {
+            Some(Node::File { contents, .. }) => Some(contents.clone()),
+            _ => None,
+        }
+    }
+
+    /// Whether `path` is a directory
+    pub fn is_dir(&self, path: &Path) -> bool {
+        path.parent().is_none()
+            || matches!(
+                self.nodes().get(path),
+                Some(Node::Dir { .. })
+            )
+    }
+
+    /// Sets the modification time of the entry `path`, returns whether it
+    /// exists
+    pub fn set_modified(&self, path: impl AsRef<Path>, time: SystemTime) -> bool {
+        match self.nodes().get_mut(path.as_ref()) {
+            Some(Node::Dir { modified } | Node::File { modified, .. }) => {
+                *modified = time;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Paths of all entries, in order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.nodes().keys().cloned().collect()
+    }
+
+    /// Creates the missing directories above `path`
+    fn create_parents(nodes: &mut BTreeMap<PathBuf, Node>, path: &Path) {
+        for parent in path.ancestors().skip(1).filter(|parent| parent.parent().is_some()) {
+            nodes.entry(parent.to_path_buf()).or_insert(Node::Dir {
+                modified: SystemTime::now(),
+            });
+        }
+    }
+}
+
+impl Fs for MemFs {
+    fn metadata(&self, path: &Path) -> io::Result<Option<TargetMetadata>> {
+        if path.parent().is_none() {
+            return Ok(Some(TargetMetadata {
+                is_dir: true,
+                len: 0,
+                modified: SystemTime::UNIX_EPOCH,
+            }));
+        }
+        Ok(
+            self.nodes().get(path).map(|node| match node {
+                Node::Dir { modified } => TargetMetadata {
+                    is_dir: true,
+                    len: 0,
+                    modified: *modified,
+                },
+                Node::File { contents, modified } => TargetMetadata {
+                    is_dir: false,
+                    len: contents.len() as u64,
+                    modified: *modified,
+                },
+            }),
+        )
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        if !self.is_dir(path) {
+            return Err(not_found(path));
+        }
+        Ok(self
+            .nodes()
+            .keys()
+            .filter(|entry| entry.parent() == Some(path))
+            .filter_map(|entry| entry.file_name().map(ToOwned::to_owned))
+            .collect())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let contents = self.read(path).ok_or_else(|| not_found(path))?;
+        Ok(Box::new(io::Cursor::new(contents)))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes();
+        if let Some(Node::File { .. }) = nodes.get(path) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} is a file", path.display()),
+            ));
+        }
+        Self::create_parents(&mut nodes, path);
+        if path.parent().is_some() {
+            nodes.entry(path.to_path_buf()).or_insert(Node::Dir {
+                modified: SystemTime::now(),
+            });
+        }
+        Ok(())
+    }
+
+    fn copy(&self, source: &mut fs::File, path: &Path, progress: &mut dyn FnMut(u64)) -> io::Result<()> {
+        let mut contents = Vec::new();
+        source.read_to_end(&mut contents)?;
+        progress(contents.len() as u64);
+        self.write(path, contents);
+        self.set_modified(path, source.metadata()?.modified()?);
+        Ok(())
+    }
+
+    fn sync_metadata(&self, source: &Path, path: &Path) -> io::Result<Option<bool>> {
+        let modified = fs::metadata(source)?.modified()?;
+        match self.metadata(path)? {
+            Some(meta) if meta.modified == modified => Ok(Some(false)),
+            Some(_) => Ok(Some(self.set_modified(path, modified))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes();
+        if nodes.remove(path).is_none() {
+            return Err(not_found(path));
+        }
+        nodes.retain(|entry, _| !entry.starts_with(path));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        let mut nodes = self.nodes();
+        if !nodes.contains_key(from) {
+            return Err(AppError::from(not_found(from)).context_to("rename", from, to));
+        }
+        let moved: Vec<_> = nodes.keys().filter(|entry| entry.starts_with(from)).cloned().collect();
+        nodes.retain(|entry, _| !entry.starts_with(to));
+        Self::create_parents(&mut nodes, to);
+        for entry in moved {
+            let node = nodes.remove(&entry).expect("listed above");
+            let relative = entry.strip_prefix(from)?;
+            nodes.insert(
+                match relative.as_os_str().is_empty() {
+                    true => to.to_path_buf(),
+                    false => to.join(relative),
+                },
+                node,
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_trees_in_memory() {
+        let fs = MemFs::new();
+        fs.write("/dst/a/b.txt", "b");
+        fs.create_dir_all("/dst/c".as_ref()).unwrap();
+        assert_eq!(
+            fs.read_dir("/dst".as_ref()).unwrap(),
+            ["a", "c"]
+        );
+        assert!(fs.metadata("/dst/a".as_ref()).unwrap().is_some_and(|meta| meta.is_dir));
+
+        fs.rename("/dst/a".as_ref(), "/dst/c/d".as_ref()).unwrap();
+        assert_eq!(
+            fs.read("/dst/c/d/b.txt").as_deref(),
+            Some(&b"b"[..])
+        );
+        assert!(fs.metadata("/dst/a/b.txt".as_ref()).unwrap().is_none());
+
+        fs.remove("/dst/c".as_ref()).unwrap();
+        assert_eq!(fs.paths(), [PathBuf::from("/dst")]);
+        assert_eq!(
+            fs.remove("/dst/c".as_ref()).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Filters of applications embedding fsync.
+//!
+//! A [PathFilter] registered with [App::add_filter](crate::App::add_filter)
+//! is asked before every copy, removal and rename, so an application can
+//! keep changes from the destination by its own rules, e.g. a database
+//! lookup. Closures taking the operation, the source and the destination
+//! path are filters, a [FilterChain] combines several:
+//!
+//! ```
+//! use std::path::Path;
+//! use fsync::{FilterChain, PathFilter};
+//!
+//! let chain = FilterChain::new()
+//!     .with(|_operation: &str, source: &Path, _destination: &Path| {
+//!         source.extension().is_none_or(|ext| ext != "tmp")
+//!     })
+//!     .with(|operation: &str, _source: &Path, _destination: &Path| operation != "remove");
+//! assert!(chain.allows("copy", Path::new("/src/a.txt"), Path::new("a.txt")));
+//! assert!(!chain.allows("copy", Path::new("/src/a.tmp"), Path::new("a.tmp")));
+//! assert!(!chain.allows("remove", Path::new("/src/a.txt"), Path::new("a.txt")));
+//! ```
+//!
+//! Filters run on the thread applying the changes, before the change is
+//! logged or counted; rejected changes are counted as skipped.
+
+use std::{fmt, path::Path};
+
+/// Predicate deciding whether a change of the source is applied.
+pub trait PathFilter: Send + Sync {
+    /// Whether `operation` (`copy`, `remove` or `rename`) of `source` is
+    /// applied to `destination`, both paths being the new ones of renames.
+    /// Destination paths are relative to the destination root.
+    fn allows(&self, operation: &str, source: &Path, destination: &Path) -> bool;
+}
+
+impl<F> PathFilter for F
+where
+    F: Fn(&str, &Path, &Path) -> bool + Send + Sync,
+{
+    fn allows(&self, operation: &str, source: &Path, destination: &Path) -> bool {
+        self(operation, source, destination)
+    }
+}
+
+/// Filters which all have to allow a change, asked in the order they were
+/// added until one rejects it
+#[derive(Default)]
+pub struct FilterChain {
+    /// Filters in the order they are asked
+    filters: Vec<Box<dyn PathFilter>>,
+}
+
+impl FilterChain {
+    /// Chain allowing every change
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The chain with `filter` asked last
+    pub fn with(mut self, filter: impl PathFilter + 'static) -> Self {
+        self.push(filter);
+        self
+    }
+
+    /// Adds `filter`, asked after the ones added before
+    pub fn push(&mut self, filter: impl PathFilter + 'static) {
+        self.filters.push(Box::new(filter));
+    }
+
+    /// No filters were added
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
+
+impl PathFilter for FilterChain {
+    fn allows(&self, operation: &str, source: &Path, destination: &Path) -> bool {
+        self.filters
+            .iter()
+            .all(|filter| filter.allows(operation, source, destination))
+    }
+}
+
+impl fmt::Debug for FilterChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FilterChain").field("filters", &self.filters.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[test]
+    fn stops_at_the_first_rejection() {
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counted = asked.clone();
+        let chain = FilterChain::new()
+            .with(|_: &str, source: &Path, _: &Path| source.starts_with("/src/keep"))
+            .with(move |_: &str, _: &Path, _: &Path| {
+                counted.fetch_add(1, Ordering::Relaxed);
+                true
+            });
+        assert!(chain.allows(
+            "copy",
+            Path::new("/src/keep/a"),
+            Path::new("keep/a")
+        ));
+        assert!(!chain.allows(
+            "copy",
+            Path::new("/src/other/a"),
+            Path::new("other/a")
+        ));
+        assert_eq!(asked.load(Ordering::Relaxed), 1);
+        assert!(FilterChain::new().allows(
+            "remove",
+            Path::new("/src/a"),
+            Path::new("a")
+        ));
+    }
+}
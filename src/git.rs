@@ -0,0 +1,264 @@
+//! Git history of the destination.
+//!
+//! With a `[git]` section the destination directory is a git repository,
+//! initialised on the first commit if it is not one yet. The changes of
+//! the initial synchronisation and of every batch the watcher applied,
+//! once they settled, are staged and committed with a message listing
+//! them, so every state of the mirror can be looked up and restored with
+//! git. Only local destinations can be committed.
+//!
+//! The `.git` directories of the source, at any depth, are not synchronised:
+//! they would replace the repository of the destination, or run the hooks
+//! and commands of their configuration when it is committed.
+//!
+//! ```toml
+//! [git]
+//! # binary = "/usr/bin/git"
+//! # author = "fsync <fsync@localhost>"
+//! ```
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use serde::Deserialize;
+
+use crate::{hooks::Batch, AppError};
+
+/// Paths listed in a commit message, the others are counted
+const MAX_LISTED: usize = 100;
+
+/// `[git]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct GitConfig {
+    /// git executable
+    #[serde(default = "GitConfig::default_binary")]
+    pub(crate) binary: PathBuf,
+    /// Author and committer of the commits, `Name <email>`
+    #[serde(default = "GitConfig::default_author")]
+    pub(crate) author: String,
+}
+
+impl GitConfig {
+    /// Default of [GitConfig::binary]: `git` from `PATH`
+    fn default_binary() -> PathBuf {
+        PathBuf::from("git")
+    }
+
+    /// Default of [GitConfig::author]
+    fn default_author() -> String {
+        "fsync <fsync@localhost>".into()
+    }
+}
+
+/// Commits the batches applied to a destination repository
+#[derive(Debug)]
+pub(crate) struct Git {
+    /// git executable
+    binary: PathBuf,
+    /// Name and email of the author
+    author: (String, String),
+    /// Destination directory, the work tree
+    dir: PathBuf,
+}
+
+impl Git {
+    /// Commits the changes of the destination directory `dir`
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if `dir` is not a local directory or
+    /// the author is not in the `Name <email>` form.
+    pub(crate) fn new(config: &GitConfig, dir: &Path) -> Result<Self, AppError> {
+        if crate::target::split_scheme(dir).is_some() {
+            return Err(AppError::Backend(format!(
+                "git can only commit a local destination, not {}",
+                dir.display()
+            )));
+        }
+        let author = config
+            .author
+            .split_once('<')
+            .and_then(|(name, email)| {
+                Some((
+                    name.trim(),
+                    email.trim().strip_suffix('>')?,
+                ))
+            })
+            .filter(|(name, email)| !name.is_empty() && !email.is_empty())
+            .ok_or_else(|| {
+                AppError::Backend(format!(
+                    "git author {:?} is not in the `Name <email>` form",
+                    config.author
+                ))
+            })?;
+        Ok(Self {
+            binary: config.binary.clone(),
+            author: (author.0.to_owned(), author.1.to_owned()),
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Runs git in the destination directory as the author
+    fn run<I, S>(&self, args: I) -> Result<Output, AppError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut command = Command::new(&self.binary);
+        command
+            .arg("-C")
+            .arg(&self.dir)
+            .arg("-c")
+            .arg(format!("user.name={}", self.author.0))
+            .arg("-c")
+            .arg(format!("user.email={}", self.author.1))
+            .args(args);
+        tracing::trace!("git: {command:?}");
+        Ok(command.output()?)
+    }
+
+    /// Runs git and turns a non-zero exit status into an error
+    fn check<I, S>(&self, args: I) -> Result<Output, AppError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let output = self.run(args)?;
+        if !output.status.success() {
+            return Err(AppError::Backend(format!(
+                "git {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(output)
+    }
+
+    /// Stages everything and commits it with a message listing `batch`,
+    /// nothing is committed if the tree did not change
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if git fails, [AppError::IoError] if
+    /// it can not be run.
+    pub(crate) fn commit(&self, batch: &Batch) -> Result<(), AppError> {
+        if !self.dir.join(".git").exists() {
+            tracing::info!(
+                "initialising a git repository in {:?}",
+                self.dir
+            );
+            self.check(["init", "--quiet"])?;
+        }
+        self.check(["add", "--all"])?;
+        // Exits with 1 if something is staged
+        if self.run(["diff", "--cached", "--quiet"])?.status.success() {
+            return Ok(());
+        }
+        let message = message(batch);
+        self.check(["commit", "--quiet", "--no-verify", "--message", &message])?;
+        tracing::info!(
+            "committed: {}",
+            message.lines().next().unwrap_or_default()
+        );
+        Ok(())
+    }
+}
+
+/// Whether `path` is in a `.git` directory below the source `root`
+pub(crate) fn in_repository(root: &Path, path: &Path) -> bool {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .any(|component| component.as_os_str() == ".git")
+}
+
+/// Commit message of `batch`: the counts, then the changed paths
+fn message(batch: &Batch) -> String {
+    let mut counts = Vec::new();
+    for (count, what) in [
+        (batch.copied, "copied"),
+        (batch.removed, "removed"),
+        (batch.renamed, "renamed"),
+    ] {
+        if count > 0 {
+            counts.push(format!("{count} {what}"));
+        }
+    }
+    let mut message = match counts.is_empty() {
+        true => "fsync: synchronised".to_owned(),
+        false => format!("fsync: {}", counts.join(", ")),
+    };
+    if !batch.paths.is_empty() {
+        message.push('\n');
+    }
+    for path in batch.paths.iter().take(MAX_LISTED) {
+        message.push_str(&format!("\n{}", path.display()));
+    }
+    if batch.paths.len() > MAX_LISTED {
+        message.push_str(&format!(
+            "\n... and {} more",
+            batch.paths.len() - MAX_LISTED
+        ));
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commits_the_changes_of_a_batch() {
+        if Command::new("git").arg("--version").output().is_err() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!(
+            "fsync-git-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = GitConfig {
+            binary: GitConfig::default_binary(),
+            author: "Mirror <mirror@example.org>".into(),
+        };
+        assert!(Git::new(
+            &GitConfig {
+                author: "mirror".into(),
+                ..config.clone()
+            },
+            &dir
+        )
+        .is_err());
+        let git = Git::new(&config, &dir).unwrap();
+
+        std::fs::write(dir.join("a.conf"), "a = 1").unwrap();
+        let batch = Batch {
+            copied: 1,
+            paths: vec!["a.conf".into()],
+            ..Batch::default()
+        };
+        git.commit(&batch).unwrap();
+        // Nothing changed since
+        git.commit(&batch).unwrap();
+        let log = git.check(["log", "--format=%an %s%n%b"]).unwrap();
+        assert_eq!(
+            String::from_utf8(log.stdout).unwrap().trim(),
+            "Mirror fsync: 1 copied\na.conf"
+        );
+
+        let root = Path::new("/src/.git");
+        assert!(in_repository(
+            root,
+            &root.join("nested/.git/config")
+        ));
+        assert!(in_repository(root, &root.join(".git")));
+        assert!(!in_repository(
+            root,
+            &root.join("nested/.gitignore")
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+//! Shell style path patterns.
+//!
+//! - `?` matches one character other than `/`
+//! - `*` matches any characters other than `/`
+//! - `**` matches any characters including `/`
+//!
+//! A pattern without a `/` is matched against the file name only, otherwise
+//! against the whole path relative to the synchronised root.
+
+use std::path::Path;
+
+/// Compiled pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Glob {
+    /// Pattern as written
+    pattern: String,
+    /// Matched against the file name only
+    name_only: bool,
+}
+
+impl Glob {
+    /// Pattern from its textual form
+    pub(crate) fn new(pattern: &str) -> Self {
+        let pattern = pattern.trim_start_matches("./");
+        Self {
+            name_only: !pattern.contains('/'),
+            pattern: pattern.to_owned(),
+        }
+    }
+
+    /// Pattern matched against the whole path even without a `/`
+    pub(crate) fn anchored(pattern: &str) -> Self {
+        Self {
+            name_only: false,
+            ..Self::new(pattern)
+        }
+    }
+
+    /// `path` (relative to the root) matches the pattern
+    pub(crate) fn matches(&self, path: &Path) -> bool {
+        let text = path.to_string_lossy().replace('\\', "/");
+        let text = match self.name_only {
+            true => text.rsplit('/').next().unwrap_or_default(),
+            false => text.trim_start_matches('/'),
+        };
+        matches(self.pattern.as_bytes(), text.as_bytes())
+    }
+}
+
+/// Backtracking match of `pattern` against `text`
+fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `**/` also matches no directory at all
+            rest.strip_prefix(b"/").is_some_and(|after| matches(after, text))
+                || (0..=text.len()).any(|skip| matches(rest, &text[skip..]))
+        }
+        [b'*', rest @ ..] => {
+            let end = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=end).any(|skip| matches(rest, &text[skip..]))
+        }
+        [b'?', rest @ ..] => matches!(text, [c, ..] if *c != b'/') && matches(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && matches(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        let glob = Glob::new("*.pdf");
+        assert!(glob.matches(Path::new("reports/2024/q1.pdf")));
+        assert!(!glob.matches(Path::new("reports/q1.pdf.tmp")));
+
+        let glob = Glob::new("reports/*/q?.pdf");
+        assert!(glob.matches(Path::new("reports/2024/q1.pdf")));
+        assert!(!glob.matches(Path::new("reports/2024/05/q1.pdf")));
+
+        let glob = Glob::new("build/**/*.o");
+        assert!(glob.matches(Path::new("build/a/b/c.o")));
+        assert!(glob.matches(Path::new("build/c.o")));
+        assert!(!glob.matches(Path::new("src/c.o")));
+    }
+}
@@ -0,0 +1,394 @@
+//! Shell commands run around synchronisations.
+//!
+//! ```toml
+//! [hooks]
+//! before_sync = "mount /mnt/backup"
+//! after_sync = "logger initial sync done: $FSYNC_COPIED copied"
+//! after_batch = "curl -X POST https://cdn.example/purge --data-binary @\"$FSYNC_PATHS_FILE\""
+//! ```
+//!
+//! `before_sync` runs before the initial synchronisation, which is aborted
+//! if the command fails. `after_sync` runs after it, `after_batch` once the
+//! changes seen by the watcher settled (no new change for
+//! [SETTLE_TIME]). Commands run with `sh -c` (`cmd /C` on Windows, as they
+//! are written, the variables are `%FSYNC_COPIED%` there) and get:
+//!
+//! - `FSYNC_HOOK`: `before_sync`, `after_sync` or `after_batch`
+//! - `FSYNC_SOURCE`, `FSYNC_DESTINATION`
+//! - `FSYNC_COPIED`, `FSYNC_REMOVED`, `FSYNC_RENAMED`, `FSYNC_ERRORS`: counts of the batch
+//! - `FSYNC_PATHS_FILE`: file listing the changed paths relative to the
+//!   destination, one per line, removed once the command finished
+//! - `FSYNC_PATHS`: the same paths, unless the list is too long for the
+//!   environment ([MAX_PATHS_VARIABLE])
+//!
+//! `--exec <command>` (or `exec` in `[hooks]`) runs a command entr-style.
+//! With a `{}` placeholder it runs once for each synced file, `{}` standing
+//! for its source path, and fsync waits for it. Otherwise it runs once per
+//! settled batch with the variables above, and a run still going when the
+//! next batch settles is stopped first, so a dev server gets restarted. It
+//! gets `SIGTERM` and is killed if it is still running after
+//! [STOP_TIMEOUT].
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::AppError;
+
+/// Time without changes after which a batch is complete
+pub(crate) const SETTLE_TIME: Duration = Duration::from_millis(500);
+/// Longest `FSYNC_PATHS`, below the limit of Linux for one variable
+pub(crate) const MAX_PATHS_VARIABLE: usize = 64 * 1024;
+/// Time a stopped `--exec` run gets to exit before it is killed
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `[hooks]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HooksConfig {
+    /// Command run before the initial synchronisation
+    #[serde(default)]
+    pub(crate) before_sync: Option<String>,
+    /// Command run after the initial synchronisation
+    #[serde(default)]
+    pub(crate) after_sync: Option<String>,
+    /// Command run after each batch of changes seen by the watcher
+    #[serde(default)]
+    pub(crate) after_batch: Option<String>,
+    /// `--exec` command, run per synced file or per batch
+    #[serde(default)]
+    pub(crate) exec: Option<String>,
+}
+
+impl HooksConfig {
+    /// At least one of the `before_sync`, `after_sync` and `after_batch` hooks is set
+    pub(crate) fn any(&self) -> bool {
+        self.before_sync.is_some() || self.after_sync.is_some() || self.after_batch.is_some()
+    }
+}
+
+/// Operations applied together: by the initial synchronisation, or by the
+/// watcher until the changes settled
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct Batch {
+    /// Files and directories copied
+    pub copied: usize,
+    /// Entries removed
+    pub removed: usize,
+    /// Entries renamed
+    pub renamed: usize,
+    /// Failed operations
+    pub errors: usize,
+    /// Changed paths relative to the destination
+    pub paths: Vec<PathBuf>,
+}
+
+impl Batch {
+    /// Nothing happened
+    pub fn is_empty(&self) -> bool {
+        self.copied + self.removed + self.renamed + self.errors == 0
+    }
+}
+
+/// Shell process running `command`
+fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+
+        let mut process = Command::new("cmd");
+        // cmd parses the command line itself, quoting it again would break
+        // the quotes of the command
+        process.arg("/C").raw_arg(command);
+        process
+    }
+    #[cfg(not(windows))]
+    {
+        let mut process = Command::new("sh");
+        process.arg("-c").arg(command);
+        process
+    }
+}
+
+/// `FSYNC_PATHS_FILE` of a command, removed when dropped
+#[derive(Debug)]
+struct PathsFile(PathBuf);
+
+impl PathsFile {
+    /// Writes `paths` one per line to a new file
+    fn write(paths: &str) -> io::Result<Self> {
+        /// Files written so far, naming the next one
+        static FILES: AtomicU64 = AtomicU64::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "fsync-paths-{}-{}",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = Self(path);
+        let mut output = options.open(&file.0)?;
+        output.write_all(paths.as_bytes())?;
+        if !paths.is_empty() {
+            output.write_all(b"\n")?;
+        }
+        Ok(file)
+    }
+}
+
+impl Drop for PathsFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Shell process running the `hook` command with the variables describing
+/// `batch`, and the file of its paths
+fn batch_process(hook: &str, command: &str, source: &Path, destination: &str, batch: &Batch) -> io::Result<(Command, PathsFile)> {
+    let paths = batch
+        .paths
+        .iter()
+        .map(|path| path.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let file = PathsFile::write(&paths)?;
+    let mut process = shell(command);
+    process
+        .env("FSYNC_HOOK", hook)
+        .env("FSYNC_SOURCE", source)
+        .env("FSYNC_DESTINATION", destination)
+        .env("FSYNC_COPIED", batch.copied.to_string())
+        .env(
+            "FSYNC_REMOVED",
+            batch.removed.to_string(),
+        )
+        .env(
+            "FSYNC_RENAMED",
+            batch.renamed.to_string(),
+        )
+        .env("FSYNC_ERRORS", batch.errors.to_string())
+        .env("FSYNC_PATHS_FILE", &file.0);
+    // Too long for the environment, the command could not start
+    if paths.len() <= MAX_PATHS_VARIABLE {
+        process.env("FSYNC_PATHS", paths);
+    } else {
+        tracing::debug!(
+            "{hook}: {} paths only listed in FSYNC_PATHS_FILE",
+            batch.paths.len()
+        );
+    }
+    Ok((process, file))
+}
+
+/// Runs the `hook` command with the variables describing `batch`.
+///
+/// # Errors
+///
+/// [AppError] is returned if the command could not be started or failed.
+pub(crate) fn run(hook: &str, command: &str, source: &Path, destination: &str, batch: &Batch) -> Result<(), AppError> {
+    tracing::debug!("{hook} hook: {command}");
+    let (mut process, _file) = batch_process(
+        hook,
+        command,
+        source,
+        destination,
+        batch,
+    )?;
+    let status = process.status()?;
+    if !status.success() {
+        return Err(AppError::Backend(format!(
+            "{hook} hook failed: {status}"
+        )));
+    }
+    Ok(())
+}
+
+/// `--exec` command
+#[derive(Debug)]
+pub(crate) struct Exec {
+    /// Shell command, `{}` replaced by the synced path
+    command: String,
+    /// Process started for the last batch and the file of its paths
+    running: Mutex<Option<(Child, PathsFile)>>,
+}
+
+impl Exec {
+    /// Runner of `command`
+    pub(crate) fn new(command: String) -> Self {
+        Self {
+            command,
+            running: Mutex::default(),
+        }
+    }
+
+    /// The command runs for each synced file instead of each batch
+    pub(crate) fn per_path(&self) -> bool {
+        self.command.contains("{}")
+    }
+
+    /// Runs the command for the synced file `path` and waits for it.
+    ///
+    /// The path is passed in `FSYNC_PATH`, so it needs no shell quoting.
+    ///
+    /// # Errors
+    ///
+    /// [AppError] is returned if the command could not be started or failed.
+    pub(crate) fn path(&self, path: &Path) -> Result<(), AppError> {
+        let variable = if cfg!(windows) {
+            "\"%FSYNC_PATH%\""
+        } else {
+            "\"$FSYNC_PATH\""
+        };
+        let command = self.command.replace("{}", variable);
+        tracing::debug!("exec: {command}");
+        let status = shell(&command).env("FSYNC_PATH", path).status()?;
+        if !status.success() {
+            return Err(AppError::Backend(format!(
+                "exec {}: {status}",
+                path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Starts the command for a settled `batch`, killing the run of the previous batch.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::IoError] is returned if the command could not be started.
+    pub(crate) fn batch(&self, source: &Path, destination: &str, batch: &Batch) -> Result<(), AppError> {
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((mut child, _file)) = running.take() {
+            if child.try_wait()?.is_none() {
+                tracing::debug!("exec: restarting {}", self.command);
+                stop(&mut child);
+            }
+            let _ = child.wait();
+        }
+        tracing::debug!("exec: {}", self.command);
+        let (mut process, file) = batch_process(
+            "exec",
+            &self.command,
+            source,
+            destination,
+            batch,
+        )?;
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut process, 0);
+        *running = Some((process.spawn()?, file));
+        Ok(())
+    }
+}
+
+/// Stops `child` together with the processes it started: on unix they get
+/// `SIGTERM` and [STOP_TIMEOUT] to exit before they are killed
+fn stop(child: &mut Child) {
+    #[cfg(unix)]
+    if let Ok(group) = libc::pid_t::try_from(child.id()) {
+        // SAFETY: kill has no memory safety requirements, the group was
+        // created for the child by Exec::batch
+        unsafe { libc::kill(-group, libc::SIGTERM) };
+        let deadline = std::time::Instant::now() + STOP_TIMEOUT;
+        while std::time::Instant::now() < deadline {
+            if !matches!(child.try_wait(), Ok(None)) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        tracing::warn!("exec: killing the previous run, still running after SIGTERM");
+        // SAFETY: as above
+        unsafe { libc::kill(-group, libc::SIGKILL) };
+        return;
+    }
+    let _ = child.kill();
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variables_describe_the_batch() {
+        let batch = Batch {
+            copied: 2,
+            paths: vec!["a".into(), "b/c".into()],
+            ..Batch::default()
+        };
+        run(
+            "after_batch",
+            r#"test "$FSYNC_COPIED" = 2 && test "$FSYNC_PATHS" = "$(printf 'a\nb/c')""#,
+            Path::new("/src"),
+            "/dst",
+            &batch,
+        )
+        .unwrap();
+        assert!(run(
+            "after_batch",
+            "exit 3",
+            Path::new("/src"),
+            "/dst",
+            &batch
+        )
+        .is_err());
+
+        // Only the file lists the paths of large batches
+        let batch = Batch {
+            paths: (0..20_000).map(|i| PathBuf::from(format!("dir/file-{i}"))).collect(),
+            ..Batch::default()
+        };
+        run(
+            "after_batch",
+            r#"test -z "$FSYNC_PATHS" && test "$(wc -l < "$FSYNC_PATHS_FILE")" -eq 20000"#,
+            Path::new("/src"),
+            "/dst",
+            &batch,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn exec_kills_runs_ignoring_sigterm() {
+        let exec = Exec::new("trap '' TERM; sleep 60".into());
+        exec.batch(
+            Path::new("/src"),
+            "/dst",
+            &Batch::default(),
+        )
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        let started = std::time::Instant::now();
+        exec.batch(
+            Path::new("/src"),
+            "/dst",
+            &Batch::default(),
+        )
+        .unwrap();
+        assert!(started.elapsed() < STOP_TIMEOUT + Duration::from_secs(5));
+        let (mut child, _) = exec.running.lock().unwrap().take().unwrap();
+        stop(&mut child);
+        let _ = child.wait();
+    }
+
+    #[test]
+    fn exec_substitutes_the_path() {
+        let exec = Exec::new("test {} = \"/src/it's here\"".into());
+        assert!(exec.per_path());
+        exec.path(Path::new("/src/it's here")).unwrap();
+        assert!(exec.path(Path::new("/src/other")).is_err());
+        assert!(!Exec::new("make docs".into()).per_path());
+    }
+}
@@ -0,0 +1,360 @@
+//! Files which are never synchronised.
+//!
+//! Editors and office suites keep swap, backup and lock files next to the
+//! documents being edited. They are ignored by default:
+//!
+//! | Program | Names |
+//! |---|---|
+//! | Vim | `.*.swp`, `.*.swo`, `.*.swx`, `*~`, `4913` |
+//! | Emacs | `#*#`, `.#*` |
+//! | JetBrains IDEs | `*___jb_tmp___`, `*___jb_old___` |
+//! | Microsoft Office | `~$*`, `~WRL*.tmp` |
+//! | LibreOffice | `.~lock.*#` |
+//! | Kate, gedit | `*.kate-swp`, `.goutputstream-*` |
+//!
+//! An `[ignore]` section opts out:
+//!
+//! ```toml
+//! [ignore]
+//! editor_files = false
+//! ```
+//!
+//! Directories of the source can also keep their own entries out with a
+//! `.fwatchignore` file. Like `.gitignore` files, one line holds one pattern
+//! (see [glob](crate::glob)) relative to the directory of the file, and it
+//! applies to everything below that directory:
+//!
+//! ```text
+//! # A trailing / only matches directories
+//! target/
+//! # A leading / only matches next to the file
+//! /notes.txt
+//! *.log
+//! # A ! takes matching entries back in
+//! !keep.log
+//! ```
+//!
+//! Files deeper in the tree are consulted after the ones above them, and the
+//! last matching pattern decides. Entries of an ignored directory cannot be
+//! taken back in. The files are read again when they change; entries already
+//! stored at the destination stay there, and newly included entries are
+//! copied when they change next. `ignore_files = false` in the `[ignore]`
+//! section turns them off.
+//!
+//! An editor saving by renaming its temporary file over the document still
+//! gets the document copied, and renaming a document to an ignored name
+//! removes it from the destination.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+
+use crate::{glob::Glob, paths, queue::Operation};
+
+/// Name of the ignore files of the source directories
+pub(crate) const IGNORE_FILE: &str = ".fwatchignore";
+
+/// Names of the temporary files of editors
+const EDITOR_FILES: &[&str] = &[
+    ".*.swp",
+    ".*.swo",
+    ".*.swx",
+    "*~",
+    "4913",
+    "#*#",
+    ".#*",
+    "*___jb_tmp___",
+    "*___jb_old___",
+    "~$*",
+    "~WRL*.tmp",
+    ".~lock.*#",
+    "*.kate-swp",
+    ".goutputstream-*",
+];
+
+/// `[ignore]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct IgnoreConfig {
+    /// Ignores the temporary files of editors
+    #[serde(default = "IgnoreConfig::enabled")]
+    pub(crate) editor_files: bool,
+    /// Honours the `.fwatchignore` files of the source directories
+    #[serde(default = "IgnoreConfig::enabled")]
+    pub(crate) ignore_files: bool,
+}
+
+impl IgnoreConfig {
+    /// Default of the switches
+    fn enabled() -> bool {
+        true
+    }
+}
+
+impl Default for IgnoreConfig {
+    fn default() -> Self {
+        Self {
+            editor_files: Self::enabled(),
+            ignore_files: Self::enabled(),
+        }
+    }
+}
+
+/// Pattern of an ignore file
+#[derive(Debug)]
+struct Rule {
+    /// Pattern relative to the directory of the file
+    glob: Glob,
+    /// Takes matching entries back in
+    negated: bool,
+    /// Only matches directories
+    dir_only: bool,
+}
+
+impl Rule {
+    /// Rules of the ignore file `text`
+    fn parse(text: &str) -> Vec<Self> {
+        text.lines()
+            .filter_map(|line| {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    return None;
+                }
+                let (negated, line) = match line.strip_prefix('!') {
+                    Some(line) => (true, line),
+                    None => (
+                        false,
+                        line.strip_prefix('\\').unwrap_or(line),
+                    ),
+                };
+                let (dir_only, line) = match line.strip_suffix('/') {
+                    Some(line) => (true, line),
+                    None => (false, line),
+                };
+                let glob = match line.strip_prefix('/') {
+                    Some(line) => Glob::anchored(line),
+                    None => Glob::new(line),
+                };
+                (!line.is_empty()).then_some(Self { glob, negated, dir_only })
+            })
+            .collect()
+    }
+}
+
+/// Patterns of the ignored files
+#[derive(Debug)]
+pub(crate) struct Ignore {
+    /// File name patterns
+    patterns: Vec<Glob>,
+    /// Source root, the top of the ignore files
+    root: PathBuf,
+    /// Rules of the ignore files by their directory, none if they are not
+    /// honoured
+    files: Option<Mutex<HashMap<PathBuf, Arc<[Rule]>>>>,
+}
+
+impl Ignore {
+    /// Ignored files of `config` in the source `root`, none if nothing is
+    /// ignored
+    pub(crate) fn new(config: &IgnoreConfig, root: &Path) -> Option<Self> {
+        (config.editor_files || config.ignore_files).then(|| Self {
+            patterns: match config.editor_files {
+                true => EDITOR_FILES.iter().map(|pattern| Glob::new(pattern)).collect(),
+                false => Vec::new(),
+            },
+            root: root.to_path_buf(),
+            files: config.ignore_files.then(Mutex::default),
+        })
+    }
+
+    /// Whether the entry at `path` is ignored
+    pub(crate) fn ignores(&self, path: &Path) -> bool {
+        if self.patterns.iter().any(|pattern| pattern.matches(path)) {
+            return true;
+        }
+        let (Some(files), Ok(relative)) = (
+            &self.files,
+            path.strip_prefix(&self.root),
+        ) else {
+            return false;
+        };
+        let mut files = files.lock().unwrap_or_else(|e| e.into_inner());
+        let components: Vec<_> = relative.components().collect();
+        let mut dirs = vec![self.root.clone()];
+        // An ignored directory hides everything in it
+        for (index, component) in components.iter().enumerate() {
+            let entry = dirs[index].join(component);
+            let is_dir = index + 1 < components.len() || paths::extended(&entry).is_dir();
+            let mut ignored = false;
+            for dir in &dirs {
+                let rules = files.entry(dir.clone()).or_insert_with(|| Self::read(dir));
+                let relative = entry.strip_prefix(dir).unwrap_or(&entry);
+                if let Some(rule) = rules
+                    .iter()
+                    .rev()
+                    .find(|rule| (is_dir || !rule.dir_only) && rule.glob.matches(relative))
+                {
+                    ignored = !rule.negated;
+                }
+            }
+            if ignored {
+                return true;
+            }
+            dirs.push(entry);
+        }
+        false
+    }
+
+    /// Rules of the ignore file of the directory `dir`, none if it has none
+    fn read(dir: &Path) -> Arc<[Rule]> {
+        let file = dir.join(IGNORE_FILE);
+        match fs::read_to_string(paths::extended(&file)) {
+            Ok(text) => {
+                tracing::debug!("read {}", file.display());
+                Rule::parse(&text).into()
+            }
+            Err(err) => {
+                if err.kind() != io::ErrorKind::NotFound {
+                    tracing::warn!("{}: {err}", file.display());
+                }
+                Arc::new([])
+            }
+        }
+    }
+
+    /// Reads the ignore file at `path` again when it is next needed, and
+    /// the ones below `path` if it was `moved` away or over
+    fn forget(&self, path: &Path, moved: bool) {
+        let Some(files) = &self.files else {
+            return;
+        };
+        let mut files = files.lock().unwrap_or_else(|e| e.into_inner());
+        if path.file_name().is_some_and(|name| name == IGNORE_FILE) {
+            if let Some(dir) = path.parent() {
+                files.remove(dir);
+            }
+        } else if moved {
+            files.retain(|dir, _| !dir.starts_with(path));
+        }
+    }
+
+    /// `operation` without its ignored paths, none if nothing is left
+    pub(crate) fn select(&self, operation: &Operation) -> Option<Operation> {
+        match operation {
+            Operation::Copy { path } => self.forget(path, false),
+            Operation::Remove { path } => self.forget(path, true),
+            Operation::Rename { from, to } => {
+                self.forget(from, true);
+                self.forget(to, true);
+            }
+        }
+        match operation {
+            Operation::Copy { path } | Operation::Remove { path } if self.ignores(path) => None,
+            Operation::Rename { from, to } => match (self.ignores(from), self.ignores(to)) {
+                (false, false) => Some(operation.clone()),
+                // Saved through a temporary file
+                (true, false) => Some(Operation::Copy { path: to.clone() }),
+                (false, true) => Some(Operation::Remove { path: from.clone() }),
+                (true, true) => None,
+            },
+            _ => Some(operation.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignores_editor_files() {
+        let ignore = Ignore::new(
+            &IgnoreConfig::default(),
+            Path::new("/src"),
+        )
+        .unwrap();
+        for name in [
+            "src/.main.rs.swp",
+            "notes.txt~",
+            "#notes.txt#",
+            ".#notes.txt",
+            "Main.java___jb_tmp___",
+            "~$report.docx",
+            ".~lock.report.odt#",
+        ] {
+            assert!(
+                ignore.ignores(Path::new(name)),
+                "{name}"
+            );
+        }
+        for name in ["src/main.rs", "report.docx", "a~b.txt", "issue#12.md"] {
+            assert!(
+                !ignore.ignores(Path::new(name)),
+                "{name}"
+            );
+        }
+        let rename = Operation::Rename {
+            from: "/src/Main.java___jb_tmp___".into(),
+            to: "/src/Main.java".into(),
+        };
+        assert_eq!(
+            ignore.select(&rename),
+            Some(Operation::Copy {
+                path: "/src/Main.java".into()
+            })
+        );
+        assert!(Ignore::new(
+            &IgnoreConfig {
+                editor_files: false,
+                ignore_files: false,
+            },
+            Path::new("/src")
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn cascading_ignore_files() {
+        let root = std::env::temp_dir().join(format!(
+            "fsync-ignore-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(root.join("project/target/debug")).unwrap();
+        fs::create_dir_all(root.join("project/logs")).unwrap();
+        fs::write(root.join(IGNORE_FILE), "*.log\n").unwrap();
+        fs::write(
+            root.join("project").join(IGNORE_FILE),
+            "# Build output\ntarget/\n/notes.txt\n!keep.log\n",
+        )
+        .unwrap();
+        let ignore = Ignore::new(&IgnoreConfig::default(), &root).unwrap();
+        for (path, ignored) in [
+            ("project/target/debug/app", true),
+            ("project/target", true),
+            ("project/notes.txt", true),
+            ("project/logs/notes.txt", false),
+            ("project/logs/build.log", true),
+            ("project/keep.log", false),
+            ("other/keep.log", true),
+            ("other/src/main.rs", false),
+        ] {
+            assert_eq!(
+                ignore.ignores(&root.join(path)),
+                ignored,
+                "{path}"
+            );
+        }
+
+        // Changed at runtime
+        let file = root.join("project").join(IGNORE_FILE);
+        fs::write(&file, "logs/\n").unwrap();
+        ignore.select(&Operation::Copy { path: file });
+        assert!(!ignore.ignores(&root.join("project/target/debug/app")));
+        assert!(ignore.ignores(&root.join("project/logs/notes.txt")));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -2,9 +2,13 @@
 //!
 //! - [Config]
 //! - [App]
+//! - [SyncTarget] destination backends
+//! - [watcher], [planner] and [executor]: the stages of a synchronisation,
+//!   each behind a trait embedders can implement
 //!
 //! [Config]: crate::config::Config
 //! [App]: crate::app::App
+//! [SyncTarget]: crate::target::SyncTarget
 
 // Lints
 #![deny(missing_docs, rustdoc::missing_crate_level_docs, rustdoc::broken_intra_doc_links)]
@@ -14,8 +18,118 @@
     clippy::missing_panics_doc
 )]
 
+#[cfg(feature = "agent")]
+pub mod agent;
 mod app;
+mod audit;
+mod coalesce;
+mod compare;
+#[cfg(feature = "compression")]
+mod compression;
 mod config;
+mod confirm;
+mod delta;
+#[cfg(feature = "desktop")]
+mod desktop;
+#[cfg(feature = "email")]
+mod email;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod events;
+pub mod executor;
+mod failover;
+mod failures;
+pub mod filesystem;
+mod filter;
+mod git;
+mod glob;
+mod hooks;
+mod ignore;
+mod logging;
+#[cfg(feature = "media")]
+mod media;
+pub mod metrics;
+mod names;
+mod observer;
+mod only;
+#[cfg(feature = "otel")]
+mod otel;
+#[cfg(feature = "parity")]
+mod parity;
+mod paths;
+pub mod peer;
+mod permissions;
+mod plan;
+pub mod planner;
+mod policy;
+pub mod progress;
+mod queue;
+mod quota;
+mod rename;
+mod report;
+mod retention;
+mod retry;
+mod route;
+#[cfg(feature = "scripting")]
+mod script;
+mod secret;
+mod selection;
+mod stats;
+pub mod status;
+pub mod target;
+mod template;
+#[cfg(test)]
+mod testing;
+mod transform;
+#[cfg(feature = "tui")]
+pub mod tui;
+mod watchdog;
+pub mod watcher;
+#[cfg(feature = "webhooks")]
+mod webhooks;
 
 pub use app::*;
+pub use audit::AuditConfig;
+pub use compare::{CompareMode, DiffEntry, Difference, TreeDiff};
+#[cfg(feature = "compression")]
+pub use compression::CompressionConfig;
 pub use config::*;
+#[cfg(feature = "desktop")]
+pub use desktop::DesktopConfig;
+#[cfg(feature = "email")]
+pub use email::{EmailConfig, SmtpSecurity};
+#[cfg(feature = "encryption")]
+pub use encryption::{decrypt, EncryptionConfig};
+pub use events::SyncEvent;
+pub use failover::FailoverConfig;
+pub use failures::{ErrorReport, Failure};
+pub use filter::{FilterChain, PathFilter};
+pub use git::GitConfig;
+pub use hooks::{Batch, HooksConfig};
+pub use ignore::IgnoreConfig;
+pub use logging::*;
+#[cfg(feature = "media")]
+pub use media::{MediaConfig, StripGps};
+pub use names::{NamesConfig, ReservedNames};
+pub use observer::SyncObserver;
+#[cfg(feature = "parity")]
+pub use parity::{scrub, ParityConfig, ScrubReport};
+pub use paths::UnicodeForm;
+pub use permissions::PermissionsConfig;
+pub use plan::{Action, MetadataReport, PlanReport, Preview, SyncPlan};
+pub use policy::{ErrorPolicy, OnError};
+pub use quota::QuotaConfig;
+pub use rename::RenameConfig;
+pub use report::ReportConfig;
+pub use retention::RetentionConfig;
+pub use retry::RetryConfig;
+pub use route::RouteConfig;
+#[cfg(feature = "scripting")]
+pub use script::ScriptConfig;
+pub use secret::*;
+pub use stats::{eta, format_bytes, format_rate, InFlight, Phase, Stats, Status};
+pub use target::{SyncTarget, TargetMetadata};
+pub use transform::{Transform, TransformChain};
+pub use watchdog::WatchdogConfig;
+#[cfg(feature = "webhooks")]
+pub use webhooks::{WebhookConfig, WebhookEvent};
@@ -0,0 +1,160 @@
+//! Logger setup.
+//!
+//! Logging is done with `tracing`: every applied change runs in an
+//! `operation` span carrying the path, size and duration, and the initial
+//! scan in an `initial_sync` span. `RUST_LOG` selects the level (`info` by
+//! default) with the usual `tracing-subscriber` directives.
+//!
+//! With `--log-format json` every event is written as one JSON object per
+//! line, with the fields of the event and of its span.
+//!
+//! With a `[log_file]` section (or `--log-file <path>`) the log is written to
+//! a file instead of the standard error, rotated by size and/or time:
+//!
+//! ```toml
+//! [log_file]
+//! path = "/var/log/fsync/fsync.log"
+//! max_size = 10485760
+//! rotation = "daily"
+//! keep = 7
+//! ```
+//!
+//! A `[syslog]` section sends the log to the local syslog daemon, or to a
+//! remote server with `address = "udp://<host>:514"` (or `tcp://`). It also
+//! replaces the standard error unless a log file is set.
+//!
+//! The dashboard of `--tui` takes the place of the standard error.
+//!
+//! On Windows an `[event_log]` section additionally reports warnings and
+//! errors to the Windows Event Log.
+//!
+//! Built with the `otel` feature, the spans are also exported over OTLP (see
+//! the `otel` module).
+
+mod eventlog;
+mod rotate;
+mod syslog;
+
+use std::{io::IsTerminal, sync::Mutex};
+
+pub use eventlog::EventLogConfig;
+use rotate::RotatingFile;
+pub use rotate::{LogFileConfig, Rotation};
+pub use syslog::{Facility, SyslogConfig};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::{AppError, Config};
+
+/// Output format of the log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Keeps the exporters of the log alive, flushes them when dropped
+#[must_use = "the exporters stop when the guard is dropped"]
+pub struct LogGuard {
+    /// OTLP exporters
+    #[cfg(feature = "otel")]
+    _telemetry: Option<crate::otel::Telemetry>,
+}
+
+/// Installs the global subscriber for `config`.
+///
+/// Records of the `log` crate (emitted by dependencies) are forwarded to it.
+///
+/// # Errors
+///
+/// [AppError] is returned if the log file could not be opened, the syslog
+/// daemon could not be reached, the Windows event source could not be
+/// opened or the OTLP exporters could not be built.
+pub fn init_logger(config: &Config) -> Result<LogGuard, AppError> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let writer = match (&config.log_file, &config.syslog) {
+        (Some(log_file), _) => Some((
+            BoxMakeWriter::new(Mutex::new(RotatingFile::open(
+                log_file.clone(),
+            )?)),
+            false,
+        )),
+        // The dashboard owns the terminal
+        (None, None) if config.tui => None,
+        (None, None) => Some((
+            BoxMakeWriter::new(|| crate::progress::Stderr),
+            std::io::stderr().is_terminal(),
+        )),
+        (None, Some(_)) => None,
+    };
+    let fmt = writer.map(|(writer, ansi)| {
+        let fmt = tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi);
+        match config.log_format() {
+            LogFormat::Text => fmt.boxed(),
+            LogFormat::Json => fmt
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false)
+                .boxed(),
+        }
+    });
+    let syslog = match &config.syslog {
+        Some(syslog) => Some(
+            tracing_subscriber::fmt::layer()
+                .with_writer(syslog::Syslog::connect(syslog)?)
+                .with_ansi(false)
+                .with_level(false)
+                .without_time(),
+        ),
+        None => None,
+    };
+    let subscriber = tracing_subscriber::registry().with(filter).with(fmt).with(syslog);
+
+    #[cfg(windows)]
+    let event_log = match &config.event_log {
+        Some(event_log) => Some(
+            tracing_subscriber::fmt::layer()
+                .with_writer(eventlog::EventLog::open(event_log)?)
+                .with_ansi(false)
+                .with_level(false)
+                .without_time()
+                .with_filter(tracing_subscriber::filter::LevelFilter::WARN),
+        ),
+        None => None,
+    };
+    #[cfg(windows)]
+    let subscriber = subscriber.with(event_log);
+    #[cfg(not(windows))]
+    if config.event_log.is_some() {
+        return Err(AppError::Backend(
+            "[event_log] is only available on Windows".into(),
+        ));
+    }
+
+    #[cfg(feature = "otel")]
+    let telemetry = config.backends.otlp.as_ref().map(crate::otel::Telemetry::new).transpose()?;
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(telemetry.as_ref().map(crate::otel::Telemetry::layer));
+
+    let _ = subscriber.try_init();
+    Ok(LogGuard {
+        #[cfg(feature = "otel")]
+        _telemetry: telemetry,
+    })
+}
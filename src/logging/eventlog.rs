@@ -0,0 +1,212 @@
+//! Windows Event Log sink.
+//!
+//! Warnings and errors are reported to the `Application` log under an event
+//! source (`fwatch` by default). The source is registered on first use when
+//! fsync has the rights to (e.g. running as a service under LocalSystem),
+//! with the message file of .NET so Event Viewer shows the text as is.
+
+use serde::Deserialize;
+
+/// `[event_log]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EventLogConfig {
+    /// Event source name, `fwatch` if not set
+    #[serde(default)]
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub(crate) source: Option<String>,
+}
+
+#[cfg(windows)]
+pub(crate) use windows::EventLog;
+
+/// Calls of the Win32 event logging API
+#[cfg(windows)]
+mod windows {
+    use std::{
+        io::{self, Write},
+        ptr,
+    };
+
+    use tracing::{Level, Metadata};
+    use tracing_subscriber::fmt::MakeWriter;
+    use windows_sys::Win32::{
+        Foundation::HANDLE,
+        System::{
+            EventLog::{DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_WARNING_TYPE},
+            Registry::{
+                RegCloseKey, RegCreateKeyExW, RegSetValueExW, HKEY, HKEY_LOCAL_MACHINE, KEY_SET_VALUE, REG_DWORD, REG_EXPAND_SZ,
+                REG_OPTION_NON_VOLATILE,
+            },
+        },
+    };
+
+    use super::EventLogConfig;
+
+    /// Message file whose every event identifier formats as the first string
+    const MESSAGE_FILE: &str = r"%SystemRoot%\Microsoft.NET\Framework64\v4.0.30319\EventLogMessages.dll";
+
+    /// NUL terminated UTF-16 copy of `value`
+    fn wide(value: &str) -> Vec<u16> {
+        value.encode_utf16().chain([0]).collect()
+    }
+
+    /// Registers `source` in the `Application` log, existing entries are
+    /// overwritten with the same values
+    fn register(source: &str) -> io::Result<()> {
+        let key = wide(&format!(
+            r"SYSTEM\CurrentControlSet\Services\EventLog\Application\{source}"
+        ));
+        let mut handle: HKEY = 0;
+        // SAFETY: the strings are NUL terminated and outlive the calls
+        unsafe {
+            let status = RegCreateKeyExW(
+                HKEY_LOCAL_MACHINE,
+                key.as_ptr(),
+                0,
+                ptr::null(),
+                REG_OPTION_NON_VOLATILE,
+                KEY_SET_VALUE,
+                ptr::null(),
+                &mut handle,
+                ptr::null_mut(),
+            );
+            if status != 0 {
+                return Err(io::Error::from_raw_os_error(
+                    status as i32,
+                ));
+            }
+            let file = wide(MESSAGE_FILE);
+            let types: u32 = 7;
+            RegSetValueExW(
+                handle,
+                wide("EventMessageFile").as_ptr(),
+                0,
+                REG_EXPAND_SZ,
+                file.as_ptr().cast(),
+                (file.len() * 2) as u32,
+            );
+            RegSetValueExW(
+                handle,
+                wide("TypesSupported").as_ptr(),
+                0,
+                REG_DWORD,
+                ptr::addr_of!(types).cast(),
+                4,
+            );
+            RegCloseKey(handle);
+        }
+        Ok(())
+    }
+
+    /// Writer reporting every formatted event as one Event Log entry
+    #[derive(Debug)]
+    pub(crate) struct EventLog {
+        /// Handle of the registered event source
+        handle: HANDLE,
+    }
+
+    // SAFETY: event source handles may be used from any thread
+    unsafe impl Send for EventLog {}
+    // SAFETY: ReportEventW is thread safe
+    unsafe impl Sync for EventLog {}
+
+    impl EventLog {
+        /// Opens the event source of `config`, registering it if possible
+        pub(crate) fn open(config: &EventLogConfig) -> io::Result<Self> {
+            let source = config.source.as_deref().unwrap_or("fwatch");
+            // Without administrative rights the entries are still written,
+            // Event Viewer only complains about the missing description
+            let _ = register(source);
+            let name = wide(source);
+            // SAFETY: the name is NUL terminated
+            let handle = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+            if handle == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { handle })
+        }
+
+        /// Reports `message` as an entry of the type matching `level`
+        fn report(&self, level: Level, message: &str) {
+            let (kind, id) = match level {
+                Level::ERROR => (EVENTLOG_ERROR_TYPE, 1),
+                _ => (EVENTLOG_WARNING_TYPE, 2),
+            };
+            let message = wide(message.trim_end());
+            let strings = [message.as_ptr()];
+            // SAFETY: one NUL terminated string is passed, no raw data
+            unsafe {
+                ReportEventW(
+                    self.handle,
+                    kind,
+                    0,
+                    id,
+                    ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    ptr::null(),
+                );
+            }
+        }
+    }
+
+    impl Drop for EventLog {
+        fn drop(&mut self) {
+            // SAFETY: the handle was returned by RegisterEventSourceW
+            unsafe { DeregisterEventSource(self.handle) };
+        }
+    }
+
+    /// Event being formatted
+    pub(crate) struct Entry<'a> {
+        /// Destination
+        log: &'a EventLog,
+        /// Level of the event
+        level: Level,
+        /// Formatted event
+        buffer: Vec<u8>,
+    }
+
+    impl Write for Entry<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for Entry<'_> {
+        fn drop(&mut self) {
+            if !self.buffer.is_empty() {
+                self.log.report(
+                    self.level,
+                    &String::from_utf8_lossy(&self.buffer),
+                );
+            }
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for EventLog {
+        type Writer = Entry<'a>;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            Entry {
+                log: self,
+                level: Level::WARN,
+                buffer: Vec::new(),
+            }
+        }
+
+        fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+            Entry {
+                log: self,
+                level: *meta.level(),
+                buffer: Vec::new(),
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Log file with size and time based rotation.
+//!
+//! The current file keeps its name, rotated files get a numbered suffix:
+//! `fsync.log.1` is the most recent one, files beyond
+//! [LogFileConfig::keep] are removed.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::Deserialize;
+
+/// How often the log file is rotated regardless of its size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    /// Only by size
+    #[default]
+    Never,
+    /// At the start of every hour (UTC)
+    Hourly,
+    /// At midnight (UTC)
+    Daily,
+}
+
+impl Rotation {
+    /// Period `time` falls into, rotation happens when it changes
+    pub(crate) fn period(self, time: SystemTime) -> u64 {
+        let secs = crate::peer::unix_secs(time);
+        match self {
+            Rotation::Never => 0,
+            Rotation::Hourly => secs / 3600,
+            Rotation::Daily => secs / 86400,
+        }
+    }
+}
+
+/// `[log_file]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogFileConfig {
+    /// Path of the current log file
+    pub(crate) path: PathBuf,
+    /// Size in bytes after which the file is rotated, unlimited if not set
+    #[serde(default)]
+    pub(crate) max_size: Option<u64>,
+    /// Time based rotation
+    #[serde(default)]
+    pub(crate) rotation: Rotation,
+    /// Number of rotated files kept
+    #[serde(default = "LogFileConfig::default_keep")]
+    pub(crate) keep: usize,
+}
+
+impl LogFileConfig {
+    /// Rotated files kept by default
+    fn default_keep() -> usize {
+        5
+    }
+
+    /// Log file at `path` with the default rotation settings
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_size: None,
+            rotation: Rotation::default(),
+            keep: Self::default_keep(),
+        }
+    }
+}
+
+/// Log file writer, rotating before a write when the limits are reached
+#[derive(Debug)]
+pub(crate) struct RotatingFile {
+    /// Settings
+    config: LogFileConfig,
+    /// Current file, opened for appending
+    file: File,
+    /// Size of the current file
+    size: u64,
+    /// [Rotation::period] of the current file
+    period: u64,
+}
+
+impl RotatingFile {
+    /// Opens (or creates) the current log file, creating its directory
+    pub(crate) fn open(config: LogFileConfig) -> io::Result<Self> {
+        if let Some(dir) = config.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let metadata = file.metadata()?;
+        let period = config
+            .rotation
+            .period(metadata.modified().unwrap_or_else(|_| SystemTime::now()));
+        Ok(Self {
+            size: metadata.len(),
+            period,
+            config,
+            file,
+        })
+    }
+
+    /// Path of the rotated file number `index`
+    fn rotated(path: &Path, index: usize) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{index}"));
+        name.into()
+    }
+
+    /// Shifts the rotated files and starts a new current file
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.config.path;
+        self.file.flush()?;
+        if self.config.keep == 0 {
+            fs::remove_file(path)?;
+        } else {
+            for index in (1..self.config.keep).rev() {
+                let from = Self::rotated(path, index);
+                if from.exists() {
+                    fs::rename(from, Self::rotated(path, index + 1))?;
+                }
+            }
+            fs::rename(path, Self::rotated(path, 1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = self.config.rotation.period(SystemTime::now());
+        let full = self
+            .config
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + buf.len() as u64 > max);
+        if full || period != self.period {
+            self.period = period;
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest() {
+        let dir = std::env::temp_dir().join(format!(
+            "fsync-log-test-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("fsync.log");
+        let mut file = RotatingFile::open(LogFileConfig {
+            max_size: Some(10),
+            keep: 2,
+            ..LogFileConfig::new(path.clone())
+        })
+        .unwrap();
+
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "fourth\n"
+        );
+        assert_eq!(
+            fs::read_to_string(RotatingFile::rotated(&path, 1)).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(RotatingFile::rotated(&path, 2)).unwrap(),
+            "second\n"
+        );
+        assert!(!RotatingFile::rotated(&path, 3).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
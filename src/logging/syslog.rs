@@ -0,0 +1,382 @@
+//! Syslog sink.
+//!
+//! Messages go to the local daemon through `/dev/log` (RFC 3164 style, as
+//! expected by rsyslog and journald), or to a remote server over UDP or TCP
+//! (RFC 5424, framed by octet counting on TCP as in RFC 6587).
+
+use std::{
+    io::{self, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Facility of the messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    /// Generic user-level messages
+    User = 1,
+    /// System daemons
+    #[default]
+    Daemon = 3,
+    /// Local use 0
+    Local0 = 16,
+    /// Local use 1
+    Local1 = 17,
+    /// Local use 2
+    Local2 = 18,
+    /// Local use 3
+    Local3 = 19,
+    /// Local use 4
+    Local4 = 20,
+    /// Local use 5
+    Local5 = 21,
+    /// Local use 6
+    Local6 = 22,
+    /// Local use 7
+    Local7 = 23,
+}
+
+/// `[syslog]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SyslogConfig {
+    /// `udp://<host>:<port>` or `tcp://<host>:<port>` of a remote server,
+    /// the local daemon if not set
+    #[serde(default)]
+    pub(crate) address: Option<String>,
+    /// Facility of the messages, `daemon` if not set
+    #[serde(default)]
+    pub(crate) facility: Facility,
+    /// Application name of the messages, `fsync` if not set
+    #[serde(default)]
+    pub(crate) app_name: Option<String>,
+}
+
+/// Time allowed to connect to or write to a TCP server
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest wait before connecting again to an unreachable TCP server
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Connection to a remote TCP server
+#[derive(Debug, Default)]
+struct TcpConnection {
+    /// Open connection
+    stream: Option<TcpStream>,
+    /// Messages are dropped until then after a failed connection attempt
+    retry_at: Option<Instant>,
+    /// Wait after the next failed attempt, doubled on every failure
+    backoff: Duration,
+}
+
+/// Addresses `address` resolves to
+fn resolve(address: &str) -> io::Result<Vec<SocketAddr>> {
+    let addresses: Vec<_> = address.to_socket_addrs()?.collect();
+    match addresses.is_empty() {
+        true => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("syslog server {address:?} has no address"),
+        )),
+        false => Ok(addresses),
+    }
+}
+
+/// Connects to the first reachable address of `address` within [TCP_TIMEOUT]
+fn connect_tcp(address: &str) -> io::Result<TcpStream> {
+    let mut last = None;
+    for address in resolve(address)? {
+        match TcpStream::connect_timeout(&address, TCP_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(err) => last = Some(err),
+        }
+    }
+    Err(last.unwrap_or_else(|| io::ErrorKind::NotFound.into()))
+}
+
+/// Connection to the syslog daemon
+#[derive(Debug)]
+enum Transport {
+    /// `/dev/log`
+    #[cfg(unix)]
+    Local(std::os::unix::net::UnixDatagram),
+    /// Remote server over UDP
+    Udp(UdpSocket),
+    /// Remote server over TCP, reconnected with a backoff after a failure
+    Tcp {
+        /// Server address
+        address: String,
+        /// Connection state
+        connection: Mutex<TcpConnection>,
+    },
+}
+
+/// Writer sending every formatted event as one syslog message
+#[derive(Debug)]
+pub(crate) struct Syslog {
+    /// Connection
+    transport: Transport,
+    /// Facility of the messages
+    facility: Facility,
+    /// Application name
+    app_name: String,
+    /// Host name sent to remote servers
+    hostname: String,
+}
+
+impl Syslog {
+    /// Connects to the daemon or server of `config`
+    pub(crate) fn connect(config: &SyslogConfig) -> io::Result<Self> {
+        let transport = match config.address.as_deref() {
+            Some(address) if address.starts_with("udp://") => {
+                let server = resolve(&address["udp://".len()..])?[0];
+                // Bound in the address family of the server
+                let socket = match server {
+                    SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+                    SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+                };
+                socket.connect(server)?;
+                Transport::Udp(socket)
+            }
+            Some(address) if address.starts_with("tcp://") => {
+                let address = address["tcp://".len()..].to_owned();
+                let connection = Mutex::new(TcpConnection {
+                    stream: Some(connect_tcp(&address)?),
+                    ..TcpConnection::default()
+                });
+                Transport::Tcp { address, connection }
+            }
+            Some(address) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("syslog address {address:?}: expected udp:// or tcp://"),
+                ))
+            }
+            #[cfg(unix)]
+            None => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect("/dev/log")?;
+                Transport::Local(socket)
+            }
+            #[cfg(not(unix))]
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "no local syslog daemon, set the [syslog] address",
+                ))
+            }
+        };
+        Ok(Self {
+            transport,
+            facility: config.facility,
+            app_name: config.app_name.clone().unwrap_or_else(|| "fsync".into()),
+            hostname: crate::peer::hostname(),
+        })
+    }
+
+    /// Sends `message` with the severity of `level`
+    fn send(&self, level: Level, message: &str) -> io::Result<()> {
+        let severity = match level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        let priority = (self.facility as u8) * 8 + severity;
+        let pid = std::process::id();
+        let message = message.trim_end();
+        match &self.transport {
+            #[cfg(unix)]
+            Transport::Local(socket) => socket
+                .send(
+                    format!(
+                        "<{priority}>{}[{pid}]: {message}",
+                        self.app_name
+                    )
+                    .as_bytes(),
+                )
+                .map(drop),
+            Transport::Udp(socket) => socket.send(self.rfc5424(priority, pid, message).as_bytes()).map(drop),
+            Transport::Tcp { address, connection } => {
+                let message = self.rfc5424(priority, pid, message);
+                // Octet counting, messages may span lines
+                let frame = format!("{} {message}", message.len());
+                let mut connection = connection.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(stream) = connection.stream.as_mut() {
+                    if stream.write_all(frame.as_bytes()).is_ok() {
+                        return Ok(());
+                    }
+                    connection.stream = None;
+                }
+                if connection.retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "syslog server unreachable",
+                    ));
+                }
+                match connect_tcp(address).and_then(|mut stream| stream.write_all(frame.as_bytes()).map(|()| stream)) {
+                    Ok(stream) => {
+                        *connection = TcpConnection {
+                            stream: Some(stream),
+                            ..TcpConnection::default()
+                        };
+                        Ok(())
+                    }
+                    Err(err) => {
+                        connection.backoff = (connection.backoff * 2).clamp(Duration::from_secs(1), MAX_BACKOFF);
+                        connection.retry_at = Some(Instant::now() + connection.backoff);
+                        Err(err)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Message in the RFC 5424 format, the server sets the timestamp
+    fn rfc5424(&self, priority: u8, pid: u32, message: &str) -> String {
+        format!(
+            "<{priority}>1 - {} {} {pid} - - {message}",
+            self.hostname, self.app_name
+        )
+    }
+}
+
+/// Event being formatted
+pub(crate) struct Message<'a> {
+    /// Destination
+    syslog: &'a Syslog,
+    /// Level of the event
+    level: Level,
+    /// Formatted event
+    buffer: Vec<u8>,
+}
+
+impl Write for Message<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Message<'_> {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            // Nowhere left to report a failure of the log itself
+            let _ = self.syslog.send(
+                self.level,
+                &String::from_utf8_lossy(&self.buffer),
+            );
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = Message<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Message {
+            syslog: self,
+            level: Level::INFO,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        Message {
+            syslog: self,
+            level: *meta.level(),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_messages_carry_the_priority() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let syslog = Syslog::connect(&SyslogConfig {
+            address: Some(format!(
+                "udp://{}",
+                server.local_addr().unwrap()
+            )),
+            facility: Facility::Local3,
+            ..SyslogConfig::default()
+        })
+        .unwrap();
+        syslog.send(Level::WARN, "disk full\n").unwrap();
+
+        let mut buf = [0; 512];
+        let len = server.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(
+            message.starts_with("<156>1 - "),
+            "{message}"
+        );
+        assert!(message.ends_with(&format!(
+            " fsync {} - - disk full",
+            std::process::id()
+        )));
+    }
+
+    #[test]
+    fn tcp_messages_are_octet_counted() {
+        use std::io::Read;
+
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let syslog = Syslog::connect(&SyslogConfig {
+            address: Some(format!(
+                "tcp://{}",
+                server.local_addr().unwrap()
+            )),
+            ..SyslogConfig::default()
+        })
+        .unwrap();
+        syslog.send(Level::ERROR, "first\nsecond line").unwrap();
+        drop(syslog);
+
+        let mut received = String::new();
+        server.accept().unwrap().0.read_to_string(&mut received).unwrap();
+        let (len, message) = received.split_once(' ').unwrap();
+        assert_eq!(
+            len.parse::<usize>().unwrap(),
+            message.len()
+        );
+        assert!(
+            message.ends_with(" - - first\nsecond line"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn udp_follows_the_address_family() {
+        // Hosts without IPv6 have nothing to check
+        let Ok(server) = UdpSocket::bind("[::1]:0") else {
+            return;
+        };
+        let syslog = Syslog::connect(&SyslogConfig {
+            address: Some(format!(
+                "udp://{}",
+                server.local_addr().unwrap()
+            )),
+            ..SyslogConfig::default()
+        })
+        .unwrap();
+        syslog.send(Level::INFO, "over IPv6").unwrap();
+        let mut buf = [0; 512];
+        let len = server.recv(&mut buf).unwrap();
+        assert!(std::str::from_utf8(&buf[..len]).unwrap().ends_with(" over IPv6"));
+    }
+}
@@ -1,19 +1,448 @@
-use env_logger::Env;
-use fsync::{App, Config};
+use std::io::IsTerminal;
+
+use fsync::{App, Command, Config, Output};
 use libc::EXIT_FAILURE;
 
 fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-
     let config = Config::from_args().unwrap_or_else(|err| {
         eprintln!("Arguments error: {err}");
         std::process::exit(EXIT_FAILURE);
     });
+    let _logger = fsync::init_logger(&config).unwrap_or_else(|err| {
+        eprintln!("Logger error: {err}");
+        std::process::exit(EXIT_FAILURE);
+    });
 
-    let mut app = App::new(config);
+    let output = config.output();
+    if let Some(listen) = config.metrics() {
+        if let Err(err) = fsync::metrics::serve(listen) {
+            fail("Metrics", err, output);
+        }
+    }
 
-    if let Err(err) = app.run() {
-        eprintln!("Application error: {err}");
-        std::process::exit(EXIT_FAILURE);
+    match config.command() {
+        Command::Serve => {
+            if let Err(err) = fsync::peer::serve(&config) {
+                fail("Server", err, output);
+            }
+            return;
+        }
+        Command::Agent => {
+            #[cfg(feature = "agent")]
+            if let Err(err) = fsync::agent::run(&config) {
+                fail("Agent", err, output);
+            }
+            #[cfg(not(feature = "agent"))]
+            fail(
+                "Agent",
+                "fsync was built without the `agent` feature",
+                output,
+            );
+            #[allow(unreachable_code)]
+            return;
+        }
+        Command::KeyringSet => {
+            if let Err(err) = keyring_set(&config) {
+                fail("Keyring", err, output);
+            }
+            return;
+        }
+        Command::Decrypt => {
+            if let Err(err) = decrypt(&config) {
+                fail("Decrypt", err, output);
+            }
+            return;
+        }
+        Command::Restore => {
+            if let Err(err) = fsync::target::restore(&config) {
+                fail("Restore", err, output);
+            }
+            if output == Output::Json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "restored": config.destination() })
+                );
+            }
+            return;
+        }
+        Command::Scrub => {
+            if let Err(err) = scrub(&config) {
+                fail("Scrub", err, output);
+            }
+            return;
+        }
+        Command::Prune => {
+            if let Err(err) = prune(&config) {
+                fail("Prune", err, output);
+            }
+            return;
+        }
+        Command::Sync | Command::Plan | Command::Apply | Command::Diff | Command::RepairPerms => {}
+    }
+
+    let (command, stat, yes, tui, metadata_only) = (
+        config.command(),
+        config.stat(),
+        config.yes(),
+        config.tui(),
+        config.metadata_only(),
+    );
+    let plan = config.plan().cloned();
+    let status = config.status().map(str::to_owned);
+    let status_token = config.status_token().cloned();
+    let mut app = App::new(config).unwrap_or_else(|err| fail("Destination", err, output));
+    if command == Command::Plan {
+        if let Err(err) = print_plan(&app, output) {
+            fail("Plan", err, output);
+        }
+        return;
+    }
+    if command == Command::Diff {
+        if let Err(err) = print_diff(&app, stat, output) {
+            fail("Diff", err, output);
+        }
+        return;
+    }
+    if metadata_only || command == Command::RepairPerms {
+        let (what, report) = match metadata_only {
+            true => ("Metadata", app.sync_metadata()),
+            false => ("Permissions", app.repair_permissions()),
+        };
+        match report.and_then(|report| print_metadata(&report, output)) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(EXIT_FAILURE),
+            Err(err) => fail(what, err, output),
+        }
+    }
+    if let Some(plan) = plan {
+        match apply_plan(&app, &plan, output) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(EXIT_FAILURE),
+            Err(err) => fail("Apply", err, output),
+        }
+    }
+    if !yes && !app.interactive() {
+        match preview(&app) {
+            Ok(true) => {}
+            Ok(false) => fail(
+                "Preview",
+                "the destination was left unchanged, pass --yes to proceed",
+                output,
+            ),
+            Err(err) => fail("Preview", err, output),
+        }
+    }
+    if let Some(listen) = status {
+        if let Err(err) = fsync::status::serve(&listen, status_token, app.handle()) {
+            fail("Status", err, output);
+        }
+    }
+    let handle = app.handle();
+    #[cfg(unix)]
+    report_on_signals(handle.clone(), output);
+    if !tui && !app.interactive() {
+        console_commands(handle.clone());
+    }
+
+    if !tui && output == Output::Text {
+        if let Some(bars) = fsync::progress::ProgressBars::stdout() {
+            app.add_observer(bars);
+        }
+    }
+    #[cfg(feature = "tui")]
+    let dashboard = match tui {
+        true => Some(fsync::tui::start(&mut app).unwrap_or_else(|err| fail("Dashboard", err, output))),
+        false => None,
+    };
+    #[cfg(not(feature = "tui"))]
+    if tui {
+        fail(
+            "Dashboard",
+            "fsync was built without the `tui` feature",
+            output,
+        );
+    }
+    let result = app.run();
+    #[cfg(feature = "tui")]
+    drop(dashboard);
+    match result {
+        Ok(report) if output == Output::Json => {
+            let summary = serde_json::json!({ "stats": handle.stats(), "failures": report });
+            if let Err(err) = print_json(&summary) {
+                fail("Output", err, output);
+            }
+        }
+        Ok(report) if !report.is_empty() => eprintln!("{report}"),
+        Ok(_) => {}
+        Err(err) => fail("Application", err, output),
+    }
+}
+
+/// Reports the error `err` of `what` on the standard error, and as
+/// `{"error": ..., "context": ...}` on the standard output with
+/// `--output json`, then exits
+fn fail(what: &str, err: impl std::fmt::Display, output: Output) -> ! {
+    eprintln!("{what} error: {err}");
+    if output == Output::Json {
+        println!(
+            "{}",
+            serde_json::json!({ "error": err.to_string(), "context": what.to_lowercase() })
+        );
+    }
+    std::process::exit(EXIT_FAILURE);
+}
+
+/// Prints `value` as JSON on the standard output
+fn print_json(value: &impl serde::Serialize) -> Result<(), fsync::AppError> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).map_err(std::io::Error::from)?
+    );
+    Ok(())
+}
+
+/// Prints what the initial sync would overwrite or remove at the destination
+/// and asks on a terminal. Returns whether the sync proceeds.
+fn preview(app: &App) -> Result<bool, fsync::AppError> {
+    let preview = app.preview(&app.plan()?)?;
+    if preview.is_empty() {
+        return Ok(true);
+    }
+    eprintln!("{preview}");
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    eprint!("Proceed? [y/N]: ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+/// `fsync plan`: prints the changes of the initial sync
+fn print_plan(app: &App, output: Output) -> Result<(), fsync::AppError> {
+    let plan = app.plan()?;
+    match output {
+        Output::Json => print_json(&plan)?,
+        Output::Text => println!("{plan}"),
+    }
+    Ok(())
+}
+
+/// `fsync diff`: prints the differences, with the byte counts with `stat`
+fn print_diff(app: &App, stat: bool, output: Output) -> Result<(), fsync::AppError> {
+    let diff = app.diff()?;
+    match output {
+        Output::Json => print_json(&diff)?,
+        Output::Text if stat => println!("{diff:#}"),
+        Output::Text => println!("{diff}"),
+    }
+    Ok(())
+}
+
+/// `fsync apply`: applies `plan` and prints the report.
+/// Returns whether every change was applied.
+fn apply_plan(app: &App, plan: &fsync::SyncPlan, output: Output) -> Result<bool, fsync::AppError> {
+    let report = app.apply(plan)?;
+    match output {
+        Output::Json => print_json(&report)?,
+        Output::Text => println!("{report}"),
     }
+    Ok(report.failures.is_empty())
+}
+
+/// `--metadata-only` and `fsync repair-perms`: prints the report of the
+/// updated entries. Returns whether every entry was updated.
+fn print_metadata(report: &fsync::MetadataReport, output: Output) -> Result<bool, fsync::AppError> {
+    match output {
+        Output::Json => print_json(&report)?,
+        Output::Text => println!("{report}"),
+    }
+    Ok(report.failures.is_empty())
+}
+
+/// Logs the statistics on `SIGUSR1`, and before exiting on `SIGINT` and `SIGTERM`.
+/// With `--output json` they are also printed as one JSON line each.
+/// `SIGUSR2` asks for a rescan.
+#[cfg(unix)]
+fn report_on_signals(handle: fsync::AppHandle, output: Output) {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    /// Last signal received, zero once handled
+    static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+    extern "C" fn on_signal(signal: libc::c_int) {
+        SIGNAL.store(signal, Ordering::Relaxed);
+    }
+
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGUSR1, libc::SIGUSR2] {
+        // SAFETY: the handler only stores to an atomic
+        unsafe {
+            libc::signal(
+                signal,
+                on_signal as *const () as libc::sighandler_t,
+            )
+        };
+    }
+    std::thread::spawn(move || loop {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let signal = SIGNAL.swap(0, Ordering::Relaxed);
+        if signal == 0 {
+            continue;
+        }
+        if signal == libc::SIGUSR2 {
+            tracing::info!("rescan requested");
+            handle.rescan();
+            continue;
+        }
+        let stats = handle.stats();
+        if output == Output::Json {
+            println!(
+                "{}",
+                serde_json::json!({ "stats": stats })
+            );
+        }
+        match signal {
+            libc::SIGUSR1 => tracing::info!("statistics: {stats}"),
+            signal => {
+                #[cfg(feature = "tui")]
+                fsync::tui::restore();
+                tracing::info!("summary: {stats}");
+                fsync::target::close_archives();
+                std::process::exit(128 + signal);
+            }
+        }
+    });
+}
+
+/// Reads the commands typed on a terminal: `rescan` and `stats`
+fn console_commands(handle: fsync::AppHandle) {
+    use std::io::{BufRead, IsTerminal};
+
+    if !std::io::stdin().is_terminal() {
+        return;
+    }
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line.as_deref().map(str::trim) {
+                Ok("rescan") => {
+                    tracing::info!("rescan requested");
+                    handle.rescan();
+                }
+                Ok("stats") => tracing::info!("statistics: {}", handle.stats()),
+                Ok("") => {}
+                Ok(command) => eprintln!("unknown command {command:?}, try rescan or stats"),
+                Err(_) => return,
+            }
+        }
+    });
+}
+
+/// `fsync keyring set <name>`: stores the first line of standard input
+#[cfg(feature = "keyring")]
+fn keyring_set(config: &Config) -> Result<(), fsync::AppError> {
+    let name = config.entry().unwrap_or_default();
+    eprintln!("Secret for {name:?}:");
+    let mut value = String::new();
+    std::io::stdin().read_line(&mut value)?;
+    fsync::keyring_set(
+        name,
+        value.trim_end_matches(['\r', '\n']),
+    )?;
+    eprintln!("Stored as {{ keyring = {name:?} }}");
+    if config.output() == Output::Json {
+        println!(
+            "{}",
+            serde_json::json!({ "stored": name })
+        );
+    }
+    Ok(())
+}
+
+/// Keychain support is not compiled in
+#[cfg(not(feature = "keyring"))]
+fn keyring_set(_config: &Config) -> Result<(), fsync::AppError> {
+    Err(fsync::AppError::Backend(
+        "fsync was built without the `keyring` feature".into(),
+    ))
+}
+
+/// `fsync decrypt <file> <output>`: restores a file of an encrypted
+/// destination
+#[cfg(feature = "encryption")]
+fn decrypt(config: &Config) -> Result<(), fsync::AppError> {
+    fsync::decrypt(config)?;
+    if config.output() == Output::Json {
+        println!(
+            "{}",
+            serde_json::json!({ "decrypted": config.destination() })
+        );
+    }
+    Ok(())
+}
+
+/// Encryption support is not compiled in
+#[cfg(not(feature = "encryption"))]
+fn decrypt(_config: &Config) -> Result<(), fsync::AppError> {
+    Err(fsync::AppError::Backend(
+        "fsync was built without the `encryption` feature".into(),
+    ))
+}
+
+/// `fsync scrub <dir>`: checks and repairs the files of a destination
+/// stored with parity, fails if some could not be repaired
+#[cfg(feature = "parity")]
+fn scrub(config: &Config) -> Result<(), fsync::AppError> {
+    let report = fsync::scrub(config)?;
+    match config.output() {
+        Output::Json => print_json(&report)?,
+        Output::Text => {
+            for path in &report.repaired {
+                println!("repaired {}", path.display());
+            }
+            for path in &report.damaged {
+                println!("damaged  {}", path.display());
+            }
+            println!(
+                "{} checked, {} repaired, {} damaged, {} without parity",
+                report.checked,
+                report.repaired.len(),
+                report.damaged.len(),
+                report.unprotected
+            );
+        }
+    }
+    match report.damaged.len() {
+        0 => Ok(()),
+        damaged => Err(fsync::AppError::Backend(format!(
+            "{damaged} files could not be repaired"
+        ))),
+    }
+}
+
+/// `fsync prune <dir>`: removes the versions of a dedup destination the
+/// retention rules do not keep and prints what was freed
+fn prune(config: &Config) -> Result<(), fsync::AppError> {
+    let report = fsync::target::prune(config)?;
+    match config.output() {
+        Output::Json => print_json(&report)?,
+        Output::Text => println!(
+            "{} versions kept, {} removed, {} chunks removed ({})",
+            report.kept,
+            report.removed,
+            report.chunks,
+            fsync::format_bytes(report.freed)
+        ),
+    }
+    Ok(())
+}
+
+/// Parity support is not compiled in
+#[cfg(not(feature = "parity"))]
+fn scrub(_config: &Config) -> Result<(), fsync::AppError> {
+    Err(fsync::AppError::Backend(
+        "fsync was built without the `parity` feature".into(),
+    ))
 }
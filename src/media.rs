@@ -0,0 +1,282 @@
+//! Privacy and previews of the synchronised images.
+//!
+//! With `strip_gps` in the `[media]` section the [StripGps] transform
+//! clears the GPS position from every Exif segment of the JPEG images on
+//! their way to the destination and blanks their XMP metadata, which may
+//! repeat it, keeping the size of the files, so photos can be shared
+//! without telling where they were taken. With `thumbnails` a
+//! preview of every JPEG and PNG image, at most `thumbnail_size` pixels on
+//! its longer side, is stored in that directory of the destination under
+//! the path of the image, and removed and renamed with it:
+//!
+//! ```toml
+//! [media]
+//! strip_gps = true
+//! thumbnails = ".thumbnails"      # photos/a.jpg -> .thumbnails/photos/a.jpg
+//! thumbnail_size = 256
+//! ```
+//!
+//! Images which can not be decoded are stored without a thumbnail.
+
+use std::{
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, ImageFormat, ImageReader};
+use serde::Deserialize;
+
+use crate::{app::Context, paths, transform::Transformed, AppError, Transform};
+
+/// Tag of the IFD0 entry pointing to the GPS IFD
+const GPS_IFD: u16 = 0x8825;
+
+/// Headers of the APP1 segments holding XMP metadata, the main packet and
+/// the extended one
+const XMP: &[&[u8]] = &[b"http://ns.adobe.com/xap/1.0/\0", b"http://ns.adobe.com/xmp/extension/\0"];
+
+/// Bytes of a value of every TIFF type, by its number
+const TYPE_SIZES: [usize; 13] = [0, 1, 1, 2, 4, 8, 1, 1, 2, 4, 8, 4, 8];
+
+/// `[media]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct MediaConfig {
+    /// Whether the GPS position is cleared from the JPEG images
+    #[serde(default)]
+    pub(crate) strip_gps: bool,
+    /// Directory of the destination the thumbnails are stored in, none are
+    /// made if not set
+    #[serde(default)]
+    pub(crate) thumbnails: Option<PathBuf>,
+    /// Longer side of the thumbnails, in pixels
+    #[serde(default = "MediaConfig::default_thumbnail_size")]
+    pub(crate) thumbnail_size: u32,
+}
+
+impl MediaConfig {
+    /// Default of [MediaConfig::thumbnail_size]
+    fn default_thumbnail_size() -> u32 {
+        256
+    }
+}
+
+/// [Transform] clearing the GPS position from the Exif data of JPEG
+/// images and blanking their XMP metadata, other files are passed on as
+/// they are
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StripGps;
+
+impl Transform for StripGps {
+    fn transform<'a>(&self, source: &Path, mut input: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        let mut head = Vec::new();
+        input.by_ref().take(2).read_to_end(&mut head)?;
+        if head != [0xFF, 0xD8] {
+            return Ok(Box::new(
+                io::Cursor::new(head).chain(input),
+            ));
+        }
+        let mut jpeg = head;
+        input.read_to_end(&mut jpeg)?;
+        if strip_gps(&mut jpeg) {
+            tracing::debug!(
+                "{}: GPS position cleared",
+                source.display()
+            );
+        }
+        Ok(Box::new(io::Cursor::new(jpeg)))
+    }
+}
+
+/// Clears the GPS IFDs of the Exif segments of the JPEG image `jpeg` and
+/// fills its XMP packets with spaces, returns whether it had any
+fn strip_gps(jpeg: &mut [u8]) -> bool {
+    let mut cleared = false;
+    let mut at = 2;
+    while let Some(&[0xFF, marker, high, low]) = jpeg.get(at..at + 4) {
+        // The image data follows the start of the scan
+        if marker == 0xDA {
+            break;
+        }
+        let len = usize::from(u16::from_be_bytes([high, low]));
+        let Some(segment) = jpeg.get_mut(at + 4..at + 2 + len) else {
+            break;
+        };
+        if marker == 0xE1 && segment.starts_with(b"Exif\0\0") {
+            cleared |= clear_gps(&mut segment[6..]).is_some();
+        }
+        if let Some(header) = XMP.iter().find(|header| marker == 0xE1 && segment.starts_with(header)) {
+            segment[header.len()..].fill(b' ');
+            cleared = true;
+        }
+        at += 2 + len;
+    }
+    cleared
+}
+
+/// Zeroes the entries and the values of the GPS IFD of the TIFF structure
+/// `tiff`, none if it has none
+fn clear_gps(tiff: &mut [u8]) -> Option<()> {
+    let big_endian = match tiff.get(..4)? {
+        b"MM\0*" => true,
+        b"II*\0" => false,
+        _ => return None,
+    };
+    let u16_at = |tiff: &[u8], at: usize| {
+        let bytes = tiff.get(at..at + 2)?.try_into().ok()?;
+        Some(match big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    };
+    let offset_at = |tiff: &[u8], at: usize| {
+        let bytes = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some(match big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        } as usize)
+    };
+    let ifd0 = offset_at(tiff, 4)?;
+    let gps = (0..usize::from(u16_at(tiff, ifd0)?)).find_map(|index| {
+        let entry = ifd0 + 2 + 12 * index;
+        (u16_at(tiff, entry)? == GPS_IFD).then(|| offset_at(tiff, entry + 8))?
+    })?;
+    let count = usize::from(u16_at(tiff, gps)?);
+    for index in 0..count {
+        let entry = gps + 2 + 12 * index;
+        let kind = usize::from(u16_at(tiff, entry + 2)?);
+        let len = TYPE_SIZES.get(kind).copied().unwrap_or_default() * offset_at(tiff, entry + 4)?;
+        // Larger values are stored at an offset
+        if len > 4 {
+            let offset = offset_at(tiff, entry + 8)?;
+            if let Some(value) = tiff.get_mut(offset..offset + len) {
+                value.fill(0);
+            }
+        }
+    }
+    // An IFD without entries
+    tiff.get_mut(gps..gps + 2 + 12 * count)?.fill(0);
+    Some(())
+}
+
+/// Thumbnail of the image `src`, at most `size` pixels on its longer
+/// side, in the format of the image; none if it is not a JPEG or PNG image
+///
+/// # Errors
+///
+/// Errors reading the image are returned, [AppError::Backend] if it can
+/// not be decoded or the thumbnail encoded.
+pub(crate) fn thumbnail(src: &Path, size: u32) -> Result<Option<Transformed>, AppError> {
+    let format = match ImageFormat::from_path(src) {
+        Ok(format @ (ImageFormat::Jpeg | ImageFormat::Png)) => format,
+        _ => return Ok(None),
+    };
+    let image = ImageReader::open(paths::extended(src))
+        .context("open", src)?
+        .with_guessed_format()
+        .context("read", src)?
+        .decode()
+        .map_err(|err| {
+            AppError::Backend(format!(
+                "image {}: {err}",
+                src.display()
+            ))
+        })?
+        .thumbnail(size, size);
+    // JPEG has no transparency
+    let image = match format {
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()),
+        _ => image,
+    };
+    let (thumbnail, mut file) = Transformed::create().context("create thumbnail of", src)?;
+    let mut output = io::BufWriter::new(&mut file);
+    image.write_to(&mut output, format).map_err(|err| {
+        AppError::Backend(format!(
+            "thumbnail of {}: {err}",
+            src.display()
+        ))
+    })?;
+    output.flush().context("write", &thumbnail.path)?;
+    drop(output);
+    let modified = std::fs::metadata(paths::extended(src))
+        .and_then(|meta| meta.modified())
+        .context("read modification time", src)?;
+    file.set_modified(modified).context("write", &thumbnail.path)?;
+    Ok(Some(thumbnail))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_gps_and_makes_thumbnails() {
+        // IFD0 pointing to a GPS IFD with an inline and an offset value
+        let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+        tiff.extend([1, 0]);
+        tiff.extend([0x25, 0x88, 4, 0, 1, 0, 0, 0, 26, 0, 0, 0]);
+        tiff.extend([0; 4]);
+        tiff.extend([2, 0]);
+        tiff.extend([1, 0, 2, 0, 2, 0, 0, 0, b'N', 0, 0, 0]);
+        tiff.extend([2, 0, 5, 0, 3, 0, 0, 0, 56, 0, 0, 0]);
+        tiff.extend([0; 4]);
+        tiff.extend([0x11; 24]);
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend((8 + tiff.len() as u16).to_be_bytes());
+        jpeg.extend(b"Exif\0\0");
+        jpeg.extend(&tiff);
+        jpeg.extend([0xFF, 0xD9]);
+
+        let mut stripped = Vec::new();
+        StripGps
+            .transform(Path::new("a.jpg"), Box::new(&jpeg[..]))
+            .unwrap()
+            .read_to_end(&mut stripped)
+            .unwrap();
+        assert_eq!(stripped.len(), jpeg.len());
+        // The GPS IFD and its values start 26 bytes into the TIFF data
+        let gps = 12 + 26;
+        assert!(stripped[gps..gps + 54].iter().all(|&byte| byte == 0));
+        assert_eq!(&stripped[..gps], &jpeg[..gps]);
+        assert_eq!(&stripped[gps + 54..], &jpeg[gps + 54..]);
+
+        // Another Exif segment and an XMP packet repeating the position
+        let xmp = br#"<x:xmpmeta><rdf:Description exif:GPSLatitude="51,30.0N"/></x:xmpmeta>"#;
+        let mut repeated = jpeg[..jpeg.len() - 2].to_vec();
+        repeated.extend_from_within(2..jpeg.len() - 2);
+        repeated.extend([0xFF, 0xE1]);
+        repeated.extend((2 + XMP[0].len() as u16 + xmp.len() as u16).to_be_bytes());
+        repeated.extend(XMP[0]);
+        repeated.extend(xmp);
+        repeated.extend([0xFF, 0xD9]);
+        assert!(strip_gps(&mut repeated));
+        let second = jpeg.len() - 2 + gps - 2;
+        assert!(repeated[second..second + 54].iter().all(|&byte| byte == 0));
+        assert!(!repeated.windows(4).any(|window| window == b"51,3"));
+        let mut kept = Vec::new();
+        StripGps
+            .transform(
+                Path::new("a.txt"),
+                Box::new(&b"text"[..]),
+            )
+            .unwrap()
+            .read_to_end(&mut kept)
+            .unwrap();
+        assert_eq!(kept, b"text");
+
+        let src = std::env::temp_dir().join(format!(
+            "fsync-media-{}.png",
+            std::process::id()
+        ));
+        image::RgbImage::new(64, 32).save(&src).unwrap();
+        let made = thumbnail(&src, 16).unwrap().unwrap();
+        let image = ImageReader::open(&made.path)
+            .unwrap()
+            .with_guessed_format()
+            .unwrap()
+            .decode()
+            .unwrap();
+        assert_eq!((image.width(), image.height()), (16, 8));
+        assert!(thumbnail(Path::new("notes.txt"), 16).unwrap().is_none());
+        std::fs::remove_file(src).unwrap();
+    }
+}
@@ -0,0 +1,418 @@
+//! Prometheus metrics.
+//!
+//! Counters are process wide, so `fsync agent` reports the sum of all of its
+//! jobs. `--metrics <addr>` (or `metrics = "<addr>"` in the configuration
+//! file) serves them in the text exposition format at `/metrics`.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::AppError;
+
+/// Kinds of [AppError] counted by `fsync_errors_total`
+const ERROR_KINDS: [&str; 7] = [
+    "io",
+    "system_time",
+    "path",
+    "strip_prefix",
+    "backend",
+    "insufficient_space",
+    "quota",
+];
+
+/// Counters and gauges of the process
+struct Metrics {
+    /// Files uploaded to the destination
+    copied: AtomicU64,
+    /// Entries removed from the destination
+    removed: AtomicU64,
+    /// Entries renamed at the destination
+    renamed: AtomicU64,
+    /// Bytes of the uploaded files
+    bytes: AtomicU64,
+    /// Changes waiting in the offline queue
+    queued: AtomicU64,
+    /// Failures by [ERROR_KINDS]
+    errors: [AtomicU64; ERROR_KINDS.len()],
+    /// Seconds since the epoch of the last successful synchronisation
+    last_sync: AtomicU64,
+    /// `1` while the watchdog sees no events
+    stale: AtomicU64,
+}
+
+/// Metrics of the process
+static METRICS: Metrics = Metrics {
+    copied: AtomicU64::new(0),
+    removed: AtomicU64::new(0),
+    renamed: AtomicU64::new(0),
+    bytes: AtomicU64::new(0),
+    queued: AtomicU64::new(0),
+    errors: [const { AtomicU64::new(0) }; ERROR_KINDS.len()],
+    last_sync: AtomicU64::new(0),
+    stale: AtomicU64::new(0),
+};
+
+/// Counts a file uploaded with `bytes` bytes
+pub(crate) fn copied(bytes: u64) {
+    METRICS.copied.fetch_add(1, Ordering::Relaxed);
+    METRICS.bytes.fetch_add(bytes, Ordering::Relaxed);
+    synced();
+}
+
+/// Counts a removed entry
+pub(crate) fn removed() {
+    METRICS.removed.fetch_add(1, Ordering::Relaxed);
+    synced();
+}
+
+/// Counts a renamed entry
+pub(crate) fn renamed() {
+    METRICS.renamed.fetch_add(1, Ordering::Relaxed);
+    synced();
+}
+
+/// Records the number of changes waiting in the offline queue
+pub(crate) fn queued(len: usize) {
+    METRICS.queued.store(len as u64, Ordering::Relaxed);
+}
+
+/// Counts a failure
+pub(crate) fn error(error: &AppError) {
+    let kind = match error.root_cause() {
+        AppError::IoError(_) | AppError::Watch(_) => 0,
+        AppError::SystemTime(_) => 1,
+        AppError::PathErr(_) | AppError::StalePlan { .. } => 2,
+        AppError::StripPrefix(_) => 3,
+        AppError::Backend(_) => 4,
+        AppError::InsufficientSpace { .. } => 5,
+        AppError::QuotaExceeded { .. } => 6,
+        // Wrappers, never the root cause
+        AppError::Failed { .. } | AppError::TooManyErrors { .. } => 0,
+    };
+    METRICS.errors[kind].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Marks the destination as up to date
+pub(crate) fn synced() {
+    METRICS.last_sync.store(
+        crate::peer::unix_secs(SystemTime::now()),
+        Ordering::Relaxed,
+    );
+}
+
+/// Records whether the watchdog considers the watcher dead
+pub(crate) fn stale(stale: bool) {
+    METRICS.stale.store(stale.into(), Ordering::Relaxed);
+}
+
+/// Current values, used by the OpenTelemetry export
+#[cfg(feature = "otel")]
+pub(crate) struct Values {
+    /// Files uploaded to the destination
+    pub(crate) copied: u64,
+    /// Entries removed from the destination
+    pub(crate) removed: u64,
+    /// Entries renamed at the destination
+    pub(crate) renamed: u64,
+    /// Bytes of the uploaded files
+    pub(crate) bytes: u64,
+    /// Changes waiting in the offline queue
+    pub(crate) queued: u64,
+    /// Failures by error kind
+    pub(crate) errors: [(&'static str, u64); ERROR_KINDS.len()],
+    /// Seconds since the epoch of the last successful synchronisation
+    pub(crate) last_sync: u64,
+    /// `1` while the watchdog sees no events
+    pub(crate) stale: u64,
+}
+
+/// Reads the current values
+#[cfg(feature = "otel")]
+pub(crate) fn values() -> Values {
+    let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+    let mut errors = ERROR_KINDS.map(|kind| (kind, 0));
+    for ((_, value), counter) in errors.iter_mut().zip(&METRICS.errors) {
+        *value = load(counter);
+    }
+    Values {
+        copied: load(&METRICS.copied),
+        removed: load(&METRICS.removed),
+        renamed: load(&METRICS.renamed),
+        bytes: load(&METRICS.bytes),
+        queued: load(&METRICS.queued),
+        errors,
+        last_sync: load(&METRICS.last_sync),
+        stale: load(&METRICS.stale),
+    }
+}
+
+/// Metrics in the Prometheus text exposition format
+fn render() -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+        let _ = writeln!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} {kind}"
+        );
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+    let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+
+    metric(
+        "fsync_files_copied_total",
+        "counter",
+        "Files uploaded to the destination.",
+        &[("", load(&METRICS.copied))],
+    );
+    metric(
+        "fsync_files_removed_total",
+        "counter",
+        "Entries removed from the destination.",
+        &[("", load(&METRICS.removed))],
+    );
+    metric(
+        "fsync_files_renamed_total",
+        "counter",
+        "Entries renamed at the destination.",
+        &[("", load(&METRICS.renamed))],
+    );
+    metric(
+        "fsync_bytes_transferred_total",
+        "counter",
+        "Bytes of the uploaded files.",
+        &[("", load(&METRICS.bytes))],
+    );
+    metric(
+        "fsync_queued_changes",
+        "gauge",
+        "Changes waiting for an unavailable destination.",
+        &[("", load(&METRICS.queued))],
+    );
+    let labels = ERROR_KINDS.map(|kind| format!("{{kind=\"{kind}\"}}"));
+    let errors = labels
+        .iter()
+        .zip(&METRICS.errors)
+        .map(|(labels, value)| (labels.as_str(), load(value)))
+        .collect::<Vec<_>>();
+    metric(
+        "fsync_errors_total",
+        "counter",
+        "Failed operations by error kind.",
+        &errors,
+    );
+    metric(
+        "fsync_last_sync_timestamp_seconds",
+        "gauge",
+        "Time of the last successful synchronisation.",
+        &[("", load(&METRICS.last_sync))],
+    );
+    metric(
+        "fsync_watcher_stale",
+        "gauge",
+        "1 if the watcher delivered no events for the watchdog timeout.",
+        &[("", load(&METRICS.stale))],
+    );
+    out
+}
+
+/// Serves the metrics at `http://<listen>/metrics` in a background thread.
+///
+/// # Errors
+///
+/// [AppError::IoError] is returned if `listen` could not be bound.
+pub fn serve(listen: &str) -> Result<(), AppError> {
+    let listener = TcpListener::bind(listen)?;
+    tracing::info!(
+        "metrics available at http://{}/metrics",
+        listener.local_addr()?
+    );
+    respond(listener, |request| {
+        match request.path.as_str() {
+            "/metrics" => Some(Response {
+                status: "200 OK",
+                content_type: "text/plain; version=0.0.4",
+                body: render(),
+            }),
+            _ => None,
+        }
+    });
+    Ok(())
+}
+
+/// Request received by [respond]
+pub(crate) struct Request {
+    /// Method, e.g. `GET`
+    pub(crate) method: String,
+    /// Path and query
+    pub(crate) path: String,
+    /// Header names in lower case and their values
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Value of the header `name`, given in lower case
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reply of a [respond] route
+pub(crate) struct Response {
+    /// Status line, e.g. `200 OK`
+    pub(crate) status: &'static str,
+    /// `Content-Type` header
+    pub(crate) content_type: &'static str,
+    /// Body
+    pub(crate) body: String,
+}
+
+/// Longest accepted request line and headers
+const MAX_REQUEST: u64 = 8 * 1024;
+/// Connections answered at the same time, later ones are refused
+const MAX_CONNECTIONS: usize = 16;
+/// Read and write timeout of a connection
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answers the requests of `listener` in background threads, `route`
+/// returns `None` for unknown paths
+pub(crate) fn respond<F>(listener: TcpListener, route: F)
+where
+    F: Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+{
+    let route = Arc::new(route);
+    let active = Arc::new(AtomicUsize::new(0));
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            if active.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+                active.fetch_sub(1, Ordering::AcqRel);
+                let _ = stream.set_write_timeout(Some(TIMEOUT));
+                let _ = reply(&stream, "503 Service Unavailable", None);
+                continue;
+            }
+            let (route, active) = (route.clone(), active.clone());
+            std::thread::spawn(move || {
+                if let Err(err) = answer(stream, &*route) {
+                    tracing::debug!("HTTP request failed: {err}");
+                }
+                active.fetch_sub(1, Ordering::AcqRel);
+            });
+        }
+    });
+}
+
+/// Reads one request of `stream` and sends the reply of `route`
+fn answer<F>(stream: TcpStream, route: &F) -> io::Result<()>
+where
+    F: Fn(&Request) -> Option<Response>,
+{
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Read every header, so closing the connection doesn't reset it
+    let mut headers = Vec::new();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || !header.ends_with('\n') {
+            return reply(&stream, "400 Bad Request", None);
+        }
+        if header.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((
+                name.trim().to_ascii_lowercase(),
+                value.trim().to_owned(),
+            ));
+        }
+    }
+    let mut parts = request.split_whitespace();
+    let request = parts.next().zip(parts.next()).map(|(method, path)| Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        headers,
+    });
+    match request.as_ref().and_then(route) {
+        Some(response) => reply(
+            &stream,
+            response.status,
+            Some(&response),
+        ),
+        None => reply(&stream, "404 Not Found", None),
+    }
+}
+
+/// Sends `status` and the body of `response`, if any, and closes the connection
+fn reply(mut stream: &TcpStream, status: &str, response: Option<&Response>) -> io::Result<()> {
+    let response = match response {
+        Some(Response { content_type, body, .. }) => format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        ),
+        None => format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    };
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition_format() {
+        copied(10);
+        error(&AppError::Backend("unreachable".into()));
+        let text = render();
+        assert!(text.contains("# TYPE fsync_files_copied_total counter\n"));
+        assert!(text
+            .lines()
+            .any(|line| line.starts_with("fsync_bytes_transferred_total ") && line != "fsync_bytes_transferred_total 0"));
+        assert!(text.contains("fsync_errors_total{kind=\"io\"} "));
+        assert!(!text.contains("fsync_errors_total{kind=\"backend\"} 0\n"));
+    }
+
+    #[test]
+    fn answers_bounded_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        respond(listener, |request| {
+            (request.method == "GET" && request.header("host") == Some("localhost")).then(|| Response {
+                status: "200 OK",
+                content_type: "text/plain",
+                body: "hello".into(),
+            })
+        });
+        let request = |request: &[u8]| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = request(b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+        assert!(request(b"GET /other HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 "));
+        // Headers longer than the limit
+        let mut endless = b"GET / HTTP/1.1\r\n".to_vec();
+        endless.resize(MAX_REQUEST as usize, b'a');
+        assert!(request(&endless).starts_with("HTTP/1.1 400 "));
+    }
+}
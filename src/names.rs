@@ -0,0 +1,348 @@
+//! Destination names valid on every filesystem.
+//!
+//! Linux accepts almost any name, NTFS and exFAT shares reject `<>:"\|?*`,
+//! control characters and names ending with a dot or a space. With a
+//! `[names]` section such characters are escaped as `%` and their hex UTF-8
+//! bytes instead of failing the copy:
+//!
+//! ```toml
+//! [names]
+//! escape = true
+//! # mapping = "/var/lib/fsync/names.jsonl"
+//! ```
+//!
+//! Every escaped path is recorded once in the `mapping` file as a JSON line
+//! with the `escaped` destination path and its `original` one, so the
+//! original names can be restored. Two source names escaping to the same
+//! destination name fail instead of overwriting each other. The mapping lives
+//! next to the offline queue unless `mapping` is set.
+//!
+//! Windows also reserves the device names `CON`, `PRN`, `AUX`, `NUL`,
+//! `COM1`-`COM9` and `LPT1`-`LPT9`, in any case and with any extension
+//! (`aux.txt`, `Nul.tar.gz`). `reserved` decides what happens to them:
+//!
+//! ```toml
+//! [names]
+//! reserved = "escape"   # "keep" (default), "skip" or "escape"
+//! ```
+//!
+//! Skipped entries are logged as a warning. Escaped ones get the last
+//! character before the extension escaped (`CO%4E`, `au%78.txt`) and are
+//! recorded in the mapping like the other escaped names.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::AppError;
+
+/// Characters Windows filesystems reject in names, besides control ones
+const FORBIDDEN: &[char] = &['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+/// Device names Windows reserves, followed by a digit for `COM` and `LPT`
+const RESERVED: &[&str] = &["CON", "PRN", "AUX", "NUL"];
+
+/// Reserved device names followed by a digit
+const NUMBERED: &[&str] = &["COM", "LPT"];
+
+/// `[names]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct NamesConfig {
+    /// Escapes the characters rejected by Windows filesystems
+    #[serde(default)]
+    pub(crate) escape: bool,
+    /// File recording the escaped paths
+    #[serde(default)]
+    pub(crate) mapping: Option<PathBuf>,
+    /// What happens to the device names reserved by Windows
+    #[serde(default)]
+    pub(crate) reserved: ReservedNames,
+}
+
+/// Handling of the device names reserved by Windows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReservedNames {
+    /// Stored as they are, which fails at Windows destinations
+    #[default]
+    Keep,
+    /// Not synchronised, with a warning
+    Skip,
+    /// Stored with a character escaped
+    Escape,
+}
+
+/// Line of the mapping file
+#[derive(Debug, Serialize, Deserialize)]
+struct Mapping {
+    /// Destination path
+    escaped: PathBuf,
+    /// Destination path before escaping
+    original: PathBuf,
+}
+
+/// Maps the destination paths to valid names
+#[derive(Debug)]
+pub(crate) struct Names {
+    /// Escapes the characters rejected by Windows filesystems
+    escape: bool,
+    /// What happens to the device names reserved by Windows
+    reserved: ReservedNames,
+    /// File recording the escaped paths
+    mapping: PathBuf,
+    /// Original paths of the escaped ones
+    escaped: Mutex<HashMap<PathBuf, PathBuf>>,
+}
+
+impl Names {
+    /// Names of `config`, recording the escaped paths in its mapping or
+    /// `mapping` if it has none.
+    ///
+    /// Lines which could not be parsed are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Errors reading the mapping file are returned.
+    pub(crate) fn open(config: &NamesConfig, mapping: PathBuf) -> io::Result<Self> {
+        let mapping = config.mapping.clone().unwrap_or(mapping);
+        let mut escaped = HashMap::new();
+        match fs::File::open(&mapping) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    match serde_json::from_str::<Mapping>(&line?) {
+                        Ok(entry) => {
+                            escaped.insert(entry.escaped, entry.original);
+                        }
+                        Err(err) => tracing::warn!("{mapping:?}: skipping name mapping: {err}"),
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(Self {
+            escape: config.escape,
+            reserved: config.reserved,
+            mapping,
+            escaped: Mutex::new(escaped),
+        })
+    }
+
+    /// Destination path of the destination path `path` before escaping.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::PathErr] is returned if another path escapes to the same
+    /// one, errors writing the mapping file are returned.
+    pub(crate) fn map(&self, path: &Path) -> Result<PathBuf, AppError> {
+        if !self.escape && self.reserved != ReservedNames::Escape {
+            return Ok(path.to_path_buf());
+        }
+        let escaped: PathBuf = path
+            .components()
+            .map(|part| match part {
+                Component::Normal(name) => self.escape(name.to_os_string()),
+                part => part.as_os_str().to_os_string(),
+            })
+            .collect();
+        let mut known = self.escaped.lock().unwrap_or_else(|e| e.into_inner());
+        match known.get(&escaped) {
+            Some(original) if original == path => {}
+            // Also a name which looks escaped already
+            Some(original) => {
+                return Err(AppError::PathErr(format!(
+                    "{} and {} are both stored as {}",
+                    original.display(),
+                    path.display(),
+                    escaped.display()
+                )))
+            }
+            None if escaped == path => {}
+            None => {
+                self.record(&escaped, path)?;
+                tracing::info!(
+                    "{} is stored as {}",
+                    path.display(),
+                    escaped.display()
+                );
+                known.insert(escaped.clone(), path.to_path_buf());
+            }
+        }
+        Ok(escaped)
+    }
+
+    /// Whether `path` is not synchronised because of a reserved name in it
+    pub(crate) fn skips(&self, path: &Path) -> bool {
+        self.reserved == ReservedNames::Skip
+            && path.components().any(|part| {
+                matches!(
+                    part,
+                    Component::Normal(name) if name.to_str().is_some_and(is_reserved)
+                )
+            })
+    }
+
+    /// `name` escaped as configured
+    fn escape(&self, name: OsString) -> OsString {
+        let name = if self.escape { escape(name) } else { name };
+        match name.to_str() {
+            Some(text) if self.reserved == ReservedNames::Escape && is_reserved(text) => escape_reserved(text).into(),
+            _ => name,
+        }
+    }
+
+    /// Appends the mapping of `original` to `escaped` to the mapping file
+    fn record(&self, escaped: &Path, original: &Path) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.mapping)?;
+        writeln!(
+            file,
+            "{}",
+            serde_json::to_string(&Mapping {
+                escaped: escaped.to_path_buf(),
+                original: original.to_path_buf(),
+            })?
+        )
+    }
+}
+
+/// `name` with the characters Windows filesystems reject escaped, names
+/// which are not valid Unicode are kept as they are
+fn escape(name: OsString) -> OsString {
+    let Some(text) = name.to_str() else {
+        return name;
+    };
+    let last = text.char_indices().last().map_or(0, |(index, _)| index);
+    let mut escaped = String::with_capacity(text.len());
+    for (index, c) in text.char_indices() {
+        // Windows drops trailing dots and spaces
+        if FORBIDDEN.contains(&c) || c.is_ascii_control() || (index == last && matches!(c, '.' | ' ')) {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                escaped.push_str(&format!("%{byte:02X}"));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped.into()
+}
+
+/// Whether Windows reserves `name` for a device
+fn is_reserved(name: &str) -> bool {
+    let stem = name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(' ')
+        .to_ascii_uppercase();
+    RESERVED.contains(&stem.as_str())
+        || NUMBERED.iter().any(|device| {
+            stem.strip_prefix(device).is_some_and(|number| {
+                matches!(
+                    number,
+                    "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" | "\u{b9}" | "\u{b2}" | "\u{b3}"
+                )
+            })
+        })
+}
+
+/// Reserved `name` with the last character before the extension escaped
+fn escape_reserved(name: &str) -> String {
+    let end = name.find('.').unwrap_or(name.len());
+    let (stem, extension) = name.split_at(end);
+    let Some((index, last)) = stem.char_indices().last() else {
+        return name.to_string();
+    };
+    let mut escaped = stem[..index].to_string();
+    let mut bytes = [0; 4];
+    for byte in last.encode_utf8(&mut bytes).bytes() {
+        escaped.push_str(&format!("%{byte:02X}"));
+    }
+    escaped + extension
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_rejected_characters_once() {
+        let mapping = std::env::temp_dir().join(format!(
+            "fsync-names-{}.jsonl",
+            std::process::id()
+        ));
+        let config = NamesConfig {
+            escape: true,
+            mapping: None,
+            reserved: ReservedNames::Keep,
+        };
+        let names = Names::open(&config, mapping.clone()).unwrap();
+        assert_eq!(
+            names.map(Path::new("a:b/c?.txt")).unwrap(),
+            PathBuf::from("a%3Ab/c%3F.txt")
+        );
+        assert_eq!(
+            names.map(Path::new("a:b/c?.txt")).unwrap(),
+            PathBuf::from("a%3Ab/c%3F.txt")
+        );
+        assert_eq!(
+            names.map(Path::new("dir./end ")).unwrap(),
+            PathBuf::from("dir%2E/end%20")
+        );
+        assert_eq!(
+            names.map(Path::new("plain/name.txt")).unwrap(),
+            PathBuf::from("plain/name.txt")
+        );
+        // The mapping survives restarts
+        let names = Names::open(&config, mapping.clone()).unwrap();
+        assert!(names.map(Path::new("a:b/c?.txt")).is_ok());
+        assert!(names.map(Path::new("a%3Ab/c%3F.txt")).is_err());
+        assert!(names.map(Path::new("a%3Ab/c?.txt")).is_err());
+        assert_eq!(
+            fs::read_to_string(&mapping).unwrap().lines().count(),
+            2
+        );
+        fs::remove_file(mapping).unwrap();
+    }
+
+    #[test]
+    fn reserved_device_names() {
+        for name in ["CON", "nul", "aux.txt", "Nul.tar.gz", "COM1", "lpt9.log", "com\u{b9}"] {
+            assert!(is_reserved(name), "{name}");
+        }
+        for name in ["CONSOLE", "com0", "LPT10", "nul_", "a.con", ".aux"] {
+            assert!(!is_reserved(name), "{name}");
+        }
+        let mapping = std::env::temp_dir().join(format!(
+            "fsync-reserved-{}.jsonl",
+            std::process::id()
+        ));
+        let mut config = NamesConfig {
+            escape: false,
+            mapping: None,
+            reserved: ReservedNames::Escape,
+        };
+        let names = Names::open(&config, mapping.clone()).unwrap();
+        assert_eq!(
+            names.map(Path::new("aux/con.txt")).unwrap(),
+            PathBuf::from("au%78/co%6E.txt")
+        );
+        assert!(!names.skips(Path::new("aux/con.txt")));
+        config.reserved = ReservedNames::Skip;
+        let names = Names::open(&config, mapping.clone()).unwrap();
+        assert!(names.skips(Path::new("dir/NUL")));
+        assert!(!names.skips(Path::new("dir/null")));
+        assert_eq!(
+            names.map(Path::new("dir/NUL")).unwrap(),
+            PathBuf::from("dir/NUL")
+        );
+        fs::remove_file(mapping).unwrap();
+    }
+}
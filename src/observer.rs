@@ -0,0 +1,74 @@
+//! Callbacks for applications embedding fsync.
+//!
+//! A [SyncObserver] registered with [App::add_observer](crate::App::add_observer)
+//! is told about every change applied to the destination, so a UI, metrics
+//! or a search index can follow the synchronisation:
+//!
+//! ```
+//! use std::path::Path;
+//! use fsync::SyncObserver;
+//!
+//! struct Printer;
+//!
+//! impl SyncObserver for Printer {
+//!     fn on_copy(&self, _source: &Path, destination: &Path, bytes: u64) {
+//!         println!("{} ({bytes} bytes)", destination.display());
+//!     }
+//! }
+//! ```
+//!
+//! Callbacks run on the thread applying the changes, slow observers delay
+//! the synchronisation.
+
+use std::path::Path;
+
+use crate::{watcher::Operation, AppError, Batch};
+
+/// Receiver of the synchronisation events of an [App](crate::App).
+///
+/// Every method does nothing by default. Destination paths are relative to
+/// the destination root.
+pub trait SyncObserver: Send + Sync {
+    /// A change of the source passed the filters and is about to be applied
+    fn on_change(&self, change: &Operation) {
+        let _ = change;
+    }
+
+    /// A file was copied or a directory created
+    fn on_copy(&self, source: &Path, destination: &Path, bytes: u64) {
+        let _ = (source, destination, bytes);
+    }
+
+    /// The initial synchronisation is about to copy `files` files of `bytes`
+    /// bytes
+    fn on_initial_sync(&self, files: u64, bytes: u64) {
+        let _ = (files, bytes);
+    }
+
+    /// `copied` of the `total` bytes of `source` were stored at `destination`.
+    ///
+    /// Called after each chunk of a file being copied, at least once per file.
+    fn on_progress(&self, source: &Path, destination: &Path, copied: u64, total: u64) {
+        let _ = (source, destination, copied, total);
+    }
+
+    /// An entry was removed from the destination
+    fn on_remove(&self, source: &Path, destination: &Path) {
+        let _ = (source, destination);
+    }
+
+    /// An entry of the destination was renamed from the source path `from` to `to`
+    fn on_rename(&self, from: &Path, to: &Path, destination: &Path) {
+        let _ = (from, to, destination);
+    }
+
+    /// Applying `operation` (`copy`, `remove` or `rename`) to `source` failed
+    fn on_error(&self, operation: &str, source: &Path, error: &AppError) {
+        let _ = (operation, source, error);
+    }
+
+    /// The initial synchronisation finished, or the changes seen by the watcher settled
+    fn on_batch_complete(&self, batch: &Batch) {
+        let _ = batch;
+    }
+}
@@ -0,0 +1,159 @@
+//! Synchronisation of the matching files only.
+//!
+//! With `--only <pattern>`, given once per pattern, or `only` in the
+//! configuration file, nothing is synchronised unless it matches one of the
+//! [glob](crate::glob) patterns, relative to the source:
+//!
+//! ```sh
+//! fsync /mnt/shared ./papers --only '*.pdf' --only '*.docx'
+//! ```
+//!
+//! Patterns on the command line replace the ones of the file. Directories
+//! are only created for the matching files in them; removals and renames of
+//! directories apply to the ones already stored. Renaming a file to a
+//! matching name copies it, renaming it away from one removes it.
+
+use std::path::{Path, PathBuf};
+
+use crate::{glob::Glob, paths, queue::Operation};
+
+/// Patterns of the synchronised files
+#[derive(Debug)]
+pub(crate) struct Only {
+    /// Patterns of which one has to match
+    patterns: Vec<Glob>,
+    /// Source root the patterns are relative to
+    root: PathBuf,
+}
+
+impl Only {
+    /// Files of the source `root` matching `patterns`, none if every file is
+    /// synchronised
+    pub(crate) fn new(patterns: &[String], root: &Path) -> Option<Self> {
+        (!patterns.is_empty()).then(|| Self {
+            patterns: patterns.iter().map(|pattern| Glob::new(pattern)).collect(),
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Whether the entry at `path` matches, directories never do
+    pub(crate) fn includes(&self, path: &Path) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        !paths::extended(path).is_dir() && self.patterns.iter().any(|pattern| pattern.matches(relative))
+    }
+
+    /// `operation` limited to the matching files, none if nothing is left.
+    /// `stored_dir` tells whether a source path is a directory at the
+    /// destination.
+    pub(crate) fn select(&self, operation: &Operation, stored_dir: impl Fn(&Path) -> bool) -> Option<Operation> {
+        match operation {
+            Operation::Copy { path } => self.includes(path).then(|| operation.clone()),
+            Operation::Remove { path } => (self.includes(path) || stored_dir(path)).then(|| operation.clone()),
+            Operation::Rename { to, .. } if paths::extended(to).is_dir() => Some(operation.clone()),
+            Operation::Rename { from, to } => match (self.includes(from), self.includes(to)) {
+                (true, true) => Some(operation.clone()),
+                (false, true) => Some(Operation::Copy { path: to.clone() }),
+                (true, false) => Some(Operation::Remove { path: from.clone() }),
+                (false, false) => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// Source with `papers/a.pdf`, `papers/b.docx` and `papers/c.txt`, and
+    /// [Only] of its PDFs and the DOCX files in `papers`
+    fn papers() -> (TempDir, Only) {
+        let root = TempDir::new("only");
+        fs::create_dir_all(root.join("papers")).unwrap();
+        for name in ["papers/a.pdf", "papers/b.docx", "papers/c.txt"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        let only = Only::new(
+            &["*.pdf".to_owned(), "papers/*.docx".to_owned()],
+            &root,
+        )
+        .unwrap();
+        (root, only)
+    }
+
+    #[test]
+    fn only_matching_files_are_included() {
+        let (root, only) = papers();
+        assert!(only.includes(&root.join("papers/a.pdf")));
+        assert!(only.includes(&root.join("papers/b.docx")));
+        assert!(!only.includes(&root.join("papers/c.txt")));
+        assert!(Only::new(&[], &root).is_none());
+    }
+
+    #[test]
+    fn directories_are_not_copied() {
+        let (root, only) = papers();
+        assert!(!only.includes(&root.join("papers")));
+        assert_eq!(
+            only.select(
+                &Operation::Copy {
+                    path: root.join("papers")
+                },
+                |_| false
+            ),
+            None
+        );
+        assert_eq!(
+            only.select(
+                &Operation::Copy {
+                    path: root.join("papers/c.txt")
+                },
+                |_| false
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn renames_to_a_matching_name_copy() {
+        let (root, only) = papers();
+        let rename = Operation::Rename {
+            from: root.join("papers/draft.tmp"),
+            to: root.join("papers/a.pdf"),
+        };
+        assert_eq!(
+            only.select(&rename, |_| false),
+            Some(Operation::Copy {
+                path: root.join("papers/a.pdf")
+            })
+        );
+    }
+
+    #[test]
+    fn renames_away_from_a_matching_name_remove() {
+        let (root, only) = papers();
+        let rename = Operation::Rename {
+            from: root.join("papers/old.pdf"),
+            to: root.join("papers/c.txt"),
+        };
+        assert_eq!(
+            only.select(&rename, |_| false),
+            Some(Operation::Remove {
+                path: root.join("papers/old.pdf")
+            })
+        );
+    }
+
+    #[test]
+    fn removals_of_stored_directories_apply() {
+        let (root, only) = papers();
+        let remove = Operation::Remove { path: root.join("old") };
+        assert_eq!(
+            only.select(&remove, |_| true),
+            Some(remove.clone())
+        );
+        assert_eq!(only.select(&remove, |_| false), None);
+    }
+}
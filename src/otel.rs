@@ -0,0 +1,159 @@
+//! OpenTelemetry export.
+//!
+//! With an `[otlp]` section in the configuration file the `tracing` spans
+//! (see [crate::init_logger]) are exported as traces and the counters of
+//! [crate::metrics] as metrics over OTLP/HTTP.
+//!
+//! ```toml
+//! [otlp]
+//! endpoint = "http://collector:4318"
+//! service_name = "fsync-backup"
+//! ```
+//!
+//! Without `endpoint` the standard `OTEL_EXPORTER_OTLP_*` variables are used.
+
+use opentelemetry::{
+    metrics::{Meter, MeterProvider},
+    trace::TracerProvider,
+    KeyValue,
+};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{metrics::SdkMeterProvider, trace::SdkTracerProvider, Resource};
+use serde::Deserialize;
+
+use crate::{metrics, AppError};
+
+/// `[otlp]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OtlpConfig {
+    /// Base URL of the OTLP/HTTP collector, `/v1/traces` and `/v1/metrics`
+    /// are appended
+    #[serde(default)]
+    pub(crate) endpoint: Option<String>,
+    /// `service.name` resource attribute, `fsync` if not set
+    #[serde(default)]
+    pub(crate) service_name: Option<String>,
+}
+
+/// Exporters, flushed when dropped
+pub(crate) struct Telemetry {
+    /// Trace pipeline
+    tracer: SdkTracerProvider,
+    /// Metric pipeline
+    meter: SdkMeterProvider,
+}
+
+impl Telemetry {
+    /// Builds the exporters of `config`.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if the endpoint is not valid.
+    pub(crate) fn new(config: &OtlpConfig) -> Result<Self, AppError> {
+        let error = |e: opentelemetry_otlp::ExporterBuildError| AppError::Backend(format!("otlp: {e}"));
+        let endpoint = |path: &str| {
+            config.endpoint.as_ref().map(|endpoint| {
+                format!(
+                    "{}{path}",
+                    endpoint.trim_end_matches('/')
+                )
+            })
+        };
+        let resource = Resource::builder()
+            .with_service_name(config.service_name.clone().unwrap_or_else(|| "fsync".into()))
+            .build();
+
+        let mut spans = SpanExporter::builder().with_http();
+        if let Some(endpoint) = endpoint("/v1/traces") {
+            spans = spans.with_endpoint(endpoint);
+        }
+        let tracer = SdkTracerProvider::builder()
+            .with_batch_exporter(spans.build().map_err(error)?)
+            .with_resource(resource.clone())
+            .build();
+
+        let mut values = MetricExporter::builder().with_http();
+        if let Some(endpoint) = endpoint("/v1/metrics") {
+            values = values.with_endpoint(endpoint);
+        }
+        let meter = SdkMeterProvider::builder()
+            .with_periodic_exporter(values.build().map_err(error)?)
+            .with_resource(resource)
+            .build();
+        register(&meter.meter("fsync"));
+
+        Ok(Self { tracer, meter })
+    }
+
+    /// Layer exporting the spans
+    pub(crate) fn layer<S>(&self) -> impl tracing_subscriber::Layer<S>
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.tracer.tracer("fsync"))
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        let _ = self.tracer.shutdown();
+        let _ = self.meter.shutdown();
+    }
+}
+
+/// Observes the counters of [crate::metrics]
+fn register(meter: &Meter) {
+    let counter = |name: &'static str, description: &'static str, read: fn(&metrics::Values) -> u64| {
+        meter
+            .u64_observable_counter(name)
+            .with_description(description)
+            .with_callback(move |observer| observer.observe(read(&metrics::values()), &[]))
+            .build();
+    };
+    counter(
+        "fsync.files.copied",
+        "Files uploaded to the destination",
+        |v| v.copied,
+    );
+    counter(
+        "fsync.files.removed",
+        "Entries removed from the destination",
+        |v| v.removed,
+    );
+    counter(
+        "fsync.files.renamed",
+        "Entries renamed at the destination",
+        |v| v.renamed,
+    );
+    counter(
+        "fsync.bytes.transferred",
+        "Bytes of the uploaded files",
+        |v| v.bytes,
+    );
+
+    meter
+        .u64_observable_counter("fsync.errors")
+        .with_description("Failed operations by error kind")
+        .with_callback(|observer| {
+            for (kind, value) in metrics::values().errors {
+                observer.observe(value, &[KeyValue::new("kind", kind)]);
+            }
+        })
+        .build();
+    meter
+        .u64_observable_gauge("fsync.queued_changes")
+        .with_description("Changes waiting for an unavailable destination")
+        .with_callback(|observer| observer.observe(metrics::values().queued, &[]))
+        .build();
+    meter
+        .u64_observable_gauge("fsync.last_sync")
+        .with_description("Time of the last successful synchronisation")
+        .with_unit("s")
+        .with_callback(|observer| observer.observe(metrics::values().last_sync, &[]))
+        .build();
+    meter
+        .u64_observable_gauge("fsync.watcher_stale")
+        .with_description("1 if the watcher delivered no events for the watchdog timeout")
+        .with_callback(|observer| observer.observe(metrics::values().stale, &[]))
+        .build();
+}
@@ -0,0 +1,451 @@
+//! Reed-Solomon parity of the files at local destinations.
+//!
+//! With a `[parity]` section every file stored at a local destination gets
+//! a sidecar next to it, `notes/todo.txt.fsync-par` for `notes/todo.txt`,
+//! holding the SHA-256 of every block of the file and Reed-Solomon parity
+//! blocks. The destination lists, renames and removes the files without
+//! their sidecars showing.
+//!
+//! `fsync scrub <dir>` checks the files against their sidecars and
+//! rebuilds the damaged blocks from the others, without going back to the
+//! source: every stripe of [STRIPE] blocks survives as many damaged blocks
+//! as it has parity blocks, `redundancy` percent of them.
+//!
+//! ```toml
+//! [parity]
+//! block_size = 65536
+//! redundancy = 10
+//! ```
+
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{app::Context, AppError};
+
+/// Suffix appended to the names of the sidecars
+const SUFFIX: &str = ".fsync-par";
+
+/// Start of every sidecar, followed by the block size, the redundancy,
+/// the length of the file and the hash of these
+const MAGIC: &[u8] = b"fsync-par2\n";
+
+/// Bytes of the header fields after [MAGIC]
+const FIELDS_LEN: usize = 13;
+
+/// Largest block size, blocks of a stripe are held in memory
+const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Data blocks of a stripe, the last stripe of a file may have fewer
+pub(crate) const STRIPE: usize = 20;
+
+/// Bytes of a block hash
+const HASH_LEN: usize = 32;
+
+/// `[parity]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct ParityConfig {
+    /// Bytes of a block, the unit which is checked and repaired, at most
+    /// 16 MiB
+    #[serde(default = "ParityConfig::default_block_size")]
+    pub(crate) block_size: u32,
+    /// Parity blocks of a stripe, in percent of its data blocks, at least
+    /// one
+    #[serde(default = "ParityConfig::default_redundancy")]
+    pub(crate) redundancy: u8,
+}
+
+impl ParityConfig {
+    /// Default of [ParityConfig::block_size]
+    fn default_block_size() -> u32 {
+        64 * 1024
+    }
+
+    /// Default of [ParityConfig::redundancy]
+    fn default_redundancy() -> u8 {
+        10
+    }
+}
+
+/// Parity blocks of a stripe of `data` blocks
+fn parity_blocks(data: usize, redundancy: u8) -> usize {
+    (data * usize::from(redundancy)).div_ceil(100).clamp(1, data)
+}
+
+/// Path of the sidecar of the file `path`
+pub(crate) fn sidecar(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(SUFFIX);
+    name.into()
+}
+
+/// Whether `name` is the name of a sidecar
+pub(crate) fn is_sidecar(name: &OsStr) -> bool {
+    name.to_str()
+        .is_some_and(|name| name.len() > SUFFIX.len() && name.ends_with(SUFFIX))
+}
+
+/// Reads up to `buf.len()` bytes, fewer only at the end of `reader`
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+/// Sidecar written in the temporary directory, removed when dropped
+pub(crate) struct Sidecar {
+    /// Sidecar, opened for reading
+    pub(crate) file: fs::File,
+    /// Path of the sidecar
+    path: PathBuf,
+}
+
+impl Drop for Sidecar {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Sidecar of the contents of `reader`
+///
+/// # Errors
+///
+/// Errors reading the contents or writing the sidecar are returned,
+/// [io::ErrorKind::InvalidInput] for a zero redundancy or a block size out
+/// of range.
+pub(crate) fn protect(mut reader: impl Read, config: &ParityConfig) -> io::Result<Sidecar> {
+    /// Sidecars written so far, naming the next one
+    static SIDECARS: AtomicU64 = AtomicU64::new(0);
+
+    if !(1..=MAX_BLOCK_SIZE).contains(&config.block_size) || config.redundancy == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the parity block size must be 1 to {MAX_BLOCK_SIZE} bytes and the redundancy not zero"),
+        ));
+    }
+    let path = std::env::temp_dir().join(format!(
+        "fsync-{}-{}{SUFFIX}",
+        std::process::id(),
+        SIDECARS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    let mut sidecar = Sidecar { file, path };
+    let block_size = config.block_size as usize;
+    // The length and the hash are only known at the end, written over the
+    // placeholders
+    let mut writer = io::BufWriter::new(&sidecar.file);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[0; FIELDS_LEN + HASH_LEN])?;
+    let mut len = 0;
+    let mut end = false;
+    while !end {
+        let mut data = Vec::with_capacity(STRIPE);
+        while data.len() < STRIPE {
+            let mut block = vec![0; block_size];
+            let read = read_full(&mut reader, &mut block)?;
+            if read == 0 {
+                break;
+            }
+            len += read as u64;
+            data.push(block);
+            if read < block_size {
+                end = true;
+                break;
+            }
+        }
+        if data.is_empty() {
+            break;
+        }
+        let mut parity = vec![vec![0; block_size]; parity_blocks(data.len(), config.redundancy)];
+        ReedSolomon::new(data.len(), parity.len())
+            .and_then(|codec| codec.encode_sep(&data, &mut parity))
+            .map_err(|err| io::Error::other(format!("{err:?}")))?;
+        for block in data.iter().chain(&parity) {
+            writer.write_all(&Sha256::digest(block))?;
+        }
+        for block in &parity {
+            writer.write_all(block)?;
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    let mut fields = Vec::with_capacity(FIELDS_LEN + HASH_LEN);
+    fields.extend_from_slice(&config.block_size.to_le_bytes());
+    fields.push(config.redundancy);
+    fields.extend_from_slice(&len.to_le_bytes());
+    fields.extend_from_slice(&header_hash(&fields));
+    sidecar.file.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+    sidecar.file.write_all(&fields)?;
+    sidecar.file.rewind()?;
+    Ok(sidecar)
+}
+
+/// Hash of the header fields
+fn header_hash(fields: &[u8]) -> [u8; HASH_LEN] {
+    Sha256::new().chain_update(MAGIC).chain_update(fields).finalize().into()
+}
+
+/// Result of checking a file against its sidecar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Check {
+    /// Every block matches
+    Intact,
+    /// Damaged blocks were rebuilt
+    Repaired,
+    /// Some stripe has more damaged blocks than parity blocks
+    Damaged,
+}
+
+/// Checks the file `path` against the sidecar `parity`, rebuilding its
+/// damaged blocks
+///
+/// # Errors
+///
+/// Errors reading the file or the sidecar or writing the file are
+/// returned, [io::ErrorKind::InvalidData] for a sidecar which is not one.
+fn check(path: &Path, parity: &Path) -> io::Result<Check> {
+    let mut sidecar = io::BufReader::new(fs::File::open(parity)?);
+    let mut header = [0; MAGIC.len() + FIELDS_LEN + HASH_LEN];
+    sidecar.read_exact(&mut header)?;
+    if !header.starts_with(MAGIC) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a parity sidecar",
+        ));
+    }
+    let (fields, hash) = header[MAGIC.len()..].split_at(FIELDS_LEN);
+    if header_hash(fields) != hash {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "damaged parity sidecar header",
+        ));
+    }
+    let field = |at: usize, len: usize| {
+        header[MAGIC.len() + at..MAGIC.len() + at + len]
+            .iter()
+            .rev()
+            .fold(0u64, |value, &byte| {
+                value << 8 | u64::from(byte)
+            })
+    };
+    let (block_size, redundancy, len) = (
+        field(0, 4) as usize,
+        field(4, 1) as u8,
+        field(5, 8),
+    );
+    if !(1..=MAX_BLOCK_SIZE as usize).contains(&block_size) || redundancy == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "damaged parity sidecar",
+        ));
+    }
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let blocks = len.div_ceil(block_size as u64) as usize;
+    let mut result = match file.metadata()?.len() == len {
+        true => Check::Intact,
+        false => Check::Repaired,
+    };
+    for first in (0..blocks).step_by(STRIPE) {
+        let data = (blocks - first).min(STRIPE);
+        let parity = parity_blocks(data, redundancy);
+        let mut hashes = vec![0; (data + parity) * HASH_LEN];
+        sidecar.read_exact(&mut hashes)?;
+        let mut shards = Vec::with_capacity(data + parity);
+        file.seek(SeekFrom::Start(
+            (first * block_size) as u64,
+        ))?;
+        for _ in 0..data {
+            let mut block = vec![0; block_size];
+            read_full(&mut file, &mut block)?;
+            shards.push(block);
+        }
+        for _ in 0..parity {
+            let mut block = vec![0; block_size];
+            sidecar.read_exact(&mut block)?;
+            shards.push(block);
+        }
+        let mut shards: Vec<_> = shards
+            .into_iter()
+            .zip(hashes.chunks(HASH_LEN))
+            .map(|(block, hash)| (Sha256::digest(&block)[..] == *hash).then_some(block))
+            .collect();
+        let damaged: Vec<_> = (0..data).filter(|&i| shards[i].is_none()).collect();
+        if damaged.is_empty() {
+            continue;
+        }
+        if shards.iter().filter(|shard| shard.is_none()).count() > parity {
+            result = Check::Damaged;
+            continue;
+        }
+        ReedSolomon::new(data, parity)
+            .and_then(|codec| codec.reconstruct_data(&mut shards))
+            .map_err(|err| io::Error::other(format!("{err:?}")))?;
+        for i in damaged {
+            let offset = ((first + i) * block_size) as u64;
+            let block = shards[i].as_deref().unwrap_or_default();
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&block[..block.len().min((len - offset) as usize)])?;
+        }
+        if result == Check::Intact {
+            result = Check::Repaired;
+        }
+    }
+    // Only cut or extended once every block was verified or rebuilt
+    if result == Check::Repaired && file.metadata()?.len() != len {
+        file.set_len(len)?;
+    }
+    file.sync_all()?;
+    Ok(result)
+}
+
+/// Outcome of `fsync scrub`
+#[derive(Debug, Clone, Default, Serialize)]
+#[non_exhaustive]
+pub struct ScrubReport {
+    /// Files checked against their sidecar
+    pub checked: usize,
+    /// Files without a sidecar
+    pub unprotected: usize,
+    /// Files whose damaged blocks were rebuilt
+    pub repaired: Vec<PathBuf>,
+    /// Files too damaged to be rebuilt, or whose sidecar is
+    pub damaged: Vec<PathBuf>,
+}
+
+/// `fsync scrub <dir>`: checks the files of the local destination of
+/// `config` against their sidecars and rebuilds their damaged blocks
+///
+/// # Errors
+///
+/// [AppError::IoError] is returned if the destination can not be walked.
+pub fn scrub(config: &crate::Config) -> Result<ScrubReport, AppError> {
+    let root = config.destination();
+    let mut report = ScrubReport::default();
+    for entry in walkdir::WalkDir::new(root) {
+        let entry = entry.map_err(io::Error::from).context("read", root)?;
+        if !entry.file_type().is_file() || is_sidecar(entry.file_name()) {
+            continue;
+        }
+        let (path, parity) = (entry.path(), sidecar(entry.path()));
+        if !parity.is_file() {
+            report.unprotected += 1;
+            continue;
+        }
+        report.checked += 1;
+        let stored = path.strip_prefix(root)?.to_path_buf();
+        match check(path, &parity) {
+            Ok(Check::Intact) => {}
+            Ok(Check::Repaired) => {
+                tracing::warn!("repaired {path:?}");
+                report.repaired.push(stored);
+            }
+            Ok(Check::Damaged) => {
+                tracing::error!("{path:?} is damaged beyond its parity");
+                report.damaged.push(stored);
+            }
+            Err(err) => {
+                tracing::error!("checking {path:?}: {err}");
+                report.damaged.push(stored);
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repairs_damaged_blocks() {
+        let dir = std::env::temp_dir().join(format!(
+            "fsync-parity-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        // xorshift noise, 50 blocks and a half: 3 stripes
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let contents: Vec<u8> = (0..4096 * 50 + 2048)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let path = dir.join("image.raw");
+        fs::write(&path, &contents).unwrap();
+        let config = ParityConfig {
+            block_size: 4096,
+            redundancy: 10,
+        };
+        let mut protected = protect(&contents[..], &config).unwrap();
+        let mut parity = Vec::new();
+        protected.file.read_to_end(&mut parity).unwrap();
+        fs::write(sidecar(&path), &parity).unwrap();
+        assert_eq!(
+            check(&path, &sidecar(&path)).unwrap(),
+            Check::Intact
+        );
+
+        // Two blocks of the first stripe, the end of the last one
+        let mut damaged = contents.clone();
+        damaged[100] ^= 1;
+        damaged[4096 * 7 + 5] ^= 0xff;
+        damaged[4096 * 50 + 2000] ^= 1;
+        fs::write(&path, &damaged).unwrap();
+        assert_eq!(
+            check(&path, &sidecar(&path)).unwrap(),
+            Check::Repaired
+        );
+        assert_eq!(fs::read(&path).unwrap(), contents);
+
+        // Truncated, the missing blocks are rebuilt
+        fs::write(&path, &contents[..4096 * 49]).unwrap();
+        assert_eq!(
+            check(&path, &sidecar(&path)).unwrap(),
+            Check::Repaired
+        );
+        assert_eq!(fs::read(&path).unwrap(), contents);
+
+        // A damaged length is not trusted
+        let mut header = parity.clone();
+        header[MAGIC.len() + 5] ^= 1;
+        fs::write(sidecar(&path), &header).unwrap();
+        assert!(check(&path, &sidecar(&path)).is_err());
+        assert_eq!(fs::read(&path).unwrap(), contents);
+        fs::write(sidecar(&path), &parity).unwrap();
+        assert!(protect(
+            &contents[..],
+            &ParityConfig {
+                block_size: MAX_BLOCK_SIZE + 1,
+                redundancy: 10,
+            }
+        )
+        .is_err());
+
+        // One block too many for the parity of the stripe
+        damaged[4096 * 3] ^= 1;
+        fs::write(&path, &damaged).unwrap();
+        assert_eq!(
+            check(&path, &sidecar(&path)).unwrap(),
+            Check::Damaged
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+//! Normalization of the paths reported by the watcher.
+//!
+//! Watchers do not always report paths the way the source was configured:
+//! FSEvents reports `/private/var/...` for a source in `/var/...`, a source
+//! reached through a symbolic link may come back resolved, and Windows APIs
+//! hand out `\\?\C:\...` extended-length paths. [EventPaths] maps such paths
+//! back below the configured source, so the destination paths do not depend
+//! on how the kernel reported the change. The other way around, paths too
+//! long for the plain Windows APIs get the `\\?\` prefix with [extended]
+//! before they are read or written.
+//!
+//! macOS stores and reports names decomposed (NFD) while Linux and Windows
+//! mostly keep them composed (NFC), so the same name may reach the
+//! destination in two byte sequences. With `unicode_normalization` the
+//! destination names are converted to one [UnicodeForm]:
+//!
+//! ```toml
+//! unicode_normalization = "nfc"   # or "nfd", unchanged if not set
+//! ```
+//!
+//! The comparison with the destination then also finds a file stored in the
+//! other form, e.g. by an earlier version, and renames it instead of storing
+//! the file twice.
+
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Component, Path, PathBuf, Prefix},
+};
+
+use serde::Deserialize;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::queue::Operation;
+
+/// Maps the paths of events to the configured source
+#[derive(Debug)]
+pub(crate) struct EventPaths {
+    /// Source as configured
+    source: PathBuf,
+    /// Source with the symbolic links resolved, if it exists
+    canonical: Option<PathBuf>,
+}
+
+impl EventPaths {
+    /// Normalization of the paths below `source`
+    pub(crate) fn new(source: &Path) -> Self {
+        Self {
+            source: source.to_path_buf(),
+            canonical: fs::canonicalize(source).ok().map(|path| strip_verbatim(&path)),
+        }
+    }
+
+    /// `path` below the configured source if it is a different form of a
+    /// path inside of it, unchanged otherwise
+    pub(crate) fn normalize(&self, path: PathBuf) -> PathBuf {
+        let path = strip_verbatim(&path);
+        if path.starts_with(&self.source) {
+            return path;
+        }
+        let Some(canonical) = &self.canonical else {
+            return path;
+        };
+        let below = |path: &Path| path.strip_prefix(canonical).ok().map(|relative| self.source.join(relative));
+        std::path::absolute(&path)
+            .ok()
+            .and_then(|absolute| below(&absolute))
+            .or_else(|| resolve(&path).and_then(|resolved| below(&resolved)))
+            .unwrap_or(path)
+    }
+
+    /// `operation` with its paths [normalized](EventPaths::normalize)
+    pub(crate) fn operation(&self, operation: Operation) -> Operation {
+        match operation {
+            Operation::Copy { path } => Operation::Copy {
+                path: self.normalize(path),
+            },
+            Operation::Remove { path } => Operation::Remove {
+                path: self.normalize(path),
+            },
+            Operation::Rename { from, to } => Operation::Rename {
+                from: self.normalize(from),
+                to: self.normalize(to),
+            },
+        }
+    }
+}
+
+/// Unicode normalization form of the destination names
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnicodeForm {
+    /// Composed characters, the usual form on Linux and Windows
+    Nfc,
+    /// Decomposed characters, the form of macOS
+    Nfd,
+}
+
+impl UnicodeForm {
+    /// The other form
+    pub(crate) fn other(self) -> Self {
+        match self {
+            Self::Nfc => Self::Nfd,
+            Self::Nfd => Self::Nfc,
+        }
+    }
+
+    /// `path` with its names converted to the form, names which are not
+    /// valid Unicode are kept as they are
+    pub(crate) fn normalize(self, path: &Path) -> PathBuf {
+        path.components()
+            .map(|part| match part {
+                Component::Normal(name) => match name.to_str() {
+                    Some(name) => OsString::from(match self {
+                        Self::Nfc => name.nfc().collect::<String>(),
+                        Self::Nfd => name.nfd().collect::<String>(),
+                    }),
+                    None => name.to_os_string(),
+                },
+                part => part.as_os_str().to_os_string(),
+            })
+            .collect()
+    }
+}
+
+/// `path` with its symbolic links resolved, only the ones of the parent for
+/// an entry which does not exist anymore
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let resolved = match fs::canonicalize(path) {
+        Ok(resolved) => resolved,
+        Err(_) => fs::canonicalize(path.parent()?).ok()?.join(path.file_name()?),
+    };
+    Some(strip_verbatim(&resolved))
+}
+
+/// Length from which Windows paths get the `\\?\` prefix, the limit of
+/// `MAX_PATH` for directories which leaves room for a 8.3 file name
+#[cfg(windows)]
+const LONG_PATH: usize = 248;
+
+/// `path` as an extended-length `\\?\` path if it is too long for the
+/// plain Windows APIs, unchanged otherwise and on other systems
+pub(crate) fn extended(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    if path.as_os_str().len() >= LONG_PATH {
+        // Extended-length paths are not normalized by Windows
+        let Ok(absolute) = std::path::absolute(path) else {
+            return path.to_path_buf();
+        };
+        let mut components = absolute.components();
+        let Some(Component::Prefix(prefix)) = components.next() else {
+            return absolute;
+        };
+        let mut extended = std::ffi::OsString::from(r"\\?\");
+        match prefix.kind() {
+            Prefix::Disk(disk) => extended.push(format!("{}:", char::from(disk))),
+            Prefix::UNC(server, share) => {
+                extended.push(r"UNC\");
+                extended.push(server);
+                extended.push(r"\");
+                extended.push(share);
+            }
+            _ => return absolute,
+        }
+        extended.push(r"\");
+        let mut extended = PathBuf::from(extended);
+        extended.extend(components.filter(|part| *part != Component::RootDir));
+        return extended;
+    }
+    path.to_path_buf()
+}
+
+/// `path` without the `\\?\` prefix of an extended-length disk path
+pub(crate) fn strip_verbatim(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::VerbatimDisk(disk) => {
+                let mut plain = PathBuf::from(format!("{}:\\", char::from(disk)));
+                plain.extend(components.filter(|part| *part != Component::RootDir));
+                plain
+            }
+            _ => path.to_path_buf(),
+        },
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_resolved_paths_to_the_source() {
+        let root = std::env::temp_dir().join(format!(
+            "fsync-paths-{}",
+            std::process::id()
+        ));
+        let real = root.join("real");
+        fs::create_dir_all(real.join("a")).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&real, root.join("link")).unwrap();
+        #[cfg(windows)]
+        std::os::windows::fs::symlink_dir(&real, root.join("link")).unwrap();
+        let canonical = fs::canonicalize(&real).unwrap();
+
+        let paths = EventPaths::new(&root.join("link"));
+        assert_eq!(
+            paths.normalize(root.join("link/a/b")),
+            root.join("link/a/b")
+        );
+        assert_eq!(
+            paths.normalize(canonical.join("a/b")),
+            root.join("link/a/b")
+        );
+        // Removed already, only the parent is resolved
+        assert_eq!(
+            paths.normalize(canonical.join("gone")),
+            root.join("link/gone")
+        );
+        assert_eq!(
+            paths.normalize("/elsewhere/a".into()),
+            PathBuf::from("/elsewhere/a")
+        );
+        #[cfg(windows)]
+        assert_eq!(
+            strip_verbatim(Path::new(r"\\?\C:\src\a")),
+            PathBuf::from(r"C:\src\a")
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn long_paths_get_the_extended_prefix() {
+        let long = PathBuf::from(r"C:\src").join("d".repeat(300));
+        assert_eq!(
+            extended(&long),
+            PathBuf::from(r"\\?\C:\src").join("d".repeat(300))
+        );
+        let unc = PathBuf::from(r"\\server\share\src").join("d".repeat(300));
+        assert_eq!(
+            extended(&unc),
+            PathBuf::from(r"\\?\UNC\server\share\src").join("d".repeat(300))
+        );
+        assert_eq!(
+            extended(Path::new(r"C:\src\a")),
+            PathBuf::from(r"C:\src\a")
+        );
+    }
+
+    #[test]
+    fn normalizes_names_to_one_form() {
+        let (composed, decomposed) = (
+            "caf\u{e9}/na\u{ef}ve.txt",
+            "cafe\u{301}/nai\u{308}ve.txt",
+        );
+        assert_eq!(
+            UnicodeForm::Nfc.normalize(Path::new(decomposed)),
+            PathBuf::from(composed)
+        );
+        assert_eq!(
+            UnicodeForm::Nfd.normalize(Path::new(composed)),
+            PathBuf::from(decomposed)
+        );
+        assert_eq!(
+            UnicodeForm::Nfc.normalize(Path::new(composed)),
+            PathBuf::from(composed)
+        );
+    }
+}
@@ -0,0 +1,769 @@
+//! Native fsync-to-fsync protocol.
+//!
+//! `fsync serve <dir>` accepts connections from other fsync instances and
+//! applies the pushed changes to `<dir>`. The client side is the
+//! `fwatch://host:port` destination ([PeerTarget](crate::target::PeerTarget)).
+//!
+//! Every frame is a big-endian `u32` length followed by a JSON message.
+//! File contents follow the [Request::Upload] frame as raw bytes.
+//! Large files that already exist on the server are sent as a
+//! [delta](crate::delta) against the server copy instead
+//! ([Request::Signature] and [Request::Delta]).
+//! Interrupted uploads are kept on the server and continued after a resume
+//! handshake: [Request::Partial] reports the length and SHA-256 of the
+//! received prefix, the client compares it with its own content and sends
+//! only the rest ([Request::Upload] with an `offset`).
+//! Clients authenticate with an HMAC-SHA256 of a server chosen nonce
+//! keyed with the shared token, so the token never crosses the wire.
+//! Every frame after the answer to the challenge is [sealed] with keys
+//! derived from the token and the nonces of both sides.
+//!
+//! The protocol runs over TCP or, with the `quic` feature and
+//! `transport = "quic"`, over QUIC streams.
+
+use std::{
+    fs,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    delta::{self, Signature},
+    target::LocalTarget,
+    AppError, Secret, SyncTarget, TargetMetadata,
+};
+
+#[cfg(feature = "quic")]
+pub(crate) mod quic;
+pub(crate) mod sealed;
+
+use sealed::{Role, Sealed};
+
+/// Protocol version, bumped on incompatible changes
+pub(crate) const PROTOCOL_VERSION: u32 = 4;
+/// Default port of `fsync serve`
+pub(crate) const DEFAULT_PORT: u16 = 7979;
+/// Largest accepted JSON frame, enough for the signature of any file
+const MAX_FRAME: u32 = 4 * 1024 * 1024;
+/// Connections served at once, further ones are closed
+const MAX_CONNECTIONS: usize = 16;
+/// Time a TCP client gets to answer the challenge
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `[peer]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PeerConfig {
+    /// Shared secret of the server and its clients
+    pub(crate) token: Secret,
+    /// Address `fsync serve` listens on
+    #[serde(default)]
+    pub(crate) listen: Option<String>,
+    /// Transport used by both sides
+    #[serde(default)]
+    pub(crate) transport: PeerTransport,
+}
+
+/// Transport the protocol runs on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerTransport {
+    /// Single TCP connection
+    #[default]
+    Tcp,
+    /// QUIC stream per session, requires the `quic` feature
+    Quic,
+}
+
+/// Client to server messages
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum Request {
+    /// First message of the connection
+    Hello {
+        /// [PROTOCOL_VERSION] of the client
+        version: u32,
+    },
+    /// Answer to [Response::Challenge]
+    Auth {
+        /// Hex encoded HMAC-SHA256 of the nonce
+        mac: String,
+        /// Hex encoded random nonce of the client, part of the session keys
+        nonce: String,
+    },
+    /// [SyncTarget::metadata]
+    Metadata {
+        /// `/` separated path relative to the served root
+        path: String,
+    },
+    /// [SyncTarget::create_dir_all]
+    CreateDir {
+        /// `/` separated path relative to the served root
+        path: String,
+    },
+    /// [SyncTarget::upload], `len - offset` bytes of content follow
+    Upload {
+        /// `/` separated path relative to the served root
+        path: String,
+        /// Content length
+        len: u64,
+        /// Source modification time, seconds since the epoch
+        mtime: u64,
+        /// Bytes already received by an interrupted upload, see [Request::Partial]
+        #[serde(default)]
+        offset: u64,
+    },
+    /// Content received so far by an interrupted upload, answered with [Response::Partial]
+    Partial {
+        /// `/` separated path relative to the served root
+        path: String,
+    },
+    /// Block signature of an existing file, answered with [Response::Signature]
+    Signature {
+        /// `/` separated path relative to the served root
+        path: String,
+    },
+    /// [SyncTarget::upload] as a delta against the current file, the delta stream follows
+    Delta {
+        /// `/` separated path relative to the served root
+        path: String,
+        /// Block size of the signature the delta was computed against
+        block_size: u32,
+        /// Source modification time, seconds since the epoch
+        mtime: u64,
+    },
+    /// [SyncTarget::remove]
+    Remove {
+        /// `/` separated path relative to the served root
+        path: String,
+    },
+    /// [SyncTarget::rename]
+    Rename {
+        /// Current path
+        from: String,
+        /// New path
+        to: String,
+    },
+}
+
+/// Server to client messages
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub(crate) enum Response {
+    /// Answer to [Request::Hello]
+    Challenge {
+        /// Hex encoded random nonce
+        nonce: String,
+    },
+    /// Request succeeded
+    Ok,
+    /// Answer to [Request::Metadata]
+    Metadata {
+        /// [None] if the entry does not exist
+        entry: Option<WireMetadata>,
+    },
+    /// Answer to [Request::Partial]
+    Partial {
+        /// Received bytes, zero if there is no interrupted upload
+        offset: u64,
+        /// Hex encoded SHA-256 of the received bytes
+        hash: String,
+    },
+    /// Answer to [Request::Signature]
+    Signature {
+        /// Signature of the server copy
+        signature: Signature,
+    },
+    /// Request failed
+    Error {
+        /// Description of the failure
+        message: String,
+    },
+}
+
+/// [TargetMetadata] as sent over the wire
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct WireMetadata {
+    /// Entry is a directory
+    pub(crate) is_dir: bool,
+    /// Size in bytes
+    pub(crate) len: u64,
+    /// Modification time, seconds since the epoch
+    pub(crate) modified: u64,
+}
+
+impl From<TargetMetadata> for WireMetadata {
+    fn from(value: TargetMetadata) -> Self {
+        Self {
+            is_dir: value.is_dir,
+            len: value.len,
+            modified: unix_secs(value.modified),
+        }
+    }
+}
+
+impl From<WireMetadata> for TargetMetadata {
+    fn from(value: WireMetadata) -> Self {
+        Self {
+            is_dir: value.is_dir,
+            len: value.len,
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(value.modified),
+        }
+    }
+}
+
+/// Seconds since the epoch, zero for earlier times
+pub(crate) fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Host name of the machine
+pub(crate) fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut name = [0u8; 256];
+        // SAFETY: the buffer is valid for its whole length
+        if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } == 0 {
+            let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            return String::from_utf8_lossy(&name[..len]).into_owned();
+        }
+    }
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// Writes a single frame
+pub(crate) fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let payload = serde_json::to_vec(message)?;
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()
+}
+
+/// Reads a single frame
+pub(crate) fn read_frame<R: Read, T: for<'de> Deserialize<'de>>(reader: &mut R) -> io::Result<T> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes"),
+        ));
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+/// Hex encoded random nonce of the handshake
+pub(crate) fn random_nonce() -> Result<String, AppError> {
+    let mut nonce = [0; 32];
+    getrandom::getrandom(&mut nonce).map_err(|e| AppError::Backend(e.to_string()))?;
+    Ok(hex(&nonce))
+}
+
+/// Hex encoded HMAC-SHA256 of `nonce` keyed with `token`
+pub(crate) fn sign(token: &str, nonce: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(nonce.as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+/// Constant time check of the client answer
+fn verify(token: &str, nonce: &str, answer: &str) -> bool {
+    let Some(answer) = unhex(answer) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(nonce.as_bytes());
+    mac.verify_slice(&answer).is_ok()
+}
+
+/// Lowercase hex encoding
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decodes [hex] output
+fn unhex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Converts a wire path to a path relative to the served root.
+///
+/// Absolute paths and `..` components are rejected, so clients can't
+/// escape the served directory.
+pub(crate) fn relative_path(path: &str) -> Result<PathBuf, AppError> {
+    let mut result = PathBuf::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => {
+                return Err(AppError::PathErr(format!(
+                    "{path}: parent components are not allowed"
+                )))
+            }
+            _ if part.contains('\\') || (cfg!(windows) && part.contains(':')) => {
+                return Err(AppError::PathErr(format!(
+                    "{path}: invalid component {part:?}"
+                )));
+            }
+            _ => result.push(part),
+        }
+    }
+    Ok(result)
+}
+
+/// [relative_path] of an entry below the served root, never the root
+/// itself
+fn entry_path(path: &str) -> Result<PathBuf, AppError> {
+    let relative = relative_path(path)?;
+    if relative.as_os_str().is_empty() {
+        return Err(AppError::PathErr(format!(
+            "{path:?}: not below the served root"
+        )));
+    }
+    Ok(relative)
+}
+
+/// Server side of a single connection
+struct Session<S> {
+    /// Client connection
+    stream: S,
+    /// Served directory
+    root: Arc<LocalTarget>,
+    /// Shared secret
+    token: Arc<String>,
+}
+
+impl<S: Read + Write> Session<S> {
+    /// Runs the session logging its outcome
+    fn run_logged(self, peer_addr: String, authenticated: impl FnOnce()) {
+        tracing::info!("peer connected: {peer_addr}");
+        match self.run(authenticated) {
+            Ok(()) => tracing::info!("peer disconnected: {peer_addr}"),
+            Err(err) => tracing::error!("peer {peer_addr}: {err}"),
+        }
+    }
+
+    /// Authenticates the client, calling `authenticated` once it did, and
+    /// serves its requests until it disconnects
+    fn run(mut self, authenticated: impl FnOnce()) -> Result<(), AppError> {
+        let (server_nonce, client_nonce) = self.handshake()?;
+        authenticated();
+        write_frame(&mut self.stream, &Response::Ok)?;
+        let Self { stream, root, token } = self;
+        Session {
+            stream: Sealed::new(
+                stream,
+                Role::Server,
+                &token,
+                &server_nonce,
+                &client_nonce,
+            ),
+            root,
+            token,
+        }
+        .serve()
+    }
+
+    /// Checks the version and the answer of the client to the challenge,
+    /// returning the nonces of the server and of the client
+    fn handshake(&mut self) -> Result<(String, String), AppError> {
+        let Request::Hello { version } = read_frame(&mut self.stream)? else {
+            return Err(AppError::Backend(
+                "peer: expected hello".into(),
+            ));
+        };
+        if version != PROTOCOL_VERSION {
+            let message = format!("protocol version {version} is not supported, expected {PROTOCOL_VERSION}");
+            write_frame(
+                &mut self.stream,
+                &Response::Error {
+                    message: message.clone(),
+                },
+            )?;
+            return Err(AppError::Backend(message));
+        }
+
+        let nonce = random_nonce()?;
+        write_frame(
+            &mut self.stream,
+            &Response::Challenge { nonce: nonce.clone() },
+        )?;
+        match read_frame(&mut self.stream)? {
+            Request::Auth {
+                mac,
+                nonce: client_nonce,
+            } if verify(&self.token, &nonce, &mac) => Ok((nonce, client_nonce)),
+            _ => {
+                write_frame(
+                    &mut self.stream,
+                    &Response::Error {
+                        message: "authentication failed".into(),
+                    },
+                )?;
+                Err(AppError::Backend(
+                    "peer: authentication failed".into(),
+                ))
+            }
+        }
+    }
+
+    /// Serves the requests of the authenticated client until it disconnects
+    fn serve(mut self) -> Result<(), AppError> {
+        loop {
+            let request = match read_frame(&mut self.stream) {
+                Ok(request) => request,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err.into()),
+            };
+            tracing::debug!("peer: {request:?}");
+            let response = match self.handle(request) {
+                Ok(response) => response,
+                // Content of a failed upload is still in the stream
+                Err(AppError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                // The rest of a malformed delta can't be skipped
+                Err(AppError::IoError(err)) if err.kind() == io::ErrorKind::InvalidData => return Err(err.into()),
+                Err(err) => Response::Error {
+                    message: err.to_string(),
+                },
+            };
+            write_frame(&mut self.stream, &response)?;
+        }
+    }
+
+    /// Applies a single request to the served directory
+    fn handle(&mut self, request: Request) -> Result<Response, AppError> {
+        match request {
+            Request::Metadata { path } => Ok(Response::Metadata {
+                entry: self.root.metadata(&relative_path(&path)?)?.map(WireMetadata::from),
+            }),
+            Request::CreateDir { path } => {
+                self.root.create_dir_all(&relative_path(&path)?)?;
+                Ok(Response::Ok)
+            }
+            Request::Upload {
+                path,
+                len,
+                mtime,
+                offset,
+            } => {
+                let path = self.root.root().join(entry_path(&path)?);
+                self.receive(&path, len, mtime, offset)?;
+                Ok(Response::Ok)
+            }
+            Request::Partial { path } => {
+                let temp = temp_path(&self.root.root().join(entry_path(&path)?))?;
+                let (offset, hash) = match fs::File::open(temp) {
+                    Ok(file) => {
+                        let mut hasher = Sha256::new();
+                        let offset = io::copy(&mut BufReader::new(file), &mut hasher)?;
+                        (offset, hex(&hasher.finalize()))
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => (0, String::new()),
+                    Err(err) => return Err(err.into()),
+                };
+                Ok(Response::Partial { offset, hash })
+            }
+            Request::Signature { path } => {
+                let file = fs::File::open(self.root.root().join(entry_path(&path)?))?;
+                let block_size = delta::block_size(file.metadata()?.len());
+                let signature = Signature::compute(BufReader::new(file), block_size)?;
+                Ok(Response::Signature { signature })
+            }
+            Request::Delta { path, block_size, mtime } => {
+                let path = self.root.root().join(entry_path(&path)?);
+                self.receive_delta(&path, block_size, mtime)?;
+                Ok(Response::Ok)
+            }
+            Request::Remove { path } => {
+                self.root.remove(&entry_path(&path)?)?;
+                Ok(Response::Ok)
+            }
+            Request::Rename { from, to } => {
+                self.root.rename(&entry_path(&from)?, &entry_path(&to)?)?;
+                Ok(Response::Ok)
+            }
+            Request::Hello { .. } | Request::Auth { .. } => Err(AppError::Backend(
+                "peer: unexpected handshake message".into(),
+            )),
+        }
+    }
+
+    /// Receives the content into a temporary file and moves it to `path`.
+    ///
+    /// With a non-zero `offset` the content continues the temporary file of
+    /// an interrupted upload. The temporary file is kept if the connection
+    /// breaks, so the upload can be resumed later.
+    fn receive(&mut self, path: &Path, len: u64, mtime: u64, offset: u64) -> Result<(), AppError> {
+        let temp = temp_path(path)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = if offset > 0 {
+            let file = fs::OpenOptions::new().write(true).open(&temp)?;
+            if file.metadata()?.len() < offset || offset > len {
+                return Err(AppError::Backend(format!(
+                    "{path:?}: no partial upload of {offset} bytes"
+                )));
+            }
+            file.set_len(offset)?;
+            file
+        } else {
+            fs::File::create(&temp)?
+        };
+        file.seek(SeekFrom::End(0))?;
+
+        let received = io::copy(
+            &mut (&mut self.stream).take(len - offset),
+            &mut file,
+        )?;
+        if received != len - offset {
+            tracing::info!(
+                "peer: upload of {path:?} interrupted after {} bytes",
+                offset + received
+            );
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))?;
+        drop(file);
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+
+    /// Rebuilds `path` from its current content and the delta stream
+    fn receive_delta(&mut self, path: &Path, block_size: u32, mtime: u64) -> Result<(), AppError> {
+        let temp = temp_path(path)?;
+        let base = fs::File::open(path)?;
+        let mut file = BufWriter::new(fs::File::create(&temp)?);
+
+        // The client waits for the response, so nothing past the delta is buffered
+        let result = delta::apply(
+            base,
+            block_size,
+            &mut BufReader::new(&mut self.stream),
+            &mut file,
+        )
+        .and_then(|written| file.flush().map(|()| written));
+        let written = match result {
+            Ok(written) => written,
+            Err(err) => {
+                drop(file);
+                let _ = fs::remove_file(&temp);
+                return Err(err.into());
+            }
+        };
+        tracing::debug!("peer: rebuilt {path:?} ({written} bytes)");
+
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))?;
+        drop(file);
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+}
+
+/// Temporary file the content of `path` is received into
+fn temp_path(path: &Path) -> Result<PathBuf, AppError> {
+    let file_name = path.file_name().ok_or_else(|| AppError::PathErr(format!("{path:?}")))?;
+    Ok(path.with_file_name(format!(
+        ".{}.fsync-part",
+        file_name.to_string_lossy()
+    )))
+}
+
+/// Serves the destination of the configuration to other fsync instances.
+///
+/// Every connection (QUIC stream with `transport = "quic"`) is handled
+/// in its own thread.
+///
+/// # Errors
+///
+/// [AppError] is returned if the destination is not a directory, no token
+/// is configured or the listening socket could not be bound.
+pub fn serve(config: &crate::Config) -> Result<(), AppError> {
+    let peer = config.backends.peer.clone().unwrap_or_default();
+    let token = peer.token.expose()?;
+    if token.is_empty() {
+        return Err(AppError::Backend(
+            "fsync serve requires a [peer] token".into(),
+        ));
+    }
+    let token = Arc::new(token.to_owned());
+    let root = Arc::new(LocalTarget::new(
+        config.destination().clone(),
+    ));
+    root.connect()?;
+
+    let listen = config
+        .listen
+        .clone()
+        .or(peer.listen)
+        .unwrap_or_else(|| format!("0.0.0.0:{DEFAULT_PORT}"));
+    match peer.transport {
+        PeerTransport::Tcp => {
+            let listener = TcpListener::bind(&listen)?;
+            tracing::info!(
+                "serving {} on {listen}",
+                root.describe()
+            );
+            serve_tcp(listener, root, token)
+        }
+        #[cfg(feature = "quic")]
+        PeerTransport::Quic => {
+            tracing::info!("serving {}", root.describe());
+            quic::serve(&listen, move |stream, peer_addr| {
+                Session {
+                    stream,
+                    root: root.clone(),
+                    token: token.clone(),
+                }
+                .run_logged(peer_addr, || {})
+            })
+        }
+        #[cfg(not(feature = "quic"))]
+        PeerTransport::Quic => Err(AppError::Backend(
+            "fsync was built without the `quic` feature".into(),
+        )),
+    }
+}
+
+/// Accepts TCP connections, one thread per connection and at most
+/// [MAX_CONNECTIONS] of them
+pub(crate) fn serve_tcp(listener: TcpListener, root: Arc<LocalTarget>, token: Arc<String>) -> Result<(), AppError> {
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream: TcpStream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::warn!("{err}");
+                continue;
+            }
+        };
+        let peer_addr = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        if active.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+            active.fetch_sub(1, Ordering::AcqRel);
+            tracing::warn!("peer {peer_addr}: {MAX_CONNECTIONS} connections served already, closed");
+            continue;
+        }
+        // Clients which don't authenticate don't hold their thread
+        let timeouts = stream
+            .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+            .and_then(|()| stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT)))
+            .and_then(|()| stream.try_clone());
+        let control = match timeouts {
+            Ok(control) => control,
+            Err(err) => {
+                active.fetch_sub(1, Ordering::AcqRel);
+                tracing::warn!("peer {peer_addr}: {err}");
+                continue;
+            }
+        };
+        let session = Session {
+            stream,
+            root: root.clone(),
+            token: token.clone(),
+        };
+        let active = active.clone();
+        std::thread::spawn(move || {
+            session.run_logged(peer_addr, || {
+                // Authenticated clients may stay idle while their source is
+                let _ = control.set_read_timeout(None);
+                let _ = control.set_write_timeout(None);
+            });
+            active.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_path_stays_inside_root() {
+        assert_eq!(
+            relative_path("a/./b/").unwrap(),
+            PathBuf::from("a").join("b")
+        );
+        assert_eq!(
+            relative_path("/a").unwrap(),
+            PathBuf::from("a")
+        );
+        assert!(relative_path("a/../../etc").is_err());
+        assert!(relative_path("a\\..\\b").is_err());
+        // The root lists, but can't be written, moved or removed
+        assert_eq!(
+            relative_path("").unwrap(),
+            PathBuf::new()
+        );
+        for root in ["", "/", "./"] {
+            assert!(entry_path(root).is_err());
+        }
+        assert_eq!(
+            entry_path("a").unwrap(),
+            PathBuf::from("a")
+        );
+    }
+
+    #[test]
+    fn unauthenticated_connections_are_bounded() {
+        let root = std::env::temp_dir().join(format!(
+            "fsync-serve-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&root).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let target = Arc::new(LocalTarget::new(root.clone()));
+        std::thread::spawn(move || {
+            serve_tcp(
+                listener,
+                target,
+                Arc::new("secret".into()),
+            )
+        });
+
+        let started = std::time::Instant::now();
+        let idle: Vec<_> = (0..MAX_CONNECTIONS).map(|_| TcpStream::connect(address).unwrap()).collect();
+        let closed = |mut stream: &TcpStream| {
+            stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT * 3)).unwrap();
+            matches!(stream.read(&mut [0; 1]), Ok(0) | Err(_))
+        };
+        // One too many
+        assert!(closed(
+            &TcpStream::connect(address).unwrap()
+        ));
+        // The others are closed once they took too long to authenticate
+        assert!(idle.iter().all(closed));
+        assert!(started.elapsed() < HANDSHAKE_TIMEOUT * 3);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn challenge_answer() {
+        let mac = sign("secret", "nonce");
+        assert!(verify("secret", "nonce", &mac));
+        assert!(!verify("other", "nonce", &mac));
+        assert!(!verify("secret", "nonce", "zz"));
+    }
+}
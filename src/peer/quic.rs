@@ -0,0 +1,278 @@
+//! QUIC transport of the peer protocol.
+//!
+//! Every protocol session runs on its own bidirectional stream, so a
+//! client reconnecting after an error opens a new stream on the existing
+//! connection instead of a new handshake. QUIC also recovers from packet
+//! loss per stream and survives address changes of the client.
+//!
+//! The server uses a self-signed certificate generated at startup. The
+//! traffic is encrypted, but the client relies on the token challenge
+//! rather than on the certificate to trust the server.
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex},
+};
+
+use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+use tokio::runtime::Runtime;
+
+use crate::AppError;
+
+/// Name the server certificate is issued for
+const SERVER_NAME: &str = "fsync";
+/// Application protocol negotiated during the handshake
+const ALPN: &[u8] = b"fsync-peer";
+
+/// Runtime driving the QUIC endpoints
+fn runtime() -> io::Result<Arc<Runtime>> {
+    Ok(Arc::new(
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()?,
+    ))
+}
+
+/// Resolves `address` to a single socket address
+fn resolve(address: &str) -> io::Result<SocketAddr> {
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{address}: no address"),
+        )
+    })
+}
+
+/// Blocking [Read] and [Write] over a QUIC stream
+pub(crate) struct QuicStream {
+    /// Runtime the stream belongs to
+    runtime: Arc<Runtime>,
+    /// Sending half
+    send: SendStream,
+    /// Receiving half
+    recv: RecvStream,
+}
+
+impl Read for QuicStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.runtime.block_on(self.recv.read(buf)) {
+            Ok(read) => Ok(read.unwrap_or(0)),
+            Err(err) => Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                err,
+            )),
+        }
+    }
+}
+
+impl Write for QuicStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.runtime
+            .block_on(self.send.write(buf))
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionReset, e))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for QuicStream {
+    fn drop(&mut self) {
+        let _ = self.send.finish();
+    }
+}
+
+/// Client endpoint keeping a single connection to the server
+pub(crate) struct QuicClient {
+    /// Runtime driving the endpoint
+    runtime: Arc<Runtime>,
+    /// Local UDP endpoint
+    endpoint: Endpoint,
+    /// Connection the streams are opened on
+    connection: Mutex<Option<Connection>>,
+}
+
+impl QuicClient {
+    /// Creates the client endpoint.
+    ///
+    /// # Errors
+    ///
+    /// [AppError] is returned if the UDP socket could not be bound.
+    pub(crate) fn new() -> Result<Self, AppError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(|e| AppError::Backend(format!("quic: {e}")))?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(TokenAuthenticated(provider)))
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        let crypto = QuicClientConfig::try_from(crypto).map_err(|e| AppError::Backend(format!("quic: {e}")))?;
+
+        let runtime = runtime()?;
+        let mut endpoint = {
+            let _guard = runtime.enter();
+            Endpoint::client(SocketAddr::from(([0, 0, 0, 0], 0)))?
+        };
+        endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+        Ok(Self {
+            runtime,
+            endpoint,
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// Opens a new stream to `address`, connecting first if needed
+    pub(crate) fn open(&self, address: &str) -> io::Result<QuicStream> {
+        let _guard = self.runtime.enter();
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let connection = match connection.as_ref() {
+            Some(existing) if existing.close_reason().is_none() => existing.clone(),
+            _ => {
+                let address = resolve(address)?;
+                let connecting = self
+                    .endpoint
+                    .connect(address, SERVER_NAME)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let established = self
+                    .runtime
+                    .block_on(connecting)
+                    .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?;
+                connection.insert(established).clone()
+            }
+        };
+
+        let (send, recv) = self
+            .runtime
+            .block_on(connection.open_bi())
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionReset, e))?;
+        Ok(QuicStream {
+            runtime: self.runtime.clone(),
+            send,
+            recv,
+        })
+    }
+}
+
+/// Accepts any server certificate, the server proves the knowledge of the
+/// token during the protocol handshake instead
+#[derive(Debug)]
+struct TokenAuthenticated(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for TokenAuthenticated {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Accepts QUIC connections on `listen` and passes every stream to `handler`
+/// together with the address of the client.
+///
+/// Streams are handled in their own threads.
+pub(crate) fn serve<F>(listen: &str, handler: F) -> Result<(), AppError>
+where
+    F: Fn(QuicStream, String) + Send + Sync + 'static,
+{
+    let certificate = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()])
+        .map_err(|e| AppError::Backend(format!("quic certificate: {e}")))?;
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certificate.key_pair.serialize_der(),
+    ));
+    let mut crypto = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])
+    .map_err(|e| AppError::Backend(format!("quic: {e}")))?
+    .with_no_client_auth()
+    .with_single_cert(
+        vec![certificate.cert.der().clone()],
+        key,
+    )
+    .map_err(|e| AppError::Backend(format!("quic: {e}")))?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(crypto).map_err(|e| AppError::Backend(format!("quic: {e}")))?;
+
+    let runtime = runtime()?;
+    let address = resolve(listen)?;
+    let endpoint = {
+        let _guard = runtime.enter();
+        Endpoint::server(
+            ServerConfig::with_crypto(Arc::new(crypto)),
+            address,
+        )?
+    };
+    tracing::info!("accepting QUIC connections on {address}");
+
+    let handler = Arc::new(handler);
+    while let Some(incoming) = runtime.block_on(endpoint.accept()) {
+        let runtime = runtime.clone();
+        let handler = handler.clone();
+        runtime.clone().spawn(async move {
+            let connection = match incoming.await {
+                Ok(connection) => connection,
+                Err(err) => return tracing::warn!("quic: {err}"),
+            };
+            let peer_addr = connection.remote_address().to_string();
+            while let Ok((send, recv)) = connection.accept_bi().await {
+                let stream = QuicStream {
+                    runtime: runtime.clone(),
+                    send,
+                    recv,
+                };
+                let handler = handler.clone();
+                let peer_addr = peer_addr.clone();
+                std::thread::spawn(move || handler(stream, peer_addr));
+            }
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,250 @@
+//! Authenticated encryption of an established session.
+//!
+//! Once the client answered the challenge, both sides derive a key per
+//! direction from the token and the nonces of the handshake, and every
+//! later byte travels in ChaCha20-Poly1305 records: a big-endian `u32`
+//! ciphertext length followed by the ciphertext and its tag. The nonce of
+//! a record is its number in the direction, so records can't be
+//! modified, replayed, reordered or moved to another session unnoticed.
+
+use std::io::{self, Read, Write};
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Largest plaintext of a record
+const RECORD: usize = 64 * 1024;
+/// Poly1305 tag length
+const TAG: usize = 16;
+
+/// Side of the session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    /// Connecting side, `fwatch://` destinations
+    Client,
+    /// `fsync serve`
+    Server,
+}
+
+/// Key of the records sent by `sender`
+fn key(token: &str, server_nonce: &str, client_nonce: &str, sender: Role) -> ChaCha20Poly1305 {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(match sender {
+        Role::Client => b"fsync peer client",
+        Role::Server => b"fsync peer server",
+    });
+    mac.update(server_nonce.as_bytes());
+    mac.update(client_nonce.as_bytes());
+    ChaCha20Poly1305::new(&mac.finalize().into_bytes())
+}
+
+/// Nonce of the record number `counter`
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// One direction of the session
+struct Direction {
+    /// Key of the direction
+    cipher: ChaCha20Poly1305,
+    /// Number of the next record
+    counter: u64,
+}
+
+/// Stream whose records are encrypted and authenticated.
+///
+/// Written bytes are sealed once a record is full or on
+/// [flush](Write::flush).
+pub(crate) struct Sealed<S> {
+    /// Underlying stream
+    stream: S,
+    /// Records sent
+    send: Direction,
+    /// Records received
+    receive: Direction,
+    /// Plaintext not sealed yet
+    output: Vec<u8>,
+    /// Plaintext of the last received record
+    input: Vec<u8>,
+    /// Bytes of [Sealed::input] already read
+    read: usize,
+}
+
+impl<S> Sealed<S> {
+    /// Seals the session of `role` keyed by the token and the nonces of the handshake
+    pub(crate) fn new(stream: S, role: Role, token: &str, server_nonce: &str, client_nonce: &str) -> Self {
+        let (send, receive) = match role {
+            Role::Client => (Role::Client, Role::Server),
+            Role::Server => (Role::Server, Role::Client),
+        };
+        Self {
+            stream,
+            send: Direction {
+                cipher: key(token, server_nonce, client_nonce, send),
+                counter: 0,
+            },
+            receive: Direction {
+                cipher: key(
+                    token,
+                    server_nonce,
+                    client_nonce,
+                    receive,
+                ),
+                counter: 0,
+            },
+            output: Vec::with_capacity(RECORD),
+            input: Vec::new(),
+            read: 0,
+        }
+    }
+}
+
+impl<S: Write> Sealed<S> {
+    /// Sends the buffered plaintext as a record
+    fn seal(&mut self) -> io::Result<()> {
+        let sealed = self
+            .send
+            .cipher
+            .encrypt(
+                &nonce(self.send.counter),
+                self.output.as_slice(),
+            )
+            .map_err(|_| io::Error::other("could not seal the record"))?;
+        self.send.counter += 1;
+        self.output.clear();
+        self.stream.write_all(&(sealed.len() as u32).to_be_bytes())?;
+        self.stream.write_all(&sealed)
+    }
+}
+
+impl<S: Write> Write for Sealed<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(RECORD - self.output.len());
+        self.output.extend_from_slice(&buf[..len]);
+        if self.output.len() == RECORD {
+            self.seal()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.output.is_empty() {
+            self.seal()?;
+        }
+        self.stream.flush()
+    }
+}
+
+impl<S: Read> Sealed<S> {
+    /// Receives and opens the next record, false at the end of the stream
+    fn open(&mut self) -> io::Result<bool> {
+        let mut len = [0; 4];
+        match self.stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if !(TAG..=RECORD + TAG).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record of {len} bytes"),
+            ));
+        }
+        let mut sealed = vec![0; len];
+        self.stream.read_exact(&mut sealed)?;
+        self.input = self
+            .receive
+            .cipher
+            .decrypt(
+                &nonce(self.receive.counter),
+                sealed.as_slice(),
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "record authentication failed",
+                )
+            })?;
+        self.receive.counter += 1;
+        self.read = 0;
+        Ok(true)
+    }
+}
+
+impl<S: Read> Read for Sealed<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read == self.input.len() {
+            if !self.open()? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.input.len() - self.read);
+        buf[..len].copy_from_slice(&self.input[self.read..self.read + len]);
+        self.read += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticates_every_record() {
+        let mut client = Sealed::new(
+            Vec::new(),
+            Role::Client,
+            "secret",
+            "server",
+            "client",
+        );
+        let content: Vec<u8> = (0..RECORD * 2 + 10).map(|i| i as u8).collect();
+        client.write_all(&content).unwrap();
+        client.flush().unwrap();
+        let wire = client.stream.clone();
+        assert_eq!(
+            wire.len(),
+            content.len() + 3 * (4 + TAG)
+        );
+        assert_ne!(&wire[4..68], &content[..64]);
+
+        let server = |wire: &[u8], token: &str| {
+            let mut server = Sealed::new(
+                wire,
+                Role::Server,
+                token,
+                "server",
+                "client",
+            );
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).map(|_| received)
+        };
+        assert_eq!(
+            server(&wire, "secret").unwrap(),
+            content
+        );
+        assert!(server(&wire, "other").is_err());
+
+        let mut tampered = wire.clone();
+        tampered[100] ^= 1;
+        assert!(server(&tampered, "secret").is_err());
+        // Records out of order
+        let first = 4 + RECORD + TAG;
+        let mut swapped = wire[first..2 * first].to_vec();
+        swapped.extend_from_slice(&wire[..first]);
+        assert!(server(&swapped, "secret").is_err());
+        // The server can't be answered with the records of the client
+        let mut echo = Sealed::new(
+            wire.as_slice(),
+            Role::Client,
+            "secret",
+            "server",
+            "client",
+        );
+        assert!(echo.read(&mut [0; 16]).is_err());
+    }
+}
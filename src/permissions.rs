@@ -0,0 +1,183 @@
+//! Permissions of an existing mirror.
+//!
+//! `fsync repair-perms <source> <destination>` walks the source and gives
+//! every entry stored at a local destination the permissions and the owner
+//! of its source, without copying anything, which fixes mirrors made
+//! before the copies kept them. The `[permissions]` section sets the modes
+//! instead, and leaves the owners alone with `ownership = false`; changing
+//! the owner usually takes root. On Windows only the read-only attribute
+//! is repaired.
+//!
+//! ```toml
+//! [permissions]
+//! file_mode = 0o644
+//! dir_mode = 0o755
+//! ownership = false
+//! ```
+
+use std::{fs, io, path::Path};
+
+use serde::Deserialize;
+
+/// `[permissions]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct PermissionsConfig {
+    /// Mode of the files, the one of the source if not set
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) file_mode: Option<u32>,
+    /// Mode of the directories, the one of the source if not set
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) dir_mode: Option<u32>,
+    /// Whether the owner and the group of the source are set too
+    #[serde(default = "PermissionsConfig::default_ownership")]
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) ownership: bool,
+}
+
+impl PermissionsConfig {
+    /// Default of [PermissionsConfig::ownership]
+    fn default_ownership() -> bool {
+        true
+    }
+}
+
+impl Default for PermissionsConfig {
+    fn default() -> Self {
+        Self {
+            file_mode: None,
+            dir_mode: None,
+            ownership: Self::default_ownership(),
+        }
+    }
+}
+
+/// Gives the entry `path` the permissions and the owner of the local entry
+/// `source`, or the modes of `config`, returns whether anything changed
+///
+/// # Errors
+///
+/// Errors reading the metadata or changing it are returned.
+#[cfg(unix)]
+pub(crate) fn repair(source: &Path, path: &Path, config: &PermissionsConfig) -> io::Result<bool> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let (meta, stored) = (
+        fs::metadata(source)?,
+        fs::metadata(path)?,
+    );
+    let mode = match meta.is_dir() {
+        true => config.dir_mode,
+        false => config.file_mode,
+    }
+    .unwrap_or(meta.mode())
+        & 0o7777;
+    let mut changed = false;
+    // First, changing the owner clears the set-user-ID bits
+    if config.ownership && (stored.uid(), stored.gid()) != (meta.uid(), meta.gid()) {
+        std::os::unix::fs::chown(path, Some(meta.uid()), Some(meta.gid()))?;
+        changed = true;
+    }
+    if fs::metadata(path)?.mode() & 0o7777 != mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// Gives the entry `path` the read-only attribute of the local entry
+/// `source`, returns whether it changed
+///
+/// # Errors
+///
+/// Errors reading the metadata or changing it are returned.
+#[cfg(not(unix))]
+pub(crate) fn repair(source: &Path, path: &Path, _config: &PermissionsConfig) -> io::Result<bool> {
+    let readonly = fs::metadata(source)?.permissions().readonly();
+    let mut permissions = fs::metadata(path)?.permissions();
+    if permissions.readonly() == readonly {
+        return Ok(false);
+    }
+    permissions.set_readonly(readonly);
+    fs::set_permissions(path, permissions)?;
+    Ok(true)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::{
+        target::{LocalTarget, SyncTarget},
+        testing::TempDir,
+    };
+
+    /// Mode of the entry `path`
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn modes_of_the_source_are_restored() {
+        let (source, destination) = (
+            TempDir::new("perms-src"),
+            TempDir::new("perms-dst"),
+        );
+        fs::write(source.join("id"), "secret").unwrap();
+        fs::write(destination.join("id"), "secret").unwrap();
+        fs::set_permissions(
+            source.join("id"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        fs::set_permissions(
+            destination.join("id"),
+            fs::Permissions::from_mode(0o666),
+        )
+        .unwrap();
+        let target = LocalTarget::new(destination.to_path_buf());
+        let config = PermissionsConfig::default();
+
+        let repaired = target.repair_permissions(
+            &source.join("id"),
+            Path::new("id"),
+            &config,
+        );
+        assert_eq!(repaired.unwrap(), Some(true));
+        assert_eq!(mode(&destination.join("id")), 0o600);
+        // Nothing left to change
+        let repaired = target.repair_permissions(
+            &source.join("id"),
+            Path::new("id"),
+            &config,
+        );
+        assert_eq!(repaired.unwrap(), Some(false));
+    }
+
+    #[test]
+    fn modes_of_the_configuration_win() {
+        let (source, destination) = (
+            TempDir::new("perms-src"),
+            TempDir::new("perms-dst"),
+        );
+        fs::create_dir(source.join("keys")).unwrap();
+        fs::create_dir(destination.join("keys")).unwrap();
+        let config = PermissionsConfig {
+            dir_mode: Some(0o750),
+            ..PermissionsConfig::default()
+        };
+        assert!(repair(
+            &source.join("keys"),
+            &destination.join("keys"),
+            &config
+        )
+        .unwrap());
+        assert_eq!(mode(&destination.join("keys")), 0o750);
+        assert!(!repair(
+            &source.join("keys"),
+            &destination.join("keys"),
+            &config
+        )
+        .unwrap());
+    }
+}
@@ -0,0 +1,362 @@
+//! Plans of the initial synchronisation.
+//!
+//! The initial scan first compares the source with the destination and
+//! records what has to change in a [SyncPlan], then applies it. Embedders
+//! can take both steps themselves to inspect or narrow the plan before
+//! anything is written:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), fsync::AppError> {
+//! use fsync::{Action, App, Config};
+//!
+//! let mut app = App::new(Config::build("./src".into(), "./dst".into()))?;
+//! let mut plan = app.plan()?;
+//! // Leave the directories out
+//! plan.retain(|action| !matches!(action, Action::Mkdir { .. }));
+//! for action in &plan {
+//!     println!("{action}");
+//! }
+//! let report = app.apply(&plan)?;
+//! println!("{report}");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Copies and directories are applied like the changes seen by the watcher,
+//! with the filters, retries, hooks and the audit trail; removals and
+//! renames are applied to the destination paths as they are.
+//!
+//! Plans and the [PlanReport] of what was applied serialize with serde, and
+//! `fsync plan <source> <destination> --json` prints the plan of the initial
+//! sync without applying it:
+//!
+//! ```json
+//! {
+//!   "actions": [
+//!     { "action": "mkdir", "source": "/src/docs", "destination": "docs" },
+//!     { "action": "copy", "source": "/src/docs/a.pdf", "destination": "docs/a.pdf" }
+//!   ],
+//!   "unchanged": 12
+//! }
+//! ```
+//!
+//! Plans made by [App::plan](crate::App::plan) also record the source and
+//! destination they were made for and when. `fsync apply plan.json` applies
+//! such a saved plan as it is, for a review before sensitive destinations are
+//! changed; nothing is applied if a source file to copy or a destination
+//! entry the plan changes was modified since the plan was made, or if a
+//! renamed entry is gone or its new name taken.
+//!
+//! [App::preview](crate::App::preview) lists the destination entries a plan
+//! overwrites or removes. Before the initial sync of a synchronisation fsync
+//! prints it and asks, so swapped source and destination arguments do not
+//! overwrite the data they were meant to save; `--yes` proceeds without
+//! asking.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ErrorReport;
+
+/// Change of the destination
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Copy a source file over its destination
+    Copy {
+        /// Source path
+        source: PathBuf,
+        /// Destination path, relative to the destination root
+        destination: PathBuf,
+    },
+    /// Create the directory of a source directory
+    Mkdir {
+        /// Source path
+        source: PathBuf,
+        /// Destination path, relative to the destination root
+        destination: PathBuf,
+    },
+    /// Remove a destination entry
+    Remove {
+        /// Destination path, relative to the destination root
+        destination: PathBuf,
+    },
+    /// Rename a destination entry
+    Rename {
+        /// Old destination path, relative to the destination root
+        from: PathBuf,
+        /// New destination path, relative to the destination root
+        to: PathBuf,
+    },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Copy { destination, .. } => write!(f, "copy {}", destination.display()),
+            Action::Mkdir { destination, .. } => write!(f, "mkdir {}", destination.display()),
+            Action::Remove { destination } => write!(f, "remove {}", destination.display()),
+            Action::Rename { from, to } => write!(
+                f,
+                "rename {} -> {}",
+                from.display(),
+                to.display()
+            ),
+        }
+    }
+}
+
+/// Ordered changes bringing the destination up to date with the source
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncPlan {
+    /// Source the plan was made for, as configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<PathBuf>,
+    /// Destination the plan was made for, as configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    destination: Option<PathBuf>,
+    /// RFC 3339 time the plan was made
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    planned_at: Option<String>,
+    /// Changes in the order they are applied
+    actions: Vec<Action>,
+    /// Files found up to date
+    #[serde(default)]
+    unchanged: u64,
+}
+
+impl SyncPlan {
+    /// Empty plan
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `action`, applied after the ones added before
+    pub fn push(&mut self, action: Action) {
+        self.actions.push(action);
+    }
+
+    /// Keeps the actions `keep` returns true for
+    pub fn retain(&mut self, keep: impl FnMut(&Action) -> bool) {
+        self.actions.retain(keep);
+    }
+
+    /// Changes in the order they are applied
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    /// Changes in the order they are applied
+    pub fn iter(&self) -> std::slice::Iter<'_, Action> {
+        self.actions.iter()
+    }
+
+    /// Number of changes
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    /// Nothing has to change
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Files found up to date, counted as skipped when the plan is applied
+    pub fn unchanged(&self) -> u64 {
+        self.unchanged
+    }
+
+    /// Source the plan was made for, none if it was not made by an
+    /// [App](crate::App)
+    pub fn source(&self) -> Option<&Path> {
+        self.source.as_deref()
+    }
+
+    /// Destination the plan was made for, none if it was not made by an
+    /// [App](crate::App)
+    pub fn destination(&self) -> Option<&Path> {
+        self.destination.as_deref()
+    }
+
+    /// RFC 3339 time the plan was made, changes after it make the plan stale
+    pub fn planned_at(&self) -> Option<&str> {
+        self.planned_at.as_deref()
+    }
+
+    /// Plan of `source` and `destination` made at `now`
+    pub(crate) fn made_for(source: &Path, destination: &Path, now: SystemTime) -> Self {
+        Self {
+            source: Some(source.to_path_buf()),
+            destination: Some(destination.to_path_buf()),
+            planned_at: Some(humantime::format_rfc3339_nanos(now).to_string()),
+            ..Self::default()
+        }
+    }
+
+    /// Counts a file found up to date
+    pub(crate) fn unchanged_file(&mut self) {
+        self.unchanged += 1;
+    }
+}
+
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for action in &self.actions {
+            writeln!(f, "{action}")?;
+        }
+        write!(
+            f,
+            "{} changes, {} files up to date",
+            self.actions.len(),
+            self.unchanged
+        )
+    }
+}
+
+impl<'a> IntoIterator for &'a SyncPlan {
+    type Item = &'a Action;
+    type IntoIter = std::slice::Iter<'a, Action>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Number of paths [Preview] prints
+const SAMPLE: usize = 5;
+
+/// Destination entries a [SyncPlan] overwrites or removes, relative to the
+/// destination root
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Preview {
+    /// Files copied over
+    pub overwritten: Vec<PathBuf>,
+    /// Entries removed
+    pub removed: Vec<PathBuf>,
+}
+
+impl Preview {
+    /// Nothing stored is overwritten or removed
+    pub fn is_empty(&self) -> bool {
+        self.overwritten.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl fmt::Display for Preview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files would be overwritten and {} entries removed at the destination",
+            self.overwritten.len(),
+            self.removed.len()
+        )?;
+        let paths = self.overwritten.iter().chain(&self.removed);
+        for path in paths.clone().take(SAMPLE) {
+            write!(f, "\n  {}", path.display())?;
+        }
+        let more = paths.count().saturating_sub(SAMPLE);
+        if more > 0 {
+            write!(f, "\n  ... and {more} more")?;
+        }
+        Ok(())
+    }
+}
+
+/// What [App::apply](crate::App::apply) did with a [SyncPlan]
+#[derive(Debug, Clone, Default, Serialize)]
+#[non_exhaustive]
+pub struct PlanReport {
+    /// Actions applied, in order
+    pub applied: Vec<Action>,
+    /// Actions left out by a filter, or whose source was gone
+    pub skipped: Vec<Action>,
+    /// Changes which could not be applied
+    pub failures: ErrorReport,
+}
+
+impl fmt::Display for PlanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} applied, {} skipped, {}",
+            self.applied.len(),
+            self.skipped.len(),
+            self.failures
+        )
+    }
+}
+
+/// What [App::sync_metadata](crate::App::sync_metadata) did
+#[derive(Debug, Clone, Default, Serialize)]
+#[non_exhaustive]
+pub struct MetadataReport {
+    /// Destination files whose metadata was brought up to date
+    pub updated: Vec<PathBuf>,
+    /// Files whose metadata matched already
+    pub unchanged: usize,
+    /// Destination files missing or of another size than their source,
+    /// left as they are
+    pub differing: Vec<PathBuf>,
+    /// Files whose metadata could not be updated
+    pub failures: ErrorReport,
+}
+
+impl fmt::Display for MetadataReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} updated, {} unchanged, {} differing, {}",
+            self.updated.len(),
+            self.unchanged,
+            self.differing.len(),
+            self.failures
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plans_round_trip_through_json() {
+        let mut plan = SyncPlan::new();
+        plan.push(Action::Mkdir {
+            source: "/src/docs".into(),
+            destination: "docs".into(),
+        });
+        plan.push(Action::Rename {
+            from: "docs/cafe\u{301}.txt".into(),
+            to: "docs/caf\u{e9}.txt".into(),
+        });
+        plan.unchanged_file();
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(
+            json["actions"][0],
+            serde_json::json!({ "action": "mkdir", "source": "/src/docs", "destination": "docs" })
+        );
+        assert_eq!(json["unchanged"], 1);
+        assert_eq!(
+            serde_json::from_value::<SyncPlan>(json).unwrap(),
+            plan
+        );
+    }
+
+    #[test]
+    fn previews_print_a_sample() {
+        let preview = Preview {
+            overwritten: (0..6).map(|i| PathBuf::from(format!("{i}.txt"))).collect(),
+            removed: vec!["old".into()],
+        };
+        let printed = preview.to_string();
+        assert!(printed.starts_with("6 files would be overwritten and 1 entries removed"));
+        assert!(printed.contains("\n  4.txt\n  ... and 2 more"));
+        assert!(Preview::default().is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+//! Decisions of the initial sync.
+//!
+//! For every file of the source the initial sync asks a [Planner] what to
+//! do with its destination file. The default [MetadataPlanner] copies the
+//! files missing at the destination and the ones whose size or modification
+//! time, in seconds, differ there; `fsync diff` reports the same files as
+//! differing. Embedders with other needs, comparing checksums or never
+//! overwriting, set their own with
+//! [App::set_planner](crate::App::set_planner).
+
+use std::{fs, path::Path};
+
+use crate::{app::Context, Action, AppError, TargetMetadata};
+
+/// File of the source with its counterpart at the destination
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct Candidate<'a> {
+    /// Source path
+    pub source: &'a Path,
+    /// Metadata of the source file
+    pub metadata: &'a fs::Metadata,
+    /// Destination path, relative to the destination root
+    pub destination: &'a Path,
+    /// Metadata of the destination file, none if it is missing
+    pub stored: Option<&'a TargetMetadata>,
+}
+
+/// Decides the actions of the initial sync
+pub trait Planner: Send + Sync {
+    /// Action bringing the destination file of `candidate` up to date, none
+    /// if it is
+    ///
+    /// # Errors
+    ///
+    /// Errors comparing the files are returned, the file is reported as a
+    /// failure of the initial sync.
+    fn plan(&self, candidate: &Candidate<'_>) -> Result<Option<Action>, AppError>;
+}
+
+/// Planner comparing the size and the modification time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetadataPlanner;
+
+impl Planner for MetadataPlanner {
+    fn plan(&self, candidate: &Candidate<'_>) -> Result<Option<Action>, AppError> {
+        let copy = Action::Copy {
+            source: candidate.source.to_path_buf(),
+            destination: candidate.destination.to_path_buf(),
+        };
+        let Some(stored) = candidate.stored else {
+            tracing::info!(
+                "syncing(file not present): {:?}",
+                candidate.destination
+            );
+            return Ok(Some(copy));
+        };
+        let src_last_modified = candidate
+            .metadata
+            .modified()
+            .map_err(AppError::from)
+            .and_then(|modified| Ok(modified.elapsed()?))
+            .context(
+                "read modification time",
+                candidate.source,
+            )?
+            .as_secs();
+        let dst_last_modified = stored
+            .modified
+            .elapsed()
+            .context(
+                "read modification time",
+                candidate.destination,
+            )?
+            .as_secs();
+
+        tracing::debug!(
+            "{} modified: {}",
+            candidate.source.display(),
+            src_last_modified
+        );
+        tracing::debug!(
+            "{} modified: {}",
+            candidate.destination.display(),
+            dst_last_modified
+        );
+        if src_last_modified != dst_last_modified || candidate.metadata.len() != stored.len {
+            tracing::info!(
+                "syncing(metadata change): {:?}",
+                candidate.destination
+            );
+            return Ok(Some(copy));
+        }
+        Ok(None)
+    }
+}
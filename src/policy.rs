@@ -0,0 +1,41 @@
+//! Reactions to failed changes.
+//!
+//! By default a failure during the initial scan aborts the run, while
+//! failures in the watch loop are logged and the watch goes on. An `[errors]`
+//! section changes both:
+//!
+//! ```toml
+//! [errors]
+//! initial_sync = "continue"   # default "abort"
+//! max_consecutive = 10        # default: never stop
+//! ```
+//!
+//! With `initial_sync = "continue"` the files which could not be synchronised
+//! are logged and skipped. With `max_consecutive` the watch stops and
+//! [App::run](crate::App::run) fails after that many changes in a row could
+//! not be applied to a reachable destination; changes queued for an
+//! unavailable destination do not count.
+
+use serde::Deserialize;
+
+/// `[errors]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ErrorPolicy {
+    /// What a failure during the initial scan does
+    #[serde(default)]
+    pub(crate) initial_sync: OnError,
+    /// Failed changes in a row after which the watch stops
+    #[serde(default)]
+    pub(crate) max_consecutive: Option<u32>,
+}
+
+/// Reaction to a failure
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// Stop and return the error
+    #[default]
+    Abort,
+    /// Log the error and go on with the next change
+    Continue,
+}
@@ -0,0 +1,225 @@
+//! Progress bars of the command line.
+//!
+//! When the standard output is a terminal, `fsync <source> <destination>`
+//! shows a bar for the files and bytes copied by the initial sync, and one
+//! below it for every file of at least 8 MiB while it is copied, during the
+//! initial sync and afterwards, with the throughput and the time left. The
+//! log goes on above the bars. They are
+//! left out with `--tui` and `--output json`.
+
+use std::{
+    collections::HashMap,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::{paths, AppError, Batch, SyncObserver};
+
+/// Files from this size on get a bar of their own
+const LARGE: u64 = 8 * 1024 * 1024;
+
+/// Bars of the process, once shown
+static BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Bar of the initial sync
+struct Overall {
+    /// Bytes copied
+    bar: ProgressBar,
+    /// Files copied
+    done: u64,
+    /// Files to copy
+    files: u64,
+}
+
+impl Overall {
+    /// Shows the number of files copied
+    fn count(&mut self) {
+        self.done += 1;
+        self.bar.set_message(format!(
+            "{}/{} files",
+            self.done, self.files
+        ));
+    }
+}
+
+/// Copy in progress
+struct Transfer {
+    /// Bytes copied so far
+    copied: u64,
+    /// Bar of a large file
+    bar: Option<ProgressBar>,
+}
+
+/// Progress bars following an [App](crate::App)
+pub struct ProgressBars {
+    /// Bars shown
+    multi: MultiProgress,
+    /// Bar of the initial sync while it runs
+    overall: Mutex<Option<Overall>>,
+    /// Copies in progress, by source path
+    transfers: Mutex<HashMap<PathBuf, Transfer>>,
+}
+
+impl ProgressBars {
+    /// Bars drawn on the standard output, none if it is not a terminal
+    pub fn stdout() -> Option<Self> {
+        if !io::stdout().is_terminal() {
+            return None;
+        }
+        let multi = BARS
+            .get_or_init(|| MultiProgress::with_draw_target(ProgressDrawTarget::stdout()))
+            .clone();
+        Some(Self {
+            multi,
+            overall: Mutex::default(),
+            transfers: Mutex::default(),
+        })
+    }
+
+    /// Bar of the initial sync, as long as it runs
+    fn overall(&self) -> std::sync::MutexGuard<'_, Option<Overall>> {
+        self.overall.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Copies in progress
+    fn transfers(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Transfer>> {
+        self.transfers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Moves the bar of the initial sync `copied` bytes of `source` on
+    fn advance(&self, source: &Path, copied: u64) -> Option<Transfer> {
+        let mut transfers = self.transfers();
+        let transfer = transfers.remove(source);
+        let before = transfer.as_ref().map_or(0, |transfer| transfer.copied);
+        if let Some(overall) = self.overall().as_ref() {
+            overall.bar.inc(copied.saturating_sub(before));
+        }
+        transfer
+    }
+}
+
+/// Style of the bars, `template` with the default style as fallback
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ")
+}
+
+impl SyncObserver for ProgressBars {
+    fn on_initial_sync(&self, files: u64, bytes: u64) {
+        let bar = self.multi.add(
+            ProgressBar::new(bytes).with_style(style(
+                "{msg:>15} [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec}, ~{eta} remaining",
+            )),
+        );
+        bar.set_message(format!("0/{files} files"));
+        *self.overall() = Some(Overall { bar, done: 0, files });
+    }
+
+    fn on_progress(&self, source: &Path, destination: &Path, copied: u64, total: u64) {
+        let mut transfer = self.advance(source, copied).unwrap_or(Transfer { copied: 0, bar: None });
+        transfer.copied = copied;
+        if total >= LARGE {
+            transfer
+                .bar
+                .get_or_insert_with(|| {
+                    self.multi.add(
+                        ProgressBar::new(total)
+                            .with_style(style(
+                                "  {msg:>13} [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec}, ~{eta} remaining",
+                            ))
+                            .with_message(destination.display().to_string()),
+                    )
+                })
+                .set_position(copied);
+        }
+        self.transfers().insert(source.to_path_buf(), transfer);
+    }
+
+    fn on_copy(&self, source: &Path, _destination: &Path, bytes: u64) {
+        if let Some(bar) = self.advance(source, bytes).and_then(|transfer| transfer.bar) {
+            bar.finish_and_clear();
+        }
+        if !paths::extended(source).is_dir() {
+            if let Some(overall) = self.overall().as_mut() {
+                overall.count();
+            }
+        }
+    }
+
+    fn on_error(&self, _operation: &str, source: &Path, _error: &AppError) {
+        if let Some(bar) = self.transfers().remove(source).and_then(|transfer| transfer.bar) {
+            bar.abandon();
+        }
+    }
+
+    fn on_batch_complete(&self, _batch: &Batch) {
+        if let Some(overall) = self.overall().take() {
+            overall.bar.finish_and_clear();
+        }
+    }
+}
+
+/// Runs `write` with the bars, if any, hidden
+pub(crate) fn suspend<R>(write: impl FnOnce() -> R) -> R {
+    match BARS.get() {
+        Some(bars) => bars.suspend(write),
+        None => write(),
+    }
+}
+
+/// Standard error of the log, printed above the progress bars
+pub(crate) struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        suspend(|| io::stderr().write_all(buf)).map(|()| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_files_and_bytes_of_the_initial_sync() {
+        let bars = ProgressBars {
+            multi: MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+            overall: Mutex::default(),
+            transfers: Mutex::default(),
+        };
+        let (big, small) = (
+            Path::new("/src/big.iso"),
+            Path::new("/src/a.txt"),
+        );
+        bars.on_initial_sync(2, LARGE + 3);
+        bars.on_progress(
+            big,
+            Path::new("big.iso"),
+            LARGE / 2,
+            LARGE,
+        );
+        assert!(bars.transfers()[big].bar.is_some());
+        bars.on_progress(big, Path::new("big.iso"), LARGE, LARGE);
+        bars.on_copy(big, Path::new("big.iso"), LARGE);
+        bars.on_progress(small, Path::new("a.txt"), 3, 3);
+        assert!(bars.transfers()[small].bar.is_none());
+        bars.on_copy(small, Path::new("a.txt"), 3);
+        {
+            let overall = bars.overall();
+            let overall = overall.as_ref().unwrap();
+            assert_eq!(overall.bar.position(), LARGE + 3);
+            assert_eq!(overall.done, 2);
+        }
+        assert!(bars.transfers().is_empty());
+        bars.on_batch_complete(&Batch::default());
+        assert!(bars.overall().is_none());
+    }
+}
@@ -0,0 +1,238 @@
+//! Operations waiting for an unavailable destination.
+//!
+//! While the destination (a network share, a remote backend) is not
+//! reachable, changes of the source are appended to a journal file instead
+//! of being applied. The journal is replayed in order once the destination
+//! is back, and it survives restarts of fsync.
+//!
+//! The journal holds one JSON encoded [Operation] per line.
+
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Change of the source, recorded by source paths
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// Copy the file or create the directory
+    Copy {
+        /// Source path
+        path: PathBuf,
+    },
+    /// Remove the destination entry
+    Remove {
+        /// Source path
+        path: PathBuf,
+    },
+    /// Rename the destination entry
+    Rename {
+        /// Old source path
+        from: PathBuf,
+        /// New source path
+        to: PathBuf,
+    },
+}
+
+/// Per-user directory of the files fsync keeps between runs: the state
+/// directory (`~/.local/state/fsync` on Linux), else the local data
+/// directory, readable by the user only
+pub(crate) fn state_dir() -> PathBuf {
+    let dir = dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("fsync");
+    if let Err(err) = fs::create_dir_all(&dir) {
+        tracing::warn!("could not create {dir:?}: {err}");
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(err) = fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)) {
+            tracing::warn!("could not restrict {dir:?}: {err}");
+        }
+    }
+    dir
+}
+
+/// File of the `kind` of state kept for a source and destination pair in
+/// the [state_dir]
+pub(crate) fn state_file(kind: &str, source: &Path, destination: &Path) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(source.as_os_str().as_encoded_bytes());
+    hasher.update([0]);
+    hasher.update(destination.as_os_str().as_encoded_bytes());
+    let digest = crate::peer::hex(&hasher.finalize());
+    state_dir().join(format!(
+        "{kind}-{}.jsonl",
+        &digest[..16]
+    ))
+}
+
+/// Journal of the operations not yet applied to the destination
+#[derive(Debug)]
+pub(crate) struct OfflineQueue {
+    /// Journal file
+    path: PathBuf,
+    /// Operations in the order they happened
+    pending: VecDeque<Operation>,
+}
+
+impl OfflineQueue {
+    /// Default journal location for a source and destination pair
+    pub(crate) fn default_path(source: &Path, destination: &Path) -> PathBuf {
+        state_file("queue", source, destination)
+    }
+
+    /// Loads the journal at `path`, a missing file is an empty queue.
+    ///
+    /// Lines which could not be parsed (e.g. a truncated last line) are skipped.
+    pub(crate) fn open(path: PathBuf) -> io::Result<Self> {
+        let mut pending = VecDeque::new();
+        match fs::File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    match serde_json::from_str(&line?) {
+                        Ok(operation) => pending.push_back(operation),
+                        Err(err) => tracing::warn!("{path:?}: skipping queued operation: {err}"),
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        if !pending.is_empty() {
+            tracing::info!(
+                "{} queued operations loaded from {path:?}",
+                pending.len()
+            );
+        }
+        crate::metrics::queued(pending.len());
+        Ok(Self { path, pending })
+    }
+
+    /// No operations are waiting
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Number of waiting operations
+    pub(crate) fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Oldest waiting operation
+    pub(crate) fn front(&self) -> Option<&Operation> {
+        self.pending.front()
+    }
+
+    /// Appends `operation` to the queue and the journal
+    pub(crate) fn push(&mut self, operation: Operation) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(
+            file,
+            "{}",
+            serde_json::to_string(&operation)?
+        )?;
+        self.pending.push_back(operation);
+        crate::metrics::queued(self.pending.len());
+        Ok(())
+    }
+
+    /// Drops the oldest operation, the journal is updated by [OfflineQueue::persist]
+    pub(crate) fn pop(&mut self) {
+        self.pending.pop_front();
+        crate::metrics::queued(self.pending.len());
+    }
+
+    /// Rewrites the journal with the waiting operations, removing it if there are none
+    pub(crate) fn persist(&self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            };
+        }
+        let temp = self.path.with_extension("tmp");
+        let mut file = io::BufWriter::new(fs::File::create(&temp)?);
+        for operation in &self.pending {
+            writeln!(
+                file,
+                "{}",
+                serde_json::to_string(operation)?
+            )?;
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(temp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_survives_reopening() {
+        let path = std::env::temp_dir().join(format!(
+            "fsync-queue-test-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let mut queue = OfflineQueue::open(path.clone()).unwrap();
+        queue.push(Operation::Copy { path: "/src/a".into() }).unwrap();
+        queue
+            .push(Operation::Rename {
+                from: "/src/a".into(),
+                to: "/src/b".into(),
+            })
+            .unwrap();
+        queue.push(Operation::Remove { path: "/src/c".into() }).unwrap();
+
+        let mut queue = OfflineQueue::open(path.clone()).unwrap();
+        assert_eq!(queue.len(), 3);
+        assert_eq!(
+            queue.front(),
+            Some(&Operation::Copy { path: "/src/a".into() })
+        );
+        queue.pop();
+        queue.persist().unwrap();
+
+        let mut queue = OfflineQueue::open(path.clone()).unwrap();
+        assert_eq!(
+            queue.front(),
+            Some(&Operation::Rename {
+                from: "/src/a".into(),
+                to: "/src/b".into()
+            })
+        );
+        queue.pop();
+        queue.pop();
+        queue.persist().unwrap();
+        assert!(!path.exists());
+
+        let default = OfflineQueue::default_path("/src".as_ref(), "/dst".as_ref());
+        assert_eq!(
+            default.parent(),
+            Some(state_dir().as_path())
+        );
+        assert_ne!(
+            default,
+            OfflineQueue::default_path("/src".as_ref(), "/other".as_ref())
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(state_dir()).unwrap().permissions().mode() & 0o777,
+                0o700
+            );
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Cap on the bytes stored at the destination.
+//!
+//! With a `[quota]` section copies which would grow the destination beyond
+//! `max_bytes` fail with [AppError::QuotaExceeded], so a runaway source can
+//! not fill the destination disk. Removals and renames go on, copies resume
+//! once there is room again. Crossing the quota logs an error and sends the
+//! `quota` webhook event (with `--features webhooks`), once until the
+//! destination fits again.
+//!
+//! ```toml
+//! [quota]
+//! max_bytes = 53687091200   # 50 GiB
+//! ```
+//!
+//! The stored bytes are counted from the sizes at the destination of the
+//! source files during the initial scan, and followed by the copies and
+//! removals afterwards. Files at the destination without a source file are
+//! not counted.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+use serde::Deserialize;
+
+use crate::AppError;
+
+/// `[quota]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuotaConfig {
+    /// Most bytes stored at the destination
+    pub(crate) max_bytes: u64,
+}
+
+/// Callback raising the alert
+type Alert = Box<dyn Fn(&str) + Send>;
+
+/// Bytes stored at the destination of an [App](crate::App)
+pub(crate) struct Quota {
+    /// Most bytes stored at the destination
+    max_bytes: u64,
+    /// Bytes stored now
+    used: Mutex<u64>,
+    /// The alert was raised since the destination last fit
+    alerted: AtomicBool,
+    /// Sends the alert to the webhooks
+    alert: Mutex<Alert>,
+}
+
+impl Quota {
+    /// Quota of `config`, `alert` is called with the message when it is
+    /// exceeded
+    pub(crate) fn new(config: &QuotaConfig, alert: impl Fn(&str) + Send + 'static) -> Self {
+        Self {
+            max_bytes: config.max_bytes,
+            used: Mutex::new(0),
+            alerted: AtomicBool::new(false),
+            alert: Mutex::new(Box::new(alert)),
+        }
+    }
+
+    /// Sets the bytes stored now
+    pub(crate) fn set(&self, used: u64) {
+        *self.used.lock().unwrap_or_else(|e| e.into_inner()) = used;
+        tracing::debug!(
+            "quota: {used} of {} bytes used",
+            self.max_bytes
+        );
+    }
+
+    /// Checks that replacing `old` stored bytes with `new` ones fits.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::QuotaExceeded] is returned if it does not.
+    pub(crate) fn check(&self, old: u64, new: u64) -> Result<(), AppError> {
+        let used = *self.used.lock().unwrap_or_else(|e| e.into_inner());
+        if used.saturating_sub(old).saturating_add(new) <= self.max_bytes {
+            self.alerted.store(false, Ordering::Relaxed);
+            return Ok(());
+        }
+        let err = AppError::QuotaExceeded {
+            quota: self.max_bytes,
+            used,
+            needed: new.saturating_sub(old),
+        };
+        if !self.alerted.swap(true, Ordering::Relaxed) {
+            tracing::error!("{err}");
+            (self.alert.lock().unwrap_or_else(|e| e.into_inner()))(&err.to_string());
+        }
+        Err(err)
+    }
+
+    /// Records that `old` stored bytes were replaced with `new` ones
+    pub(crate) fn update(&self, old: u64, new: u64) {
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        *used = used.saturating_sub(old).saturating_add(new);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Quota of 100 bytes with 60 of them used, and the count of its alerts
+    fn quota() -> (Quota, Arc<Mutex<u32>>) {
+        let alerts = Arc::new(Mutex::new(0));
+        let counted = alerts.clone();
+        let quota = Quota::new(
+            &QuotaConfig { max_bytes: 100 },
+            move |_| *counted.lock().unwrap() += 1,
+        );
+        quota.set(60);
+        (quota, alerts)
+    }
+
+    #[test]
+    fn copies_fit_up_to_the_quota() {
+        let (quota, alerts) = quota();
+        quota.check(0, 40).unwrap();
+        quota.update(0, 40);
+        assert!(matches!(
+            quota.check(0, 1),
+            Err(AppError::QuotaExceeded { needed: 1, .. })
+        ));
+        assert_eq!(*alerts.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn alerts_are_raised_once_until_it_fits_again() {
+        let (quota, alerts) = quota();
+        assert!(quota.check(0, 41).is_err());
+        assert!(quota.check(0, 41).is_err());
+        assert_eq!(*alerts.lock().unwrap(), 1);
+        quota.check(0, 40).unwrap();
+        assert!(quota.check(0, 41).is_err());
+        assert_eq!(*alerts.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn replacing_a_file_counts_the_difference() {
+        let (quota, _) = quota();
+        // 60 stored bytes replaced by 100
+        quota.check(60, 100).unwrap();
+        quota.update(60, 100);
+        assert!(quota.check(0, 1).is_err());
+        // and by a smaller file again
+        quota.check(100, 30).unwrap();
+        quota.update(100, 30);
+        quota.check(0, 70).unwrap();
+        assert!(quota.check(0, 71).is_err());
+    }
+
+    #[test]
+    fn removals_free_their_bytes() {
+        let (quota, _) = quota();
+        quota.update(60, 0);
+        quota.check(0, 100).unwrap();
+        // More than counted never goes below zero
+        quota.update(10, 0);
+        quota.check(0, 100).unwrap();
+        assert!(quota.check(0, 101).is_err());
+    }
+}
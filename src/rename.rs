@@ -0,0 +1,321 @@
+//! Renaming of the files at the destination.
+//!
+//! `[[rename]]` entries change the names the files are stored under, after
+//! the template and the routes placed them. Every entry whose `pattern`
+//! matches the file name is applied in turn to the name the entries before
+//! it left: the matches are replaced by `replace`, which refers to the
+//! groups of the pattern as `$1` or `${name}`, the name is folded to `case`,
+//! then `prefix` is put in front of it and `suffix` in front of its
+//! extension. An entry without a pattern applies to every file:
+//!
+//! ```toml
+//! [[rename]]
+//! case = "lower"
+//! prefix = "{year}-{month}-{day}_"     # IMG_1234.JPG -> 2024-05-01_img_1234.jpg
+//!
+//! [[rename]]
+//! pattern = '^(.*)\.jpeg$'
+//! replace = "$1.jpg"
+//! ```
+//!
+//! `replace`, `prefix` and `suffix` may use the UTC modification date
+//! `{year}`, `{month}` and `{day}`. Directories keep their names. A file
+//! keeps the name it got first while fsync runs, so removals and renames
+//! find it after its contents are gone. The rules must leave a file name,
+//! not a path or `..`, and a file renamed to the name another one got,
+//! e.g. `IMG.JPG` folded to the `img.jpg` next to it, is numbered like the
+//! files the template places at the same destination.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{app::Context, paths, template::Placements, AppError};
+
+/// Placeholders filled in from the modification time
+const DATE: &[&str] = &["{year}", "{month}", "{day}"];
+
+/// Case a name is folded to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Case {
+    /// `IMG_1234.JPG` -> `img_1234.jpg`
+    Lower,
+    /// `img_1234.jpg` -> `IMG_1234.JPG`
+    Upper,
+}
+
+/// `[[rename]]` entry of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct RenameConfig {
+    /// Regular expression the file names are matched against
+    #[serde(default)]
+    pub(crate) pattern: Option<String>,
+    /// Replacement of the matches of [RenameConfig::pattern]
+    #[serde(default)]
+    pub(crate) replace: Option<String>,
+    /// Case the names are folded to
+    #[serde(default)]
+    pub(crate) case: Option<Case>,
+    /// Text put in front of the names
+    #[serde(default)]
+    pub(crate) prefix: String,
+    /// Text put in front of the extensions
+    #[serde(default)]
+    pub(crate) suffix: String,
+}
+
+impl RenameConfig {
+    /// Whether the entry uses a date placeholder
+    fn dated(&self) -> bool {
+        [self.replace.as_deref().unwrap_or_default(), &self.prefix, &self.suffix]
+            .iter()
+            .any(|text| DATE.iter().any(|placeholder| text.contains(placeholder)))
+    }
+}
+
+/// Rename rules of an [App](crate::App)
+#[derive(Debug)]
+pub(crate) struct Renamer {
+    /// Entries in the order they are applied, with their compiled pattern
+    rules: Vec<(RenameConfig, Option<Regex>)>,
+    /// One of the entries uses a date placeholder
+    dated: bool,
+    /// Destinations of the source files renamed so far
+    renamed: Mutex<Placements>,
+}
+
+impl Renamer {
+    /// Renamer of `rules`, none if there are none
+    ///
+    /// # Errors
+    ///
+    /// [AppError::PathErr] is returned for invalid patterns.
+    pub(crate) fn new(rules: &[RenameConfig]) -> Result<Option<Self>, AppError> {
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let pattern = rule
+                    .pattern
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()
+                    .map_err(|err| AppError::PathErr(format!("invalid rename pattern: {err}")))?;
+                Ok((rule.clone(), pattern))
+            })
+            .collect::<Result<Vec<_>, AppError>>()?;
+        Ok(Some(Self {
+            dated: rules.iter().any(|(rule, _)| rule.dated()),
+            rules,
+            renamed: Mutex::default(),
+        }))
+    }
+
+    /// Destination of the source entry `source` stored at `relative`
+    /// without renaming. Directories, entries gone before they were renamed
+    /// and names which are not valid UTF-8 keep the `relative` path.
+    ///
+    /// # Errors
+    ///
+    /// Errors reading the metadata of an existing file are returned,
+    /// [AppError::PathErr] if the rules leave an empty name or one which is
+    /// not a file name.
+    pub(crate) fn destination(&self, source: &Path, relative: PathBuf) -> Result<PathBuf, AppError> {
+        let mut renamed = self.renamed.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(destination) = renamed.get(source) {
+            return Ok(destination.clone());
+        }
+        let meta = match fs::metadata(paths::extended(source)) {
+            Ok(meta) if meta.is_file() => meta,
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(AppError::from(err).context("read metadata", source)),
+            _ => return Ok(relative),
+        };
+        let Some(mut name) = relative.file_name().and_then(|name| name.to_str()).map(str::to_owned) else {
+            return Ok(relative);
+        };
+        // 2024-05-03T10:00:00Z
+        let date = match self.dated {
+            true => humantime::format_rfc3339_seconds(meta.modified().context("read modification time", source)?).to_string(),
+            false => String::new(),
+        };
+        let fill = |text: &str| match self.dated {
+            true => text
+                .replace("{year}", &date[..4])
+                .replace("{month}", &date[5..7])
+                .replace("{day}", &date[8..10]),
+            false => text.to_owned(),
+        };
+        for (rule, pattern) in &self.rules {
+            if let Some(pattern) = pattern {
+                if !pattern.is_match(&name) {
+                    continue;
+                }
+                if let Some(replace) = &rule.replace {
+                    name = pattern.replace_all(&name, fill(replace).as_str()).into_owned();
+                }
+            }
+            name = match rule.case {
+                Some(Case::Lower) => name.to_lowercase(),
+                Some(Case::Upper) => name.to_uppercase(),
+                None => name,
+            };
+            let (stem, extension) = match name.rsplit_once('.') {
+                Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+                _ => (name.as_str(), None),
+            };
+            name = format!(
+                "{}{stem}{}{}",
+                fill(&rule.prefix),
+                fill(&rule.suffix),
+                extension.map(|extension| format!(".{extension}")).unwrap_or_default()
+            );
+        }
+        if name.is_empty() {
+            return Err(AppError::PathErr(format!(
+                "the rename rules leave {} without a name",
+                source.display()
+            )));
+        }
+        if name == "." || name == ".." || name.contains(std::path::is_separator) {
+            return Err(AppError::PathErr(format!(
+                "the rename rules turn {} into {name:?}, which is not a file name",
+                source.display()
+            )));
+        }
+        tracing::debug!(
+            "{}: renamed to {name}",
+            source.display()
+        );
+        Ok(renamed.place(source, relative.with_file_name(name)))
+    }
+
+    /// Forgets the name of `source`, or of the files below it, once it is
+    /// removed or renamed
+    pub(crate) fn forget(&self, source: &Path) {
+        self.renamed.lock().unwrap_or_else(|e| e.into_inner()).forget(source);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime},
+    };
+
+    use super::*;
+
+    #[test]
+    fn renames_by_pattern_case_and_date() {
+        let root = std::env::temp_dir().join(format!(
+            "fsync-rename-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(root.join("DCIM")).unwrap();
+        for name in ["IMG_1234.JPG", "clip.jpeg"] {
+            fs::write(root.join(name), "jpg").unwrap();
+            fs::File::options()
+                .write(true)
+                .open(root.join(name))
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_561_200))
+                .unwrap();
+        }
+        let rules: Vec<RenameConfig> = toml::from_str::<HashMap<String, Vec<RenameConfig>>>(
+            r#"
+            [[rename]]
+            case = "lower"
+            prefix = "{year}-{month}-{day}_"
+            [[rename]]
+            pattern = '^(.*)\.jpeg$'
+            replace = "$1.jpg"
+            suffix = "_video"
+            "#,
+        )
+        .unwrap()
+        .remove("rename")
+        .unwrap();
+        let renamer = Renamer::new(&rules).unwrap().unwrap();
+
+        assert_eq!(
+            renamer
+                .destination(
+                    &root.join("IMG_1234.JPG"),
+                    "photos/IMG_1234.JPG".into()
+                )
+                .unwrap(),
+            PathBuf::from("photos/2024-05-01_img_1234.jpg")
+        );
+        assert_eq!(
+            renamer
+                .destination(
+                    &root.join("clip.jpeg"),
+                    "clip.jpeg".into()
+                )
+                .unwrap(),
+            PathBuf::from("2024-05-01_clip_video.jpg")
+        );
+        assert_eq!(
+            renamer.destination(&root.join("DCIM"), "DCIM".into()).unwrap(),
+            PathBuf::from("DCIM")
+        );
+        // Removed, the name is remembered
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            renamer
+                .destination(
+                    &root.join("IMG_1234.JPG"),
+                    "photos/IMG_1234.JPG".into()
+                )
+                .unwrap(),
+            PathBuf::from("photos/2024-05-01_img_1234.jpg")
+        );
+        assert!(Renamer::new(&[RenameConfig {
+            pattern: Some("(".into()),
+            ..rules[1].clone()
+        }])
+        .is_err());
+
+        // Folded onto the name of another file
+        fs::create_dir_all(&root).unwrap();
+        for name in ["IMG.JPG", "img.jpg"] {
+            fs::write(root.join(name), "jpg").unwrap();
+        }
+        let renamer = Renamer::new(&rules[..1]).unwrap().unwrap();
+        let date = &renamer.destination(&root.join("img.jpg"), "img.jpg".into()).unwrap();
+        let folded = renamer.destination(&root.join("IMG.JPG"), "IMG.JPG".into()).unwrap();
+        assert_eq!(
+            folded.to_str().unwrap(),
+            date.to_str().unwrap().replace(".jpg", " (2).jpg")
+        );
+        renamer.forget(&root.join("img.jpg"));
+        renamer.forget(&root.join("IMG.JPG"));
+        assert_eq!(
+            &renamer.destination(&root.join("IMG.JPG"), "IMG.JPG".into()).unwrap(),
+            date
+        );
+
+        // Names leaving the directory are refused
+        for replace in ["../$1", "a/$1", ".."] {
+            let escape = Renamer::new(&[RenameConfig {
+                pattern: Some("^(.*)$".into()),
+                replace: Some(replace.into()),
+                case: None,
+                prefix: String::new(),
+                suffix: String::new(),
+            }])
+            .unwrap()
+            .unwrap();
+            assert!(escape.destination(&root.join("img.jpg"), "img.jpg".into()).is_err());
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
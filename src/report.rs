@@ -0,0 +1,245 @@
+//! Periodic summary reports.
+//!
+//! With a `[report]` section a digest of every `interval` is logged: files
+//! copied with their bytes, removals, renames, errors, the largest copied
+//! files and the current lag. It is also appended to `file` and sent as
+//! `report` webhook event (with `--features webhooks`) if configured.
+//!
+//! ```toml
+//! [report]
+//! interval = "1day"   # default 1h
+//! # file = "/var/log/fsync/report.log"
+//! ```
+//!
+//! The lag is the time since the destination was last updated while changes
+//! wait in the offline queue, zero otherwise.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+
+use crate::{stats::Counters, AppError, SyncObserver};
+
+/// Largest files listed in a report
+const LARGEST: usize = 5;
+
+/// `[report]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReportConfig {
+    /// Period covered by a report
+    #[serde(default = "ReportConfig::default_interval", with = "crate::config::humantime_serde")]
+    pub(crate) interval: Duration,
+    /// File the reports are appended to
+    #[serde(default)]
+    pub(crate) file: Option<PathBuf>,
+}
+
+impl ReportConfig {
+    /// Default of [ReportConfig::interval]
+    fn default_interval() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+}
+
+/// Activity since the last report
+#[derive(Debug, Default)]
+struct Period {
+    /// Files and directories copied
+    copied: u64,
+    /// Bytes of the copied files
+    bytes: u64,
+    /// Entries removed
+    removed: u64,
+    /// Entries renamed
+    renamed: u64,
+    /// Failed operations
+    errors: u64,
+    /// Largest copied files with their sizes, largest first
+    largest: Vec<(u64, PathBuf)>,
+}
+
+impl Period {
+    /// Text of the report covering `interval`
+    fn summary(&self, interval: Duration, queued: u64, lag: Duration) -> String {
+        let mut summary = format!(
+            "last {}: {} files copied ({} bytes), {} removed, {} renamed, {} errors",
+            humantime::format_duration(interval),
+            self.copied,
+            self.bytes,
+            self.removed,
+            self.renamed,
+            self.errors
+        );
+        if !self.largest.is_empty() {
+            let largest = self
+                .largest
+                .iter()
+                .map(|(bytes, path)| format!("{} ({bytes} bytes)", path.display()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            summary.push_str(&format!("; largest: {largest}"));
+        }
+        summary.push_str(&format!(
+            "; {queued} changes queued, lag {}",
+            humantime::format_duration(Duration::from_secs(lag.as_secs()))
+        ));
+        summary
+    }
+}
+
+/// Activity shared between the [Digest] observer and the report thread
+#[derive(Debug, Default)]
+struct Activity {
+    /// Counts of the current period
+    period: Period,
+    /// Last successful operation
+    last_synced: Option<SystemTime>,
+}
+
+/// Observer collecting the activity of a period
+#[derive(Debug)]
+struct Digest(Arc<Mutex<Activity>>);
+
+impl Digest {
+    /// Updates the activity
+    fn record(&self, update: impl FnOnce(&mut Activity)) {
+        let mut activity = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        update(&mut activity);
+    }
+}
+
+impl SyncObserver for Digest {
+    fn on_copy(&self, source: &Path, destination: &Path, bytes: u64) {
+        let is_file = source.is_file();
+        self.record(|activity| {
+            activity.last_synced = Some(SystemTime::now());
+            let period = &mut activity.period;
+            period.copied += 1;
+            period.bytes += bytes;
+            if is_file {
+                let at = period.largest.partition_point(|(size, _)| *size >= bytes);
+                period.largest.insert(at, (bytes, destination.to_path_buf()));
+                period.largest.truncate(LARGEST);
+            }
+        });
+    }
+
+    fn on_remove(&self, _source: &Path, _destination: &Path) {
+        self.record(|activity| {
+            activity.last_synced = Some(SystemTime::now());
+            activity.period.removed += 1;
+        });
+    }
+
+    fn on_rename(&self, _from: &Path, _to: &Path, _destination: &Path) {
+        self.record(|activity| {
+            activity.last_synced = Some(SystemTime::now());
+            activity.period.renamed += 1;
+        });
+    }
+
+    fn on_error(&self, _operation: &str, _source: &Path, _error: &AppError) {
+        self.record(|activity| activity.period.errors += 1);
+    }
+}
+
+/// Running report thread, stopped when dropped
+#[derive(Debug)]
+pub(crate) struct Reporter {
+    /// Dropped to wake up and stop the thread
+    _stop: mpsc::Sender<()>,
+}
+
+impl Reporter {
+    /// Starts reporting every `config.interval`, `notify` sends the report to
+    /// the webhooks. Returns the observer to register with the
+    /// [App](crate::App).
+    pub(crate) fn start<F>(config: ReportConfig, stats: Arc<Counters>, notify: F) -> (Self, impl SyncObserver)
+    where
+        F: Fn(&str) + Send + 'static,
+    {
+        let activity = Arc::new(Mutex::new(Activity::default()));
+        let (stop, stopped) = mpsc::channel();
+        let shared = activity.clone();
+        std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(config.interval) {
+                let (period, last_synced) = {
+                    let mut activity = shared.lock().unwrap_or_else(|e| e.into_inner());
+                    (
+                        std::mem::take(&mut activity.period),
+                        activity.last_synced,
+                    )
+                };
+                let queued = stats.queue_depth();
+                let lag = match last_synced {
+                    Some(time) if queued > 0 => time.elapsed().unwrap_or_default(),
+                    _ => Duration::ZERO,
+                };
+                let summary = period.summary(config.interval, queued, lag);
+                tracing::info!("report: {summary}");
+                if let Some(file) = &config.file {
+                    if let Err(err) = append(file, &summary) {
+                        tracing::error!("report {}: {err}", file.display());
+                    }
+                }
+                notify(&summary);
+            }
+        });
+        (Self { _stop: stop }, Digest(activity))
+    }
+}
+
+/// Appends `summary` with the current time to `file`
+fn append(file: &Path, summary: &str) -> std::io::Result<()> {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(file)?;
+    writeln!(
+        file,
+        "{} {summary}",
+        humantime::format_rfc3339_seconds(SystemTime::now())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_keeps_the_largest_files() {
+        let digest = Digest(Arc::default());
+        let file = std::env::current_exe().unwrap();
+        for (index, bytes) in [3, 9, 1, 7, 5, 8, 2].into_iter().enumerate() {
+            digest.on_copy(
+                &file,
+                Path::new(&format!("f{index}")),
+                bytes,
+            );
+        }
+        digest.on_error(
+            "copy",
+            &file,
+            &AppError::Backend("down".into()),
+        );
+
+        let activity = digest.0.lock().unwrap();
+        let sizes = activity.period.largest.iter().map(|(bytes, _)| *bytes).collect::<Vec<_>>();
+        assert_eq!(sizes, [9, 8, 7, 5, 3]);
+        assert_eq!(
+            activity.period.summary(
+                Duration::from_secs(3600),
+                0,
+                Duration::ZERO
+            ),
+            "last 1h: 7 files copied (35 bytes), 0 removed, 0 renamed, 1 errors; largest: f1 (9 bytes), f5 (8 bytes), \
+             f3 (7 bytes), f4 (5 bytes), f0 (3 bytes); 0 changes queued, lag 0s"
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Retention of the kept versions.
+//!
+//! The `[retention]` section decides which of the versions a `dedup:`
+//! destination keeps under `versions/` survive `fsync prune <dir>`: the
+//! `keep_last` newest versions of every file, the newest version of every
+//! day within `keep_daily` and the newest of every week, starting on Monday,
+//! within `keep_weekly`. The other versions are removed, then the chunks no
+//! manifest refers to anymore:
+//!
+//! ```toml
+//! [retention]
+//! keep_last = 5
+//! keep_daily = "30 days"
+//! keep_weekly = "1 year"
+//! ```
+//!
+//! Days and weeks are the UTC ones of the time a version was replaced.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
+/// Seconds of a day
+const DAY: u64 = 24 * 60 * 60;
+
+/// `[retention]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RetentionConfig {
+    /// Newest versions of every file which are kept
+    #[serde(default)]
+    pub(crate) keep_last: usize,
+    /// Age up to which the newest version of every day is kept
+    #[serde(default, with = "crate::config::humantime_serde::option")]
+    pub(crate) keep_daily: Option<Duration>,
+    /// Age up to which the newest version of every week is kept
+    #[serde(default, with = "crate::config::humantime_serde::option")]
+    pub(crate) keep_weekly: Option<Duration>,
+}
+
+impl RetentionConfig {
+    /// Whether each of the versions replaced at `times` is kept at `now`
+    pub(crate) fn retained(&self, times: &[SystemTime], now: SystemTime) -> Vec<bool> {
+        let mut newest: Vec<usize> = (0..times.len()).collect();
+        newest.sort_by_key(|&index| std::cmp::Reverse(times[index]));
+        let mut kept = vec![false; times.len()];
+        let (mut days, mut weeks) = (HashSet::new(), HashSet::new());
+        for (rank, index) in newest.into_iter().enumerate() {
+            let age = now.duration_since(times[index]).unwrap_or_default();
+            let day = times[index].duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / DAY;
+            // 1970-01-01 was a Thursday
+            let week = (day + 3) / 7;
+            // Only the newest version of a day or week counts for it
+            let newest_of_day = days.insert(day);
+            let newest_of_week = weeks.insert(week);
+            kept[index] = rank < self.keep_last
+                || newest_of_day && self.keep_daily.is_some_and(|within| age < within)
+                || newest_of_week && self.keep_weekly.is_some_and(|within| age < within);
+        }
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_last_daily_and_weekly_versions() {
+        // Monday 2024-05-06, noon
+        let now = UNIX_EPOCH + Duration::from_secs(1_714_996_800);
+        let ago = |secs: u64| now - Duration::from_secs(secs);
+        let times = [
+            ago(60),
+            ago(2 * 60 * 60),
+            // Two of Sunday
+            ago(DAY),
+            ago(DAY + 60),
+            // Friday of the last week, and the Saturday of the week before
+            ago(3 * DAY),
+            ago(9 * DAY),
+            ago(60 * DAY),
+        ];
+        let retention = RetentionConfig {
+            keep_last: 1,
+            keep_daily: Some(Duration::from_secs(7 * DAY)),
+            keep_weekly: Some(Duration::from_secs(30 * DAY)),
+        };
+        assert_eq!(
+            retention.retained(&times, now),
+            [true, false, true, false, true, true, false]
+        );
+        assert!(RetentionConfig::default().retained(&times, now).iter().all(|kept| !kept));
+    }
+}
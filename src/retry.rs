@@ -0,0 +1,267 @@
+//! Retries of transient failures.
+//!
+//! With a `[retry]` section copies, removals and renames failing with a
+//! transient error (a file locked by an antivirus, an interrupted network
+//! share, a timeout) are attempted again after a delay which doubles with
+//! every attempt. Other errors, e.g. a missing file, fail at once.
+//!
+//! ```toml
+//! [retry]
+//! attempts = 5          # default 3, the first attempt included
+//! backoff = "500ms"     # delay before the first retry, default 500ms
+//! max_backoff = "30s"   # default 30s
+//! jitter = true         # default true
+//! # dead_letter = "/var/lib/fsync/dead-letter.jsonl"
+//! ```
+//!
+//! With `jitter` every delay is picked at random between half and all of
+//! the backoff, so instances hitting the same share do not retry in step.
+//! Changes still failing after the retries are logged as given up and
+//! appended to `dead_letter` as JSON lines if it is set.
+
+use std::{
+    fs,
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{audit::AuditLog, queue::Operation, AppError};
+
+/// `[retry]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetryConfig {
+    /// Attempts of an operation, the first one included
+    #[serde(default = "RetryConfig::default_attempts")]
+    pub(crate) attempts: u32,
+    /// Delay before the first retry
+    #[serde(default = "RetryConfig::default_backoff", with = "crate::config::humantime_serde")]
+    pub(crate) backoff: Duration,
+    /// Longest delay between two attempts
+    #[serde(default = "RetryConfig::default_max_backoff", with = "crate::config::humantime_serde")]
+    pub(crate) max_backoff: Duration,
+    /// Randomises the delays
+    #[serde(default = "RetryConfig::default_jitter")]
+    pub(crate) jitter: bool,
+    /// File the given up changes are appended to
+    #[serde(default)]
+    pub(crate) dead_letter: Option<PathBuf>,
+}
+
+impl RetryConfig {
+    /// Default of [RetryConfig::attempts]
+    fn default_attempts() -> u32 {
+        3
+    }
+
+    /// Default of [RetryConfig::backoff]
+    fn default_backoff() -> Duration {
+        Duration::from_millis(500)
+    }
+
+    /// Default of [RetryConfig::max_backoff]
+    fn default_max_backoff() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    /// Default of [RetryConfig::jitter]
+    fn default_jitter() -> bool {
+        true
+    }
+
+    /// Delay before the retry number `retry`, starting at 0
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self.backoff.saturating_mul(2_u32.saturating_pow(retry)).min(self.max_backoff);
+        if !self.jitter {
+            return delay;
+        }
+        let mut random = [0; 4];
+        let fraction = match getrandom::getrandom(&mut random) {
+            Ok(()) => f64::from(u32::from_le_bytes(random)) / f64::from(u32::MAX),
+            Err(_) => 1.0,
+        };
+        delay.mul_f64(0.5 + fraction / 2.0)
+    }
+
+    /// Runs `operation` until it succeeds, fails with an error which is not
+    /// [transient](is_transient) or the attempts are used up. `what` names
+    /// the operation in the logs.
+    ///
+    /// Returns the last result with the number of attempts made.
+    pub(crate) fn run<T>(&self, what: &str, mut operation: impl FnMut() -> Result<T, AppError>) -> (Result<T, AppError>, u32) {
+        let mut attempt = 1;
+        loop {
+            match operation() {
+                Err(err) if attempt < self.attempts && is_transient(&err) => {
+                    let delay = self.delay(attempt - 1);
+                    tracing::warn!(
+                        "{what}: {err} (attempt {attempt} of {}), retrying in {}",
+                        self.attempts,
+                        humantime::format_duration(Duration::from_millis(
+                            delay.as_millis() as u64
+                        ))
+                    );
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return (result, attempt),
+            }
+        }
+    }
+}
+
+/// Whether `error` may go away by itself
+pub(crate) fn is_transient(error: &AppError) -> bool {
+    match error.root_cause() {
+        AppError::IoError(err) => matches!(
+            err.kind(),
+            ErrorKind::PermissionDenied
+                | ErrorKind::Interrupted
+                | ErrorKind::WouldBlock
+                | ErrorKind::TimedOut
+                | ErrorKind::ResourceBusy
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+        ),
+        _ => false,
+    }
+}
+
+/// Line of the dead letter file
+#[derive(Debug, Serialize)]
+struct DeadLetter<'a> {
+    /// RFC 3339 time of giving up
+    timestamp: String,
+    /// Change given up
+    #[serde(flatten)]
+    operation: &'a Operation,
+    /// Attempts made
+    attempts: u32,
+    /// Last error
+    error: String,
+}
+
+/// Appends `operation`, given up after `attempts` with `error`, to `file`
+pub(crate) fn dead_letter(file: &Path, operation: &Operation, attempts: u32, error: &AppError) -> io::Result<()> {
+    let record = DeadLetter {
+        timestamp: AuditLog::now(),
+        operation,
+        attempts,
+        error: error.to_string(),
+    };
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(file)?;
+    writeln!(
+        file,
+        "{}",
+        serde_json::to_string(&record)?
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// Three attempts a millisecond or two apart
+    fn retry() -> RetryConfig {
+        RetryConfig {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+            jitter: true,
+            dead_letter: None,
+        }
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let mut calls = 0;
+        let (result, attempts) = retry().run("copy a", || {
+            calls += 1;
+            match calls {
+                1 | 2 => Err(io::Error::from(ErrorKind::PermissionDenied).into()),
+                _ => Ok(()),
+            }
+        });
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn other_errors_fail_at_once() {
+        let (result, attempts) = retry().run("copy b", || -> Result<(), AppError> {
+            Err(AppError::from(io::Error::from(ErrorKind::NotFound)).context("open", "b"))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn attempts_are_used_up() {
+        let (result, attempts) = retry().run("copy c", || -> Result<(), AppError> {
+            Err(io::Error::from(ErrorKind::TimedOut).into())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn delays_double_up_to_the_longest() {
+        let retry = RetryConfig {
+            jitter: false,
+            max_backoff: Duration::from_millis(5),
+            ..retry()
+        };
+        assert_eq!(retry.delay(0), Duration::from_millis(1));
+        assert_eq!(retry.delay(2), Duration::from_millis(4));
+        assert_eq!(
+            retry.delay(10),
+            Duration::from_millis(5)
+        );
+        assert_eq!(
+            retry.delay(u32::MAX),
+            Duration::from_millis(5)
+        );
+    }
+
+    #[test]
+    fn jitter_keeps_between_half_and_all_of_the_delay() {
+        let retry = RetryConfig {
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(1),
+            ..retry()
+        };
+        for _ in 0..100 {
+            let delay = retry.delay(0);
+            assert!(delay >= Duration::from_millis(500));
+            assert!(delay <= Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn given_up_changes_are_appended() {
+        let dir = TempDir::new("dead-letter");
+        let file = dir.join("dead-letter.jsonl");
+        let error = AppError::from(io::Error::from(ErrorKind::TimedOut));
+        for name in ["a", "b"] {
+            dead_letter(
+                &file,
+                &Operation::Copy { path: name.into() },
+                3,
+                &error,
+            )
+            .unwrap();
+        }
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["op"], "copy");
+        assert_eq!(lines[1]["path"], "b");
+        assert_eq!(lines[1]["attempts"], 3);
+    }
+}
@@ -0,0 +1,280 @@
+//! Routing of the files by their type.
+//!
+//! `[[route]]` entries send files to a directory of the destination, or
+//! keep them from it, by their extension or by the MIME type detected from
+//! their contents. The first entry matching a file decides, files matching
+//! none are stored under their usual path:
+//!
+//! ```toml
+//! [[route]]
+//! mime = "image/*"
+//! to = "media"                # photos/a.jpg -> media/photos/a.jpg
+//!
+//! [[route]]
+//! extensions = ["exe", "dll"]
+//! skip = true
+//!
+//! [[route]]
+//! to = "docs"                 # everything else
+//! ```
+//!
+//! An entry with both `extensions` and `mime` matches files which have one
+//! of the extensions and the type; an entry with neither matches every file.
+//! Extensions are compared case-insensitively. Types are detected from the
+//! first bytes of a file (images, audio, video, archives, documents,
+//! executables ...), files without a known signature have no type. A file
+//! keeps the route it got first while fsync runs, so removals and renames
+//! find it after its contents are gone; directories are only created for
+//! the routed files in them.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::Deserialize;
+
+use crate::{glob::Glob, paths};
+
+/// `[[route]]` entry of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteConfig {
+    /// Extensions of the matching files, without the dot
+    #[serde(default)]
+    pub(crate) extensions: Vec<String>,
+    /// MIME type of the matching files, `*` matches any subtype
+    #[serde(default)]
+    pub(crate) mime: Option<String>,
+    /// Destination directory of the matching files
+    #[serde(default)]
+    pub(crate) to: Option<PathBuf>,
+    /// The matching files are not synchronised
+    #[serde(default)]
+    pub(crate) skip: bool,
+}
+
+impl RouteConfig {
+    /// Whether the file at `path` of the type `mime` matches
+    fn matches(&self, path: &Path, mime: Option<&str>) -> bool {
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        (self.extensions.is_empty()
+            || extension.is_some_and(|extension| self.extensions.iter().any(|ext| ext.eq_ignore_ascii_case(extension))))
+            && self
+                .mime
+                .as_deref()
+                .is_none_or(|pattern| mime.is_some_and(|mime| Glob::anchored(pattern).matches(Path::new(mime))))
+    }
+}
+
+/// Routes of an [App](crate::App)
+#[derive(Debug)]
+pub(crate) struct Router {
+    /// Routes in the order they are tried
+    routes: Vec<RouteConfig>,
+    /// Route of the source files seen so far, by its index
+    routed: Mutex<HashMap<PathBuf, Option<usize>>>,
+}
+
+impl Router {
+    /// Router of `routes`, none if there are none
+    pub(crate) fn new(routes: &[RouteConfig]) -> Option<Self> {
+        (!routes.is_empty()).then(|| Self {
+            routes: routes.to_vec(),
+            routed: Mutex::default(),
+        })
+    }
+
+    /// Route of the source entry `source`
+    fn route(&self, source: &Path) -> Option<&RouteConfig> {
+        let mut routed = self.routed.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(index) = routed.get(source) {
+            return index.map(|index| &self.routes[index]);
+        }
+        let path = paths::extended(source);
+        if !path.is_file() {
+            return None;
+        }
+        let mime = infer::get_from_path(&path).ok().flatten().map(|kind| kind.mime_type());
+        let index = self.routes.iter().position(|route| route.matches(source, mime));
+        tracing::debug!(
+            "{}: type {}, route {index:?}",
+            source.display(),
+            mime.unwrap_or("unknown")
+        );
+        routed.insert(source.to_path_buf(), index);
+        index.map(|index| &self.routes[index])
+    }
+
+    /// Destination of the source entry `source` stored at `relative`
+    /// without routes
+    pub(crate) fn destination(&self, source: &Path, relative: PathBuf) -> PathBuf {
+        match self.route(source).and_then(|route| route.to.as_ref()) {
+            Some(to) => to.join(relative),
+            None => relative,
+        }
+    }
+
+    /// Whether the source entry `source` is not synchronised
+    pub(crate) fn skips(&self, source: &Path) -> bool {
+        self.route(source).is_some_and(|route| route.skip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// PNG signature
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    /// Router of the `[[route]]` entries in `toml`
+    fn router(toml: &str) -> Router {
+        let routes = toml::from_str::<HashMap<String, Vec<RouteConfig>>>(toml)
+            .unwrap()
+            .remove("route")
+            .unwrap();
+        Router::new(&routes).unwrap()
+    }
+
+    #[test]
+    fn routes_by_detected_type() {
+        let root = TempDir::new("route-type");
+        // A PNG signature behind a misleading extension
+        fs::write(root.join("image.dat"), PNG).unwrap();
+        fs::write(root.join("image.png"), "not an image").unwrap();
+        let router = router(
+            r#"
+            [[route]]
+            mime = "image/*"
+            to = "media"
+            "#,
+        );
+        assert_eq!(
+            router.destination(
+                &root.join("image.dat"),
+                "image.dat".into()
+            ),
+            PathBuf::from("media/image.dat")
+        );
+        assert_eq!(
+            router.destination(
+                &root.join("image.png"),
+                "image.png".into()
+            ),
+            PathBuf::from("image.png")
+        );
+    }
+
+    #[test]
+    fn extensions_match_regardless_of_case() {
+        let root = TempDir::new("route-extension");
+        fs::write(root.join("tool.EXE"), "MZ").unwrap();
+        fs::write(root.join("tool.sh"), "#!/bin/sh").unwrap();
+        let router = router(
+            r#"
+            [[route]]
+            extensions = ["exe"]
+            skip = true
+            "#,
+        );
+        assert!(router.skips(&root.join("tool.EXE")));
+        assert!(!router.skips(&root.join("tool.sh")));
+    }
+
+    #[test]
+    fn extensions_and_type_both_have_to_match() {
+        let root = TempDir::new("route-both");
+        fs::write(root.join("a.png"), PNG).unwrap();
+        fs::write(root.join("b.dat"), PNG).unwrap();
+        let router = router(
+            r#"
+            [[route]]
+            extensions = ["png"]
+            mime = "image/png"
+            to = "png"
+            "#,
+        );
+        assert_eq!(
+            router.destination(&root.join("a.png"), "a.png".into()),
+            PathBuf::from("png/a.png")
+        );
+        assert_eq!(
+            router.destination(&root.join("b.dat"), "b.dat".into()),
+            PathBuf::from("b.dat")
+        );
+    }
+
+    #[test]
+    fn first_matching_route_decides() {
+        let root = TempDir::new("route-first");
+        fs::write(root.join("image.png"), PNG).unwrap();
+        fs::write(root.join("notes.txt"), "notes").unwrap();
+        let router = router(
+            r#"
+            [[route]]
+            mime = "image/*"
+            to = "media"
+            [[route]]
+            to = "docs"
+            "#,
+        );
+        assert_eq!(
+            router.destination(
+                &root.join("image.png"),
+                "image.png".into()
+            ),
+            PathBuf::from("media/image.png")
+        );
+        assert_eq!(
+            router.destination(
+                &root.join("notes.txt"),
+                "notes.txt".into()
+            ),
+            PathBuf::from("docs/notes.txt")
+        );
+    }
+
+    #[test]
+    fn routes_are_remembered_after_removal() {
+        let root = TempDir::new("route-removed");
+        fs::write(root.join("image.dat"), PNG).unwrap();
+        let router = router(
+            r#"
+            [[route]]
+            mime = "image/*"
+            to = "media"
+            "#,
+        );
+        let image = root.join("image.dat");
+        assert_eq!(
+            router.destination(&image, "image.dat".into()),
+            PathBuf::from("media/image.dat")
+        );
+        fs::remove_file(&image).unwrap();
+        assert_eq!(
+            router.destination(&image, "image.dat".into()),
+            PathBuf::from("media/image.dat")
+        );
+    }
+
+    #[test]
+    fn directories_are_not_routed() {
+        let root = TempDir::new("route-dir");
+        fs::create_dir(root.join("dir")).unwrap();
+        let router = router(
+            r#"
+            [[route]]
+            to = "docs"
+            "#,
+        );
+        assert_eq!(
+            router.destination(&root.join("dir"), "dir".into()),
+            PathBuf::from("dir")
+        );
+        assert!(Router::new(&[]).is_none());
+    }
+}
@@ -0,0 +1,241 @@
+//! Rhai scripts filtering and routing the changes.
+//!
+//! With `--features scripting` a `[script]` section loads a
+//! [Rhai](https://rhai.rs) script which may define two functions:
+//!
+//! - `filter(event)` returns `false` to skip a change. `event` has the
+//!   fields `op` (`copy`, `remove` or `rename`), `path` (source path),
+//!   `from` (old source path of renames) and `destination` (relative to the
+//!   destination root).
+//! - `destination(path, destination)` returns the destination path, relative
+//!   to the destination root, of the source `path` instead of `destination`.
+//!
+//! ```rhai
+//! fn filter(event) {
+//!     !(event.op == "copy" && first_line(event.path).contains("DRAFT"))
+//! }
+//!
+//! fn destination(path, destination) {
+//!     if matches(destination, "*.log") { `logs/${destination}` } else { destination }
+//! }
+//! ```
+//!
+//! Scripts can use `first_line(path)`, `size(path)`, `is_dir(path)` and
+//! `matches(path, pattern)` ([glob](crate::glob) patterns). Failing calls
+//! are logged and the change is applied unchanged.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use serde::Deserialize;
+
+use crate::{glob::Glob, queue::Operation, AppError};
+
+/// Longest first line returned to scripts
+const MAX_LINE: u64 = 64 * 1024;
+
+/// `[script]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScriptConfig {
+    /// Script file
+    pub(crate) path: PathBuf,
+}
+
+/// Compiled script of an [App](crate::App)
+pub(crate) struct Script {
+    /// Engine with the helper functions
+    engine: Engine,
+    /// Compiled script
+    ast: AST,
+    /// `filter` is defined
+    filter: bool,
+    /// `destination` is defined
+    destination: bool,
+}
+
+impl Script {
+    /// Compiles the script of `config`.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if the script could not be read or compiled.
+    pub(crate) fn load(config: &ScriptConfig) -> Result<Self, AppError> {
+        let mut engine = Engine::new();
+        engine
+            .register_fn("first_line", |path: &str| {
+                first_line(Path::new(path)).unwrap_or_default()
+            })
+            .register_fn("size", |path: &str| {
+                fs::metadata(path).map_or(0, |meta| {
+                    i64::try_from(meta.len()).unwrap_or(i64::MAX)
+                })
+            })
+            .register_fn("is_dir", |path: &str| {
+                Path::new(path).is_dir()
+            })
+            .register_fn(
+                "matches",
+                |path: &str, pattern: &str| Glob::new(pattern).matches(Path::new(path)),
+            );
+        let ast = engine.compile_file(config.path.clone()).map_err(|e| {
+            AppError::Backend(format!(
+                "script {}: {e}",
+                config.path.display()
+            ))
+        })?;
+        let defined = |name: &str| ast.iter_functions().any(|function| function.name == name);
+        let (filter, destination) = (
+            defined("filter"),
+            defined("destination"),
+        );
+        tracing::info!(
+            "script {} loaded (filter: {filter}, destination: {destination})",
+            config.path.display()
+        );
+        Ok(Self {
+            engine,
+            ast,
+            filter,
+            destination,
+        })
+    }
+
+    /// `filter(event)` of the script, `true` if not defined or failing
+    pub(crate) fn filter(&self, operation: &Operation, destination: &Path) -> bool {
+        if !self.filter {
+            return true;
+        }
+        let text = |path: &Path| Dynamic::from(path.to_string_lossy().into_owned());
+        let mut event = Map::new();
+        let (op, path) = match operation {
+            Operation::Copy { path } => ("copy", path),
+            Operation::Remove { path } => ("remove", path),
+            Operation::Rename { from, to } => {
+                event.insert("from".into(), text(from));
+                ("rename", to)
+            }
+        };
+        event.insert("op".into(), op.into());
+        event.insert("path".into(), text(path));
+        event.insert("destination".into(), text(destination));
+        match self.engine.call_fn::<Dynamic>(
+            &mut Scope::new(),
+            &self.ast,
+            "filter",
+            (event,),
+        ) {
+            Ok(result) if result.is_unit() => true,
+            Ok(result) => result.as_bool().unwrap_or_else(|kind| {
+                tracing::warn!("script filter returned {kind} instead of bool");
+                true
+            }),
+            Err(err) => {
+                tracing::warn!(
+                    "script filter {}: {err}",
+                    path.display()
+                );
+                true
+            }
+        }
+    }
+
+    /// `destination(path, destination)` of the script, `destination` if not
+    /// defined or failing
+    pub(crate) fn destination(&self, path: &Path, destination: PathBuf) -> PathBuf {
+        if !self.destination {
+            return destination;
+        }
+        let args = (
+            path.to_string_lossy().into_owned(),
+            destination.to_string_lossy().into_owned(),
+        );
+        match self.engine.call_fn::<String>(
+            &mut Scope::new(),
+            &self.ast,
+            "destination",
+            args,
+        ) {
+            Ok(routed) => PathBuf::from(routed.trim_start_matches('/')),
+            Err(err) => {
+                tracing::warn!(
+                    "script destination {}: {err}",
+                    path.display()
+                );
+                destination
+            }
+        }
+    }
+}
+
+/// First line of the file at `path` without the line break
+fn first_line(path: &Path) -> std::io::Result<String> {
+    let mut line = String::new();
+    BufReader::new(fs::File::open(path)?.take(MAX_LINE)).read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_and_route() {
+        let dir = std::env::temp_dir().join(format!(
+            "fsync-script-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("fsync.rhai");
+        fs::write(
+            &script,
+            r#"
+                fn filter(event) {
+                    !(event.op == "copy" && first_line(event.path).contains("DRAFT"))
+                }
+                fn destination(path, destination) {
+                    if matches(destination, "*.log") { `logs/${destination}` } else { destination }
+                }
+            "#,
+        )
+        .unwrap();
+        let (draft, done) = (
+            dir.join("draft.md"),
+            dir.join("done.md"),
+        );
+        fs::write(&draft, "DRAFT: later\nbody").unwrap();
+        fs::write(&done, "# Done").unwrap();
+
+        let script = Script::load(&ScriptConfig { path: script }).unwrap();
+        assert!(!script.filter(
+            &Operation::Copy { path: draft.clone() },
+            Path::new("draft.md")
+        ));
+        assert!(script.filter(
+            &Operation::Copy { path: done },
+            Path::new("done.md")
+        ));
+        assert!(script.filter(
+            &Operation::Remove { path: draft },
+            Path::new("draft.md")
+        ));
+        assert_eq!(
+            script.destination(
+                Path::new("/src/a/b.log"),
+                "a/b.log".into()
+            ),
+            PathBuf::from("logs/a/b.log")
+        );
+        assert_eq!(
+            script.destination(
+                Path::new("/src/a/b.txt"),
+                "a/b.txt".into()
+            ),
+            PathBuf::from("a/b.txt")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
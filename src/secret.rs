@@ -0,0 +1,211 @@
+//! Passwords and tokens of the configuration file.
+//!
+//! A [Secret] is either written inline or refers to an entry of the
+//! platform keychain (Secret Service, macOS Keychain, Windows Credential
+//! Manager):
+//!
+//! ```toml
+//! [b2]
+//! key_id = "0012ab"
+//! application_key = { keyring = "b2" }
+//! ```
+//!
+//! Keychain entries are stored with `fsync keyring set <name>` under the
+//! `fsync` service.
+//!
+//! Secrets injected by Docker or Kubernetes are read from an environment
+//! variable or a file, trailing newlines of the file are ignored:
+//!
+//! ```toml
+//! application_key = { env = "FWATCH_B2_KEY" }
+//! client_secret = { file = "/run/secrets/gdrive_secret" }
+//! ```
+
+use std::{fmt::Debug, fs, path::PathBuf, sync::OnceLock};
+
+use serde::Deserialize;
+
+use crate::AppError;
+
+/// Service name of the keychain entries
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "fsync";
+
+/// Where the value of a [Secret] comes from
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum Source {
+    /// Written in the configuration file
+    Plain(String),
+    /// Keychain entry
+    Keyring {
+        /// Entry name
+        keyring: String,
+    },
+    /// Environment variable
+    Env {
+        /// Variable name
+        env: String,
+    },
+    /// File containing only the secret
+    File {
+        /// Path of the file
+        file: PathBuf,
+    },
+}
+
+/// Credential of a backend, looked up on first use.
+///
+/// The value is never printed by [Debug].
+#[derive(Clone, Deserialize)]
+#[serde(from = "Source")]
+pub struct Secret {
+    /// Origin of the value
+    source: Source,
+    /// Value once looked up
+    value: OnceLock<String>,
+}
+
+impl From<Source> for Secret {
+    fn from(source: Source) -> Self {
+        Self {
+            source,
+            value: OnceLock::new(),
+        }
+    }
+}
+
+impl Default for Secret {
+    fn default() -> Self {
+        Source::Plain(String::new()).into()
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.source {
+            Source::Plain(_) => f.write_str("Secret(..)"),
+            Source::Keyring { keyring } => write!(f, "Secret(keyring: {keyring})"),
+            Source::Env { env } => write!(f, "Secret(env: {env})"),
+            Source::File { file } => write!(f, "Secret(file: {})", file.display()),
+        }
+    }
+}
+
+impl Secret {
+    /// Value of the secret.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if the keychain entry, the environment
+    /// variable or the file is missing, or the keychain is not available.
+    pub(crate) fn expose(&self) -> Result<&str, AppError> {
+        if let Some(value) = self.value.get() {
+            return Ok(value);
+        }
+        let value = match &self.source {
+            Source::Plain(value) => value.clone(),
+            Source::Keyring { keyring } => keyring_get(keyring)?,
+            Source::Env { env } => std::env::var(env).map_err(|e| {
+                AppError::Backend(format!(
+                    "environment variable {env}: {e}"
+                ))
+            })?,
+            Source::File { file } => fs::read_to_string(file)
+                .map_err(|e| {
+                    AppError::Backend(format!(
+                        "secret file {}: {e}",
+                        file.display()
+                    ))
+                })?
+                .trim_end_matches(['\r', '\n'])
+                .to_owned(),
+        };
+        Ok(self.value.get_or_init(|| value))
+    }
+}
+
+/// Reads the keychain entry `name`
+#[cfg(feature = "keyring")]
+fn keyring_get(name: &str) -> Result<String, AppError> {
+    keyring::Entry::new(KEYRING_SERVICE, name)
+        .and_then(|entry| entry.get_password())
+        .map_err(|e| AppError::Backend(format!("keyring entry {name:?}: {e}")))
+}
+
+/// Keychain support is not compiled in
+#[cfg(not(feature = "keyring"))]
+fn keyring_get(name: &str) -> Result<String, AppError> {
+    Err(AppError::Backend(format!(
+        "keyring entry {name:?}: fsync was built without the `keyring` feature"
+    )))
+}
+
+/// Stores `value` as the keychain entry `name`, used by `fsync keyring set`.
+///
+/// # Errors
+///
+/// [AppError::Backend] is returned if the keychain is not available.
+#[cfg(feature = "keyring")]
+pub fn keyring_set(name: &str, value: &str) -> Result<(), AppError> {
+    keyring::Entry::new(KEYRING_SERVICE, name)
+        .and_then(|entry| entry.set_password(value))
+        .map_err(|e| AppError::Backend(format!("keyring entry {name:?}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration section with a secret
+    #[derive(Debug, Deserialize)]
+    struct Section {
+        /// Secret under test
+        token: Secret,
+    }
+
+    #[test]
+    fn plain_value_is_not_printed() {
+        let section: Section = toml::from_str(r#"token = "hunter2""#).unwrap();
+        assert_eq!(
+            section.token.expose().unwrap(),
+            "hunter2"
+        );
+        assert!(!format!("{section:?}").contains("hunter2"));
+
+        let section: Section = toml::from_str(r#"token = { keyring = "peer" }"#).unwrap();
+        assert_eq!(
+            format!("{:?}", section.token),
+            "Secret(keyring: peer)"
+        );
+    }
+
+    #[test]
+    fn env_and_file_sources() {
+        std::env::set_var("FSYNC_TEST_SECRET", "from-env");
+        let section: Section = toml::from_str(r#"token = { env = "FSYNC_TEST_SECRET" }"#).unwrap();
+        assert_eq!(
+            section.token.expose().unwrap(),
+            "from-env"
+        );
+
+        let file = std::env::temp_dir().join(format!(
+            "fsync-secret-{}",
+            std::process::id()
+        ));
+        fs::write(&file, "from-file\n").unwrap();
+        let section: Section = toml::from_str(&format!(
+            "token = {{ file = {:?} }}",
+            file.to_str().unwrap()
+        ))
+        .unwrap();
+        assert_eq!(
+            section.token.expose().unwrap(),
+            "from-file"
+        );
+        fs::remove_file(file).unwrap();
+
+        let section: Section = toml::from_str(r#"token = { env = "FSYNC_TEST_SECRET_MISSING" }"#).unwrap();
+        assert!(section.token.expose().is_err());
+    }
+}
@@ -0,0 +1,218 @@
+//! Sources narrower than a directory.
+//!
+//! A file given as the source is watched through its parent directory, and
+//! only the changes of that file are synchronised. It is stored under its
+//! own name if the destination is a directory (an existing one, a path with
+//! a trailing separator or a remote backend), otherwise the destination is
+//! the path of the stored file:
+//!
+//! ```sh
+//! fsync ~/.bashrc /mnt/backup/          # /mnt/backup/.bashrc
+//! fsync ~/.bashrc /mnt/backup/bashrc    # /mnt/backup/bashrc
+//! ```
+//!
+//! Editors saving through a temporary file rename it over the watched file,
+//! such a rename copies the file, renaming the file away removes it from
+//! the destination.
+//!
+//! A source with `*` or `?` in it is a [glob](crate::glob) pattern. The
+//! directory before the first wildcard is watched and only the files below it
+//! matching the rest of the pattern are synchronised, with their paths
+//! relative to that directory:
+//!
+//! ```sh
+//! fsync '/var/log/**/*.log' /mnt/logs   # /var/log/nginx/access.log -> /mnt/logs/nginx/access.log
+//! ```
+//!
+//! The files are matched during the initial scan and for every change
+//! afterwards, so new files matching the pattern are picked up. Directories
+//! are only created for the matching files in them; removals and renames of
+//! directories apply to the ones already stored.
+
+use std::{
+    ffi::OsString,
+    path::{is_separator, Component, Path, PathBuf},
+};
+
+use crate::{glob::Glob, queue::Operation};
+
+/// Part of the source which is synchronised
+#[derive(Debug)]
+pub(crate) enum Selection {
+    /// One file of the source directory
+    File(SingleFile),
+    /// Files matching a pattern
+    Glob(Glob),
+}
+
+impl Selection {
+    /// Selection of the source of `config` if it is a file or a pattern,
+    /// the source is changed to the directory to watch
+    pub(crate) fn resolve(config: &mut crate::Config) -> Option<Self> {
+        if config.source.is_file() {
+            return SingleFile::resolve(config).map(Self::File);
+        }
+        let text = config.source.to_string_lossy();
+        if config.source.exists() || !text.contains(['*', '?']) {
+            return None;
+        }
+        let (mut root, mut pattern) = (PathBuf::new(), Vec::new());
+        for part in config.source.components() {
+            let wildcard = part.as_os_str().to_string_lossy().contains(['*', '?']);
+            match part {
+                Component::Normal(name) if wildcard || !pattern.is_empty() => pattern.push(name.to_string_lossy().into_owned()),
+                part => root.push(part),
+            }
+        }
+        if root.as_os_str().is_empty() {
+            root.push(".");
+        }
+        config.source = root;
+        Some(Self::Glob(Glob::anchored(
+            &pattern.join("/"),
+        )))
+    }
+
+    /// Source files of the initial scan, `entries` being the ones of the
+    /// watched directory
+    pub(crate) fn entries(&self, source: &Path, entries: impl FnOnce() -> Vec<PathBuf>) -> Vec<PathBuf> {
+        match self {
+            Self::File(file) => vec![file.path.clone()],
+            Self::Glob(glob) => entries()
+                .into_iter()
+                .filter(|entry| entry.is_file() && entry.strip_prefix(source).is_ok_and(|relative| glob.matches(relative)))
+                .collect(),
+        }
+    }
+
+    /// Destination path of the source path `relative` to the watched
+    /// directory
+    pub(crate) fn destination(&self, relative: PathBuf) -> PathBuf {
+        match self {
+            Self::File(file) if relative == Path::new(&file.name) => PathBuf::from(&file.stored),
+            _ => relative,
+        }
+    }
+
+    /// Change of the selection for a change in the watched directory, none
+    /// if it concerns other entries. `stored_dir` tells whether the source
+    /// path is a directory at the destination.
+    pub(crate) fn select(&self, source: &Path, operation: &Operation, stored_dir: impl Fn(&Path) -> bool) -> Option<Operation> {
+        let selected = |path: &Path| match self {
+            Self::File(file) => path == file.path,
+            Self::Glob(glob) => path.strip_prefix(source).is_ok_and(|relative| glob.matches(relative)) && !path.is_dir(),
+        };
+        let stored = |path: &Path| matches!(self, Self::Glob(_)) && stored_dir(path);
+        match operation {
+            Operation::Copy { path } if selected(path) => Some(operation.clone()),
+            Operation::Remove { path } if selected(path) || stored(path) => Some(operation.clone()),
+            Operation::Rename { from, to } if selected(to) && (selected(from) || stored(from)) => Some(operation.clone()),
+            Operation::Rename { from, .. } if stored(from) => Some(operation.clone()),
+            // Saved through a temporary file
+            Operation::Rename { to, .. } if selected(to) => Some(Operation::Copy { path: to.clone() }),
+            Operation::Rename { from, .. } if selected(from) => Some(Operation::Remove { path: from.clone() }),
+            _ => None,
+        }
+    }
+
+    /// The watched directory is watched recursively
+    pub(crate) fn recursive(&self) -> bool {
+        matches!(self, Self::Glob(_))
+    }
+}
+
+/// File synchronised instead of a directory
+#[derive(Debug)]
+pub(crate) struct SingleFile {
+    /// Source file, below the watched parent directory
+    path: PathBuf,
+    /// Name of the source file
+    name: OsString,
+    /// Name at the destination
+    stored: OsString,
+}
+
+impl SingleFile {
+    /// Single file of `config`, the source and the destination are changed
+    /// to the directories holding the files
+    fn resolve(config: &mut crate::Config) -> Option<Self> {
+        let name = config.source.file_name()?.to_os_string();
+        let parent = match config.source.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let destination = &config.destination;
+        let into_directory = crate::target::split_scheme(destination).is_some()
+            || destination.is_dir()
+            || destination.as_os_str().to_string_lossy().ends_with(is_separator);
+        let stored = match (
+            into_directory,
+            destination.file_name(),
+            destination.parent(),
+        ) {
+            (false, Some(stored), Some(parent)) => {
+                let stored = stored.to_os_string();
+                config.destination = if parent.as_os_str().is_empty() {
+                    PathBuf::from(".")
+                } else {
+                    parent.to_path_buf()
+                };
+                stored
+            }
+            _ => name.clone(),
+        };
+        config.source = parent;
+        Some(Self {
+            path: config.source.join(&name),
+            name,
+            stored,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_sources_watch_the_directory_before_the_wildcards() {
+        let mut config = crate::Config::build(
+            "/var/log/**/*.log".into(),
+            "/mnt/logs".into(),
+        );
+        let Some(Selection::Glob(glob)) = Selection::resolve(&mut config) else {
+            panic!("not a glob source");
+        };
+        assert_eq!(config.source, PathBuf::from("/var/log"));
+        assert!(glob.matches(Path::new("nginx/access.log")));
+        assert!(glob.matches(Path::new("syslog.log")));
+        assert!(!glob.matches(Path::new("nginx/access.log.1")));
+
+        let mut config = crate::Config::build(
+            "/var/log/*.log".into(),
+            "/mnt/logs".into(),
+        );
+        let Some(selection) = Selection::resolve(&mut config) else {
+            panic!("not a glob source");
+        };
+        let source = Path::new("/var/log");
+        let copy = |path: &str| Operation::Copy { path: source.join(path) };
+        assert!(selection.select(source, &copy("syslog.log"), |_| false).is_some());
+        // Anchored at the watched directory, unlike a name only pattern
+        assert!(selection
+            .select(
+                source,
+                &copy("nginx/access.log"),
+                |_| false
+            )
+            .is_none());
+        let rename = Operation::Rename {
+            from: source.join("new.tmp"),
+            to: source.join("new.log"),
+        };
+        assert_eq!(
+            selection.select(source, &rename, |_| false),
+            Some(copy("new.log"))
+        );
+    }
+}
@@ -0,0 +1,594 @@
+//! Statistics of an [App](crate::App).
+//!
+//! Unlike [crate::metrics], which are process wide, statistics belong to a
+//! single synchronisation and are read through its [AppHandle](crate::AppHandle).
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+/// Cumulative statistics of a synchronisation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Files uploaded to the destination
+    pub copied: u64,
+    /// Bytes of the uploaded files
+    pub bytes: u64,
+    /// Entries removed from the destination
+    pub removed: u64,
+    /// Entries renamed at the destination
+    pub renamed: u64,
+    /// Files found up to date, or gone before they could be copied
+    pub skipped: u64,
+    /// Failed operations
+    pub errors: u64,
+    /// Time spent applying changes
+    pub busy: Duration,
+    /// Time since the synchronisation was created
+    pub uptime: Duration,
+    /// Bytes stored per second over the last ten seconds
+    pub throughput: u64,
+    /// Bytes the initial sync still has to copy, none outside of it
+    pub remaining: Option<u64>,
+}
+
+impl Stats {
+    /// Estimated time until the initial sync is done at the current
+    /// [throughput](Stats::throughput)
+    pub fn eta(&self) -> Option<Duration> {
+        eta(self.remaining?, self.throughput)
+    }
+}
+
+/// Time to store `bytes` at `throughput` bytes per second, none without
+/// throughput
+pub fn eta(bytes: u64, throughput: u64) -> Option<Duration> {
+    (throughput > 0).then(|| Duration::from_secs(bytes.div_ceil(throughput)))
+}
+
+/// `bytes` in decimal units, as in `2.3 GB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KB", "MB", "GB", "TB", "PB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// `throughput` and the time left for `remaining` bytes, as in
+/// `2.3 GB/s, ~4m remaining`
+pub fn format_rate(throughput: u64, remaining: Option<u64>) -> String {
+    let rate = format!("{}/s", format_bytes(throughput));
+    match remaining.and_then(|remaining| eta(remaining, throughput)) {
+        // Minutes once it takes longer
+        Some(eta) if eta >= Duration::from_secs(60) => format!(
+            "{rate}, ~{} remaining",
+            humantime::format_duration(Duration::from_secs(
+                eta.as_secs() / 60 * 60
+            ))
+        ),
+        Some(eta) => format!(
+            "{rate}, ~{} remaining",
+            humantime::format_duration(eta)
+        ),
+        None => rate,
+    }
+}
+
+/// Fields of the statistics of `/status`, the durations in seconds
+impl Serialize for Stats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut stats = serializer.serialize_struct("Stats", 11)?;
+        stats.serialize_field("copied", &self.copied)?;
+        stats.serialize_field("bytes", &self.bytes)?;
+        stats.serialize_field("removed", &self.removed)?;
+        stats.serialize_field("renamed", &self.renamed)?;
+        stats.serialize_field("skipped", &self.skipped)?;
+        stats.serialize_field("errors", &self.errors)?;
+        stats.serialize_field("busy_seconds", &self.busy.as_secs_f64())?;
+        stats.serialize_field("uptime_seconds", &self.uptime.as_secs())?;
+        stats.serialize_field(
+            "throughput_bytes_per_second",
+            &self.throughput,
+        )?;
+        stats.serialize_field("remaining_bytes", &self.remaining)?;
+        stats.serialize_field(
+            "eta_seconds",
+            &self.eta().map(|eta| eta.as_secs()),
+        )?;
+        stats.end()
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} files copied ({} bytes), {} removed, {} renamed, {} skipped, {} errors, {} applying changes, up {}",
+            self.copied,
+            self.bytes,
+            self.removed,
+            self.renamed,
+            self.skipped,
+            self.errors,
+            humantime::format_duration(Duration::from_millis(
+                self.busy.as_millis() as u64
+            )),
+            humantime::format_duration(Duration::from_secs(
+                self.uptime.as_secs()
+            )),
+        )?;
+        if self.throughput > 0 {
+            write!(
+                f,
+                ", {}",
+                format_rate(self.throughput, self.remaining)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Stage of an [App](crate::App)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Initial synchronisation, before watching
+    Starting,
+    /// The watcher is running
+    Watching,
+    /// The watcher stopped
+    Stopped,
+    /// A rescan compares the source with the destination again
+    Scanning,
+}
+
+/// State of an [App](crate::App) at a point in time, for dashboards and
+/// the `/status` endpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Status {
+    /// Watched source
+    pub source: PathBuf,
+    /// Description of the destination
+    pub destination: String,
+    /// Current stage
+    pub phase: Phase,
+    /// Age of the oldest change of the source not yet applied, zero once
+    /// the destination caught up
+    pub lag: Duration,
+    /// Changes waiting for an unavailable destination
+    pub queue_depth: u64,
+    /// Changes being applied
+    pub in_flight: Vec<InFlight>,
+    /// Time of the last change of the source seen by the watcher
+    pub last_event: Option<SystemTime>,
+    /// Time and description of the last failure
+    pub last_error: Option<(SystemTime, String)>,
+    /// Time since the synchronisation was created
+    pub uptime: Duration,
+    /// Cumulative statistics
+    pub stats: Stats,
+}
+
+/// Change of the destination being applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct InFlight {
+    /// `copy`, `mkdir`, `remove` or `rename`
+    pub operation: &'static str,
+    /// Destination path, relative to the destination root
+    pub path: PathBuf,
+    /// When it started
+    pub started: SystemTime,
+}
+
+/// RFC 3339 representation of `time`
+fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// Fields of `/status`, the times in RFC 3339 and the durations in seconds
+impl Serialize for Status {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut status = serializer.serialize_struct("Status", 11)?;
+        status.serialize_field("source", &self.source)?;
+        status.serialize_field("destination", &self.destination)?;
+        status.serialize_field("phase", &self.phase)?;
+        status.serialize_field(
+            "watcher_alive",
+            &matches!(
+                self.phase,
+                Phase::Watching | Phase::Scanning
+            ),
+        )?;
+        status.serialize_field("lag_seconds", &self.lag.as_secs_f64())?;
+        status.serialize_field("queue_depth", &self.queue_depth)?;
+        status.serialize_field("in_flight", &self.in_flight)?;
+        status.serialize_field(
+            "last_event",
+            &self.last_event.map(timestamp),
+        )?;
+        status.serialize_field(
+            "last_error",
+            &self.last_error.as_ref().map(|(time, message)| {
+                serde_json::json!({
+                    "time": timestamp(*time),
+                    "message": message,
+                })
+            }),
+        )?;
+        status.serialize_field("uptime_seconds", &self.uptime.as_secs())?;
+        status.serialize_field("stats", &self.stats)?;
+        status.end()
+    }
+}
+
+impl Serialize for InFlight {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut in_flight = serializer.serialize_struct("InFlight", 3)?;
+        in_flight.serialize_field("operation", self.operation)?;
+        in_flight.serialize_field("path", &self.path)?;
+        in_flight.serialize_field("started", &timestamp(self.started))?;
+        in_flight.end()
+    }
+}
+
+/// Counters and state updated by the [App](crate::App)
+#[derive(Debug)]
+pub(crate) struct Counters {
+    /// Files uploaded to the destination
+    copied: AtomicU64,
+    /// Bytes of the uploaded files
+    bytes: AtomicU64,
+    /// Entries removed from the destination
+    removed: AtomicU64,
+    /// Entries renamed at the destination
+    renamed: AtomicU64,
+    /// Files not copied
+    skipped: AtomicU64,
+    /// Failed operations
+    errors: AtomicU64,
+    /// Microseconds spent applying changes
+    busy: AtomicU64,
+    /// Creation time
+    started: Instant,
+    /// [Phase] as its index
+    phase: AtomicU8,
+    /// Changes waiting for an unavailable destination
+    queued: AtomicU64,
+    /// Seconds since the epoch of the last change of the source, zero if none
+    last_event: AtomicU64,
+    /// Time and description of the last failure
+    last_error: Mutex<Option<(SystemTime, String)>>,
+    /// Bytes stored within the [THROUGHPUT_WINDOW], by when
+    transferred: Mutex<VecDeque<(Instant, u64)>>,
+    /// Bytes the initial sync still has to copy, [u64::MAX] outside of it
+    remaining: AtomicU64,
+    /// Changes being applied, by an id
+    in_flight: Mutex<BTreeMap<u64, InFlight>>,
+    /// Id of the next change applied
+    next_flight: AtomicU64,
+    /// Time of the oldest change of the source not yet applied
+    pending_since: Mutex<Option<SystemTime>>,
+}
+
+/// Entry of [Counters::in_flight] while its change is applied
+pub(crate) struct Flight<'a> {
+    /// Counters holding the entry
+    counters: &'a Counters,
+    /// Id of the entry
+    id: u64,
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        self.counters
+            .in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// Window of the rolling throughput
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self {
+            copied: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            removed: AtomicU64::new(0),
+            renamed: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            busy: AtomicU64::new(0),
+            started: Instant::now(),
+            phase: AtomicU8::new(Phase::Starting as u8),
+            queued: AtomicU64::new(0),
+            last_event: AtomicU64::new(0),
+            last_error: Mutex::default(),
+            transferred: Mutex::default(),
+            remaining: AtomicU64::new(u64::MAX),
+            in_flight: Mutex::default(),
+            next_flight: AtomicU64::new(0),
+            pending_since: Mutex::default(),
+        }
+    }
+}
+
+impl Counters {
+    /// Counts a file uploaded with `bytes` bytes
+    pub(crate) fn copied(&self, bytes: u64) {
+        self.copied.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a removed entry
+    pub(crate) fn removed(&self) {
+        self.removed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a renamed entry
+    pub(crate) fn renamed(&self) {
+        self.renamed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a file which did not need to be copied
+    pub(crate) fn skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a failed operation, remembered as the last error
+    pub(crate) fn error(&self, error: &dyn Display) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.failed(error);
+    }
+
+    /// Remembers a failure without counting an operation
+    pub(crate) fn failed(&self, error: &dyn Display) {
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some((SystemTime::now(), error.to_string()));
+    }
+
+    /// Enters `phase`
+    pub(crate) fn phase(&self, phase: Phase) {
+        self.phase.store(phase as u8, Ordering::Relaxed);
+    }
+
+    /// Records the number of queued changes
+    pub(crate) fn queued(&self, len: usize) {
+        self.queued.store(len as u64, Ordering::Relaxed);
+    }
+
+    /// Records a change of the source
+    pub(crate) fn event(&self) {
+        let now = SystemTime::now();
+        self.last_event.store(
+            crate::peer::unix_secs(now),
+            Ordering::Relaxed,
+        );
+        self.pending_since
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert(now);
+    }
+
+    /// Every change of the source seen so far is applied
+    pub(crate) fn caught_up(&self) {
+        *self.pending_since.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Records the change `operation` of `path` as being applied until the
+    /// returned [Flight] is dropped
+    pub(crate) fn in_flight(&self, operation: &'static str, path: PathBuf) -> Flight<'_> {
+        let id = self.next_flight.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.lock().unwrap_or_else(|e| e.into_inner()).insert(
+            id,
+            InFlight {
+                operation,
+                path,
+                started: SystemTime::now(),
+            },
+        );
+        Flight { counters: self, id }
+    }
+
+    /// Current [Phase]
+    pub(crate) fn current_phase(&self) -> Phase {
+        match self.phase.load(Ordering::Relaxed) {
+            0 => Phase::Starting,
+            1 => Phase::Watching,
+            3 => Phase::Scanning,
+            _ => Phase::Stopped,
+        }
+    }
+
+    /// Changes waiting for an unavailable destination
+    pub(crate) fn queue_depth(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Time of the last change of the source
+    pub(crate) fn last_event(&self) -> Option<SystemTime> {
+        match self.last_event.load(Ordering::Relaxed) {
+            0 => None,
+            secs => Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    /// Time and description of the last failure
+    pub(crate) fn last_error(&self) -> Option<(SystemTime, String)> {
+        self.last_error.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Records `bytes` stored at the destination, during a copy or at its
+    /// end
+    pub(crate) fn transferred(&self, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut transferred = self.transferred.lock().unwrap_or_else(|e| e.into_inner());
+        transferred.push_back((now, bytes));
+        while transferred.front().is_some_and(|(time, _)| now - *time > THROUGHPUT_WINDOW) {
+            transferred.pop_front();
+        }
+        let _ = self.remaining.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |remaining| (remaining != u64::MAX).then(|| remaining.saturating_sub(bytes)),
+        );
+    }
+
+    /// The initial sync starts copying `bytes` bytes, or is done with none
+    pub(crate) fn initial_sync(&self, bytes: Option<u64>) {
+        self.remaining.store(
+            bytes.unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Bytes per second stored over the [THROUGHPUT_WINDOW], or since the
+    /// first bytes within it
+    fn throughput(&self) -> u64 {
+        let now = Instant::now();
+        let transferred = self.transferred.lock().unwrap_or_else(|e| e.into_inner());
+        let recent = transferred.iter().filter(|(time, _)| now - *time <= THROUGHPUT_WINDOW);
+        let Some((first, _)) = recent.clone().next() else {
+            return 0;
+        };
+        let bytes: u64 = recent.map(|(_, bytes)| bytes).sum();
+        // At least a second, so a first chunk is not taken as a burst
+        let span = (now - *first).clamp(
+            Duration::from_secs(1),
+            THROUGHPUT_WINDOW,
+        );
+        (bytes as f64 / span.as_secs_f64()) as u64
+    }
+
+    /// Adds the duration of an operation
+    pub(crate) fn busy(&self, duration: Duration) {
+        self.busy.fetch_add(
+            duration.as_micros() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Current state of the synchronisation of `source` to `destination`
+    pub(crate) fn status(&self, source: PathBuf, destination: String) -> Status {
+        let in_flight: Vec<_> = self
+            .in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .cloned()
+            .collect();
+        let pending_since = *self.pending_since.lock().unwrap_or_else(|e| e.into_inner());
+        let oldest = in_flight.iter().map(|flight| flight.started).chain(pending_since).min();
+        let stats = self.snapshot();
+        Status {
+            source,
+            destination,
+            phase: self.current_phase(),
+            lag: oldest.and_then(|oldest| oldest.elapsed().ok()).unwrap_or_default(),
+            queue_depth: self.queue_depth(),
+            in_flight,
+            last_event: self.last_event(),
+            last_error: self.last_error(),
+            uptime: stats.uptime,
+            stats,
+        }
+    }
+
+    /// Current values
+    pub(crate) fn snapshot(&self) -> Stats {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        Stats {
+            copied: load(&self.copied),
+            bytes: load(&self.bytes),
+            removed: load(&self.removed),
+            renamed: load(&self.renamed),
+            skipped: load(&self.skipped),
+            errors: load(&self.errors),
+            busy: Duration::from_micros(load(&self.busy)),
+            uptime: self.started.elapsed(),
+            throughput: self.throughput(),
+            remaining: Some(load(&self.remaining)).filter(|remaining| *remaining != u64::MAX),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throughput_and_time_left() {
+        assert_eq!(
+            format_rate(2_300_000_000, Some(552_000_000_000)),
+            "2.3 GB/s, ~4m remaining"
+        );
+        assert_eq!(format_rate(999, None), "999 B/s");
+        assert_eq!(eta(10, 0), None);
+
+        let counters = Counters::default();
+        counters.initial_sync(Some(3000));
+        counters.transferred(1000);
+        let stats = counters.snapshot();
+        // Within the first second
+        assert_eq!(stats.throughput, 1000);
+        assert_eq!(stats.remaining, Some(2000));
+        assert_eq!(
+            stats.eta(),
+            Some(Duration::from_secs(2))
+        );
+        counters.initial_sync(None);
+        assert_eq!(counters.snapshot().remaining, None);
+    }
+
+    #[test]
+    fn lags_behind_the_unapplied_changes() {
+        let counters = Counters::default();
+        let status = counters.status("/src".into(), "/dst".into());
+        assert_eq!(status.lag, Duration::ZERO);
+        assert!(status.in_flight.is_empty());
+
+        counters.event();
+        let flight = counters.in_flight("copy", "a.txt".into());
+        std::thread::sleep(Duration::from_millis(10));
+        let status = counters.status("/src".into(), "/dst".into());
+        assert!(status.lag >= Duration::from_millis(10));
+        assert_eq!(
+            status.in_flight[0].path,
+            PathBuf::from("a.txt")
+        );
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(
+            json["in_flight"][0]["operation"],
+            "copy"
+        );
+        assert_eq!(json["watcher_alive"], false);
+
+        drop(flight);
+        counters.caught_up();
+        let status = counters.status("/src".into(), "/dst".into());
+        assert_eq!(status.lag, Duration::ZERO);
+        assert!(status.in_flight.is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+//! Health and status endpoint.
+//!
+//! `--status <addr>` (or `status = "<addr>"` in the configuration file)
+//! serves two JSON documents:
+//!
+//! - `/healthz`: `{"status":"ok"}`, or `503 Service Unavailable` once the
+//!   watcher stopped, for liveness probes
+//! - `/status`: the [Status](crate::Status) of the synchronisation, its
+//!   stage, lag, queue depth and changes in flight, the time of the last
+//!   source change, the last error and the statistics
+//!
+//! `POST /rescan` asks for an [App::rescan](crate::App::rescan) while fsync
+//! watches and answers `202 Accepted`. With `status_token` set in the
+//! configuration file the request must carry it as
+//! `Authorization: Bearer <token>`, without one it is only accepted when
+//! the endpoint listens on a loopback address.
+
+use std::net::TcpListener;
+
+use serde_json::json;
+
+use crate::{
+    metrics::{respond, Request, Response},
+    AppError, AppHandle, Phase, Secret,
+};
+
+/// Whether `request` may ask for a rescan, `token` is the expected bearer token
+fn authorized(request: &Request, token: Option<&str>, loopback: bool) -> bool {
+    let Some(token) = token else {
+        return loopback;
+    };
+    let Some(given) = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // Constant time, the token is not guessed byte by byte
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Serves `/healthz` and `/status` of `handle` at `listen` in a background thread,
+/// `token` guards `POST /rescan`.
+///
+/// # Errors
+///
+/// [AppError::IoError] is returned if `listen` could not be bound, or the
+/// error of looking `token` up.
+pub fn serve(listen: &str, token: Option<Secret>, handle: AppHandle) -> Result<(), AppError> {
+    let token = token.as_ref().map(Secret::expose).transpose()?.map(str::to_owned);
+    let listener = TcpListener::bind(listen)?;
+    let loopback = listener.local_addr()?.ip().is_loopback();
+    tracing::info!(
+        "status available at http://{}/status",
+        listener.local_addr()?
+    );
+    respond(listener, move |request| {
+        match (
+            request.method.as_str(),
+            request.path.as_str(),
+        ) {
+            ("POST", "/rescan") if !authorized(request, token.as_deref(), loopback) => Some(Response {
+                status: "403 Forbidden",
+                content_type: "application/json",
+                body: json!({ "error": "rescan not authorized" }).to_string(),
+            }),
+            ("POST", "/rescan") => {
+                tracing::info!("rescan requested");
+                handle.rescan();
+                Some(Response {
+                    status: "202 Accepted",
+                    content_type: "application/json",
+                    body: json!({ "rescan": "requested" }).to_string(),
+                })
+            }
+            (_, "/healthz") => Some(match handle.phase() {
+                Phase::Stopped => Response {
+                    status: "503 Service Unavailable",
+                    content_type: "application/json",
+                    body: json!({ "status": "stopped" }).to_string(),
+                },
+                _ => Response {
+                    status: "200 OK",
+                    content_type: "application/json",
+                    body: json!({ "status": "ok" }).to_string(),
+                },
+            }),
+            (_, "/status") => Some(Response {
+                status: "200 OK",
+                content_type: "application/json",
+                body: json!(handle.status()).to_string(),
+            }),
+            _ => None,
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rescans_need_the_token() {
+        let request = |authorization: Option<&str>| Request {
+            method: "POST".into(),
+            path: "/rescan".into(),
+            headers: authorization
+                .map(|value| {
+                    (
+                        "authorization".to_owned(),
+                        value.to_owned(),
+                    )
+                })
+                .into_iter()
+                .collect(),
+        };
+        assert!(authorized(&request(None), None, true));
+        assert!(!authorized(&request(None), None, false));
+        assert!(authorized(
+            &request(Some("Bearer secret")),
+            Some("secret"),
+            false
+        ));
+        assert!(!authorized(
+            &request(None),
+            Some("secret"),
+            true
+        ));
+        assert!(!authorized(
+            &request(Some("Bearer secreT")),
+            Some("secret"),
+            true
+        ));
+        assert!(!authorized(
+            &request(Some("secret")),
+            Some("secret"),
+            true
+        ));
+    }
+}
@@ -0,0 +1,376 @@
+//! Destination backends.
+//!
+//! [App](crate::App) never touches the destination directly.
+//! It maps every source path to a path relative to the destination root
+//! and hands it over to a [SyncTarget] implementation.
+
+use std::{
+    ffi::OsString,
+    io::{self, Read, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use crate::AppError;
+
+#[cfg(feature = "azure")]
+mod azure;
+#[cfg(feature = "b2")]
+mod b2;
+mod dedup;
+#[cfg(feature = "gcs")]
+mod gcs;
+#[cfg(feature = "gdrive")]
+mod gdrive;
+#[cfg(feature = "http")]
+pub(crate) mod http;
+mod local;
+mod peer;
+mod rclone;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod zip;
+
+#[cfg(feature = "azure")]
+pub use azure::*;
+#[cfg(feature = "b2")]
+pub use b2::*;
+pub use dedup::*;
+#[cfg(feature = "gcs")]
+pub use gcs::*;
+#[cfg(feature = "gdrive")]
+pub use gdrive::*;
+#[cfg(feature = "http")]
+pub use http::{NetworkConfig, TlsConfig};
+pub use local::*;
+pub use peer::*;
+pub use rclone::*;
+#[cfg(feature = "sqlite")]
+pub use sqlite::*;
+pub use zip::*;
+
+/// Bytes copied between two progress reports
+pub(crate) const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Metadata of an entry stored at the destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetMetadata {
+    /// Entry is a directory (folder)
+    pub is_dir: bool,
+    /// Size of the entry in bytes
+    pub len: u64,
+    /// Last modification time
+    pub modified: SystemTime,
+}
+
+/// Destination abstraction.
+///
+/// All paths are relative to the destination root.
+/// An empty path represents the root itself.
+pub trait SyncTarget: Send + Sync {
+    /// Human readable description of the destination used in logs
+    fn describe(&self) -> String;
+
+    /// Prepares the destination before the first operation.
+    ///
+    /// # Errors
+    ///
+    /// Returns [AppError] if the destination is not reachable
+    fn connect(&self) -> Result<(), AppError>;
+
+    /// Metadata of the entry at `path`.
+    /// [None] is returned if there is no such entry.
+    ///
+    /// # Errors
+    ///
+    /// Returns [AppError] if the destination could not be queried
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError>;
+
+    /// Names of the entries of the directory at `path`,
+    /// [None] if the backend can not list its directories
+    ///
+    /// # Errors
+    ///
+    /// Returns [AppError] if the directory could not be read
+    fn read_dir(&self, _path: &Path) -> Result<Option<Vec<OsString>>, AppError> {
+        Ok(None)
+    }
+
+    /// Bytes which can still be stored at the destination,
+    /// [None] if the backend does not know
+    ///
+    /// # Errors
+    ///
+    /// Returns [AppError] if the destination could not be queried
+    fn available_space(&self) -> Result<Option<u64>, AppError> {
+        Ok(None)
+    }
+
+    /// Creates the directory at `path` with all of its parents
+    ///
+    /// # Errors
+    ///
+    /// Returns [AppError] if one of the directories could not be created
+    fn create_dir_all(&self, path: &Path) -> Result<(), AppError>;
+
+    /// Stores the local file `src` at `path` replacing the previous content.
+    /// Missing parent directories are created.
+    ///
+    /// # Errors
+    ///
+    /// Returns [AppError] if the source could not be read or the destination written
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError>;
+
+    /// [upload](SyncTarget::upload) reporting the bytes of `src` stored so
+    /// far to `progress`.
+    ///
+    /// Backends which can not follow their transfers report the whole file
+    /// once it is stored.
+    ///
+    /// # Errors
+    ///
+    /// Same as [upload](SyncTarget::upload)
+    fn upload_with_progress(&self, src: &Path, path: &Path, progress: &mut dyn FnMut(u64)) -> Result<(), AppError> {
+        self.upload(src, path)?;
+        progress(std::fs::metadata(src)?.len());
+        Ok(())
+    }
+
+    /// Removes the file or the directory with its contents at `path`, an
+    /// entry which is already gone is not an error
+    ///
+    /// # Errors
+    ///
+    /// Returns [AppError] if the entry could not be removed
+    fn remove(&self, path: &Path) -> Result<(), AppError>;
+
+    /// Renames (moves) the entry from `from` to `to`
+    ///
+    /// # Errors
+    ///
+    /// Returns [AppError] if the entry could not be renamed
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError>;
+
+    /// Brings the metadata of the stored file `path` up to the one of the
+    /// local file `src` without copying it, returns whether anything
+    /// changed, [None] if the backend can not update metadata in place
+    ///
+    /// # Errors
+    ///
+    /// Returns [AppError] if the metadata could not be read or written
+    fn sync_metadata(&self, _src: &Path, _path: &Path) -> Result<Option<bool>, AppError> {
+        Ok(None)
+    }
+
+    /// SHA-256 of the contents of the stored file `path`, [None] if the
+    /// backend can not tell it without downloading the file
+    ///
+    /// # Errors
+    ///
+    /// Returns [AppError] if the file could not be read
+    fn checksum(&self, _path: &Path) -> Result<Option<[u8; 32]>, AppError> {
+        Ok(None)
+    }
+
+    /// Gives the stored entry `path` the permissions and the owner of the
+    /// local entry `src`, or the modes of `config`, returns whether anything
+    /// changed, [None] if the backend keeps no permissions
+    ///
+    /// # Errors
+    ///
+    /// Returns [AppError] if the permissions could not be read or changed
+    fn repair_permissions(&self, _src: &Path, _path: &Path, _config: &crate::PermissionsConfig) -> Result<Option<bool>, AppError> {
+        Ok(None)
+    }
+}
+
+/// Copies `reader` to `writer` in [CHUNK_SIZE] chunks, calling `progress`
+/// with the bytes copied so far after each of them.
+///
+/// `progress` is called at least once, also for empty input.
+pub(crate) fn copy_chunked<R, W>(reader: &mut R, writer: &mut W, progress: &mut dyn FnMut(u64)) -> io::Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match reader.read(&mut buffer[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        writer.write_all(&buffer[..filled])?;
+        copied += filled as u64;
+        if filled > 0 || copied == 0 {
+            progress(copied);
+        }
+        if filled < buffer.len() {
+            return Ok(copied);
+        }
+    }
+}
+
+/// Joins the components of a relative destination path with `/`
+/// prefixing them with `prefix` (if not empty)
+pub(crate) fn object_key(prefix: &str, path: &Path) -> String {
+    let mut key = prefix.trim_matches('/').to_owned();
+    for component in path.components() {
+        if let std::path::Component::Normal(name) = component {
+            if !key.is_empty() {
+                key.push('/');
+            }
+            key.push_str(&name.to_string_lossy());
+        }
+    }
+    key
+}
+
+/// Splits `scheme:rest` destinations.
+///
+/// Single letter schemes are not accepted to keep Windows drive letters
+/// (`C:\dir`) working as local paths.
+pub(crate) fn split_scheme(destination: &Path) -> Option<(&str, &str)> {
+    let (scheme, rest) = destination.to_str()?.split_once(':')?;
+    (scheme.len() > 1 && scheme.chars().all(|c| c.is_ascii_alphanumeric())).then_some((scheme, rest))
+}
+
+/// Builds the destination backend described by the configuration.
+///
+/// Destinations in the `scheme:location` form select a remote backend,
+/// everything else is treated as a local path.
+///
+/// # Errors
+///
+/// [AppError::Backend] is returned if the scheme requires a backend which
+/// was not compiled in or is not configured, or if the encryption key is
+/// invalid
+pub fn open(config: &crate::Config) -> Result<Box<dyn SyncTarget>, AppError> {
+    let target = open_backend(config)?;
+    #[cfg(feature = "encryption")]
+    if let Some(encryption) = &config.backends.encryption {
+        return Ok(Box::new(
+            crate::encryption::EncryptedTarget::new(
+                target,
+                encryption,
+                encryption.index(config.source(), config.destination()),
+            )?,
+        ));
+    }
+    Ok(target)
+}
+
+/// Backend of the destination, before the files are encrypted
+fn open_backend(config: &crate::Config) -> Result<Box<dyn SyncTarget>, AppError> {
+    let destination = config.destination();
+
+    match split_scheme(destination) {
+        #[cfg(feature = "gdrive")]
+        Some(("gdrive", folder)) => {
+            let Some(gdrive) = config.backends.gdrive.clone() else {
+                return Err(AppError::Backend(
+                    "gdrive destination requires a [gdrive] section in the config file".into(),
+                ));
+            };
+            Ok(Box::new(GoogleDriveTarget::new(
+                gdrive,
+                &config.backends.network,
+                folder,
+            )?))
+        }
+        #[cfg(feature = "azure")]
+        Some(("azure", location)) => {
+            let Some(azure) = config.backends.azure.clone() else {
+                return Err(AppError::Backend(
+                    "azure destination requires an [azure] section in the config file".into(),
+                ));
+            };
+            Ok(Box::new(AzureBlobTarget::new(
+                azure,
+                &config.backends.network,
+                location,
+            )?))
+        }
+        #[cfg(feature = "b2")]
+        Some(("b2", location)) => {
+            let Some(b2) = config.backends.b2.clone() else {
+                return Err(AppError::Backend(
+                    "b2 destination requires a [b2] section in the config file".into(),
+                ));
+            };
+            Ok(Box::new(B2Target::new(
+                b2,
+                &config.backends.network,
+                location,
+            )?))
+        }
+        #[cfg(feature = "gcs")]
+        Some(("gs", location)) => Ok(Box::new(GcsTarget::new(
+            config.backends.gcs.clone().unwrap_or_default(),
+            &config.backends.network,
+            location,
+        )?)),
+        Some(("fwatch", location)) => {
+            let Some(peer) = config.backends.peer.clone() else {
+                return Err(AppError::Backend(
+                    "fwatch destination requires a [peer] token in the config file".into(),
+                ));
+            };
+            Ok(Box::new(PeerTarget::new(
+                peer, location,
+            )))
+        }
+        Some(("rclone", remote)) => Ok(Box::new(RcloneTarget::new(
+            config.backends.rclone.clone().unwrap_or_default(),
+            remote,
+        ))),
+        Some(("zip", archive)) => Ok(Box::new(ZipTarget::new(archive.into()))),
+        Some(("dedup", store)) => Ok(Box::new(DedupTarget::new(store.into()))),
+        #[cfg(feature = "sqlite")]
+        Some(("sqlite", database)) => Ok(Box::new(SqliteTarget::new(
+            database.into(),
+        ))),
+        #[cfg(not(feature = "gdrive"))]
+        Some(("gdrive", _)) => Err(AppError::Backend(
+            "fsync was built without the `gdrive` feature".into(),
+        )),
+        #[cfg(not(feature = "azure"))]
+        Some(("azure", _)) => Err(AppError::Backend(
+            "fsync was built without the `azure` feature".into(),
+        )),
+        #[cfg(not(feature = "b2"))]
+        Some(("b2", _)) => Err(AppError::Backend(
+            "fsync was built without the `b2` feature".into(),
+        )),
+        #[cfg(not(feature = "gcs"))]
+        Some(("gs", _)) => Err(AppError::Backend(
+            "fsync was built without the `gcs` feature".into(),
+        )),
+        #[cfg(not(feature = "sqlite"))]
+        Some(("sqlite", _)) => Err(AppError::Backend(
+            "fsync was built without the `sqlite` feature".into(),
+        )),
+        _ => {
+            let target = LocalTarget::new(destination.clone());
+            #[cfg(feature = "compression")]
+            let target = match &config.backends.compression {
+                Some(compression) => target.compressed(
+                    compression.level,
+                    compression.skip_incompressible,
+                ),
+                None => target,
+            };
+            #[cfg(feature = "parity")]
+            let target = match &config.backends.parity {
+                Some(parity) => target.protected(parity.clone()),
+                None => target,
+            };
+            Ok(Box::new(target))
+        }
+    }
+}
@@ -0,0 +1,572 @@
+//! Azure Blob Storage destination.
+//!
+//! Files are stored as block blobs below an optional prefix of a container.
+//! Without the hierarchical namespace directories only exist as common
+//! blob name prefixes, so renaming a directory means copying every blob
+//! below it. Accounts with the hierarchical namespace (ADLS Gen2) use the
+//! DFS endpoint for directories and atomic renames.
+//! Large uploads interrupted midway reuse the blocks staged so far.
+
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
+use base64::Engine;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::{
+    http::{self, AccessToken, NetworkConfig},
+    SyncTarget, TargetMetadata,
+};
+use crate::{AppError, Secret};
+
+/// REST API version sent with every request
+const API_VERSION: &str = "2021-08-06";
+/// Files up to this size are uploaded with a single Put Blob request
+const SINGLE_PUT_LIMIT: u64 = 256 * 1024 * 1024;
+/// Block size of larger uploads
+const BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+/// Instance metadata endpoint issuing managed identity tokens
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+/// Metadata header keeping the source modification time (unix seconds)
+const MTIME_HEADER: &str = "x-ms-meta-fsyncmtime";
+
+/// Authentication method
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AzureAuth {
+    /// Shared access signature appended to every request
+    SasToken(Secret),
+    /// Managed identity of the host (Azure VM, App Service, AKS)
+    Msi {
+        /// Client ID of a user-assigned identity
+        #[serde(default)]
+        client_id: Option<String>,
+    },
+}
+
+/// `[azure]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct AzureConfig {
+    /// Storage account name
+    pub(crate) account: String,
+    /// Authentication method
+    pub(crate) auth: AzureAuth,
+    /// Account has the hierarchical namespace enabled
+    #[serde(default)]
+    pub(crate) hierarchical_namespace: bool,
+    /// Blob service endpoint override, e.g. for Azurite
+    #[serde(default)]
+    pub(crate) blob_endpoint: Option<String>,
+    /// DFS endpoint override
+    #[serde(default)]
+    pub(crate) dfs_endpoint: Option<String>,
+}
+
+/// Managed identity token response
+#[derive(Debug, Deserialize)]
+struct MsiToken {
+    /// Bearer token
+    access_token: String,
+    /// Lifetime in seconds, encoded as a string
+    expires_in: String,
+}
+
+/// Container (with an optional prefix) in Azure Blob Storage
+pub struct AzureBlobTarget {
+    /// Backend configuration
+    config: AzureConfig,
+    /// Container name
+    container: String,
+    /// Blob name prefix all destination paths are stored under
+    prefix: String,
+    /// HTTP client
+    agent: http::Agent,
+    /// Managed identity token
+    token: Mutex<Option<AccessToken>>,
+}
+
+impl AzureBlobTarget {
+    /// Creates the target for `location` in the `container/prefix` form.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if the `[network]` proxy is not valid.
+    pub fn new(config: AzureConfig, network: &NetworkConfig, location: &str) -> Result<Self, AppError> {
+        let location = location.trim_matches('/');
+        let (container, prefix) = location.split_once('/').unwrap_or((location, ""));
+        Ok(Self {
+            config,
+            container: container.to_owned(),
+            prefix: prefix.to_owned(),
+            agent: http::agent(network)?,
+            token: Mutex::new(None),
+        })
+    }
+
+    /// Blob service endpoint
+    fn blob_endpoint(&self) -> String {
+        match &self.config.blob_endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_owned(),
+            None => format!(
+                "https://{}.blob.core.windows.net",
+                self.config.account
+            ),
+        }
+    }
+
+    /// DFS endpoint of the hierarchical namespace
+    fn dfs_endpoint(&self) -> String {
+        match &self.config.dfs_endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_owned(),
+            None => format!(
+                "https://{}.dfs.core.windows.net",
+                self.config.account
+            ),
+        }
+    }
+
+    /// Blob name of a destination path
+    fn blob_name(&self, path: &Path) -> String {
+        super::object_key(&self.prefix, path)
+    }
+
+    /// URL of a blob (or of the container if `name` is empty)
+    /// with the SAS token already applied
+    fn url(&self, endpoint: &str, name: &str) -> String {
+        let mut url = format!("{endpoint}/{}", self.container);
+        if !name.is_empty() {
+            url.push('/');
+            url.push_str(&http::encode_path(name));
+        }
+        if let AzureAuth::SasToken(sas) = &self.config.auth {
+            url.push('?');
+            url.push_str(sas_query(sas));
+        }
+        url
+    }
+
+    /// Locks the cached token
+    fn token(&self) -> MutexGuard<'_, Option<AccessToken>> {
+        self.token.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Managed identity token, refreshed if expired
+    fn msi_token(&self, client_id: Option<&str>) -> Result<String, AppError> {
+        let mut cached = self.token();
+        if let Some(token) = cached.as_ref().filter(|t| t.is_valid()) {
+            return Ok(token.token.clone());
+        }
+        let mut request = self
+            .agent
+            .get(IMDS_TOKEN_URL)
+            .set("Metadata", "true")
+            .query("api-version", "2018-02-01")
+            .query("resource", "https://storage.azure.com/");
+        if let Some(client_id) = client_id {
+            request = request.query("client_id", client_id);
+        }
+        let token: MsiToken = request.call()?.into_json()?;
+        let expires_in = token.expires_in.parse::<u64>().unwrap_or(0);
+        *cached = Some(AccessToken::new(
+            token.access_token.clone(),
+            expires_in,
+        ));
+        Ok(token.access_token)
+    }
+
+    /// Request with the authentication and version headers
+    fn request(&self, method: &str, url: &str) -> Result<ureq::Request, AppError> {
+        let request = self.agent.request(method, url).set("x-ms-version", API_VERSION);
+        Ok(match &self.config.auth {
+            AzureAuth::SasToken(_) => request,
+            AzureAuth::Msi { client_id } => request.set(
+                "Authorization",
+                &format!(
+                    "Bearer {}",
+                    self.msi_token(client_id.as_deref())?
+                ),
+            ),
+        })
+    }
+
+    /// Lists blob names starting with `prefix`
+    fn list(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<String>, AppError> {
+        let mut names = Vec::new();
+        let mut marker = String::new();
+        loop {
+            let mut request = self
+                .request(
+                    "GET",
+                    &self.url(&self.blob_endpoint(), ""),
+                )?
+                .query("restype", "container")
+                .query("comp", "list")
+                .query("prefix", prefix);
+            if let Some(limit) = limit {
+                request = request.query("maxresults", &limit.to_string());
+            }
+            if !marker.is_empty() {
+                request = request.query("marker", &marker);
+            }
+            let body = request.call()?.into_string()?;
+            names.extend(xml_values(&body, "Name"));
+            marker = xml_values(&body, "NextMarker").pop().unwrap_or_default();
+            if marker.is_empty() || limit.is_some_and(|limit| names.len() >= limit) {
+                return Ok(names);
+            }
+        }
+    }
+
+    /// Uploads `src` as a sequence of blocks committed with Put Block List.
+    ///
+    /// Block IDs contain the SHA-256 of the block, so blocks already staged
+    /// by an interrupted upload (uncommitted blocks are kept for a week) are
+    /// recognised and skipped.
+    fn upload_blocks(&self, src: &Path, url: &str, len: u64, mtime: u64) -> Result<(), AppError> {
+        let staged = match http::optional(
+            self.request("GET", url)?
+                .query("comp", "blocklist")
+                .query("blocklisttype", "uncommitted")
+                .call(),
+        )? {
+            Some(response) => xml_values(&response.into_string()?, "Name"),
+            None => Vec::new(),
+        };
+
+        let mut file = fs::File::open(src)?;
+        let mut block_ids = Vec::new();
+        let mut offset = 0;
+
+        while offset < len {
+            let size = BLOCK_SIZE.min(len - offset);
+            let mut hasher = Sha256::new();
+            io::copy(&mut (&mut file).take(size), &mut hasher)?;
+            let digest = crate::peer::hex(&hasher.finalize());
+            let block_id = base64::engine::general_purpose::STANDARD.encode(format!(
+                "{:08}-{}",
+                block_ids.len(),
+                &digest[..32]
+            ));
+
+            if staged.contains(&block_id) {
+                tracing::debug!(
+                    "azure: block {} of {src:?} already staged",
+                    block_ids.len()
+                );
+            } else {
+                file.seek(SeekFrom::Start(offset))?;
+                self.request("PUT", url)?
+                    .query("comp", "block")
+                    .query("blockid", &block_id)
+                    .set("Content-Length", &size.to_string())
+                    .send((&file).take(size))?;
+                tracing::debug!(
+                    "azure: block {} of {src:?} uploaded",
+                    block_ids.len()
+                );
+            }
+            block_ids.push(block_id);
+            offset += size;
+            file.seek(SeekFrom::Start(offset))?;
+        }
+
+        let mut list = String::from(r#"<?xml version="1.0" encoding="utf-8"?><BlockList>"#);
+        for id in block_ids {
+            list.push_str(&format!("<Latest>{id}</Latest>"));
+        }
+        list.push_str("</BlockList>");
+
+        self.request("PUT", url)?
+            .query("comp", "blocklist")
+            .set(MTIME_HEADER, &mtime.to_string())
+            .send_string(&list)?;
+        Ok(())
+    }
+
+    /// Server side copy of a single blob
+    fn copy_blob(&self, from: &str, to: &str) -> Result<(), AppError> {
+        let source = self.url(&self.blob_endpoint(), from);
+        let target = self.url(&self.blob_endpoint(), to);
+        let response = self
+            .request("PUT", &target)?
+            .set("x-ms-copy-source", &source)
+            .send_bytes(&[])?;
+
+        let mut status = response.header("x-ms-copy-status").unwrap_or("success").to_owned();
+        while status == "pending" {
+            std::thread::sleep(Duration::from_millis(500));
+            let response = self.request("HEAD", &target)?.call()?;
+            status = response.header("x-ms-copy-status").unwrap_or("success").to_owned();
+        }
+        match status.as_str() {
+            "success" => Ok(()),
+            other => Err(AppError::Backend(format!(
+                "copy of {from} to {to}: {other}"
+            ))),
+        }
+    }
+
+    /// Deletes a single blob, missing blobs are ignored
+    fn delete_blob(&self, name: &str) -> Result<(), AppError> {
+        http::optional(
+            self.request(
+                "DELETE",
+                &self.url(&self.blob_endpoint(), name),
+            )?
+            .call(),
+        )?;
+        Ok(())
+    }
+}
+
+impl SyncTarget for AzureBlobTarget {
+    fn describe(&self) -> String {
+        format!(
+            "azure:{}/{}/{}",
+            self.config.account, self.container, self.prefix
+        )
+    }
+
+    fn connect(&self) -> Result<(), AppError> {
+        if let AzureAuth::SasToken(sas) = &self.config.auth {
+            sas.expose()?;
+        }
+        self.list(&self.prefix, Some(1))?;
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError> {
+        let name = self.blob_name(path);
+        let directory = TargetMetadata {
+            is_dir: true,
+            len: 0,
+            modified: SystemTime::UNIX_EPOCH,
+        };
+        if path.as_os_str().is_empty() {
+            return Ok(Some(directory));
+        }
+
+        let head = http::optional(
+            self.request(
+                "HEAD",
+                &self.url(&self.blob_endpoint(), &name),
+            )?
+            .call(),
+        )?;
+        if let Some(head) = head {
+            if head.header("x-ms-meta-hdi_isfolder") == Some("true") {
+                return Ok(Some(directory));
+            }
+            let modified = match head.header(MTIME_HEADER).and_then(|m| m.parse::<u64>().ok()) {
+                Some(secs) => SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+                None => head
+                    .header("Last-Modified")
+                    .and_then(|date| httpdate::parse_http_date(date).ok())
+                    .unwrap_or(SystemTime::UNIX_EPOCH),
+            };
+            return Ok(Some(TargetMetadata {
+                is_dir: false,
+                len: head.header("Content-Length").and_then(|l| l.parse().ok()).unwrap_or(0),
+                modified,
+            }));
+        }
+
+        // Virtual directory of the flat namespace
+        Ok((!self.list(&format!("{name}/"), Some(1))?.is_empty()).then_some(directory))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), AppError> {
+        if !self.config.hierarchical_namespace || path.as_os_str().is_empty() {
+            // Directories appear together with the first blob below them
+            return Ok(());
+        }
+        self.request(
+            "PUT",
+            &self.url(
+                &self.dfs_endpoint(),
+                &self.blob_name(path),
+            ),
+        )?
+        .query("resource", "directory")
+        .send_bytes(&[])?;
+        Ok(())
+    }
+
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError> {
+        let url = self.url(
+            &self.blob_endpoint(),
+            &self.blob_name(path),
+        );
+        let meta = fs::metadata(src)?;
+        let mtime = meta
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        if meta.len() > SINGLE_PUT_LIMIT {
+            return self.upload_blocks(src, &url, meta.len(), mtime);
+        }
+
+        self.request("PUT", &url)?
+            .set("x-ms-blob-type", "BlockBlob")
+            .set(MTIME_HEADER, &mtime.to_string())
+            .set(
+                "Content-Length",
+                &meta.len().to_string(),
+            )
+            .send(fs::File::open(src)?)?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), AppError> {
+        let name = self.blob_name(path);
+        if self.config.hierarchical_namespace {
+            http::optional(
+                self.request(
+                    "DELETE",
+                    &self.url(&self.dfs_endpoint(), &name),
+                )?
+                .query("recursive", "true")
+                .call(),
+            )?;
+            return Ok(());
+        }
+        // Flat namespace: the blob, or every blob of the virtual directory
+        for blob in self.list(&format!("{name}/"), None)? {
+            self.delete_blob(&blob)?;
+        }
+        self.delete_blob(&name)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        let from = self.blob_name(from);
+        let to_path = to;
+        let to = self.blob_name(to);
+
+        if self.config.hierarchical_namespace {
+            if let Some(parent) = to_path.parent() {
+                self.create_dir_all(parent)?;
+            }
+            let mut source = format!(
+                "/{}/{}",
+                self.container,
+                http::encode_path(&from)
+            );
+            if let AzureAuth::SasToken(sas) = &self.config.auth {
+                source.push('?');
+                source.push_str(sas_query(sas));
+            }
+            self.request(
+                "PUT",
+                &self.url(&self.dfs_endpoint(), &to),
+            )?
+            .set("x-ms-rename-source", &source)
+            .send_bytes(&[])?;
+            return Ok(());
+        }
+
+        // Flat namespace: copy the blob or every blob of the virtual directory
+        let dir_prefix = format!("{from}/");
+        let mut names = self.list(&dir_prefix, None)?;
+        if names.is_empty() {
+            names.push(from.clone());
+        }
+        for name in names {
+            let new_name = format!("{to}{}", &name[from.len()..]);
+            tracing::debug!("azure: copying {name} to {new_name}");
+            self.copy_blob(&name, &new_name)?;
+            self.delete_blob(&name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Query string of the SAS token, looked up by [SyncTarget::connect]
+fn sas_query(sas: &Secret) -> &str {
+    sas.expose().unwrap_or_default().trim_start_matches('?')
+}
+
+/// Text contents of every `<tag>` element of an XML document
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        values.push(
+            rest[..end]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&"),
+        );
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_the_blobs() {
+        let config: AzureConfig = toml::from_str(
+            r#"
+            account = "acct"
+            auth = { sas_token = "?sv=2021-08-06&sig=abc" }
+            "#,
+        )
+        .unwrap();
+        let target = AzureBlobTarget::new(
+            config,
+            &NetworkConfig::default(),
+            "container/backups/",
+        )
+        .unwrap();
+        let name = target.blob_name("docs/a b.txt".as_ref());
+        assert_eq!(name, "backups/docs/a b.txt");
+        assert_eq!(
+            target.url(&target.blob_endpoint(), &name),
+            "https://acct.blob.core.windows.net/container/backups/docs/a%20b.txt?sv=2021-08-06&sig=abc"
+        );
+        assert_eq!(
+            target.url(&target.dfs_endpoint(), ""),
+            "https://acct.dfs.core.windows.net/container?sv=2021-08-06&sig=abc"
+        );
+
+        let config: AzureConfig = toml::from_str(
+            r#"
+            account = "devstoreaccount1"
+            auth = { msi = {} }
+            blob_endpoint = "http://127.0.0.1:10000/devstoreaccount1/"
+            "#,
+        )
+        .unwrap();
+        let azurite = AzureBlobTarget::new(config, &NetworkConfig::default(), "c").unwrap();
+        assert_eq!(
+            azurite.url(&azurite.blob_endpoint(), "a"),
+            "http://127.0.0.1:10000/devstoreaccount1/c/a"
+        );
+
+        let listing = "<EnumerationResults><Blobs><Blob><Name>a&amp;b.txt</Name></Blob>\
+                       <Blob><Name>&lt;c&gt;</Name></Blob></Blobs><NextMarker/></EnumerationResults>";
+        assert_eq!(
+            xml_values(listing, "Name"),
+            ["a&b.txt", "<c>"]
+        );
+        assert!(xml_values(listing, "NextMarker").is_empty());
+    }
+}
@@ -0,0 +1,696 @@
+//! Backblaze B2 destination.
+//!
+//! Uses the native B2 API. Removed files are hidden rather than deleted, so
+//! the bucket lifecycle rules decide how long old versions are kept.
+//! Files larger than [B2Config::large_file_threshold] are uploaded in parts
+//! with the large file API, interrupted uploads continue with the parts
+//! that are already stored.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::Read,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{
+    http::{self, NetworkConfig},
+    SyncTarget, TargetMetadata,
+};
+use crate::{AppError, Secret};
+
+/// Account authorization endpoint
+const AUTHORIZE_URL: &str = "https://api.backblazeb2.com/b2api/v2/b2_authorize_account";
+/// File info key B2 tools use for the source modification time
+const MTIME_INFO: &str = "src_last_modified_millis";
+/// Largest file `b2_copy_file` accepts
+const COPY_FILE_LIMIT: u64 = 5 * 1000 * 1000 * 1000;
+
+/// `[b2]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct B2Config {
+    /// Application key ID
+    pub(crate) key_id: String,
+    /// Application key
+    pub(crate) application_key: Secret,
+    /// Files above this size use the large file API
+    #[serde(default = "default_large_file_threshold")]
+    pub(crate) large_file_threshold: u64,
+}
+
+/// Default [B2Config::large_file_threshold]: 200 MB
+fn default_large_file_threshold() -> u64 {
+    200 * 1000 * 1000
+}
+
+/// Response of `b2_authorize_account`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Authorization {
+    /// Account the key belongs to
+    account_id: String,
+    /// Token for all API calls
+    authorization_token: String,
+    /// Base URL of the API calls
+    api_url: String,
+    /// Optimal part size of large files
+    recommended_part_size: u64,
+    /// Smallest allowed part size
+    absolute_minimum_part_size: u64,
+}
+
+/// Response of `b2_get_upload_url` and `b2_get_upload_part_url`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadUrl {
+    /// Where the content is posted
+    upload_url: String,
+    /// Token valid for this upload URL only
+    authorization_token: String,
+}
+
+/// File version as returned by the listing calls
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileVersion {
+    /// ID of this version
+    file_id: Option<String>,
+    /// Full file name
+    file_name: String,
+    /// Size in bytes
+    content_length: u64,
+    /// Upload time in milliseconds since the epoch
+    upload_timestamp: u64,
+    /// `upload`, `hide`, `start` or `folder`
+    action: String,
+    /// Custom file info
+    #[serde(default)]
+    file_info: HashMap<String, String>,
+}
+
+impl FileVersion {
+    /// Converts the version to [TargetMetadata], keeping the source
+    /// modification time if it was recorded
+    fn metadata(&self) -> TargetMetadata {
+        let millis = self
+            .file_info
+            .get(MTIME_INFO)
+            .and_then(|m| m.parse().ok())
+            .unwrap_or(self.upload_timestamp);
+        TargetMetadata {
+            is_dir: false,
+            len: self.content_length,
+            modified: SystemTime::UNIX_EPOCH + Duration::from_millis(millis),
+        }
+    }
+}
+
+/// Response of `b2_list_file_names`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileNames {
+    /// Matching files
+    files: Vec<FileVersion>,
+    /// Start of the next page
+    next_file_name: Option<String>,
+}
+
+/// Response of `b2_list_buckets`
+#[derive(Debug, Deserialize)]
+struct Buckets {
+    /// Matching buckets
+    buckets: Vec<Bucket>,
+}
+
+/// Bucket description
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bucket {
+    /// Bucket ID used by the API calls
+    bucket_id: String,
+}
+
+/// Response of `b2_start_large_file`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LargeFile {
+    /// ID of the unfinished file
+    file_id: String,
+    /// Full file name
+    #[serde(default)]
+    file_name: String,
+    /// Custom file info
+    #[serde(default)]
+    file_info: HashMap<String, String>,
+}
+
+/// Response of `b2_list_unfinished_large_files`
+#[derive(Debug, Deserialize)]
+struct LargeFiles {
+    /// Unfinished files
+    files: Vec<LargeFile>,
+}
+
+/// Response of `b2_list_parts`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Parts {
+    /// Uploaded parts
+    parts: Vec<Part>,
+    /// Start of the next page
+    next_part_number: Option<u64>,
+}
+
+/// Uploaded part of an unfinished large file
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Part {
+    /// 1-based part number
+    part_number: u64,
+    /// Size in bytes
+    content_length: u64,
+    /// SHA-1 of the part
+    content_sha1: String,
+}
+
+/// Established session
+#[derive(Debug, Clone)]
+struct Session {
+    /// Account authorization
+    auth: Authorization,
+    /// ID of the destination bucket
+    bucket_id: String,
+}
+
+/// Bucket (with an optional prefix) in Backblaze B2
+pub struct B2Target {
+    /// Backend configuration
+    config: B2Config,
+    /// Bucket name
+    bucket: String,
+    /// File name prefix all destination paths are stored under
+    prefix: String,
+    /// HTTP client
+    agent: http::Agent,
+    /// Session, established by [SyncTarget::connect]
+    session: Mutex<Option<Session>>,
+}
+
+impl B2Target {
+    /// Creates the target for `location` in the `bucket/prefix` form.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if the `[network]` proxy is not valid.
+    pub fn new(config: B2Config, network: &NetworkConfig, location: &str) -> Result<Self, AppError> {
+        let location = location.trim_matches('/');
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        Ok(Self {
+            config,
+            bucket: bucket.to_owned(),
+            prefix: prefix.to_owned(),
+            agent: http::agent(network)?,
+            session: Mutex::new(None),
+        })
+    }
+
+    /// Locks the session
+    fn lock(&self) -> MutexGuard<'_, Option<Session>> {
+        self.session.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Current session
+    fn session(&self) -> Result<Session, AppError> {
+        self.lock()
+            .clone()
+            .ok_or_else(|| AppError::Backend("B2 target is not connected".into()))
+    }
+
+    /// Authorizes the account and looks up the bucket
+    fn authorize(&self) -> Result<Session, AppError> {
+        use base64::Engine;
+
+        let credentials = format!(
+            "{}:{}",
+            self.config.key_id,
+            self.config.application_key.expose()?
+        );
+        let basic = base64::engine::general_purpose::STANDARD.encode(credentials);
+        let auth: Authorization = self
+            .agent
+            .get(AUTHORIZE_URL)
+            .set(
+                "Authorization",
+                &format!("Basic {basic}"),
+            )
+            .call()?
+            .into_json()?;
+        let buckets: Buckets = self
+            .agent
+            .post(&format!(
+                "{}/b2api/v2/b2_list_buckets",
+                auth.api_url
+            ))
+            .set(
+                "Authorization",
+                &auth.authorization_token,
+            )
+            .send_json(json!({ "accountId": auth.account_id, "bucketName": self.bucket }))?
+            .into_json()?;
+        let bucket_id = buckets.buckets.into_iter().next().map(|b| b.bucket_id).ok_or_else(|| {
+            AppError::Backend(format!(
+                "B2 bucket {:?} not found",
+                self.bucket
+            ))
+        })?;
+        let session = Session { auth, bucket_id };
+        *self.lock() = Some(session.clone());
+        Ok(session)
+    }
+
+    /// Calls an API operation re-authorizing once if the token expired
+    fn api(&self, operation: &str, body: serde_json::Value) -> Result<ureq::Response, AppError> {
+        let session = self.session()?;
+        let request = |session: &Session| {
+            self.agent
+                .post(&format!(
+                    "{}/b2api/v2/{operation}",
+                    session.auth.api_url
+                ))
+                .set(
+                    "Authorization",
+                    &session.auth.authorization_token,
+                )
+        };
+        match request(&session).send_json(body.clone()) {
+            Err(ureq::Error::Status(401, _)) => Ok(request(&self.authorize()?).send_json(body)?),
+            result => Ok(result?),
+        }
+    }
+
+    /// File name of a destination path
+    fn file_name(&self, path: &Path) -> String {
+        super::object_key(&self.prefix, path)
+    }
+
+    /// Lists file names starting with `prefix`
+    fn list(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<FileVersion>, AppError> {
+        let session = self.session()?;
+        let mut files = Vec::new();
+        let mut start = None;
+        loop {
+            let page: FileNames = self
+                .api(
+                    "b2_list_file_names",
+                    json!({
+                        "bucketId": session.bucket_id,
+                        "prefix": prefix,
+                        "startFileName": start,
+                        "maxFileCount": limit.unwrap_or(1000),
+                    }),
+                )?
+                .into_json()?;
+            files.extend(page.files);
+            start = page.next_file_name;
+            if start.is_none() || limit.is_some_and(|limit| files.len() >= limit) {
+                return Ok(files);
+            }
+        }
+    }
+
+    /// Latest version of the file with exactly the `name`
+    fn find(&self, name: &str) -> Result<Option<FileVersion>, AppError> {
+        Ok(self
+            .list(name, Some(1))?
+            .into_iter()
+            .find(|f| f.file_name == name && f.action == "upload"))
+    }
+
+    /// Part size used for large uploads and copies
+    fn part_size(&self, session: &Session) -> u64 {
+        session
+            .auth
+            .recommended_part_size
+            .max(session.auth.absolute_minimum_part_size)
+    }
+
+    /// Unfinished large file of an interrupted upload of the same source version.
+    ///
+    /// Unfinished uploads of other versions are cancelled.
+    fn unfinished(&self, name: &str, mtime: &str) -> Result<Option<LargeFile>, AppError> {
+        let session = self.session()?;
+        let files: LargeFiles = self
+            .api(
+                "b2_list_unfinished_large_files",
+                json!({ "bucketId": session.bucket_id, "namePrefix": name }),
+            )?
+            .into_json()?;
+
+        let mut resumable = None;
+        for file in files.files.into_iter().filter(|f| f.file_name == name) {
+            if resumable.is_none() && file.file_info.get(MTIME_INFO).map(String::as_str) == Some(mtime) {
+                resumable = Some(file);
+            } else {
+                tracing::debug!("b2: cancelling stale upload of {name}");
+                self.api(
+                    "b2_cancel_large_file",
+                    json!({ "fileId": file.file_id }),
+                )?;
+            }
+        }
+        Ok(resumable)
+    }
+
+    /// Size and SHA-1 of the parts uploaded so far, by part number
+    fn parts(&self, file_id: &str) -> Result<HashMap<u64, (u64, String)>, AppError> {
+        let mut parts = HashMap::new();
+        let mut start = 1;
+        loop {
+            let page: Parts = self
+                .api(
+                    "b2_list_parts",
+                    json!({ "fileId": file_id, "startPartNumber": start, "maxPartCount": 1000 }),
+                )?
+                .into_json()?;
+            parts.extend(page.parts.into_iter().map(|p| {
+                (
+                    p.part_number,
+                    (p.content_length, p.content_sha1),
+                )
+            }));
+            match page.next_part_number {
+                Some(next) => start = next,
+                None => return Ok(parts),
+            }
+        }
+    }
+
+    /// Uploads `src` in parts with the large file API.
+    ///
+    /// An interrupted upload of the same version is continued: parts whose
+    /// size and SHA-1 match the local content are not sent again.
+    fn upload_large(&self, src: &Path, name: &str, len: u64, mtime: u128) -> Result<(), AppError> {
+        let session = self.session()?;
+        let (large, uploaded) = match self.unfinished(name, &mtime.to_string())? {
+            Some(large) => {
+                let uploaded = self.parts(&large.file_id)?;
+                tracing::info!(
+                    "b2: resuming upload of {name}, {} parts already uploaded",
+                    uploaded.len()
+                );
+                (large, uploaded)
+            }
+            None => {
+                let large: LargeFile = self
+                    .api(
+                        "b2_start_large_file",
+                        json!({
+                            "bucketId": session.bucket_id,
+                            "fileName": name,
+                            "contentType": "b2/x-auto",
+                            "fileInfo": { MTIME_INFO: mtime.to_string() },
+                        }),
+                    )?
+                    .into_json()?;
+                (large, HashMap::new())
+            }
+        };
+
+        let upload: UploadUrl = self
+            .api(
+                "b2_get_upload_part_url",
+                json!({ "fileId": large.file_id }),
+            )?
+            .into_json()?;
+        let part_size = self.part_size(&session);
+        let mut file = fs::File::open(src)?;
+        let mut sha1_array = Vec::new();
+        let mut buffer = Vec::with_capacity(part_size as usize);
+        let mut offset = 0;
+
+        while offset < len {
+            buffer.clear();
+            (&mut file).take(part_size).read_to_end(&mut buffer)?;
+            let sha1 = sha1_smol::Sha1::from(&buffer).digest().to_string();
+            let part_number = sha1_array.len() as u64 + 1;
+            if uploaded.get(&part_number) == Some(&(buffer.len() as u64, sha1.clone())) {
+                tracing::debug!("b2: part {part_number} of {name} already uploaded");
+            } else {
+                self.agent
+                    .post(&upload.upload_url)
+                    .set(
+                        "Authorization",
+                        &upload.authorization_token,
+                    )
+                    .set(
+                        "X-Bz-Part-Number",
+                        &part_number.to_string(),
+                    )
+                    .set("X-Bz-Content-Sha1", &sha1)
+                    .send_bytes(&buffer)?;
+                tracing::debug!("b2: part {part_number} of {name} uploaded");
+            }
+            sha1_array.push(sha1);
+            offset += buffer.len() as u64;
+        }
+
+        self.api(
+            "b2_finish_large_file",
+            json!({ "fileId": large.file_id, "partSha1Array": sha1_array }),
+        )?;
+        Ok(())
+    }
+
+    /// Server side copy of a single file version to the new name
+    fn copy(&self, file: &FileVersion, name: &str) -> Result<(), AppError> {
+        let file_id = file.file_id.as_deref().unwrap_or_default();
+        if file.content_length <= COPY_FILE_LIMIT {
+            self.api(
+                "b2_copy_file",
+                json!({ "sourceFileId": file_id, "fileName": name }),
+            )?;
+            return Ok(());
+        }
+
+        // Large files are assembled from copied ranges
+        let session = self.session()?;
+        let large: LargeFile = self
+            .api(
+                "b2_start_large_file",
+                json!({
+                    "bucketId": session.bucket_id,
+                    "fileName": name,
+                    "contentType": "b2/x-auto",
+                    "fileInfo": file.file_info,
+                }),
+            )?
+            .into_json()?;
+        let part_size = self.part_size(&session);
+        let mut sha1_array = Vec::new();
+        let mut offset = 0;
+        while offset < file.content_length {
+            let end = (offset + part_size).min(file.content_length) - 1;
+            let part: serde_json::Value = self
+                .api(
+                    "b2_copy_part",
+                    json!({
+                        "sourceFileId": file_id,
+                        "largeFileId": large.file_id,
+                        "partNumber": sha1_array.len() + 1,
+                        "range": format!("bytes={offset}-{end}"),
+                    }),
+                )?
+                .into_json()?;
+            sha1_array.push(part["contentSha1"].as_str().unwrap_or_default().to_owned());
+            offset = end + 1;
+        }
+        self.api(
+            "b2_finish_large_file",
+            json!({ "fileId": large.file_id, "partSha1Array": sha1_array }),
+        )?;
+        Ok(())
+    }
+
+    /// Hides the file `name`
+    fn hide(&self, name: &str) -> Result<(), AppError> {
+        let session = self.session()?;
+        self.api(
+            "b2_hide_file",
+            json!({ "bucketId": session.bucket_id, "fileName": name }),
+        )?;
+        Ok(())
+    }
+}
+
+impl SyncTarget for B2Target {
+    fn describe(&self) -> String {
+        format!("b2:{}/{}", self.bucket, self.prefix)
+    }
+
+    fn connect(&self) -> Result<(), AppError> {
+        self.authorize()?;
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError> {
+        let directory = TargetMetadata {
+            is_dir: true,
+            len: 0,
+            modified: SystemTime::UNIX_EPOCH,
+        };
+        if path.as_os_str().is_empty() {
+            return Ok(Some(directory));
+        }
+        let name = self.file_name(path);
+        if let Some(file) = self.find(&name)? {
+            return Ok(Some(file.metadata()));
+        }
+        Ok((!self.list(&format!("{name}/"), Some(1))?.is_empty()).then_some(directory))
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> Result<(), AppError> {
+        // Folders only exist as file name prefixes
+        Ok(())
+    }
+
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError> {
+        let name = self.file_name(path);
+        let meta = fs::metadata(src)?;
+        let mtime = meta
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+
+        if meta.len() > self.config.large_file_threshold {
+            return self.upload_large(src, &name, meta.len(), mtime);
+        }
+
+        let content = fs::read(src)?;
+        let sha1 = sha1_smol::Sha1::from(&content).digest().to_string();
+        let session = self.session()?;
+        let upload: UploadUrl = self
+            .api(
+                "b2_get_upload_url",
+                json!({ "bucketId": session.bucket_id }),
+            )?
+            .into_json()?;
+        self.agent
+            .post(&upload.upload_url)
+            .set(
+                "Authorization",
+                &upload.authorization_token,
+            )
+            .set(
+                "X-Bz-File-Name",
+                &http::encode_path(&name),
+            )
+            .set("Content-Type", "b2/x-auto")
+            .set("X-Bz-Content-Sha1", &sha1)
+            .set(
+                &format!("X-Bz-Info-{MTIME_INFO}"),
+                &mtime.to_string(),
+            )
+            .send_bytes(&content)?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), AppError> {
+        let name = self.file_name(path);
+        // Folders disappear with their last file
+        for file in self
+            .list(&format!("{name}/"), None)?
+            .into_iter()
+            .filter(|f| f.action == "upload")
+        {
+            self.hide(&file.file_name)?;
+        }
+        match self.find(&name)? {
+            Some(_) => self.hide(&name),
+            None => Ok(()),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        let from = self.file_name(from);
+        let to = self.file_name(to);
+
+        let mut files = self.list(&format!("{from}/"), None)?;
+        if let Some(file) = self.find(&from)? {
+            files.push(file);
+        }
+        for file in files.into_iter().filter(|f| f.action == "upload") {
+            let new_name = format!("{to}{}", &file.file_name[from.len()..]);
+            tracing::debug!(
+                "b2: copying {} to {new_name}",
+                file.file_name
+            );
+            self.copy(&file, &new_name)?;
+            self.hide(&file.file_name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_reads_the_files() {
+        let config: B2Config = toml::from_str(
+            r#"
+            key_id = "0012"
+            application_key = "K001"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.large_file_threshold, 200_000_000);
+        let target = B2Target::new(
+            config,
+            &NetworkConfig::default(),
+            "/bucket/backups/",
+        )
+        .unwrap();
+        assert_eq!(target.bucket, "bucket");
+        assert_eq!(
+            target.file_name("docs/a.txt".as_ref()),
+            "backups/docs/a.txt"
+        );
+
+        let page: FileNames = serde_json::from_str(
+            r#"{"files":[
+                {"fileId":"4_z1","fileName":"backups/docs/a.txt","contentLength":7,"uploadTimestamp":1700000000000,
+                 "action":"upload","fileInfo":{"src_last_modified_millis":"1600000000500"}},
+                {"fileId":null,"fileName":"backups/docs/b.txt","contentLength":3,"uploadTimestamp":1700000000000,
+                 "action":"upload"}
+            ],"nextFileName":null}"#,
+        )
+        .unwrap();
+        let modified = |file: &FileVersion| file.metadata().modified.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(page.files[0].metadata().len, 7);
+        assert_eq!(
+            modified(&page.files[0]),
+            Duration::from_millis(1_600_000_000_500)
+        );
+        assert_eq!(
+            modified(&page.files[1]),
+            Duration::from_millis(1_700_000_000_000)
+        );
+
+        let session = Session {
+            auth: serde_json::from_str(
+                r#"{"accountId":"a","authorizationToken":"t","apiUrl":"https://api001.backblazeb2.com",
+                    "recommendedPartSize":100000000,"absoluteMinimumPartSize":5000000}"#,
+            )
+            .unwrap(),
+            bucket_id: "b".into(),
+        };
+        assert_eq!(target.part_size(&session), 100_000_000);
+    }
+}
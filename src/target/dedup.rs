@@ -0,0 +1,570 @@
+//! Deduplicating destination.
+//!
+//! `dedup:<dir>` destinations split every file into chunks cut where its
+//! contents match a rolling hash, so that an edit only changes the chunks
+//! around it, and store each chunk once under its SHA-256. A new version
+//! of a large file which changed slightly, a VM image of the next day,
+//! only adds its changed chunks. Every file is a manifest listing its
+//! chunks, the manifests of the versions it replaces are kept:
+//!
+//! - `chunks/<2 hex>/<SHA-256>`: contents of a chunk
+//! - `tree/<path>`: manifest of the current version of `path`
+//! - `versions/<path>/<time>.json`: manifests of the replaced and removed
+//!   versions of `path`, by the time they were replaced
+//!
+//! `fsync restore` rebuilds the files of the manifests, see [restore], and
+//! `fsync prune` removes the versions the `[retention]` rules do not keep,
+//! see [prune].
+
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{SyncTarget, TargetMetadata};
+use crate::{
+    app::Context,
+    filesystem::{Fs, RealFs},
+    paths, AppError,
+};
+
+/// Smallest chunk, but for the last one of a file
+const MIN_CHUNK: usize = 64 * 1024;
+/// Largest chunk
+const MAX_CHUNK: usize = 1024 * 1024;
+/// Bits of the rolling hash which are zero at a cut, 256 KiB chunks on
+/// average
+const AVG_BITS: u32 = 18;
+
+/// Random value of every byte for the rolling hash, splitmix64 of the byte
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut z = (i as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Reads the next chunk of `reader` into `chunk`, false at the end
+fn next_chunk(reader: &mut impl BufRead, chunk: &mut Vec<u8>) -> io::Result<bool> {
+    chunk.clear();
+    let mut hash = 0u64;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(!chunk.is_empty());
+        }
+        let mut used = buf.len();
+        let mut cut = false;
+        for (i, &byte) in buf.iter().enumerate() {
+            let len = chunk.len() + i + 1;
+            if len <= MIN_CHUNK {
+                continue;
+            }
+            // The hash only depends on the last 64 bytes
+            hash = (hash << 1).wrapping_add(GEAR[usize::from(byte)]);
+            if hash >> (64 - AVG_BITS) == 0 || len == MAX_CHUNK {
+                (used, cut) = (i + 1, true);
+                break;
+            }
+        }
+        chunk.extend_from_slice(&buf[..used]);
+        reader.consume(used);
+        if cut {
+            return Ok(true);
+        }
+    }
+}
+
+/// File stored at a dedup destination
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Manifest {
+    /// Bytes of the file
+    len: u64,
+    /// Modification time of the source, seconds since the Unix epoch
+    modified: u64,
+    /// Nanoseconds of the modification time
+    nanos: u32,
+    /// SHA-256 of the chunks, in order
+    chunks: Vec<String>,
+}
+
+impl Manifest {
+    /// Reads the manifest at `path`
+    fn read(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(
+            paths::extended(path),
+        )?)?)
+    }
+}
+
+/// Destination storing the files as deduplicated chunks
+#[derive(Debug)]
+pub struct DedupTarget {
+    /// Directory of the store
+    root: PathBuf,
+}
+
+impl DedupTarget {
+    /// Creates the target storing the files in the directory `root`.
+    /// The directory is not checked until [SyncTarget::connect] is called.
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Manifest of the current version of `path`, or its directory
+    fn tree(&self, path: &Path) -> PathBuf {
+        paths::extended(&self.root.join("tree").join(path))
+    }
+
+    /// Writes `contents` to `path` through a temporary file, so that it is
+    /// never seen written in part
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        /// Temporary files written so far, naming the next one
+        static WRITES: AtomicU64 = AtomicU64::new(0);
+
+        let tmp = paths::extended(&self.root.join("tmp").join(format!(
+            "{}-{}",
+            std::process::id(),
+            WRITES.fetch_add(1, Ordering::Relaxed)
+        )));
+        fs::write(&tmp, contents)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&tmp, path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+    }
+
+    /// Moves the current manifest of `path` to its versions
+    fn keep(&self, path: &Path) -> io::Result<()> {
+        let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let version = paths::extended(
+            &self.root.join("versions").join(path).join(format!(
+                "{}.{:09}.json",
+                since.as_secs(),
+                since.subsec_nanos()
+            )),
+        );
+        if let Some(parent) = version.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(self.tree(path), version)
+    }
+}
+
+/// Path of the chunk `hash` of the store at `root`
+fn chunk(root: &Path, hash: &str) -> PathBuf {
+    paths::extended(&root.join("chunks").join(&hash[..2]).join(hash))
+}
+
+impl SyncTarget for DedupTarget {
+    fn describe(&self) -> String {
+        format!("dedup:{:?}", self.root)
+    }
+
+    fn connect(&self) -> Result<(), AppError> {
+        // The store itself must exist, like a local destination
+        let _ = fs::read_dir(paths::extended(&self.root)).context("read directory", &self.root)?;
+        for dir in ["chunks", "tree", "versions", "tmp"] {
+            let dir = self.root.join(dir);
+            fs::create_dir_all(paths::extended(&dir)).context("create directory", &dir)?;
+        }
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError> {
+        let tree = self.tree(path);
+        let meta = match fs::metadata(&tree) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            meta => meta.context("read metadata", &tree)?,
+        };
+        if meta.is_dir() {
+            return Ok(Some(TargetMetadata {
+                is_dir: true,
+                len: 0,
+                modified: meta.modified()?,
+            }));
+        }
+        let manifest = Manifest::read(&tree).context("read", &tree)?;
+        Ok(Some(TargetMetadata {
+            is_dir: false,
+            len: manifest.len,
+            modified: UNIX_EPOCH + Duration::new(manifest.modified, manifest.nanos),
+        }))
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Option<Vec<OsString>>, AppError> {
+        let dir = self.tree(path);
+        Ok(Some(
+            RealFs.read_dir(&dir).context("read directory", &dir)?,
+        ))
+    }
+
+    fn available_space(&self) -> Result<Option<u64>, AppError> {
+        RealFs.available_space(&self.root).context("read free space of", &self.root)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), AppError> {
+        let dir = self.tree(path);
+        fs::create_dir_all(&dir).context("create directory", &dir)
+    }
+
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError> {
+        self.upload_with_progress(src, path, &mut |_| {})
+    }
+
+    fn upload_with_progress(&self, src: &Path, path: &Path, progress: &mut dyn FnMut(u64)) -> Result<(), AppError> {
+        let file = fs::File::open(paths::extended(src)).context("open", src)?;
+        let since = file.metadata()?.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut reader = io::BufReader::with_capacity(super::CHUNK_SIZE, file);
+        let mut manifest = Manifest {
+            len: 0,
+            modified: since.as_secs(),
+            nanos: since.subsec_nanos(),
+            chunks: Vec::new(),
+        };
+        let (mut contents, mut added) = (Vec::with_capacity(MAX_CHUNK), 0);
+        while next_chunk(&mut reader, &mut contents).context("read", src)? {
+            let hash = crate::peer::hex(&Sha256::digest(&contents));
+            let stored = chunk(&self.root, &hash);
+            if !reuse(&stored).context("touch", &stored)? {
+                self.write(&stored, &contents).context("write", &stored)?;
+                added += 1;
+            }
+            manifest.len += contents.len() as u64;
+            manifest.chunks.push(hash);
+            progress(manifest.len);
+        }
+        let tree = self.tree(path);
+        match Manifest::read(&tree) {
+            Ok(current) if current.chunks != manifest.chunks => self.keep(path).context("keep the version of", path)?,
+            _ => {}
+        }
+        self.write(
+            &tree,
+            &serde_json::to_vec(&manifest).map_err(io::Error::from)?,
+        )
+        .context("write", &tree)?;
+        tracing::debug!(
+            "{path:?}: {added} of {} chunks added",
+            manifest.chunks.len()
+        );
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), AppError> {
+        if path.as_os_str().is_empty() {
+            return Err(AppError::PathErr(
+                "the root of the store can not be removed".into(),
+            ));
+        }
+        let tree = self.tree(path);
+        match fs::metadata(&tree) {
+            // Removed together with its parent already
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err).context("read metadata", &tree),
+            Ok(meta) if !meta.is_dir() => self.keep(path).context("keep the version of", path),
+            Ok(_) => {
+                // The files of the directory are kept as versions
+                for entry in walkdir::WalkDir::new(&tree) {
+                    let entry = entry.map_err(io::Error::from).context("read", &tree)?;
+                    if entry.file_type().is_file() {
+                        let file = path.join(entry.path().strip_prefix(&tree)?);
+                        self.keep(&file).context("keep the version of", &file)?;
+                    }
+                }
+                fs::remove_dir_all(&tree).context("remove", &tree)
+            }
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        // The versions stay under the former path
+        let (from, to) = (self.tree(from), self.tree(to));
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent).context("create directory", parent)?;
+        }
+        fs::rename(&from, &to).context("rename", &from)
+    }
+}
+
+/// `fsync restore <manifest> <output>`: rebuilds the file of a manifest
+/// of a dedup destination, current or kept version, at the destination of
+/// `config`. A directory of manifests is rebuilt as a whole.
+///
+/// # Errors
+///
+/// [AppError::PathErr] is returned if the source is not part of a dedup
+/// destination or one of its chunks is damaged, [AppError::IoError] if it
+/// can not be read or the output written.
+pub fn restore(config: &crate::Config) -> Result<(), AppError> {
+    let (src, dst) = (config.source(), config.destination());
+    let Some(root) = src.ancestors().find(|dir| dir.join("chunks").is_dir()) else {
+        return Err(AppError::PathErr(format!(
+            "{} is not part of a dedup destination",
+            src.display()
+        )));
+    };
+    if !src.is_dir() {
+        return restore_file(root, src, dst);
+    }
+    for entry in walkdir::WalkDir::new(src) {
+        let entry = entry.map_err(io::Error::from).context("read", src)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let output = dst.join(entry.path().strip_prefix(src)?);
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent).context("create directory", parent)?;
+        }
+        restore_file(root, entry.path(), &output)?;
+    }
+    Ok(())
+}
+
+/// Chunks written or reused this recently are not removed by [prune], the
+/// manifest of an upload in progress is only written after its chunks
+const PRUNE_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Marks the stored chunk as just written, so [prune] keeps it until the
+/// manifest referring to it is written; false if it is not stored
+fn reuse(stored: &Path) -> io::Result<bool> {
+    match fs::File::options().write(true).open(paths::extended(stored)) {
+        Ok(file) => file.set_modified(SystemTime::now()).map(|()| true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Outcome of `fsync prune`
+#[derive(Debug, Clone, Default, Serialize)]
+#[non_exhaustive]
+pub struct PruneReport {
+    /// Versions the retention rules keep
+    pub kept: usize,
+    /// Versions removed
+    pub removed: usize,
+    /// Chunks no manifest referred to anymore, removed
+    pub chunks: usize,
+    /// Bytes of the removed chunks
+    pub freed: u64,
+}
+
+/// `fsync prune <dir>`: removes the versions of the dedup destination of
+/// `config` which the `[retention]` rules do not keep, then the chunks of
+/// none of the remaining manifests
+///
+/// # Errors
+///
+/// [AppError::PathErr] is returned if there is no `[retention]` section or
+/// the destination is not a dedup store, [AppError::IoError] if it can not
+/// be walked or a manifest read.
+pub fn prune(config: &crate::Config) -> Result<PruneReport, AppError> {
+    let Some(retention) = &config.retention else {
+        return Err(AppError::PathErr(
+            "fsync prune needs the [retention] rules".into(),
+        ));
+    };
+    let root = config.destination();
+    if !root.join("chunks").is_dir() {
+        return Err(AppError::PathErr(format!(
+            "{} is not a dedup destination",
+            root.display()
+        )));
+    }
+    let mut report = PruneReport::default();
+    let versions = root.join("versions");
+    let now = SystemTime::now();
+    // The versions of a file are the manifests of its directory
+    for entry in walkdir::WalkDir::new(&versions).contents_first(true) {
+        let entry = entry.map_err(io::Error::from).context("read", &versions)?;
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+        let mut kept: Vec<(PathBuf, SystemTime)> = Vec::new();
+        for version in fs::read_dir(entry.path()).context("read directory", entry.path())? {
+            let version = version.context("read directory", entry.path())?;
+            if !version.file_type().is_ok_and(|kind| kind.is_file()) {
+                continue;
+            }
+            let path = version.path();
+            // <seconds>.<nanoseconds>.json
+            let replaced = path
+                .file_name()
+                .and_then(|name| name.to_str()?.strip_suffix(".json")?.split_once('.'))
+                .and_then(|(secs, nanos)| Some(UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos.parse().ok()?)));
+            if let Some(replaced) = replaced {
+                kept.push((path, replaced));
+            }
+        }
+        let times: Vec<_> = kept.iter().map(|(_, replaced)| *replaced).collect();
+        for ((path, _), kept) in kept.iter().zip(retention.retained(&times, now)) {
+            if kept {
+                report.kept += 1;
+                continue;
+            }
+            fs::remove_file(path).context("remove", path)?;
+            tracing::info!("pruned: {}", path.display());
+            report.removed += 1;
+        }
+        // Emptied by the versions removed, or by the ones of its files
+        if entry.path() != versions {
+            let _ = fs::remove_dir(entry.path());
+        }
+    }
+
+    let mut referred = std::collections::HashSet::new();
+    for dir in [root.join("tree"), versions] {
+        for entry in walkdir::WalkDir::new(&dir) {
+            let entry = entry.map_err(io::Error::from).context("read", &dir)?;
+            if entry.file_type().is_file() {
+                referred.extend(Manifest::read(entry.path()).context("read", entry.path())?.chunks);
+            }
+        }
+    }
+    let chunks = root.join("chunks");
+    for entry in walkdir::WalkDir::new(&chunks).min_depth(2) {
+        let entry = entry.map_err(io::Error::from).context("read", &chunks)?;
+        let meta = entry
+            .metadata()
+            .map_err(io::Error::from)
+            .context("read metadata", entry.path())?;
+        let recent = meta
+            .modified()
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age < PRUNE_GRACE));
+        if !meta.is_file() || recent || entry.file_name().to_str().is_some_and(|hash| referred.contains(hash)) {
+            continue;
+        }
+        fs::remove_file(entry.path()).context("remove", entry.path())?;
+        report.chunks += 1;
+        report.freed += meta.len();
+    }
+    Ok(report)
+}
+
+/// Rebuilds the file of the manifest `src` of the store at `root` as
+/// `output`
+fn restore_file(root: &Path, src: &Path, output: &Path) -> Result<(), AppError> {
+    let manifest = Manifest::read(src).context("read", src)?;
+    let file = fs::File::create(paths::extended(output)).context("create", output)?;
+    let mut writer = io::BufWriter::new(&file);
+    for hash in &manifest.chunks {
+        let stored = chunk(root, hash);
+        let contents = fs::read(&stored).context("read", &stored)?;
+        if crate::peer::hex(&Sha256::digest(&contents)) != *hash {
+            return Err(AppError::PathErr(format!(
+                "chunk {hash} of {} is damaged",
+                src.display()
+            )));
+        }
+        writer.write_all(&contents).context("write", output)?;
+    }
+    writer.flush().context("write", output)?;
+    drop(writer);
+    file.set_modified(UNIX_EPOCH + Duration::new(manifest.modified, manifest.nanos))
+        .context("write", output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_changed_chunks_only() {
+        let dir = std::env::temp_dir().join(format!(
+            "fsync-dedup-{}",
+            std::process::id()
+        ));
+        let root = dir.join("store");
+        fs::create_dir_all(&root).unwrap();
+        // xorshift noise, 4 MiB of an image
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut image: Vec<u8> = (0..4 * 1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let src = dir.join("disk.img");
+        fs::write(&src, &image).unwrap();
+
+        let target = DedupTarget::new(root.clone());
+        target.connect().unwrap();
+        target.upload(&src, "vm/disk.img".as_ref()).unwrap();
+        let chunks = || {
+            walkdir::WalkDir::new(root.join("chunks"))
+                .into_iter()
+                .filter(|e| e.as_ref().unwrap().file_type().is_file())
+                .count()
+        };
+        let first = chunks();
+        assert!(first > 4);
+
+        // Reused chunks are put out of reach of a concurrent prune
+        let old = SystemTime::now() - 2 * PRUNE_GRACE;
+        for entry in walkdir::WalkDir::new(root.join("chunks")).min_depth(2) {
+            let file = fs::File::options().write(true).open(entry.unwrap().path()).unwrap();
+            file.set_modified(old).unwrap();
+        }
+        target.upload(&src, "vm/copy.img".as_ref()).unwrap();
+        for entry in walkdir::WalkDir::new(root.join("chunks")).min_depth(2) {
+            let modified = entry.unwrap().metadata().unwrap().modified().unwrap();
+            assert!(modified.elapsed().unwrap() < PRUNE_GRACE);
+        }
+        target.remove("vm/copy.img".as_ref()).unwrap();
+
+        // A few bytes inserted in the middle
+        image.splice(2_000_000..2_000_000, *b"changed");
+        fs::write(&src, &image).unwrap();
+        target.upload(&src, "vm/disk.img".as_ref()).unwrap();
+        assert!(chunks() <= first + 2);
+        let meta = target.metadata("vm/disk.img".as_ref()).unwrap().unwrap();
+        assert_eq!(meta.len, image.len() as u64);
+
+        let versions: Vec<_> = fs::read_dir(root.join("versions/vm/disk.img"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(versions.len(), 1);
+        let (current, former) = (
+            dir.join("current.img"),
+            dir.join("former.img"),
+        );
+        restore_file(
+            &root,
+            &root.join("tree/vm/disk.img"),
+            &current,
+        )
+        .unwrap();
+        restore_file(&root, &versions[0], &former).unwrap();
+        assert_eq!(fs::read(&current).unwrap(), image);
+        assert_eq!(
+            fs::read(&former).unwrap().len(),
+            image.len() - 7
+        );
+
+        target.remove("vm".as_ref()).unwrap();
+        assert!(target.metadata("vm/disk.img".as_ref()).unwrap().is_none());
+        assert_eq!(
+            fs::read_dir(root.join("versions/vm/disk.img")).unwrap().count(),
+            2
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,624 @@
+//! Google Drive destination.
+//!
+//! Drive addresses files by ID rather than by path: a folder may even contain
+//! several files with the same name. The target resolves destination paths
+//! component by component and caches the resulting IDs.
+//! Authorization uses the OAuth device flow, the refresh token is stored in
+//! [GoogleDriveConfig::token_file].
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{
+    http::{self, AccessToken, NetworkConfig},
+    SyncTarget, TargetMetadata,
+};
+use crate::{AppError, Secret};
+
+/// OAuth device authorization endpoint
+const DEVICE_CODE_URL: &str = "https://oauth2.googleapis.com/device/code";
+/// OAuth token endpoint
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+/// Drive metadata endpoint
+const FILES_URL: &str = "https://www.googleapis.com/drive/v3/files";
+/// Drive media upload endpoint
+const UPLOAD_URL: &str = "https://www.googleapis.com/upload/drive/v3/files";
+/// Only files created by fsync are accessible
+const SCOPE: &str = "https://www.googleapis.com/auth/drive.file";
+/// MIME type Drive uses for folders
+const FOLDER_MIME: &str = "application/vnd.google-apps.folder";
+/// Fields requested for every file resource
+const FILE_FIELDS: &str = "id,mimeType,size,modifiedTime";
+
+/// `[gdrive]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GoogleDriveConfig {
+    /// OAuth client ID of a "TVs and Limited Input devices" client
+    pub(crate) client_id: String,
+    /// OAuth client secret
+    pub(crate) client_secret: Secret,
+    /// Where the refresh token is kept between runs
+    #[serde(default = "default_token_file")]
+    pub(crate) token_file: PathBuf,
+    /// ID of the folder destination paths are resolved from
+    #[serde(default = "default_root_folder")]
+    pub(crate) root_folder_id: String,
+}
+
+/// Default [GoogleDriveConfig::token_file]
+fn default_token_file() -> PathBuf {
+    PathBuf::from("fsync-gdrive-token.json")
+}
+
+/// Default [GoogleDriveConfig::root_folder_id]: "My Drive"
+fn default_root_folder() -> String {
+    "root".into()
+}
+
+/// Writes the refresh token to `path`, readable by the user only
+fn store_token(path: &Path, token: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(0o600);
+        // The mode only applies to new files
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(token)
+}
+
+/// Persisted part of the authorization
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredToken {
+    /// Long living token used to obtain access tokens
+    refresh_token: String,
+}
+
+/// Response of the device authorization endpoint
+#[derive(Debug, Deserialize)]
+struct DeviceCode {
+    /// Code used to poll the token endpoint
+    device_code: String,
+    /// Code the user enters at [DeviceCode::verification_url]
+    user_code: String,
+    /// Page where the user authorizes the device
+    verification_url: String,
+    /// Lifetime of the codes in seconds
+    expires_in: u64,
+    /// Minimal polling interval in seconds
+    interval: u64,
+}
+
+/// Successful token endpoint response
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    /// Short living bearer token
+    access_token: String,
+    /// Lifetime of the access token in seconds
+    expires_in: u64,
+    /// Only present when the device is authorized for the first time
+    refresh_token: Option<String>,
+}
+
+/// Failed token endpoint response
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    /// OAuth error code, e.g. `authorization_pending`
+    error: String,
+}
+
+/// Drive file resource
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DriveFile {
+    /// File ID
+    id: String,
+    /// MIME type, folders use [FOLDER_MIME]
+    mime_type: String,
+    /// Size in bytes. Drive encodes int64 values as strings.
+    size: Option<String>,
+    /// RFC 3339 modification time
+    modified_time: Option<String>,
+}
+
+impl DriveFile {
+    /// Converts the resource to [TargetMetadata]
+    fn metadata(&self) -> Result<TargetMetadata, AppError> {
+        let modified = match self.modified_time.as_deref() {
+            Some(time) => humantime::parse_rfc3339_weak(time).map_err(|e| {
+                AppError::Backend(format!(
+                    "invalid modifiedTime {time:?}: {e}"
+                ))
+            })?,
+            None => SystemTime::UNIX_EPOCH,
+        };
+        Ok(TargetMetadata {
+            is_dir: self.mime_type == FOLDER_MIME,
+            len: self.size.as_deref().and_then(|s| s.parse().ok()).unwrap_or(0),
+            modified,
+        })
+    }
+}
+
+/// Response of the files listing
+#[derive(Debug, Deserialize)]
+struct FileList {
+    /// Matching files
+    files: Vec<DriveFile>,
+}
+
+/// Mutable part of the target
+#[derive(Debug, Default)]
+struct State {
+    /// Current access token
+    token: Option<AccessToken>,
+    /// Refresh token obtained via the device flow
+    refresh_token: Option<String>,
+    /// Resolved IDs by path relative to the destination root
+    ids: HashMap<PathBuf, String>,
+}
+
+/// Destination folder in Google Drive
+pub struct GoogleDriveTarget {
+    /// Backend configuration
+    config: GoogleDriveConfig,
+    /// Destination folder path relative to [GoogleDriveConfig::root_folder_id]
+    folder: PathBuf,
+    /// HTTP client
+    agent: http::Agent,
+    /// Tokens and ID cache
+    state: Mutex<State>,
+}
+
+impl GoogleDriveTarget {
+    /// Creates the target for `folder` inside the configured root folder.
+    /// Nothing is requested until [SyncTarget::connect] is called.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Backend] is returned if the `[network]` proxy is not valid.
+    pub fn new(config: GoogleDriveConfig, network: &NetworkConfig, folder: &str) -> Result<Self, AppError> {
+        Ok(Self {
+            config,
+            folder: PathBuf::from(folder.trim_matches('/')),
+            agent: http::agent(network)?,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Locks the mutable state
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Valid access token, refreshing it if necessary
+    fn access_token(&self) -> Result<String, AppError> {
+        let mut state = self.state();
+        if let Some(token) = state.token.as_ref().filter(|t| t.is_valid()) {
+            return Ok(token.token.clone());
+        }
+        let refresh_token = match state.refresh_token.clone() {
+            Some(token) => token,
+            None => {
+                return Err(AppError::Backend(
+                    "Google Drive is not authorized".into(),
+                ))
+            }
+        };
+        let response: TokenResponse = self
+            .agent
+            .post(TOKEN_URL)
+            .send_form(&[
+                (
+                    "client_id",
+                    self.config.client_id.as_str(),
+                ),
+                (
+                    "client_secret",
+                    self.config.client_secret.expose()?,
+                ),
+                ("refresh_token", refresh_token.as_str()),
+                ("grant_type", "refresh_token"),
+            ])?
+            .into_json()?;
+        state.token = Some(AccessToken::new(
+            response.access_token.clone(),
+            response.expires_in,
+        ));
+        Ok(response.access_token)
+    }
+
+    /// Authorization header value
+    fn bearer(&self) -> Result<String, AppError> {
+        Ok(format!(
+            "Bearer {}",
+            self.access_token()?
+        ))
+    }
+
+    /// Runs the OAuth device flow and stores the refresh token
+    fn authorize_device(&self) -> Result<(), AppError> {
+        let code: DeviceCode = self
+            .agent
+            .post(DEVICE_CODE_URL)
+            .send_form(&[
+                (
+                    "client_id",
+                    self.config.client_id.as_str(),
+                ),
+                ("scope", SCOPE),
+            ])?
+            .into_json()?;
+
+        tracing::warn!(
+            "Google Drive authorization required: visit {} and enter the code {}",
+            code.verification_url,
+            code.user_code
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(code.expires_in);
+        let mut interval = Duration::from_secs(code.interval.max(1));
+
+        while Instant::now() < deadline {
+            std::thread::sleep(interval);
+            let response = self.agent.post(TOKEN_URL).send_form(&[
+                (
+                    "client_id",
+                    self.config.client_id.as_str(),
+                ),
+                (
+                    "client_secret",
+                    self.config.client_secret.expose()?,
+                ),
+                ("device_code", code.device_code.as_str()),
+                (
+                    "grant_type",
+                    "urn:ietf:params:oauth:grant-type:device_code",
+                ),
+            ]);
+            match response {
+                Ok(response) => {
+                    let token: TokenResponse = response.into_json()?;
+                    let Some(refresh_token) = token.refresh_token.clone() else {
+                        return Err(AppError::Backend(
+                            "no refresh token in the authorization response".into(),
+                        ));
+                    };
+                    let stored = serde_json::to_vec(&StoredToken {
+                        refresh_token: refresh_token.clone(),
+                    })
+                    .map_err(|e| AppError::Backend(e.to_string()))?;
+                    store_token(&self.config.token_file, &stored)?;
+
+                    let mut state = self.state();
+                    state.refresh_token = Some(refresh_token);
+                    state.token = Some(AccessToken::new(
+                        token.access_token,
+                        token.expires_in,
+                    ));
+                    tracing::info!("Google Drive authorized");
+                    return Ok(());
+                }
+                Err(ureq::Error::Status(_, response)) => {
+                    let error: TokenError = response.into_json()?;
+                    match error.error.as_str() {
+                        "authorization_pending" => {}
+                        "slow_down" => interval += Duration::from_secs(5),
+                        other => {
+                            return Err(AppError::Backend(format!(
+                                "authorization failed: {other}"
+                            )))
+                        }
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Err(AppError::Backend(
+            "authorization code expired".into(),
+        ))
+    }
+
+    /// Looks up a child of the `parent` folder by name
+    fn find_child(&self, parent: &str, name: &str) -> Result<Option<DriveFile>, AppError> {
+        let name = name.replace('\\', "\\\\").replace('\'', "\\'");
+        let query = format!("'{parent}' in parents and name = '{name}' and trashed = false");
+        let list: FileList = self
+            .agent
+            .get(FILES_URL)
+            .set("Authorization", &self.bearer()?)
+            .query("q", &query)
+            .query(
+                "fields",
+                &format!("files({FILE_FIELDS})"),
+            )
+            .query("pageSize", "1")
+            .call()?
+            .into_json()?;
+        Ok(list.files.into_iter().next())
+    }
+
+    /// Creates a folder `name` inside `parent`
+    fn create_folder(&self, parent: &str, name: &str) -> Result<String, AppError> {
+        tracing::debug!("gdrive: creating folder {name:?} in {parent}");
+        let file: DriveFile = self
+            .agent
+            .post(FILES_URL)
+            .set("Authorization", &self.bearer()?)
+            .query("fields", FILE_FIELDS)
+            .send_json(json!({ "name": name, "mimeType": FOLDER_MIME, "parents": [parent] }))?
+            .into_json()?;
+        Ok(file.id)
+    }
+
+    /// Resolves `path` to the file ID walking it component by component.
+    ///
+    /// Missing folders are created if `create` is set, otherwise
+    /// [None] is returned.
+    fn resolve(&self, path: &Path, create: bool) -> Result<Option<String>, AppError> {
+        let mut current = PathBuf::new();
+        let mut id = match self.state().ids.get(&current) {
+            Some(id) => id.clone(),
+            None => {
+                return Err(AppError::Backend(
+                    "Google Drive target is not connected".into(),
+                ))
+            }
+        };
+
+        for component in path.components() {
+            let Component::Normal(name) = component else {
+                continue;
+            };
+            current.push(name);
+            if let Some(cached) = self.state().ids.get(&current) {
+                id = cached.clone();
+                continue;
+            }
+            let name = name.to_string_lossy();
+            id = match self.find_child(&id, &name)? {
+                Some(file) => file.id,
+                None if create => self.create_folder(&id, &name)?,
+                None => return Ok(None),
+            };
+            self.state().ids.insert(current.clone(), id.clone());
+        }
+
+        Ok(Some(id))
+    }
+
+    /// Resolves the parent folder of `path` creating it if necessary.
+    /// Returns the parent ID and the file name.
+    fn resolve_parent(&self, path: &Path) -> Result<(String, String), AppError> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| AppError::PathErr(path.to_string_lossy().to_string()))?
+            .to_string_lossy()
+            .to_string();
+        let parent = self
+            .resolve(
+                path.parent().unwrap_or(Path::new("")),
+                true,
+            )?
+            .ok_or_else(|| AppError::PathErr(path.to_string_lossy().to_string()))?;
+        Ok((parent, name))
+    }
+
+    /// Forgets cached IDs of `path` and everything below it
+    fn forget(&self, path: &Path) {
+        self.state()
+            .ids
+            .retain(|cached, _| !cached.starts_with(path) || cached.as_os_str().is_empty());
+    }
+}
+
+impl SyncTarget for GoogleDriveTarget {
+    fn describe(&self) -> String {
+        format!("gdrive:{}", self.folder.display())
+    }
+
+    fn connect(&self) -> Result<(), AppError> {
+        match fs::read(&self.config.token_file) {
+            Ok(stored) => {
+                let stored: StoredToken = serde_json::from_slice(&stored).map_err(|e| {
+                    AppError::Backend(format!(
+                        "{:?}: {e}",
+                        self.config.token_file
+                    ))
+                })?;
+                self.state().refresh_token = Some(stored.refresh_token);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => self.authorize_device()?,
+            Err(err) => return Err(err.into()),
+        }
+
+        // Destination folder becomes the root of all relative paths
+        self.state().ids.insert(
+            PathBuf::new(),
+            self.config.root_folder_id.clone(),
+        );
+        let folder = self.folder.clone();
+        let root = self
+            .resolve(&folder, true)?
+            .unwrap_or_else(|| self.config.root_folder_id.clone());
+        let mut state = self.state();
+        state.ids.clear();
+        state.ids.insert(PathBuf::new(), root);
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError> {
+        let Some(name) = path.file_name() else {
+            // Root is always a folder
+            return Ok(Some(TargetMetadata {
+                is_dir: true,
+                len: 0,
+                modified: SystemTime::UNIX_EPOCH,
+            }));
+        };
+        let Some(parent) = self.resolve(
+            path.parent().unwrap_or(Path::new("")),
+            false,
+        )?
+        else {
+            return Ok(None);
+        };
+        match self.find_child(&parent, &name.to_string_lossy())? {
+            Some(file) => {
+                self.state().ids.insert(path.to_path_buf(), file.id.clone());
+                Ok(Some(file.metadata()?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), AppError> {
+        self.resolve(path, true)?;
+        Ok(())
+    }
+
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let src_meta = fs::metadata(src)?;
+        let modified = humantime::format_rfc3339_millis(src_meta.modified()?).to_string();
+
+        // Resumable session: metadata first, then the content in chunks
+        let existing = self.find_child(&parent, &name)?;
+        let session = match existing {
+            Some(file) => self
+                .agent
+                .request(
+                    "PATCH",
+                    &format!("{UPLOAD_URL}/{}", file.id),
+                )
+                .query("uploadType", "resumable")
+                .set("Authorization", &self.bearer()?)
+                .set(
+                    "X-Upload-Content-Length",
+                    &src_meta.len().to_string(),
+                )
+                .send_json(json!({ "modifiedTime": modified }))?,
+            None => self
+                .agent
+                .post(UPLOAD_URL)
+                .query("uploadType", "resumable")
+                .set("Authorization", &self.bearer()?)
+                .set(
+                    "X-Upload-Content-Length",
+                    &src_meta.len().to_string(),
+                )
+                .send_json(json!({ "name": name, "parents": [parent], "modifiedTime": modified }))?,
+        };
+        let location = session
+            .header("Location")
+            .ok_or_else(|| AppError::Backend("upload session without Location header".into()))?
+            .to_owned();
+
+        // Interrupted uploads continue from the last byte Drive received
+        let location = format!(
+            "{location}&fields={}",
+            http::encode(FILE_FIELDS)
+        );
+        let file: DriveFile = http::resumable_upload(
+            &self.agent,
+            &location,
+            &fs::File::open(src)?,
+            src_meta.len(),
+        )?
+        .into_json()?;
+        self.state().ids.insert(path.to_path_buf(), file.id);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), AppError> {
+        let Some(id) = self.resolve(path, false)? else {
+            tracing::debug!("gdrive: {path:?} is already absent");
+            return Ok(());
+        };
+        // Trashed files can still be restored from the Drive UI
+        self.agent
+            .request("PATCH", &format!("{FILES_URL}/{id}"))
+            .set("Authorization", &self.bearer()?)
+            .send_json(json!({ "trashed": true }))?;
+        self.forget(path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        let id = self
+            .resolve(from, false)?
+            .ok_or_else(|| AppError::PathErr(from.to_string_lossy().to_string()))?;
+        let (old_parent, _) = self.resolve_parent(from)?;
+        let (new_parent, name) = self.resolve_parent(to)?;
+
+        let mut request = self
+            .agent
+            .request("PATCH", &format!("{FILES_URL}/{id}"))
+            .set("Authorization", &self.bearer()?);
+        if old_parent != new_parent {
+            request = request.query("addParents", &new_parent).query("removeParents", &old_parent);
+        }
+        request.send_json(json!({ "name": name }))?;
+
+        self.forget(from);
+        self.state().ids.insert(to.to_path_buf(), id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_the_token_privately_and_reads_the_files() {
+        let path = std::env::temp_dir().join(format!(
+            "fsync-gdrive-token-{}.json",
+            std::process::id()
+        ));
+        fs::write(&path, "former").unwrap();
+        store_token(&path, br#"{"refresh_token":"1//x"}"#).unwrap();
+        let stored: StoredToken = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(stored.refresh_token, "1//x");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+        fs::remove_file(path).unwrap();
+
+        let file: DriveFile =
+            serde_json::from_str(r#"{"id":"1a","mimeType":"text/plain","size":"42","modifiedTime":"2024-05-01T10:00:00.000Z"}"#)
+                .unwrap();
+        let meta = file.metadata().unwrap();
+        assert!(!meta.is_dir);
+        assert_eq!(meta.len, 42);
+        assert_eq!(
+            meta.modified,
+            humantime::parse_rfc3339("2024-05-01T10:00:00Z").unwrap()
+        );
+        let folder: DriveFile = serde_json::from_str(&format!(
+            r#"{{"id":"2b","mimeType":"{FOLDER_MIME}"}}"#
+        ))
+        .unwrap();
+        assert!(folder.metadata().unwrap().is_dir);
+    }
+}
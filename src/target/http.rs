@@ -0,0 +1,652 @@
+//! HTTP client shared by the remote backends.
+//!
+//! Requests go through an outbound proxy if one is configured in the
+//! `[network]` section or in the `HTTPS_PROXY`/`ALL_PROXY`/`HTTP_PROXY`
+//! environment variables. Hosts matching the `NO_PROXY` exceptions are
+//! contacted directly. `[network.tls]` adds trusted certificate
+//! authorities and a client certificate to the TLS connections.
+
+// Helpers are used by different subsets of the enabled backends
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::AppError;
+
+/// Timeout for establishing connections to the remote services
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Chunk size of [resumable_upload], Google requires multiples of 256 KiB
+pub(crate) const RESUMABLE_CHUNK: u64 = 32 * 256 * 1024;
+/// Interrupted uploads are resumed at most this many times
+pub(crate) const RESUME_ATTEMPTS: u32 = 5;
+
+/// `[network]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct NetworkConfig {
+    /// `http://`, `socks4://` or `socks5://` proxy URL, optionally with
+    /// `user:password@`. The proxy environment variables are used if not set.
+    #[serde(default)]
+    pub(crate) proxy: Option<String>,
+    /// Hosts contacted without the proxy: exact names, domain suffixes
+    /// (`.corp.example` or `corp.example`) or `*` for all of them.
+    /// `NO_PROXY` is used if not set.
+    #[serde(default)]
+    pub(crate) no_proxy: Option<Vec<String>>,
+    /// `[network.tls]` settings
+    #[serde(default)]
+    pub(crate) tls: TlsConfig,
+}
+
+/// `[network.tls]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TlsConfig {
+    /// PEM bundle of additional trusted certificate authorities
+    #[serde(default)]
+    pub(crate) ca_file: Option<PathBuf>,
+    /// PEM certificate chain presented to servers requiring client authentication
+    #[serde(default)]
+    pub(crate) client_cert: Option<PathBuf>,
+    /// PEM private key of [TlsConfig::client_cert]
+    #[serde(default)]
+    pub(crate) client_key: Option<PathBuf>,
+    /// Accepts any server certificate. Only meant for lab setups.
+    #[serde(default)]
+    pub(crate) insecure_skip_verify: bool,
+}
+
+impl TlsConfig {
+    /// rustls configuration, [None] if the defaults are not changed
+    fn client_config(&self) -> Result<Option<rustls::ClientConfig>, AppError> {
+        use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+        if self.ca_file.is_none() && self.client_cert.is_none() && !self.insecure_skip_verify {
+            return Ok(None);
+        }
+        let pem_error = |path: &Path, e: rustls::pki_types::pem::Error| AppError::Backend(format!("{}: {e}", path.display()));
+        let tls_error = |e: rustls::Error| AppError::Backend(format!("tls: {e}"));
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+        let builder = if self.insecure_skip_verify {
+            tracing::warn!("TLS certificates of the remote backends are not verified");
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+        } else {
+            let mut roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            if let Some(ca_file) = &self.ca_file {
+                for certificate in CertificateDer::pem_file_iter(ca_file).map_err(|e| pem_error(ca_file, e))? {
+                    roots
+                        .add(certificate.map_err(|e| pem_error(ca_file, e))?)
+                        .map_err(tls_error)?;
+                }
+            }
+            builder.with_root_certificates(roots)
+        };
+
+        let config = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let chain = CertificateDer::pem_file_iter(cert)
+                    .map_err(|e| pem_error(cert, e))?
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| pem_error(cert, e))?;
+                let key = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+                builder.with_client_auth_cert(chain, key).map_err(tls_error)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(AppError::Backend(
+                    "[network.tls] client_cert and client_key must be set together".into(),
+                ))
+            }
+        };
+        Ok(Some(config))
+    }
+
+    /// Settings for connections not made by ureq (SMTP), the default roots
+    /// if nothing is configured
+    #[cfg(feature = "email")]
+    pub(crate) fn rustls_config(&self) -> Result<Arc<rustls::ClientConfig>, AppError> {
+        if let Some(config) = self.client_config()? {
+            return Ok(Arc::new(config));
+        }
+        let roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| AppError::Backend(format!("tls: {e}")))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        Ok(Arc::new(config))
+    }
+}
+
+/// Server certificate verifier of `insecure_skip_verify`
+#[derive(Debug)]
+struct NoVerification(Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl NetworkConfig {
+    /// Proxy URL of the configuration or the environment
+    fn proxy_url(&self) -> Option<String> {
+        self.proxy.clone().or_else(|| {
+            [
+                "HTTPS_PROXY",
+                "https_proxy",
+                "ALL_PROXY",
+                "all_proxy",
+                "HTTP_PROXY",
+                "http_proxy",
+            ]
+            .iter()
+            .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+        })
+    }
+
+    /// Proxy exceptions of the configuration or the environment
+    fn no_proxy(&self) -> Vec<String> {
+        match &self.no_proxy {
+            Some(hosts) => hosts.clone(),
+            None => std::env::var("NO_PROXY")
+                .or_else(|_| std::env::var("no_proxy"))
+                .map(|hosts| {
+                    hosts
+                        .split(',')
+                        .map(|host| host.trim().to_owned())
+                        .filter(|h| !h.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// HTTP agent used for all requests of a backend, routing them through the
+/// proxy unless the host is one of the exceptions
+#[derive(Clone)]
+pub(crate) struct Agent {
+    /// Agent connecting directly
+    direct: ureq::Agent,
+    /// Agent connecting through the proxy
+    proxied: Option<ureq::Agent>,
+    /// Proxy of [Agent::proxied]
+    proxy: Option<ureq::Proxy>,
+    /// Hosts contacted directly
+    no_proxy: Vec<String>,
+    /// TLS settings replacing the defaults
+    tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl Agent {
+    /// Builds the agents with `configure` applied to the shared settings
+    fn build(
+        proxy: Option<ureq::Proxy>,
+        no_proxy: Vec<String>,
+        tls: Option<Arc<rustls::ClientConfig>>,
+        configure: impl Fn(ureq::AgentBuilder) -> ureq::AgentBuilder,
+    ) -> Self {
+        let builder = || {
+            let builder = ureq::AgentBuilder::new().timeout_connect(CONNECT_TIMEOUT);
+            configure(match &tls {
+                Some(tls) => builder.tls_config(tls.clone()),
+                None => builder,
+            })
+        };
+        Self {
+            direct: builder().build(),
+            proxied: proxy.clone().map(|proxy| builder().proxy(proxy).build()),
+            proxy,
+            no_proxy,
+            tls,
+        }
+    }
+
+    /// Same agent not following redirects
+    pub(crate) fn without_redirects(&self) -> Self {
+        Self::build(
+            self.proxy.clone(),
+            self.no_proxy.clone(),
+            self.tls.clone(),
+            |builder| builder.redirects(0),
+        )
+    }
+
+    /// Agent for the host of `url`
+    fn route(&self, url: &str) -> &ureq::Agent {
+        match &self.proxied {
+            Some(proxied) if !bypasses_proxy(&self.no_proxy, host(url)) => proxied,
+            _ => &self.direct,
+        }
+    }
+
+    /// Request with an arbitrary method
+    pub(crate) fn request(&self, method: &str, url: &str) -> ureq::Request {
+        self.route(url).request(method, url)
+    }
+
+    /// `GET` request
+    pub(crate) fn get(&self, url: &str) -> ureq::Request {
+        self.route(url).get(url)
+    }
+
+    /// `POST` request
+    pub(crate) fn post(&self, url: &str) -> ureq::Request {
+        self.route(url).post(url)
+    }
+
+    /// `PUT` request
+    pub(crate) fn put(&self, url: &str) -> ureq::Request {
+        self.route(url).put(url)
+    }
+
+    /// `DELETE` request
+    pub(crate) fn delete(&self, url: &str) -> ureq::Request {
+        self.route(url).delete(url)
+    }
+}
+
+/// Builds the HTTP agent used for all requests of a backend.
+///
+/// # Errors
+///
+/// [AppError::Backend] is returned if the proxy URL is not valid or the
+/// certificates and keys of the TLS settings could not be loaded.
+pub(crate) fn agent(network: &NetworkConfig) -> Result<Agent, AppError> {
+    let proxy = network
+        .proxy_url()
+        .map(|url| ureq::Proxy::new(&url).map_err(|e| AppError::Backend(format!("proxy {url:?}: {e}"))))
+        .transpose()?;
+    let tls = network.tls.client_config()?.map(Arc::new);
+    Ok(Agent::build(
+        proxy,
+        network.no_proxy(),
+        tls,
+        |builder| builder,
+    ))
+}
+
+/// Host name of `url` without the port
+fn host(url: &str) -> &str {
+    let authority = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = authority.split(['/', '?', '#']).next().unwrap_or_default();
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    match authority.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    }
+}
+
+/// `host` matches one of the `NO_PROXY` style exceptions
+fn bypasses_proxy(no_proxy: &[String], host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    no_proxy.iter().any(|exception| {
+        let exception = exception.trim_start_matches('*').trim_start_matches('.').to_ascii_lowercase();
+        exception.is_empty() || host == exception || host.ends_with(&format!(".{exception}"))
+    })
+}
+
+impl From<ureq::Error> for AppError {
+    fn from(value: ureq::Error) -> Self {
+        match value {
+            ureq::Error::Status(code, response) => {
+                let url = response.get_url().to_owned();
+                let body = response.into_string().unwrap_or_default();
+                AppError::Backend(format!(
+                    "{url}: HTTP {code}: {}",
+                    body.trim()
+                ))
+            }
+            ureq::Error::Transport(transport) => AppError::Backend(transport.to_string()),
+        }
+    }
+}
+
+/// Maps `404 Not Found` responses to [None]
+pub(crate) fn optional(result: Result<ureq::Response, ureq::Error>) -> Result<Option<ureq::Response>, AppError> {
+    match result {
+        Ok(response) => Ok(Some(response)),
+        Err(ureq::Error::Status(404, _)) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Failures worth resuming: transport errors, throttling and server errors
+pub(crate) fn is_transient(error: &ureq::Error) -> bool {
+    match error {
+        ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+/// Sends `len` bytes of `file` to a Google resumable upload session at `location`.
+///
+/// After an interrupted chunk the session is asked how many bytes it
+/// already has (`Content-Range: bytes */<len>`) and the upload continues
+/// from there instead of restarting. Returns the final response, which
+/// carries the created resource.
+///
+/// # Errors
+///
+/// [AppError] is returned if the session rejects the content, reports
+/// received bytes it was not sent or that do not advance with a chunk, or
+/// the upload is still failing after [RESUME_ATTEMPTS] resumptions.
+pub(crate) fn resumable_upload(agent: &Agent, location: &str, file: &fs::File, len: u64) -> Result<ureq::Response, AppError> {
+    // `308 Resume Incomplete` must not be followed
+    let agent = agent.without_redirects();
+    if len == 0 {
+        return Ok(agent.put(location).send_bytes(&[])?);
+    }
+
+    let mut offset = 0;
+    let mut attempts = 0;
+    // Every answered chunk advances the offset, leaving room for sessions
+    // storing chunks in parts
+    let max_requests = 2 * len.div_ceil(RESUMABLE_CHUNK) + RESUME_ATTEMPTS as u64;
+    for _ in 0..max_requests {
+        let size = RESUMABLE_CHUNK.min(len - offset);
+        let end = offset + size - 1;
+        let mut content = file;
+        content.seek(SeekFrom::Start(offset))?;
+        let result = agent
+            .put(location)
+            .set("Content-Length", &size.to_string())
+            .set(
+                "Content-Range",
+                &format!("bytes {offset}-{end}/{len}"),
+            )
+            .send(content.take(size));
+        let error = match result {
+            Ok(response) if response.status() == 308 => {
+                match received(&response) {
+                    Some(received) if received > offset && received < len => offset = received,
+                    received => {
+                        return Err(AppError::Backend(format!(
+                            "upload session reports {received:?} of {len} bytes after a chunk up to byte {end}"
+                        )))
+                    }
+                }
+                tracing::debug!("{offset} of {len} bytes uploaded");
+                continue;
+            }
+            Ok(response) => return Ok(response),
+            Err(err) if is_transient(&err) && attempts < RESUME_ATTEMPTS => err,
+            Err(err) => return Err(err.into()),
+        };
+
+        attempts += 1;
+        tracing::warn!(
+            "upload interrupted at {offset} of {len} bytes, resuming: {}",
+            AppError::from(error)
+        );
+        std::thread::sleep(Duration::from_secs(attempts as u64));
+        match agent
+            .put(location)
+            .set(
+                "Content-Range",
+                &format!("bytes */{len}"),
+            )
+            .send_bytes(&[])
+        {
+            // Nothing stored without a `Range`
+            Ok(response) if response.status() == 308 => match received(&response).unwrap_or(0) {
+                received if received < len => offset = received,
+                received => {
+                    return Err(AppError::Backend(format!(
+                        "upload session reports {received} of {len} bytes but is not finished"
+                    )))
+                }
+            },
+            Ok(response) => return Ok(response),
+            // Retried with the next attempt
+            Err(err) if is_transient(&err) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Err(AppError::Backend(format!(
+        "upload still incomplete at {offset} of {len} bytes after {max_requests} requests"
+    )))
+}
+
+/// Bytes stored by a resumable session according to its `Range: bytes=0-<last>` header
+fn received(response: &ureq::Response) -> Option<u64> {
+    response
+        .header("Range")
+        .and_then(|range| range.rsplit_once('-'))
+        .and_then(|(_, last)| last.trim().parse::<u64>().ok())
+        .and_then(|last| last.checked_add(1))
+}
+
+/// Percent-encodes everything except unreserved characters
+pub(crate) fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Percent-encodes an object key keeping `/` separators
+pub(crate) fn encode_path(path: &str) -> String {
+    path.split('/').map(encode).collect::<Vec<_>>().join("/")
+}
+
+/// Bearer token with its expiration
+#[derive(Debug)]
+pub(crate) struct AccessToken {
+    /// Bearer token
+    pub(crate) token: String,
+    /// Token should be refreshed after this moment
+    expires_at: Instant,
+}
+
+impl AccessToken {
+    /// Token valid for `expires_in` seconds.
+    /// It is considered expired a minute earlier.
+    pub(crate) fn new(token: String, expires_in: u64) -> Self {
+        Self {
+            token,
+            expires_at: Instant::now() + Duration::from_secs(expires_in.saturating_sub(60)),
+        }
+    }
+
+    /// Token can still be used
+    pub(crate) fn is_valid(&self) -> bool {
+        self.expires_at > Instant::now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn received_bytes_of_resumable_session() {
+        let response: ureq::Response = "HTTP/1.1 308 Resume Incomplete\r\nRange: bytes=0-8388607\r\n\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            received(&response),
+            Some(8 * 1024 * 1024)
+        );
+        let response: ureq::Response = "HTTP/1.1 308 Resume Incomplete\r\n\r\n".parse().unwrap();
+        assert_eq!(received(&response), None);
+    }
+
+    #[test]
+    fn resumable_sessions_must_advance() {
+        use std::{
+            io::{self, BufRead, Write},
+            net::TcpListener,
+        };
+
+        // Answers every request with `response`
+        let serve = |response: &'static str| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut reader = io::BufReader::new(stream.unwrap());
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                            break;
+                        }
+                        if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                    io::copy(
+                        &mut (&mut reader).take(length),
+                        &mut io::sink(),
+                    )
+                    .unwrap();
+                    reader.get_mut().write_all(response.as_bytes()).unwrap();
+                }
+            });
+            format!("http://{address}/upload")
+        };
+        let path = std::env::temp_dir().join(format!(
+            "fsync-resumable-{}",
+            std::process::id()
+        ));
+        fs::write(&path, vec![7; 1000]).unwrap();
+        let file = fs::File::open(&path).unwrap();
+        let agent = agent(&NetworkConfig::default()).unwrap();
+        for response in [
+            "HTTP/1.1 308 Resume Incomplete\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 308 Resume Incomplete\r\nRange: bytes=0-0\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 308 Resume Incomplete\r\nRange: bytes=0-4999\r\nContent-Length: 0\r\n\r\n",
+        ] {
+            let location = serve(response);
+            let err = resumable_upload(&agent, &location, &file, 1000).unwrap_err();
+            assert!(
+                err.to_string().contains("upload session reports"),
+                "{err}"
+            );
+        }
+        let location = serve("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}");
+        assert_eq!(
+            resumable_upload(&agent, &location, &file, 1000).unwrap().status(),
+            200
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn tls_settings() {
+        assert!(TlsConfig::default().client_config().unwrap().is_none());
+        let insecure = TlsConfig {
+            insecure_skip_verify: true,
+            ..TlsConfig::default()
+        };
+        assert!(insecure.client_config().unwrap().is_some());
+        let missing_key = TlsConfig {
+            client_cert: Some("client.pem".into()),
+            ..TlsConfig::default()
+        };
+        assert!(missing_key.client_config().is_err());
+        let missing_ca = TlsConfig {
+            ca_file: Some("/nonexistent/ca.pem".into()),
+            ..TlsConfig::default()
+        };
+        assert!(missing_ca.client_config().is_err());
+    }
+
+    #[test]
+    fn proxy_exceptions() {
+        let no_proxy = vec!["localhost".to_owned(), ".corp.example".to_owned(), "10.0.0.7".to_owned()];
+        assert_eq!(
+            host("https://user:pw@Storage.corp.example:8443/b/o?x=1"),
+            "Storage.corp.example"
+        );
+        assert_eq!(
+            host("http://[::1]:10000/devstoreaccount1"),
+            "::1"
+        );
+        assert!(bypasses_proxy(&no_proxy, "localhost"));
+        assert!(bypasses_proxy(
+            &no_proxy,
+            "Storage.corp.example"
+        ));
+        assert!(bypasses_proxy(
+            &no_proxy,
+            "corp.example"
+        ));
+        assert!(bypasses_proxy(
+            &no_proxy,
+            host("http://10.0.0.7:9000/")
+        ));
+        assert!(!bypasses_proxy(
+            &no_proxy,
+            "storage.googleapis.com"
+        ));
+        assert!(!bypasses_proxy(
+            &no_proxy,
+            "notcorp.example"
+        ));
+        assert!(bypasses_proxy(
+            &["*".to_owned()],
+            "storage.googleapis.com"
+        ));
+    }
+}
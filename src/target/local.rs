@@ -0,0 +1,366 @@
+//! Local filesystem destination
+
+use std::{
+    ffi::OsString,
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use super::{SyncTarget, TargetMetadata};
+use crate::{
+    app::Context,
+    filesystem::{Fs, RealFs},
+    paths, AppError,
+};
+
+/// Destination directory on a local (or mounted) filesystem
+pub struct LocalTarget {
+    /// Destination root directory
+    root: PathBuf,
+    /// Filesystem the files are stored on
+    fs: Arc<dyn Fs>,
+    /// zstd level the files are stored compressed at, with whether the
+    /// files which are compressed already are stored as they are
+    #[cfg(feature = "compression")]
+    compression: Option<(i32, bool)>,
+    /// Parity the files are protected with, in sidecars next to them
+    #[cfg(feature = "parity")]
+    parity: Option<crate::ParityConfig>,
+}
+
+impl fmt::Debug for LocalTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalTarget")
+            .field("root", &self.root)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LocalTarget {
+    /// Creates the target rooted at `root`.
+    /// The directory is not checked until [SyncTarget::connect] is called.
+    pub fn new(root: PathBuf) -> Self {
+        Self::with_fs(root, RealFs)
+    }
+
+    /// Creates the target rooted at `root` of the filesystem `fs`
+    pub fn with_fs(root: PathBuf, fs: impl Fs + 'static) -> Self {
+        Self {
+            root,
+            fs: Arc::new(fs),
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "parity")]
+            parity: None,
+        }
+    }
+
+    /// Stores the files compressed with zstd at `level`, except the ones
+    /// which are compressed already if `skip_incompressible` is set, see
+    /// `CompressionConfig`
+    #[cfg(feature = "compression")]
+    pub fn compressed(self, level: i32, skip_incompressible: bool) -> Self {
+        Self {
+            compression: Some((level, skip_incompressible)),
+            ..self
+        }
+    }
+
+    /// Stores a parity sidecar next to every file, see `ParityConfig`
+    #[cfg(feature = "parity")]
+    pub fn protected(self, parity: crate::ParityConfig) -> Self {
+        Self {
+            parity: Some(parity),
+            ..self
+        }
+    }
+
+    /// Writes the sidecar of the stored file `stored`
+    #[cfg(feature = "parity")]
+    fn protect(&self, stored: &Path, parity: &crate::ParityConfig) -> Result<(), AppError> {
+        let contents = self.fs.open(stored).context("open", stored)?;
+        let mut sidecar = crate::parity::protect(contents, parity).context("compute parity of", stored)?;
+        let path = crate::parity::sidecar(stored);
+        self.fs.copy(&mut sidecar.file, &path, &mut |_| {}).context("write", &path)
+    }
+
+    /// Destination root getter
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// `path` below the root, in the extended-length form if it is too long
+    /// for the plain Windows APIs
+    fn path(&self, path: &Path) -> PathBuf {
+        paths::extended(&self.root.join(path))
+    }
+
+    /// Path and metadata of the compressed file `path`, none if it is not
+    /// stored compressed
+    #[cfg(feature = "compression")]
+    fn stored_file(&self, path: &Path) -> std::io::Result<Option<(PathBuf, TargetMetadata)>> {
+        if self.compression.is_none() || path.as_os_str().is_empty() {
+            return Ok(None);
+        }
+        let stored = crate::compression::compressed(&self.path(path));
+        Ok(self
+            .fs
+            .metadata(&stored)?
+            .filter(|meta| !meta.is_dir)
+            .map(|meta| (stored, meta)))
+    }
+
+    /// Removes the file `path` if there is one, left from contents stored
+    /// compressed or plain before
+    #[cfg(feature = "compression")]
+    fn remove_stale(&self, path: &Path) -> Result<(), AppError> {
+        if self.fs.metadata(path)?.is_some_and(|meta| !meta.is_dir) {
+            self.fs.remove(path).context("remove", path)?;
+        }
+        Ok(())
+    }
+
+    /// Stores the contents of `src` at `path`, compressed if configured
+    fn store(&self, src: &Path, path: &Path, progress: &mut dyn FnMut(u64)) -> Result<(), AppError> {
+        let dst = self.path(path);
+        #[cfg(feature = "compression")]
+        if let Some((level, skip_incompressible)) = self.compression {
+            let stored = crate::compression::compressed(&dst);
+            if skip_incompressible
+                && crate::compression::plain_name(path)
+                && crate::compression::incompressible(&paths::extended(src)).context("read", src)?
+            {
+                tracing::debug!("storing {src:?} uncompressed");
+                self.remove_stale(&stored)?;
+                let mut source = fs::File::open(paths::extended(src)).context("open", src)?;
+                return self.fs.copy(&mut source, &dst, progress).context("write", &dst);
+            }
+            self.remove_stale(&dst)?;
+            let len = fs::metadata(paths::extended(src)).context("read metadata", src)?.len();
+            let mut compressed = crate::compression::compress(&paths::extended(src), level).context("compress", src)?;
+            let total = compressed.len.max(1);
+            // Progress in bytes of the source
+            return self
+                .fs
+                .copy(
+                    &mut compressed.file,
+                    &stored,
+                    &mut |copied| progress((u128::from(copied) * u128::from(len) / u128::from(total)) as u64),
+                )
+                .context("write", &stored);
+        }
+        let mut source = fs::File::open(paths::extended(src)).context("open", src)?;
+
+        self.fs.copy(&mut source, &dst, progress).context("write", &dst)
+    }
+}
+
+/// Rejects the root itself and paths outside of it, which are never
+/// removed or renamed
+fn below_root(path: &Path) -> Result<(), AppError> {
+    if path.as_os_str().is_empty()
+        || !path.components().all(|part| {
+            matches!(
+                part,
+                Component::Normal(_) | Component::CurDir
+            )
+        })
+    {
+        return Err(AppError::PathErr(format!(
+            "{} is not below the destination root",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+impl SyncTarget for LocalTarget {
+    fn describe(&self) -> String {
+        format!("{:?}", self.root)
+    }
+
+    fn connect(&self) -> Result<(), AppError> {
+        // Just an error propogation
+        let _ = self.fs.read_dir(&self.root).context("read directory", &self.root)?;
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError> {
+        #[cfg(feature = "compression")]
+        if let Some((stored, meta)) = self.stored_file(path)? {
+            use std::io::Read;
+
+            let mut head = Vec::new();
+            self.fs
+                .open(&stored)
+                .and_then(|file| file.take(crate::compression::HEADER_LEN).read_to_end(&mut head))
+                .context("read", &stored)?;
+            return Ok(Some(TargetMetadata {
+                len: crate::compression::content_size(&head).unwrap_or(meta.len),
+                ..meta
+            }));
+        }
+        Ok(self.fs.metadata(&self.path(path))?)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Option<Vec<OsString>>, AppError> {
+        let dir = self.path(path);
+        let names = self.fs.read_dir(&dir).context("read directory", &dir)?;
+        #[cfg(feature = "parity")]
+        let names = match self.parity {
+            Some(_) => names.into_iter().filter(|name| !crate::parity::is_sidecar(name)).collect(),
+            None => names,
+        };
+        // Compressed files are listed under the names of the source
+        #[cfg(feature = "compression")]
+        let names = match self.compression {
+            Some(_) => names
+                .into_iter()
+                .map(
+                    |name| match crate::compression::original(&name) {
+                        Some(original)
+                            if self
+                                .fs
+                                .metadata(&dir.join(&name))
+                                .is_ok_and(|meta| meta.is_some_and(|meta| !meta.is_dir)) =>
+                        {
+                            original
+                        }
+                        _ => name,
+                    },
+                )
+                .collect(),
+            None => names,
+        };
+        Ok(Some(names))
+    }
+
+    fn available_space(&self) -> Result<Option<u64>, AppError> {
+        self.fs.available_space(&self.root).context("read free space of", &self.root)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), AppError> {
+        Ok(self.fs.create_dir_all(&self.path(path))?)
+    }
+
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError> {
+        self.upload_with_progress(src, path, &mut |_| {})
+    }
+
+    fn upload_with_progress(&self, src: &Path, path: &Path, progress: &mut dyn FnMut(u64)) -> Result<(), AppError> {
+        self.store(src, path, progress)?;
+        #[cfg(feature = "parity")]
+        if let Some(parity) = &self.parity {
+            let dst = self.path(path);
+            #[cfg(feature = "compression")]
+            let dst = self
+                .stored_file(path)
+                .context("read metadata", &dst)?
+                .map_or(dst, |(stored, _)| stored);
+            self.protect(&dst, parity)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), AppError> {
+        below_root(path)?;
+        let dst = self.path(path);
+        #[cfg(feature = "compression")]
+        let dst = self
+            .stored_file(path)
+            .context("read metadata", &dst)?
+            .map_or(dst, |(stored, _)| stored);
+
+        match self.fs.remove(&dst) {
+            // Removed together with its parent already
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                tracing::debug!("already removed: {dst:?}");
+                Ok(())
+            }
+            result => result.context("remove", &dst),
+        }?;
+        #[cfg(feature = "parity")]
+        if self.parity.is_some() {
+            let sidecar = crate::parity::sidecar(&dst);
+            match self.fs.remove(&sidecar) {
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                result => result.context("remove", &sidecar)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_metadata(&self, src: &Path, path: &Path) -> Result<Option<bool>, AppError> {
+        let dst = self.path(path);
+        #[cfg(feature = "compression")]
+        let dst = self
+            .stored_file(path)
+            .context("read metadata", &dst)?
+            .map_or(dst, |(stored, _)| stored);
+        self.fs
+            .sync_metadata(&paths::extended(src), &dst)
+            .context("update metadata of", &dst)
+    }
+
+    fn repair_permissions(&self, src: &Path, path: &Path, config: &crate::PermissionsConfig) -> Result<Option<bool>, AppError> {
+        let dst = self.path(path);
+        #[cfg(feature = "compression")]
+        let dst = self
+            .stored_file(path)
+            .context("read metadata", &dst)?
+            .map_or(dst, |(stored, _)| stored);
+        self.fs
+            .repair_permissions(&paths::extended(src), &dst, config)
+            .context("repair permissions of", &dst)
+    }
+
+    fn checksum(&self, path: &Path) -> Result<Option<[u8; 32]>, AppError> {
+        // The compressed contents are not the ones of the source
+        #[cfg(feature = "compression")]
+        if self.stored_file(path).context("read metadata", path)?.is_some() {
+            return Ok(None);
+        }
+        let dst = self.path(path);
+        let file = self.fs.open(&dst).context("open", &dst)?;
+        Ok(Some(
+            crate::compare::checksum(file).context("read", &dst)?,
+        ))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        below_root(from)?;
+        below_root(to)?;
+        let (stored, renamed) = (self.path(from), self.path(to));
+        #[cfg(feature = "compression")]
+        let (stored, renamed) = match self.stored_file(from).context("read metadata", from)? {
+            Some((stored, _)) => (
+                stored,
+                crate::compression::compressed(&renamed),
+            ),
+            None => (stored, renamed),
+        };
+        self.fs.rename(&stored, &renamed)?;
+        // The sidecar follows its file
+        #[cfg(feature = "parity")]
+        if self.parity.is_some() && self.fs.metadata(&crate::parity::sidecar(&stored))?.is_some() {
+            self.fs.rename(
+                &crate::parity::sidecar(&stored),
+                &crate::parity::sidecar(&renamed),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_free_space() {
+        let target = LocalTarget::new(std::env::temp_dir());
+        assert!(target.available_space().unwrap().is_some_and(|bytes| bytes > 0));
+        assert!(LocalTarget::new("/does/not/exist".into()).available_space().is_err());
+    }
+}
@@ -0,0 +1,439 @@
+//! Remote fsync instance destination (`fwatch://host:port`).
+//!
+//! See [the protocol description](crate::peer).
+
+use std::{
+    fs,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    net::TcpStream,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use sha2::{Digest, Sha256};
+
+use super::{SyncTarget, TargetMetadata};
+use crate::{
+    delta,
+    peer::{
+        self,
+        sealed::{Role, Sealed},
+        PeerConfig, PeerTransport, Request, Response,
+    },
+    AppError,
+};
+
+/// Files at least this large resume interrupted uploads or are sent as a
+/// delta if the server has a copy
+const LARGE_FILE: u64 = 4 * 1024 * 1024;
+
+/// Bidirectional byte stream the protocol runs on
+pub(crate) trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Transport for T {}
+
+/// Directory served by `fsync serve` on another machine
+pub struct PeerTarget {
+    /// Shared secret
+    config: PeerConfig,
+    /// `host:port` of the server
+    address: String,
+    /// Authenticated connection, reestablished after failures
+    stream: Mutex<Option<Box<dyn Transport>>>,
+    /// QUIC endpoint, created by the first connection
+    #[cfg(feature = "quic")]
+    quic: std::sync::OnceLock<peer::quic::QuicClient>,
+}
+
+impl PeerTarget {
+    /// Creates the target for `location` in the `//host[:port]` form.
+    pub fn new(config: PeerConfig, location: &str) -> Self {
+        let address = location.trim_start_matches("//").trim_end_matches('/');
+        let address = match address.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => address.to_owned(),
+            _ => format!("{address}:{}", peer::DEFAULT_PORT),
+        };
+        Self {
+            config,
+            address,
+            stream: Mutex::new(None),
+            #[cfg(feature = "quic")]
+            quic: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// Locks the connection
+    fn lock(&self) -> MutexGuard<'_, Option<Box<dyn Transport>>> {
+        self.stream.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Opens a stream of the configured transport
+    fn transport(&self) -> Result<Box<dyn Transport>, AppError> {
+        match self.config.transport {
+            PeerTransport::Tcp => Ok(Box::new(TcpStream::connect(
+                &self.address,
+            )?)),
+            #[cfg(feature = "quic")]
+            PeerTransport::Quic => {
+                if self.quic.get().is_none() {
+                    // Called with the connection locked, nobody else initializes it meanwhile
+                    let _ = self.quic.set(peer::quic::QuicClient::new()?);
+                }
+                let client = self.quic.get().ok_or_else(|| AppError::Backend("quic: no endpoint".into()))?;
+                Ok(Box::new(client.open(&self.address)?))
+            }
+            #[cfg(not(feature = "quic"))]
+            PeerTransport::Quic => Err(AppError::Backend(
+                "fsync was built without the `quic` feature".into(),
+            )),
+        }
+    }
+
+    /// Opens the connection and passes the handshake
+    fn open(&self) -> Result<Box<dyn Transport>, AppError> {
+        let mut stream = self.transport()?;
+
+        peer::write_frame(
+            &mut stream,
+            &Request::Hello {
+                version: peer::PROTOCOL_VERSION,
+            },
+        )?;
+        let nonce = match peer::read_frame(&mut stream)? {
+            Response::Challenge { nonce } => nonce,
+            Response::Error { message } => {
+                return Err(AppError::Backend(format!(
+                    "{}: {message}",
+                    self.address
+                )))
+            }
+            other => {
+                return Err(AppError::Backend(format!(
+                    "{}: unexpected {other:?}",
+                    self.address
+                )))
+            }
+        };
+        let token = self.config.token.expose()?;
+        let client_nonce = peer::random_nonce()?;
+        peer::write_frame(
+            &mut stream,
+            &Request::Auth {
+                mac: peer::sign(token, &nonce),
+                nonce: client_nonce.clone(),
+            },
+        )?;
+        match peer::read_frame(&mut stream)? {
+            // A server without the token can't open or seal the following frames
+            Response::Ok => Ok(Box::new(Sealed::new(
+                stream,
+                Role::Client,
+                token,
+                &nonce,
+                &client_nonce,
+            ))),
+            Response::Error { message } => Err(AppError::Backend(format!(
+                "{}: {message}",
+                self.address
+            ))),
+            other => Err(AppError::Backend(format!(
+                "{}: unexpected {other:?}",
+                self.address
+            ))),
+        }
+    }
+
+    /// Sends `request` followed by whatever `body` writes and waits for the response.
+    ///
+    /// The connection is dropped on transport errors and reopened by the next call.
+    fn call<F>(&self, request: &Request, body: F) -> Result<Response, AppError>
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()>,
+    {
+        let mut guard = self.lock();
+        let stream = match guard.as_mut() {
+            Some(stream) => stream,
+            None => guard.insert(self.open()?),
+        };
+
+        let result = (|| -> io::Result<Response> {
+            peer::write_frame(stream, request)?;
+            body(stream)?;
+            stream.flush()?;
+            peer::read_frame(stream)
+        })();
+
+        match result {
+            Ok(Response::Error { message }) => Err(AppError::Backend(format!(
+                "{}: {message}",
+                self.address
+            ))),
+            Ok(response) => Ok(response),
+            Err(err) => {
+                *guard = None;
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Sends a request expecting [Response::Ok]
+    fn call_ok(&self, request: &Request) -> Result<(), AppError> {
+        self.expect_ok(self.call(request, |_| Ok(()))?)
+    }
+
+    /// Checks for [Response::Ok]
+    fn expect_ok(&self, response: Response) -> Result<(), AppError> {
+        match response {
+            Response::Ok => Ok(()),
+            other => Err(AppError::Backend(format!(
+                "{}: unexpected {other:?}",
+                self.address
+            ))),
+        }
+    }
+
+    /// Resume handshake: bytes of an interrupted upload of `path` the server
+    /// already has, zero if they don't match the beginning of `file`
+    fn resume_offset(&self, file: &fs::File, path: &Path, len: u64) -> Result<u64, AppError> {
+        let (offset, hash) = match self.call(
+            &Request::Partial { path: wire_path(path) },
+            |_| Ok(()),
+        )? {
+            Response::Partial { offset, hash } => (offset, hash),
+            other => {
+                return Err(AppError::Backend(format!(
+                    "{}: unexpected {other:?}",
+                    self.address
+                )))
+            }
+        };
+        if offset == 0 || offset >= len {
+            return Ok(0);
+        }
+
+        let mut hasher = Sha256::new();
+        io::copy(
+            &mut BufReader::new(file).take(offset),
+            &mut hasher,
+        )?;
+        let matches = peer::hex(&hasher.finalize()) == hash;
+        if !matches {
+            tracing::debug!("partial upload of {path:?} differs from the source, restarting");
+        }
+        Ok(if matches { offset } else { 0 })
+    }
+
+    /// Sends `src` as a delta against the server copy of `path`
+    fn upload_delta(&self, src: &Path, path: &Path, len: u64, mtime: u64) -> Result<(), AppError> {
+        let path = wire_path(path);
+        let signature = match self.call(
+            &Request::Signature { path: path.clone() },
+            |_| Ok(()),
+        )? {
+            Response::Signature { signature } => signature,
+            other => {
+                return Err(AppError::Backend(format!(
+                    "{}: unexpected {other:?}",
+                    self.address
+                )))
+            }
+        };
+
+        let file = fs::File::open(src)?;
+        let request = Request::Delta {
+            path,
+            block_size: signature.block_size,
+            mtime,
+        };
+        let response = self.call(&request, |stream| {
+            let mut out = BufWriter::new(stream);
+            let matched = delta::encode(
+                &signature,
+                BufReader::new(file.take(len)),
+                &mut out,
+            )?;
+            tracing::debug!("delta {src:?}: {matched} of {len} bytes reused");
+            out.flush()
+        })?;
+        self.expect_ok(response)
+    }
+}
+
+/// `/` separated wire path
+fn wire_path(path: &Path) -> String {
+    super::object_key("", path)
+}
+
+impl SyncTarget for PeerTarget {
+    fn describe(&self) -> String {
+        format!("fwatch://{}", self.address)
+    }
+
+    fn connect(&self) -> Result<(), AppError> {
+        let stream = self.open()?;
+        *self.lock() = Some(stream);
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError> {
+        match self.call(
+            &Request::Metadata { path: wire_path(path) },
+            |_| Ok(()),
+        )? {
+            Response::Metadata { entry } => Ok(entry.map(TargetMetadata::from)),
+            other => Err(AppError::Backend(format!(
+                "{}: unexpected {other:?}",
+                self.address
+            ))),
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), AppError> {
+        self.call_ok(&Request::CreateDir { path: wire_path(path) })
+    }
+
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError> {
+        self.upload_with_progress(src, path, &mut |_| {})
+    }
+
+    fn upload_with_progress(&self, src: &Path, path: &Path, progress: &mut dyn FnMut(u64)) -> Result<(), AppError> {
+        let file = fs::File::open(src)?;
+        let meta = file.metadata()?;
+        let len = meta.len();
+        let mtime = peer::unix_secs(meta.modified()?);
+
+        let offset = if len >= LARGE_FILE {
+            self.resume_offset(&file, path, len)?
+        } else {
+            0
+        };
+        if offset > 0 {
+            tracing::info!("resuming upload of {src:?} at {offset} of {len} bytes");
+        } else if len >= LARGE_FILE {
+            match self.metadata(path)? {
+                Some(remote) if !remote.is_dir && remote.len >= LARGE_FILE => match self.upload_delta(src, path, len, mtime) {
+                    Ok(()) => {
+                        progress(len);
+                        return Ok(());
+                    }
+                    Err(err) => tracing::warn!("delta transfer of {src:?} failed, sending the whole file: {err}"),
+                },
+                _ => {}
+            }
+        }
+
+        // Exactly `len - offset` bytes must follow the request, even if the file grows meanwhile
+        let request = Request::Upload {
+            path: wire_path(path),
+            len,
+            mtime,
+            offset,
+        };
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        let response = self.call(&request, |stream| {
+            let sent = super::copy_chunked(
+                &mut file.take(len - offset),
+                stream,
+                &mut |sent| progress(offset + sent),
+            )?;
+            if sent != len - offset {
+                // File shrank: the server still waits for the rest of the content
+                return Err(io::Error::other(format!(
+                    "{src:?} changed during the upload"
+                )));
+            }
+            Ok(())
+        })?;
+        self.expect_ok(response)
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), AppError> {
+        self.call_ok(&Request::Remove { path: wire_path(path) })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        self.call_ok(&Request::Rename {
+            from: wire_path(from),
+            to: wire_path(to),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::Arc};
+
+    use super::*;
+    use crate::{target::LocalTarget, testing::TempDir};
+
+    /// Client authenticating with `token` of a server of `root` with the
+    /// token `secret`, listening on a free loopback port
+    fn served(root: &Path, token: &str) -> PeerTarget {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let target = Arc::new(LocalTarget::new(root.to_path_buf()));
+        std::thread::spawn(move || {
+            peer::serve_tcp(
+                listener,
+                target,
+                Arc::new("secret".into()),
+            )
+        });
+        let config = toml::from_str(&format!("token = {token:?}")).unwrap();
+        PeerTarget::new(config, &format!("//{address}"))
+    }
+
+    #[test]
+    fn changes_reach_the_server() {
+        let (source, root) = (
+            TempDir::new("peer-src"),
+            TempDir::new("peer-root"),
+        );
+        fs::write(source.join("a.txt"), "a").unwrap();
+        let target = served(&root, "secret");
+        target.connect().unwrap();
+
+        target.create_dir_all(Path::new("docs")).unwrap();
+        target
+            .upload(
+                &source.join("a.txt"),
+                Path::new("docs/a.txt"),
+            )
+            .unwrap();
+        assert_eq!(
+            fs::read(root.join("docs/a.txt")).unwrap(),
+            b"a"
+        );
+        let stored = target.metadata(Path::new("docs/a.txt")).unwrap().unwrap();
+        assert_eq!((stored.is_dir, stored.len), (false, 1));
+
+        target
+            .rename(
+                Path::new("docs/a.txt"),
+                Path::new("docs/b.txt"),
+            )
+            .unwrap();
+        assert!(!root.join("docs/a.txt").exists());
+        assert_eq!(
+            fs::read(root.join("docs/b.txt")).unwrap(),
+            b"a"
+        );
+
+        target.remove(Path::new("docs")).unwrap();
+        assert!(!root.join("docs").exists());
+        assert!(target.metadata(Path::new("docs")).unwrap().is_none());
+    }
+
+    #[test]
+    fn wrong_tokens_are_rejected() {
+        let root = TempDir::new("peer-root");
+        let target = served(&root, "guess");
+        assert!(matches!(
+            target.connect(),
+            Err(AppError::Backend(_))
+        ));
+        assert!(target.create_dir_all(Path::new("docs")).is_err());
+        assert!(!root.join("docs").exists());
+    }
+}
@@ -0,0 +1,301 @@
+//! rclone delegation destination.
+//!
+//! fsync keeps detecting changes and deciding what to do, while every
+//! transfer is delegated to the `rclone` binary. Any remote configured in
+//! rclone (`rclone config`) can be used as a destination.
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    time::SystemTime,
+};
+
+use serde::Deserialize;
+
+use super::{SyncTarget, TargetMetadata};
+use crate::AppError;
+
+/// `[rclone]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct RcloneConfig {
+    /// rclone executable
+    #[serde(default = "default_binary")]
+    pub(crate) binary: PathBuf,
+    /// Extra flags passed to every invocation, e.g. `["--config", "rclone.conf"]`
+    #[serde(default)]
+    pub(crate) flags: Vec<String>,
+}
+
+impl Default for RcloneConfig {
+    fn default() -> Self {
+        Self {
+            binary: default_binary(),
+            flags: Vec::new(),
+        }
+    }
+}
+
+/// Default [RcloneConfig::binary]: `rclone` from `PATH`
+fn default_binary() -> PathBuf {
+    PathBuf::from("rclone")
+}
+
+/// Entry printed by `rclone lsjson --stat`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListEntry {
+    /// Size in bytes, `-1` for directories
+    size: i64,
+    /// RFC 3339 modification time
+    mod_time: String,
+    /// Entry is a directory
+    is_dir: bool,
+}
+
+/// Remote path handled by rclone
+pub struct RcloneTarget {
+    /// Backend configuration
+    config: RcloneConfig,
+    /// Destination root in the rclone `remote:path` form
+    remote: String,
+}
+
+impl RcloneTarget {
+    /// Creates the target for `remote` in the rclone `remote:path` form.
+    pub fn new(config: RcloneConfig, remote: &str) -> Self {
+        Self {
+            config,
+            remote: remote.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    /// rclone location of a destination path
+    fn location(&self, path: &Path) -> String {
+        let path = super::object_key("", path);
+        match (
+            self.remote.ends_with(':'),
+            path.is_empty(),
+        ) {
+            (_, true) => self.remote.clone(),
+            (true, false) => format!("{}{path}", self.remote),
+            (false, false) => format!("{}/{path}", self.remote),
+        }
+    }
+
+    /// rclone invocation with `args` and the configured flags
+    fn command<I, S>(&self, args: I) -> Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut command = Command::new(&self.config.binary);
+        command.args(args).args(&self.config.flags);
+        command
+    }
+
+    /// Runs rclone with the configured flags
+    fn run<I, S>(&self, args: I) -> Result<Output, AppError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut command = self.command(args);
+        tracing::trace!("rclone: {command:?}");
+        Ok(command.output()?)
+    }
+
+    /// Runs rclone and turns a non-zero exit status into an error
+    fn check<I, S>(&self, args: I) -> Result<Output, AppError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let output = self.run(args)?;
+        if !output.status.success() {
+            return Err(AppError::Backend(format!(
+                "rclone {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(output)
+    }
+}
+
+impl SyncTarget for RcloneTarget {
+    fn describe(&self) -> String {
+        format!("rclone:{}", self.remote)
+    }
+
+    fn connect(&self) -> Result<(), AppError> {
+        let version = self.check(["version"])?;
+        tracing::debug!(
+            "{}",
+            String::from_utf8_lossy(&version.stdout).lines().next().unwrap_or_default()
+        );
+        self.check(["mkdir", &self.remote])?;
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError> {
+        let output = self.run(["lsjson", "--stat", &self.location(path)])?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.contains("not found") {
+                return Ok(None);
+            }
+            return Err(AppError::Backend(format!(
+                "rclone {}: {}",
+                output.status,
+                stderr.trim()
+            )));
+        }
+        let entry: ListEntry =
+            serde_json::from_slice(&output.stdout).map_err(|e| AppError::Backend(format!("rclone lsjson: {e}")))?;
+        Ok(Some(TargetMetadata {
+            is_dir: entry.is_dir,
+            len: entry.size.max(0) as u64,
+            modified: humantime::parse_rfc3339_weak(&entry.mod_time).unwrap_or(SystemTime::UNIX_EPOCH),
+        }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), AppError> {
+        self.check(["mkdir", &self.location(path)])?;
+        Ok(())
+    }
+
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError> {
+        let location = self.location(path);
+        self.check([OsStr::new("copyto"), src.as_os_str(), OsStr::new(&location)])?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), AppError> {
+        let location = self.location(path);
+        match self.metadata(path)? {
+            Some(meta) if meta.is_dir => self.check(["purge", &location])?,
+            Some(_) => self.check(["deletefile", &location])?,
+            None => return Ok(()),
+        };
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        let from = self.location(from);
+        let to = self.location(to);
+        self.check(["moveto", &from, &to])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    /// Target of `remote` without flags
+    fn target(remote: &str) -> RcloneTarget {
+        RcloneTarget::new(RcloneConfig::default(), remote)
+    }
+
+    #[test]
+    fn rclone_destinations_are_parsed() {
+        let config = Config::build(
+            ".".into(),
+            "rclone:backup:photos/2024/".into(),
+        );
+        let target = crate::target::open(&config).unwrap();
+        assert_eq!(
+            target.describe(),
+            "rclone:backup:photos/2024"
+        );
+    }
+
+    #[test]
+    fn locations_join_the_remote() {
+        let path = Path::new("albums/a.jpg");
+        assert_eq!(
+            target("backup:").location(path),
+            "backup:albums/a.jpg"
+        );
+        assert_eq!(
+            target("backup:photos/").location(path),
+            "backup:photos/albums/a.jpg"
+        );
+        assert_eq!(
+            target("backup:photos").location(Path::new("")),
+            "backup:photos"
+        );
+    }
+
+    #[test]
+    fn flags_follow_the_arguments() {
+        let config = RcloneConfig {
+            binary: "/opt/rclone".into(),
+            flags: vec!["--config".into(), "rclone.conf".into()],
+        };
+        let target = RcloneTarget::new(config, "backup:");
+        let command = target.command(["moveto", "backup:a", "backup:b"]);
+        assert_eq!(command.get_program(), "/opt/rclone");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["moveto", "backup:a", "backup:b", "--config", "rclone.conf"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn transfers_are_delegated() {
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        let dir = crate::testing::TempDir::new("rclone");
+        let (binary, log) = (dir.join("rclone"), dir.join("calls"));
+        // Records its arguments and fails the unknown commands
+        fs::write(
+            &binary,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> '{}'\ncase $1 in copyto|moveto|mkdir) ;; *) exit 1 ;; esac\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(
+            &binary,
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        // Busy while processes forked by other tests still hold it open
+        while let Err(err) = Command::new(&binary).arg("mkdir").status() {
+            assert_eq!(
+                err.kind(),
+                std::io::ErrorKind::ExecutableFileBusy
+            );
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        fs::remove_file(&log).unwrap();
+        let config = RcloneConfig {
+            binary,
+            flags: vec!["-q".into()],
+        };
+        let target = RcloneTarget::new(config, "backup:photos");
+
+        target
+            .upload(
+                Path::new("/src/a.jpg"),
+                Path::new("a.jpg"),
+            )
+            .unwrap();
+        target.rename(Path::new("a.jpg"), Path::new("b.jpg")).unwrap();
+        assert!(matches!(
+            target.check(["about"]),
+            Err(AppError::Backend(_))
+        ));
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "copyto /src/a.jpg backup:photos/a.jpg -q\n\
+             moveto backup:photos/a.jpg backup:photos/b.jpg -q\n\
+             about -q\n"
+        );
+    }
+}
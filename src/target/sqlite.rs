@@ -0,0 +1,350 @@
+//! SQLite database destination.
+//!
+//! `sqlite:<file>` destinations store the whole tree in one SQLite
+//! database, created if missing, to be shipped as a single file and
+//! queried with SQL. Every file and directory is a row of the `entries`
+//! table:
+//!
+//! | column     | contents                                                  |
+//! |------------|-----------------------------------------------------------|
+//! | `path`     | path below the destination root, `/`-separated            |
+//! | `parent`   | path of the parent directory, empty for the root          |
+//! | `is_dir`   | 1 for directories                                         |
+//! | `len`      | bytes of the file                                         |
+//! | `modified` | modification time of the source, nanoseconds since 1970   |
+//! | `contents` | contents of the file, `NULL` for directories              |
+//!
+//! ```sql
+//! SELECT path, len, datetime(modified / 1000000000, 'unixepoch') FROM entries WHERE NOT is_dir;
+//! ```
+//!
+//! Contents are written and read in chunks, files above [MAX_LEN] can not
+//! be stored in a SQLite value.
+
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{blob::ZeroBlob, params, Connection, OptionalExtension};
+
+use super::{SyncTarget, TargetMetadata};
+use crate::{
+    app::Context,
+    filesystem::{Fs, RealFs},
+    paths, AppError,
+};
+
+/// Largest file SQLite stores in one value by default
+const MAX_LEN: u64 = 1_000_000_000;
+
+/// Tables of the database
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS entries (
+        path TEXT PRIMARY KEY NOT NULL,
+        parent TEXT NOT NULL,
+        is_dir INTEGER NOT NULL,
+        len INTEGER NOT NULL,
+        modified INTEGER NOT NULL,
+        contents BLOB
+    );
+    CREATE INDEX IF NOT EXISTS entries_parent ON entries (parent);
+";
+
+impl From<rusqlite::Error> for AppError {
+    fn from(err: rusqlite::Error) -> Self {
+        AppError::Backend(format!("sqlite: {err}"))
+    }
+}
+
+/// Key of the entry `path`, its components joined with `/`
+fn key(path: &Path) -> Result<String, AppError> {
+    Ok(path
+        .components()
+        .filter_map(|part| match part {
+            Component::Normal(part) => Some(part.to_str()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            AppError::PathErr(format!(
+                "{} is not valid UTF-8",
+                path.display()
+            ))
+        })?
+        .join("/"))
+}
+
+/// Key of the parent of the entry `key`, empty below the root
+fn parent(key: &str) -> &str {
+    key.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// Nanoseconds of `time` since the Unix epoch
+fn nanos(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| {
+        i64::try_from(since.as_nanos()).unwrap_or(i64::MAX)
+    })
+}
+
+/// Condition of the rows of `?1` and the entries below it
+const BELOW: &str = "(path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/')";
+
+/// Destination storing the tree in a SQLite database
+pub struct SqliteTarget {
+    /// Path of the database
+    path: PathBuf,
+    /// The database once connected
+    connection: Mutex<Option<Connection>>,
+}
+
+impl std::fmt::Debug for SqliteTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteTarget")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SqliteTarget {
+    /// Creates the target storing the files in the database `path`.
+    /// The database is not opened until [SyncTarget::connect] is called.
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            connection: Mutex::default(),
+        }
+    }
+
+    /// Runs `f` on the database, opening it on first use
+    fn with<T>(&self, f: impl FnOnce(&mut Connection) -> Result<T, AppError>) -> Result<T, AppError> {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        if connection.is_none() {
+            let opened = Connection::open(paths::extended(&self.path))?;
+            opened.execute_batch(SCHEMA)?;
+            *connection = Some(opened);
+        }
+        f(connection.as_mut().expect("opened above"))
+    }
+}
+
+/// Adds the missing directories `key` and above
+fn add_dirs(connection: &Connection, key: &str) -> Result<(), AppError> {
+    let mut insert = connection
+        .prepare_cached("INSERT OR IGNORE INTO entries (path, parent, is_dir, len, modified) VALUES (?1, ?2, 1, 0, ?3)")?;
+    let now = nanos(SystemTime::now());
+    let mut dir = key;
+    while !dir.is_empty() {
+        insert.execute(params![dir, parent(dir), now])?;
+        dir = parent(dir);
+    }
+    Ok(())
+}
+
+impl SyncTarget for SqliteTarget {
+    fn describe(&self) -> String {
+        format!("sqlite:{:?}", self.path)
+    }
+
+    fn connect(&self) -> Result<(), AppError> {
+        self.with(|_| Ok(()))
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError> {
+        let key = key(path)?;
+        if key.is_empty() {
+            return Ok(Some(TargetMetadata {
+                is_dir: true,
+                len: 0,
+                modified: SystemTime::now(),
+            }));
+        }
+        self.with(|connection| {
+            Ok(connection
+                .prepare_cached("SELECT is_dir, len, modified FROM entries WHERE path = ?1")?
+                .query_row(params![key], |row| {
+                    Ok(TargetMetadata {
+                        is_dir: row.get(0)?,
+                        len: row.get::<_, i64>(1)?.max(0) as u64,
+                        modified: UNIX_EPOCH + Duration::from_nanos(row.get::<_, i64>(2)?.max(0) as u64),
+                    })
+                })
+                .optional()?)
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Option<Vec<OsString>>, AppError> {
+        let key = key(path)?;
+        self.with(|connection| {
+            let mut select = connection.prepare_cached("SELECT path FROM entries WHERE parent = ?1")?;
+            let names = select
+                .query_map(params![key], |row| {
+                    row.get::<_, String>(0)
+                })?
+                .map(|path| Ok(path?.rsplit('/').next().unwrap_or_default().into()))
+                .collect::<Result<_, AppError>>()?;
+            Ok(Some(names))
+        })
+    }
+
+    fn available_space(&self) -> Result<Option<u64>, AppError> {
+        let dir = self
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        RealFs.available_space(dir).context("read free space of", dir)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), AppError> {
+        let key = key(path)?;
+        self.with(|connection| add_dirs(connection, &key))
+    }
+
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError> {
+        self.upload_with_progress(src, path, &mut |_| {})
+    }
+
+    fn upload_with_progress(&self, src: &Path, path: &Path, progress: &mut dyn FnMut(u64)) -> Result<(), AppError> {
+        let key = key(path)?;
+        let mut source = fs::File::open(paths::extended(src)).context("open", src)?;
+        let meta = source.metadata().context("read metadata", src)?;
+        if meta.len() > MAX_LEN {
+            return Err(AppError::Backend(format!(
+                "{} is too large for a SQLite destination",
+                src.display()
+            )));
+        }
+        self.with(|connection| {
+            let transaction = connection.transaction()?;
+            add_dirs(&transaction, parent(&key))?;
+            transaction.execute(
+                "INSERT OR REPLACE INTO entries (path, parent, is_dir, len, modified, contents) VALUES (?1, ?2, 0, ?3, ?4, ?5)",
+                params![
+                    key,
+                    parent(&key),
+                    meta.len() as i64,
+                    nanos(meta.modified()?),
+                    ZeroBlob(meta.len() as i32)
+                ],
+            )?;
+            let mut blob = transaction.blob_open(
+                rusqlite::MAIN_DB,
+                c"entries",
+                c"contents",
+                transaction.last_insert_rowid(),
+                false,
+            )?;
+            let copied = super::copy_chunked(
+                &mut std::io::Read::take(&mut source, meta.len()),
+                &mut blob,
+                progress,
+            )
+            .context("read", src)?;
+            drop(blob);
+            if copied != meta.len() {
+                return Err(AppError::PathErr(format!(
+                    "{} changed while it was stored",
+                    src.display()
+                )));
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), AppError> {
+        let key = key(path)?;
+        if key.is_empty() {
+            return Err(AppError::PathErr(
+                "the root of the database can not be removed".into(),
+            ));
+        }
+        self.with(|connection| {
+            connection.execute(
+                &format!("DELETE FROM entries WHERE {BELOW}"),
+                params![key],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        let (from, to) = (key(from)?, key(to)?);
+        self.with(|connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                &format!("DELETE FROM entries WHERE {BELOW}"),
+                params![to],
+            )?;
+            add_dirs(&transaction, parent(&to))?;
+            // The entries below keep their parents below the renamed one
+            transaction.execute(
+                &format!(
+                    "UPDATE entries SET
+                        path = ?2 || substr(path, length(?1) + 1),
+                        parent = CASE WHEN path = ?1 THEN ?3 ELSE ?2 || substr(parent, length(?1) + 1) END
+                    WHERE {BELOW}"
+                ),
+                params![from, to, parent(&to)],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_tree_in_one_database() {
+        let dir = std::env::temp_dir().join(format!(
+            "fsync-sqlite-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("a.txt");
+        fs::write(&src, "contents").unwrap();
+        let path = dir.join("mirror.db");
+
+        let target = SqliteTarget::new(path.clone());
+        target.connect().unwrap();
+        target.upload(&src, "docs/old/a.txt".as_ref()).unwrap();
+        target.upload(&src, "docs/b.txt".as_ref()).unwrap();
+        target.rename("docs/old".as_ref(), "docs/new".as_ref()).unwrap();
+        target.remove("docs/b.txt".as_ref()).unwrap();
+        drop(target);
+
+        let target = SqliteTarget::new(path.clone());
+        assert_eq!(
+            target.read_dir("docs".as_ref()).unwrap().unwrap(),
+            ["new"]
+        );
+        let meta = target.metadata("docs/new/a.txt".as_ref()).unwrap().unwrap();
+        assert_eq!(
+            (meta.len, meta.modified),
+            (
+                8,
+                fs::metadata(&src).unwrap().modified().unwrap()
+            )
+        );
+        let (parent, contents): (String, Vec<u8>) = Connection::open(&path)
+            .unwrap()
+            .query_row(
+                "SELECT parent, contents FROM entries WHERE path = 'docs/new/a.txt'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (parent.as_str(), &contents[..]),
+            ("docs/new", &b"contents"[..])
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1088 @@
+//! Zip archive destination.
+//!
+//! `zip:<file>` destinations store the whole tree in one zip archive,
+//! for destinations where many small files are slow or limited. Files are
+//! stored uncompressed and appended as they change. The central directory
+//! at the end of the archive lists the current copy of every entry, the
+//! replaced and removed ones stay behind as unlisted bytes until the
+//! archive is compacted, once they take more space than the listed ones.
+//!
+//! The central directory is written every [COMMIT_INTERVAL] while entries
+//! change, when the destination is dropped and by [close_archives] before
+//! fsync exits on a signal. An archive cut short in
+//! between is recovered from the entries themselves on the next start.
+//! Archives with more than 65535 entries or above 4 GiB use the zip64
+//! extensions.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Bound,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{SyncTarget, TargetMetadata};
+use crate::{
+    app::Context,
+    filesystem::{Fs, RealFs},
+    paths, AppError,
+};
+
+/// Time between two writes of the central directory while entries change
+const COMMIT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Unlisted bytes below which the archive is never compacted
+const MIN_DEAD: u64 = 16 * 1024 * 1024;
+
+/// Signature of a local file header
+const LOCAL: u32 = 0x0403_4b50;
+/// Signature of a central directory header
+const CENTRAL: u32 = 0x0201_4b50;
+/// Signature of the end of central directory record
+const END: u32 = 0x0605_4b50;
+/// Signature of the zip64 end of central directory record
+const ZIP64_END: u32 = 0x0606_4b50;
+/// Signature of the zip64 end of central directory locator
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+/// Extra field of the zip64 sizes and offset
+const ZIP64_EXTRA: u16 = 0x0001;
+/// Extra field of the Unix modification time
+const TIME_EXTRA: u16 = 0x5455;
+/// Extra field of the NTFS times, to 100 ns
+const NTFS_EXTRA: u16 = 0x000a;
+/// Bytes of the two time extra fields
+const TIME_LEN: u16 = 9 + 36;
+/// 100 ns intervals between 1601, the NTFS epoch, and 1970
+const NTFS_EPOCH: u64 = 11_644_473_600 * 10_000_000;
+/// Values standing for a zip64 one
+const MAX_U32: u64 = 0xFFFF_FFFF;
+/// Bytes of a local file header without its name and extra fields
+const LOCAL_LEN: u64 = 30;
+
+/// CRC-32 lookup table
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => 0xEDB8_8320 ^ (crc >> 1),
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Writer computing the CRC-32 of what it writes
+struct CrcWriter<W> {
+    /// Written to
+    inner: W,
+    /// CRC of the bytes so far, inverted
+    crc: u32,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        for &byte in &buf[..written] {
+            self.crc = CRC_TABLE[usize::from(self.crc as u8 ^ byte)] ^ (self.crc >> 8);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Entry listed in the central directory
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Offset of the local file header
+    offset: u64,
+    /// Compression method, stored (0) for the entries of fsync
+    method: u16,
+    /// CRC-32 of the contents
+    crc: u32,
+    /// Bytes stored in the archive
+    stored: u64,
+    /// Bytes of the contents
+    size: u64,
+    /// Unix modification time
+    modified: u32,
+    /// Nanoseconds of the modification time, to the 100 ns NTFS keeps
+    nanos: u32,
+    /// Unix permissions and file type
+    mode: u32,
+    /// The entry is a directory
+    dir: bool,
+}
+
+impl Entry {
+    /// Directory stored at `offset`
+    fn dir(offset: u64) -> Self {
+        let (modified, nanos) = unix_time(SystemTime::now());
+        Self {
+            offset,
+            method: 0,
+            crc: 0,
+            stored: 0,
+            size: 0,
+            modified,
+            nanos,
+            mode: 0o040_755,
+            dir: true,
+        }
+    }
+
+    /// The sizes or the offset do not fit the plain fields
+    fn zip64(&self) -> bool {
+        self.stored >= MAX_U32 || self.size >= MAX_U32 || self.offset >= MAX_U32
+    }
+
+    /// Local file header of the entry named `name`, with the zip64 sizes
+    /// if `zip64` is set
+    fn local_header(&self, name: &str, zip64: bool) -> Vec<u8> {
+        let (time, date) = dos_time(self.modified);
+        let mut header = Vec::with_capacity(LOCAL_LEN as usize + name.len() + 29);
+        put32(&mut header, LOCAL);
+        put16(&mut header, if zip64 { 45 } else { 20 });
+        // Names are UTF-8
+        put16(&mut header, 0x0800);
+        put16(&mut header, self.method);
+        put16(&mut header, time);
+        put16(&mut header, date);
+        put32(&mut header, self.crc);
+        let sizes = match zip64 {
+            true => [MAX_U32; 2],
+            false => [self.stored, self.size],
+        };
+        sizes.iter().for_each(|&size| put32(&mut header, size as u32));
+        put16(&mut header, name.len() as u16);
+        put16(
+            &mut header,
+            if zip64 { TIME_LEN + 20 } else { TIME_LEN },
+        );
+        header.extend_from_slice(name.as_bytes());
+        self.time_extra(&mut header);
+        if zip64 {
+            put16(&mut header, ZIP64_EXTRA);
+            put16(&mut header, 16);
+            put64(&mut header, self.size);
+            put64(&mut header, self.stored);
+        }
+        header
+    }
+
+    /// Central directory header of the entry named `name`
+    fn central_header(&self, name: &str) -> Vec<u8> {
+        let zip64 = self.zip64();
+        let (time, date) = dos_time(self.modified);
+        let mut header = Vec::with_capacity(46 + name.len() + 37);
+        put32(&mut header, CENTRAL);
+        // Made by Unix, to keep the permissions
+        put16(&mut header, 0x0300 | 45);
+        put16(&mut header, if zip64 { 45 } else { 20 });
+        put16(&mut header, 0x0800);
+        put16(&mut header, self.method);
+        put16(&mut header, time);
+        put16(&mut header, date);
+        put32(&mut header, self.crc);
+        for value in [self.stored, self.size] {
+            put32(&mut header, value.min(MAX_U32) as u32);
+        }
+        put16(&mut header, name.len() as u16);
+        put16(
+            &mut header,
+            if zip64 { TIME_LEN + 28 } else { TIME_LEN },
+        );
+        // Comment, disk and internal attributes
+        header.extend_from_slice(&[0; 6]);
+        put32(
+            &mut header,
+            self.mode << 16 | u32::from(self.dir) << 4,
+        );
+        put32(
+            &mut header,
+            self.offset.min(MAX_U32) as u32,
+        );
+        header.extend_from_slice(name.as_bytes());
+        self.time_extra(&mut header);
+        if zip64 {
+            put16(&mut header, ZIP64_EXTRA);
+            put16(&mut header, 24);
+            put64(&mut header, self.size);
+            put64(&mut header, self.stored);
+            put64(&mut header, self.offset);
+        }
+        header
+    }
+
+    /// Appends the extra fields of the modification time to `header`, the
+    /// NTFS one keeping the fraction of the second
+    fn time_extra(&self, header: &mut Vec<u8>) {
+        put16(header, TIME_EXTRA);
+        put16(header, 5);
+        header.push(1);
+        put32(header, self.modified);
+        put16(header, NTFS_EXTRA);
+        put16(header, 32);
+        put32(header, 0);
+        put16(header, 1);
+        put16(header, 24);
+        let ntfs = NTFS_EPOCH + u64::from(self.modified) * 10_000_000 + u64::from(self.nanos / 100);
+        // Modification, access and creation times
+        (0..3).for_each(|_| put64(header, ntfs));
+    }
+}
+
+/// Appends `value` to `buf`, little-endian
+fn put16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Appends `value` to `buf`, little-endian
+fn put32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Appends `value` to `buf`, little-endian
+fn put64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Little-endian value at `at` of `buf`, zero past its end
+fn get(buf: &[u8], at: usize, len: usize) -> u64 {
+    buf.get(at..at + len).map_or(0, |bytes| {
+        bytes.iter().rev().fold(0, |value, &byte| {
+            value << 8 | u64::from(byte)
+        })
+    })
+}
+
+/// Seconds and nanoseconds of `time` since the Unix epoch, saturated to
+/// the zip field
+fn unix_time(time: SystemTime) -> (u32, u32) {
+    time.duration_since(UNIX_EPOCH).map_or((0, 0), |since| {
+        match u32::try_from(since.as_secs()) {
+            Ok(secs) => (secs, since.subsec_nanos()),
+            Err(_) => (u32::MAX, 0),
+        }
+    })
+}
+
+/// MS-DOS time and date of the Unix time `unix`, in UTC
+fn dos_time(unix: u32) -> (u16, u16) {
+    let (days, secs) = (i64::from(unix / 86_400), unix % 86_400);
+    // Civil date of a day count, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    if !(1980..2108).contains(&year) {
+        return (0, 1 << 5 | 1);
+    }
+    let time = (secs / 3600) << 11 | (secs / 60 % 60) << 5 | ((secs % 60) / 2);
+    let date = (year - 1980) << 9 | month << 5 | day;
+    (time as u16, date as u16)
+}
+
+/// Unix time of the MS-DOS `time` and `date`, taken as UTC
+fn from_dos_time(time: u16, date: u16) -> u32 {
+    let (year, month, day) = (
+        i64::from(date >> 9) + 1980,
+        i64::from(date >> 5 & 15),
+        i64::from(date & 31),
+    );
+    // Day count of a civil date, see dos_time
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = i64::from(time >> 11) * 3600 + i64::from(time >> 5 & 63) * 60 + i64::from(time & 31) * 2;
+    (days * 86_400 + secs).clamp(0, i64::from(u32::MAX)) as u32
+}
+
+/// Name of the entry `path` in the archive, with a trailing slash for
+/// directories
+fn entry_name(path: &Path, dir: bool) -> Result<String, AppError> {
+    let mut name = path
+        .components()
+        .filter_map(|part| match part {
+            Component::Normal(part) => Some(part.to_str()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            AppError::PathErr(format!(
+                "{} is not valid UTF-8",
+                path.display()
+            ))
+        })?
+        .join("/");
+    if dir {
+        name.push('/');
+    }
+    Ok(name)
+}
+
+/// Parses the central directory header at the start of `buf`, with its
+/// length
+fn parse_central(buf: &[u8]) -> Option<(PathBuf, Entry, usize)> {
+    if get(buf, 0, 4) != u64::from(CENTRAL) {
+        return None;
+    }
+    let (name_len, extra_len, comment_len) = (
+        get(buf, 28, 2) as usize,
+        get(buf, 30, 2) as usize,
+        get(buf, 32, 2) as usize,
+    );
+    let name = std::str::from_utf8(buf.get(46..46 + name_len)?).ok()?;
+    let mut entry = Entry {
+        offset: get(buf, 42, 4),
+        method: get(buf, 10, 2) as u16,
+        crc: get(buf, 16, 4) as u32,
+        stored: get(buf, 20, 4),
+        size: get(buf, 24, 4),
+        modified: from_dos_time(
+            get(buf, 12, 2) as u16,
+            get(buf, 14, 2) as u16,
+        ),
+        nanos: 0,
+        mode: match get(buf, 5, 1) {
+            3 => (get(buf, 38, 4) >> 16) as u32,
+            _ => 0,
+        },
+        dir: name.ends_with('/'),
+    };
+    parse_extra(
+        buf.get(46 + name_len..46 + name_len + extra_len)?,
+        &mut entry,
+        true,
+    );
+    Some((
+        PathBuf::from(name.trim_end_matches('/')),
+        entry,
+        46 + name_len + extra_len + comment_len,
+    ))
+}
+
+/// Reads the zip64 values and the modification time of `extra` into
+/// `entry`, the offset too if `central`
+fn parse_extra(mut extra: &[u8], entry: &mut Entry, central: bool) {
+    while extra.len() >= 4 {
+        let (id, len) = (
+            get(extra, 0, 2) as u16,
+            get(extra, 2, 2) as usize,
+        );
+        let Some(data) = extra.get(4..4 + len) else {
+            return;
+        };
+        match id {
+            ZIP64_EXTRA => {
+                let mut at = 0;
+                let mut next = |value: &mut u64| {
+                    if *value == MAX_U32 && at + 8 <= data.len() {
+                        *value = get(data, at, 8);
+                        at += 8;
+                    }
+                };
+                next(&mut entry.size);
+                next(&mut entry.stored);
+                if central {
+                    next(&mut entry.offset);
+                }
+            }
+            TIME_EXTRA if data.first().is_some_and(|flags| flags & 1 == 1) && data.len() >= 5 => {
+                entry.modified = get(data, 1, 4) as u32;
+            }
+            NTFS_EXTRA if get(data, 4, 2) == 1 && get(data, 6, 2) >= 8 => {
+                let since = get(data, 8, 8).saturating_sub(NTFS_EPOCH);
+                entry.modified = (since / 10_000_000).min(u64::from(u32::MAX)) as u32;
+                entry.nanos = (since % 10_000_000) as u32 * 100;
+            }
+            _ => {}
+        }
+        extra = &extra[4 + len..];
+    }
+}
+
+/// Open archive with its listed entries
+struct Archive {
+    /// Path of the archive
+    path: PathBuf,
+    /// The archive, opened for reading and writing
+    file: fs::File,
+    /// Listed entries
+    entries: BTreeMap<PathBuf, Entry>,
+    /// End of the entries, where the central directory starts
+    end: u64,
+    /// Bytes of the replaced and removed entries
+    dead: u64,
+    /// The central directory does not list the entries any more
+    dirty: bool,
+}
+
+impl Archive {
+    /// Opens the archive at `path`, creating it if missing
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(paths::extended(&path))?;
+        let mut archive = match file.metadata()?.len() {
+            0 => Self {
+                path,
+                file,
+                entries: BTreeMap::new(),
+                end: 0,
+                dead: 0,
+                dirty: true,
+            },
+            len => match Self::central_directory(&mut file, len)? {
+                Some((entries, end)) => Self {
+                    path,
+                    file,
+                    entries,
+                    end,
+                    dead: 0,
+                    dirty: false,
+                },
+                None => {
+                    tracing::warn!("{path:?} has no central directory, recovering the entries");
+                    let (entries, end) = Self::recover(&mut file)?;
+                    Self {
+                        path,
+                        file,
+                        entries,
+                        end,
+                        dead: 0,
+                        dirty: true,
+                    }
+                }
+            },
+        };
+        let listed: u64 = archive.entries.iter().map(|(name, entry)| record_len(name, entry)).sum();
+        archive.dead = archive.end.saturating_sub(listed);
+        Ok(archive)
+    }
+
+    /// Entries listed by the central directory of `file`, of `len` bytes,
+    /// with the offset of the directory. None if there is none.
+    fn central_directory(file: &mut fs::File, len: u64) -> io::Result<Option<(BTreeMap<PathBuf, Entry>, u64)>> {
+        // The end record is followed by a comment of up to 64 KiB
+        let tail_len = len.min(22 + 0xFFFF);
+        file.seek(SeekFrom::Start(len - tail_len))?;
+        let mut tail = vec![0; tail_len as usize];
+        file.read_exact(&mut tail)?;
+        let Some(at) = (0..tail.len().saturating_sub(21))
+            .rev()
+            .find(|&at| get(&tail, at, 4) == u64::from(END))
+        else {
+            return Ok(None);
+        };
+        let (mut count, mut size, mut offset) = (
+            get(&tail, at + 10, 2),
+            get(&tail, at + 12, 4),
+            get(&tail, at + 16, 4),
+        );
+        if at >= 20 && get(&tail, at - 20, 4) == u64::from(ZIP64_LOCATOR) {
+            let mut record = [0; 56];
+            file.seek(SeekFrom::Start(get(&tail, at - 12, 8)))?;
+            file.read_exact(&mut record)?;
+            if get(&record, 0, 4) == u64::from(ZIP64_END) {
+                (count, size, offset) = (
+                    get(&record, 32, 8),
+                    get(&record, 40, 8),
+                    get(&record, 48, 8),
+                );
+            }
+        }
+        if offset.checked_add(size).is_none_or(|cd_end| cd_end > len) {
+            return Ok(None);
+        }
+        let mut directory = vec![0; size as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut directory)?;
+        let mut entries = BTreeMap::new();
+        let mut rest = &directory[..];
+        for _ in 0..count {
+            let Some((name, entry, used)) = parse_central(rest) else {
+                return Ok(None);
+            };
+            entries.insert(name, entry);
+            rest = &rest[used..];
+        }
+        Ok(Some((entries, offset)))
+    }
+
+    /// Entries found by walking the local headers of `file`, the later copy
+    /// of an entry winning, with the end of the last one
+    fn recover(file: &mut fs::File) -> io::Result<(BTreeMap<PathBuf, Entry>, u64)> {
+        let mut entries = BTreeMap::new();
+        let mut offset = 0;
+        let mut reader = io::BufReader::new(&mut *file);
+        reader.seek(SeekFrom::Start(0))?;
+        loop {
+            let mut header = [0; LOCAL_LEN as usize];
+            if reader.read_exact(&mut header).is_err() || get(&header, 0, 4) != u64::from(LOCAL) {
+                break;
+            }
+            let (name_len, extra_len) = (
+                get(&header, 26, 2) as usize,
+                get(&header, 28, 2) as usize,
+            );
+            let mut rest = vec![0; name_len + extra_len];
+            // Sizes following the contents can not be recovered
+            if get(&header, 6, 2) & 0x08 != 0 || reader.read_exact(&mut rest).is_err() {
+                break;
+            }
+            let Ok(name) = std::str::from_utf8(&rest[..name_len]) else {
+                break;
+            };
+            let mut entry = Entry {
+                offset,
+                method: get(&header, 8, 2) as u16,
+                crc: get(&header, 14, 4) as u32,
+                stored: get(&header, 18, 4),
+                size: get(&header, 22, 4),
+                modified: from_dos_time(
+                    get(&header, 10, 2) as u16,
+                    get(&header, 12, 2) as u16,
+                ),
+                nanos: 0,
+                mode: match name.ends_with('/') {
+                    true => 0o040_755,
+                    false => 0o100_644,
+                },
+                dir: name.ends_with('/'),
+            };
+            parse_extra(&rest[name_len..], &mut entry, false);
+            let end = offset + LOCAL_LEN + rest.len() as u64 + entry.stored;
+            if end > reader.get_ref().metadata()?.len() {
+                break;
+            }
+            reader.seek(SeekFrom::Start(end))?;
+            entries.insert(
+                PathBuf::from(name.trim_end_matches('/')),
+                entry,
+            );
+            offset = end;
+        }
+        Ok((entries, offset))
+    }
+
+    /// Lists `entry` as `path`, the copy it replaces becomes dead
+    fn list(&mut self, path: PathBuf, entry: Entry) {
+        if let Some(old) = self.entries.insert(path.clone(), entry) {
+            self.dead += record_len(&path, &old);
+        }
+        self.dirty = true;
+    }
+
+    /// Unlists `path` and the entries below it
+    fn unlist(&mut self, path: &Path) -> Vec<(PathBuf, Entry)> {
+        let removed: Vec<_> = self
+            .entries
+            .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .take_while(|(name, _)| name.starts_with(path))
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect();
+        for (name, entry) in &removed {
+            self.entries.remove(name);
+            self.dead += record_len(name, entry);
+        }
+        self.dirty |= !removed.is_empty();
+        removed
+    }
+
+    /// Adds the missing directories `path` and above
+    fn add_dirs(&mut self, path: &Path) -> io::Result<()> {
+        let mut missing: Vec<_> = path
+            .ancestors()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .take_while(|dir| !self.entries.get(*dir).is_some_and(|entry| entry.dir))
+            .map(Path::to_path_buf)
+            .collect();
+        while let Some(dir) = missing.pop() {
+            let entry = Entry::dir(self.end);
+            let name = entry_name(&dir, true).map_err(io::Error::other)?;
+            let header = entry.local_header(&name, false);
+            self.file.seek(SeekFrom::Start(self.end))?;
+            self.file.write_all(&header)?;
+            self.end += header.len() as u64;
+            self.list(dir, entry);
+        }
+        Ok(())
+    }
+
+    /// Appends the contents of `source` as the file `path`, reporting the
+    /// bytes copied so far to `progress`
+    fn append(&mut self, source: &mut fs::File, path: &Path, progress: &mut dyn FnMut(u64)) -> Result<(), AppError> {
+        if let Some(parent) = path.parent() {
+            self.add_dirs(parent)?;
+        }
+        let name = entry_name(path, false)?;
+        let meta = source.metadata()?;
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions());
+        #[cfg(not(unix))]
+        let mode = 0o100_644;
+        let (modified, nanos) = unix_time(meta.modified()?);
+        let mut entry = Entry {
+            offset: self.end,
+            method: 0,
+            crc: 0,
+            stored: 0,
+            size: 0,
+            modified,
+            nanos,
+            mode,
+            dir: false,
+        };
+        let zip64 = meta.len() >= MAX_U32;
+        let header = entry.local_header(&name, zip64);
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&header)?;
+        let mut writer = CrcWriter {
+            inner: io::BufWriter::new(&mut self.file),
+            crc: !0,
+        };
+        let copied = super::copy_chunked(source, &mut writer, progress)?;
+        writer.flush()?;
+        if copied >= MAX_U32 && !zip64 {
+            return Err(AppError::PathErr(format!(
+                "{} grew past 4 GiB while it was stored",
+                path.display()
+            )));
+        }
+        (entry.crc, entry.stored, entry.size) = (!writer.crc, copied, copied);
+        drop(writer);
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        self.file.write_all(&entry.local_header(&name, zip64))?;
+        self.end = entry.offset + header.len() as u64 + copied;
+        self.list(path.to_path_buf(), entry);
+        Ok(())
+    }
+
+    /// Offset of the contents of `entry`, after its local header
+    fn data(&mut self, entry: &Entry) -> io::Result<u64> {
+        let mut header = [0; LOCAL_LEN as usize];
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        self.file.read_exact(&mut header)?;
+        Ok(entry.offset + LOCAL_LEN + get(&header, 26, 2) + get(&header, 28, 2))
+    }
+
+    /// Appends a copy of `entry`, listed as `path`
+    fn copy(&mut self, entry: &Entry, path: PathBuf) -> io::Result<()> {
+        let data = self.data(entry)?;
+        let copy = Entry {
+            offset: self.end,
+            ..entry.clone()
+        };
+        let name = entry_name(&path, entry.dir).map_err(io::Error::other)?;
+        let header = copy.local_header(
+            &name,
+            copy.stored >= MAX_U32 || copy.size >= MAX_U32,
+        );
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&header)?;
+        // Read and written in chunks, both ends in the same file
+        let mut buf = vec![0; super::CHUNK_SIZE];
+        let (mut read, mut written) = (data, self.end + header.len() as u64);
+        while read < data + entry.stored {
+            let len = (data + entry.stored - read).min(buf.len() as u64) as usize;
+            self.file.seek(SeekFrom::Start(read))?;
+            self.file.read_exact(&mut buf[..len])?;
+            self.file.seek(SeekFrom::Start(written))?;
+            self.file.write_all(&buf[..len])?;
+            (read, written) = (read + len as u64, written + len as u64);
+        }
+        self.end = written;
+        self.list(path, copy);
+        Ok(())
+    }
+
+    /// Writes the central directory after the entries, compacting the
+    /// archive first if most of it is dead
+    fn commit(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if self.dead > MIN_DEAD && self.dead > self.end - self.dead {
+            self.compact()?;
+        }
+        let mut directory = Vec::new();
+        for (path, entry) in &self.entries {
+            let name = entry_name(path, entry.dir).map_err(io::Error::other)?;
+            directory.extend(entry.central_header(&name));
+        }
+        let (count, size, offset) = (
+            self.entries.len() as u64,
+            directory.len() as u64,
+            self.end,
+        );
+        let zip64_end = offset + size;
+        put32(&mut directory, ZIP64_END);
+        put64(&mut directory, 44);
+        put16(&mut directory, 0x0300 | 45);
+        put16(&mut directory, 45);
+        directory.extend_from_slice(&[0; 8]);
+        put64(&mut directory, count);
+        put64(&mut directory, count);
+        put64(&mut directory, size);
+        put64(&mut directory, offset);
+        put32(&mut directory, ZIP64_LOCATOR);
+        put32(&mut directory, 0);
+        put64(&mut directory, zip64_end);
+        put32(&mut directory, 1);
+        put32(&mut directory, END);
+        directory.extend_from_slice(&[0; 4]);
+        put16(&mut directory, count.min(0xFFFF) as u16);
+        put16(&mut directory, count.min(0xFFFF) as u16);
+        put32(&mut directory, size.min(MAX_U32) as u32);
+        put32(
+            &mut directory,
+            offset.min(MAX_U32) as u32,
+        );
+        put16(&mut directory, 0);
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&directory)?;
+        self.file.set_len(offset + directory.len() as u64)?;
+        self.file.sync_data()?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Rewrites the archive with the listed entries only
+    fn compact(&mut self) -> io::Result<()> {
+        tracing::info!(
+            "compacting {:?}, {} unlisted",
+            self.path,
+            crate::format_bytes(self.dead)
+        );
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(".tmp");
+        let compacted = PathBuf::from(name);
+        let mut output = io::BufWriter::new(fs::File::create(paths::extended(
+            &compacted,
+        ))?);
+        let entries: Vec<_> = self.entries.values().cloned().collect();
+        let mut offsets = Vec::with_capacity(entries.len());
+        let mut offset = 0;
+        for entry in &entries {
+            let len = self.data(entry)? - entry.offset + entry.stored;
+            self.file.seek(SeekFrom::Start(entry.offset))?;
+            io::copy(
+                &mut (&mut self.file).take(len),
+                &mut output,
+            )?;
+            offsets.push(offset);
+            offset += len;
+        }
+        output.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+        fs::rename(
+            paths::extended(&compacted),
+            paths::extended(&self.path),
+        )?;
+        self.file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(paths::extended(&self.path))?;
+        for (entry, offset) in self.entries.values_mut().zip(offsets) {
+            entry.offset = offset;
+        }
+        (self.end, self.dead, self.dirty) = (offset, 0, true);
+        Ok(())
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        if let Err(err) = self.commit() {
+            tracing::error!(
+                "writing the central directory of {:?}: {err}",
+                self.path
+            );
+        }
+    }
+}
+
+/// Bytes of the local record of `entry`, listed as `path`, as fsync writes
+/// it
+fn record_len(path: &Path, entry: &Entry) -> u64 {
+    let name = path.as_os_str().len() as u64 + u64::from(entry.dir);
+    let extra = if entry.stored >= MAX_U32 || entry.size >= MAX_U32 {
+        TIME_LEN + 20
+    } else {
+        TIME_LEN
+    };
+    LOCAL_LEN + name + u64::from(extra) + entry.stored
+}
+
+/// Archive of a [ZipTarget], shared with the thread writing its central
+/// directory
+struct Shared {
+    /// Path of the archive
+    path: PathBuf,
+    /// The archive once connected
+    archive: Mutex<Option<Archive>>,
+}
+
+impl Shared {
+    /// Locks the archive, poisoning is ignored
+    fn lock(&self) -> MutexGuard<'_, Option<Archive>> {
+        self.archive.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Archives of the zip destinations created so far, see [close_archives]
+static ARCHIVES: Mutex<Vec<Weak<Shared>>> = Mutex::new(Vec::new());
+
+/// Writes the central directories of the open zip destinations, for a
+/// process about to exit without dropping them. The archives stay locked
+/// afterwards, so that no entry is appended past the directory.
+pub fn close_archives() {
+    let archives = std::mem::take(&mut *ARCHIVES.lock().unwrap_or_else(|e| e.into_inner()));
+    for shared in archives.iter().filter_map(Weak::upgrade) {
+        let mut archive = shared.lock();
+        if let Some(Err(err)) = archive.as_mut().map(Archive::commit) {
+            tracing::error!(
+                "writing the central directory of {:?}: {err}",
+                shared.path
+            );
+        }
+        std::mem::forget(archive);
+    }
+}
+
+/// Destination storing the tree in a zip archive
+pub struct ZipTarget {
+    /// The archive, shared with the committing thread
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for ZipTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZipTarget")
+            .field("path", &self.shared.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ZipTarget {
+    /// Creates the target storing the files in the archive `path`.
+    /// The archive is not opened until [SyncTarget::connect] is called.
+    pub fn new(path: PathBuf) -> Self {
+        let shared = Arc::new(Shared {
+            path,
+            archive: Mutex::default(),
+        });
+        let mut archives = ARCHIVES.lock().unwrap_or_else(|e| e.into_inner());
+        archives.retain(|archive| archive.strong_count() > 0);
+        archives.push(Arc::downgrade(&shared));
+        drop(archives);
+        // Ends with the last use of the target
+        let committed = Arc::downgrade(&shared);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(COMMIT_INTERVAL);
+            let Some(shared) = committed.upgrade() else {
+                break;
+            };
+            let mut archive = shared.lock();
+            if let Some(Err(err)) = archive.as_mut().map(Archive::commit) {
+                tracing::error!(
+                    "writing the central directory of {:?}: {err}",
+                    shared.path
+                );
+            }
+        });
+        Self { shared }
+    }
+
+    /// Runs `f` on the archive, opening it on first use
+    fn with<T>(&self, f: impl FnOnce(&mut Archive) -> Result<T, AppError>) -> Result<T, AppError> {
+        let mut archive = self.shared.lock();
+        if archive.is_none() {
+            let path = &self.shared.path;
+            *archive = Some(Archive::open(path.clone()).context("open", path)?);
+        }
+        f(archive.as_mut().expect("opened above"))
+    }
+}
+
+impl SyncTarget for ZipTarget {
+    fn describe(&self) -> String {
+        format!("zip:{:?}", self.shared.path)
+    }
+
+    fn connect(&self) -> Result<(), AppError> {
+        self.with(|_| Ok(()))
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<TargetMetadata>, AppError> {
+        if path.as_os_str().is_empty() {
+            return Ok(Some(TargetMetadata {
+                is_dir: true,
+                len: 0,
+                modified: SystemTime::now(),
+            }));
+        }
+        self.with(|archive| {
+            Ok(
+                archive.entries.get(path).map(|entry| TargetMetadata {
+                    is_dir: entry.dir,
+                    len: entry.size,
+                    modified: UNIX_EPOCH + Duration::new(entry.modified.into(), entry.nanos),
+                }),
+            )
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Option<Vec<OsString>>, AppError> {
+        self.with(|archive| {
+            Ok(Some(
+                archive
+                    .entries
+                    .range::<Path, _>((Bound::Excluded(path), Bound::Unbounded))
+                    .take_while(|(name, _)| name.starts_with(path))
+                    .filter(|(name, _)| name.parent() == Some(path))
+                    .filter_map(|(name, _)| name.file_name().map(Into::into))
+                    .collect(),
+            ))
+        })
+    }
+
+    fn available_space(&self) -> Result<Option<u64>, AppError> {
+        let path = &self.shared.path;
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        RealFs.available_space(dir).context("read free space of", dir)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), AppError> {
+        let archive = &self.shared.path;
+        self.with(|zip| zip.add_dirs(path).context("write", archive))
+    }
+
+    fn upload(&self, src: &Path, path: &Path) -> Result<(), AppError> {
+        self.upload_with_progress(src, path, &mut |_| {})
+    }
+
+    fn upload_with_progress(&self, src: &Path, path: &Path, progress: &mut dyn FnMut(u64)) -> Result<(), AppError> {
+        let mut source = fs::File::open(paths::extended(src)).context("open", src)?;
+        self.with(|archive| archive.append(&mut source, path, progress))
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), AppError> {
+        if path.as_os_str().is_empty() {
+            return Err(AppError::PathErr(
+                "the root of the archive can not be removed".into(),
+            ));
+        }
+        self.with(|archive| {
+            archive.unlist(path);
+            Ok(())
+        })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), AppError> {
+        let archive = &self.shared.path;
+        self.with(|zip| {
+            if let Some(parent) = to.parent() {
+                zip.add_dirs(parent).context("write", archive)?;
+            }
+            // Local headers carry the names, the entries are copied
+            for (path, entry) in zip.unlist(from) {
+                let moved = to.join(path.strip_prefix(from)?);
+                zip.copy(&entry, moved).context("write", archive)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_tree_in_one_archive() {
+        let dir = std::env::temp_dir().join(format!(
+            "fsync-zip-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&a, "first").unwrap();
+        fs::write(&b, "second file").unwrap();
+        let path = dir.join("backup.zip");
+
+        let target = ZipTarget::new(path.clone());
+        target.connect().unwrap();
+        target.upload(&a, "docs/a.txt".as_ref()).unwrap();
+        target.upload(&b, "docs/old/b.txt".as_ref()).unwrap();
+        target.upload(&b, "docs/a.txt".as_ref()).unwrap();
+        target.rename("docs/old".as_ref(), "docs/new".as_ref()).unwrap();
+        target.remove("docs/new/b.txt".as_ref()).unwrap();
+        let (replaced, compacted) = target
+            .with(|archive| {
+                let end = archive.end;
+                archive.compact()?;
+                Ok((end, archive.end))
+            })
+            .unwrap();
+        assert!(compacted < replaced);
+        drop(target);
+
+        let target = ZipTarget::new(path.clone());
+        assert_eq!(
+            target.read_dir("docs".as_ref()).unwrap().unwrap(),
+            ["a.txt", "new"]
+        );
+        let meta = target.metadata("docs/a.txt".as_ref()).unwrap().unwrap();
+        assert_eq!(meta.len, 11);
+        assert_eq!(
+            meta.modified.duration_since(UNIX_EPOCH).unwrap().as_micros(),
+            fs::metadata(&b)
+                .unwrap()
+                .modified()
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_micros()
+        );
+        target.upload(&a, "c.txt".as_ref()).unwrap();
+        drop(target);
+
+        // Cut short before the central directory was written
+        let (entries, end) = Archive::recover(&mut fs::File::open(&path).unwrap()).unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(end).unwrap();
+        assert!(end < len && entries.contains_key(Path::new("c.txt")));
+        let target = ZipTarget::new(path.clone());
+        assert!(target.metadata("c.txt".as_ref()).unwrap().is_some_and(|meta| meta.len == 5));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
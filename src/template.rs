@@ -0,0 +1,338 @@
+//! Destination paths built from a template.
+//!
+//! With `destination_template` the files are not stored under their source
+//! paths but under the template filled in from the path and the metadata of
+//! every file, e.g. to sort a camera upload folder by date:
+//!
+//! ```toml
+//! destination_template = "{year}/{month}/{filename}"   # 2024/05/IMG_0042.jpg
+//! # destination_template = "{ext}/{path}"               # jpg/holidays/IMG_0042.jpg
+//! ```
+//!
+//! | Placeholder | Value |
+//! |---|---|
+//! | `{path}` | path relative to the source |
+//! | `{dir}` | directory of the file relative to the source, empty at the top |
+//! | `{filename}` | file name |
+//! | `{stem}` | file name without the extension |
+//! | `{ext}` | extension without the dot, empty if there is none |
+//! | `{year}`, `{month}`, `{day}` | UTC date of the last modification |
+//!
+//! Directories of the source are not created at the destination, only the
+//! ones the files are stored in. A file stays where it was placed first
+//! while fsync runs, so later modifications update it in place and a removal
+//! or rename finds it. Entries gone before they were placed, removed
+//! directories or files placed by an earlier run, are looked up under their
+//! source path.
+//!
+//! Files of different source directories may fill the template in the same
+//! way, e.g. two `IMG_0042.jpg` taken the same month. The one placed second
+//! is stored as `IMG_0042 (2).jpg`, so it doesn't overwrite the first one.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{app::Context, paths, AppError};
+
+/// Placeholders of a template
+const PLACEHOLDERS: &[&str] = &["path", "dir", "filename", "stem", "ext", "year", "month", "day"];
+
+/// Placeholders filled in from the modification time
+const DATE: &[&str] = &["year", "month", "day"];
+
+/// Most placements remembered at once
+const MAX_PLACED: usize = 100_000;
+
+/// `path` with ` (n)` appended to the stem of its file name
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.file_stem().unwrap_or_default());
+    name.push(format!(" ({n})"));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Destinations given to the source files while fsync runs, so that a
+/// file keeps its destination and two files don't share one
+#[derive(Debug, Default)]
+pub(crate) struct Placements {
+    /// Destination of each placed source file
+    destinations: HashMap<PathBuf, PathBuf>,
+    /// Source file placed at each destination
+    sources: HashMap<PathBuf, PathBuf>,
+    /// [MAX_PLACED] files are placed and their sources exist, new ones
+    /// are not remembered
+    full: bool,
+}
+
+impl Placements {
+    /// Destination `source` was placed at
+    pub(crate) fn get(&self, source: &Path) -> Option<&PathBuf> {
+        self.destinations.get(source)
+    }
+
+    /// Places `source` at `destination`, or at `destination` numbered from
+    /// 2 on if another source file is placed there, and returns where
+    pub(crate) fn place(&mut self, source: &Path, destination: PathBuf) -> PathBuf {
+        let mut placed = destination.clone();
+        let mut n = 1;
+        while self.sources.get(&placed).is_some_and(|owner| owner != source) {
+            n += 1;
+            placed = numbered(&destination, n);
+        }
+        if n > 1 {
+            tracing::warn!(
+                "{}: {} is taken by {}, stored as {}",
+                source.display(),
+                destination.display(),
+                self.sources[&destination].display(),
+                placed.display()
+            );
+        }
+        if self.destinations.len() >= MAX_PLACED && !self.full {
+            // Placements of files gone unnoticed
+            self.destinations.retain(|source, _| paths::extended(source).exists());
+            let destinations = &self.destinations;
+            self.sources.retain(|_, source| destinations.contains_key(source));
+            self.full = self.destinations.len() >= MAX_PLACED;
+            if self.full {
+                tracing::warn!("{MAX_PLACED} files placed, later ones may collide");
+            }
+        }
+        if !self.full {
+            self.destinations.insert(source.to_path_buf(), placed.clone());
+            self.sources.insert(placed.clone(), source.to_path_buf());
+        }
+        placed
+    }
+
+    /// Forgets the placement of `source`, or of the files below it, once
+    /// it is removed or renamed
+    pub(crate) fn forget(&mut self, source: &Path) {
+        match self.destinations.remove(source) {
+            Some(destination) => {
+                self.sources.remove(&destination);
+            }
+            None => {
+                self.destinations.retain(|placed, _| !placed.starts_with(source));
+                self.sources.retain(|_, placed| !placed.starts_with(source));
+            }
+        }
+        self.full = false;
+    }
+}
+
+/// Parsed `destination_template`
+#[derive(Debug)]
+pub(crate) struct Template {
+    /// Template as written
+    pattern: String,
+    /// The template uses a date placeholder
+    dated: bool,
+    /// Destination paths of the source paths placed so far
+    placed: Mutex<Placements>,
+}
+
+impl Template {
+    /// Template from its textual form.
+    ///
+    /// # Errors
+    ///
+    /// [AppError::PathErr] is returned for unknown placeholders.
+    pub(crate) fn new(pattern: &str) -> Result<Self, AppError> {
+        let mut dated = false;
+        for name in placeholders(pattern) {
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(AppError::PathErr(format!(
+                    "unknown placeholder {{{name}}} in the destination template {pattern:?}"
+                )));
+            }
+            dated |= DATE.contains(&name);
+        }
+        Ok(Self {
+            pattern: pattern.to_owned(),
+            dated,
+            placed: Mutex::default(),
+        })
+    }
+
+    /// Destination of the source entry `source`, `relative` to the source
+    /// root. Directories and entries gone before they were placed keep the
+    /// `relative` path.
+    ///
+    /// # Errors
+    ///
+    /// Errors reading the metadata of an existing file are returned.
+    pub(crate) fn destination(&self, source: &Path, relative: &Path) -> Result<PathBuf, AppError> {
+        let mut placed = self.placed.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(destination) = placed.get(source) {
+            return Ok(destination.clone());
+        }
+        let meta = match fs::metadata(paths::extended(source)) {
+            Ok(meta) if meta.is_file() => meta,
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(AppError::from(err).context("read metadata", source)),
+            _ => return Ok(relative.to_path_buf()),
+        };
+        let date = match self.dated {
+            true => humantime::format_rfc3339_seconds(meta.modified().context("read modification time", source)?).to_string(),
+            false => String::new(),
+        };
+        let name = |path: Option<&std::ffi::OsStr>| path.map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let value = |placeholder: &str| match placeholder {
+            "path" => relative.to_string_lossy().replace('\\', "/"),
+            "dir" => relative
+                .parent()
+                .map(|dir| dir.to_string_lossy().replace('\\', "/"))
+                .unwrap_or_default(),
+            "filename" => name(relative.file_name()),
+            "stem" => name(relative.file_stem()),
+            "ext" => name(relative.extension()),
+            // 2024-05-03T10:00:00Z
+            "year" => date[..4].to_owned(),
+            "month" => date[5..7].to_owned(),
+            _ => date[8..10].to_owned(),
+        };
+        let mut rendered = String::new();
+        let mut rest = self.pattern.as_str();
+        while let Some((before, after)) = rest.split_once('{') {
+            let Some((placeholder, after)) = after.split_once('}') else {
+                break;
+            };
+            rendered.push_str(before);
+            rendered.push_str(&value(placeholder));
+            rest = after;
+        }
+        rendered.push_str(rest);
+        // Empty placeholders leave no empty directories
+        let destination: PathBuf = rendered.split('/').filter(|part| !part.is_empty()).collect();
+        Ok(placed.place(source, destination))
+    }
+
+    /// Forgets where `source` was placed, see [Placements::forget]
+    pub(crate) fn forget(&self, source: &Path) {
+        self.placed.lock().unwrap_or_else(|e| e.into_inner()).forget(source);
+    }
+}
+
+/// Names of the placeholders in `pattern`
+fn placeholders(pattern: &str) -> impl Iterator<Item = &str> {
+    pattern
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    #[test]
+    fn fills_in_the_path_and_the_date() {
+        let root = std::env::temp_dir().join(format!(
+            "fsync-template-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(root.join("holidays")).unwrap();
+        let file = root.join("holidays/IMG_0042.jpg");
+        fs::write(&file, "jpg").unwrap();
+        fs::write(root.join("top.txt"), "txt").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_730_400))
+            .unwrap();
+        let relative = Path::new("holidays/IMG_0042.jpg");
+
+        let template = Template::new("{year}/{month}/{day}/{filename}").unwrap();
+        assert_eq!(
+            template.destination(&file, relative).unwrap(),
+            PathBuf::from("2024/05/03/IMG_0042.jpg")
+        );
+        let template = Template::new("{ext}/{dir}/{stem}.{ext}").unwrap();
+        assert_eq!(
+            template.destination(&file, relative).unwrap(),
+            PathBuf::from("jpg/holidays/IMG_0042.jpg")
+        );
+        assert_eq!(
+            template
+                .destination(
+                    &root.join("top.txt"),
+                    Path::new("top.txt")
+                )
+                .unwrap(),
+            PathBuf::from("txt/top.txt")
+        );
+
+        let template = Template::new("{year}/{filename}").unwrap();
+        template.destination(&file, relative).unwrap();
+        // Placed while it existed
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            template.destination(&file, relative).unwrap(),
+            PathBuf::from("2024/IMG_0042.jpg")
+        );
+        assert_eq!(
+            template.destination(&root.join("gone"), Path::new("gone")).unwrap(),
+            PathBuf::from("gone")
+        );
+        assert!(Template::new("{camera}/{filename}").is_err());
+    }
+
+    #[test]
+    fn colliding_files_are_numbered() {
+        let root = std::env::temp_dir().join(format!(
+            "fsync-template-collide-{}",
+            std::process::id()
+        ));
+        for dir in ["a", "b", "c"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("IMG_0042.jpg"), dir).unwrap();
+        }
+        let template = Template::new("{filename}").unwrap();
+        let destination = |dir: &str| {
+            template
+                .destination(
+                    &root.join(dir).join("IMG_0042.jpg"),
+                    &Path::new(dir).join("IMG_0042.jpg"),
+                )
+                .unwrap()
+        };
+        assert_eq!(
+            destination("a"),
+            PathBuf::from("IMG_0042.jpg")
+        );
+        assert_eq!(
+            destination("b"),
+            PathBuf::from("IMG_0042 (2).jpg")
+        );
+        assert_eq!(
+            destination("a"),
+            PathBuf::from("IMG_0042.jpg")
+        );
+        assert_eq!(
+            destination("c"),
+            PathBuf::from("IMG_0042 (3).jpg")
+        );
+        // Removed, its destination is free again
+        fs::remove_file(root.join("a/IMG_0042.jpg")).unwrap();
+        template.forget(&root.join("a/IMG_0042.jpg"));
+        fs::write(root.join("a/IMG_0042.jpg"), "new").unwrap();
+        assert_eq!(
+            destination("a"),
+            PathBuf::from("IMG_0042.jpg")
+        );
+        template.forget(&root);
+        assert!(template.placed.lock().unwrap().destinations.is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+//! Transforms of the copied contents.
+//!
+//! A [Transform] registered with
+//! [App::add_transform](crate::App::add_transform) wraps the reader of
+//! every copied file, so an application can change the contents on their
+//! way to the destination, e.g. normalise line endings or redact secrets.
+//! A [TransformChain] passes the contents through several, in the order
+//! they were added:
+//!
+//! ```
+//! use std::{io::{self, Read}, path::Path};
+//! use fsync::{Transform, TransformChain};
+//!
+//! /// Stores the text files with `\n` line endings
+//! struct Unix;
+//!
+//! impl Transform for Unix {
+//!     fn transform<'a>(&self, source: &Path, mut input: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+//!         if source.extension().is_none_or(|ext| ext != "txt") {
+//!             return Ok(input);
+//!         }
+//!         let mut text = String::new();
+//!         input.read_to_string(&mut text)?;
+//!         Ok(Box::new(io::Cursor::new(text.replace("\r\n", "\n"))))
+//!     }
+//! }
+//!
+//! let chain = TransformChain::new().with(Unix);
+//! let mut stored = String::new();
+//! chain
+//!     .transform(Path::new("/src/a.txt"), Box::new(&b"a\r\nb\r\n"[..]))
+//!     .unwrap()
+//!     .read_to_string(&mut stored)
+//!     .unwrap();
+//! assert_eq!(stored, "a\nb\n");
+//! ```
+//!
+//! The transformed contents are written to a temporary file with the
+//! modification time and the permissions of the source, which is stored
+//! instead of it. Stored files whose size the transforms changed differ from
+//! their source for the default [Planner](crate::planner::Planner), the initial sync
+//! copies them again unless a planner comparing the modification times
+//! alone is set.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::paths;
+
+/// Change of the contents of the copied files.
+pub trait Transform: Send + Sync {
+    /// Reader of the contents of the source file `source` stored at the
+    /// destination, given the reader `input` of its contents
+    ///
+    /// # Errors
+    ///
+    /// Errors reading the contents are returned, the copy fails with them.
+    fn transform<'a>(&self, source: &Path, input: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>>;
+}
+
+/// Transforms applied in the order they were added, each to the contents
+/// the one before produced
+#[derive(Default)]
+pub struct TransformChain {
+    /// Transforms in the order they are applied
+    transforms: Vec<Box<dyn Transform>>,
+}
+
+impl TransformChain {
+    /// Chain keeping the contents
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The chain with `transform` applied last
+    pub fn with(mut self, transform: impl Transform + 'static) -> Self {
+        self.push(transform);
+        self
+    }
+
+    /// Adds `transform`, applied after the ones added before
+    pub fn push(&mut self, transform: impl Transform + 'static) {
+        self.transforms.push(Box::new(transform));
+    }
+
+    /// No transforms were added
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+}
+
+impl Transform for TransformChain {
+    fn transform<'a>(&self, source: &Path, input: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        self.transforms.iter().try_fold(input, |input, transform| {
+            transform.transform(source, input)
+        })
+    }
+}
+
+impl std::fmt::Debug for TransformChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransformChain")
+            .field("transforms", &self.transforms.len())
+            .finish()
+    }
+}
+
+/// Temporary file of transformed contents, removed when dropped
+#[derive(Debug)]
+pub(crate) struct Transformed {
+    /// Path of the file
+    pub(crate) path: PathBuf,
+}
+
+impl Transformed {
+    /// New empty temporary file, opened for writing
+    ///
+    /// # Errors
+    ///
+    /// Errors creating the file are returned.
+    pub(crate) fn create() -> io::Result<(Self, fs::File)> {
+        /// Files made so far, naming the next one
+        static FILES: AtomicU64 = AtomicU64::new(0);
+
+        let transformed = Self {
+            path: std::env::temp_dir().join(format!(
+                "fsync-{}-{}.transformed",
+                std::process::id(),
+                FILES.fetch_add(1, Ordering::Relaxed)
+            )),
+        };
+        let file = fs::OpenOptions::new().write(true).create_new(true).open(&transformed.path)?;
+        Ok((transformed, file))
+    }
+}
+
+impl Drop for Transformed {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Writes the contents of the file `src` passed through `transform` to a
+/// temporary file with its modification time and permissions
+///
+/// # Errors
+///
+/// Errors reading the source, of the transform and writing the temporary
+/// file are returned.
+pub(crate) fn apply(transform: &dyn Transform, src: &Path) -> io::Result<Transformed> {
+    let source = fs::File::open(paths::extended(src))?;
+    let meta = source.metadata()?;
+    let (transformed, mut file) = Transformed::create()?;
+    let mut output = io::BufWriter::new(&mut file);
+    io::copy(
+        &mut transform.transform(
+            src,
+            Box::new(io::BufReader::new(source)),
+        )?,
+        &mut output,
+    )?;
+    output.flush()?;
+    drop(output);
+    file.set_modified(meta.modified()?)?;
+    // A read-only copy could not be removed on Windows
+    #[cfg(unix)]
+    file.set_permissions(meta.permissions())?;
+    Ok(transformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// Stores the contents in upper case
+    struct Upper;
+
+    impl Transform for Upper {
+        fn transform<'a>(&self, _source: &Path, mut input: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+            let mut text = String::new();
+            input.read_to_string(&mut text)?;
+            Ok(Box::new(io::Cursor::new(
+                text.to_uppercase(),
+            )))
+        }
+    }
+
+    #[test]
+    fn transformed_copies_keep_the_metadata() {
+        let source = TempDir::new("transform");
+        let src = source.join("a.txt");
+        fs::write(&src, "abc").unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(60 * 60);
+        let file = fs::File::options().write(true).open(&src).unwrap();
+        file.set_modified(modified).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        drop(file);
+
+        let transformed = apply(&TransformChain::new().with(Upper), &src).unwrap();
+        assert_eq!(
+            fs::read_to_string(&transformed.path).unwrap(),
+            "ABC"
+        );
+        let meta = fs::metadata(&transformed.path).unwrap();
+        assert_eq!(meta.modified().unwrap(), modified);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                meta.permissions().mode() & 0o7777,
+                0o640
+            );
+        }
+        // Removed once stored
+        let path = transformed.path.clone();
+        drop(transformed);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,359 @@
+//! Terminal dashboard.
+//!
+//! Built with the `tui` feature, `--tui` replaces the log on the terminal
+//! with live panels: the stage, queue depth and statistics of the
+//! synchronisation, the copies in progress, the recent changes and the
+//! errors. `r` asks for a rescan, `q`, `Esc` or `Ctrl-C` stops fsync.
+//!
+//! The log is not written to the standard error while the dashboard is
+//! shown, a `[log_file]` or `[syslog]` still receives it.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, SystemTime},
+};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, LineGauge, List, Paragraph},
+    Frame,
+};
+
+use crate::{eta, App, AppError, AppHandle, SyncObserver};
+
+/// Recent changes kept for the dashboard
+const RECENT: usize = 200;
+
+/// Errors kept for the dashboard
+const ERRORS: usize = 50;
+
+/// Interval between two redraws
+const TICK: Duration = Duration::from_millis(250);
+
+/// What the dashboard shows besides the statistics
+#[derive(Debug, Default)]
+struct Feed {
+    /// Recent changes, the newest last
+    events: VecDeque<String>,
+    /// Copies in progress with the bytes copied and the size, by destination
+    transfers: BTreeMap<PathBuf, (u64, u64)>,
+    /// Recent errors, the newest last
+    errors: VecDeque<String>,
+}
+
+impl Feed {
+    /// Adds the change `line`, forgetting the oldest one past [RECENT]
+    fn event(&mut self, line: String) {
+        push(&mut self.events, line, RECENT);
+    }
+}
+
+/// Appends `line` to `lines`, keeping at most `keep` of them
+fn push(lines: &mut VecDeque<String>, line: String, keep: usize) {
+    let time = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+    // Only the time of the day
+    lines.push_back(format!("{} {line}", &time[11..19]));
+    if lines.len() > keep {
+        lines.pop_front();
+    }
+}
+
+/// Observer feeding the dashboard
+struct Observer(Arc<Mutex<Feed>>);
+
+impl Observer {
+    /// The feed, even if a redraw panicked
+    fn feed(&self) -> std::sync::MutexGuard<'_, Feed> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl SyncObserver for Observer {
+    fn on_copy(&self, _source: &Path, destination: &Path, bytes: u64) {
+        let mut feed = self.feed();
+        feed.transfers.remove(destination);
+        feed.event(format!(
+            "copy {} ({bytes} bytes)",
+            destination.display()
+        ));
+    }
+
+    fn on_progress(&self, _source: &Path, destination: &Path, copied: u64, total: u64) {
+        let mut feed = self.feed();
+        match copied < total {
+            true => feed.transfers.insert(
+                destination.to_path_buf(),
+                (copied, total),
+            ),
+            false => feed.transfers.remove(destination),
+        };
+    }
+
+    fn on_remove(&self, _source: &Path, destination: &Path) {
+        self.feed().event(format!(
+            "remove {}",
+            destination.display()
+        ));
+    }
+
+    fn on_rename(&self, from: &Path, _to: &Path, destination: &Path) {
+        self.feed().event(format!(
+            "rename {} -> {}",
+            from.display(),
+            destination.display()
+        ));
+    }
+
+    fn on_error(&self, operation: &str, source: &Path, error: &AppError) {
+        let mut feed = self.feed();
+        let line = format!(
+            "{operation} {}: {error}",
+            source.display()
+        );
+        feed.event(line.clone());
+        push(&mut feed.errors, line, ERRORS);
+    }
+}
+
+/// Dashboard shown on the terminal until it is dropped
+#[must_use = "the dashboard closes when it is dropped"]
+pub struct Dashboard {
+    /// Asks the drawing thread to return
+    stop: Arc<AtomicBool>,
+    /// Drawing thread
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Shows the dashboard of `app` on the terminal, fed by the changes applied
+/// from now on.
+///
+/// Quitting it with `q`, `Esc` or `Ctrl-C` restores the terminal, prints the
+/// statistics and exits the process.
+///
+/// # Errors
+///
+/// [AppError::IoError] is returned if the terminal could not be set up.
+pub fn start(app: &mut App) -> Result<Dashboard, AppError> {
+    let feed = Arc::new(Mutex::new(Feed::default()));
+    app.add_observer(Observer(feed.clone()));
+    let handle = app.handle();
+    let mut terminal = ratatui::try_init()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = std::thread::spawn({
+        let stop = stop.clone();
+        move || {
+            while !stop.load(Ordering::Relaxed) {
+                let drawn = terminal.draw(|frame| {
+                    draw(
+                        frame,
+                        &handle,
+                        &feed.lock().unwrap_or_else(|e| e.into_inner()),
+                    )
+                });
+                if let Err(err) = drawn {
+                    restore();
+                    tracing::error!("dashboard stopped: {err}");
+                    return;
+                }
+                if quit_pressed(&handle).unwrap_or(false) {
+                    restore();
+                    tracing::info!("summary: {}", handle.stats());
+                    std::process::exit(0);
+                }
+            }
+        }
+    });
+    Ok(Dashboard {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+/// Puts the terminal back into its normal mode, does nothing if the
+/// dashboard is not shown
+pub fn restore() {
+    ratatui::restore();
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        restore();
+    }
+}
+
+/// Waits for a key until the next redraw, returns whether it quits; `r`
+/// asks `handle` for a rescan
+fn quit_pressed(handle: &AppHandle) -> std::io::Result<bool> {
+    if !event::poll(TICK)? {
+        return Ok(false);
+    }
+    Ok(match event::read()? {
+        Event::Key(key) if key.kind == KeyEventKind::Press && key.code == KeyCode::Char('r') => {
+            handle.rescan();
+            false
+        }
+        Event::Key(key) if key.kind == KeyEventKind::Press => {
+            matches!(
+                key.code,
+                KeyCode::Char('q') | KeyCode::Esc
+            ) || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL))
+        }
+        _ => false,
+    })
+}
+
+/// Draws the panels of `handle` and `feed`
+fn draw(frame: &mut Frame, handle: &AppHandle, feed: &Feed) {
+    let [header, body, errors, footer] = Layout::vertical([
+        Constraint::Length(4),
+        Constraint::Min(5),
+        Constraint::Length(8),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [events, transfers] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(body);
+
+    let status = handle.status();
+    let stats = status.stats;
+    let last_event = status
+        .last_event
+        .map(|time| humantime::format_rfc3339_seconds(time).to_string())
+        .unwrap_or_else(|| "none".into());
+    frame.render_widget(
+        Paragraph::new(vec![
+            Line::from(format!(
+                "{:?}, lag {}, {} in flight, {} changes queued, last change {last_event}",
+                status.phase,
+                humantime::format_duration(Duration::from_secs(
+                    status.lag.as_secs()
+                )),
+                status.in_flight.len(),
+                status.queue_depth
+            )),
+            Line::from(stats.to_string()),
+        ])
+        .block(Block::bordered().title("fsync")),
+        header,
+    );
+    frame.render_widget(
+        List::new(feed.events.iter().rev().map(String::as_str)).block(Block::bordered().title("Recent changes")),
+        events,
+    );
+
+    let block = Block::bordered().title(format!(
+        "Transfers ({})",
+        feed.transfers.len()
+    ));
+    let inner = block.inner(transfers);
+    frame.render_widget(block, transfers);
+    for (row, (path, (copied, total))) in feed.transfers.iter().take(inner.height.into()).enumerate() {
+        let gauge = LineGauge::default()
+            .ratio((*copied as f64 / (*total).max(1) as f64).clamp(0.0, 1.0))
+            .label(
+                match eta(total - copied, stats.throughput) {
+                    Some(eta) => format!(
+                        "{} ~{} left",
+                        path.display(),
+                        humantime::format_duration(eta)
+                    ),
+                    None => path.display().to_string(),
+                },
+            )
+            .filled_style(Style::default().fg(Color::Green));
+        frame.render_widget(
+            gauge,
+            Rect {
+                y: inner.y + row as u16,
+                height: 1,
+                ..inner
+            },
+        );
+    }
+
+    frame.render_widget(
+        List::new(feed.errors.iter().rev().map(String::as_str))
+            .style(Style::default().fg(Color::Red))
+            .block(Block::bordered().title(format!(
+                "Errors ({})",
+                feed.errors.len()
+            ))),
+        errors,
+    );
+    frame.render_widget(
+        Paragraph::new("r rescan  q quit"),
+        footer,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{backend::TestBackend, Terminal};
+
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn draws_the_changes_and_transfers() {
+        let observer = Observer(Arc::default());
+        observer.on_copy(
+            Path::new("/src/a.txt"),
+            Path::new("a.txt"),
+            3,
+        );
+        observer.on_progress(
+            Path::new("/src/big.iso"),
+            Path::new("big.iso"),
+            50,
+            100,
+        );
+        observer.on_error(
+            "remove",
+            Path::new("/src/b.txt"),
+            &AppError::PathErr("gone".into()),
+        );
+        let app = App::new(Config::build(
+            std::env::temp_dir(),
+            std::env::temp_dir().join("fsync-tui"),
+        ))
+        .unwrap();
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 24)).unwrap();
+        terminal.draw(|frame| draw(frame, &app.handle(), &observer.feed())).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        for text in [
+            "Recent changes",
+            "copy a.txt (3 bytes)",
+            "Transfers (1)",
+            "big.iso",
+            "Errors (1)",
+        ] {
+            assert!(screen.contains(text), "{text} missing");
+        }
+        observer.on_progress(
+            Path::new("/src/big.iso"),
+            Path::new("big.iso"),
+            100,
+            100,
+        );
+        assert!(observer.feed().transfers.is_empty());
+    }
+}
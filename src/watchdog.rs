@@ -0,0 +1,184 @@
+//! Detection of a silently dead watcher.
+//!
+//! With a `[watchdog]` section a background thread raises an alert when the
+//! watcher delivered no event for `timeout`: an error is logged, the
+//! `fsync_watcher_stale` metric is set and the `stale` webhook event is
+//! sent (with `--features webhooks`).
+//!
+//! ```toml
+//! [watchdog]
+//! timeout = "1h"
+//! # probe = true
+//! ```
+//!
+//! Quiet sources look the same as a dead watcher. With `probe = true` the
+//! watchdog creates and removes the [PROBE] directory in the source after
+//! half of the `timeout` without events, its events serve as heartbeat and
+//! are not synchronised.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+
+use crate::{metrics, stats::Counters};
+
+/// Name of the heartbeat directory created in the source
+pub(crate) const PROBE: &str = ".fsync-watchdog";
+
+/// Longest time between two checks
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `[watchdog]` section of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct WatchdogConfig {
+    /// Time without events after which the watcher is considered dead
+    #[serde(default = "WatchdogConfig::default_timeout", with = "crate::config::humantime_serde")]
+    pub(crate) timeout: Duration,
+    /// Creates heartbeat events in the source
+    #[serde(default)]
+    pub(crate) probe: bool,
+}
+
+impl WatchdogConfig {
+    /// Default of [WatchdogConfig::timeout]
+    fn default_timeout() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+}
+
+/// Heartbeat directory of `source`
+pub(crate) fn probe_path(source: &Path) -> PathBuf {
+    source.join(PROBE)
+}
+
+/// Running watchdog, stopped when dropped
+#[derive(Debug)]
+pub(crate) struct Watchdog {
+    /// Tells the thread to stop
+    stop: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    /// Starts watching the events counted by `stats` for the watcher of
+    /// `source`, `alert` is called with the message when the watcher went
+    /// stale
+    pub(crate) fn start<F>(config: &WatchdogConfig, source: &Path, stats: Arc<Counters>, alert: F) -> Self
+    where
+        F: Fn(&str) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let timeout = config.timeout;
+        let probe = config.probe.then(|| probe_path(source));
+        let source = source.to_path_buf();
+        let stopped = stop.clone();
+        let started = SystemTime::now();
+        let check = (timeout / 4).clamp(
+            Duration::from_secs(1),
+            MAX_CHECK_INTERVAL,
+        );
+
+        std::thread::spawn(move || {
+            let mut stale = false;
+            let mut probed = false;
+            while !stopped.load(Ordering::Relaxed) {
+                std::thread::sleep(check);
+                let last = stats.last_event().unwrap_or(started).max(started);
+                let quiet = last.elapsed().unwrap_or_default();
+                if let Some(probe) = probe.as_deref().filter(|_| quiet >= timeout / 2 && !probed) {
+                    // A directory: only creation and removal events
+                    if let Err(err) = fs::create_dir(probe).and_then(|()| fs::remove_dir(probe)) {
+                        tracing::warn!("watchdog: {}: {err}", probe.display());
+                    }
+                    probed = true;
+                }
+                if quiet < timeout / 2 {
+                    probed = false;
+                }
+                match (stale, quiet >= timeout) {
+                    (false, true) => {
+                        let message = format!(
+                            "no events from the watcher of {} for {}",
+                            source.display(),
+                            humantime::format_duration(Duration::from_secs(quiet.as_secs()))
+                        );
+                        tracing::error!("watchdog: {message}");
+                        metrics::stale(true);
+                        alert(&message);
+                        stale = true;
+                    }
+                    (true, false) => {
+                        tracing::info!(
+                            "watchdog: events of {} are flowing again",
+                            source.display()
+                        );
+                        metrics::stale(false);
+                        stale = false;
+                    }
+                    _ => {}
+                }
+            }
+            metrics::stale(false);
+        });
+        Self { stop }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Instant};
+
+    use super::*;
+
+    /// Watchdog of `stats` with a timeout of two seconds, sending its alerts
+    fn watchdog(stats: Arc<Counters>) -> (Watchdog, mpsc::Receiver<String>) {
+        let (sender, alerts) = mpsc::channel();
+        let config = WatchdogConfig {
+            timeout: Duration::from_secs(2),
+            probe: false,
+        };
+        let watchdog = Watchdog::start(
+            &config,
+            Path::new("/source"),
+            stats,
+            move |message| {
+                let _ = sender.send(message.to_owned());
+            },
+        );
+        (watchdog, alerts)
+    }
+
+    #[test]
+    fn alerts_without_events() {
+        let (_watchdog, alerts) = watchdog(Arc::default());
+        let message = alerts.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(message.contains("/source"), "{message}");
+        // Once until events flow again
+        assert!(alerts.recv_timeout(Duration::from_secs(2)).is_err());
+    }
+
+    #[test]
+    fn stays_quiet_while_events_arrive() {
+        let stats = Arc::<Counters>::default();
+        let (_watchdog, alerts) = watchdog(stats.clone());
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(4) {
+            stats.event();
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert!(alerts.try_recv().is_err());
+    }
+}
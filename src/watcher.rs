@@ -0,0 +1,501 @@
+//! Sources of the changes of the source.
+//!
+//! [App](crate::App) learns about the changes below the watched directory
+//! from an [EventSource]. The default [NotifyWatcher] uses the watcher of
+//! the platform: inotify, FSEvents or `ReadDirectoryChangesW`. Embedders
+//! can feed fsync from elsewhere, a message queue or a test, with
+//! [App::set_event_source](crate::App::set_event_source).
+//!
+//! The paths of the [Operation]s are resolved against the watched
+//! directory, held briefly to coalesce atomic saves and then filtered like
+//! the ones of the platform watcher.
+//!
+//! The `[watcher]` section picks the source of [App::run](crate::App::run):
+//!
+//! ```toml
+//! [watcher]
+//! backend = "usn"     # or "fanotify", "notify" by default
+//! ```
+//!
+//! On NTFS volumes the `usn` backend reads the change journal of the volume
+//! and keeps its position between the runs, so the changes made while fsync
+//! was not running are applied on startup without comparing the whole
+//! source, see [EventSource::missed]. On Linux the `fanotify` backend
+//! watches the whole filesystem of the source with one mark instead of an
+//! inotify watch for every directory, which large trees run out of; the
+//! `notify` backend polls the directories left without one with
+//! `poll_unwatched`.
+//!
+//! On macOS a `[watcher.fsevents]` section replaces [NotifyWatcher] with an
+//! `FsEvents` stream of that latency and those flags. A source which only
+//! knows the directory something changed in returns [Poll::Rescan] with it,
+//! and the directory is compared with its stored copy.
+//!
+//! Built with the `test-util` feature, `injector()` returns an
+//! `EventInjector` and the `InjectedEvents` source it feeds, to run the
+//! watch loop on changes made up by a test or a simulation. Watching ends
+//! once the injector is dropped and its changes are applied.
+
+#[cfg(target_os = "linux")]
+mod fanotify;
+mod fsevents;
+#[cfg(target_os = "linux")]
+pub(crate) mod inotify;
+mod usn;
+
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use serde::Deserialize;
+
+pub use crate::queue::Operation;
+use crate::AppError;
+#[cfg(target_os = "linux")]
+pub use fanotify::Fanotify;
+#[cfg(target_os = "macos")]
+pub use fsevents::FsEvents;
+pub use fsevents::FsEventsConfig;
+#[cfg(windows)]
+pub use usn::UsnJournal;
+
+/// `[watcher]` section of the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct WatcherConfig {
+    /// Source of the changes
+    #[serde(default)]
+    pub(crate) backend: Backend,
+    /// Latency and flags of the FSEvents stream of the `notify` backend on
+    /// macOS, the ones of [NotifyWatcher] if not set
+    #[serde(default)]
+    pub(crate) fsevents: Option<FsEventsConfig>,
+    /// Interval the subtrees inotify has no watches left for are polled
+    /// at, on Linux; the watch fails to start if not set
+    #[serde(default, with = "crate::config::humantime_serde::option")]
+    pub(crate) poll_unwatched: Option<Duration>,
+}
+
+/// Source of the changes of the `[watcher]` section
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// [NotifyWatcher], the watcher of the platform
+    #[default]
+    Notify,
+    /// `UsnJournal`, the change journal of an NTFS volume, on Windows
+    Usn,
+    /// `Fanotify`, one mark on the filesystem of the source, on Linux
+    Fanotify,
+}
+
+/// Event source of `config`, keeping its state next to the queue file
+/// `queue_file`
+///
+/// # Errors
+///
+/// [AppError::PathErr] is returned for a backend of another platform.
+pub(crate) fn event_source(config: &WatcherConfig, queue_file: &Path) -> Result<Box<dyn EventSource>, AppError> {
+    #[cfg(not(windows))]
+    let _ = queue_file;
+    if config.fsevents.is_some() && (cfg!(not(target_os = "macos")) || config.backend != Backend::Notify) {
+        tracing::warn!("[watcher.fsevents] only tunes the notify backend on macOS, it is ignored");
+    }
+    Ok(match config.backend {
+        Backend::Notify => match &config.fsevents {
+            #[cfg(target_os = "macos")]
+            Some(fsevents) => Box::new(FsEvents::new(fsevents.clone())),
+            _ => Box::new(NotifyWatcher {
+                poll_unwatched: config.poll_unwatched,
+                ..NotifyWatcher::default()
+            }),
+        },
+        #[cfg(windows)]
+        Backend::Usn => Box::new(UsnJournal::new(
+            queue_file.with_extension("usn.json"),
+        )),
+        #[cfg(not(windows))]
+        Backend::Usn => {
+            return Err(AppError::PathErr(
+                "the usn watcher backend is only available on Windows".into(),
+            ))
+        }
+        #[cfg(target_os = "linux")]
+        Backend::Fanotify => Box::new(Fanotify::default()),
+        #[cfg(not(target_os = "linux"))]
+        Backend::Fanotify => {
+            return Err(AppError::PathErr(
+                "the fanotify watcher backend is only available on Linux".into(),
+            ))
+        }
+    })
+}
+
+/// What an [EventSource] saw while it was polled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Poll {
+    /// Changes of one event, none if it changes no contents
+    Changes(Vec<Operation>),
+    /// Nothing happened
+    Idle,
+    /// The source lost track of the changes below the directory, or only
+    /// knows that something in it changed; the directory is compared with
+    /// its stored copy again
+    Rescan(PathBuf),
+    /// The source stopped, watching ends
+    Closed,
+}
+
+/// Source of the changes below the watched directory
+pub trait EventSource: Send {
+    /// Starts watching `root`, and the directories below it if `recursive`
+    /// is set
+    ///
+    /// # Errors
+    ///
+    /// [AppError] is returned if `root` can not be watched.
+    fn start(&mut self, root: &Path, recursive: bool) -> Result<(), AppError>;
+
+    /// Changes below `root` made since the source last stopped watching it,
+    /// applied instead of the initial scan; none if the source does not
+    /// know them, the default
+    ///
+    /// # Errors
+    ///
+    /// [AppError] is returned if the changes can not be read.
+    fn missed(&mut self, root: &Path, recursive: bool) -> Result<Option<Vec<Operation>>, AppError> {
+        let _ = (root, recursive);
+        Ok(None)
+    }
+
+    /// Waits up to `timeout` for the next event
+    ///
+    /// # Errors
+    ///
+    /// Errors of the watcher are returned, they are logged and watching
+    /// goes on.
+    fn poll(&mut self, timeout: Duration) -> Result<Poll, AppError>;
+}
+
+/// Watcher of the platform, through [notify]
+#[derive(Debug, Default)]
+pub struct NotifyWatcher {
+    /// Watcher with its events, once started
+    watching: Option<(
+        RecommendedWatcher,
+        Receiver<notify::Result<Event>>,
+    )>,
+    /// Old paths of a rename, until the event with the new ones
+    renamed: Vec<PathBuf>,
+    /// Watched directory
+    root: PathBuf,
+    /// Interval the directories left without an inotify watch are polled
+    /// at, if they are
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    poll_unwatched: Option<Duration>,
+    /// Watcher of the directories polled instead, sending to the channel
+    /// of the events while it is kept
+    #[cfg(target_os = "linux")]
+    polled: Option<notify::PollWatcher>,
+}
+
+impl EventSource for NotifyWatcher {
+    fn start(&mut self, root: &Path, recursive: bool) -> Result<(), AppError> {
+        let (tx, rx) = mpsc::channel();
+        // Automatically select the best implementation for your platform.
+        // You can also access each implementation directly e.g. INotifyWatcher.
+        let mut watcher = RecommendedWatcher::new(tx.clone(), Config::default())?;
+        let mode = match recursive {
+            true => RecursiveMode::Recursive,
+            false => RecursiveMode::NonRecursive,
+        };
+        match watcher.watch(root, mode) {
+            Ok(()) => {}
+            #[cfg(target_os = "linux")]
+            Err(notify::Error {
+                kind: notify::ErrorKind::MaxFilesWatch,
+                paths,
+            }) => {
+                let failed = paths.first().map_or(root, PathBuf::as_path);
+                self.polled = Some(self.poll_unwatched(root, failed, tx)?);
+            }
+            Err(err) => return Err(err.into()),
+        }
+        self.watching = Some((watcher, rx));
+        self.root = root.to_path_buf();
+        Ok(())
+    }
+
+    fn poll(&mut self, timeout: Duration) -> Result<Poll, AppError> {
+        let Some((_, rx)) = &self.watching else {
+            return Ok(Poll::Closed);
+        };
+        let event = match rx.recv_timeout(timeout) {
+            Ok(event) => event?,
+            Err(RecvTimeoutError::Timeout) => return Ok(Poll::Idle),
+            Err(RecvTimeoutError::Disconnected) => return Ok(Poll::Closed),
+        };
+        tracing::trace!("Change: {event:?}");
+        // FSEvents coalesced the changes below a directory
+        if event.need_rescan() {
+            let dir = event.paths.first().cloned().unwrap_or_else(|| self.root.clone());
+            return Ok(Poll::Rescan(dir));
+        }
+        Ok(Poll::Changes(self.operations(event)))
+    }
+}
+
+impl NotifyWatcher {
+    /// Polls the subtrees of `root` left without an inotify watch once the
+    /// one of `failed` could not be added, sending their events to `tx`
+    ///
+    /// # Errors
+    ///
+    /// [AppError::Watch] is returned if they are not polled, with the
+    /// `sysctl` raising the limit, and if they can not be.
+    #[cfg(target_os = "linux")]
+    fn poll_unwatched(
+        &self,
+        root: &Path,
+        failed: &Path,
+        tx: mpsc::Sender<notify::Result<Event>>,
+    ) -> Result<notify::PollWatcher, AppError> {
+        use crate::app::Context;
+
+        // The watcher reports the absolute paths
+        let root = std::path::absolute(root).context("resolve", root)?;
+        let dirs = inotify::directories(&root).count();
+        let Some(interval) = self.poll_unwatched else {
+            return Err(notify::Error::generic(&format!(
+                "inotify ran out of watches at {}; raise the limit with {} or set poll_unwatched in [watcher]",
+                failed.display(),
+                inotify::sysctl(dirs)
+            ))
+            .into());
+        };
+        let unwatched = inotify::unwatched(&root, failed);
+        tracing::warn!(
+            "inotify ran out of watches at {}, polling {} subtrees every {}; raise the limit with {}",
+            failed.display(),
+            unwatched.len(),
+            humantime::format_duration(interval),
+            inotify::sysctl(dirs)
+        );
+        let mut polled = notify::PollWatcher::new(
+            tx,
+            Config::default().with_poll_interval(interval),
+        )?;
+        for dir in unwatched {
+            polled.watch(&dir, RecursiveMode::Recursive)?;
+        }
+        Ok(polled)
+    }
+}
+
+/// Event source of a destination fanned out from the source of another
+/// [App](crate::App), receiving the changes its watcher saw
+#[derive(Debug)]
+pub(crate) struct Mirrored {
+    /// Changes of the watched source
+    rx: Receiver<Vec<Operation>>,
+}
+
+/// Sender of the changes of a watched source with the [Mirrored] source
+/// receiving them
+pub(crate) fn mirror() -> (mpsc::Sender<Vec<Operation>>, Mirrored) {
+    let (tx, rx) = mpsc::channel();
+    (tx, Mirrored { rx })
+}
+
+impl EventSource for Mirrored {
+    fn start(&mut self, _root: &Path, _recursive: bool) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn poll(&mut self, timeout: Duration) -> Result<Poll, AppError> {
+        Ok(match self.rx.recv_timeout(timeout) {
+            Ok(operations) => Poll::Changes(operations),
+            Err(RecvTimeoutError::Timeout) => Poll::Idle,
+            Err(RecvTimeoutError::Disconnected) => Poll::Closed,
+        })
+    }
+}
+
+/// Injects made up changes into [InjectedEvents]
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Clone)]
+pub struct EventInjector {
+    /// Changes to deliver
+    tx: mpsc::Sender<Operation>,
+}
+
+/// Event source delivering the changes of an [EventInjector]
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug)]
+pub struct InjectedEvents {
+    /// Changes not yet delivered
+    rx: Receiver<Operation>,
+}
+
+/// Injector with the event source it feeds
+#[cfg(any(test, feature = "test-util"))]
+pub fn injector() -> (EventInjector, InjectedEvents) {
+    let (tx, rx) = mpsc::channel();
+    (
+        EventInjector { tx },
+        InjectedEvents { rx },
+    )
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl EventInjector {
+    /// Delivers `operation`, nothing happens if the source was dropped
+    pub fn inject(&self, operation: Operation) {
+        let _ = self.tx.send(operation);
+    }
+
+    /// The file or directory `path` of the source was created or changed
+    pub fn copy(&self, path: impl Into<PathBuf>) {
+        self.inject(Operation::Copy { path: path.into() });
+    }
+
+    /// The entry `path` of the source was removed
+    pub fn remove(&self, path: impl Into<PathBuf>) {
+        self.inject(Operation::Remove { path: path.into() });
+    }
+
+    /// The entry `from` of the source was renamed to `to`
+    pub fn rename(&self, from: impl Into<PathBuf>, to: impl Into<PathBuf>) {
+        self.inject(Operation::Rename {
+            from: from.into(),
+            to: to.into(),
+        });
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl EventSource for InjectedEvents {
+    fn start(&mut self, _root: &Path, _recursive: bool) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn poll(&mut self, timeout: Duration) -> Result<Poll, AppError> {
+        Ok(match self.rx.recv_timeout(timeout) {
+            Ok(operation) => Poll::Changes(vec![operation]),
+            Err(RecvTimeoutError::Timeout) => Poll::Idle,
+            Err(RecvTimeoutError::Disconnected) => Poll::Closed,
+        })
+    }
+}
+
+impl NotifyWatcher {
+    /// Changes of `event`.
+    ///
+    /// Captured events:
+    ///
+    /// - [Modify](notify::EventKind::Modify)
+    /// - [Create](notify::EventKind::Create)
+    /// - [Remove](notify::EventKind::Remove)
+    ///
+    /// Modify event captured differently
+    /// based on the [ModifyKind]
+    /// One is captured during renaming, another one is during file modification
+    fn operations(&mut self, event: Event) -> Vec<Operation> {
+        let mut operations = Vec::new();
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(rename_mode)) => match rename_mode {
+                RenameMode::From => self.renamed = event.paths,
+                RenameMode::To => {
+                    let mut new_filenames = event.paths;
+                    self.renamed.iter().for_each(
+                        |old_filename| match new_filenames.pop() {
+                            Some(new_filename) => operations.push(Operation::Rename {
+                                from: old_filename.clone(),
+                                to: new_filename,
+                            }),
+                            None => tracing::error!(
+                                "Cannot rename {:?}. Nothing left in the event",
+                                old_filename
+                            ),
+                        },
+                    )
+                }
+                _ => tracing::warn!("rename mode could not be handled: {rename_mode:?}"),
+            },
+            EventKind::Create(_) => {
+                event
+                    .paths
+                    .into_iter()
+                    .for_each(|path| operations.push(Operation::Copy { path }));
+            }
+            EventKind::Modify(ModifyKind::Any) => {
+                // During directory removal there will be the second MODYFY(ANY) event
+                // causing parent directory to update itself for some reason
+                event
+                    .paths
+                    .into_iter()
+                    .for_each(|path| operations.push(Operation::Copy { path }));
+            }
+            EventKind::Remove(_) => event
+                .paths
+                .into_iter()
+                .for_each(|path| operations.push(Operation::Remove { path })),
+            EventKind::Modify(ModifyKind::Data(_)) => event
+                .paths
+                .into_iter()
+                .for_each(|path| operations.push(Operation::Copy { path })),
+            // Access, metadata and unknown events change no contents
+            kind => tracing::trace!("ignored event: {kind:?}"),
+        }
+        operations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use notify::event::{CreateKind, DataChange};
+
+    use super::*;
+
+    #[test]
+    fn pairs_the_names_of_a_rename() {
+        let mut watcher = NotifyWatcher::default();
+        let event = |kind, path: &str| Event::new(kind).add_path(path.into());
+        assert!(watcher
+            .operations(event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+                "/src/a"
+            ))
+            .is_empty());
+        assert_eq!(
+            watcher.operations(event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+                "/src/b"
+            )),
+            [Operation::Rename {
+                from: "/src/a".into(),
+                to: "/src/b".into()
+            }]
+        );
+        assert_eq!(
+            watcher.operations(event(
+                EventKind::Create(CreateKind::File),
+                "/src/c"
+            )),
+            [Operation::Copy { path: "/src/c".into() }]
+        );
+        assert_eq!(
+            watcher.operations(event(
+                EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+                "/src/c"
+            )),
+            [Operation::Copy { path: "/src/c".into() }]
+        );
+        assert!(watcher.poll(Duration::ZERO).is_ok_and(|poll| poll == Poll::Closed));
+    }
+}
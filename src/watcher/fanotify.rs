@@ -0,0 +1,451 @@
+//! fanotify watcher of a whole filesystem.
+//!
+//! inotify needs a watch for every directory of the source, which runs into
+//! `max_user_watches` on large trees. One fanotify mark instead reports the
+//! changes of the whole filesystem the source is on, [Fanotify] keeps the
+//! ones below the source. Every event names the directory of the entry by
+//! its file handle, which is opened to find its path. Marking a filesystem
+//! takes `CAP_SYS_ADMIN` and Linux 5.9 or newer. A rename is one event with
+//! both names from Linux 5.17 on, older kernels report the two names
+//! separately and without a way to pair them, so renames are synchronised
+//! as a removal and a copy there.
+
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr},
+    fs, io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    queue::Operation,
+    watcher::{EventSource, Poll},
+    AppError,
+};
+
+/// Bytes of the events read at once
+const BUFFER: usize = 64 * 1024;
+
+/// Changes reported by the mark, with [libc::FAN_RENAME] or the two names
+/// of a rename
+const MASK: u64 =
+    libc::FAN_CREATE | libc::FAN_DELETE | libc::FAN_MODIFY | libc::FAN_ATTRIB | libc::FAN_CLOSE_WRITE | libc::FAN_ONDIR;
+
+/// Event of the fanotify group
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Event {
+    /// What happened
+    mask: u64,
+    /// File handle of the directory of the entry, none for an overflow
+    directory: Option<Vec<u8>>,
+    /// Name of the entry in the directory
+    name: PathBuf,
+    /// File handle of the old directory and the old name of a
+    /// [libc::FAN_RENAME]
+    from: Option<(Vec<u8>, PathBuf)>,
+}
+
+/// Events in `buffer`, read from a group reporting the directory and the
+/// name of the entries
+fn events(buffer: &[u8]) -> Vec<Event> {
+    let read = |at: usize, len: usize| buffer.get(at..at + len);
+    let u16_at = |at| {
+        read(at, 2).map_or(0, |bytes| {
+            u16::from_ne_bytes([bytes[0], bytes[1]])
+        })
+    };
+    let u32_at = |at| {
+        read(at, 4).map_or(0, |bytes| {
+            u32::from_ne_bytes(bytes.try_into().unwrap_or_default())
+        })
+    };
+    let u64_at = |at| {
+        read(at, 8).map_or(0, |bytes| {
+            u64::from_ne_bytes(bytes.try_into().unwrap_or_default())
+        })
+    };
+    let mut events = Vec::new();
+    let mut at = 0;
+    // fanotify_event_metadata
+    while let len @ 24.. = u32_at(at) as usize {
+        let mut event = Event {
+            mask: u64_at(at + 8),
+            directory: None,
+            name: PathBuf::new(),
+            from: None,
+        };
+        let mut info = at + usize::from(u16_at(at + 6));
+        // fanotify_event_info_fid: the header, the filesystem, the handle
+        // and the name
+        while info + 12 <= at + len {
+            let info_len = usize::from(u16_at(info + 2));
+            if info_len == 0 {
+                break;
+            }
+            let kind = read(info, 1).unwrap_or_default();
+            if [
+                libc::FAN_EVENT_INFO_TYPE_DFID_NAME,
+                libc::FAN_EVENT_INFO_TYPE_OLD_DFID_NAME,
+                libc::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME,
+            ]
+            .iter()
+            .any(|&dfid| kind == [dfid])
+            {
+                let handle_len = u32_at(info + 12) as usize;
+                let handle = read(info + 12, 8 + handle_len).unwrap_or_default();
+                let name = read(
+                    info + 20 + handle_len,
+                    info_len.saturating_sub(20 + handle_len),
+                )
+                .unwrap_or_default();
+                let name = name.split(|&byte| byte == 0).next().unwrap_or_default();
+                let entry = (
+                    handle.to_vec(),
+                    OsStr::from_bytes(name).into(),
+                );
+                match kind == [libc::FAN_EVENT_INFO_TYPE_OLD_DFID_NAME] {
+                    true => event.from = Some(entry),
+                    false => (event.directory, event.name) = (Some(entry.0), entry.1),
+                }
+            }
+            info += info_len;
+        }
+        events.push(event);
+        at += len;
+    }
+    events
+}
+
+/// Change of the source by the event `mask` of the entry at `path`, which
+/// is `inside` the source or outside of it; `from` is the old path of a
+/// [libc::FAN_RENAME] and whether it was inside
+fn change(mask: u64, from: Option<(PathBuf, bool)>, path: PathBuf, inside: bool) -> Option<Operation> {
+    if mask & libc::FAN_RENAME != 0 {
+        return match (from, inside) {
+            (Some((from, true)), true) => Some(Operation::Rename { from, to: path }),
+            // Moved out of the source
+            (Some((path, true)), false) => Some(Operation::Remove { path }),
+            // Moved into the source, or from a directory which is gone
+            (_, true) => Some(Operation::Copy { path }),
+            (_, false) => None,
+        };
+    }
+    if !inside {
+        return None;
+    }
+    // Either name of a rename on kernels without FAN_RENAME
+    match mask & (libc::FAN_DELETE | libc::FAN_MOVED_FROM) != 0 {
+        true => Some(Operation::Remove { path }),
+        false => Some(Operation::Copy { path }),
+    }
+}
+
+/// fanotify group watching the filesystem of the source, an [EventSource]
+#[derive(Debug, Default)]
+pub struct Fanotify {
+    /// Group with the mark and the watched directory, once started
+    watching: Option<(OwnedFd, OwnedFd)>,
+    /// Watched directory with the symbolic links resolved
+    root: PathBuf,
+    /// Whether the entries below the subdirectories are watched
+    recursive: bool,
+}
+
+/// `fd` of a call returning it, the error of the call if it failed
+fn owned(fd: libc::c_int) -> io::Result<OwnedFd> {
+    match fd {
+        // SAFETY: the descriptor was just opened
+        0.. => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+impl Fanotify {
+    /// Path of the directory with the file handle `handle` on the
+    /// filesystem of the directory `mount`, none if it is gone
+    fn directory(mount: &OwnedFd, handle: &[u8]) -> Option<PathBuf> {
+        // file_handle, aligned like its integers
+        let mut aligned = vec![0u32; handle.len().div_ceil(4)];
+        // SAFETY: the buffer holds the handle
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                handle.as_ptr(),
+                aligned.as_mut_ptr().cast(),
+                handle.len(),
+            )
+        };
+        // SAFETY: the buffer holds a file_handle of its `handle_bytes`
+        let directory = owned(unsafe {
+            libc::open_by_handle_at(
+                mount.as_raw_fd(),
+                aligned.as_mut_ptr().cast(),
+                libc::O_PATH | libc::O_CLOEXEC,
+            )
+        })
+        .ok()?;
+        fs::read_link(format!(
+            "/proc/self/fd/{}",
+            directory.as_raw_fd()
+        ))
+        .ok()
+    }
+}
+
+impl EventSource for Fanotify {
+    fn start(&mut self, root: &Path, recursive: bool) -> Result<(), AppError> {
+        let root = fs::canonicalize(root).map_err(|err| AppError::from(err).context("resolve", root))?;
+        let path = CString::new(root.as_os_str().as_bytes()).map_err(|err| AppError::PathErr(err.to_string()))?;
+        let started = || -> io::Result<_> {
+            // SAFETY: plain calls, the path is NUL terminated
+            unsafe {
+                let group = owned(libc::fanotify_init(
+                    libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK | libc::FAN_REPORT_DFID_NAME,
+                    (libc::O_RDONLY | libc::O_CLOEXEC) as libc::c_uint,
+                ))?;
+                let mark = |mask| match libc::fanotify_mark(
+                    group.as_raw_fd(),
+                    libc::FAN_MARK_ADD | libc::FAN_MARK_FILESYSTEM,
+                    mask,
+                    libc::AT_FDCWD,
+                    path.as_ptr(),
+                ) {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                };
+                match mark(MASK | libc::FAN_RENAME) {
+                    // Before Linux 5.17
+                    Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                        tracing::debug!("fanotify without FAN_RENAME, renames are synchronised as removals and copies");
+                        mark(MASK | libc::FAN_MOVED_FROM | libc::FAN_MOVED_TO)?;
+                    }
+                    result => result?,
+                }
+                let mount = owned(libc::open(
+                    path.as_ptr(),
+                    // open_by_handle_at() takes no O_PATH descriptors
+                    libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+                ))?;
+                Ok((group, mount))
+            }
+        };
+        self.watching = Some(started().map_err(|err| AppError::from(err).context("watch the filesystem of", &root))?);
+        self.root = root;
+        self.recursive = recursive;
+        Ok(())
+    }
+
+    fn poll(&mut self, timeout: Duration) -> Result<Poll, AppError> {
+        let Some((group, mount)) = &self.watching else {
+            return Ok(Poll::Closed);
+        };
+        let mut ready = libc::pollfd {
+            fd: group.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        // SAFETY: one pollfd is passed
+        match unsafe { libc::poll(&mut ready, 1, timeout) } {
+            0 => return Ok(Poll::Idle),
+            1.. => {}
+            _ => match io::Error::last_os_error() {
+                err if err.kind() == io::ErrorKind::Interrupted => return Ok(Poll::Idle),
+                err => return Err(err.into()),
+            },
+        }
+        let mut buffer = vec![0u8; BUFFER];
+        // SAFETY: the buffer holds BUFFER bytes
+        let len = match unsafe {
+            libc::read(
+                group.as_raw_fd(),
+                buffer.as_mut_ptr().cast(),
+                BUFFER,
+            )
+        } {
+            len @ 0.. => len as usize,
+            _ => match io::Error::last_os_error() {
+                err if err.kind() == io::ErrorKind::WouldBlock => return Ok(Poll::Idle),
+                err => return Err(err.into()),
+            },
+        };
+        let mut directories = HashMap::new();
+        let mut operations = Vec::new();
+        for event in events(&buffer[..len]) {
+            if event.mask & libc::FAN_Q_OVERFLOW != 0 {
+                tracing::warn!(
+                    "the fanotify queue overflowed, rescanning {}",
+                    self.root.display()
+                );
+                return Ok(Poll::Rescan(self.root.clone()));
+            }
+            let Some(handle) = event.directory else {
+                continue;
+            };
+            let mut resolve = |handle: Vec<u8>, name: &Path| {
+                let parent = directories
+                    .entry(handle)
+                    .or_insert_with_key(|handle| Self::directory(mount, handle))
+                    .clone()?;
+                let inside = match self.recursive {
+                    true => parent.starts_with(&self.root),
+                    false => parent == self.root,
+                };
+                Some((parent.join(name), inside))
+            };
+            let from = event.from.and_then(|(handle, name)| resolve(handle, &name));
+            let Some((path, inside)) = resolve(handle, &event.name) else {
+                continue;
+            };
+            operations.extend(change(event.mask, from, path, inside));
+        }
+        Ok(Poll::Changes(operations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_pairs_the_events() {
+        // fanotify_event_metadata with a fanotify_event_info_fid of a
+        // 4 byte handle and the name "a.txt"
+        let mut info = vec![libc::FAN_EVENT_INFO_TYPE_DFID_NAME, 0, 32, 0];
+        info.extend([0; 8]);
+        info.extend(4u32.to_ne_bytes());
+        info.extend(1i32.to_ne_bytes());
+        info.extend([1, 2, 3, 4]);
+        info.extend(b"a.txt\0\0\0");
+        let mut buffer = (24 + info.len() as u32).to_ne_bytes().to_vec();
+        buffer.extend([3, 0]);
+        buffer.extend(24u16.to_ne_bytes());
+        buffer.extend(libc::FAN_CLOSE_WRITE.to_ne_bytes());
+        buffer.extend((-1i32).to_ne_bytes());
+        buffer.extend(1i32.to_ne_bytes());
+        buffer.extend(&info);
+        let mut handle = 4u32.to_ne_bytes().to_vec();
+        handle.extend(1i32.to_ne_bytes());
+        handle.extend([1, 2, 3, 4]);
+        assert_eq!(
+            events(&buffer),
+            [Event {
+                mask: libc::FAN_CLOSE_WRITE,
+                directory: Some(handle.clone()),
+                name: "a.txt".into(),
+                from: None,
+            }]
+        );
+
+        // A rename with the old name first
+        let mut renamed = (28 + 2 * info.len() as u32).to_ne_bytes().to_vec();
+        renamed.extend([3, 0]);
+        renamed.extend(24u16.to_ne_bytes());
+        renamed.extend(libc::FAN_RENAME.to_ne_bytes());
+        renamed.extend((-1i32).to_ne_bytes());
+        renamed.extend(1i32.to_ne_bytes());
+        info[0] = libc::FAN_EVENT_INFO_TYPE_OLD_DFID_NAME;
+        renamed.extend(&info);
+        info[0] = libc::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME;
+        info[24..29].copy_from_slice(b"b.txt");
+        renamed.extend(&info);
+        renamed.extend([0; 4]);
+        assert_eq!(
+            events(&renamed),
+            [Event {
+                mask: libc::FAN_RENAME,
+                directory: Some(handle.clone()),
+                name: "b.txt".into(),
+                from: Some((handle, "a.txt".into())),
+            }]
+        );
+
+        let change = |mask, from: Option<(&str, bool)>, path: &str, inside| {
+            change(
+                mask,
+                from.map(|(from, inside)| (from.into(), inside)),
+                path.into(),
+                inside,
+            )
+        };
+        assert_eq!(
+            change(
+                libc::FAN_CLOSE_WRITE,
+                None,
+                "/src/a",
+                true
+            ),
+            Some(Operation::Copy { path: "/src/a".into() })
+        );
+        assert_eq!(
+            change(libc::FAN_ATTRIB, None, "/src/a", true),
+            Some(Operation::Copy { path: "/src/a".into() })
+        );
+        assert_eq!(
+            change(
+                libc::FAN_RENAME,
+                Some(("/src/a", true)),
+                "/src/b",
+                true
+            ),
+            Some(Operation::Rename {
+                from: "/src/a".into(),
+                to: "/src/b".into()
+            })
+        );
+        assert_eq!(
+            change(
+                libc::FAN_RENAME,
+                Some(("/src/b", true)),
+                "/other/b",
+                false
+            ),
+            Some(Operation::Remove { path: "/src/b".into() })
+        );
+        assert_eq!(
+            change(
+                libc::FAN_RENAME,
+                Some(("/other/c", false)),
+                "/src/c",
+                true
+            ),
+            Some(Operation::Copy { path: "/src/c".into() })
+        );
+        // Without FAN_RENAME
+        assert_eq!(
+            change(
+                libc::FAN_MOVED_FROM,
+                None,
+                "/src/a",
+                true
+            ),
+            Some(Operation::Remove { path: "/src/a".into() })
+        );
+        assert_eq!(
+            change(libc::FAN_MOVED_TO, None, "/src/b", true),
+            Some(Operation::Copy { path: "/src/b".into() })
+        );
+        assert_eq!(
+            change(
+                libc::FAN_DELETE | libc::FAN_ONDIR,
+                None,
+                "/src/c",
+                true
+            ),
+            Some(Operation::Remove { path: "/src/c".into() })
+        );
+        assert_eq!(
+            change(
+                libc::FAN_CREATE,
+                None,
+                "/other/d",
+                false
+            ),
+            None
+        );
+    }
+}
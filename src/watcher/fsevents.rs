@@ -0,0 +1,439 @@
+//! FSEvents stream of the `[watcher.fsevents]` section.
+//!
+//! FSEvents reports the paths which changed, and with `file_events` unset
+//! or once it fell behind only the directories with a change somewhere
+//! below them. Those are handed to the watch loop as [Poll::Rescan], which
+//! compares the directory with its stored copy.
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use super::{Operation, Poll};
+
+/// Something below the path changed, its subtree has to be compared
+const MUST_SCAN_SUB_DIRS: u32 = 0x1;
+/// Events were dropped by the client, or by the kernel
+const DROPPED: u32 = 0x2 | 0x4;
+/// The entry at the path was created, removed or its contents modified
+const CONTENTS: u32 = 0x100 | 0x200 | 0x1000;
+/// The entry was renamed from or to the path
+const RENAMED: u32 = 0x800;
+
+/// `[watcher.fsevents]` section of the configuration file
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+#[derive(Debug, Clone, Deserialize)]
+pub struct FsEventsConfig {
+    /// Time FSEvents collects the changes before delivering them
+    #[serde(default, with = "crate::config::humantime_serde")]
+    pub(crate) latency: Duration,
+    /// Whether the changed files are reported, otherwise only their
+    /// directories
+    #[serde(default = "FsEventsConfig::enabled")]
+    pub(crate) file_events: bool,
+    /// Whether the first change after a quiet time is delivered at once,
+    /// otherwise after the latency
+    #[serde(default = "FsEventsConfig::enabled")]
+    pub(crate) no_defer: bool,
+    /// Whether the changes fsync makes itself, the files removed with
+    /// `--move`, are left out
+    #[serde(default)]
+    pub(crate) ignore_self: bool,
+}
+
+impl FsEventsConfig {
+    /// Default of the flags which are set unless configured otherwise
+    fn enabled() -> bool {
+        true
+    }
+}
+
+/// Translation of the events of the stream into polls
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+#[derive(Debug, Default)]
+struct Changes {
+    /// Watched directory, as FSEvents reports it
+    root: PathBuf,
+    /// Whether the directories below the root are watched
+    recursive: bool,
+    /// Whether the changed files are reported, otherwise their directories
+    file_events: bool,
+    /// Path a rename left, until the event of the path it went to
+    renamed: Option<PathBuf>,
+}
+
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+impl Changes {
+    /// Polls of the changed `paths` with their flags, `exists` tells
+    /// whether an entry is still there
+    fn polls(&mut self, paths: Vec<(PathBuf, u32)>, exists: impl Fn(&Path) -> bool) -> VecDeque<Poll> {
+        let mut polls = VecDeque::new();
+        let mut operations = Vec::new();
+        for (path, flags) in paths {
+            let watched = match self.recursive {
+                true => path.starts_with(&self.root),
+                false => path == self.root || path.parent() == Some(&self.root),
+            };
+            if !watched {
+                continue;
+            }
+            let rescan = if flags & DROPPED != 0 {
+                Some(self.root.clone())
+            } else if flags & MUST_SCAN_SUB_DIRS != 0 || !self.file_events {
+                Some(path.clone())
+            } else {
+                None
+            };
+            if let Some(dir) = rescan {
+                if !polls.back().is_some_and(|poll| *poll == Poll::Rescan(dir.clone())) {
+                    polls.extend((!operations.is_empty()).then(|| Poll::Changes(std::mem::take(&mut operations))));
+                    polls.push_back(Poll::Rescan(dir));
+                }
+                continue;
+            }
+            if flags & RENAMED != 0 {
+                match (exists(&path), self.renamed.take()) {
+                    (false, left) => {
+                        // Moved out of the source, unless another path follows
+                        operations.extend(left.map(|path| Operation::Remove { path }));
+                        self.renamed = Some(path);
+                    }
+                    (true, Some(from)) => operations.push(Operation::Rename { from, to: path }),
+                    // Moved in from outside
+                    (true, None) => operations.push(Operation::Copy { path }),
+                }
+            } else if flags & CONTENTS != 0 {
+                operations.push(match exists(&path) {
+                    true => Operation::Copy { path },
+                    false => Operation::Remove { path },
+                });
+            }
+        }
+        polls.extend((!operations.is_empty()).then_some(Poll::Changes(operations)));
+        polls
+    }
+
+    /// Removal of the path a rename left if none followed it
+    fn expire(&mut self) -> Option<Operation> {
+        self.renamed.take().map(|path| Operation::Remove { path })
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub use macos::FsEvents;
+
+/// The stream, through the CoreServices framework
+#[cfg(target_os = "macos")]
+mod macos {
+    use std::{
+        collections::VecDeque,
+        ffi::{c_char, c_void, CStr, CString, OsStr},
+        fs,
+        os::unix::ffi::OsStrExt,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::{self, Receiver, RecvTimeoutError, Sender},
+            Arc,
+        },
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use fsevent_sys::{self as sys, core_foundation as cf};
+
+    use super::{Changes, FsEventsConfig};
+    use crate::{
+        app::Context,
+        watcher::{EventSource, Poll},
+        AppError,
+    };
+
+    /// Changed paths with their flags, of one callback
+    type Batch = Vec<(PathBuf, u32)>;
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        /// Runs the run loop of the thread for up to `seconds`
+        fn CFRunLoopRunInMode(mode: cf::CFStringRef, seconds: f64, return_after_source_handled: cf::Boolean) -> i32;
+    }
+
+    /// Thread running the stream until it is dropped
+    #[derive(Debug)]
+    struct Stream {
+        /// Set to stop the thread
+        stop: Arc<AtomicBool>,
+        /// Thread of the run loop
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Stream {
+        /// Starts a stream of the changes below `root`
+        fn start(root: &Path, config: &FsEventsConfig) -> Result<(Self, Receiver<Batch>), AppError> {
+            let path = CString::new(root.as_os_str().as_bytes()).map_err(|_| {
+                AppError::PathErr(format!(
+                    "{} can not be watched",
+                    root.display()
+                ))
+            })?;
+            let mut flags = sys::kFSEventStreamCreateFlagNone;
+            for (set, flag) in [
+                (
+                    config.file_events,
+                    sys::kFSEventStreamCreateFlagFileEvents,
+                ),
+                (
+                    config.no_defer,
+                    sys::kFSEventStreamCreateFlagNoDefer,
+                ),
+                (
+                    config.ignore_self,
+                    sys::kFSEventStreamCreateFlagIgnoreSelf,
+                ),
+            ] {
+                if set {
+                    flags |= flag;
+                }
+            }
+            let latency = config.latency.as_secs_f64();
+            let (tx, rx) = mpsc::channel();
+            let (started_tx, started_rx) = mpsc::channel();
+            let stop = Arc::<AtomicBool>::default();
+            let stopped = stop.clone();
+            let thread = thread::Builder::new()
+                .name("fsevents".into())
+                .spawn(move || {
+                    let tx: *mut Sender<Batch> = Box::into_raw(Box::new(tx));
+                    // SAFETY: the stream only calls back on this thread, while
+                    // its run loop runs, and is released before the sender
+                    unsafe {
+                        let paths = cf::CFArrayCreateMutable(
+                            cf::kCFAllocatorDefault,
+                            1,
+                            &cf::kCFTypeArrayCallBacks,
+                        );
+                        let string = cf::CFStringCreateWithCString(
+                            cf::kCFAllocatorDefault,
+                            path.as_ptr(),
+                            cf::kCFStringEncodingUTF8,
+                        );
+                        cf::CFArrayAppendValue(paths, string);
+                        cf::CFRelease(string);
+                        let context = sys::FSEventStreamContext {
+                            version: 0,
+                            info: tx.cast(),
+                            retain: None,
+                            release: None,
+                            copy_description: None,
+                        };
+                        let stream = sys::FSEventStreamCreate(
+                            cf::kCFAllocatorDefault,
+                            callback,
+                            &context,
+                            paths,
+                            sys::kFSEventStreamEventIdSinceNow,
+                            latency,
+                            flags,
+                        );
+                        cf::CFRelease(paths);
+                        sys::FSEventStreamScheduleWithRunLoop(
+                            stream,
+                            cf::CFRunLoopGetCurrent(),
+                            cf::kCFRunLoopDefaultMode,
+                        );
+                        let started = sys::FSEventStreamStart(stream) != 0;
+                        let _ = started_tx.send(started);
+                        while started && !stopped.load(Ordering::Relaxed) {
+                            CFRunLoopRunInMode(cf::kCFRunLoopDefaultMode, 0.1, 0);
+                        }
+                        if started {
+                            sys::FSEventStreamStop(stream);
+                        }
+                        sys::FSEventStreamInvalidate(stream);
+                        sys::FSEventStreamRelease(stream);
+                        drop(Box::from_raw(tx));
+                    }
+                })
+                .context("start the FSEvents thread for", root)?;
+            let stream = Self {
+                stop,
+                thread: Some(thread),
+            };
+            match started_rx.recv() {
+                Ok(true) => Ok((stream, rx)),
+                _ => Err(AppError::PathErr(format!(
+                    "FSEvents can not watch {}",
+                    root.display()
+                ))),
+            }
+        }
+    }
+
+    impl Drop for Stream {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Relaxed);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// Sends the changed paths of a callback to the [Sender] of `info`
+    extern "C" fn callback(
+        _stream: sys::FSEventStreamRef,
+        info: *mut c_void,
+        count: usize,
+        paths: *mut c_void,
+        flags: *const sys::FSEventStreamEventFlags,
+        _ids: *const sys::FSEventStreamEventId,
+    ) {
+        // SAFETY: without `UseCFTypes` the paths are `count` C strings, and
+        // `info` is the sender of the stream
+        let (tx, batch) = unsafe {
+            let paths = paths as *const *const c_char;
+            let batch: Batch = (0..count)
+                .map(|index| {
+                    let path = CStr::from_ptr(*paths.add(index));
+                    (
+                        PathBuf::from(OsStr::from_bytes(path.to_bytes())),
+                        *flags.add(index),
+                    )
+                })
+                .collect();
+            (&*(info as *const Sender<Batch>), batch)
+        };
+        let _ = tx.send(batch);
+    }
+
+    /// FSEvents stream with the latency and the flags of a
+    /// [FsEventsConfig], instead of the ones of the
+    /// [NotifyWatcher](crate::watcher::NotifyWatcher)
+    #[derive(Debug)]
+    pub struct FsEvents {
+        /// Latency and flags of the stream
+        config: FsEventsConfig,
+        /// Stream with its changes, once started
+        watching: Option<(Stream, Receiver<Batch>)>,
+        /// Translation of the changes
+        changes: Changes,
+        /// Polls of the last changes not returned yet
+        pending: VecDeque<Poll>,
+    }
+
+    impl FsEvents {
+        /// Stream of `config`
+        pub fn new(config: FsEventsConfig) -> Self {
+            Self {
+                config,
+                watching: None,
+                changes: Changes::default(),
+                pending: VecDeque::new(),
+            }
+        }
+    }
+
+    impl EventSource for FsEvents {
+        fn start(&mut self, root: &Path, recursive: bool) -> Result<(), AppError> {
+            // FSEvents reports the paths with the symbolic links resolved
+            let root = fs::canonicalize(root).context("resolve", root)?;
+            self.watching = Some(Stream::start(&root, &self.config)?);
+            self.changes = Changes {
+                root,
+                recursive,
+                file_events: self.config.file_events,
+                renamed: None,
+            };
+            Ok(())
+        }
+
+        fn poll(&mut self, timeout: Duration) -> Result<Poll, AppError> {
+            if let Some(poll) = self.pending.pop_front() {
+                return Ok(poll);
+            }
+            let Some((_, rx)) = &self.watching else {
+                return Ok(Poll::Closed);
+            };
+            let batch = match rx.recv_timeout(timeout) {
+                Ok(batch) => batch,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Ok(match self.changes.expire() {
+                        Some(operation) => Poll::Changes(vec![operation]),
+                        None => Poll::Idle,
+                    })
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(Poll::Closed),
+            };
+            tracing::trace!("FSEvents: {batch:?}");
+            self.pending = self.changes.polls(batch, |path| {
+                fs::symlink_metadata(path).is_ok()
+            });
+            Ok(self.pending.pop_front().unwrap_or(Poll::Changes(Vec::new())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turns_the_events_into_changes_and_rescans() {
+        let mut changes = Changes {
+            root: "/src".into(),
+            recursive: true,
+            file_events: true,
+            renamed: None,
+        };
+        let exists = |path: &Path| {
+            ["/src/b", "/src/c", "/src/d"]
+                .iter()
+                .any(|existing| path == Path::new(existing))
+        };
+        let polls = changes.polls(
+            vec![
+                ("/src/a".into(), RENAMED),
+                ("/src/b".into(), RENAMED),
+                ("/src/c".into(), 0x100 | 0x1000),
+                ("/src/e".into(), 0x200),
+                ("/src/d".into(), 0x400),
+                ("/src/dir".into(), MUST_SCAN_SUB_DIRS),
+                ("/other/f".into(), 0x100),
+                ("/src/gone".into(), RENAMED),
+            ],
+            exists,
+        );
+        assert_eq!(
+            polls,
+            [
+                Poll::Changes(vec![
+                    Operation::Rename {
+                        from: "/src/a".into(),
+                        to: "/src/b".into()
+                    },
+                    Operation::Copy { path: "/src/c".into() },
+                    Operation::Remove { path: "/src/e".into() },
+                ]),
+                Poll::Rescan("/src/dir".into()),
+            ]
+        );
+        // Moved out of the source
+        assert_eq!(
+            changes.expire(),
+            Some(Operation::Remove {
+                path: "/src/gone".into()
+            })
+        );
+        changes.file_events = false;
+        assert_eq!(
+            changes.polls(
+                vec![("/src/dir".into(), 0), ("/src/dir".into(), 0), ("/src".into(), DROPPED),],
+                exists
+            ),
+            [Poll::Rescan("/src/dir".into()), Poll::Rescan("/src".into())]
+        );
+    }
+}
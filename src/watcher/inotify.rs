@@ -0,0 +1,109 @@
+//! Watch limit of inotify.
+//!
+//! inotify needs a watch for every directory of the source, and a user has
+//! at most `fs.inotify.max_user_watches` of them. The initial scan counts
+//! the directories and warns with the `sysctl` raising the limit if they
+//! do not fit. Once [NotifyWatcher](super::NotifyWatcher) runs out of
+//! watches, the subtrees left without one are polled every
+//! `poll_unwatched` of the `[watcher]` section:
+//!
+//! ```toml
+//! [watcher]
+//! poll_unwatched = "1m"
+//! ```
+//!
+//! Without it the watch does not start.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Limit of the watches of a user
+const MAX_USER_WATCHES: &str = "/proc/sys/fs/inotify/max_user_watches";
+
+/// Watches a user may have, none if the limit can not be read
+pub(crate) fn max_user_watches() -> Option<usize> {
+    fs::read_to_string(MAX_USER_WATCHES).ok()?.trim().parse().ok()
+}
+
+/// Limit fitting `dirs` directories, with a quarter of them more for the
+/// new ones and the other watchers of the user, rounded up to a power of
+/// two
+fn needed(dirs: usize) -> usize {
+    dirs.saturating_add(dirs / 4).next_power_of_two()
+}
+
+/// Commands raising the limit to fit `dirs` directories, now and after a
+/// reboot
+pub(crate) fn sysctl(dirs: usize) -> String {
+    let needed = needed(dirs);
+    format!(
+        "`sudo sysctl fs.inotify.max_user_watches={needed}`, and \
+         `echo fs.inotify.max_user_watches={needed} | sudo tee /etc/sysctl.d/90-fsync.conf` to keep it"
+    )
+}
+
+/// Warns if the `dirs` directories of a source do not fit the limit
+pub(crate) fn check(dirs: usize) {
+    match max_user_watches() {
+        Some(limit) if dirs > limit => tracing::warn!(
+            "the source has {dirs} directories, more than the {limit} inotify watches of {MAX_USER_WATCHES}; \
+             changes in the directories left without a watch are missed, raise the limit with {}",
+            sysctl(dirs)
+        ),
+        _ => tracing::debug!("{dirs} directories to watch"),
+    }
+}
+
+/// Directories below `root`, the ones the watcher of [notify] walks
+pub(crate) fn directories(root: &Path) -> impl Iterator<Item = PathBuf> {
+    walkdir::WalkDir::new(root)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_dir())
+        .map(walkdir::DirEntry::into_path)
+}
+
+/// Roots of the subtrees of `root` without a watch, once adding the one of
+/// the directory `failed` ran out of watches.
+///
+/// The watcher walks the directories in the same order and stops at the
+/// first failing one, so it and every directory after it are unwatched.
+pub(crate) fn unwatched(root: &Path, failed: &Path) -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for dir in directories(root).skip_while(|dir| dir != failed) {
+        if !roots.last().is_some_and(|last| dir.starts_with(last)) {
+            roots.push(dir);
+        }
+    }
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_the_limit_and_finds_the_unwatched_subtrees() {
+        assert_eq!(needed(100_000), 131_072);
+        assert_eq!(needed(120_000), 262_144);
+        assert!(sysctl(100_000).contains("fs.inotify.max_user_watches=131072"));
+
+        let root = std::env::temp_dir().join(format!(
+            "fsync-inotify-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(root.join("a/b/c")).unwrap();
+        fs::create_dir_all(root.join("a/d")).unwrap();
+        fs::write(root.join("a/file"), "").unwrap();
+        assert_eq!(directories(&root).count(), 5);
+        assert_eq!(
+            unwatched(&root, &root.join("a")),
+            [root.join("a")]
+        );
+        assert!(unwatched(&root, &root.join("missing")).is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
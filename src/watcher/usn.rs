@@ -0,0 +1,634 @@
+//! Change journal of NTFS volumes.
+//!
+//! NTFS records every change of a volume in its USN journal, numbered by
+//! a growing update sequence number. `UsnJournal` reads the records of the
+//! source from the volume and keeps the number it read up to in a state
+//! file next to the queue, so the changes made while fsync was not running
+//! are replayed on startup instead of comparing the whole source. Reading
+//! the journal takes administrator rights.
+//!
+//! The records name an entry by its file reference and the one of its
+//! parent directory, the paths are resolved through the parent, so the
+//! entries which were removed are found too.
+
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::queue::Operation;
+
+/// The data of the entry was overwritten
+const DATA_OVERWRITE: u32 = 0x0000_0001;
+/// Data was added to the entry
+const DATA_EXTEND: u32 = 0x0000_0002;
+/// The entry was truncated
+const DATA_TRUNCATION: u32 = 0x0000_0004;
+/// The entry was created
+const FILE_CREATE: u32 = 0x0000_0100;
+/// The entry was removed
+const FILE_DELETE: u32 = 0x0000_0200;
+/// The entry was renamed, the record has the old name
+const RENAME_OLD_NAME: u32 = 0x0000_1000;
+/// The entry was renamed, the record has the new name
+const RENAME_NEW_NAME: u32 = 0x0000_2000;
+/// Its attributes or times changed
+const BASIC_INFO_CHANGE: u32 = 0x0000_8000;
+/// The last handle of the entry was closed, the record sums up the changes
+/// made through it
+const CLOSE: u32 = 0x8000_0000;
+
+/// Position in the journal of a volume, saved between runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(not(windows), allow(dead_code))]
+struct Cursor {
+    /// Identifier of the journal, a new one is made when it is recreated
+    journal: u64,
+    /// Number of the next record to read
+    usn: i64,
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+impl Cursor {
+    /// Cursor saved at `path`, none if there is none or it can not be read
+    fn load(path: &Path) -> Option<Self> {
+        let saved = fs::read(path).ok()?;
+        serde_json::from_slice(&saved)
+            .inspect_err(|err| tracing::warn!("{}: {err}", path.display()))
+            .ok()
+    }
+
+    /// Saves the cursor at `path`
+    ///
+    /// # Errors
+    ///
+    /// Errors writing the file are returned.
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec(self)?)?;
+        fs::rename(temporary, path)
+    }
+}
+
+/// Record of the journal
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(windows), allow(dead_code))]
+struct Record {
+    /// File reference of the parent directory
+    parent: u64,
+    /// What changed
+    reason: u32,
+    /// Name of the entry
+    name: OsString,
+}
+
+/// Number of the record following the ones in `buffer`, the output of
+/// `FSCTL_READ_USN_JOURNAL`, with the records of version 2
+#[cfg_attr(not(windows), allow(dead_code))]
+fn records(buffer: &[u8]) -> (i64, Vec<Record>) {
+    let read = |at: usize, len: usize| buffer.get(at..at + len);
+    let u16_at = |at| {
+        read(at, 2).map_or(0, |bytes| {
+            u16::from_le_bytes([bytes[0], bytes[1]])
+        })
+    };
+    let u32_at = |at| {
+        read(at, 4).map_or(0, |bytes| {
+            u32::from_le_bytes(bytes.try_into().unwrap_or_default())
+        })
+    };
+    let u64_at = |at| {
+        read(at, 8).map_or(0, |bytes| {
+            u64::from_le_bytes(bytes.try_into().unwrap_or_default())
+        })
+    };
+    let next = u64_at(0) as i64;
+    let mut records = Vec::new();
+    let mut at = 8;
+    while let len @ 1.. = u32_at(at) as usize {
+        // USN_RECORD_V2
+        if u16_at(at + 4) == 2 {
+            let (name_len, name_at) = (
+                usize::from(u16_at(at + 56)),
+                usize::from(u16_at(at + 58)),
+            );
+            let name: Vec<u16> = read(at + name_at, name_len)
+                .unwrap_or_default()
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .collect();
+            records.push(Record {
+                parent: u64_at(at + 16),
+                reason: u32_at(at + 40),
+                name: String::from_utf16_lossy(&name).into(),
+            });
+        }
+        at += len;
+    }
+    (next, records)
+}
+
+/// Turns the records into the changes of the source
+#[derive(Debug, Default)]
+#[cfg_attr(not(windows), allow(dead_code))]
+struct Changes {
+    /// Old path of a rename below the source, until the record with the new
+    /// one
+    renamed: Option<PathBuf>,
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+impl Changes {
+    /// Change of the record with `reason` of the entry at `path`, which is
+    /// `inside` the source or outside of it. Renames are told by the
+    /// records of their names, the other changes once the entry is closed.
+    fn change(&mut self, reason: u32, path: PathBuf, inside: bool) -> Option<Operation> {
+        if reason & CLOSE == 0 {
+            if reason & RENAME_OLD_NAME != 0 {
+                self.renamed = inside.then_some(path);
+            } else if reason & RENAME_NEW_NAME != 0 {
+                return match (self.renamed.take(), inside) {
+                    (Some(from), true) => Some(Operation::Rename { from, to: path }),
+                    // Moved out of the source
+                    (Some(path), false) => Some(Operation::Remove { path }),
+                    // Moved into the source
+                    (None, true) => Some(Operation::Copy { path }),
+                    (None, false) => None,
+                };
+            }
+            return None;
+        }
+        if !inside {
+            return None;
+        }
+        if reason & FILE_DELETE != 0 {
+            return Some(Operation::Remove { path });
+        }
+        (reason & (FILE_CREATE | DATA_OVERWRITE | DATA_EXTEND | DATA_TRUNCATION | BASIC_INFO_CHANGE) != 0)
+            .then_some(Operation::Copy { path })
+    }
+}
+
+#[cfg(windows)]
+pub use windows::UsnJournal;
+
+/// Calls of the Win32 change journal API
+#[cfg(windows)]
+mod windows {
+    use std::{
+        collections::HashMap,
+        ffi::OsString,
+        io, mem,
+        os::windows::ffi::{OsStrExt, OsStringExt},
+        path::{Path, PathBuf},
+        ptr,
+        time::{Duration, Instant},
+    };
+
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, ERROR_JOURNAL_ENTRY_DELETED, GENERIC_READ, HANDLE, INVALID_HANDLE_VALUE},
+        Storage::FileSystem::{
+            CreateFileW, FileIdType, GetFinalPathNameByHandleW, GetVolumeNameForVolumeMountPointW, GetVolumePathNameW,
+            OpenFileById, FILE_FLAG_BACKUP_SEMANTICS, FILE_ID_DESCRIPTOR, FILE_ID_DESCRIPTOR_0, FILE_READ_ATTRIBUTES,
+            FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_EXISTING,
+        },
+        System::{
+            Ioctl::{FSCTL_QUERY_USN_JOURNAL, FSCTL_READ_USN_JOURNAL, READ_USN_JOURNAL_DATA_V0, USN_JOURNAL_DATA_V0},
+            IO::DeviceIoControl,
+        },
+    };
+
+    use super::{records, Changes, Cursor};
+    use crate::{
+        queue::Operation,
+        watcher::{EventSource, Poll},
+        AppError,
+    };
+
+    /// Bytes of the records read at once
+    const BUFFER: usize = 64 * 1024;
+
+    /// Pause between two reads of a journal without new records
+    const PAUSE: Duration = Duration::from_millis(100);
+
+    /// NUL terminated UTF-16 copy of `path`
+    fn wide(path: &Path) -> Vec<u16> {
+        path.as_os_str().encode_wide().chain([0]).collect()
+    }
+
+    /// Handle closed when dropped
+    #[derive(Debug)]
+    struct Handle(HANDLE);
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            // SAFETY: the handle was opened and is closed once
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    /// Handle of `opened`, the error of the last call if it failed
+    fn handle(opened: HANDLE) -> io::Result<Handle> {
+        match opened {
+            INVALID_HANDLE_VALUE => Err(io::Error::last_os_error()),
+            handle => Ok(Handle(handle)),
+        }
+    }
+
+    /// Path of the entry `handle` is open on, as `\\?\C:\...`
+    fn final_path(handle: &Handle) -> io::Result<PathBuf> {
+        let mut path = vec![0u16; 1024];
+        loop {
+            // SAFETY: the buffer holds `path.len()` characters
+            let len = unsafe {
+                GetFinalPathNameByHandleW(
+                    handle.0,
+                    path.as_mut_ptr(),
+                    path.len() as u32,
+                    0,
+                )
+            } as usize;
+            match len {
+                0 => return Err(io::Error::last_os_error()),
+                // Too small, `len` counts the NUL
+                len if len > path.len() => path.resize(len, 0),
+                len => return Ok(OsString::from_wide(&path[..len]).into()),
+            }
+        }
+    }
+
+    /// Change journal of the volume of the source, an [EventSource]
+    #[derive(Debug)]
+    pub struct UsnJournal {
+        /// File the position in the journal is saved in
+        state: PathBuf,
+        /// Volume of the source with the journal, once opened
+        volume: Option<Handle>,
+        /// Watched directory as resolved by the volume, once opened
+        root: PathBuf,
+        /// Whether the entries below the subdirectories are watched
+        recursive: bool,
+        /// Position of the records handed out
+        cursor: Cursor,
+        /// Position saved last
+        saved: Option<Cursor>,
+        /// Pairs the names of the renames
+        changes: Changes,
+    }
+
+    impl UsnJournal {
+        /// Journal whose position is saved in `state`
+        pub fn new(state: PathBuf) -> Self {
+            Self {
+                saved: Cursor::load(&state),
+                state,
+                volume: None,
+                root: PathBuf::new(),
+                recursive: true,
+                cursor: Cursor { journal: 0, usn: 0 },
+                changes: Changes::default(),
+            }
+        }
+
+        /// Opens the volume of `root` and finds the end of its journal
+        ///
+        /// # Errors
+        ///
+        /// Errors opening the volume or querying its journal are returned,
+        /// e.g. for volumes which are not NTFS or without administrator
+        /// rights.
+        fn open(&mut self, root: &Path, recursive: bool) -> Result<(), AppError> {
+            if self.volume.is_some() {
+                return Ok(());
+            }
+            let mut mount = vec![0u16; 1024];
+            let mut name = vec![0u16; 64];
+            // SAFETY: the strings are NUL terminated, the buffers hold their
+            // lengths
+            let volume = unsafe {
+                if GetVolumePathNameW(
+                    wide(root).as_ptr(),
+                    mount.as_mut_ptr(),
+                    mount.len() as u32,
+                ) == 0
+                    || GetVolumeNameForVolumeMountPointW(
+                        mount.as_ptr(),
+                        name.as_mut_ptr(),
+                        name.len() as u32,
+                    ) == 0
+                {
+                    return Err(AppError::from(io::Error::last_os_error()).context("find the volume of", root));
+                }
+                // \\?\Volume{...}\ without the last backslash opens the volume
+                let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                name.truncate(len.saturating_sub(1));
+                name.push(0);
+                handle(CreateFileW(
+                    name.as_ptr(),
+                    GENERIC_READ,
+                    FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+                    ptr::null(),
+                    OPEN_EXISTING,
+                    0,
+                    0,
+                ))
+                .map_err(|err| AppError::from(err).context("open the volume of", root))?
+            };
+            let mut journal: USN_JOURNAL_DATA_V0 = unsafe { mem::zeroed() };
+            let mut returned = 0;
+            // SAFETY: the output is a USN_JOURNAL_DATA_V0
+            if unsafe {
+                DeviceIoControl(
+                    volume.0,
+                    FSCTL_QUERY_USN_JOURNAL,
+                    ptr::null(),
+                    0,
+                    ptr::addr_of_mut!(journal).cast(),
+                    mem::size_of::<USN_JOURNAL_DATA_V0>() as u32,
+                    &mut returned,
+                    ptr::null_mut(),
+                )
+            } == 0
+            {
+                return Err(AppError::from(io::Error::last_os_error()).context("query the change journal of", root));
+            }
+            self.root = Self::resolve(root)?;
+            self.recursive = recursive;
+            self.cursor = match self.saved {
+                // Records the journal still has
+                Some(saved) if saved.journal == journal.UsnJournalID && saved.usn >= journal.FirstUsn => saved,
+                _ => Cursor {
+                    journal: journal.UsnJournalID,
+                    usn: journal.NextUsn,
+                },
+            };
+            self.volume = Some(volume);
+            Ok(())
+        }
+
+        /// `root` as the volume resolves the paths of the records
+        fn resolve(root: &Path) -> Result<PathBuf, AppError> {
+            // SAFETY: the path is NUL terminated
+            let root_handle = handle(unsafe {
+                CreateFileW(
+                    wide(root).as_ptr(),
+                    FILE_READ_ATTRIBUTES,
+                    FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+                    ptr::null(),
+                    OPEN_EXISTING,
+                    FILE_FLAG_BACKUP_SEMANTICS,
+                    0,
+                )
+            })
+            .map_err(|err| AppError::from(err).context("open", root))?;
+            final_path(&root_handle).map_err(|err| AppError::from(err).context("resolve", root))
+        }
+
+        /// Path of the directory with the file reference `reference`, none
+        /// if it is gone
+        fn directory(volume: &Handle, reference: u64) -> Option<PathBuf> {
+            let id = FILE_ID_DESCRIPTOR {
+                dwSize: mem::size_of::<FILE_ID_DESCRIPTOR>() as u32,
+                Type: FileIdType,
+                Anonymous: FILE_ID_DESCRIPTOR_0 {
+                    FileId: reference as i64,
+                },
+            };
+            // SAFETY: the descriptor outlives the call
+            let directory = handle(unsafe {
+                OpenFileById(
+                    volume.0,
+                    &id,
+                    FILE_READ_ATTRIBUTES,
+                    FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+                    ptr::null(),
+                    FILE_FLAG_BACKUP_SEMANTICS,
+                )
+            })
+            .ok()?;
+            final_path(&directory).ok()
+        }
+
+        /// Changes of the records from the cursor on, none once the journal
+        /// has no more; [Poll::Rescan] if it dropped records not read yet
+        ///
+        /// # Errors
+        ///
+        /// Errors reading the journal are returned.
+        fn read(&mut self) -> Result<Option<Poll>, AppError> {
+            let Some(volume) = &self.volume else {
+                return Ok(Some(Poll::Closed));
+            };
+            let input = READ_USN_JOURNAL_DATA_V0 {
+                StartUsn: self.cursor.usn,
+                ReasonMask: u32::MAX,
+                ReturnOnlyOnClose: 0,
+                Timeout: 0,
+                BytesToWaitFor: 0,
+                UsnJournalID: self.cursor.journal,
+            };
+            let mut buffer = vec![0u8; BUFFER];
+            let mut returned = 0;
+            // SAFETY: the input is a READ_USN_JOURNAL_DATA_V0, the buffer
+            // holds BUFFER bytes
+            if unsafe {
+                DeviceIoControl(
+                    volume.0,
+                    FSCTL_READ_USN_JOURNAL,
+                    ptr::addr_of!(input).cast(),
+                    mem::size_of::<READ_USN_JOURNAL_DATA_V0>() as u32,
+                    buffer.as_mut_ptr().cast(),
+                    BUFFER as u32,
+                    &mut returned,
+                    ptr::null_mut(),
+                )
+            } == 0
+            {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(ERROR_JOURNAL_ENTRY_DELETED as i32) {
+                    tracing::warn!(
+                        "the change journal dropped records not read yet, rescanning {}",
+                        self.root.display()
+                    );
+                    self.volume = None;
+                    self.saved = None;
+                    let root = self.root.clone();
+                    self.open(&root, self.recursive)?;
+                    return Ok(Some(Poll::Rescan(root)));
+                }
+                return Err(AppError::from(err).context("read the change journal of", &self.root));
+            }
+            buffer.truncate(returned as usize);
+            let (next, records) = records(&buffer);
+            if records.is_empty() && next == self.cursor.usn {
+                return Ok(None);
+            }
+            self.cursor.usn = next;
+            let mut directories = HashMap::new();
+            let mut operations = Vec::new();
+            for record in records {
+                let Some(parent) = directories
+                    .entry(record.parent)
+                    .or_insert_with(|| Self::directory(volume, record.parent))
+                    .clone()
+                else {
+                    continue;
+                };
+                let inside = match self.recursive {
+                    true => parent.starts_with(&self.root),
+                    false => parent == self.root,
+                };
+                operations.extend(self.changes.change(
+                    record.reason,
+                    parent.join(&record.name),
+                    inside,
+                ));
+            }
+            Ok(Some(Poll::Changes(operations)))
+        }
+
+        /// Saves the position of the records handed out, they were applied
+        /// by the time the next ones are requested
+        fn save(&mut self) {
+            if self.volume.is_none() || self.saved == Some(self.cursor) {
+                return;
+            }
+            match self.cursor.save(&self.state) {
+                Ok(()) => self.saved = Some(self.cursor),
+                Err(err) => tracing::warn!(
+                    "could not save the position in the change journal to {}: {err}",
+                    self.state.display()
+                ),
+            }
+        }
+    }
+
+    impl EventSource for UsnJournal {
+        fn start(&mut self, root: &Path, recursive: bool) -> Result<(), AppError> {
+            self.open(root, recursive)
+        }
+
+        fn missed(&mut self, root: &Path, recursive: bool) -> Result<Option<Vec<Operation>>, AppError> {
+            self.open(root, recursive)?;
+            if self.saved != Some(self.cursor) {
+                tracing::info!("no usable position in the change journal, comparing the whole source");
+                return Ok(None);
+            }
+            let mut missed = Vec::new();
+            loop {
+                match self.read()? {
+                    Some(Poll::Changes(operations)) => missed.extend(operations),
+                    None => return Ok(Some(missed)),
+                    // Closed or dropped the records
+                    Some(_) => return Ok(None),
+                }
+            }
+        }
+
+        fn poll(&mut self, timeout: Duration) -> Result<Poll, AppError> {
+            self.save();
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(poll) = self.read()? {
+                    return Ok(poll);
+                }
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Ok(Poll::Idle);
+                }
+                std::thread::sleep(left.min(PAUSE));
+            }
+        }
+    }
+
+    impl Drop for UsnJournal {
+        fn drop(&mut self) {
+            self.save();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_pairs_the_journal_records() {
+        // The next number, then a USN_RECORD_V2 named "a.txt"
+        let name: Vec<u8> = "a.txt".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let mut record = vec![0u8; 60];
+        record[..4].copy_from_slice(&(60 + name.len() as u32).to_le_bytes());
+        record[4..6].copy_from_slice(&2u16.to_le_bytes());
+        record[16..24].copy_from_slice(&7u64.to_le_bytes());
+        record[40..44].copy_from_slice(&(FILE_CREATE | CLOSE).to_le_bytes());
+        record[56..58].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        record[58..60].copy_from_slice(&60u16.to_le_bytes());
+        record.extend(&name);
+        let mut buffer = 42i64.to_le_bytes().to_vec();
+        buffer.extend(&record);
+        assert_eq!(
+            records(&buffer),
+            (
+                42,
+                vec![Record {
+                    parent: 7,
+                    reason: FILE_CREATE | CLOSE,
+                    name: "a.txt".into()
+                }]
+            )
+        );
+
+        let mut changes = Changes::default();
+        let mut change = |reason, path: &str, inside| changes.change(reason, path.into(), inside);
+        assert_eq!(
+            change(FILE_CREATE, "/src/a", true),
+            None
+        );
+        assert_eq!(
+            change(
+                FILE_CREATE | DATA_EXTEND | CLOSE,
+                "/src/a",
+                true
+            ),
+            Some(Operation::Copy { path: "/src/a".into() })
+        );
+        assert_eq!(
+            change(RENAME_OLD_NAME, "/src/a", true),
+            None
+        );
+        assert_eq!(
+            change(RENAME_NEW_NAME, "/src/b", true),
+            Some(Operation::Rename {
+                from: "/src/a".into(),
+                to: "/src/b".into()
+            })
+        );
+        assert_eq!(
+            change(RENAME_NEW_NAME | CLOSE, "/src/b", true),
+            None
+        );
+        assert_eq!(
+            change(RENAME_OLD_NAME, "/src/b", true),
+            None
+        );
+        assert_eq!(
+            change(RENAME_NEW_NAME, "/other/b", false),
+            Some(Operation::Remove { path: "/src/b".into() })
+        );
+        assert_eq!(
+            change(FILE_DELETE | CLOSE, "/src/c", true),
+            Some(Operation::Remove { path: "/src/c".into() })
+        );
+        assert_eq!(
+            change(
+                DATA_OVERWRITE | CLOSE,
+                "/other/d",
+                false
+            ),
+            None
+        );
+    }
+}
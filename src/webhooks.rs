@@ -0,0 +1,394 @@
+//! Webhook notifications.
+//!
+//! Every `[[webhooks]]` entry POSTs its template to its URL when one of its
+//! events happens:
+//!
+//! - `initial_sync`: the initial synchronisation completed
+//! - `error_rate`: more than `max_errors_per_minute` operations failed within
+//!   a minute, sent at most once a minute
+//! - `synced`: a file matching `pattern` (every file if not set) was copied,
+//!   only sent by default if a `pattern` is set
+//! - `stale`: the `[watchdog]` saw no events of the watcher for its timeout
+//! - `report`: the periodic digest of the `[report]` section
+//! - `quota`: a copy would exceed the `[quota]` of the destination
+//!
+//! ```toml
+//! [[webhooks]]
+//! url = { env = "SLACK_WEBHOOK_URL" }
+//! events = ["initial_sync", "error_rate"]
+//! template = '{"text": "fsync on {{hostname}}: {{message}}"}'
+//! ```
+//!
+//! `{{event}}`, `{{message}}`, `{{path}}`, `{{source}}`, `{{destination}}`,
+//! `{{hostname}}` and `{{errors}}` are replaced in the template, JSON escaped
+//! unless the `content_type` is not JSON. Requests are sent in the
+//! background through the `[network]` settings, at most [QUEUED] wait and
+//! later ones are dropped.
+
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::{mpsc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::{glob::Glob, target::http, AppError, Secret};
+
+/// Window of the `error_rate` event
+const ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+/// Requests waiting to be sent, later ones are dropped
+const QUEUED: usize = 256;
+
+/// Template used when none is configured, understood by Slack and Teams
+const DEFAULT_TEMPLATE: &str = r#"{"event": "{{event}}", "text": "{{message}}"}"#;
+
+/// Event a webhook subscribes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// The initial synchronisation completed
+    InitialSync,
+    /// Too many operations failed within a minute
+    ErrorRate,
+    /// A file matching the pattern was copied
+    Synced,
+    /// The watchdog saw no events for its timeout
+    Stale,
+    /// Periodic summary report
+    Report,
+    /// The destination quota is exceeded
+    Quota,
+}
+
+/// `[[webhooks]]` entry of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    /// Target URL, usually containing a token
+    pub(crate) url: Secret,
+    /// Events sent, all of them if not set
+    #[serde(default)]
+    pub(crate) events: Option<Vec<WebhookEvent>>,
+    /// Files reported by the `synced` event
+    #[serde(default)]
+    pub(crate) pattern: Option<String>,
+    /// Threshold of the `error_rate` event
+    #[serde(default = "WebhookConfig::default_max_errors")]
+    pub(crate) max_errors_per_minute: usize,
+    /// Request body
+    #[serde(default)]
+    pub(crate) template: Option<String>,
+    /// `Content-Type` of the request
+    #[serde(default)]
+    pub(crate) content_type: Option<String>,
+}
+
+impl WebhookConfig {
+    /// Default threshold of the `error_rate` event
+    fn default_max_errors() -> usize {
+        10
+    }
+
+    /// Events sent: the configured ones, else all of them, `synced` only
+    /// with a `pattern` as it is sent for every copied file
+    fn events(&self) -> Vec<WebhookEvent> {
+        if let Some(events) = &self.events {
+            return events.clone();
+        }
+        let mut events = vec![
+            WebhookEvent::InitialSync,
+            WebhookEvent::ErrorRate,
+            WebhookEvent::Stale,
+            WebhookEvent::Report,
+            WebhookEvent::Quota,
+        ];
+        if self.pattern.is_some() {
+            events.push(WebhookEvent::Synced);
+        }
+        events
+    }
+}
+
+/// `template` with the `{{key}}` placeholders of `values` replaced in one
+/// pass, so placeholders in the values stay as they are
+fn render(template: &str, values: &[(&str, String)]) -> String {
+    let mut body = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        body.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest[2..].find("}}").and_then(|end| {
+            values
+                .iter()
+                .find(|(key, _)| *key == &rest[2..2 + end])
+                .map(|(key, value)| (key.len(), value))
+        });
+        match value {
+            Some((len, value)) => {
+                body.push_str(value);
+                rest = &rest[len + 4..];
+            }
+            None => {
+                body.push_str("{{");
+                rest = &rest[2..];
+            }
+        }
+    }
+    body.push_str(rest);
+    body
+}
+
+/// Configured webhook
+struct Webhook {
+    /// URL, looked up once
+    url: String,
+    /// Events sent
+    events: Vec<WebhookEvent>,
+    /// Files reported by the `synced` event
+    pattern: Option<Glob>,
+    /// Threshold of the `error_rate` event
+    max_errors: usize,
+    /// Request body
+    template: String,
+    /// `Content-Type` of the request
+    content_type: String,
+}
+
+/// Values replacing the placeholders of a template
+#[derive(Debug, Default)]
+pub(crate) struct Details<'a> {
+    /// Human readable description
+    pub(crate) message: String,
+    /// Path relative to the destination root
+    pub(crate) path: Option<&'a Path>,
+    /// Source directory
+    pub(crate) source: &'a str,
+    /// Description of the destination
+    pub(crate) destination: &'a str,
+    /// Errors within the last minute
+    pub(crate) errors: usize,
+}
+
+/// Request waiting to be sent
+struct Delivery {
+    /// Target URL
+    url: String,
+    /// `Content-Type`
+    content_type: String,
+    /// Rendered template
+    body: String,
+}
+
+/// Webhooks of an [App](crate::App)
+pub(crate) struct Webhooks {
+    /// Configured webhooks
+    hooks: Vec<Webhook>,
+    /// Queue of the background sender
+    sender: mpsc::SyncSender<Delivery>,
+    /// Times of the recent failures
+    errors: Mutex<VecDeque<Instant>>,
+    /// Last `error_rate` notification
+    error_notified: Mutex<Option<Instant>>,
+}
+
+impl Webhooks {
+    /// Webhooks of `config`, `None` if there are none.
+    ///
+    /// # Errors
+    ///
+    /// [AppError] is returned if an URL secret could not be read or the
+    /// HTTP client could not be built.
+    pub(crate) fn new(config: &[WebhookConfig], network: &http::NetworkConfig) -> Result<Option<Self>, AppError> {
+        if config.is_empty() {
+            return Ok(None);
+        }
+        let hooks = config
+            .iter()
+            .map(|hook| {
+                Ok(Webhook {
+                    url: hook.url.expose()?.to_owned(),
+                    events: hook.events(),
+                    pattern: hook.pattern.as_deref().map(Glob::new),
+                    max_errors: hook.max_errors_per_minute,
+                    template: hook.template.clone().unwrap_or_else(|| DEFAULT_TEMPLATE.into()),
+                    content_type: hook.content_type.clone().unwrap_or_else(|| "application/json".into()),
+                })
+            })
+            .collect::<Result<Vec<_>, AppError>>()?;
+
+        let agent = http::agent(network)?;
+        let (sender, receiver) = mpsc::sync_channel::<Delivery>(QUEUED);
+        std::thread::spawn(move || {
+            for delivery in receiver {
+                if let Err(err) = agent
+                    .post(&delivery.url)
+                    .set("Content-Type", &delivery.content_type)
+                    .send_string(&delivery.body)
+                {
+                    tracing::warn!("webhook: {err}");
+                }
+            }
+        });
+        Ok(Some(Self {
+            hooks,
+            sender,
+            errors: Mutex::default(),
+            error_notified: Mutex::default(),
+        }))
+    }
+
+    /// Sends `event` to the webhooks subscribed to it, `filter` selects them further
+    fn send(&self, event: WebhookEvent, name: &str, details: &Details, filter: impl Fn(&Webhook) -> bool) {
+        for hook in self.hooks.iter().filter(|hook| hook.events.contains(&event) && filter(hook)) {
+            let json = hook.content_type.contains("json");
+            let escape = |value: &str| match json {
+                true => {
+                    let quoted = serde_json::Value::from(value).to_string();
+                    quoted[1..quoted.len() - 1].to_owned()
+                }
+                false => value.to_owned(),
+            };
+            let path = details
+                .path
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let values = [
+                ("event", name.to_owned()),
+                ("message", details.message.clone()),
+                ("path", path),
+                ("source", details.source.to_owned()),
+                (
+                    "destination",
+                    details.destination.to_owned(),
+                ),
+                ("hostname", crate::peer::hostname()),
+                ("errors", details.errors.to_string()),
+            ]
+            .map(|(key, value)| (key, escape(&value)));
+            let body = render(&hook.template, &values);
+            let delivery = Delivery {
+                url: hook.url.clone(),
+                content_type: hook.content_type.clone(),
+                body,
+            };
+            if let Err(mpsc::TrySendError::Full(_)) = self.sender.try_send(delivery) {
+                tracing::warn!("webhook: {QUEUED} requests waiting, dropping the {name} event");
+            }
+        }
+    }
+
+    /// `initial_sync` event
+    pub(crate) fn initial_sync(&self, details: &Details) {
+        self.send(
+            WebhookEvent::InitialSync,
+            "initial_sync",
+            details,
+            |_| true,
+        );
+    }
+
+    /// `stale` event of the watchdog
+    pub(crate) fn stale(&self, details: &Details) {
+        self.send(
+            WebhookEvent::Stale,
+            "stale",
+            details,
+            |_| true,
+        );
+    }
+
+    /// `quota` event of an exceeded destination quota
+    pub(crate) fn quota(&self, details: &Details) {
+        self.send(
+            WebhookEvent::Quota,
+            "quota",
+            details,
+            |_| true,
+        );
+    }
+
+    /// `report` event with a periodic digest
+    pub(crate) fn report(&self, details: &Details) {
+        self.send(
+            WebhookEvent::Report,
+            "report",
+            details,
+            |_| true,
+        );
+    }
+
+    /// `synced` event for a copied file
+    pub(crate) fn synced(&self, details: &Details) {
+        let Some(path) = details.path else { return };
+        self.send(
+            WebhookEvent::Synced,
+            "synced",
+            details,
+            |hook| hook.pattern.as_ref().is_none_or(|pattern| pattern.matches(path)),
+        );
+    }
+
+    /// Counts a failure, sending `error_rate` when a threshold is exceeded
+    pub(crate) fn failed(&self, details: &mut Details) {
+        let now = Instant::now();
+        let mut errors = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+        errors.push_back(now);
+        while errors.front().is_some_and(|time| now.duration_since(*time) > ERROR_WINDOW) {
+            errors.pop_front();
+        }
+        details.errors = errors.len();
+        drop(errors);
+
+        let mut notified = self.error_notified.lock().unwrap_or_else(|e| e.into_inner());
+        if notified.is_some_and(|time| now.duration_since(time) < ERROR_WINDOW) {
+            return;
+        }
+        let count = details.errors;
+        if self.hooks.iter().any(|hook| count > hook.max_errors) {
+            *notified = Some(now);
+            self.send(
+                WebhookEvent::ErrorRate,
+                "error_rate",
+                details,
+                |hook| count > hook.max_errors,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_replaced_once() {
+        let values = [("path", "{{message}}".to_owned()), ("message", "copied".to_owned())];
+        assert_eq!(
+            render(
+                "{{path}}: {{message}} {{unknown}} {{",
+                &values
+            ),
+            "{{message}}: copied {{unknown}} {{"
+        );
+    }
+
+    #[test]
+    fn synced_needs_a_pattern_by_default() {
+        let config = |pattern: Option<&str>, events: Option<Vec<WebhookEvent>>| WebhookConfig {
+            url: Secret::default(),
+            events,
+            pattern: pattern.map(str::to_owned),
+            max_errors_per_minute: WebhookConfig::default_max_errors(),
+            template: None,
+            content_type: None,
+        };
+        assert!(!config(None, None).events().contains(&WebhookEvent::Synced));
+        assert!(config(Some("*.md"), None).events().contains(&WebhookEvent::Synced));
+        assert_eq!(
+            config(None, Some(vec![WebhookEvent::Synced])).events(),
+            [WebhookEvent::Synced]
+        );
+    }
+}